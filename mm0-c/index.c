@@ -19,6 +19,8 @@ u64* gi_thm_vars;
 u64* gi_thm_hyps;
 u64* gi_cur_vars;
 u64* gi_cur_hyps;
+decl_span* gi_spans;
+u64* gi_src_maps;
 
 typedef struct {
   u32 id;
@@ -53,6 +55,15 @@ bool init_index() {
         gi_thm_hyps = (u64*)&g_file[ir->ptr];
         if ((u8*)&gi_thm_hyps[gi_header->num_thms] > g_end) return false;
       } break;
+      case 0x6e617053U: { // "Span"
+        gi_spans = (decl_span*)&g_file[ir->ptr];
+        u32 num = gi_header->num_sorts + gi_header->num_terms + gi_header->num_thms;
+        if ((u8*)&gi_spans[num] > g_end) return false;
+      } break;
+      case 0x4d637253U: { // "SrcM"
+        gi_src_maps = (u64*)&g_file[ir->ptr];
+        if ((u8*)&gi_src_maps[gi_header->num_thms] > g_end) return false;
+      } break;
       default:;
     }
   }
@@ -140,6 +151,52 @@ char* lookup_stmt(u8* cmd) {
   }
 }
 
+// Get the NUL-terminated string at pos, checking that it ends before the end of the file.
+char* lookup_str(u64 pos) {
+  if (!pos || pos >= (u64)(g_end - g_file)) return 0;
+  u8* p = &g_file[pos];
+  for (u8* q = p; q < g_end; q++) {
+    if (!*q) return (char*)p;
+  }
+  return 0;
+}
+
+decl_span* lookup_stmt_span(u8* cmd) {
+  if (!init_index() || !gi_spans) return 0;
+  switch (*cmd & 0x3F) {
+    case CMD_STMT_SORT:
+      return &gi_spans[g_num_sorts];
+    case CMD_STMT_DEF:
+    case CMD_STMT_LOCAL_DEF:
+      return &gi_spans[gi_header->num_sorts + g_num_terms];
+    case CMD_STMT_AXIOM:
+    case CMD_STMT_THM:
+    case CMD_STMT_LOCAL_THM:
+      return &gi_spans[gi_header->num_sorts + gi_header->num_terms + g_num_thms];
+    default: return 0;
+  }
+}
+
+// Find the source map entry for the proof step containing the command at pos,
+// that is, the last entry starting at or before pos.
+src_entry* lookup_src(u8* cmd, u8* pos) {
+  if (!init_index() || !gi_src_maps) return 0;
+  switch (*cmd & 0x3F) {
+    case CMD_STMT_THM:
+    case CMD_STMT_LOCAL_THM: break;
+    default: return 0;
+  }
+  if (!gi_src_maps[g_num_thms]) return 0;
+  u64* p = check_vars(gi_src_maps[g_num_thms]);
+  if (!p) return 0;
+  src_entry* src = (src_entry*)p;
+  src_entry* end = &src[p[-1]];
+  if ((u8*)end > g_end) return 0;
+  src_entry* res = 0;
+  for (; src < end && &g_file[src->proof] <= pos; src++) res = src;
+  return res;
+}
+
 char* lookup_var_name(u32 var) {
   if (!gi_cur_vars || var >= gi_cur_vars[-1]) return 0;
   u64 name = gi_cur_vars[var];
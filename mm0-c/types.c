@@ -143,6 +143,22 @@ typedef struct {
   u64 name;                  // pointer to the name of the item
 } name_entry;
 
+// An entry in the "Span" table, giving the location of a declaration in the
+// source file it was compiled from.
+typedef struct {
+  u64 file;                  // pointer to the path of the source file, or 0
+  u32 full[2];               // byte range of the whole declaration
+  u32 name[2];               // byte range of the name of the declaration
+} decl_span;
+
+// An entry in the list of source map entries of a theorem in the "SrcM" table,
+// giving the location of the code that produced a step of the proof.
+typedef struct {
+  u64 proof;                 // pointer to the first proof command of the step
+  u64 file;                  // pointer to the path of the source file
+  u32 span[2];               // byte range of the code that produced the step
+} src_entry;
+
 // A command is a variable length instruction that forms the bulk of the proof
 // file. The commands are processed by a stack machine.
 //
@@ -34,6 +34,16 @@ void fail(char* err, int e) {
       debug_print_cmd(g_ucmd, data);
     }
     fprintf(stderr, "\n");
+    decl_span* sp = lookup_stmt_span(g_stmt);
+    if (sp) {
+      char* file = lookup_str(sp->file);
+      fprintf(stderr, "source: %s:%u-%u\n", file ? file : "?", sp->full[0], sp->full[1]);
+    }
+    src_entry* src = lookup_src(g_stmt, g_cmd);
+    if (src) {
+      char* file = lookup_str(src->file);
+      fprintf(stderr, "step source: %s:%u-%u\n", file ? file : "?", src->span[0], src->span[1]);
+    }
     if (name) {
       fprintf(stderr, "at %s: ", name);
     }
@@ -663,6 +673,7 @@ void verify(u8* file, u64 len) {
   ENSURE("header not long enough", len >= sizeof(header));
   header* p = (header*)file;
   ENSURE("Not a MM0B file", p->magic == MM0B_MAGIC);
+  ENSURE("MMB file was produced by a newer mm0-rs, please upgrade", p->version <= MM0B_VERSION);
   ENSURE("Wrong version", p->version == MM0B_VERSION);
   ENSURE("Too many sorts", p->num_sorts <= MAX_SORTS);
   ENSURE("header not long enough", len >= sizeof(header) + p->num_sorts);
@@ -0,0 +1,61 @@
+//! Benchmarks for the lisp evaluator, in particular for lambda application, which is
+//! the hot path of most tactics.
+//!
+//! Run with `cargo bench --bench lisp`.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use criterion::{criterion_group, criterion_main, Criterion};
+use mm0_rs::elab::ElaborateBuilder;
+use mm0_rs::{FileRef, LinedString};
+
+/// Elaborate `text`, which should have no imports, and check that it has no errors.
+fn elab(text: &str) {
+  let (_, ast) = mm1_parser::parse(Arc::new(LinedString::from(text.to_owned())), None);
+  let fut = ElaborateBuilder {
+    ast: &Arc::new(ast),
+    path: FileRef::from(PathBuf::from("bench.mm1")),
+    mm0_mode: false,
+    check_proofs: true,
+    report_upstream_errors: false,
+    cancel: Arc::default(),
+    old: None,
+    recv_dep: |_| -> Result<futures::channel::oneshot::Receiver<mm0_rs::elab::ElabResult<()>>, _> {
+      Err("unexpected import".into())
+    },
+    recv_goal: None,
+    prelude: None,
+    on_stmt: None,
+  }.elab();
+  let (_, _, errors, _) = futures::executor::block_on(fut);
+  assert!(errors.is_empty(), "{:?}", errors);
+}
+
+/// Non-tail recursion, where every call pushes a return frame.
+const FIB: &str = "do {
+  (def (fib n) (if {n < 2} n {(fib {n - 1}) + (fib {n - 2})}))
+  (def r (fib 20))
+};";
+
+/// Tail recursion through a loop with an accumulator.
+const LOOP: &str = "do {
+  (def (count n acc) (if {n = 0} acc (count {n - 1} {acc + 1})))
+  (def r (count 100000 0))
+};";
+
+/// Calls of closures passed to higher order functions.
+const MAP: &str = "do {
+  (def (range n) (letrec ([go (fn (i acc) (if {i = 0} acc (go {i - 1} (cons i acc))))]) (go n ())))
+  (def xs (range 1000))
+  (def (go k) (if {k = 0} #t (begin (map (fn (x) {x * k}) xs) (go {k - 1}))))
+  (def r (go 100))
+};";
+
+fn lambda(c: &mut Criterion) {
+  c.bench_function("fib", |b| b.iter(|| elab(FIB)));
+  c.bench_function("loop", |b| b.iter(|| elab(LOOP)));
+  c.bench_function("map", |b| b.iter(|| elab(MAP)));
+}
+
+criterion_group!(benches, lambda);
+criterion_main!(benches);
@@ -42,7 +42,8 @@ make_atoms! {
   UNFOLD: ":unfold",
   /// In MMU proofs, `(:let h p1 p2)` is a let-binding for supporting deduplication.
   LET: ":let",
-  /// In refine, `{p : t}` is a type ascription for proofs.
+  /// In refine, `{p : t}` is a type ascription for proofs. In terms, `(: e s)` is a sort
+  /// ascription, which is checked and then erased in elaborated terms and proofs.
   COLON: ":",
   /// In refine, `?` is a proof by "sorry" (stubbing the proof without immediate error)
   QMARK: "?",
@@ -60,7 +61,8 @@ make_atoms! {
   ABSTRACT: "abstract",
   /// `local` is an atom used to specify the visibility modifier in `add-decl`
   LOCAL: "local",
-  /// `:sorry` is an atom used by `get-decl` to print missing proofs
+  /// `:sorry` is an atom used by `get-decl` to print missing proofs. In refine, `:sorry`
+  /// closes the goal but marks the enclosing theorem as incomplete.
   SORRY: ":sorry",
   /// `error` is an error level recognized by `set-reporting`
   ERROR: "error",
@@ -76,4 +78,25 @@ make_atoms! {
   REFINE_EXTRA_ARGS: "refine-extra-args",
   /// `to-expr-fallback` is called when elaborating a term that is not otherwise recognized
   TO_EXPR_FALLBACK: "to-expr-fallback",
+  /// `:at` is a keyword used by `rw` to give the position of the subterm to rewrite
+  AT: ":at",
+  /// The `before-decl` function is a hook called as `(before-decl x)` before the
+  /// declaration `x` is elaborated.
+  BEFORE_DECL: "before-decl",
+  /// The `after-decl` function is a hook called as `(after-decl x)` after the
+  /// declaration `x` is elaborated.
+  AFTER_DECL: "after-decl",
+  /// The `on-new-goal` function is a hook called as `(on-new-goal g)` when a new goal `g`
+  /// is created: the initial goal of a theorem, the goals created by `refine` (including
+  /// `?`), and the rewritten goal of `rw`.
+  ON_NEW_GOAL: "on-new-goal",
+  /// The `on-assign-mvar` function is a hook called as `(on-assign-mvar m e)` when
+  /// unification is about to assign the metavariable `m` to `e`, so `m` is still unassigned.
+  ON_ASSIGN_MVAR: "on-assign-mvar",
+  /// The `rank-premises` function is a hook called as `(rank-premises g xs)` by `auto`
+  /// to choose the order in which the hints `xs` are tried on the goal `g`.
+  RANK_PREMISES: "rank-premises",
+  /// The `rw-thm` function is a hook called as `(rw-thm thm path)` by `rw` to rewrite the
+  /// first goal with the theorem `thm`, since this depends on the equality of the logic.
+  RW_THM: "rw-thm",
 }
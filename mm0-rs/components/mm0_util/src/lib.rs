@@ -187,6 +187,14 @@ pub fn u32_as_usize(n: u32) -> usize {
   n.try_into().expect("here's a nickel, get a better computer")
 }
 
+/// Get the message of a panic payload, as returned by [`std::panic::catch_unwind`].
+#[must_use]
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+  if let Some(s) = payload.downcast_ref::<&str>() { s }
+  else if let Some(s) = payload.downcast_ref::<String>() { s }
+  else { "unknown panic payload" }
+}
+
 /// Translate a number into an alphabetic numbering system, indexing into the following infinite
 /// sequence:
 /// ```ignore
@@ -506,11 +514,84 @@ fn make_relative(buf: &std::path::Path) -> String {
     .to_owned()
 }
 
+/// The policy used to decide when two paths refer to the same file, set by
+/// [`set_path_policy`]. Every path is normalized according to this policy when it is
+/// converted to a [`FileRef`], and two [`FileRef`]s are equal if their normalized paths are.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PathPolicy {
+  /// Only remove `.` and `..` components. Two paths to the same file through different
+  /// symbolic links are different files.
+  Lexical = 0,
+  /// Resolve symbolic links, using [`std::fs::canonicalize`]. This is the default.
+  /// Paths to files that do not exist are normalized lexically.
+  Canonical = 1,
+  /// Resolve symbolic links, and also compare paths without regard to case,
+  /// for case-insensitive file systems.
+  IgnoreCase = 2,
+}
+
+impl std::str::FromStr for PathPolicy {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "lexical" => Ok(Self::Lexical),
+      "canonical" => Ok(Self::Canonical),
+      "ignore-case" => Ok(Self::IgnoreCase),
+      _ => Err(format!("unknown path policy '{}', expected lexical, canonical or ignore-case", s)),
+    }
+  }
+}
+
+static PATH_POLICY: std::sync::atomic::AtomicU8 =
+  std::sync::atomic::AtomicU8::new(PathPolicy::Canonical as u8);
+
+/// Get the current [`PathPolicy`].
+#[must_use]
+pub fn get_path_policy() -> PathPolicy {
+  match PATH_POLICY.load(std::sync::atomic::Ordering::Relaxed) {
+    0 => PathPolicy::Lexical,
+    1 => PathPolicy::Canonical,
+    _ => PathPolicy::IgnoreCase,
+  }
+}
+
+/// Set the [`PathPolicy`]. This should be done before any [`FileRef`] is created,
+/// because files created under different policies will not compare equal.
+pub fn set_path_policy(p: PathPolicy) {
+  PATH_POLICY.store(p as u8, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Normalize `path` according to the current [`PathPolicy`]. The result is an absolute path.
+#[cfg(all(not(target_arch = "wasm32"), feature = "lined_string"))]
+#[must_use]
+pub fn normalize_path(path: &std::path::Path) -> PathBuf {
+  use std::path::Component;
+  let lexical = || {
+    let mut out = PathBuf::new();
+    for c in CURRENT_DIR.join(path).components() {
+      match c {
+        Component::CurDir => {}
+        Component::ParentDir => { out.pop(); }
+        c => out.push(c),
+      }
+    }
+    out
+  };
+  match get_path_policy() {
+    PathPolicy::Lexical => lexical(),
+    PathPolicy::Canonical | PathPolicy::IgnoreCase =>
+      std::fs::canonicalize(path).unwrap_or_else(|_| lexical()),
+  }
+}
+
 #[cfg_attr(feature = "memory", derive(DeepSizeOf))]
 #[derive(Default)]
 struct FileRefInner {
   path: PathBuf,
   rel: String,
+  /// The key used for comparing and hashing: the relative path,
+  /// lowercased under [`PathPolicy::IgnoreCase`]
+  key: String,
   #[cfg(feature = "server")]
   url: Option<lsp_types::Url>,
 }
@@ -521,6 +602,8 @@ struct FileRefInner {
 /// and provides (precomputed) access to these views using
 /// [`path()`](FileRef::path) and [`url()`](FileRef::url), as well as
 /// [`rel()`](FileRef::rel) to get the relative path from [`struct@CURRENT_DIR`].
+/// The path is normalized on construction according to the current [`PathPolicy`],
+/// so that different spellings of the same file give equal [`FileRef`]s.
 #[cfg_attr(feature = "memory", derive(DeepSizeOf))]
 #[derive(Clone, Default)]
 pub struct FileRef(Arc<FileRefInner>);
@@ -532,8 +615,11 @@ impl From<PathBuf> for FileRef {
 
   #[cfg(all(not(target_arch = "wasm32"), feature = "lined_string"))]
   fn from(path: PathBuf) -> FileRef {
+    let path = normalize_path(&path);
+    let rel = make_relative(&path);
     FileRef(Arc::new(FileRefInner {
-      rel: make_relative(&path),
+      key: file_key(&rel),
+      rel,
       #[cfg(feature = "server")]
       url: lsp_types::Url::from_file_path(&path).ok(),
       path,
@@ -541,6 +627,15 @@ impl From<PathBuf> for FileRef {
   }
 }
 
+/// The comparison key for a file with relative path `rel`.
+#[cfg(all(not(target_arch = "wasm32"), feature = "lined_string"))]
+fn file_key(rel: &str) -> String {
+  match get_path_policy() {
+    PathPolicy::IgnoreCase => rel.to_lowercase(),
+    PathPolicy::Lexical | PathPolicy::Canonical => rel.to_owned(),
+  }
+}
+
 #[cfg(feature = "server")]
 impl From<lsp_types::Url> for FileRef {
   #[cfg(target_arch = "wasm32")]
@@ -548,9 +643,10 @@ impl From<lsp_types::Url> for FileRef {
 
   #[cfg(not(target_arch = "wasm32"))]
   fn from(url: lsp_types::Url) -> FileRef {
-    let path = url.to_file_path().expect("bad URL");
+    // The URL is kept as is, because it is the name the client uses for the file.
+    let path = normalize_path(&url.to_file_path().expect("bad URL"));
     let rel = make_relative(&path);
-    FileRef(Arc::new(FileRefInner { path, rel, url: Some(url) }))
+    FileRef(Arc::new(FileRefInner { path, key: file_key(&rel), rel, url: Some(url) }))
   }
 }
 
@@ -583,12 +679,12 @@ impl FileRef {
   }
 }
 impl PartialEq for FileRef {
-  fn eq(&self, other: &Self) -> bool { self.0.rel == other.0.rel }
+  fn eq(&self, other: &Self) -> bool { self.0.key == other.0.key }
 }
 impl Eq for FileRef {}
 
 impl Hash for FileRef {
-  fn hash<H: Hasher>(&self, state: &mut H) { self.0.rel.hash(state) }
+  fn hash<H: Hasher>(&self, state: &mut H) { self.0.key.hash(state) }
 }
 
 impl fmt::Display for FileRef {
@@ -8,6 +8,71 @@ use crate::{Position, Range, Span};
 #[cfg(feature = "memory")]
 use mm0_deepsize_derive::DeepSizeOf;
 use std::ops::{Deref, Index};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The unit in which the `character` field of an LSP [`Position`] is counted. LSP 3.17 lets
+/// the client and server negotiate this with the `positionEncoding` capability; before that
+/// it was always UTF-16.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PositionEncoding {
+  /// Bytes of the UTF-8 encoding.
+  Utf8 = 0,
+  /// Code units of the UTF-16 encoding. This is the default.
+  Utf16 = 1,
+  /// Unicode code points.
+  Utf32 = 2,
+}
+
+impl PositionEncoding {
+  /// The name of the encoding in the LSP protocol, like `utf-16`.
+  #[must_use]
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Utf8 => "utf-8",
+      Self::Utf16 => "utf-16",
+      Self::Utf32 => "utf-32",
+    }
+  }
+
+  /// Parse the LSP name of an encoding.
+  #[must_use]
+  pub fn from_name(s: &str) -> Option<Self> {
+    match s {
+      "utf-8" => Some(Self::Utf8),
+      "utf-16" => Some(Self::Utf16),
+      "utf-32" => Some(Self::Utf32),
+      _ => None,
+    }
+  }
+
+  /// The number of units of the character `c` in this encoding.
+  #[must_use]
+  fn len(self, c: char) -> usize {
+    match self {
+      Self::Utf8 => c.len_utf8(),
+      Self::Utf16 => c.len_utf16(),
+      Self::Utf32 => 1,
+    }
+  }
+}
+
+static POSITION_ENCODING: AtomicU8 = AtomicU8::new(PositionEncoding::Utf16 as u8);
+
+/// Get the [`PositionEncoding`] used to convert to and from LSP positions.
+#[must_use]
+pub fn get_position_encoding() -> PositionEncoding {
+  match POSITION_ENCODING.load(Ordering::Relaxed) {
+    0 => PositionEncoding::Utf8,
+    1 => PositionEncoding::Utf16,
+    _ => PositionEncoding::Utf32,
+  }
+}
+
+/// Set the [`PositionEncoding`] used to convert to and from LSP positions. The language
+/// server sets this once, to the encoding negotiated with the client.
+pub fn set_position_encoding(enc: PositionEncoding) {
+  POSITION_ENCODING.store(enc as u8, Ordering::Relaxed)
+}
 
 /// Wrapper around std's String which stores data about the positions of any newline characters.
 ///
@@ -29,11 +94,13 @@ impl Index<Span> for LinedString {
   fn index(&self, s: Span) -> &[u8] { &self.as_bytes()[s.start..s.end] }
 }
 
-/// Calculates the largest index `n` (on a UTF8 boundary) such that `s[..n]` is at most `chs` UTF-16 codepoints.
+/// Calculates the largest index `n` (on a UTF8 boundary) such that `s[..n]` is at most `chs`
+/// units in the current [`PositionEncoding`].
 /// Used in [`LinedString::lsp_to_idx`] to account for additional character offset introduced by unicode.
 fn lsp_to_idx(s: &str, mut chs: usize) -> usize {
+  let enc = get_position_encoding();
   for (n, c) in s.char_indices() {
-    let i = c.len_utf16();
+    let i = enc.len(c);
     if chs < i {
       return n
     }
@@ -83,8 +150,9 @@ impl LinedString {
     Position {
       line: line.try_into().expect("too many lines"),
       character: if self.unicode {
+        let enc = get_position_encoding();
         // Safety: we know that `pos` is valid index, and we have assumed that `idx` is
-        unsafe { self.s.get_unchecked(pos..idx) }.chars().map(char::len_utf16).sum()
+        unsafe { self.s.get_unchecked(pos..idx) }.chars().map(|c| enc.len(c)).sum()
       } else {
         idx - pos
       }
@@ -93,6 +161,25 @@ impl LinedString {
     }
   }
 
+  /// The byte index of the start of the line containing the byte index `idx`.
+  #[must_use]
+  pub fn line_start(&self, idx: usize) -> usize {
+    match self.lines.binary_search(&idx) {
+      Ok(_) => idx,
+      Err(n) => n.checked_sub(1).map_or(0, |i| self.lines[i]),
+    }
+  }
+
+  /// The 1-based line and column of the byte index `idx`, where the column counts Unicode
+  /// characters. This is for locations shown to the user, such as `file:line:col` in
+  /// command line output; use [`to_pos`](Self::to_pos) for the LSP protocol.
+  #[must_use]
+  pub fn to_line_col(&self, idx: usize) -> (usize, usize) {
+    let start = self.line_start(idx);
+    let line = match self.lines.binary_search(&idx) { Ok(n) => n + 1, Err(n) => n };
+    (line + 1, self.s.get(start..idx).map_or(idx - start, |s| s.chars().count()) + 1)
+  }
+
   /// Turn a [`Span`] into an LSP [`Range`].
   #[must_use]
   pub fn to_range(&self, s: Span) -> Range {
@@ -112,11 +199,13 @@ impl LinedString {
     self.lines.len().try_into().expect("too many lines")
   }
 
-  /// Get the [`Position`] (line and UTF-16 code unit offset) of the end of the file.
+  /// Get the [`Position`] (line and offset in the current [`PositionEncoding`]) of the end
+  /// of the file.
   #[must_use]
   pub fn end(&self) -> Position { self.to_pos(self.s.len()) }
 
-  /// Calculates the byte index of the position `chs` UTF-16 code units after
+  /// Calculates the byte index of the position `chs` units (in the current
+  /// [`PositionEncoding`]) after
   /// byte index `start` in the string.
   /// If there's no unicode, we can just use (start + idx).
   /// In the presence of unicode, use the helper function [`lsp_to_idx`](Self::lsp_to_idx)
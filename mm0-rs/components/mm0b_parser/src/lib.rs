@@ -73,8 +73,17 @@ pub const MAX_BOUND_VARS: usize = 55;
 pub mod cmd {
   /// `MM0B_MAGIC = "MM0B"`: Magic number signalling the MM0B format is in use.
   pub const MM0B_MAGIC: [u8; 4] = *b"MM0B";
-  /// `MM0B_VERSION = 1`, maximum supported MMB version
+  /// `MM0B_VERSION = 1`, maximum supported MMB version, and the version of the files we write.
+  ///
+  /// When the format changes, this is bumped, and readers continue to accept files with
+  /// versions from [`MM0B_MIN_VERSION`] up to this one. Files with older versions can be
+  /// upgraded by loading them and exporting them again (`mm0-rs compile old.mmb new.mmb`).
+  /// The layout of the header up to `p_index`, and of the index table, does not change
+  /// between versions, so that [`find_producer`](crate::find_producer) can report what
+  /// wrote a file that we can't otherwise read.
   pub const MM0B_VERSION: u8 = 1;
+  /// `MM0B_MIN_VERSION = 1`, minimum supported MMB version
+  pub const MM0B_MIN_VERSION: u8 = 1;
 
   /// `DATA_8 = 0x40`, used as a command mask for an 8 bit data field
   pub const DATA_8: u8 = 0x40;
@@ -155,6 +164,19 @@ pub mod cmd {
   pub const INDEX_VAR_NAME: [u8; 4] = *b"VarN";
   /// `"HypN"` is the magic number for the hypothesis name table.
   pub const INDEX_HYP_NAME: [u8; 4] = *b"HypN";
+  /// `"SrcM"` is the magic number for the proof source map table.
+  pub const INDEX_SOURCE_MAP: [u8; 4] = *b"SrcM";
+  /// `"File"` is the magic number for the source file table.
+  pub const INDEX_FILE: [u8; 4] = *b"File";
+  /// `"Span"` is the magic number for the declaration span table.
+  pub const INDEX_SPAN: [u8; 4] = *b"Span";
+  /// `"Doc "` is the magic number for the doc comment table.
+  pub const INDEX_DOC: [u8; 4] = *b"Doc ";
+  /// `"Attr"` is the magic number for the attribute table.
+  pub const INDEX_ATTR: [u8; 4] = *b"Attr";
+  /// `"Prod"` is the magic number for the producer, a string naming the program that wrote
+  /// the file and its version, like `mm0-rs 0.1.0`.
+  pub const INDEX_PRODUCER: [u8; 4] = *b"Prod";
 }
 
 #[inline]
@@ -517,7 +539,8 @@ pub struct Header {
   /// The magic number, which is used to identify this as an mmb file. Must be
   /// equal to [`MM0B_MAGIC`](cmd::MM0B_MAGIC) = `"MM0B"`.
   pub magic: [u8; 4],
-  /// The MMB format version number. Must equal [`MM0B_VERSION`](cmd::MM0B_VERSION) = 1.
+  /// The MMB format version number. Must be between [`MM0B_MIN_VERSION`](cmd::MM0B_MIN_VERSION)
+  /// and [`MM0B_VERSION`](cmd::MM0B_VERSION) = 1.
   pub version: u8,
   /// The number of sorts in the file. This is limited to 128.
   pub num_sorts: u8,
@@ -548,13 +571,16 @@ impl Header {
   /// For example, none of the pointers in the header should be greater than the length
   /// of the file, the terms pointer should be less than the theorems pointer, etc.
   pub fn check(&self, mmb: &[u8]) -> Result<(), ParseError> {
-    use crate::cmd::{MM0B_MAGIC, MM0B_VERSION};
+    use crate::cmd::{MM0B_MAGIC, MM0B_MIN_VERSION, MM0B_VERSION};
 
     if self.magic != MM0B_MAGIC {
       return Err(ParseError::BadMagic { parsed_magic: self.magic })
     }
-    if self.version != MM0B_VERSION {
-      return Err(ParseError::BadVersion { parsed_version: self.version })
+    if !(MM0B_MIN_VERSION..=MM0B_VERSION).contains(&self.version) {
+      return Err(ParseError::BadVersion {
+        parsed_version: self.version,
+        producer: crate::find_producer(mmb).map(Into::into),
+      })
     }
 
     let p_terms = u32_as_usize(self.p_terms.get());
@@ -654,3 +680,27 @@ pub struct NameEntry {
   /// A pointer to the entity's name as a UTF-8 C string.
   pub p_name: U64<LE>,
 }
+
+/// The source location of a declaration, in the `"Span"` index table.
+#[repr(C, align(8))]
+#[derive(Debug, Clone, Copy, FromBytes, AsBytes)]
+pub struct DeclSpan {
+  /// A pointer to the path of the source file as a UTF-8 C string, or 0 if unknown.
+  pub p_file: U64<LE>,
+  /// The byte range of the whole declaration in the source file.
+  pub full: [U32<LE>; 2],
+  /// The byte range of the name of the declaration in the source file.
+  pub name: [U32<LE>; 2],
+}
+
+/// The source of a step of a proof, in the `"SrcM"` index table.
+#[repr(C, align(8))]
+#[derive(Debug, Clone, Copy, FromBytes, AsBytes)]
+pub struct SourceMapEntry {
+  /// A pointer to the first proof command of the step.
+  pub p_proof: U64<LE>,
+  /// A pointer to the path of the source file as a UTF-8 C string.
+  pub p_file: U64<LE>,
+  /// The byte range of the expression that produced the step in the source file.
+  pub span: [U32<LE>; 2],
+}
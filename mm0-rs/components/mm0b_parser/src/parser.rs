@@ -1,7 +1,8 @@
 //! Parser for MMB binary proof files.
 use crate::{
-  cmd, cstr_from_bytes_prefix, exhausted, u32_as_usize, u64_as_usize, Arg, Header, NameEntry,
-  NumdStmtCmd, ProofCmd, SortData, StmtCmd, TableEntry, TermEntry, ThmEntry, UnifyCmd,
+  cmd, cstr_from_bytes_prefix, exhausted, u32_as_usize, u64_as_usize, Arg, DeclSpan, Header,
+  NameEntry, NumdStmtCmd, ProofCmd, SortData, SourceMapEntry, StmtCmd, TableEntry, TermEntry,
+  ThmEntry, UnifyCmd,
 };
 use byteorder::LE;
 use mm0_util::{SortId, TermId, ThmId};
@@ -151,6 +152,7 @@ make_index_trait! {
 }
 impl<'a> NoSymbolNames for Option<VarNames<'a>> {}
 impl<'a> NoSymbolNames for Option<HypNames<'a>> {}
+impl<'a> NoSymbolNames for Option<SourceInfo<'a>> {}
 
 /// This index subcomponent supplies variable names for terms and theorems.
 #[derive(Debug)]
@@ -184,6 +186,7 @@ make_index_trait! {
 }
 impl<'a> NoVarNames for Option<SymbolNames<'a>> {}
 impl<'a> NoVarNames for Option<HypNames<'a>> {}
+impl<'a> NoVarNames for Option<SourceInfo<'a>> {}
 
 /// This index subcomponent supplies hypothesis names for theorems.
 #[derive(Debug)]
@@ -213,9 +216,75 @@ make_index_trait! {
 }
 impl<'a> NoHypNames for Option<SymbolNames<'a>> {}
 impl<'a> NoHypNames for Option<VarNames<'a>> {}
+impl<'a> NoHypNames for Option<SourceInfo<'a>> {}
 
-/// A basic index, usable for getting names of declarations and variables.
-pub type BasicIndex<'a> = (Option<SymbolNames<'a>>, (Option<VarNames<'a>>, Option<HypNames<'a>>));
+/// This index subcomponent supplies information about the sources of the declarations and
+/// proofs: the source files, the spans, doc comments and attributes of declarations, and the
+/// source maps of proofs. Each of these comes from a separate table, which may be missing.
+#[derive(Debug, Default)]
+pub struct SourceInfo<'a> {
+  /// A pointer to the list of source files (the `"File"` table)
+  files: Option<U64<LE>>,
+  /// The spans of the sorts, terms and theorems, in that order (the `"Span"` table)
+  spans: Option<&'a [DeclSpan]>,
+  /// The doc comments of the sorts, terms and theorems, in that order (the `"Doc "` table)
+  docs: Option<&'a [U64<LE>]>,
+  /// The attribute lists of the sorts, terms and theorems, in that order (the `"Attr"` table)
+  attrs: Option<&'a [U64<LE>]>,
+  /// The source maps of the theorems (the `"SrcM"` table)
+  src_maps: Option<&'a [U64<LE>]>,
+}
+
+impl<'a> MmbIndexBuilder<'a> for Option<SourceInfo<'a>> {
+  fn build<X>(&mut self, f: &mut MmbFile<'a, X>, e: &'a TableEntry) -> Result<(), ParseError> {
+    fn table<'a, T: FromBytes, X>(
+      f: &MmbFile<'a, X>, e: &TableEntry, field: &mut Option<&'a [T]>, len: usize,
+    ) -> Result<(), ParseError> {
+      let rest = f.buf.get(u64_as_usize(e.ptr)..).ok_or_else(|| f.bad_index_parse())?;
+      let (tbl, _) = new_slice_prefix(rest, len).ok_or_else(|| f.bad_index_parse())?;
+      if field.replace(tbl).is_some() {
+        return Err(ParseError::DuplicateIndexTable {
+          p_index: u64_as_usize(f.header.p_index),
+          id: e.id,
+        })
+      }
+      Ok(())
+    }
+    let num_decls = f.sorts.len() + f.terms.len() + f.thms.len();
+    match e.id {
+      cmd::INDEX_FILE => {
+        if self.get_or_insert_with(Default::default).files.replace(e.ptr).is_some() {
+          return Err(ParseError::DuplicateIndexTable {
+            p_index: u64_as_usize(f.header.p_index),
+            id: e.id,
+          })
+        }
+      }
+      cmd::INDEX_SPAN =>
+        table(f, e, &mut self.get_or_insert_with(Default::default).spans, num_decls)?,
+      cmd::INDEX_DOC =>
+        table(f, e, &mut self.get_or_insert_with(Default::default).docs, num_decls)?,
+      cmd::INDEX_ATTR =>
+        table(f, e, &mut self.get_or_insert_with(Default::default).attrs, num_decls)?,
+      cmd::INDEX_SOURCE_MAP =>
+        table(f, e, &mut self.get_or_insert_with(Default::default).src_maps, f.thms.len())?,
+      _ => {}
+    }
+    Ok(())
+  }
+}
+
+make_index_trait! {
+  [<'a>, SourceInfo, HasSourceInfo, NoSourceInfo, get_source_info, get_source_info_mut]
+}
+impl<'a> NoSourceInfo for Option<SymbolNames<'a>> {}
+impl<'a> NoSourceInfo for Option<VarNames<'a>> {}
+impl<'a> NoSourceInfo for Option<HypNames<'a>> {}
+
+/// A basic index, usable for getting names of declarations and variables,
+/// and the sources they came from.
+pub type BasicIndex<'a> = (Option<SymbolNames<'a>>,
+  (Option<VarNames<'a>>, (Option<HypNames<'a>>, Option<SourceInfo<'a>>)));
 
 /// Return the raw command data (a pair `[(u8, u32)]`)
 /// while ensuring that an iterator which is literally empty
@@ -439,7 +508,10 @@ pub enum ParseError {
   /// The version is unrecognized.
   BadVersion {
     /// The MMB file version, greater than [`MM0B_VERSION`](crate::cmd::MM0B_VERSION)
+    /// or less than [`MM0B_MIN_VERSION`](crate::cmd::MM0B_MIN_VERSION)
     parsed_version: u8,
+    /// The program that wrote the file, if it could be found (see [`find_producer`]).
+    producer: Option<String>,
   },
   /// The portion of the mmb file that's supposed to contain sorts was malformed.
   BadSorts(Range<usize>),
@@ -554,12 +626,26 @@ impl std::fmt::Display for ParseError {
         This is too short to contain a header, and cannot be well-formed",
         file_len
       ),
-      ParseError::BadVersion { parsed_version } => write!(
-        f,
-        "MMB version mismatch: File header specifies version {}, but verifier version is {}",
-        parsed_version,
-        crate::cmd::MM0B_VERSION
-      ),
+      ParseError::BadVersion { parsed_version, producer } => {
+        use crate::cmd::{MM0B_MIN_VERSION, MM0B_VERSION};
+        write!(f, "MMB version mismatch: File header specifies version {}", parsed_version)?;
+        if let Some(producer) = producer { write!(f, " (produced by {})", producer)? }
+        if *parsed_version > MM0B_VERSION {
+          write!(
+            f,
+            ", but the maximum supported version is {}. \
+            The file was produced by a newer mm0-rs; upgrade to read it.",
+            MM0B_VERSION
+          )
+        } else {
+          write!(
+            f,
+            ", which is no longer supported (supported versions are {} to {}). \
+            Recompile the file from its source.",
+            MM0B_MIN_VERSION, MM0B_VERSION
+          )
+        }
+      }
       ParseError::BadIndexParse { p_index } => write!(
         f,
         "MMB index is malformed. According to the header, it begins at byte {}. {}",
@@ -627,6 +713,38 @@ impl<'a, X> MmbFile<'a, X> {
   pub fn bad_index_parse(&self) -> ParseError {
     ParseError::BadIndexParse { p_index: u64_as_usize(self.header.p_index) }
   }
+
+  /// Get the list of tables in the index, or `None` if the index is missing or malformed.
+  #[must_use]
+  pub fn index_entries(&self) -> Option<&'a [TableEntry]> {
+    index_entries(self.buf, self.header.p_index)
+  }
+
+  /// Get the producer of the file, the program that wrote it (the `"Prod"` table).
+  #[must_use]
+  pub fn producer(&self) -> Option<&'a str> { producer(self.buf, self.index_entries()?) }
+}
+
+fn index_entries(buf: &[u8], p_index: U64<LE>) -> Option<&[TableEntry]> {
+  let n = u64_as_usize(p_index);
+  if n == 0 { return None }
+  let (num_entries, rest) =
+    LayoutVerified::<_, U64<LE>>::new_unaligned_from_prefix(buf.get(n..)?)?;
+  Some(new_slice_prefix(rest, num_entries.get().try_into().ok()?)?.0)
+}
+
+fn producer<'a>(buf: &'a [u8], entries: &[TableEntry]) -> Option<&'a str> {
+  let e = entries.iter().find(|e| e.id == cmd::INDEX_PRODUCER)?;
+  cstr_from_bytes_prefix(buf.get(u64_as_usize(e.ptr)..)?)?.0.to_str().ok()
+}
+
+/// Get the producer of an MMB file (see [`MmbFile::producer`]) without parsing or checking
+/// the rest of the file. This works for files of any version, so it can be used to report
+/// what wrote a file that we can't read.
+#[must_use]
+pub fn find_producer(mmb: &[u8]) -> Option<&str> {
+  let (header, _) = LayoutVerified::<_, Header>::new_from_prefix(mmb)?;
+  producer(mmb, index_entries(mmb, header.into_ref().p_index)?)
 }
 
 impl<'a, X: MmbIndexBuilder<'a>> MmbFile<'a, X> {
@@ -664,14 +782,9 @@ impl<'a, X: MmbIndexBuilder<'a>> MmbFile<'a, X> {
       })?
       .0;
     let mut file = MmbFile { header: *header, buf, sorts, terms, thms, index: X::default() };
-    let n = u64_as_usize(header.p_index);
-    if n != 0 {
-      let (entries, _) = (|| -> Option<_> {
-        let (num_entries, rest) =
-          LayoutVerified::<_, U64<LE>>::new_unaligned_from_prefix(&*buf.get(n..)?)?;
-        new_slice_prefix(rest, num_entries.get().try_into().ok()?)
-      })()
-      .ok_or_else(|| BadIndexParse { p_index: u64_as_usize(header.p_index) })?;
+    if header.p_index.get() != 0 {
+      let entries = file.index_entries()
+        .ok_or_else(|| BadIndexParse { p_index: u64_as_usize(header.p_index) })?;
       let mut index = X::default();
       for e in entries {
         index.build(&mut file, e)?
@@ -728,8 +841,11 @@ pub fn find_header_error(mmb: &[u8]) -> ParseError {
       return Err(ParseError::BadMagic { parsed_magic: magic })
     }
     let (version, pos) = parse_u8((mmb, pos))?;
-    if version != crate::cmd::MM0B_VERSION {
-      return Err(ParseError::BadVersion { parsed_version: version })
+    if !(crate::cmd::MM0B_MIN_VERSION..=crate::cmd::MM0B_VERSION).contains(&version) {
+      return Err(ParseError::BadVersion {
+        parsed_version: version,
+        producer: find_producer(mmb).map(Into::into),
+      })
     }
     let (_num_sorts, pos) = parse_u8((mmb, pos))?;
     let (_reserved, pos) = parse_u16((mmb, pos))?;
@@ -958,6 +1074,12 @@ str_list_wrapper! {
 
   /// A handle to an list of hypothesis names in the index.
   HypListRef("h{}");
+
+  /// A handle to an list of attributes of a declaration in the index.
+  AttrListRef("attr{}");
+
+  /// A handle to the list of source file paths in the index.
+  FileListRef("file{}");
 }
 
 impl<'a, X> MmbFile<'a, X> {
@@ -1038,6 +1160,115 @@ impl<'a, X: HasHypNames<'a>> MmbFile<'a, X> {
   }
 }
 
+/// The source location of a declaration, from the `"Span"` index table.
+#[derive(Debug, Clone)]
+pub struct DeclSpanRef<'a> {
+  /// The path of the source file, if known.
+  pub file: Option<&'a str>,
+  /// The byte range of the whole declaration in the source file.
+  pub full: Range<usize>,
+  /// The byte range of the name of the declaration in the source file.
+  pub name: Range<usize>,
+}
+
+/// The source of a step of a proof, from the `"SrcM"` index table.
+#[derive(Debug, Clone)]
+pub struct SourceMapRef<'a> {
+  /// The position in the file of the first proof command of the step.
+  pub pos: usize,
+  /// The path of the source file, if known.
+  pub file: Option<&'a str>,
+  /// The byte range of the expression that produced the step in the source file.
+  pub span: Range<usize>,
+}
+
+#[inline]
+fn span_range([start, end]: [U32<LE>; 2]) -> Range<usize> {
+  u32_as_usize(start.get())..u32_as_usize(end.get())
+}
+
+impl<'a, X> MmbFile<'a, X> {
+  /// Get the C string at the given position in the file, or `None` if the pointer is 0
+  /// or the string is not valid.
+  fn index_str(&self, p: U64<LE>) -> Option<&'a str> {
+    if p.get() == 0 { return None }
+    cstr_from_bytes_prefix(self.buf.get(u64_as_usize(p)..)?)?.0.to_str().ok()
+  }
+
+  /// The index of a statement in the list of all sorts, terms and theorems, in that order.
+  fn decl_index(&self, stmt: NumdStmtCmd) -> usize {
+    use crate::NumdStmtCmd::{Axiom, Sort, TermDef, Thm};
+    match stmt {
+      Sort { sort_id } => usize::from(sort_id.0),
+      TermDef { term_id, .. } => self.sorts.len() + u32_as_usize(term_id.0),
+      Axiom { thm_id } | Thm { thm_id, .. } =>
+        self.sorts.len() + self.terms.len() + u32_as_usize(thm_id.0),
+    }
+  }
+}
+
+impl<'a, X: HasSourceInfo<'a>> MmbFile<'a, X> {
+  /// Get the list of source files that contributed to this file.
+  #[must_use]
+  pub fn source_files(&self) -> FileListRef<'a> {
+    self.index.get_source_info().and_then(|si| self.str_list_ref(si.files?))
+      .map_or_else(|| FileListRef::new(self.buf), FileListRef)
+  }
+
+  /// Get the source location of a statement, if present.
+  #[must_use]
+  pub fn stmt_span(&self, stmt: NumdStmtCmd) -> Option<DeclSpanRef<'a>> {
+    let sp = self.index.get_source_info()?.spans?.get(self.decl_index(stmt))?;
+    Some(DeclSpanRef {
+      file: self.index_str(sp.p_file),
+      full: span_range(sp.full),
+      name: span_range(sp.name),
+    })
+  }
+
+  /// Get the doc comment of a statement, if present.
+  #[must_use]
+  pub fn stmt_doc(&self, stmt: NumdStmtCmd) -> Option<&'a str> {
+    self.index_str(*self.index.get_source_info()?.docs?.get(self.decl_index(stmt))?)
+  }
+
+  /// Get the attribute list of a statement, or `None` if the index does not exist.
+  #[must_use]
+  pub fn stmt_attrs_opt(&self, stmt: NumdStmtCmd) -> Option<AttrListRef<'a>> {
+    let p = *self.index.get_source_info()?.attrs?.get(self.decl_index(stmt))?;
+    if p.get() == 0 { return Some(AttrListRef::new(self.buf)) }
+    Some(AttrListRef(self.str_list_ref(p)?))
+  }
+
+  /// Get the attribute list of a statement.
+  #[must_use]
+  pub fn stmt_attrs(&self, stmt: NumdStmtCmd) -> AttrListRef<'a> {
+    self.stmt_attrs_opt(stmt).unwrap_or_else(|| AttrListRef::new(self.buf))
+  }
+
+  /// Get the source map of a theorem, that is, the list of proof steps in the proof stream
+  /// for which the source of the step is known, or `None` if the index does not exist.
+  #[must_use]
+  pub fn thm_source_map_opt(&self, n: ThmId) -> Option<&'a [SourceMapEntry]> {
+    let p = *self.index.get_source_info()?.src_maps?.get(u32_as_usize(n.0))?;
+    if p.get() == 0 { return Some(&[]) }
+    let (len, rest) = LayoutVerified::<_, U64<LE>>::new_unaligned_from_prefix(
+      &*self.buf.get(u64_as_usize(p)..)?,
+    )?;
+    Some(new_slice_prefix(rest, len.get().try_into().ok()?)?.0)
+  }
+
+  /// Get the source map of a theorem, that is, the list of proof steps in the proof stream
+  /// for which the source of the step is known.
+  pub fn thm_source_map(&self, n: ThmId) -> impl Iterator<Item = SourceMapRef<'a>> + '_ {
+    self.thm_source_map_opt(n).unwrap_or(&[]).iter().map(move |e| SourceMapRef {
+      pos: u64_as_usize(e.p_proof),
+      file: self.index_str(e.p_file),
+      span: span_range(e.span),
+    })
+  }
+}
+
 impl<'a> TermRef<'a> {
   /// Returns true if this is a `def`, false for a `term`.
   #[inline]
@@ -62,9 +62,7 @@ use ast::{
   Atom, Binder, Const, Decl, DeclKind, Delimiter, DepType, Formula, GenNota, Literal, LocalKind,
   SExpr, SExprKind, SimpleNota, SimpleNotaKind, Stmt, StmtKind, Type,
 };
-use mm0_util::{
-  let_unchecked, unwrap_unchecked, BoxError, LinedString, Modifiers, Position, Prec, Span,
-};
+use mm0_util::{BoxError, LinedString, Modifiers, Position, Prec, Span};
 use num::cast::ToPrimitive;
 use num::BigUint;
 use std::mem;
@@ -550,7 +548,7 @@ impl<'a> Parser<'a> {
   /// Returns the span of the string literal (including the quotes), and the parsed string,
   /// and returns a failure if the string is not well formed or if there is no
   /// string at the current position.
-  fn string(&mut self) -> Result<(Span, Vec<u8>)> {
+  pub fn string(&mut self) -> Result<(Span, Vec<u8>)> {
     let start = self.idx;
     if self.cur_opt() != Some(b'\"') {
       return self.err_str("expected an string literal")
@@ -770,7 +768,7 @@ impl<'a> Parser<'a> {
         }
       }
       Some(b'$') => {
-        let f = unwrap_unchecked!(self.formula()?);
+        let f = self.formula()?.expect("checked for '$'");
         Ok(SExpr { span: f.0, k: SExprKind::Formula(f) })
       }
       Some(c) if (b'0'..=b'9').contains(&c) => {
@@ -1149,8 +1147,10 @@ impl<'a> Parser<'a> {
 /// new file differs from the old one.
 #[must_use]
 pub fn parse(file: Arc<LinedString>, old: Option<(Position, Arc<Ast>)>) -> (usize, Ast) {
+  // If the position is out of range, fall back to parsing the whole file.
+  let old = old.and_then(|(pos, ast)| Some((file.to_idx(pos)?, ast)));
   let (errors, imports, idx, mut stmts) = if let Some((pos, ast)) = old {
-    let (ix, start) = ast.last_checkpoint(file.to_idx(pos).expect("bad line position"));
+    let (ix, start) = ast.last_checkpoint(pos);
     match Arc::try_unwrap(ast) {
       Ok(mut ast) => {
         ast.errors.retain(|e| e.pos.start < start);
@@ -1175,3 +1175,34 @@ pub fn parse(file: Arc<LinedString>, old: Option<(Position, Arc<Ast>)>) -> (usiz
   }
   (0, Ast { errors: p.errors, imports: p.imports, source: file, stmts })
 }
+
+/// Parse a sequence of lisp expressions, like the contents of a `do` block. This is used for
+/// evaluating lisp code that does not come from a file, such as a query from an external tool.
+#[must_use]
+pub fn parse_sexprs(source: &[u8]) -> (Vec<SExpr>, Vec<ParseError>) {
+  let mut p = Parser { source, errors: vec![], imports: vec![], idx: 0, restart_pos: None };
+  let mut es = vec![];
+  p.ws();
+  while p.idx < p.source.len() {
+    match p.sexpr() {
+      Ok(e) => es.push(e),
+      Err(e) => { p.errors.push(e); break }
+    }
+    p.ws();
+  }
+  (es, p.errors)
+}
+
+/// Like [`parse`], but guaranteed not to panic, even on arbitrary input. If the parser
+/// panics (which is a bug), the panic is caught and the result is an [`Ast`] with no
+/// statements and a single error at the start of the file. This is the entry point
+/// for fuzzing, and is used by the server so that malformed input cannot bring it down.
+#[must_use]
+pub fn parse_no_panic(file: Arc<LinedString>, old: Option<(Position, Arc<Ast>)>) -> (usize, Ast) {
+  let file2 = file.clone();
+  std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| parse(file2, old)))
+    .unwrap_or_else(|e| {
+      let msg = format!("internal error: parser panicked: {}", mm0_util::panic_message(&*e));
+      (0, Ast { errors: vec![ParseError::new(0..0, msg.into())], source: file, ..Ast::default() })
+    })
+}
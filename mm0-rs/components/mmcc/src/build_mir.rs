@@ -1596,6 +1596,11 @@ impl<'a, 'n> BuildMir<'a, 'n> {
 }
 
 impl Initializer {
+  /// The global variables that are initialized by this initializer.
+  pub(crate) fn globals(&self) -> impl Iterator<Item=Symbol> + '_ {
+    self.globals.iter().map(|g| g.0)
+  }
+
   /// Append the call to `main` at the end of the `start` routine.
   pub(crate) fn finish(&mut self,
     mir: &HashMap<Symbol, Proc>, main: Option<Symbol>
@@ -1222,6 +1222,21 @@ impl<'a> MVars<'a> {
   }
 }
 
+/// An occurrence of a variable in the source, either at its binder or at a use,
+/// which is reported to [`ItemContext::emit_var_occurrences`](crate::ItemContext::emit_var_occurrences).
+#[derive(Debug)]
+pub struct VarOccurrence<'a> {
+  /// The span of the occurrence.
+  pub span: &'a FileSpan,
+  /// The variable.
+  pub var: VarId,
+  /// The span of the binder of the variable, if it was bound in this item.
+  pub binder: Option<&'a FileSpan>,
+  /// The type of the variable at this program point. This reflects the ownership state
+  /// of the variable: if it has been moved out of, the type is `|T|`.
+  pub ty: Ty<'a>,
+}
+
 /// The main inference context for the type inference pass.
 #[derive(Debug)]
 pub struct InferCtx<'a, 'n> {
@@ -1252,6 +1267,11 @@ pub struct InferCtx<'a, 'n> {
   /// as well as waiting for all variables to be as unified as possible so that
   /// the error messages are as precise as possible.
   pub errors: Vec<hir::Spanned<'a, TypeError<'a>>>,
+  /// The binders of the variables in the current item.
+  binders: HashMap<VarId, &'a FileSpan>,
+  /// The occurrences of variables in the current item, with their types at that point.
+  /// Like the errors, these are printed at the end so that the types are as unified as possible.
+  pub occurrences: Vec<VarOccurrence<'a>>,
 }
 
 /// A relation between types, used as an argument to [`InferCtx::relate_ty`].
@@ -1580,6 +1600,8 @@ impl<'a, 'n> InferCtx<'a, 'n> {
       labels: HashMap::new(),
       returns: None,
       errors: vec![],
+      binders: HashMap::new(),
+      occurrences: vec![],
     }
   }
 
@@ -1601,6 +1623,11 @@ impl<'a, 'n> InferCtx<'a, 'n> {
 
   fn fresh_var(&mut self, name: Symbol) -> VarId { self.var_names.push(name) }
 
+  fn record_var(&mut self, span: &'a FileSpan, var: VarId, ty: Ty<'a>) {
+    let binder = self.binders.get(&var).copied();
+    self.occurrences.push(VarOccurrence {span, var, binder, ty})
+  }
+
   fn new_ty_mvar(&mut self, span: &'a FileSpan) -> Ty<'a> {
     let n = self.mvars.ty.new_mvar(span, self.dc.context);
     intern!(self, TyKind::Infer(n))
@@ -2355,6 +2382,8 @@ impl<'a, 'n> InferCtx<'a, 'n> {
         let ty = expect_t.unwrap_or_else(|| self.new_ty_mvar(span));
         let ctx = self.new_context_next(v, expect_e, ty);
         self.dc.context = ctx.into();
+        self.binders.insert(v, span);
+        self.record_var(span, v, ty);
         (UnelabTupPat {span, k: UnelabTupPatKind::Name(g, n, ctx)},
          Some(intern!(self, ExprKind::Var(v))))
       }
@@ -3089,6 +3118,7 @@ impl<'a, 'n> InferCtx<'a, 'n> {
 
       &ast::ExprKind::Var(v) => {
         let (gen, val, ty) = self.dc.get_var(v);
+        self.record_var(span, v, ty);
         ret![Var(v, gen), Ok(val), ty]
       }
 
@@ -4054,6 +4084,7 @@ impl<'a, 'n> InferCtx<'a, 'n> {
     match k {
       &ast::ExprKind::Var(v) => {
         let (_, _, ty) = self.dc.get_var(v);
+        self.record_var(span, v, ty);
         ret![Var(v), Ok(intern!(self, PlaceKind::Var(v))), ty]
       }
 
@@ -120,7 +120,7 @@ pub mod proof;
 use std::collections::HashMap;
 use types::{entity::Entity, mir};
 use bumpalo::Bump;
-use infer::TypeError;
+use infer::{TypeError, VarOccurrence};
 #[cfg(feature = "memory")] use mm0_deepsize_derive::DeepSizeOf;
 
 pub use mm0_util::*;
@@ -156,6 +156,13 @@ pub trait ItemContext<C: Config + ?Sized> {
     _errs: Vec<hir::Spanned<'a, TypeError<'a>>>,
     _print: &impl DisplayCtx<'a>,
   ) -> Result<bool, C::Error> { Ok(false) }
+
+  /// This function is called after typechecking with the occurrences of variables in the item,
+  /// and their types at each occurrence, for example to provide hover information.
+  fn emit_var_occurrences<'a>(&mut self, _ctx: &mut C,
+    _vars: Vec<VarOccurrence<'a>>,
+    _print: &impl DisplayCtx<'a>,
+  ) {}
 }
 
 impl<C: Config> ItemContext<C> for () {
@@ -190,6 +197,67 @@ pub trait PrintLambda {
 
 impl PrintLambda for () {}
 
+bitflags! {
+  /// The intermediate representations to print, and the checks to run between passes, when
+  /// debugging a compilation failure. The output is collected in [`Dump`].
+  #[derive(Default)]
+  pub struct DumpFlags: u8 {
+    /// Print the MIR of each procedure after type checking.
+    const MIR = 1 << 0;
+    /// Print the MIR of each procedure after optimization.
+    const MIR_OPT = 1 << 1;
+    /// Print the machine code of each procedure after register allocation.
+    const VCODE = 1 << 2;
+    /// Check the consistency of the MIR after each pass, and of the final ELF proof object.
+    const CHECK = 1 << 3;
+  }
+}
+#[cfg(feature = "memory")] mm0_deepsize::deep_size_0!(DumpFlags);
+
+impl DumpFlags {
+  /// Parse a comma separated list of `mir`, `mir-opt`, `vcode`, `check` or `all`.
+  pub fn parse(s: &str) -> Result<Self, String> {
+    s.split(',').map(str::trim).try_fold(Self::empty(), |flags, k| Ok(flags | match k {
+      "mir" => Self::MIR,
+      "mir-opt" => Self::MIR_OPT,
+      "vcode" => Self::VCODE,
+      "check" => Self::CHECK,
+      "all" => Self::all(),
+      _ => return Err(format!("unknown pass '{}', expected mir, mir-opt, vcode, check or all", k)),
+    }))
+  }
+}
+
+/// The debugging output of the compiler, according to the [`DumpFlags`].
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "memory", derive(DeepSizeOf))]
+pub struct Dump {
+  /// The representations to print and checks to run.
+  pub flags: DumpFlags,
+  /// The printed representations.
+  out: String,
+  /// The failed consistency checks.
+  errors: Vec<String>,
+}
+
+impl Dump {
+  fn mir(&mut self, flag: DumpFlags, pass: &str, name: impl std::fmt::Display, cfg: &mir::Cfg) {
+    use std::fmt::Write;
+    if self.flags.contains(flag) {
+      writeln!(self.out, "{} ({}):\n{:?}", name, pass, cfg).expect("impossible")
+    }
+    if self.flags.contains(DumpFlags::CHECK) {
+      if let Err(e) = cfg.check() { self.errors.push(format!("{} ({}): {}", name, pass, e)) }
+    }
+  }
+
+  /// Take the output so far, as a pair of the printed representations and the failed
+  /// consistency checks.
+  pub fn take(&mut self) -> (String, Vec<String>) {
+    (std::mem::take(&mut self.out), std::mem::take(&mut self.errors))
+  }
+}
+
 /// The MMC compiler, which contains local state for the functions that have been
 /// loaded and typechecked thus far.
 #[derive(Clone, Debug)]
@@ -208,6 +276,8 @@ pub struct Compiler<C> {
   main: Option<Symbol>,
   /// If true, some items have not been generated correctly, so compilation cannot proceed.
   has_type_errors: bool,
+  /// The debugging output, see [`DumpFlags`].
+  pub dump: Dump,
 }
 
 impl<C: Default> Default for Compiler<C> {
@@ -223,6 +293,7 @@ impl<C> Compiler<C> {
       init: Default::default(),
       main: None,
       has_type_errors: false,
+      dump: Dump::default(),
       config,
     })
   }
@@ -236,7 +307,7 @@ impl<C: Config> Compiler<C> {
   pub fn add(&mut self, item: &ast::Item, var_names: IdxVec<VarId, Symbol>,
     mut ic: impl ItemContext<C>
   ) -> Result<(), C::Error> {
-    let Compiler {names, mir, init, main, has_type_errors, ..} = self;
+    let Compiler {names, mir, init, main, has_type_errors, dump, ..} = self;
     let hir_alloc = Bump::new();
     let mut ctx = infer::InferCtx::new(&hir_alloc, names, var_names);
     if let ast::ItemKind::Proc {kind: ast::ProcKind::Main, ref name, ..} = item.k {
@@ -247,6 +318,11 @@ impl<C: Config> Compiler<C> {
       }
     }
     let item = ctx.lower_item(item);
+    if !ctx.occurrences.is_empty() {
+      let vars = std::mem::take(&mut ctx.occurrences);
+      let pr = ctx.print(&mut ic);
+      ic.emit_var_occurrences(&mut self.config, vars, &pr);
+    }
     if !ctx.errors.is_empty() {
       let errs = std::mem::take(&mut ctx.errors);
       let pr = ctx.print(&mut ic);
@@ -257,7 +333,10 @@ impl<C: Config> Compiler<C> {
     }
     if let Some(item) = item {
       if let Some(n) = build_mir::BuildMir::new(Some(&mut ctx.mvars)).build_item(mir, init, item) {
-        mir.get_mut(&n).expect("missing").optimize(names);
+        let proc = mir.get_mut(&n).expect("missing");
+        dump.mir(DumpFlags::MIR, "after type checking", n, &proc.body);
+        proc.optimize(names);
+        dump.mir(DumpFlags::MIR_OPT, "after optimization", n, &proc.body);
       }
     }
     Ok(())
@@ -266,6 +345,40 @@ impl<C: Config> Compiler<C> {
   /// If true, then `finish` will panic.
   pub fn has_type_errors(&self) -> bool { self.has_type_errors }
 
+  /// Add the items of `other`, a compiler that was given items separately (for example in
+  /// another file), to this one, so that they can be linked into one program by
+  /// [`finish`](Self::finish). Items that were declared at the same place in both compilers,
+  /// because both were extended from a common one, are shared. Fails without changing
+  /// anything if the compilers declare different items with the same name, or if `other`
+  /// has global variables of its own, because their initializers cannot be combined.
+  pub fn link(&mut self, other: &Self) -> Result<(), Vec<String>> {
+    let mut errs = vec![];
+    let mut new = vec![];
+    for (&name, e) in &other.names {
+      match self.names.get(&name) {
+        None if matches!(e, Entity::Global(_)) || other.init.globals().any(|g| g == name) =>
+          errs.push(format!("'{}' is a global variable of a separate compilation, \
+            and global initializers cannot be linked", name)),
+        None => new.push(name),
+        Some(e2) if e2.span() == e.span() => {}
+        Some(_) => errs.push(format!("'{}' is declared differently in the linked compilers", name)),
+      }
+    }
+    match (self.main, other.main) {
+      (Some(a), Some(b)) if a != b =>
+        errs.push(format!("both compilers have a main procedure, '{}' and '{}'", a, b)),
+      _ => {}
+    }
+    if !errs.is_empty() { return Err(errs) }
+    for name in new {
+      self.names.insert(name, other.names[&name].clone());
+      if let Some(proc) = other.mir.get(&name) { self.mir.insert(name, proc.clone()); }
+    }
+    if self.main.is_none() { self.main = other.main }
+    self.has_type_errors |= other.has_type_errors;
+    Ok(())
+  }
+
   /// Reset the compiler to the initial state.
   pub fn clear(&mut self) {
     self.names = symbol::Interner::with(Self::make_names);
@@ -286,9 +399,14 @@ impl<C: Config> Compiler<C> {
     assert!(!self.has_type_errors);
     // eprintln!("{:#?}", mir);
     let (mut init, globals) = std::mem::take(&mut self.init).finish(&mir, self.main.take());
+    self.dump.mir(DumpFlags::MIR, "after type checking", "start", &init);
     init.optimize(&[]);
+    self.dump.mir(DumpFlags::MIR_OPT, "after optimization", "start", &init);
     let allocs = init.storage(&names);
-    LinkedCode::link(&names, mir, init, &allocs, &globals)
+    let code = LinkedCode::link(&names, mir, init, &allocs, &globals);
+    if self.dump.flags.contains(DumpFlags::VCODE) { code.dump_vcode(&mut self.dump.out) }
+    if self.dump.flags.contains(DumpFlags::CHECK) { code.proof().validate() }
+    code
   }
 }
 
@@ -232,6 +232,16 @@ pub struct LinkedCode {
 }
 
 impl LinkedCode {
+  /// Print the machine code of each procedure after register allocation, for debugging.
+  pub(crate) fn dump_vcode(&self, out: &mut String) {
+    use std::fmt::Write;
+    writeln!(out, "start (after register allocation):\n{:#?}", self.init.1).expect("impossible");
+    for (f, (_, code)) in self.funcs.enum_iter() {
+      writeln!(out, "{} (after register allocation):\n{:#?}", self.func_names[f], code)
+        .expect("impossible");
+    }
+  }
+
   pub(crate) fn link(
     names: &HashMap<Symbol, Entity>,
     mir: HashMap<Symbol, Proc>,
@@ -946,6 +946,25 @@ impl Cfg {
   pub fn blocks_mut(&mut self) -> impl Iterator<Item=(BlockId, &mut BasicBlock)> {
     self.blocks.enum_iter_mut().filter(|(_, bl)| !bl.is_dead())
   }
+
+  /// Check the structural invariants that should hold between passes: the entry block is
+  /// live, every live block is finished, and jumps only go to live blocks.
+  pub fn check(&self) -> Result<(), String> {
+    if self.blocks.get(BlockId::ENTRY).map_or(true, BasicBlock::is_dead) {
+      return Err("the entry block is missing".into())
+    }
+    for (id, bl) in self.blocks() {
+      let term = bl.term.as_ref().ok_or_else(|| format!("{:?} is unfinished", id))?;
+      for (_, tgt) in Successors::new(term) {
+        match self.blocks.get(tgt) {
+          None => return Err(format!("{:?} jumps to nonexistent block {:?}", id, tgt)),
+          Some(bl2) if bl2.is_dead() => return Err(format!("{:?} jumps to dead block {:?}", id, tgt)),
+          Some(_) => {}
+        }
+      }
+    }
+    Ok(())
+  }
 }
 
 impl Index<CtxBufId> for Cfg {
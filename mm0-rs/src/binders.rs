@@ -0,0 +1,104 @@
+//! Binder inference reporting.
+//!
+//! In MM1, the binder list of a declaration does not have to be complete: variables can be
+//! left untyped, in which case the elaborator infers whether they are bound or regular and
+//! what they depend on, and variables that appear only in the statement are added
+//! automatically. This module compares the written binders of each declaration against
+//! the elaborated ones, and produces the fully explicit binder list. It is used by the
+//! `--show-binders` option of `mm0-rs compile`, and by the language server, which shows
+//! the elaborated binders when hovering over the name of a declaration and provides a
+//! code action that rewrites a declaration with all binders explicit.
+use std::fmt::Write;
+use std::sync::Arc;
+use mm1_parser::{ast::{Binder, Decl, LocalKind, Stmt, StmtKind, Type as AType}, parse};
+use crate::{AtomId, DeclKey, FileRef, FrozenEnv, LinedString, Span, Type};
+
+/// A declaration whose binders were (partially) inferred.
+#[derive(Clone, Debug)]
+pub struct InferredBinders {
+  /// The span of the declaration name.
+  pub decl: Span,
+  /// The span of the binder list in the source, which is empty if there are no binders.
+  pub range: Span,
+  /// The elaborated variable binders, like `{x: nat} (ph: wff x)`.
+  pub binders: String,
+  /// The replacement text for `range`, which contains the elaborated variable binders
+  /// followed by the remaining (dummy and hypothesis) binders from the source.
+  pub text: String,
+}
+
+fn is_var(bi: &Binder) -> bool {
+  bi.kind != LocalKind::Dummy && !matches!(bi.ty, Some(AType::Formula(_)))
+}
+
+/// Print a list of elaborated binders.
+fn print_binders(env: &FrozenEnv, args: &[(Option<AtomId>, Type)]) -> String {
+  let name = |a: Option<AtomId>| a.map_or("_", |a| env.data()[a].name().as_str());
+  let mut out = String::new();
+  let mut bvars = vec![];
+  for &(a, ty) in args {
+    if !out.is_empty() { out.push(' ') }
+    match ty {
+      Type::Bound(s) => {
+        write!(out, "{{{}: {}}}", name(a), env.sort(s).name).expect("writing to a string");
+        bvars.push(a)
+      }
+      Type::Reg(s, deps) => {
+        write!(out, "({}: {}", name(a), env.sort(s).name).expect("writing to a string");
+        for (i, &x) in bvars.iter().enumerate() {
+          if deps & (1 << i) != 0 { write!(out, " {}", name(x)).expect("writing to a string") }
+        }
+        out.push(')')
+      }
+    }
+  }
+  out
+}
+
+fn analyze_decl(path: &FileRef, text: &LinedString, env: &FrozenEnv, d: &Decl) -> Option<InferredBinders> {
+  let ad = &env.data()[env.get_atom(&text[d.id])?];
+  let (fsp, args) = match ad.decl()? {
+    DeclKey::Term(t) => { let td = env.term(t); (&td.span, &td.args) }
+    DeclKey::Thm(t) => { let td = env.thm(t); (&td.span, &td.args) }
+  };
+  if fsp.file != *path || fsp.span != d.id { return None }
+  let vars = d.bis.iter().filter(|bi| is_var(bi)).count();
+  if vars >= args.len() && d.bis.iter().all(|bi| !is_var(bi) || bi.ty.is_some()) { return None }
+  let binders = print_binders(env, args);
+  // Copy the remaining binders from the source, one group at a time.
+  // We give up if a binder group mixes variables with other binders.
+  let mut text2 = binders.clone();
+  let mut last = None;
+  for bi in &d.bis {
+    if last == Some(bi.span) { continue }
+    last = Some(bi.span);
+    let group = d.bis.iter().filter(|bi2| bi2.span == bi.span);
+    if group.clone().all(|bi| !is_var(bi)) {
+      if !text2.is_empty() { text2.push(' ') }
+      text2.push_str(&String::from_utf8_lossy(&text[bi.span]));
+    } else if group.clone().any(|bi| !is_var(bi)) {
+      return None
+    }
+  }
+  let (range, text2) = match (d.bis.first(), d.bis.last()) {
+    (Some(first), Some(last)) => ((first.span.start..last.span.end).into(), text2),
+    _ => ((d.id.end..d.id.end).into(), format!(" {}", text2)),
+  };
+  Some(InferredBinders {decl: d.id, range, binders, text: text2})
+}
+
+/// Find the declarations in file `path` (with contents `text`, elaborated to `env`)
+/// that have inferred binders.
+#[must_use] pub fn analyze(path: &FileRef, text: &Arc<LinedString>, env: &FrozenEnv) -> Vec<InferredBinders> {
+  fn go(path: &FileRef, text: &LinedString, env: &FrozenEnv, s: &Stmt, out: &mut Vec<InferredBinders>) {
+    match &s.k {
+      StmtKind::Decl(d) => out.extend(analyze_decl(path, text, env, d)),
+      StmtKind::Annot(_, s) | StmtKind::DocComment(_, s) => go(path, text, env, s, out),
+      _ => {}
+    }
+  }
+  let (_, ast) = parse(text.clone(), None);
+  let mut out = vec![];
+  for s in &ast.stmts { go(path, text, env, s, &mut out) }
+  out
+}
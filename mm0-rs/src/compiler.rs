@@ -9,8 +9,9 @@
 //! [`mm0_rs::server`]: crate::server
 //! [`mm0-c`]: https://github.com/digama0/mm0/tree/master/mm0-c
 use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
-use std::collections::{HashMap, hash_map::Entry};
+use std::collections::{HashMap, HashSet, hash_map::Entry};
 use std::{io, fs};
+use std::path::{Path, PathBuf};
 use futures::{FutureExt, future::BoxFuture};
 use futures::channel::oneshot::{Sender as FSender, channel};
 use futures::executor::{ThreadPool, block_on};
@@ -20,9 +21,13 @@ use annotate_snippets::{
   display_list::{DisplayList, FormatOptions}};
 use typed_arena::Arena;
 use clap::ArgMatches;
-use mm1_parser::{parse, ErrorLevel, ParseError};
-use crate::elab::{ElabError, ElabErrorKind, ElabResult, ElaborateBuilder};
-use crate::{ArcList, FileRef, FileSpan, FrozenEnv, LinedString, MutexExt, Position, Range, Span};
+use mm1_parser::{ast::{Ast, Stmt, StmtKind}, parse_no_panic, ErrorLevel};
+use crate::elab::{ElabError, ElabErrorKind, ElabResult, ElaborateBuilder, StmtListener,
+  WarningCategory, persist, verify::verify_thm};
+use crate::explain::ErrorCode;
+use crate::imports::{ImportCycle, ImportGraph};
+use crate::{DeclKey, Environment, FileRef, FileSpan, FrozenEnv, LinedString, MutexExt, ObjectKind,
+  Position, Range, Span, StmtTrace, ThmKind};
 use crate::mmb::import::elab as mmb_elab;
 use crate::mmu::import::elab as mmu_elab;
 use crate::mmb::export::Exporter as MmbExporter;
@@ -33,10 +38,88 @@ lazy_static! {
   /// The virtual file system of files that have been included via
   /// transitive imports, protected for concurrent access by a mutex.
   static ref VFS: Vfs = Vfs(Mutex::new(HashMap::new()));
+  /// The imports of the files that have been parsed, used to detect import cycles.
+  static ref IMPORTS: Mutex<ImportGraph> = Default::default();
+  /// A lock held while printing diagnostics, messages or the `--progress` status line, so
+  /// that the output of files elaborated in parallel is not interleaved.
+  static ref OUTPUT: Mutex<()> = Mutex::new(());
 }
 
 static QUIET: AtomicBool = AtomicBool::new(false);
 
+/// What [`elaborate`] prints while elaborating a file and its imports.
+#[derive(Clone, Copy, Debug)]
+struct Report {
+  /// Print progress messages like `elab foo.mm1`.
+  log: bool,
+  /// Print the diagnostics of each file after it is elaborated.
+  diagnostics: bool,
+}
+
+impl Report {
+  /// The messages of the command line tools, which print everything except the progress
+  /// messages suppressed by `--quiet`.
+  fn cli() -> Self { Report { log: !QUIET.load(Ordering::Relaxed), diagnostics: true } }
+
+  /// Print nothing. This is used by `mm0-rs test`, which collects the diagnostics instead.
+  const SILENT: Self = Report { log: false, diagnostics: false };
+}
+
+/// Whether to show the statement being elaborated in each file, set by the `--progress` option
+/// if stderr is a terminal.
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Whether to use colors in diagnostics, set by the `--color` option.
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+fn use_color() -> bool { COLOR.load(Ordering::Relaxed) }
+
+/// Set whether to use colors in diagnostics, from the value of the `--color` option:
+/// `always`, `never`, or `auto` (the default) to use colors if the output is a terminal
+/// and the `NO_COLOR` environment variable is not set.
+fn set_color(when: Option<&str>) {
+  use std::io::IsTerminal;
+  COLOR.store(match when {
+    Some("always") => true,
+    Some("never") => false,
+    _ => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+  }, Ordering::Relaxed)
+}
+
+/// Selects which proofs in imported `.mmb` files are replayed (re-verified) on import.
+/// By default (when [`struct@REPLAY`] is `None`) these proofs are trusted.
+#[derive(Debug)]
+enum ReplayMode {
+  /// Replay all imported proofs.
+  All,
+  /// Replay only the proofs of the given theorems.
+  Only(HashSet<String>),
+}
+
+lazy_static! {
+  /// The proof replay setting for `.mmb` imports, set by the `--replay` option.
+  static ref REPLAY: Mutex<Option<ReplayMode>> = Mutex::new(None);
+}
+
+/// Replay the proofs of the selected theorems of an imported `.mmb` file,
+/// pushing an error for each proof that fails to check.
+fn replay_mmb(path: &FileRef, env: &Environment, errors: &mut Vec<ElabError>, report: Report) {
+  let g = REPLAY.ulock();
+  let mode = if let Some(mode) = &*g {mode} else {return};
+  let mut n = 0;
+  for (t, td) in env.thms.enum_iter() {
+    if let ReplayMode::Only(thms) = mode {
+      if !thms.contains(env.data[td.atom].name.as_str()) {continue}
+    }
+    n += 1;
+    if let Err(e) = verify_thm(env, t) {
+      errors.push(ElabError::new_e(td.full, format!("replay of {} failed: {}",
+        env.data[td.atom].name, e)))
+    }
+  }
+  if report.log { log_msg(format!("replayed {} proofs in {}", n, path)) }
+}
+
 /// The cached [`Environment`](crate::elab::Environment) representing a
 /// completed parse, or an incomplete parse.
 #[derive(DeepSizeOf)]
@@ -51,8 +134,9 @@ enum FileCache {
   /// [`Sender`]: FSender
   /// [`Receiver`]: futures::channel::oneshot::Receiver
   InProgress(Vec<FSender<ElabResult<()>>>),
-  /// The file has been elaborated and the result is ready.
-  Ready(FrozenEnv),
+  /// The file has been elaborated and the result is ready, along with the errors
+  /// reported in the file.
+  Ready(Option<Arc<[ElabError]>>, FrozenEnv),
 }
 
 #[derive(DeepSizeOf, Clone)]
@@ -159,7 +243,9 @@ impl Vfs {
       Entry::Occupied(e) => Ok((e.key().clone(), e.get().clone())),
       Entry::Vacant(e) => {
         let path = e.key().clone();
-        let fc = if path.has_extension("mmb") {
+        let fc = if let Some(text) = crate::get_virtual_file(&path) {
+          FileContents::new(text.to_string())
+        } else if path.has_extension("mmb") {
           FileContents::new_bin_from_file(path.path())?
         } else {
           FileContents::new(fs::read_to_string(path.path())?)
@@ -171,41 +257,77 @@ impl Vfs {
   }
 }
 
-fn mk_to_range() -> impl FnMut(&FileSpan) -> Option<Range> {
+/// Returns a function for getting the source text of the file containing a span,
+/// or `None` for binary files. The texts are cached, to avoid locking the [`VFS`] for
+/// every lookup.
+pub(crate) fn mk_get_text() -> impl FnMut(&FileSpan) -> Option<Arc<LinedString>> {
   let mut srcs = HashMap::new();
-  move |fsp: &FileSpan| -> Option<Range> {
+  move |fsp: &FileSpan| -> Option<Arc<LinedString>> {
     srcs.entry(fsp.file.ptr())
       .or_insert_with(|| VFS.0.ulock().get(&fsp.file).unwrap().text.clone())
-      .try_ascii().map(|f| f.to_range(fsp.span))
+      .try_ascii().cloned()
+  }
+}
+
+/// Get the span of the lines of `file` containing `pos`, and the line/col range of `pos`.
+fn source_lines(file: &LinedString, pos: Span) -> (Span, Range) {
+  let range = file.to_range(pos);
+  let start = file.line_start(pos.start);
+  let end = file.to_idx(Position {line: range.end.line + 1, character: 0})
+    .unwrap_or_else(|| file.len());
+  ((start..end).into(), range)
+}
+
+/// Create a [`Slice`] showing the lines `lines` with source text `source`,
+/// with an annotation at `pos` (which is contained in `lines`, and has line/col range `range`).
+fn make_slice<'a>(origin: &'a str, source: &'a str, lines: Span, range: Range, pos: Span,
+    label: &'a str, annotation_type: AnnotationType) -> Slice<'a> {
+  Slice {
+    source,
+    line_start: range.start.line as usize + 1,
+    origin: Some(origin),
+    fold: range.end.line - range.start.line >= 5,
+    annotations: vec![SourceAnnotation {
+      range: (pos.start - lines.start, pos.end - lines.start),
+      label,
+      annotation_type,
+    }],
   }
 }
 
 impl ElabErrorKind {
-  /// Convert the payload of an elaboration error to the footer data
-  /// of a [`Snippet`].
+  /// Convert the related locations of an elaboration error to secondary slices and
+  /// footer lines of a [`Snippet`]. Each location is shown with an excerpt of its source,
+  /// so that the backtrace of an error in a lisp program reads as a call chain, innermost
+  /// call first. Locations in files without source text are put in the footer instead.
   ///
   /// # Parameters
   ///
   /// - `arena`: A temporary [`typed_arena::Arena`] for storing [`String`]s that are
   ///   allocated for the snippet
-  /// - `to_range`: a function for converting (index-based) spans to (line/col) ranges
-  pub fn to_footer<'a>(&self, arena: &'a Arena<String>,
-      mut to_range: impl FnMut(&FileSpan) -> Option<Range>) -> Vec<Annotation<'a>> {
-    match self {
-      ElabErrorKind::Boxed(_, Some(info)) =>
-        info.iter().map(|(fs, e)| Annotation {
-          id: None,
-          label: Some(arena.alloc({
-            if let Some(Range {start, ..}) = to_range(fs) {
-              format!("{}:{}:{}: {}", fs.file.rel(), start.line + 1, start.character + 1, e)
-            } else {
-              format!("{}:{:#x}: {}", fs.file.rel(), fs.span.start, e)
-            }
-          })),
-          annotation_type: AnnotationType::Note,
-        }).collect(),
-      _ => vec![]
+  /// - `get_text`: a function for getting the source text of the file containing a span
+  pub fn to_related<'a>(&'a self, arena: &'a Arena<String>,
+    mut get_text: impl FnMut(&FileSpan) -> Option<Arc<LinedString>>
+  ) -> (Vec<Slice<'a>>, Vec<Annotation<'a>>) {
+    let (mut slices, mut footer) = (vec![], vec![]);
+    if let ElabErrorKind::Boxed(_, Some(info)) = self {
+      for (fs, e) in info {
+        if let Some(text) = get_text(fs) {
+          let (lines, range) = source_lines(&text, fs.span);
+          let source = arena.alloc(String::from_utf8_lossy(&text[lines]).into_owned());
+          let label = arena.alloc(format!("{}", e));
+          slices.push(make_slice(fs.file.rel(), source, lines, range, fs.span,
+            label, AnnotationType::Note));
+        } else {
+          footer.push(Annotation {
+            id: None,
+            label: Some(arena.alloc(format!("{}:{:#x}: {}", fs.file.rel(), fs.span.start, e))),
+            annotation_type: AnnotationType::Note,
+          })
+        }
+      }
     }
+    (slices, footer)
   }
 }
 
@@ -218,34 +340,26 @@ impl ElabErrorKind {
 /// - `pos`: The position of the error
 /// - `msg`: The error message
 /// - `level`: The error level
-/// - `footer`: The snippet footer (calculated by [`ElabErrorKind::to_footer`])
-/// - `to_range`: a function for converting (index-based) spans to (line/col) ranges
+/// - `code`: The error code, if any
+/// - `related`: The slices and footer for the related locations
+///   (calculated by [`ElabErrorKind::to_related`])
 fn make_snippet<'a>(path: &'a FileRef, file: &'a LinedString, pos: Span,
-    msg: &'a str, level: ErrorLevel, footer: Vec<Annotation<'a>>) -> Snippet<'a> {
+    msg: &'a str, level: ErrorLevel, code: Option<ErrorCode>,
+    (related, footer): (Vec<Slice<'a>>, Vec<Annotation<'a>>)) -> Snippet<'a> {
   let annotation_type = level.to_annotation_type();
-  let Range {start, end} = file.to_range(pos);
-  let start2 = pos.start - start.character as usize;
-  let end2 = file.to_idx(Position {line: end.line + 1, character: 0})
-    .unwrap_or_else(|| file.len());
+  let (lines, range) = source_lines(file, pos);
+  let source = unsafe {std::str::from_utf8_unchecked(&file[lines])};
+  let mut slices = vec![make_slice(path.rel(), source, lines, range, pos, "", annotation_type)];
+  slices.extend(related);
   Snippet {
     title: Some(Annotation {
-      id: None,
+      id: code.map(ErrorCode::to_str),
       label: Some(msg),
       annotation_type,
     }),
-    slices: vec![Slice {
-      source: unsafe {std::str::from_utf8_unchecked(&file[(start2..end2).into()])},
-      line_start: start.line as usize + 1,
-      origin: Some(path.rel()),
-      fold: end.line - start.line >= 5,
-      annotations: vec![SourceAnnotation {
-        range: (pos.start - start2, pos.end - start2),
-        label: "",
-        annotation_type,
-      }],
-    }],
+    slices,
     footer,
-    opt: FormatOptions { color: true, anonymized_line_numbers: false, margin: None }
+    opt: FormatOptions { color: use_color(), anonymized_line_numbers: false, margin: None }
   }
 }
 
@@ -255,17 +369,18 @@ fn make_snippet<'a>(path: &'a FileRef, file: &'a LinedString, pos: Span,
 ///
 /// - `msg`: The error message
 /// - `level`: The error level
-fn make_snippet_no_source(msg: &str, level: ErrorLevel) -> Snippet<'_> {
+/// - `code`: The error code, if any
+fn make_snippet_no_source(msg: &str, level: ErrorLevel, code: Option<ErrorCode>) -> Snippet<'_> {
   let annotation_type = level.to_annotation_type();
   Snippet {
     title: Some(Annotation {
-      id: None,
+      id: code.map(ErrorCode::to_str),
       label: Some(msg),
       annotation_type,
     }),
     slices: vec![],
     footer: vec![],
-    opt: FormatOptions { color: true, anonymized_line_numbers: false, margin: None }
+    opt: FormatOptions { color: use_color(), anonymized_line_numbers: false, margin: None }
   }
 }
 
@@ -280,13 +395,14 @@ impl ElabError {
   ///
   /// - `path`: The file that sourced the error
   /// - `file`: The file contents
-  /// - `to_range`: a function for converting (index-based) spans to (line/col) ranges
+  /// - `get_text`: a function for getting the source text of the file containing a span
   /// - `f`: The function to pass the constructed snippet
   fn to_snippet<T>(&self, path: &FileRef, file: &LinedString,
-      to_range: impl FnMut(&FileSpan) -> Option<Range>,
+      get_text: impl FnMut(&FileSpan) -> Option<Arc<LinedString>>,
       f: impl for<'a> FnOnce(Snippet<'a>) -> T) -> T {
-    f(make_snippet(path, file, self.pos, &self.kind.msg(), self.level,
-      self.kind.to_footer(&Arena::new(), to_range)))
+    let arena = Arena::new();
+    f(make_snippet(path, file, self.pos, &self.kind.msg(), self.level, self.code,
+      self.kind.to_related(&arena, get_text)))
   }
 
   /// Create a [`Snippet`] from an error when the file source is not available
@@ -303,15 +419,51 @@ impl ElabError {
     } else {
       format!("{}:{:#x}-{:#x}: {}", path, span.start, span.end, self.kind.msg())
     };
-    f(make_snippet_no_source(&s, self.level))
+    f(make_snippet_no_source(&s, self.level, self.code))
   }
 }
 
-/// Create a [`Snippet`] from this error. See [`ElabError::to_snippet`] for information
-/// about the parameters.
-fn to_snippet<T>(err: &ParseError, path: &FileRef, file: &LinedString,
-  f: impl for<'a> FnOnce(Snippet<'a>) -> T) -> T {
-  f(make_snippet(path, file, err.pos, &format!("{}", err.msg), err.level, vec![]))
+/// Print the diagnostics `errors` in file `path` with contents `text`.
+fn print_errors(path: &FileRef, text: &FileContents, errors: &[ElabError]) {
+  fn print(s: Snippet<'_>) { println!("{}\n", DisplayList::from(s)) }
+  let mut get_text = mk_get_text();
+  if let FileContents::Ascii(text) = text {
+    for e in errors { e.to_snippet(path, text, &mut get_text, print) }
+  } else {
+    for e in errors { e.to_snippet_no_source(path, e.pos, print) }
+  }
+}
+
+/// The name of the declaration in statement `s`, if it declares something.
+fn stmt_name<'a>(ast: &'a Ast, s: &Stmt) -> Option<&'a [u8]> {
+  match &s.k {
+    StmtKind::Sort(id, _) => Some(ast.span(*id)),
+    StmtKind::Decl(d) => Some(ast.span(d.id)),
+    StmtKind::Annot(_, s) | StmtKind::DocComment(_, s) => stmt_name(ast, s),
+    _ => None,
+  }
+}
+
+/// A [`StmtListener`] that prints the diagnostics of the file `path` as soon as they are
+/// reported, instead of waiting until the whole file is done, so that a long build can
+/// be followed (and its errors fixed) while it is still running. With `--progress`, it
+/// also keeps a status line on stderr, with the number of statements done and the name
+/// of the one being elaborated.
+fn stmt_listener(path: FileRef, text: FileContents, ast: Arc<Ast>) -> StmtListener {
+  let progress = PROGRESS.load(Ordering::Relaxed);
+  StmtListener::new(move |done, errors| {
+    let _g = OUTPUT.ulock();
+    if progress && !errors.is_empty() { eprint!("\r\x1b[K") }
+    print_errors(&path, &text, errors);
+    if progress {
+      match ast.stmts.get(done) {
+        None => eprint!("\r\x1b[K"),
+        Some(s) => eprint!("\r\x1b[K[{}/{}] {}{}", done, ast.stmts.len(), path,
+          stmt_name(&ast, s).map_or_else(String::new,
+            |name| format!(": {}", String::from_utf8_lossy(name)))),
+      }
+    }
+  })
 }
 
 fn log_msg(#[allow(unused_mut)] mut s: String) {
@@ -323,9 +475,56 @@ fn log_msg(#[allow(unused_mut)] mut s: String) {
       write!(s, ", memory = {}M", n >> 20).expect("writing to a string");
     }
   }
+  let _g = OUTPUT.ulock();
+  if PROGRESS.load(Ordering::Relaxed) { eprint!("\r\x1b[K") }
   println!("{}", s)
 }
 
+/// The file next to the prelude `path` in which its compiled environment is kept
+/// (see [`set_prelude_snapshot`](crate::set_prelude_snapshot)), like `prelude.mm1.env`
+/// for `prelude.mm1`.
+fn prelude_snapshot_path(path: &FileRef) -> PathBuf {
+  let mut dest = path.path().clone().into_os_string();
+  dest.push(".env");
+  dest.into()
+}
+
+/// The hash of the contents of the file `path`.
+fn file_hash(path: &Path) -> Option<String> {
+  let (_, file) = VFS.get_or_insert(path.to_owned().into()).ok()?;
+  Some(crate::content_hash(&file.text))
+}
+
+/// Load the compiled environment of the prelude `path`, if it was saved from the current
+/// contents of the prelude and the files it imports, with the current settings.
+/// Returns the environment and the direct imports of the prelude.
+fn load_prelude_snapshot(path: &FileRef) -> Option<(FrozenEnv, Vec<FileRef>)> {
+  let data = fs::read(prelude_snapshot_path(path)).ok()?;
+  let header = persist::load_header(&*data).ok()?;
+  if header.files.first().map(|(p, _)| p) != Some(path.path()) ||
+    !header.is_current(|p| file_hash(p)) { return None }
+  let (header, env) = persist::load(&data).ok()?;
+  Some((FrozenEnv::new(env), header.deps.into_iter().map(FileRef::from).collect()))
+}
+
+/// Save the compiled environment `env` of the prelude `path`, which imports `deps`,
+/// so that the next run can use [`load_prelude_snapshot`] instead of elaborating it.
+fn save_prelude_snapshot(path: &FileRef, deps: &[FileRef], env: &FrozenEnv) -> io::Result<()> {
+  let mut closure = crate::imports::import_closure(path);
+  closure.remove(path);
+  let mut files = vec![];
+  for p in std::iter::once(path).chain(&closure) {
+    let hash = file_hash(p.path()).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+      format!("could not read {}", p)))?;
+    files.push((p.path().clone(), hash));
+  }
+  let header = persist::Header::new(deps.iter().map(|p| p.path().clone()).collect(), files);
+  let dest = prelude_snapshot_path(path);
+  let tmp = dest.with_extension("env.tmp");
+  persist::save(io::BufWriter::new(fs::File::create(&tmp)?), &header, env)?;
+  fs::rename(&tmp, &dest)
+}
+
 /// Elaborate a file for an [`Environment`](crate::elab::Environment) result.
 ///
 /// This is the main elaboration function, as an `async fn`. Given a `path`,
@@ -341,10 +540,12 @@ fn log_msg(#[allow(unused_mut)] mut s: String) {
 /// The callback passed to [`elab::elaborate`], called on the imports in the file,
 /// will allocate a new [`elaborate_and_send`] task to the task pool [`struct@POOL`],
 /// which will later be joined when the result is required.
-/// (**Note**: This can result in deadlock if the import graph has a cycle.)
+/// Before that, the imports of the file are added to the [`struct@IMPORTS`] graph, and an
+/// import that would close a cycle fails immediately with [`ElabResult::ImportCycle`]
+/// instead of waiting on a file that is waiting on this one.
 ///
 /// [`Ast`]: crate::parser::Ast
-async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult<()>> {
+async fn elaborate(path: FileRef, report: Report) -> io::Result<ElabResult<()>> {
   let (path, file) = VFS.get_or_insert(path)?;
   {
     let mut g = file.parsed.lock().await;
@@ -356,28 +557,36 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
         drop(g);
         return Ok(recv.await.unwrap_or(ElabResult::Canceled))
       }
-      Some(FileCache::Ready(env)) => return Ok(ElabResult::Ok((), None, env.clone()))
+      Some(FileCache::Ready(errors, env)) => return Ok(ElabResult::Ok((), errors.clone(), env.clone()))
     }
   }
   let text = file.text.clone();
-  let (cyc, errors, env) = if path.has_extension("mmb") {
+  let print_diagnostics = report.diagnostics;
+  let mut snapshot = crate::get_prelude_snapshot() && crate::get_prelude().as_ref() == Some(&path);
+  let mut deps = Vec::new();
+  let (cyc, errors, env, printed) = if path.has_extension("mmb") {
     let (error, env) = mmb_elab(&path, &text);
-    (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
+    let mut errors = if let Err(e) = error {vec![e]} else {vec![]};
+    replay_mmb(&path, &env, &mut errors, report);
+    (None, errors, FrozenEnv::new(env), false)
   } else if path.has_extension("mmu") {
     let (error, env) = mmu_elab(&path, &text);
-    (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
+    (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env), false)
+  } else if let Some((env, deps)) = snapshot.then(|| load_prelude_snapshot(&path)).flatten() {
+    if report.log { log_msg(format!("loaded {}", prelude_snapshot_path(&path).display())) }
+    IMPORTS.ulock().set_imports(path.clone(), deps.into_iter().map(|p| (Span::default(), p)).collect());
+    snapshot = false;
+    (None, vec![], env, false)
   } else {
-    let (_, ast) = parse(text.ascii().clone(), None);
-    if !ast.errors.is_empty() {
-      for e in &ast.errors {
-        to_snippet(e, &path, &ast.source,
-          |s| println!("{}", DisplayList::from(s)))
-      }
-    }
+    let (_, mut ast) = parse_no_panic(text.ascii().clone(), None);
+    let parse_errors = std::mem::take(&mut ast.errors);
     let ast = Arc::new(ast);
-    let mut deps = Vec::new();
-    if !QUIET.load(Ordering::Relaxed) { log_msg(format!("elab {}", path)) }
-    let rd = rd.push(path.clone());
+    let parse_errors: Vec<ElabError> = parse_errors.into_iter().map(ElabError::from).collect();
+    if print_diagnostics { print_errors(&path, &text, &parse_errors) }
+    if report.log { log_msg(format!("elab {}", path)) }
+    let imports = ast.imports.iter()
+      .filter_map(|(sp, f)| Some((*sp, crate::imports::resolve(&path, f).ok()?))).collect();
+    IMPORTS.ulock().set_imports(path.clone(), imports);
     let fut =
       ElaborateBuilder {
         ast: &ast,
@@ -390,32 +599,40 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
         recv_dep: |p| {
           let p = VFS.get_or_insert(p)?.0;
           let (send, recv) = channel();
-          if rd.contains(&p) {
-            send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
+          if let Some(cyc) = IMPORTS.ulock().find_cycle(&path, &p) {
+            send.send(ElabResult::ImportCycle(cyc)).expect("failed to send");
           } else {
-            POOL.spawn_ok(elaborate_and_send(p.clone(), send, rd.clone()));
+            POOL.spawn_ok(elaborate_and_send(p.clone(), send, report));
             deps.push(p);
           }
           Ok(recv)
         },
         recv_goal: None,
-      }.elab();
+        prelude: crate::imports::prelude_for(&path),
+        on_stmt: if print_diagnostics {
+          Some(stmt_listener(path.clone(), text.clone(), ast.clone()))
+        } else { None },
+      }.elab_no_panic();
     let (cyc, _, errors, env) = fut.await;
-    (cyc, errors, env)
+    (cyc, parse_errors.into_iter().chain(errors).collect(), env, print_diagnostics)
   };
-  if !QUIET.load(Ordering::Relaxed) { log_msg(format!("elabbed {}", path)) }
+  if report.log { log_msg(format!("elabbed {}", path)) }
+  if snapshot && cyc.is_none() && errors.is_empty() {
+    if let Err(e) = save_prelude_snapshot(&path, &deps, &env) {
+      if report.log {
+        log_msg(format!("could not save {}: {}", prelude_snapshot_path(&path).display(), e))
+      }
+    }
+  }
   let errors: Option<Arc<[_]>> = if errors.is_empty() { None } else {
-    fn print(s: Snippet<'_>) { println!("{}\n", DisplayList::from(s)) }
-    let mut to_range = mk_to_range();
-    if let FileContents::Ascii(text) = &file.text {
-      for e in &errors { e.to_snippet(&path, text, &mut to_range, print) }
-    } else {
-      for e in &errors { e.to_snippet_no_source(&path, e.pos, print) }
+    if print_diagnostics && !printed {
+      let _g = OUTPUT.ulock();
+      print_errors(&path, &file.text, &errors)
     }
     Some(errors.into())
   };
   let res = match cyc {
-    None => ElabResult::Ok((), errors, env.clone()),
+    None => ElabResult::Ok((), errors.clone(), env.clone()),
     Some(cyc) => ElabResult::ImportCycle(cyc),
   };
   {
@@ -425,21 +642,21 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
         drop(s.send(res.clone()));
       }
     }
-    *g = Some(FileCache::Ready(env));
+    *g = Some(FileCache::Ready(errors, env));
   }
   Ok(res)
 }
 
 /// Elaborate a file, and pass the [`Environment`](crate::elab::Environment)
-/// result to a [`Sender`](FSender).
+/// result to a [`Sender`](FSender). The imports are reported in the same way as the
+/// file that imports them.
 ///
 /// See [`elaborate`] for details on elaboration. This function encapsulates
 /// the `async fn` into a [`BoxFuture`], in order to avoid a recursion between
 /// this function and [`elaborate`] resulting in infinite sized futures.
-fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<FileRef>) ->
-  BoxFuture<'static, ()> {
-  async {
-    if let Ok(env) = elaborate(path, rd).await {
+fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, report: Report) -> BoxFuture<'static, ()> {
+  async move {
+    if let Ok(env) = elaborate(path, report).await {
       drop(send.send(env));
     }
   }.boxed()
@@ -449,13 +666,167 @@ fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<
 /// file contents.
 pub(crate) fn elab_for_result(path: FileRef) -> io::Result<(FileContents, Option<FrozenEnv>)> {
   let (path, file) = VFS.get_or_insert(path)?;
-  let env = match block_on(elaborate(path, Default::default()))? {
+  let env = match block_on(elaborate(path, Report::cli()))? {
     ElabResult::Ok(_, _, env) => Some(env),
     _ => None
   };
   Ok((file.text.clone(), env))
 }
 
+/// Elaborate a file without printing any messages, and return its contents and diagnostics,
+/// not including the diagnostics of imported files. Returns `Err(cyc)` if the file is part
+/// of (or depends on) an import cycle `cyc`, or `Err(None)` if elaboration was canceled.
+/// This is used by the `mm0-rs test` subcommand.
+pub(crate) fn elab_for_diagnostics(path: FileRef) ->
+    io::Result<(FileContents, Result<Arc<[ElabError]>, Option<ImportCycle>>)> {
+  let (path, file) = VFS.get_or_insert(path)?;
+  let errors = match block_on(elaborate(path, Report::SILENT))? {
+    ElabResult::Ok(_, errors, _) => Ok(errors.unwrap_or_else(|| Arc::new([]))),
+    ElabResult::ImportCycle(cyc) => Err(Some(cyc)),
+    ElabResult::Canceled => Err(None),
+  };
+  Ok((file.text.clone(), errors))
+}
+
+/// The elaboration results of a set of files, which can be swapped in and out of the
+/// [`struct@VFS`] using [`swap_elaborated`]. This is used by `mm0-rs build` to keep the
+/// results of elaborating with different flags apart.
+#[derive(Debug, Default)]
+pub(crate) struct ElabCache(HashMap<FileRef, (Option<Arc<[ElabError]>>, FrozenEnv)>);
+
+/// Replace the results of all files that have finished elaborating by the ones in `cache`,
+/// and return the old results. This should not be called while files are being elaborated.
+pub(crate) fn swap_elaborated(cache: ElabCache) -> ElabCache {
+  let mut cache = cache.0;
+  let mut old = HashMap::new();
+  for (path, file) in &*VFS.0.ulock() {
+    let mut g = file.parsed.try_lock().expect("swap_elaborated called during elaboration");
+    if let Some(FileCache::Ready(errors, env)) = g.take() { old.insert(path.clone(), (errors, env)); }
+    *g = cache.remove(path).map(|(errors, env)| FileCache::Ready(errors, env));
+  }
+  ElabCache(old)
+}
+
+/// Get all the files that have finished elaborating, along with their contents and
+/// environments. This includes all files in the import graph of previous calls to
+/// [`elab_for_result`].
+pub(crate) fn elaborated_files() -> Vec<(FileRef, FileContents, FrozenEnv)> {
+  VFS.0.ulock().iter().filter_map(|(path, file)| match &*file.parsed.try_lock()? {
+    Some(FileCache::Ready(_, env)) => Some((path.clone(), file.text.clone(), env.clone())),
+    _ => None
+  }).collect()
+}
+
+/// Get the diagnostics of file `path` and all the files it imports, which must have
+/// finished elaborating, along with the contents of each file.
+pub(crate) fn import_diagnostics(path: &FileRef) -> Vec<(FileRef, FileContents, Arc<[ElabError]>)> {
+  let mut out = vec![];
+  let mut done = HashSet::new();
+  let mut stack = vec![path.clone()];
+  while let Some(path) = stack.pop() {
+    if !done.insert(path.clone()) { continue }
+    let file = if let Some(file) = VFS.0.ulock().get(&path) {file.clone()} else {continue};
+    let g = file.parsed.try_lock();
+    if let Some(Some(FileCache::Ready(errs, env))) = g.as_deref() {
+      out.push((path, file.text.clone(), errs.clone().unwrap_or_else(|| Arc::new([]))));
+      for spans in env.spans() {
+        for (_, k) in spans {
+          if let ObjectKind::Import(f) = k { stack.push(f.clone()) }
+        }
+      }
+    }
+  }
+  out
+}
+
+/// Count the errors and warnings reported in file `path` and all the files it imports,
+/// which must have finished elaborating.
+pub(crate) fn diagnostic_counts(path: &FileRef) -> (usize, usize) {
+  let (mut errors, mut warnings) = (0, 0);
+  for (_, _, errs) in import_diagnostics(path) {
+    for e in &*errs {
+      match e.level {
+        ErrorLevel::Error => errors += 1,
+        ErrorLevel::Warning => warnings += 1,
+        ErrorLevel::Info => {}
+      }
+    }
+  }
+  (errors, warnings)
+}
+
+/// The summary line printed at the end of `mm0-rs compile`: the number of errors and
+/// warnings in file `path` and all the files it imports, and the number of diagnostics in
+/// each [`WarningCategory`], or `None` if there are no errors or warnings.
+fn diagnostic_summary(path: &FileRef) -> Option<String> {
+  let (mut errors, mut warnings) = (0, 0);
+  let mut cats = [0; WarningCategory::ALL.len()];
+  for (_, _, errs) in import_diagnostics(path) {
+    for e in &*errs {
+      match e.level {
+        ErrorLevel::Error => errors += 1,
+        ErrorLevel::Warning => warnings += 1,
+        ErrorLevel::Info => continue,
+      }
+      if let Some(cat) = e.code.and_then(WarningCategory::of_code) { cats[cat as usize] += 1 }
+    }
+  }
+  if errors + warnings == 0 { return None }
+  let mut msg = format!("{} errors, {} warnings", errors, warnings);
+  let cats = WarningCategory::ALL.iter().zip(cats).filter(|p| p.1 != 0)
+    .map(|(cat, n)| format!("{}: {}", cat.name(), n)).collect::<Vec<_>>();
+  if !cats.is_empty() { msg += &format!(" ({})", cats.join(", ")) }
+  Some(msg)
+}
+
+/// Compare the expected result `expected` of the `output` commands with the actual result
+/// `actual`, and describe the first difference. The output is usually binary, so the
+/// difference is shown as an offset and the bytes around it, in hex.
+fn compare_output(expected: &[u8], actual: &[u8]) -> Option<String> {
+  fn hex(bytes: &[u8], i: usize) -> String {
+    let bytes = &bytes[i.min(bytes.len())..bytes.len().min(i + 16)];
+    if bytes.is_empty() { return "end of output".into() }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+  }
+  let i = expected.iter().zip(actual).position(|(a, b)| a != b)
+    .unwrap_or_else(|| expected.len().min(actual.len()));
+  if i == expected.len() && i == actual.len() { return None }
+  let start = i & !0xf;
+  Some(format!("first difference at byte {:#x} ({} bytes expected, {} found)\n\
+    expected at {:#x}: {}\n   found at {:#x}: {}",
+    i, expected.len(), actual.len(), start, hex(expected, start), start, hex(actual, start)))
+}
+
+/// Write the elaborated environment `env` of file `path` with contents `file` to `out`,
+/// as an MMU file if `mmu` is true and an MMB file otherwise.
+pub(crate) fn export(path: FileRef, file: &FileContents, env: &FrozenEnv,
+  out: &std::path::Path, mmu: bool
+) -> io::Result<()> {
+  use {fs::File, io::BufWriter};
+  let w = BufWriter::new(File::create(out)?);
+  if mmu {
+    env.export_mmu(w)?;
+  } else {
+    fn report(lvl: ErrorLevel, err: &str) {
+      println!("{}\n", DisplayList::from(Snippet {
+        title: Some(Annotation {
+          label: Some(err),
+          id: None,
+          annotation_type: lvl.to_annotation_type(),
+        }),
+        footer: vec![],
+        slices: vec![],
+        opt: FormatOptions { color: use_color(), ..Default::default() },
+      }))
+    }
+    let mut report = report;
+    let mut ex = MmbExporter::new(path, file.try_ascii().map(|fc| &**fc), env, &mut report, w);
+    ex.run(true)?;
+    ex.finish()?;
+  }
+  Ok(())
+}
+
 /// Main entry point for `mm0-rs compile` subcommand.
 ///
 /// # Arguments
@@ -466,47 +837,193 @@ pub(crate) fn elab_for_result(path: FileRef) -> io::Result<(FileContents, Option
 /// - `out.mmb` (or `out.mmu`) is the MMB file to generate, if the elaboration is
 ///   successful. The file extension is used to determine if we are outputting
 ///   binary. If this argument is omitted, the input is only elaborated.
+///
+/// With `--expect-output FILE`, the string denoted by the `output string` commands is
+/// compared with the contents of `FILE`, and the first difference is reported. This checks
+/// claims like "the verified compiler emits this binary" as part of the build.
+/// `--emit-def DEF=FILE` writes the bytes denoted by the `string` definition `DEF` to `FILE`,
+/// and `--emit-elf NAME=FILE` writes the executable produced by `mmc-finish NAME` to `FILE`.
 pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  use std::io::IsTerminal;
+  set_color(args.value_of("color"));
+  QUIET.store(args.is_present("quiet"), Ordering::Relaxed);
+  PROGRESS.store(args.is_present("progress") && io::stderr().is_terminal(), Ordering::Relaxed);
   let path = args.value_of("INPUT").expect("required arg");
   let path: FileRef = fs::canonicalize(path)?.into();
+  if let Some(thms) = args.value_of("replay") {
+    *REPLAY.ulock() = Some(if thms == "all" { ReplayMode::All } else {
+      ReplayMode::Only(thms.split(',').map(|s| s.trim().to_owned()).collect())
+    });
+  }
+  if args.is_present("minimize") {
+    let ms = args.value_of("minimize").map_or(Ok(1000), str::parse).map_err(|_|
+      io::Error::new(io::ErrorKind::InvalidInput, "--minimize: expected a number of milliseconds"))?;
+    crate::set_minimize(Some(std::time::Duration::from_millis(ms)));
+  }
+  let read_align = |file: &str| -> io::Result<crate::Alignment> {
+    crate::Alignment::parse(&fs::read_to_string(file)?).or_else(|e| {
+      println!("{}: {}", file, e);
+      std::process::exit(1)
+    })
+  };
+  if let Some(file) = args.value_of("align") { crate::set_alignment(read_align(file)?) }
+  if let Some(file) = args.value_of("export_align") { crate::set_export_alignment(read_align(file)?) }
   let (file, env) = elab_for_result(path.clone())?;
+  if let Some(msg) = diagnostic_summary(&path) { println!("{}", msg) }
   let env = env.unwrap_or_else(|| std::process::exit(1));
-  QUIET.store(args.is_present("quiet"), Ordering::Relaxed);
-  if let Some(s) = args.value_of_os("output") {
-    if let Err((fsp, e)) =
-      if s == "-" { env.run_output(io::stdout()) }
-      else { env.run_output(fs::File::create(s)?) }
-    {
+  let incomplete = env.incomplete_thms();
+  let num_incomplete = incomplete.iter().filter(|&&b| b).count();
+  if num_incomplete != 0 {
+    if args.is_present("no_sorry") {
+      let mut get_text = mk_get_text();
+      for (t, &b) in incomplete.enum_iter() {
+        let td = env.thm(t);
+        if b && matches!(td.kind, ThmKind::Thm(None)) {
+          let e = ElabError::new_e(td.span.span,
+            format!("theorem {} is incomplete", env.data()[td.atom].name()));
+          let file = VFS.get_or_insert(td.span.file.clone())?.1;
+          e.to_snippet(&td.span.file, file.text.ascii(), &mut get_text,
+            |s| println!("{}\n", DisplayList::from(s)));
+        }
+      }
+      println!("{} incomplete theorems, aborting (--no-sorry)", num_incomplete);
+      std::process::exit(1);
+    } else if !QUIET.load(Ordering::Relaxed) {
+      println!("{} of {} theorems are incomplete", num_incomplete, incomplete.len())
+    }
+  }
+  let num_unchecked = incomplete.enum_iter().filter(|&(t, _)| env.is_unchecked(t)).count();
+  if num_unchecked != 0 && !QUIET.load(Ordering::Relaxed) {
+    println!("{} of {} theorems were not checked", num_unchecked, incomplete.len())
+  }
+  if let Some(spec) = args.value_of("spec") {
+    let (spec_file, spec_env) = elab_for_result(fs::canonicalize(spec)?.into())?;
+    let spec_env = spec_env.unwrap_or_else(|| std::process::exit(1));
+    let empty = LinedString::default();
+    let errs = crate::elab::compare::check_spec(
+      &spec_env, spec_file.try_ascii().map_or(&empty, |s| &**s),
+      &env, file.try_ascii().map_or(&empty, |s| &**s));
+    let mut get_text = mk_get_text();
+    for (fsp, msg) in &errs {
+      let file = VFS.get_or_insert(fsp.file.clone())?.1;
+      let e = ElabError::new_e(fsp.span, msg.clone());
+      if let Some(text) = file.text.try_ascii() {
+        e.to_snippet(&fsp.file, text, &mut get_text, |s| println!("{}\n", DisplayList::from(s)));
+      } else {
+        e.to_snippet_no_source(&fsp.file, fsp.span, |s| println!("{}\n", DisplayList::from(s)));
+      }
+    }
+    if !errs.is_empty() {
+      println!("{} statements do not match the spec {}", errs.len(), spec);
+      std::process::exit(1);
+    }
+  }
+  if args.is_present("check_sorts") {
+    let mut get_text = mk_get_text();
+    let mut n = 0;
+    for s in env.stmts() {
+      let (fsp, errs) = match *s {
+        StmtTrace::Decl(a) => match env.data()[a].decl() {
+          Some(DeclKey::Term(t)) => (&env.term(t).span, env.audit_term(t)),
+          Some(DeclKey::Thm(t)) => (&env.thm(t).span, env.audit_thm(t)),
+          None => continue,
+        },
+        _ => continue
+      };
+      for msg in errs {
+        n += 1;
+        let file = VFS.get_or_insert(fsp.file.clone())?.1;
+        let e = ElabError::new_e(fsp.span, msg);
+        if let Some(text) = file.text.try_ascii() {
+          e.to_snippet(&fsp.file, text, &mut get_text, |s| println!("{}\n", DisplayList::from(s)));
+        } else {
+          e.to_snippet_no_source(&fsp.file, fsp.span, |s| println!("{}\n", DisplayList::from(s)));
+        }
+      }
+    }
+    if n != 0 {
+      println!("{} sort modifier violations", n);
+      std::process::exit(1);
+    }
+  }
+  if args.is_present("show_binders") {
+    let mut get_text = mk_get_text();
+    for (p, text, env) in &elaborated_files() {
+      let text = if let Some(text) = text.try_ascii() {text} else {continue};
+      for info in crate::binders::analyze(p, text, env) {
+        ElabError::info(info.decl, format!("inferred binders: {}", info.binders))
+          .to_snippet(p, text, &mut get_text, |s| println!("{}\n", DisplayList::from(s)));
+      }
+    }
+  }
+  if args.is_present("check_imports") {
+    let files = elaborated_files();
+    let envs = files.iter().map(|(p, _, env)| (p.clone(), env.clone())).collect::<HashMap<_, _>>();
+    let mut get_text = mk_get_text();
+    for (p, text, env) in &files {
+      let text = if let Some(text) = text.try_ascii() {text} else {continue};
+      for info in crate::imports::analyze(p, env, |f| envs.get(f).cloned()) {
+        if let Some(msg) = info.msg() {
+          ElabError::warn(info.span, msg).to_snippet(p, text, &mut get_text,
+            |s| println!("{}\n", DisplayList::from(s)));
+        }
+      }
+    }
+  }
+  let expect_output = args.value_of_os("expect_output");
+  if args.value_of_os("output").is_some() || expect_output.is_some() {
+    let mut buf = vec![];
+    let res = match args.value_of_os("output") {
+      Some(s) if expect_output.is_none() =>
+        if s == "-" { env.run_output(io::stdout()) }
+        else { env.run_output(fs::File::create(s)?) },
+      _ => env.run_output(&mut buf),
+    };
+    if let Err((fsp, e)) = res {
       let e = ElabError::new_e(fsp.span, e);
       let file = VFS.get_or_insert(fsp.file.clone())?.1;
-      e.to_snippet(&fsp.file, file.text.ascii(), &mut mk_to_range(),
+      e.to_snippet(&fsp.file, file.text.ascii(), &mut mk_get_text(),
         |s| println!("{}\n", DisplayList::from(s)));
       std::process::exit(1);
     }
+    if let Some(expected) = expect_output {
+      match args.value_of_os("output") {
+        Some(s) if s == "-" => io::Write::write_all(&mut io::stdout(), &buf)?,
+        Some(s) => fs::write(s, &buf)?,
+        None => {}
+      }
+      let name = std::path::Path::new(expected).display();
+      if let Some(diff) = compare_output(&fs::read(expected)?, &buf) {
+        println!("output does not match {}: {}", name, diff);
+        std::process::exit(1);
+      } else if !QUIET.load(Ordering::Relaxed) {
+        println!("output matches {} ({} bytes)", name, buf.len())
+      }
+    }
   }
-  if let Some(out) = args.value_of("OUTPUT") {
-    use {fs::File, io::BufWriter};
-    let w = BufWriter::new(File::create(out)?);
-    if out.rsplit('.').next().map_or(false, |ext| ext.eq_ignore_ascii_case("mmu")) {
-      env.export_mmu(w)?;
-    } else {
-      fn report(lvl: ErrorLevel, err: &str) {
-        println!("{}\n", DisplayList::from(Snippet {
-          title: Some(Annotation {
-            label: Some(err),
-            id: None,
-            annotation_type: lvl.to_annotation_type(),
-          }),
-          footer: vec![],
-          slices: vec![],
-          opt: FormatOptions { color: true, ..Default::default() },
-        }))
+  let emit = args.values_of("emit_def").into_iter().flatten().map(|arg| ("--emit-def", arg))
+    .chain(args.values_of("emit_elf").into_iter().flatten().map(|arg| ("--emit-elf", arg)));
+  for (flag, arg) in emit {
+    let (name, out) = arg.split_once('=').ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+      format!("{}: expected NAME=FILE, got '{}'", flag, arg)))?;
+    match env.eval_string_def(name) {
+      Ok(bytes) => {
+        fs::write(out, bytes)?;
+        #[cfg(unix)] if flag == "--emit-elf" {
+          use std::os::unix::fs::PermissionsExt;
+          fs::set_permissions(out, fs::Permissions::from_mode(0o755))?
+        }
+      }
+      Err(e) => {
+        println!("{} {}: {}", flag, name, e);
+        std::process::exit(1);
       }
-      let mut report = report;
-      let mut ex = MmbExporter::new(path, file.try_ascii().map(|fc| &**fc), &env, &mut report, w);
-      ex.run(true)?;
-      ex.finish()?;
     }
   }
+  if let Some(out) = args.value_of("OUTPUT") {
+    let out: &std::path::Path = out.as_ref();
+    let mmu = out.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("mmu"));
+    export(path, &file, &env, out, mmu)?
+  }
   Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! Debug dumps of elaborator state.
+//!
+//! This module renders parts of the state of the elaborator using [`EnvDebug`], which shows
+//! the internal representation of the data (unlike the pretty printer) but with the names of
+//! atoms, sorts, terms and theorems resolved. Cycles through lisp refs are printed as
+//! `_cycle_`. It implements the `mm0-rs debug-dump` subcommand, the `mm0.debugDump` command
+//! of the language server, and the `debug-dump` lisp builtin, which is the only way to get
+//! at the local context of a proof in progress.
+use std::{fs, io};
+use std::fmt::Write;
+use clap::ArgMatches;
+use crate::compiler::elab_for_result;
+use crate::{AtomData, DeclKey, EnvDebug, FileRef, FormatEnv, LinedString, LocalContext, Thm};
+
+/// Render the local context `lc`: the variables, hypotheses, goals and metavariables of the
+/// proof in progress.
+#[must_use] pub fn context(fe: FormatEnv<'_>, lc: &LocalContext) -> String {
+  format!("{:#?}", fe.to(lc))
+}
+
+/// Render the values of the lisp globals whose names start with `prefix`, in order of name.
+#[must_use] pub fn globals(fe: FormatEnv<'_>, prefix: &str) -> String {
+  let mut ads = fe.data.iter()
+    .filter(|ad| ad.lisp.is_some() && ad.name.starts_with(prefix.as_bytes()))
+    .collect::<Vec<&AtomData>>();
+  ads.sort_by(|a, b| a.name.cmp(&b.name));
+  let mut out = String::new();
+  for ad in ads {
+    let ld = ad.lisp.as_ref().expect("filtered");
+    writeln!(out, "{} = {:#?}", ad.name, fe.to(&ld.val)).expect("writing to a string");
+  }
+  out
+}
+
+/// All the fields of a theorem. [`Thm`] itself only shows its name in [`EnvDebug`],
+/// because it appears in proof objects.
+struct ThmFields<'a>(&'a Thm);
+
+impl EnvDebug for ThmFields<'_> {
+  fn env_dbg<'a>(&self, fe: FormatEnv<'a>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let td = self.0;
+    f.debug_struct("Thm")
+      .field("atom", &fe.to(&td.atom))
+      .field("span", &fe.to(&td.span))
+      .field("vis", &fe.to(&td.vis))
+      .field("full", &fe.to(&td.full))
+      .field("doc", &fe.to(&td.doc))
+      .field("args", &fe.to(&td.args))
+      .field("heap", &fe.to(&td.heap))
+      .field("hyps", &fe.to(&td.hyps))
+      .field("ret", &fe.to(&td.ret))
+      .field("kind", &fe.to(&td.kind))
+      .finish()
+  }
+}
+
+/// Render the sort, term or theorem `name`, or return `None` if there is none.
+#[must_use] pub fn decl(fe: FormatEnv<'_>, name: &str) -> Option<String> {
+  let ad = &fe.data[*fe.atoms.get(name.as_bytes())?];
+  let mut out = String::new();
+  if let Some(s) = ad.sort {
+    writeln!(out, "{:#?}", fe.to(&fe.sorts[s])).expect("writing to a string");
+  }
+  match ad.decl {
+    None => {}
+    Some(DeclKey::Term(t)) => writeln!(out, "{:#?}", fe.to(&fe.terms[t])).expect("writing to a string"),
+    Some(DeclKey::Thm(t)) =>
+      writeln!(out, "{:#?}", fe.to(&ThmFields(&fe.thms[t]))).expect("writing to a string"),
+  }
+  if out.is_empty() { None } else { Some(out) }
+}
+
+/// Render the parts of the environment selected by `globals` (a prefix of the names of lisp
+/// globals) and `decl` (the name of a declaration), as for [`globals`] and [`decl`].
+pub fn dump(fe: FormatEnv<'_>, globals: Option<&str>, decl: Option<&str>) -> Result<String, String> {
+  let mut out = String::new();
+  if let Some(prefix) = globals {
+    out += &self::globals(fe, prefix)
+  }
+  if let Some(name) = decl {
+    out += &self::decl(fe, name).ok_or_else(|| format!("unknown declaration '{}'", name))?
+  }
+  Ok(out)
+}
+
+/// Main entry point for `mm0-rs debug-dump` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs debug-dump <in.mm1> [--globals PREFIX] [--decl NAME] [-o out.txt]`, where:
+///
+/// - `in.mm1` is the file to elaborate.
+/// - `PREFIX`: the lisp globals whose names start with `PREFIX` are dumped. Use `--globals ''`
+///   to dump all of them.
+/// - `NAME`: the sort, term or theorem `NAME` is dumped.
+/// - `out.txt` is the file to write the dump to, or stdout if omitted.
+///
+/// The local context of a proof can be dumped with `(display (debug-dump 'context))`.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  let (file, env) = elab_for_result(path.clone())?;
+  let env = env.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+    format!("{}: elaboration failed", path)))?;
+  let source = file.try_ascii().map_or_else(LinedString::default, |s| (**s).clone());
+  let fe = unsafe { env.format_env(&source) };
+  let out = dump(fe, args.value_of("globals"), args.value_of("decl"))
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  match args.value_of("output") {
+    Some(file) => fs::write(file, out),
+    None => { print!("{}", out); Ok(()) }
+  }
+}
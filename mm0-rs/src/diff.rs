@@ -0,0 +1,106 @@
+//! Statement-level diff between two versions of a file.
+//!
+//! This module implements the `mm0-rs diff-src` subcommand, which elaborates two versions
+//! of an `.mm1` or `.mm0` file, matches up their declarations by name, and reports which
+//! ones were added, removed, had their statement changed, or only had their proof changed.
+//! Statements and proofs are compared after elaboration (see [`Comparer`]), so changes in
+//! notation, formatting, comments or the names of variables are not reported, and a proof
+//! is only reported as changed if it elaborates to a different proof term.
+use std::{fs, io};
+use clap::ArgMatches;
+use crate::elab::compare::{diff_lines, Comparer, Item};
+use crate::{DeclKey, FileRef, FrozenEnv, LinedString, TermKind, ThmKind};
+use crate::compiler::elab_for_result;
+
+/// An elaborated version of the file.
+struct Version {
+  path: FileRef,
+  source: LinedString,
+  env: FrozenEnv,
+}
+
+impl Version {
+  fn load(path: &str) -> io::Result<Version> {
+    let path: FileRef = fs::canonicalize(path)?.into();
+    let (file, env) = elab_for_result(path.clone())?;
+    let env = env.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+      format!("{}: elaboration failed", path)))?;
+    let source = file.try_ascii().map_or_else(LinedString::default, |s| (**s).clone());
+    Ok(Version {path, source, env})
+  }
+
+  /// The sorts and declarations of this file, not including imported ones.
+  fn items(&self) -> impl Iterator<Item=Item> + '_ {
+    self.env.stmts().iter().filter_map(Item::from_stmt)
+      .filter(move |it| it.span(&self.env).map_or(false, |fsp| fsp.file == self.path))
+  }
+
+  /// The kind of an item, as a keyword.
+  fn kind(&self, it: Item) -> &'static str {
+    match (it, self.env.data()[it.atom()].decl()) {
+      (Item::Sort(_), _) | (_, None) => "sort",
+      (_, Some(DeclKey::Term(t))) =>
+        if let TermKind::Term = self.env.term(t).kind {"term"} else {"def"},
+      (_, Some(DeclKey::Thm(t))) =>
+        if let ThmKind::Axiom = self.env.thm(t).kind {"axiom"} else {"theorem"},
+    }
+  }
+
+  fn name(&self, it: Item) -> String { self.env.data()[it.atom()].name().to_string() }
+}
+
+/// Whether the proofs of the theorems `it` in `old` and `it2` in `new` are the same. Other
+/// items don't have proofs, and definitions are compared as part of the statement.
+fn proof_eq(cmp: &mut Comparer<'_>, old: &FrozenEnv, it: Item, new: &FrozenEnv, it2: Item) -> bool {
+  match (old.data()[it.atom()].decl(), new.data()[it2.atom()].decl()) {
+    (Some(DeclKey::Thm(t1)), Some(DeclKey::Thm(t2))) => cmp.thm_proof(t1, t2),
+    _ => true,
+  }
+}
+
+/// Main entry point for `mm0-rs diff-src` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs diff-src <old.mm1> <new.mm1>`, where:
+///
+/// - `old.mm1` and `new.mm1` are the two versions of the file to compare.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let old = Version::load(args.value_of("OLD").expect("required arg"))?;
+  let new = Version::load(args.value_of("NEW").expect("required arg"))?;
+  let mut cmp = Comparer::new(&old.env, &new.env);
+  let (mut added, mut removed, mut changed, mut proofs) = (0, 0, 0, 0);
+  for it in new.items() {
+    match it.rename(&new.env, &old.env) {
+      Some(it2) if it2.span(&old.env).map_or(false, |fsp| fsp.file == old.path) => {}
+      _ => {
+        added += 1;
+        println!("added: {} {}", new.kind(it), new.name(it))
+      }
+    }
+  }
+  for it in old.items() {
+    let it2 = match it.rename(&old.env, &new.env) {
+      Some(it2) if it2.span(&new.env).map_or(false, |fsp| fsp.file == new.path) => it2,
+      _ => {
+        removed += 1;
+        println!("removed: {} {}", old.kind(it), old.name(it));
+        continue
+      }
+    };
+    match cmp.item(it) {
+      Some(Err(reason)) => {
+        changed += 1;
+        println!("changed: {} {} ({})\n{}", new.kind(it2), new.name(it2), reason,
+          diff_lines(&it.print(&old.env, &old.source), &it2.print(&new.env, &new.source)))
+      }
+      _ => if !proof_eq(&mut cmp, &old.env, it, &new.env, it2) {
+        proofs += 1;
+        println!("proof changed: {} {}", new.kind(it2), new.name(it2))
+      }
+    }
+  }
+  println!("{} added, {} removed, {} changed, {} with only the proof changed",
+    added, removed, changed, proofs);
+  Ok(())
+}
@@ -7,9 +7,10 @@ use pulldown_cmark::escape::WriteWrapper;
 use std::fs::{self, File};
 use std::io::{self, BufWriter, Write};
 use std::mem;
-use crate::{lisp::pretty::Annot, ArcString, AtomData, AtomId, DeclKey, DocComment, EnvMergeIter,
-  Environment, ExprNode, FileRef, FormatEnv, LinedString, LispVal, Proof, ProofNode, SliceUninit,
+use crate::{lisp::{pretty::Annot, BuiltinProc}, Alignment, ArcString, AtomData, AtomId, DeclKey, DocComment, EnvMergeIter,
+  Environment, ExprNode, FileRef, FormatEnv, FrozenEnv, LinedString, LispVal, Proof, ProofNode, SliceUninit,
   StmtTrace, TermId, Thm, ThmId, ThmKind, Type};
+use crate::compiler::FileContents;
 
 const PP_WIDTH: usize = 160;
 
@@ -395,8 +396,14 @@ impl PartialEq for CaseInsensitiveName {
 }
 impl Eq for CaseInsensitiveName {}
 
+/// The order in which the steps of a proof are listed.
 #[derive(Clone, Copy)]
-enum ProofOrder { Pre, Post }
+pub(crate) enum ProofOrder {
+  /// Each step comes before its subproofs.
+  Pre,
+  /// Each step comes after its subproofs (the default).
+  Post,
+}
 
 struct BuildDoc<'a, W> {
   thm_folder: PathBuf,
@@ -487,6 +494,19 @@ fn render_doc(w: &mut impl Write, doc: &Option<DocComment>) -> io::Result<()> {
   Ok(())
 }
 
+fn render_attrs(w: &mut impl Write, attrs: Option<&Vec<ArcString>>) -> io::Result<()> {
+  if let Some(attrs) = attrs {
+    write!(w, r#"      <div class="attrs">"#)?;
+    for attr in attrs {
+      write!(w, "<code>")?;
+      pulldown_cmark::escape::escape_html(WriteWrapper(&mut *w), &String::from_utf8_lossy(attr))?;
+      write!(w, "</code> ")?;
+    }
+    writeln!(w, "</div>")?;
+  }
+  Ok(())
+}
+
 fn disambiguated_anchor(w: &mut impl Write, ad: &AtomData, sort: bool) -> io::Result<()> {
   match ad {
     AtomData {sort: Some(_), decl: Some(_), ..} if sort => write!(w, "{}.sort", ad.name),
@@ -539,6 +559,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
       &format!("{} - {}", thmname, filename),
       &format!(r#"{} <a class="{}" href="">{}</a>"#, kind, kindclass, thmname),
       &nav, &["../proof.js"])?;
+    render_attrs(&mut file, self.env.attrs.get(&td.atom))?;
     render_doc(&mut file, &td.doc)?;
     writeln!(file, "    <pre>{}</pre>", FormatEnv {source: self.source, env: &self.env}.to(td))?;
     if let ThmKind::Thm(Some(pf)) = &td.kind {
@@ -574,9 +595,9 @@ impl<'a, W: Write> BuildDoc<'a, W> {
     let nav: String;
     let nav = if let Some(base) = &self.base_url {
       let url = base.join(path.rel()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-      nav = format!("<a href=\"{}\">src</a>", url);
+      nav = format!("<a href=\"builtins.html\">builtins</a> | <a href=\"{}\">src</a>", url);
       &nav
-    } else {""};
+    } else {"<a href=\"builtins.html\">builtins</a>"};
     header(file, "",
       &format!("Documentation index for `{}`.", path.rel()),
       &format!("{} - Index", path.rel()),
@@ -595,6 +616,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
           writeln!(file, "\">")?;
           let sid = ad.sort.expect("wf env");
           let sd = &self.env.sorts[sid];
+          render_attrs(&mut file, self.env.attrs.get(&a))?;
           render_doc(&mut file, &sd.doc)?;
           writeln!(file, "      <pre>")?;
           let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, file, "");
@@ -609,6 +631,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
           match ad.decl.expect("wf env") {
             DeclKey::Term(tid) => {
               let td = &self.env.terms[tid];
+              render_attrs(&mut file, self.env.attrs.get(&a))?;
               render_doc(&mut file, &td.doc)?;
               write!(file, "      <pre>")?;
               let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, file, "");
@@ -617,6 +640,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
             }
             DeclKey::Thm(tid) => {
               let td = &self.env.thms[tid];
+              render_attrs(&mut file, self.env.attrs.get(&a))?;
               render_doc(&mut file, &td.doc)?;
               write!(file, "      <pre>")?;
               let w = &mut HtmlPrinter::new(fe.env, &mut self.mangler, file, "");
@@ -635,6 +659,28 @@ impl<'a, W: Write> BuildDoc<'a, W> {
     writeln!(file, "{}", FOOTER)
   }
 }
+/// Write the page `builtins.html` in `dir`, documenting the builtin lisp procedures.
+fn write_builtins(mut dir: PathBuf) -> io::Result<()> {
+  use pulldown_cmark::escape::escape_html;
+  dir.push("builtins.html");
+  let mut file = BufWriter::new(File::create(dir)?);
+  header(&mut file, "", "Documentation for the builtin procedures of MM1 lisp.",
+    "Builtins", "Builtins", "<a href=\"index.html\">index</a>", &[])?;
+  let mut procs = vec![];
+  BuiltinProc::for_each(|p, _| procs.push(p));
+  for p in procs {
+    let (mut name, mut usage) = (String::new(), String::new());
+    escape_html(&mut name, p.to_str())?;
+    escape_html(&mut usage, p.usage())?;
+    writeln!(file, "    <div id=\"{}\">", name)?;
+    writeln!(file, "      <pre>{}</pre>", usage)?;
+    writeln!(file, "      <p>Takes {}.</p>", p.spec())?;
+    render_doc(&mut file, &Some(p.doc().into()))?;
+    writeln!(file, "    </div>")?
+  }
+  writeln!(file, "{}", FOOTER)
+}
+
 /// Main entry point for `mm0-rs doc` subcommand.
 ///
 /// # Arguments
@@ -648,11 +694,25 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
   let path: FileRef = fs::canonicalize(path)?.into();
   let (fc, old) = crate::compiler::elab_for_result(path.clone())?;
   let old = old.unwrap_or_else(|| std::process::exit(1));
+  let order = match args.value_of("order") {
+    Some("pre") => ProofOrder::Pre,
+    Some("post") => ProofOrder::Post,
+    _ => unreachable!(),
+  };
+  let dir = PathBuf::from(args.value_of("OUTPUT").unwrap_or("doc"));
+  build(&path, &fc, &old, dir, order, args.value_of("only"), args.value_of("src"))
+}
+
+/// Write the documentation for the elaborated file `path`, with contents `fc` and environment
+/// `old`, to the folder `dir`. If `only` is set, only the listed theorems are documented,
+/// and `src` is the base URL for source links (or `-` to disable them).
+pub(crate) fn build(path: &FileRef, fc: &FileContents, old: &FrozenEnv, mut dir: PathBuf,
+  order: ProofOrder, only: Option<&str>, src: Option<&str>,
+) -> io::Result<()> {
   println!("writing docs");
   let mut env = Environment::new();
   assert!(matches!(
-    EnvMergeIter::new(&mut env, &old, (0..0).into()).next(&mut env, &mut vec![]), Ok(None)));
-  let mut dir = PathBuf::from(args.value_of("OUTPUT").unwrap_or("doc"));
+    EnvMergeIter::new(&mut env, old, &Alignment::default(), (0..0).into()).next(&mut env, &mut vec![]), Ok(None)));
   fs::create_dir_all(&dir)?;
   macro_rules! import {($($str:expr),*) => {$({
     let mut file = dir.to_owned();
@@ -662,20 +722,15 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
     }
   })*}}
   import!("stylesheet.css", "proof.js");
-  let order = match args.value_of("order") {
-    Some("pre") => ProofOrder::Pre,
-    Some("post") => ProofOrder::Post,
-    _ => unreachable!(),
-  };
-  let only = args.value_of("only");
   let index = if only.is_some() {None} else {
+    write_builtins(dir.clone())?;
     let mut file = dir.clone();
     file.push("index.html");
     Some(BufWriter::new(File::create(file)?))
   };
   dir.push("thms");
   fs::create_dir_all(&dir)?;
-  let base_url = match args.value_of("src") {
+  let base_url = match src {
     Some("-") => None,
     src => Some(Url::parse(src.unwrap_or("https://github.com/digama0/mm0/blob/master/examples/"))
       .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
@@ -702,7 +757,7 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
       bd.thm_doc(i.checked_sub(1).map(|j| thms[j]), tid, thms.get(i+1).copied())?;
     }
   } else {
-    bd.write_all(&path, old.stmts())?;
+    bd.write_all(path, old.stmts())?;
   }
   Ok(())
 }
\ No newline at end of file
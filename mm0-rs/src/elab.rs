@@ -15,12 +15,21 @@ pub mod local_context;
 pub mod refine;
 pub mod proof;
 pub mod inout;
+pub mod verify;
+pub mod minimize;
+pub mod disc_tree;
+pub mod auto;
+pub mod conv;
+pub mod snapshot;
+pub mod persist;
+pub mod compare;
+pub mod do_cache;
 
 
 use std::collections::HashMap;
 use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
+use std::panic::AssertUnwindSafe;
 use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
 use std::{future::Future, pin::Pin, task::{Context, Poll}};
 use std::time::{Duration, Instant};
@@ -33,13 +42,15 @@ use inout::InoutHandlers;
 use environment::Literal as ELiteral;
 use lisp::LispVal;
 use local_context::try_get_span_opt;
-use crate::{ArcList, ArcString, AtomId, BoxError, Coe, DeclKey, DocComment, EnvMergeIter,
-  Environment, ErrorLevel, Expr, ExprNode, FileRef, FileSpan, FrozenEnv,
+use crate::{Alignment, ArcString, AtomId, BoxError, Coe, DeclKey, DocComment,
+  EnvMergeIter, Environment, ErrorLevel, Expr, ExprNode, FileRef, FileSpan, FrozenEnv,
   FrozenLispVal, LocalContext, Modifiers, NotaInfo, ObjectKind, Prec,
   Proof, ProofNode, Remap, Remapper, SortId, Span, Term, TermId, Thm, ThmId};
+use crate::explain::ErrorCode;
+use crate::imports::ImportCycle;
 
 #[cfg(feature = "server")]
-use {crate::LinedString, lsp_types::{Diagnostic, DiagnosticRelatedInformation, Location}};
+use {crate::LinedString, lsp_types::{Diagnostic, DiagnosticRelatedInformation, Location, NumberOrString}};
 
 /// An error payload.
 #[derive(Debug, DeepSizeOf)]
@@ -93,7 +104,11 @@ impl ElabErrorKind {
           location: to_loc(fs),
           message: format!("{}", e),
         }).collect()),
-      _ => None
+      ElabErrorKind::Upstream(file, e, _) => Some(vec![DiagnosticRelatedInformation {
+        location: to_loc(&FileSpan {file: file.clone(), span: e.pos}),
+        message: e.kind.raw_msg(),
+      }]),
+      ElabErrorKind::Boxed(_, None) => None
     }
   }
 }
@@ -113,6 +128,8 @@ pub struct ElabError {
   pub level: ErrorLevel,
   /// The type of error (currently there is only [`ElabErrorKind::Boxed`])
   pub kind: ElabErrorKind,
+  /// The error code, if this error belongs to one of the classes in [`ErrorCode`].
+  pub code: Option<ErrorCode>,
 }
 
 /// The main result type used by functions in the elaborator.
@@ -122,7 +139,7 @@ impl ElabError {
 
   /// Make an elaboration error from a position and an [`ElabErrorKind`].
   pub fn new(pos: impl Into<Span>, kind: ElabErrorKind) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Error, kind }
+    ElabError { pos: pos.into(), level: ErrorLevel::Error, kind, code: None }
   }
 
   /// Make an elaboration error from a position and anything that can be converted to a [`BoxError`].
@@ -137,12 +154,17 @@ impl ElabError {
 
   /// Make an elaboration warning from a position and a message.
   pub fn warn(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Warning, kind: ElabErrorKind::Boxed(e.into(), None)}
+    ElabError { pos: pos.into(), level: ErrorLevel::Warning, kind: ElabErrorKind::Boxed(e.into(), None), code: None }
   }
 
   /// Make an info message at a position
   pub fn info(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Info, kind: ElabErrorKind::Boxed(e.into(), None)}
+    ElabError { pos: pos.into(), level: ErrorLevel::Info, kind: ElabErrorKind::Boxed(e.into(), None), code: None }
+  }
+
+  /// Set the error code of this error.
+  #[must_use] pub fn with_code(self, code: ErrorCode) -> ElabError {
+    ElabError { code: Some(code), ..self }
   }
 
   /// Convert an [`ElabError`] into the LSP [`Diagnostic`] type.
@@ -153,7 +175,7 @@ impl ElabError {
     Diagnostic {
       range: file.to_range(self.pos),
       severity: Some(self.level.to_diag_severity()),
-      code: None,
+      code: self.code.map(|c| NumberOrString::String(c.to_str().to_owned())),
       code_description: None,
       source: Some("mm0-rs".to_owned()),
       message: self.kind.msg(),
@@ -166,7 +188,7 @@ impl ElabError {
 
 impl From<mm1_parser::ParseError> for ElabError {
   fn from(e: mm1_parser::ParseError) -> Self {
-    ElabError {pos: e.pos, level: e.level, kind: ElabErrorKind::Boxed(e.msg, None) }
+    ElabError {pos: e.pos, level: e.level, kind: ElabErrorKind::Boxed(e.msg, None), code: Some(ErrorCode::Syntax) }
   }
 }
 
@@ -177,7 +199,7 @@ impl From<mm0b_parser::ParseError> for ElabError {
 /// Records the current reporting setting. A report that is suppressed by the reporting mode
 /// will not appear in the error list / as a diagnostic, but a fatal error will still prevent
 /// proof export.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 struct ReportMode {
   /// Do we report on errors?
   error: bool,
@@ -201,6 +223,231 @@ impl ReportMode {
   }
 }
 
+/// The settings of the elaborator that can be changed by `set-option`, and restored at the
+/// end of a declaration with an `@(options)` annotation.
+#[derive(Copy, Clone, Debug)]
+struct Options {
+  timeout: Option<Duration>,
+  stack_limit: usize,
+  check_proofs: bool,
+  reporting: ReportMode,
+  dep_policy: DepPolicy,
+  div_zero_error: bool,
+  suggest_proofs: bool,
+  pp: lisp::pretty::PpOptions,
+  warnings: WarningLevels,
+  slow_decl: Duration,
+}
+
+/// The prefix of an info message suggesting that the text at its span be replaced by the
+/// rest of the message. The language server offers a code action to apply the suggestion.
+pub const SUGGESTION: &str = "try this: ";
+
+/// The policy for dependencies and disjointness conditions that are not written
+/// explicitly, set by the `(set-dep-policy)` lisp command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DepPolicy {
+  /// Silently infer dependencies (the default).
+  Infer,
+  /// Infer dependencies, but report a warning for each one.
+  Warn,
+  /// Report an error for every inferred dependency.
+  Strict,
+}
+
+impl std::str::FromStr for DepPolicy {
+  type Err = String;
+  /// Parse a policy name, one of `infer`, `warn` or `strict`.
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "infer" => Ok(Self::Infer),
+      "warn" => Ok(Self::Warn),
+      "strict" => Ok(Self::Strict),
+      _ => Err(format!("unknown dependency policy '{}', expected infer, warn or strict", s)),
+    }
+  }
+}
+
+impl DepPolicy {
+  /// Construct a diagnostic at the level appropriate for this policy, or `None` if
+  /// inferred dependencies are not reported.
+  fn report(self, sp: Span, msg: String) -> Option<ElabError> {
+    match self {
+      Self::Infer => None,
+      Self::Warn => Some(ElabError::warn(sp, msg).with_code(ErrorCode::InferredDep)),
+      Self::Strict => Some(ElabError::new_e(sp, msg).with_code(ErrorCode::InferredDep)),
+    }
+  }
+}
+
+/// A class of warnings whose severity can be changed, using `set-option`, an `@(options)`
+/// annotation, or the `--warn` command line flag. Each category corresponds to an
+/// [`ErrorCode`], which is attached to the diagnostics in the category.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WarningCategory {
+  /// Variables that are declared but never used ([`UselessDummy`](ErrorCode::UselessDummy)).
+  Unused = 0,
+  /// Global lisp definitions that shadow a builtin ([`ShadowedBuiltin`](ErrorCode::ShadowedBuiltin)).
+  Shadowing = 1,
+  /// Uses of deprecated declarations ([`Deprecated`](ErrorCode::Deprecated)). These are
+  /// reported by lisp code, using `(report-at sp 'deprecated msg)`.
+  Deprecated = 2,
+  /// Declarations that take longer than the `slow-decl-ms` option to elaborate
+  /// ([`SlowDecl`](ErrorCode::SlowDecl)).
+  SlowDecl = 3,
+}
+
+impl WarningCategory {
+  /// All the warning categories, in order.
+  pub const ALL: [Self; 4] = [Self::Unused, Self::Shadowing, Self::Deprecated, Self::SlowDecl];
+
+  /// The name of the category, as used by `set-option` and on the command line.
+  #[must_use] pub fn name(self) -> &'static str {
+    match self {
+      Self::Unused => "unused",
+      Self::Shadowing => "shadowing",
+      Self::Deprecated => "deprecated",
+      Self::SlowDecl => "slow-decl",
+    }
+  }
+
+  /// Get the category with the given name.
+  #[must_use] pub fn from_bytes(s: &[u8]) -> Option<Self> {
+    Self::ALL.iter().copied().find(|c| c.name().as_bytes() == s)
+  }
+
+  /// The error code of the diagnostics in this category.
+  #[must_use] pub fn code(self) -> ErrorCode {
+    match self {
+      Self::Unused => ErrorCode::UselessDummy,
+      Self::Shadowing => ErrorCode::ShadowedBuiltin,
+      Self::Deprecated => ErrorCode::Deprecated,
+      Self::SlowDecl => ErrorCode::SlowDecl,
+    }
+  }
+
+  /// The category of diagnostics with error code `code`, if it is one of the categories.
+  #[must_use] pub fn of_code(code: ErrorCode) -> Option<Self> {
+    Self::ALL.iter().copied().find(|c| c.code() == code)
+  }
+
+  /// Parse a command line setting `cat=level`, where `level` is one of `off`, `info`,
+  /// `warn` or `error`.
+  pub fn parse_setting(s: &str) -> Result<(Self, Option<ErrorLevel>), String> {
+    let (cat, level) = s.split_once('=')
+      .ok_or_else(|| format!("expected CATEGORY=LEVEL, found '{}'", s))?;
+    let cat = Self::from_bytes(cat.as_bytes()).ok_or_else(|| format!(
+      "unknown warning category '{}', expected unused, shadowing, deprecated or slow-decl", cat))?;
+    let level = parse_severity(level.as_bytes()).ok_or_else(|| format!(
+      "unknown severity '{}', expected off, info, warn or error", level))?;
+    Ok((cat, level))
+  }
+}
+
+/// Parse the severity of a warning category, one of `off` (which suppresses the warnings),
+/// `info`, `warn` or `error`.
+#[must_use] pub fn parse_severity(s: &[u8]) -> Option<Option<ErrorLevel>> {
+  match s {
+    b"off" => Some(None),
+    b"info" => Some(Some(ErrorLevel::Info)),
+    b"warn" => Some(Some(ErrorLevel::Warning)),
+    b"error" => Some(Some(ErrorLevel::Error)),
+    _ => None,
+  }
+}
+
+/// The severity of each [`WarningCategory`], where `None` means that the warnings in the
+/// category are not reported at all. All categories are reported as warnings by default,
+/// except for [`SlowDecl`](WarningCategory::SlowDecl), which is off.
+#[derive(Copy, Clone, Debug)]
+pub struct WarningLevels([Option<ErrorLevel>; 4]);
+
+impl Default for WarningLevels {
+  fn default() -> Self {
+    let w = Some(ErrorLevel::Warning);
+    Self([w, w, w, None])
+  }
+}
+
+impl WarningLevels {
+  /// The severity of the warnings in category `cat`.
+  #[must_use] pub fn get(&self, cat: WarningCategory) -> Option<ErrorLevel> { self.0[cat as usize] }
+
+  /// Set the severity of the warnings in category `cat`.
+  pub fn set(&mut self, cat: WarningCategory, level: Option<ErrorLevel>) { self.0[cat as usize] = level }
+}
+
+/// What lisp code is allowed to do, checked when a builtin is called. The levels are
+/// ordered, and each one allows everything the previous ones do. Reporting messages and
+/// defining globals are allowed at every level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+  /// Only computation on lisp values, like arithmetic, lists and refs.
+  Pure,
+  /// Reading the environment (declarations, notations) and working on the proof state,
+  /// as tactics do.
+  EnvRead,
+  /// Adding declarations and changing the settings of the elaborator.
+  EnvWrite,
+  /// Effects outside of the current elaboration, like registering virtual files.
+  /// This is the default.
+  Io,
+}
+
+impl Capability {
+  /// The name of the capability level, as accepted by [`FromStr`](std::str::FromStr).
+  #[must_use] pub fn to_str(self) -> &'static str {
+    match self {
+      Self::Pure => "pure",
+      Self::EnvRead => "env-read",
+      Self::EnvWrite => "env-write",
+      Self::Io => "io",
+    }
+  }
+}
+
+impl std::fmt::Display for Capability {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { self.to_str().fmt(f) }
+}
+
+impl std::str::FromStr for Capability {
+  type Err = String;
+  /// Parse a capability level, one of `pure`, `env-read`, `env-write` or `io`.
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "pure" => Ok(Self::Pure),
+      "env-read" => Ok(Self::EnvRead),
+      "env-write" => Ok(Self::EnvWrite),
+      "io" => Ok(Self::Io),
+      _ => Err(format!("unknown capability '{}', expected pure, env-read, env-write or io", s)),
+    }
+  }
+}
+
+/// The capabilities of lisp code, depending on the file it comes from.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+  /// The maximum capability of all code.
+  pub max: Capability,
+  /// The capability of the code in each directory (or file). For nested directories,
+  /// the innermost one applies.
+  pub paths: Vec<(std::path::PathBuf, Capability)>,
+}
+
+impl Default for Capabilities {
+  fn default() -> Self { Self {max: Capability::Io, paths: vec![]} }
+}
+
+impl Capabilities {
+  /// The capability of code in `file`.
+  #[must_use] pub fn get(&self, file: &FileRef) -> Capability {
+    self.paths.iter()
+      .filter(|(dir, _)| file.path().starts_with(dir))
+      .max_by_key(|(dir, _)| dir.components().count())
+      .map_or(self.max, |&(_, c)| c.min(self.max))
+  }
+}
+
 /// A function that gets called on goal view events.
 #[allow(clippy::type_complexity)]
 pub struct GoalListener(Box<dyn for<'a> FnMut(&'a Elaborator, &'a str)>);
@@ -216,6 +463,22 @@ impl std::fmt::Debug for GoalListener {
   }
 }
 
+/// A function that gets called after each statement of a file is elaborated, with the
+/// number of statements that are done and the errors reported since the last call.
+#[allow(clippy::type_complexity)]
+pub struct StmtListener(Box<dyn FnMut(usize, &[ElabError]) + Send>);
+
+impl StmtListener {
+  /// Creates a new [`StmtListener`] from a callback.
+  pub fn new(f: impl FnMut(usize, &[ElabError]) + Send + 'static) -> Self { Self(Box::new(f)) }
+}
+
+impl std::fmt::Debug for StmtListener {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    "StmtListener".fmt(f)
+  }
+}
+
 /// The [`Elaborator`] struct contains the working data for elaboration, and is the
 /// main interface to MM1 operations (along with [`Evaluator`](lisp::eval::Evaluator),
 /// which a lisp execution context).
@@ -250,12 +513,35 @@ pub struct Elaborator {
   reporting: ReportMode,
   /// Should we report backtraces in lisp errors?
   backtrace: ReportMode,
+  /// Should `rw` and `rw-in` report each rewrite they perform (see `set-rw-trace`)?
+  rw_trace: bool,
   /// The handlers for different kinds of input and output.
   inout: InoutHandlers,
   /// The arena for lisp data.
   arena: lisp::LispArena,
   /// A listener for goal view events.
   recv_goal: Option<GoalListener>,
+  /// The renamings to apply to the names of imported declarations.
+  align: Arc<Alignment>,
+  /// The policy for reporting inferred dependencies.
+  dep_policy: DepPolicy,
+  /// Is division by zero in lisp an error, rather than returning zero (the default)?
+  div_zero_error: bool,
+  /// Should a theorem proved by a tactic script suggest its proof term as a replacement
+  /// for the script (see [`suggest`](Self::suggest))?
+  suggest_proofs: bool,
+  /// The severity of each category of warnings.
+  warnings: WarningLevels,
+  /// The elaboration time above which a declaration is reported in the
+  /// [`SlowDecl`](WarningCategory::SlowDecl) category (default 1 second)
+  slow_decl: Duration,
+  /// The capabilities of lisp code, by file (see [`set_capability`](crate::set_capability)).
+  caps: Arc<Capabilities>,
+  /// The maximum capability of lisp code in this elaboration, which is lowered to
+  /// [`EnvRead`](Capability::EnvRead) for an evaluation against a [`snapshot`].
+  capability: Capability,
+  /// The state for reusing `do` blocks from the previous elaboration of the file.
+  do_cache: do_cache::DoCacheState,
 }
 
 impl Deref for Elaborator {
@@ -285,11 +571,14 @@ impl Elaborator {
       mm0_mode: bool, check_proofs: bool, cancel: Arc<AtomicBool>,
       recv_goal: Option<GoalListener>,
     ) -> Elaborator {
+    let caps = crate::get_capabilities();
     Elaborator {
+      capability: caps.get(&path),
+      caps,
       ast, path, cancel,
       errors: Vec::new(),
       env: Environment::new(),
-      timeout: Some(Duration::from_secs(5)),
+      timeout: crate::get_timeout(),
       cur_timeout: None,
       stack_limit: 1024,
       lc: LocalContext::new(),
@@ -297,24 +586,73 @@ impl Elaborator {
       mm0_mode,
       check_proofs,
       backtrace: ReportMode {error: true, warn: false, info: false},
+      rw_trace: false,
       inout: InoutHandlers::default(),
       reporting: ReportMode::new(),
       arena: Default::default(),
       recv_goal,
+      align: crate::get_alignment(),
+      dep_policy: crate::get_dep_policy(),
+      div_zero_error: false,
+      suggest_proofs: false,
+      warnings: crate::get_warning_levels(),
+      slow_decl: Duration::from_secs(1),
+      do_cache: Default::default(),
     }
   }
 
   fn span(&self, s: Span) -> &[u8] { self.ast.span(s) }
 
+  fn options(&self) -> Options {
+    Options {
+      timeout: self.timeout,
+      stack_limit: self.stack_limit,
+      check_proofs: self.check_proofs,
+      reporting: self.reporting,
+      dep_policy: self.dep_policy,
+      div_zero_error: self.div_zero_error,
+      suggest_proofs: self.suggest_proofs,
+      pp: self.env.pp,
+      warnings: self.warnings,
+      slow_decl: self.slow_decl,
+    }
+  }
+
+  fn restore_options(&mut self, o: Options) {
+    self.timeout = o.timeout;
+    self.stack_limit = o.stack_limit;
+    self.check_proofs = o.check_proofs;
+    self.reporting = o.reporting;
+    self.dep_policy = o.dep_policy;
+    self.div_zero_error = o.div_zero_error;
+    self.suggest_proofs = o.suggest_proofs;
+    self.env.pp = o.pp;
+    self.warnings = o.warnings;
+    self.slow_decl = o.slow_decl;
+  }
+
   /// Converts a [`Span`] in the current elaboration file to a [`FileSpan`].
   pub fn fspan(&self, span: Span) -> FileSpan { FileSpan {file: self.path.clone(), span} }
 
   /// Report a non-fatal elaboration error. This method suppresses the error collection
-  /// if the error reporting level is low enough.
-  pub fn report(&mut self, e: ElabError) {
+  /// if the error reporting level is low enough. Diagnostics in a [`WarningCategory`] are
+  /// reported at the severity set for the category.
+  pub fn report(&mut self, mut e: ElabError) {
+    if let Some(cat) = e.code.and_then(WarningCategory::of_code) {
+      match self.warnings.get(cat) {
+        Some(level) => e.level = level,
+        None => return,
+      }
+    }
     if self.reporting.active(e.level) {self.errors.push(e)}
   }
 
+  /// Suggest replacing the source text at `sp` with `text`, for example to record a proof
+  /// found by a tactic. This is reported as an info message starting with [`SUGGESTION`].
+  pub fn suggest(&mut self, sp: Span, text: &str) {
+    self.report(ElabError::info(sp, format!("{}{}", SUGGESTION, text)))
+  }
+
   fn push_spans(&mut self) {
     self.env.spans.push(mem::take(&mut self.spans));
   }
@@ -337,7 +675,8 @@ impl Elaborator {
 
   fn elab_simple_nota(&mut self, n: &SimpleNota) -> Result<()> {
     let a = self.env.get_atom(self.ast.span(n.id));
-    let term = self.term(a).ok_or_else(|| ElabError::new_e(n.id, "term not declared"))?;
+    let term = self.term(a).ok_or_else(||
+      ElabError::new_e(n.id, "term not declared").with_code(ErrorCode::UnknownTerm))?;
     self.spans.insert(n.id, ObjectKind::Term(term, n.id));
     let tk: ArcString = self.span(n.c.trim).into();
     let (rassoc, nargs, lits) = match n.k {
@@ -370,16 +709,19 @@ impl Elaborator {
       SimpleNotaKind::Infix {..} => self.pe.add_infix(tk.clone(), info),
     }.map_err(|r| ElabError::with_info(n.id,
       format!("constant '{}' already declared", tk).into(),
-      vec![(r.decl1, "declared here".into())]))
+      vec![(r.decl1, "declared here".into())]).with_code(ErrorCode::Redeclaration))
   }
 
   fn elab_coe(&mut self, id: Span, from: Span, to: Span) -> Result<()> {
     let a_id = self.env.get_atom(self.ast.span(id));
     let a_from = self.env.get_atom(self.ast.span(from));
     let a_to = self.env.get_atom(self.ast.span(to));
-    let t = self.term(a_id).ok_or_else(|| ElabError::new_e(id, "term not declared"))?;
-    let s1 = self.data[a_from].sort.ok_or_else(|| ElabError::new_e(from, "sort not declared"))?;
-    let s2 = self.data[a_to].sort.ok_or_else(|| ElabError::new_e(to, "sort not declared"))?;
+    let t = self.term(a_id).ok_or_else(||
+      ElabError::new_e(id, "term not declared").with_code(ErrorCode::UnknownTerm))?;
+    let s1 = self.data[a_from].sort.ok_or_else(||
+      ElabError::new_e(from, "sort not declared").with_code(ErrorCode::UnknownSort))?;
+    let s2 = self.data[a_to].sort.ok_or_else(||
+      ElabError::new_e(to, "sort not declared").with_code(ErrorCode::UnknownSort))?;
     self.check_term_nargs(id, t, 1)?;
     self.spans.insert(id, ObjectKind::Term(t, id));
     self.spans.insert(from, ObjectKind::Sort(s1));
@@ -397,7 +739,7 @@ impl Elaborator {
     let fsp = self.fspan(tk);
     self.pe.add_const(s, fsp, p).map_err(|r| ElabError::with_info(tk,
       "constant already declared with a different precedence".into(),
-      vec![(r.decl1, "declared here".into())]))
+      vec![(r.decl1, "declared here".into())]).with_code(ErrorCode::Redeclaration))
   }
 
   fn elab_gen_nota(&mut self, nota: &GenNota) -> Result<()> {
@@ -410,7 +752,8 @@ impl Elaborator {
     }
 
     let a = self.env.get_atom(self.ast.span(nota.id));
-    let term = self.term(a).ok_or_else(|| ElabError::new_e(nota.id, "term not declared"))?;
+    let term = self.term(a).ok_or_else(||
+      ElabError::new_e(nota.id, "term not declared").with_code(ErrorCode::UnknownTerm))?;
     let nargs = nota.bis.len();
     self.check_term_nargs(nota.id, term, nargs)?;
     self.spans.insert(nota.id, ObjectKind::Term(term, nota.id));
@@ -433,7 +776,7 @@ impl Elaborator {
 
     let mut get_var = |sp: Span| -> Result<usize> {
       let v = vars.get_mut(ast.span(sp))
-        .ok_or_else(|| ElabError::new_e(sp, "variable not found"))?;
+        .ok_or_else(|| ElabError::new_e(sp, "variable not found").with_code(ErrorCode::UnknownVariable))?;
       v.1 = true;
       Ok(v.0)
     };
@@ -507,7 +850,7 @@ impl Elaborator {
     else { self.pe.add_prefix(s.clone(), info) }
       .map_err(|r| ElabError::with_info(nota.id,
         format!("constant '{}' already declared", s).into(),
-        vec![(r.decl1, "declared here".into())]))
+        vec![(r.decl1, "declared here".into())]).with_code(ErrorCode::Redeclaration))
   }
 
   fn parse_and_print(&mut self, e: &SExpr, doc: String) -> Result<()> {
@@ -541,7 +884,119 @@ impl Elaborator {
 /// The result type of [`Elaborator::elab_stmt`].
 enum ElabStmt { Ok, Import(Span) }
 
+/// A pattern for matching diagnostics in an `@(allow)` or `@(expect)` annotation.
+#[derive(Copy, Clone)]
+enum DiagPattern {
+  /// Matches all diagnostics at the given level, written `error` or `warning`.
+  Level(ErrorLevel),
+  /// Matches all diagnostics with the given code, written `E0001` or `syntax`.
+  Code(ErrorCode),
+}
+
+impl DiagPattern {
+  fn matches(self, e: &ElabError) -> bool {
+    match self {
+      DiagPattern::Level(l) => e.level == l,
+      DiagPattern::Code(c) => e.code == Some(c),
+    }
+  }
+}
+
+/// An annotation which is handled by the elaborator, rather than by the `annotate` callback.
+struct BuiltinAnnot {
+  /// True for `@(expect)`, false for `@(allow)`.
+  expect: bool,
+  /// The diagnostics that are allowed or expected.
+  pats: Vec<DiagPattern>,
+}
+
 impl Elaborator {
+  /// Parse the annotation `e` if it is `(allow ...)` or `(expect ...)`. These are recognized
+  /// syntactically, so they do not depend on the definition of `annotate`.
+  fn builtin_annot(&self, e: &SExpr) -> Result<Option<BuiltinAnnot>> {
+    let es = if let SExprKind::List(es) = &e.k { es } else { return Ok(None) };
+    let expect = match es.first() {
+      Some(&SExpr {span, k: SExprKind::Atom(ast::Atom::Ident)}) => match self.span(span) {
+        b"allow" => false,
+        b"expect" => true,
+        _ => return Ok(None),
+      },
+      _ => return Ok(None),
+    };
+    let mut pats = vec![];
+    for arg in &es[1..] {
+      let s = match arg.k {
+        SExprKind::Atom(ast::Atom::Ident) => self.span(arg.span),
+        _ => return Err(ElabError::new_e(arg.span, "expected an error code")),
+      };
+      pats.push(match s {
+        b"error" if expect => DiagPattern::Level(ErrorLevel::Error),
+        b"warning" if expect => DiagPattern::Level(ErrorLevel::Warning),
+        _ => {
+          let code = std::str::from_utf8(s).ok().and_then(|s|
+            ErrorCode::from_str(s).or_else(|| ErrorCode::from_name(s)));
+          DiagPattern::Code(code.ok_or_else(|| {
+            let mut names = vec![];
+            ErrorCode::for_each(|c| names.push(c.name().as_bytes()));
+            ElabError::new_e(arg.span, format!("unknown error code '{}'{}",
+              String::from_utf8_lossy(s), crate::did_you_mean(s, names)))
+          })?)
+        }
+      })
+    }
+    if pats.is_empty() {
+      if !expect { return Err(ElabError::new_e(e.span, "expected an error code")) }
+      pats.push(DiagPattern::Level(ErrorLevel::Error))
+    }
+    Ok(Some(BuiltinAnnot {expect, pats}))
+  }
+
+  /// Elaborate the statement `s` with an `@(options k1 v1 k2 v2 ...)` annotation, where
+  /// `es` is the list of keys and values. The options are set as by `set-option` for the
+  /// duration of `s`, and restored afterward.
+  fn elab_options_annot(&mut self, es: &[SExpr], doc: String, s: &Stmt, span: Span) -> Result<ElabStmt> {
+    if es.len() % 2 != 0 {
+      return Err(ElabError::new_e(es[es.len() - 1].span, "expected a value for the option"))
+    }
+    let old = self.options();
+    let mut res = Ok(());
+    for kv in es.chunks_exact(2) {
+      res = match kv[0].k {
+        SExprKind::Atom(ast::Atom::Ident) => self.eval_lisp(&kv[1]).and_then(|v| {
+          let k = self.span(kv[0].span).to_owned();
+          self.set_option(&k, &v).map_err(|e| ElabError::new_e(kv[0].span, e))
+        }),
+        _ => Err(ElabError::new_e(kv[0].span, "expected an option name")),
+      };
+      if res.is_err() { break }
+    }
+    let res = res.and_then(|()| self.elab_stmt(doc, s, span));
+    self.restore_options(old);
+    res
+  }
+
+  /// Elaborate the statement `s` with the annotation `ann`, which is at `sp`. Warnings
+  /// matching an `@(allow)` annotation are removed from the diagnostics of the statement.
+  /// Diagnostics matching an `@(expect)` annotation are also removed, and it is an error if
+  /// there are none, including errors that would otherwise stop the elaboration of `s`.
+  fn elab_builtin_annot(&mut self, sp: Span, ann: &BuiltinAnnot, doc: String, s: &Stmt, span: Span) {
+    let start = self.errors.len();
+    if let Err(e) = self.elab_stmt(doc, s, span) { self.report(e) }
+    let new = self.errors.split_off(start);
+    let mut found = false;
+    for e in new {
+      if ann.pats.iter().any(|p| p.matches(&e)) && (ann.expect || e.level != ErrorLevel::Error) {
+        found = true
+      } else {
+        self.errors.push(e)
+      }
+    }
+    if ann.expect && !found {
+      self.report(ElabError::new_e(sp, "the expected error was not reported")
+        .with_code(ErrorCode::UnmetExpectation))
+    }
+  }
+
   /// Elaborates a single statement.
   ///
   /// # Returns
@@ -565,7 +1020,19 @@ impl Elaborator {
         let id = self.add_sort(a, fsp, span, sd, to_doc(doc)).map_err(|e| e.into_elab_error(sp))?;
         self.spans.insert(sp, ObjectKind::Sort(id));
       }
-      StmtKind::Decl(d) => self.elab_decl(span, d, to_doc(doc))?,
+      StmtKind::Decl(d) => {
+        let x = LispVal::atom(self.env.get_atom(self.ast.span(d.id)));
+        self.call_hook(d.id, AtomId::BEFORE_DECL, vec![x.clone()])?;
+        let start = Instant::now();
+        self.elab_decl(span, d, to_doc(doc))?;
+        let elapsed = start.elapsed();
+        if crate::profile::enabled() { crate::profile::record(self.ast.span(d.id), elapsed) }
+        if elapsed > self.slow_decl && self.warnings.get(WarningCategory::SlowDecl).is_some() {
+          self.report(ElabError::warn(d.id, format!("declaration took {}ms to elaborate",
+            elapsed.as_millis())).with_code(ErrorCode::SlowDecl))
+        }
+        self.call_hook(d.id, AtomId::AFTER_DECL, vec![x])?;
+      }
       StmtKind::Delimiter(Delimiter::Both(f)) => self.pe.add_delimiters(f, f),
       StmtKind::Delimiter(Delimiter::LeftRight(ls, rs)) => self.pe.add_delimiters(ls, rs),
       StmtKind::SimpleNota(n) => self.elab_simple_nota(n)?,
@@ -574,18 +1041,33 @@ impl Elaborator {
       &StmtKind::Import(sp, _) => return Ok(ElabStmt::Import(sp)),
       StmtKind::Do(es) => {
         if self.mm0_mode {
-          self.report(ElabError::warn(span, "(MM0 mode) do blocks not allowed"))
+          self.report(ElabError::warn(span, "(MM0 mode) do blocks not allowed").with_code(ErrorCode::Mm0Mode))
         }
-        for e in es { self.parse_and_print(e, mem::take(&mut doc))? }
+        self.elab_do(span, es, mem::take(&mut doc))?
       }
       StmtKind::Annot(e, s) => {
+        if let SExprKind::List(es) = &e.k {
+          if let Some(&SExpr {span: sp, k: SExprKind::Atom(ast::Atom::Ident)}) = es.first() {
+            if self.span(sp) == b"options" { return self.elab_options_annot(&es[1..], doc, s, span) }
+          }
+        }
+        if let Some(ann) = self.builtin_annot(e)? {
+          self.elab_builtin_annot(e.span, &ann, doc, s, span);
+          return Ok(ElabStmt::Ok)
+        }
         let v = self.eval_lisp(e)?;
         self.elab_stmt(doc, s, span)?;
         let ann = match &self.data[AtomId::ANNOTATE].lisp {
           Some(e) => e.val.clone(),
           None => return Err(ElabError::new_e(e.span, "define 'annotate' before using annotations")),
         };
-        let args = vec![v, self.name_of(s)];
+        let name = self.name_of(s);
+        if let Some(a) = name.as_atom().filter(|&a| a != AtomId::UNDER) {
+          // Annotations are elaborated innermost first, so this keeps them in source order
+          let attr: ArcString = self.span(e.span).into();
+          self.env.attrs.entry(a).or_default().insert(0, attr);
+        }
+        let args = vec![v, name];
         self.call_func(e.span, ann, args)?;
       },
       StmtKind::DocComment(doc2, s) => {
@@ -609,10 +1091,9 @@ pub enum ElabResult<T> {
   Ok(T, Option<Arc<[ElabError]>>, FrozenEnv),
   /// The elaboration was canceled.
   Canceled,
-  /// The dependent file could not be elaborated because of an import cycle.
-  /// The provided list does not contain the target file itself, but contains a [`FileRef`]
-  /// that is equal to the target file, demonstrating the cycle.
-  ImportCycle(ArcList<FileRef>)
+  /// The dependent file could not be elaborated because it is part of, or depends on,
+  /// this import cycle.
+  ImportCycle(ImportCycle)
 }
 
 
@@ -636,6 +1117,8 @@ pub struct ElaborateBuilder<'a, F> {
   /// The last successful parse of the same file, used for incremental elaboration.
   /// A value of `Some((idx, errs, env))` means that the new file first differs from the
   /// old one at `idx`, and the last parse produced environment `env` with errors `errs`.
+  /// The `do` blocks of `env` that only define tactics are reused if nothing they depend
+  /// on has changed (see [`do_cache`]).
   #[allow(clippy::type_complexity)]
   pub old: Option<(usize, Option<Arc<[ElabError]>>, FrozenEnv)>,
   /// A function which is called when an `import` is encountered, with the [`FileRef`] of
@@ -648,6 +1131,14 @@ pub struct ElaborateBuilder<'a, F> {
   /// to transfer an [`Environment`] containing the elaborated theorems, as well as any
   /// extra data `T`, which is collected and passed through the function.
   pub recv_goal: Option<GoalListener>,
+  /// A file to import implicitly before the first statement of the file (see
+  /// [`set_prelude`](crate::set_prelude)). It is requested from `recv_dep` like any other
+  /// import.
+  pub prelude: Option<FileRef>,
+  /// A function which is called after each statement, so that the errors can be shown
+  /// before the whole file is done. When the elaboration finishes, it is called once more
+  /// with the errors that have not been passed to it yet.
+  pub on_stmt: Option<StmtListener>,
 }
 
 impl<'a, T: Send, F> ElaborateBuilder<'a, F>
@@ -659,15 +1150,15 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
   /// A [`Future`] which returns `(cyc, toks, errs, env)` with
   ///
   /// - `cyc`: An import cycle that forced this elaboration to halt, if one was found.
-  /// - `toks`: The accumulated `T` values passed from `mk` (in the order that `import` statements
-  ///   appeared in the file)
+  /// - `toks`: The accumulated `T` values passed from `mk` (for the prelude, and then in the
+  ///   order that `import` statements appeared in the file)
   /// - `errs`: The elaboration errors found
   /// - `env`: The final environment
   ///
   /// If elaboration of an individual statement fails, the error is pushed and then elaboration
   /// continues at the next statement, so the overall elaboration process cannot fail and an
   /// environment is always produced.
-  pub fn elab(self) -> impl Future<Output=(Option<ArcList<FileRef>>, Vec<T>, Vec<ElabError>, FrozenEnv)> + Send {
+  pub fn elab(self) -> impl Future<Output=(Option<ImportCycle>, Vec<T>, Vec<ElabError>, FrozenEnv)> + Send {
 
     type ImportMap<D> = HashMap<Span, (FileRef, D)>;
     struct FrozenElaborator(Elaborator);
@@ -677,33 +1168,46 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
     enum UnfinishedStmt<T> {
       None,
       Import(Span, FileRef, Receiver<ElabResult<T>>),
+      Prelude(Span, FileRef, Receiver<ElabResult<T>>),
     }
 
     struct ElabFutureInner<T> {
       elab: FrozenElaborator,
       toks: Vec<T>,
       report_upstream_errors: bool,
-      cyc: Option<ArcList<FileRef>>,
+      cyc: Option<ImportCycle>,
       recv: ImportMap<Receiver<ElabResult<T>>>,
       idx: usize,
-      progress: UnfinishedStmt<T>
+      progress: UnfinishedStmt<T>,
+      on_stmt: Option<StmtListener>,
+      /// The number of errors that have been passed to `on_stmt`.
+      reported: usize,
+    }
+
+    fn notify(on_stmt: &mut Option<StmtListener>, reported: &mut usize,
+        idx: usize, errs: &[ElabError]) {
+      if let Some(StmtListener(f)) = on_stmt {
+        f(idx, &errs[*reported..]);
+        *reported = errs.len()
+      }
     }
 
     struct ElabFuture<T>(Option<ElabFutureInner<T>>);
 
     impl<T> Future for ElabFuture<T> {
-      type Output = (Option<ArcList<FileRef>>, Vec<T>, Vec<ElabError>, FrozenEnv);
+      type Output = (Option<ImportCycle>, Vec<T>, Vec<ElabError>, FrozenEnv);
       fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = &mut unsafe { self.get_unchecked_mut() }.0;
         let ElabFutureInner {
           elab: FrozenElaborator(elab),
-          cyc, toks, recv, idx, progress, report_upstream_errors
+          cyc, toks, recv, idx, progress, report_upstream_errors, on_stmt, reported
         } = this.as_mut().expect("poll called after Ready");
         elab.arena.install_thread_local();
         'l: loop {
+          let prelude = matches!(progress, UnfinishedStmt::Prelude(..));
           match progress {
             UnfinishedStmt::None => {},
-            UnfinishedStmt::Import(sp, p, other) => {
+            UnfinishedStmt::Import(sp, p, other) | UnfinishedStmt::Prelude(sp, p, other) => {
               match ready!(unsafe { Pin::new_unchecked(other) }.poll(cx)) {
                 Ok(ElabResult::Ok(t, errors, env)) => {
                   toks.push(t);
@@ -720,13 +1224,14 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
                             p.clone()
                           };
                           let e = OwningRef::new(errs).map(|errs| &errs[i]);
-                          elab.report(ElabError {pos: *sp, level, kind: ElabErrorKind::Upstream(file, e, n)});
+                          elab.report(ElabError {pos: *sp, level, kind: ElabErrorKind::Upstream(file, e, n), code: None});
                           break
                         }
                       }
                     }
                   }
-                  let mut it = EnvMergeIter::new(&mut elab.env, &env, *sp);
+                  elab.do_cache.import(&env);
+                  let mut it = EnvMergeIter::new(&mut elab.env, &env, &elab.align, *sp);
                   loop {
                     match it.next(&mut elab.env, &mut elab.errors) {
                       Err(e) => {elab.report(e); break}
@@ -746,20 +1251,22 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
                   break
                 }
                 Ok(ElabResult::ImportCycle(cyc2)) => {
-                  use std::fmt::Write;
-                  let mut s = format!("import cycle: {}", p.clone());
-                  for p2 in &cyc2 { write!(&mut s, " -> {}", p2).unwrap() }
-                  elab.report(ElabError::new_e(*sp, s));
+                  let msg = crate::imports::cycle_msg(&elab.path, p, &cyc2);
+                  let info = cyc2.iter()
+                    .map(|(fsp, f)| (fsp.clone(), format!("imports {}", f).into())).collect();
+                  elab.report(ElabError::with_info(*sp, msg.into(), info)
+                    .with_code(ErrorCode::ImportCycle));
                   if cyc.is_none() { *cyc = Some(cyc2) }
                 }
                 Err(_) => {} // already handled
               }
-              *idx += 1;
+              if !prelude { *idx += 1 }
             }
           }
           let ast = elab.ast.clone();
           while let Some(s) = ast.stmts.get(*idx) {
             if elab.cancel.load(Ordering::Relaxed) {break}
+            let mutations = elab.do_cache.mutations;
             match elab.elab_stmt(String::new(), s, s.span) {
               Ok(ElabStmt::Ok) => {}
               Ok(ElabStmt::Import(sp)) => {
@@ -772,34 +1279,47 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
               }
               Err(e) => elab.report(e)
             }
+            elab.do_cache_stmt(s, mutations);
             elab.push_spans();
             *idx += 1;
+            notify(on_stmt, reported, *idx, &elab.errors);
           }
           break
         }
         lisp::LispArena::uninstall_thread_local();
-        let ElabFutureInner {elab: FrozenElaborator(elab), cyc, toks, ..} =
+        let ElabFutureInner {elab: FrozenElaborator(elab), cyc, toks, mut on_stmt, mut reported, ..} =
           this.take().expect("impossible");
+        notify(&mut on_stmt, &mut reported, elab.ast.stmts.len(), &elab.errors);
         elab.arena.clear();
-        Poll::Ready((cyc, toks, elab.errors, FrozenEnv::new(elab.env)))
+        let mut env = elab.env;
+        elab.do_cache.finish(&mut env.do_cache);
+        Poll::Ready((cyc, toks, elab.errors, FrozenEnv::new(env)))
       }
     }
 
+    crate::clear_virtual_files(&self.path);
     let mut recv_dep = self.recv_dep;
     let mut recv = HashMap::new();
     let mut elab = Elaborator::new(self.ast.clone(),
       self.path, self.mm0_mode, self.check_proofs, self.cancel, self.recv_goal);
+    elab.do_cache = do_cache::DoCacheState::new(self.old.map(|(_, _, env)| env),
+      self.ast.source.as_bytes());
     elab.arena.install_thread_local();
     for &(sp, ref f) in &self.ast.imports {
       (|| -> Result<_> {
-        let f = std::str::from_utf8(f).map_err(|e| ElabError::new_e(sp, e))?;
-        let path = elab.path.path().parent().map_or_else(|| PathBuf::from(f), |p| p.join(f));
-        let r: FileRef = path.canonicalize().map_err(|e| ElabError::new_e(sp, e))?.into();
+        let r = crate::imports::resolve(&elab.path, f).map_err(|e| ElabError::new_e(sp, e))?;
         let tok = recv_dep(r.clone()).map_err(|e| ElabError::new_e(sp, e))?;
         recv.insert(sp, (r, tok));
         Ok(())
       })().unwrap_or_else(|e| elab.report(e));
     }
+    let mut progress = UnfinishedStmt::None;
+    if let Some(p) = self.prelude {
+      match recv_dep(p.clone()) {
+        Ok(tok) => progress = UnfinishedStmt::Prelude((0..0).into(), p, tok),
+        Err(e) => elab.report(ElabError::new_e(0..0, format!("prelude {}: {}", p, e))),
+      }
+    }
     lisp::LispArena::uninstall_thread_local();
     ElabFuture(Some(ElabFutureInner {
       elab: FrozenElaborator(elab),
@@ -808,7 +1328,31 @@ where F: FnMut(FileRef) -> Result<Receiver<ElabResult<T>>, BoxError> {
       cyc: None,
       recv,
       idx: 0,
-      progress: UnfinishedStmt::None,
+      progress,
+      on_stmt: self.on_stmt,
+      reported: 0,
     }))
   }
+
+  /// Like [`elab`](Self::elab), but guaranteed not to panic, even on arbitrary input.
+  /// If the elaborator panics (which is a bug), the panic is caught and the result is an
+  /// empty environment with a single error at the start of the file. This is the entry
+  /// point for fuzzing, and is used by the server so that malformed input cannot bring it
+  /// down.
+  pub fn elab_no_panic(self) -> impl Future<Output=(Option<ImportCycle>, Vec<T>, Vec<ElabError>, FrozenEnv)> + Send {
+    use futures::FutureExt;
+    let fut = std::panic::catch_unwind(AssertUnwindSafe(|| self.elab()));
+    async move {
+      let res = match fut {
+        Ok(fut) => AssertUnwindSafe(fut).catch_unwind().await,
+        Err(e) => Err(e),
+      };
+      res.unwrap_or_else(|e| {
+        // The panic may have happened while the lisp arena was installed
+        lisp::LispArena::uninstall_thread_local();
+        let msg = format!("internal error: elaborator panicked: {}", crate::panic_message(&*e));
+        (None, vec![], vec![ElabError::new_e(0..0, msg)], FrozenEnv::new(Environment::new()))
+      })
+    }
+  }
 }
\ No newline at end of file
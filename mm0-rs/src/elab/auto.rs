@@ -0,0 +1,137 @@
+//! The `auto` tactic, a bounded backward-chaining proof search.
+//!
+//! The search uses the theorems in the hint database ([`Environment::hints`]), which is
+//! populated using `(add-hint)`. To prove a goal, it first looks for a hypothesis in the
+//! local context with exactly the same statement, and otherwise tries every hint whose
+//! conclusion matches the goal, recursively proving its hypotheses. Hints whose
+//! hypotheses contain variables that do not appear in the conclusion are skipped, because
+//! the search does not invent terms. The depth is increased one step at a time up to the
+//! given bound (iterative deepening), so the shortest proof is found first, and the search
+//! gives up when the time budget runs out.
+//!
+//! The order in which the hints are tried can be changed by defining the `rank-premises`
+//! hook, which allows an external ranker (for example a machine learning model) to guide
+//! the search.
+//!
+//! The result is a `refine` script, which is checked by the usual `refine` machinery, so
+//! the search itself does not need to check sorts or disjointness conditions.
+use std::time::Instant;
+use crate::{AtomId, Environment, ExprNode, Span, ThmId, Type};
+use super::{Elaborator, ElabError, Result};
+use super::disc_tree::flatten_lisp;
+use super::lisp::{LispVal, Uncons};
+
+/// The state of a search for a single goal.
+struct Auto<'a> {
+  elab: &'a mut Elaborator,
+  /// The span of the `auto` call.
+  sp: Span,
+  /// The hypotheses of the local context, with shadowed hypotheses removed.
+  hyps: Vec<(AtomId, LispVal)>,
+  deadline: Instant,
+}
+
+/// Match the expression `p` from the statement of a theorem with heap `heap` against the
+/// term `e`, assigning the theorem variables in `subst`.
+fn matches(env: &Environment, heap: &[ExprNode], subst: &mut [Option<LispVal>],
+  p: &ExprNode, e: &LispVal
+) -> bool {
+  match *p {
+    ExprNode::Ref(i) if i < subst.len() => match &subst[i] {
+      Some(v) => v == e,
+      None => { subst[i] = Some(e.clone()); true }
+    },
+    ExprNode::Ref(i) => matches(env, heap, subst, &heap[i], e),
+    ExprNode::Dummy(..) => false,
+    ExprNode::App(t, ref ps) => {
+      let mut u = Uncons::from(e.clone());
+      u.next().and_then(|a| a.as_atom()) == Some(env.terms[t].atom) &&
+      u.len() == ps.len() && ps.iter().zip(u).all(|(p, e)| matches(env, heap, subst, p, &e))
+    }
+  }
+}
+
+/// Substitute the theorem variables in the expression `p`, or return `None`
+/// if some variable is not assigned.
+fn inst(env: &Environment, heap: &[ExprNode], subst: &[Option<LispVal>], p: &ExprNode) -> Option<LispVal> {
+  match *p {
+    ExprNode::Ref(i) if i < subst.len() => subst[i].clone(),
+    ExprNode::Ref(i) => inst(env, heap, subst, &heap[i]),
+    ExprNode::Dummy(..) => None,
+    ExprNode::App(t, ref ps) => {
+      let mut args = vec![LispVal::atom(env.terms[t].atom)];
+      for p in &**ps { args.push(inst(env, heap, subst, p)?) }
+      Some(LispVal::list(args))
+    }
+  }
+}
+
+impl Auto<'_> {
+  /// Try to apply hint `t` to the goal `tgt`, proving the new subgoals with depth `depth`.
+  fn apply(&mut self, t: ThmId, tgt: &LispVal, depth: usize) -> Option<LispVal> {
+    let env = &self.elab.env;
+    let td = &env.thms[t];
+    let mut subst = vec![None; td.args.len()];
+    if !matches(env, &td.heap, &mut subst, &td.ret, tgt) { return None }
+    for (s, &(_, ty)) in subst.iter().zip(&*td.args) {
+      if let (Some(e), Type::Bound(_)) = (s, ty) {
+        if e.as_atom().is_none() { return None }
+      }
+    }
+    let hyps = td.hyps.iter().map(|(_, h)| inst(env, &td.heap, &subst, h)).collect::<Option<Vec<_>>>()?;
+    let mut args = vec![LispVal::atom(td.atom)];
+    for h in &hyps { args.push(self.prove(h, depth)?) }
+    Some(if args.len() == 1 { args.pop().expect("nonempty") } else { LispVal::list(args) })
+  }
+
+  /// Order the hints that may apply to the goal `tgt` using the `rank-premises` hook, if
+  /// the user has defined it. The hook is called as `(rank-premises tgt candidates)`, where
+  /// `candidates` is the list of names of the hints in the default order, and returns a list
+  /// of hint names to try in that order. Errors in the hook are reported, and the default
+  /// order is used instead.
+  fn rank(&mut self, tgt: &LispVal, candidates: Vec<ThmId>) -> Vec<ThmId> {
+    let f = if let Some(d) = &self.elab.data[AtomId::RANK_PREMISES].lisp {d.val.clone()} else {
+      return candidates
+    };
+    let list = LispVal::list(candidates.iter()
+      .map(|&t| LispVal::atom(self.elab.thms[t].atom)).collect::<Vec<_>>());
+    let res = self.elab.call_func(self.sp, f, vec![tgt.clone(), list]).and_then(|res| {
+      Uncons::from(res).map(|e| e.as_atom().and_then(|a| self.elab.thm(a))
+        .ok_or_else(|| ElabError::new_e(self.sp, format!(
+          "rank-premises: expected a list of theorems, got {}", self.elab.print(&e)))))
+        .collect::<Result<Vec<_>>>()
+    });
+    match res {
+      Ok(ranked) => ranked,
+      Err(e) => { self.elab.report(e); candidates }
+    }
+  }
+
+  /// Find a proof of `tgt` with the given maximum depth.
+  fn prove(&mut self, tgt: &LispVal, depth: usize) -> Option<LispVal> {
+    if let Some((h, _)) = self.hyps.iter().find(|(_, e)| e == tgt) {
+      return Some(LispVal::atom(*h))
+    }
+    if depth == 0 || Instant::now() > self.deadline { return None }
+    let env = &self.elab.env;
+    let mut keys = vec![];
+    flatten_lisp(env, tgt, &mut keys);
+    let candidates = env.thm_index.unifiable(&keys);
+    let hints = env.hints.iter().copied().filter(|t| candidates.binary_search(t).is_ok()).collect();
+    self.rank(tgt, hints).into_iter().find_map(|t| self.apply(t, tgt, depth - 1))
+  }
+}
+
+impl Elaborator {
+  /// Search for a proof of `tgt` by backward chaining with the hint database, using
+  /// proofs of depth at most `depth`, and giving up at time `deadline`. Returns a
+  /// `refine` script for the goal. `sp` is the span used for calling the `rank-premises`
+  /// hook and reporting its errors.
+  pub(crate) fn auto(&mut self, sp: Span, tgt: &LispVal, depth: usize, deadline: Instant) -> Option<LispVal> {
+    let hyps = self.lc.proof_order.iter().enumerate()
+      .filter(|&(i, (a, _, _))| self.lc.proofs.get(a) == Some(&i))
+      .map(|(_, (a, e, _))| (*a, e.clone())).collect();
+    let mut auto = Auto {elab: self, sp, hyps, deadline};
+    (0..=depth).find_map(|d| auto.prove(tgt, d))
+  }
+}
@@ -0,0 +1,363 @@
+//! Comparing declarations between two environments.
+//!
+//! Declarations in different environments can't be compared directly, because the
+//! sorts, terms and atoms they refer to have different indexes in each environment.
+//! Here they are matched up by name instead, so that for example the statement of a
+//! theorem in an `.mm1` file can be checked against the statement of the same theorem
+//! in the `.mm0` file that specifies it, before the two are joined and verified.
+//! Expressions are compared up to the sharing of subterms (the heap) and the renaming
+//! of dummy variables, so they only need to be the same after unfolding.
+//! This is also used when merging imports, to identify a declaration that is imported
+//! along two paths with the same statement (see [`Environment::check_duplicate`]).
+
+use std::collections::{HashMap, HashSet};
+use crate::{AtomId, DeclKey, Environment, ExprNode, FormatEnv, FrozenEnv, LinedString, SortId,
+  StmtTrace, TermId, TermKind, ThmId, ThmKind, Type, FileSpan, Modifiers, ProofNode};
+
+/// Compares declarations of environment `a` with declarations of the same name in
+/// environment `b`.
+#[derive(Debug)]
+pub struct Comparer<'a> {
+  a: &'a Environment,
+  b: &'a Environment,
+  /// The matching of dummy variables in the expressions being compared, in both directions.
+  dummies: (HashMap<AtomId, AtomId>, HashMap<AtomId, AtomId>),
+  /// The pairs of heap elements of the proofs being compared that are known to be equal.
+  shared: HashSet<(usize, usize)>,
+}
+
+/// An expression together with its context, the heap and number of variables.
+struct Ctx<'a, T = ExprNode> { heap: &'a [T], nargs: usize }
+
+impl<T> Clone for Ctx<'_, T> { fn clone(&self) -> Self { *self } }
+impl<T> Copy for Ctx<'_, T> {}
+
+impl<'a> Comparer<'a> {
+  /// Create a new comparer for environments `a` and `b`.
+  #[must_use] pub fn new(a: &'a FrozenEnv, b: &'a FrozenEnv) -> Self {
+    // Safety: the comparer only reads the declarations and atom names
+    unsafe { Self::new_env(a.thaw(), b.thaw()) }
+  }
+
+  /// Create a new comparer for environments `a` and `b`, which need not be frozen.
+  #[must_use] pub fn new_env(a: &'a Environment, b: &'a Environment) -> Self {
+    Self { a, b, dummies: Default::default(), shared: Default::default() }
+  }
+
+  fn sort_eq(&self, s1: SortId, s2: SortId) -> bool {
+    self.a.sorts[s1].name == self.b.sorts[s2].name
+  }
+
+  fn term_eq(&self, t1: TermId, t2: TermId) -> bool {
+    self.a.data[self.a.terms[t1].atom].name == self.b.data[self.b.terms[t2].atom].name
+  }
+
+  fn thm_eq(&self, t1: ThmId, t2: ThmId) -> bool {
+    self.a.data[self.a.thms[t1].atom].name == self.b.data[self.b.thms[t2].atom].name
+  }
+
+  fn type_eq(&self, ty1: Type, ty2: Type) -> bool {
+    match (ty1, ty2) {
+      (Type::Bound(s1), Type::Bound(s2)) => self.sort_eq(s1, s2),
+      (Type::Reg(s1, d1), Type::Reg(s2, d2)) => d1 == d2 && self.sort_eq(s1, s2),
+      _ => false,
+    }
+  }
+
+  /// Compare binder lists. Returns a description of the first difference.
+  fn binders(&self, bis1: &[(Option<AtomId>, Type)], bis2: &[(Option<AtomId>, Type)]
+  ) -> Result<(), String> {
+    for (i, ((_, ty1), (_, ty2))) in bis1.iter().zip(bis2.iter()).enumerate() {
+      if !self.type_eq(*ty1, *ty2) { return Err(format!("binder {} differs", i + 1)) }
+    }
+    if bis1.len() != bis2.len() {
+      return Err(format!("expected {} binders, found {}", bis1.len(), bis2.len()))
+    }
+    Ok(())
+  }
+
+  fn expr_eq(&mut self, c1: Ctx<'_>, e1: &ExprNode, c2: Ctx<'_>, e2: &ExprNode) -> bool {
+    match (e1, e2) {
+      (&ExprNode::Ref(i), _) if i >= c1.nargs => self.expr_eq(c1, &c1.heap[i], c2, e2),
+      (_, &ExprNode::Ref(j)) if j >= c2.nargs => self.expr_eq(c1, e1, c2, &c2.heap[j]),
+      (&ExprNode::Ref(i), &ExprNode::Ref(j)) => i == j,
+      (&ExprNode::Dummy(a1, s1), &ExprNode::Dummy(a2, s2)) => self.dummy_eq((a1, s1), (a2, s2)),
+      (ExprNode::App(t1, es1), ExprNode::App(t2, es2)) =>
+        self.term_eq(*t1, *t2) && es1.len() == es2.len() &&
+        es1.iter().zip(&**es2).all(|(e1, e2)| self.expr_eq(c1, e1, c2, e2)),
+      _ => false,
+    }
+  }
+
+  fn dummy_eq(&mut self, (a1, s1): (AtomId, SortId), (a2, s2): (AtomId, SortId)) -> bool {
+    self.sort_eq(s1, s2) &&
+    *self.dummies.0.entry(a1).or_insert(a2) == a2 &&
+    *self.dummies.1.entry(a2).or_insert(a1) == a1
+  }
+
+  fn proofs_eq(&mut self, c1: Ctx<'_, ProofNode>, ps1: &[ProofNode],
+    c2: Ctx<'_, ProofNode>, ps2: &[ProofNode]
+  ) -> bool {
+    ps1.len() == ps2.len() && ps1.iter().zip(ps2).all(|(p1, p2)| self.proof_eq(c1, p1, c2, p2))
+  }
+
+  fn proof_eq(&mut self, c1: Ctx<'_, ProofNode>, p1: &ProofNode,
+    c2: Ctx<'_, ProofNode>, p2: &ProofNode
+  ) -> bool {
+    match (p1, p2) {
+      (&ProofNode::Ref(i), &ProofNode::Ref(j)) if i >= c1.nargs && j >= c2.nargs => {
+        if self.shared.contains(&(i, j)) { return true }
+        let res = self.proof_eq(c1, &c1.heap[i], c2, &c2.heap[j]);
+        if res { self.shared.insert((i, j)); }
+        res
+      }
+      (&ProofNode::Ref(i), _) if i >= c1.nargs => self.proof_eq(c1, &c1.heap[i], c2, p2),
+      (_, &ProofNode::Ref(j)) if j >= c2.nargs => self.proof_eq(c1, p1, c2, &c2.heap[j]),
+      (&ProofNode::Ref(i), &ProofNode::Ref(j)) => i == j,
+      (&ProofNode::Dummy(a1, s1), &ProofNode::Dummy(a2, s2)) => self.dummy_eq((a1, s1), (a2, s2)),
+      (ProofNode::Term {term: t1, args: ps1}, ProofNode::Term {term: t2, args: ps2}) |
+      (ProofNode::Cong {term: t1, args: ps1}, ProofNode::Cong {term: t2, args: ps2}) =>
+        self.term_eq(*t1, *t2) && self.proofs_eq(c1, ps1, c2, ps2),
+      (ProofNode::Hyp(i, p1), ProofNode::Hyp(j, p2)) => i == j && self.proof_eq(c1, p1, c2, p2),
+      (ProofNode::Refl(p1), ProofNode::Refl(p2)) |
+      (ProofNode::Sym(p1), ProofNode::Sym(p2)) => self.proof_eq(c1, p1, c2, p2),
+      (ProofNode::Thm {thm: t1, args: ps1, res: r1},
+       ProofNode::Thm {thm: t2, args: ps2, res: r2}) =>
+        self.thm_eq(*t1, *t2) && self.proofs_eq(c1, ps1, c2, ps2) && self.proof_eq(c1, r1, c2, r2),
+      (ProofNode::Conv(p1), ProofNode::Conv(p2)) =>
+        self.proof_eq(c1, &p1.0, c2, &p2.0) && self.proof_eq(c1, &p1.1, c2, &p2.1) &&
+        self.proof_eq(c1, &p1.2, c2, &p2.2),
+      (ProofNode::Unfold {term: t1, args: ps1, res: r1},
+       ProofNode::Unfold {term: t2, args: ps2, res: r2}) =>
+        self.term_eq(*t1, *t2) && self.proofs_eq(c1, ps1, c2, ps2) &&
+        self.proof_eq(c1, &r1.0, c2, &r2.0) && self.proof_eq(c1, &r1.1, c2, &r2.1),
+      _ => false,
+    }
+  }
+
+  /// Compare the statements of term `t1` in `a` and `t2` in `b`. The definition is only
+  /// compared if `t1` has one, so an abstract `def` in `a` matches any definition in `b`.
+  /// Returns a description of the first difference.
+  pub fn term(&mut self, t1: TermId, t2: TermId) -> Result<(), String> {
+    let (td1, td2) = (&self.a.terms[t1], &self.b.terms[t2]);
+    match (&td1.kind, &td2.kind) {
+      (TermKind::Term, TermKind::Def(_)) => return Err("expected a term, found a def".into()),
+      (TermKind::Def(_), TermKind::Term) => return Err("expected a def, found a term".into()),
+      _ => {}
+    }
+    self.binders(&td1.args, &td2.args)?;
+    if !self.type_eq(Type::Reg(td1.ret.0, td1.ret.1), Type::Reg(td2.ret.0, td2.ret.1)) {
+      return Err("the return type differs".into())
+    }
+    if let TermKind::Def(Some(e1)) = &td1.kind {
+      let e2 = match &td2.kind {
+        TermKind::Def(Some(e2)) => e2,
+        _ => return Err("the definition is missing".into()),
+      };
+      self.dummies = Default::default();
+      let c1 = Ctx { heap: &e1.heap, nargs: td1.args.len() };
+      let c2 = Ctx { heap: &e2.heap, nargs: td2.args.len() };
+      if !self.expr_eq(c1, &e1.head, c2, &e2.head) {
+        return Err("the definition differs".into())
+      }
+    }
+    Ok(())
+  }
+
+  /// Compare the statements of theorem `t1` in `a` and `t2` in `b`, not including
+  /// the proofs. Returns a description of the first difference.
+  pub fn thm(&mut self, t1: ThmId, t2: ThmId) -> Result<(), String> {
+    let (td1, td2) = (&self.a.thms[t1], &self.b.thms[t2]);
+    match (&td1.kind, &td2.kind) {
+      (ThmKind::Axiom, ThmKind::Thm(_)) => return Err("expected an axiom, found a theorem".into()),
+      (ThmKind::Thm(_), ThmKind::Axiom) => return Err("expected a theorem, found an axiom".into()),
+      _ => {}
+    }
+    self.binders(&td1.args, &td2.args)?;
+    let c1 = Ctx { heap: &td1.heap, nargs: td1.args.len() };
+    let c2 = Ctx { heap: &td2.heap, nargs: td2.args.len() };
+    self.dummies = Default::default();
+    for (i, ((_, h1), (_, h2))) in td1.hyps.iter().zip(&*td2.hyps).enumerate() {
+      if !self.expr_eq(c1, h1, c2, h2) { return Err(format!("hypothesis {} differs", i + 1)) }
+    }
+    if td1.hyps.len() != td2.hyps.len() {
+      return Err(format!("expected {} hypotheses, found {}", td1.hyps.len(), td2.hyps.len()))
+    }
+    if !self.expr_eq(c1, &td1.ret, c2, &td2.ret) { return Err("the conclusion differs".into()) }
+    Ok(())
+  }
+
+  /// Compare the proofs of theorem `t1` in `a` and `t2` in `b`, which should have the same
+  /// statement. Like statements, proofs are compared after elaboration, up to the sharing
+  /// of subproofs and the renaming of dummy variables, so this is `true` if the proof was
+  /// only reformatted, but not if a different proof of the same statement was found.
+  /// Axioms and theorems with no proof are equal to each other.
+  pub fn thm_proof(&mut self, t1: ThmId, t2: ThmId) -> bool {
+    let (td1, td2) = (&self.a.thms[t1], &self.b.thms[t2]);
+    let (pf1, pf2) = match (&td1.kind, &td2.kind) {
+      (ThmKind::Thm(Some(pf1)), ThmKind::Thm(Some(pf2))) => (pf1, pf2),
+      (ThmKind::Thm(Some(_)), _) | (_, ThmKind::Thm(Some(_))) => return false,
+      _ => return true,
+    };
+    self.dummies = Default::default();
+    self.shared = Default::default();
+    let c1 = Ctx { heap: &pf1.heap, nargs: td1.args.len() };
+    let c2 = Ctx { heap: &pf2.heap, nargs: td2.args.len() };
+    self.proofs_eq(c1, &pf1.hyps, c2, &pf2.hyps) && self.proof_eq(c1, &pf1.head, c2, &pf2.head)
+  }
+
+  /// Compare the statement of item `it` in `a` with the item of the same name in `b`.
+  /// Returns `None` if there is no such item in `b`.
+  pub fn item(&mut self, it: Item) -> Option<Result<(), String>> {
+    let it2 = it.rename_env(self.a, self.b)?;
+    Some(match (it, it2) {
+      (Item::Sort(a1), Item::Sort(a2)) => {
+        let (s1, s2) = (self.a.data[a1].sort?, self.b.data[a2].sort?);
+        let (m1, m2) = (self.a.sorts[s1].mods, self.b.sorts[s2].mods);
+        if m1 == m2 { Ok(()) } else {
+          Err(format!("expected modifiers '{}', found '{}'", m1, m2))
+        }
+      }
+      (Item::Decl(a1), Item::Decl(a2)) =>
+        match (self.a.data[a1].decl?, self.b.data[a2].decl?) {
+          (DeclKey::Term(t1), DeclKey::Term(t2)) => self.term(t1, t2),
+          (DeclKey::Thm(t1), DeclKey::Thm(t2)) => self.thm(t1, t2),
+          (DeclKey::Term(_), DeclKey::Thm(_)) =>
+            Err("expected a term or def, found a theorem".into()),
+          (DeclKey::Thm(_), DeclKey::Term(_)) =>
+            Err("expected a theorem, found a term or def".into()),
+        },
+      _ => unreachable!(),
+    })
+  }
+}
+
+/// A sort or declaration, identified by its name. Sorts and declarations have separate
+/// namespaces, so the same atom can name both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Item {
+  /// The sort with this name.
+  Sort(AtomId),
+  /// The term, def, axiom or theorem with this name.
+  Decl(AtomId),
+}
+
+impl Item {
+  /// The item declared by a statement, if any.
+  #[must_use] pub fn from_stmt(s: &StmtTrace) -> Option<Item> {
+    match *s {
+      StmtTrace::Sort(a) => Some(Item::Sort(a)),
+      StmtTrace::Decl(a) => Some(Item::Decl(a)),
+      _ => None,
+    }
+  }
+
+  /// The name of the item.
+  #[must_use] pub fn atom(self) -> AtomId { match self { Item::Sort(a) | Item::Decl(a) => a } }
+
+  /// The item with the same name and kind in environment `to`, if it exists.
+  #[must_use] pub fn rename(self, from: &FrozenEnv, to: &FrozenEnv) -> Option<Item> {
+    unsafe { self.rename_env(from.thaw(), to.thaw()) }
+  }
+
+  /// Like [`rename`](Self::rename), for environments that need not be frozen.
+  #[must_use] pub fn rename_env(self, from: &Environment, to: &Environment) -> Option<Item> {
+    let a = *to.atoms.get(&from.data[self.atom()].name)?;
+    let ad = &to.data[a];
+    match self {
+      Item::Sort(_) => ad.sort.map(|_| Item::Sort(a)),
+      Item::Decl(_) => ad.decl.map(|_| Item::Decl(a)),
+    }
+  }
+
+  /// The span of the name of the item in `env`.
+  #[must_use] pub fn span(self, env: &FrozenEnv) -> Option<&FileSpan> {
+    self.span_env(unsafe { env.thaw() })
+  }
+
+  /// Like [`span`](Self::span), for environments that need not be frozen.
+  #[must_use] pub fn span_env(self, env: &Environment) -> Option<&FileSpan> {
+    let ad = &env.data[self.atom()];
+    Some(match self {
+      Item::Sort(_) => &env.sorts[ad.sort?].span,
+      Item::Decl(_) => match ad.decl? {
+        DeclKey::Term(t) => &env.terms[t].span,
+        DeclKey::Thm(t) => &env.thms[t].span,
+      },
+    })
+  }
+
+  /// Pretty-print the statement of the item in `env`. Definitions are printed with
+  /// their values, and theorems without their proofs.
+  #[must_use] pub fn print(self, env: &FrozenEnv, source: &LinedString) -> String {
+    self.print_env(unsafe { env.thaw() }, source)
+  }
+
+  /// Like [`print`](Self::print), for environments that need not be frozen.
+  #[must_use] pub fn print_env(self, env: &Environment, source: &LinedString) -> String {
+    let fe = FormatEnv { source, env };
+    let ad = &env.data[self.atom()];
+    fe.pretty(|p| match (self, ad.sort, ad.decl) {
+      (Item::Sort(_), Some(s), _) => p.sort(s).pretty(80).to_string(),
+      (Item::Decl(_), _, Some(DeclKey::Term(t))) => p.term(t, true).pretty(80).to_string(),
+      (Item::Decl(_), _, Some(DeclKey::Thm(t))) => p.thm(t).pretty(80).to_string(),
+      _ => String::new(),
+    })
+  }
+}
+
+/// Format the two versions of a statement as a diff, with `-` before the lines of the
+/// old one and `+` before the lines of the new one.
+#[must_use] pub fn diff_lines(old: &str, new: &str) -> String {
+  let old = old.lines().map(|l| format!("- {}", l));
+  let new = new.lines().map(|l| format!("+ {}", l));
+  old.chain(new).collect::<Vec<_>>().join("\n")
+}
+
+/// Check the statements of `env`, the result of elaborating an `.mm1` file with source
+/// `source`, against `spec`, the result of elaborating the `.mm0` file with source
+/// `spec_source` that specifies it. Returns a list of errors, at the location of the
+/// declaration in `env`, or in `spec` if it is missing from `env`:
+///
+/// * A declaration in the spec is missing, or its statement is different. The message
+///   includes both statements, pretty printed.
+/// * A sort, `term` or `axiom` is not in the spec. (Additional theorems and definitions
+///   are fine, because they are not part of the statement of the development.)
+#[must_use] pub fn check_spec(
+  spec: &FrozenEnv, spec_source: &LinedString, env: &FrozenEnv, source: &LinedString,
+) -> Vec<(FileSpan, String)> {
+  let mut out = vec![];
+  let mut cmp = Comparer::new(spec, env);
+  for it in spec.stmts().iter().filter_map(Item::from_stmt) {
+    let name = spec.data()[it.atom()].name();
+    match cmp.item(it) {
+      None => if let Some(fsp) = it.span(spec) {
+        out.push((fsp.clone(), format!("'{}' is declared in the spec but not here", name)))
+      },
+      Some(Ok(())) => {}
+      Some(Err(reason)) => {
+        let it2 = it.rename(spec, env).expect("item exists");
+        if let Some(fsp) = it2.span(env) {
+          out.push((fsp.clone(), format!(
+            "the statement of '{}' does not match the spec: {}\n{}", name, reason,
+            diff_lines(&it.print(spec, spec_source), &it2.print(env, source)))))
+        }
+      }
+    }
+  }
+  for it in env.stmts().iter().filter_map(Item::from_stmt) {
+    if it.rename(env, spec).is_some() { continue }
+    let ad = &env.data()[it.atom()];
+    let what = match (it, ad.decl()) {
+      (Item::Sort(_), _) => "sort",
+      (Item::Decl(_), Some(DeclKey::Term(t))) if matches!(env.term(t).kind, TermKind::Term) => "term",
+      (Item::Decl(_), Some(DeclKey::Thm(t))) if matches!(env.thm(t).kind, ThmKind::Axiom) &&
+        !env.thm(t).vis.contains(Modifiers::LOCAL) => "axiom",
+      _ => continue,
+    };
+    if let Some(fsp) = it.span(env) {
+      out.push((fsp.clone(), format!("{} '{}' is not declared in the spec", what, ad.name())))
+    }
+  }
+  out
+}
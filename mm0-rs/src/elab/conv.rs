@@ -0,0 +1,449 @@
+//! Native combinators for building conversion proofs.
+//!
+//! A conversion `c: e1 = e2` is represented in lisp using the same syntax that is accepted
+//! by [`ProofHash`](super::proof::ProofHash) when elaborating a proof:
+//! * An expression `e` is the reflexivity proof `e = e`.
+//! * `(t c1 ... cn)` is the congruence proof `(t a1 ... an) = (t b1 ... bn)` if `ci: ai = bi`.
+//! * `(:sym c)` is a proof of `e2 = e1` if `c: e1 = e2`.
+//! * `(:unfold t (a1 ... an) c)` is a proof of `(t a1 ... an) = e` if `t` is a definition
+//!   and `c: body = e`, where `body` is the value of `t` with the `ai` substituted.
+//!
+//! The functions in this module build these terms directly, checking at each step that the
+//! pieces fit together, so that tactics can construct and compose conversions without
+//! passing them through `refine` to find out whether they are well formed.
+//!
+//! It also provides the term ordering [`Elaborator::expr_cmp`], which rewriters use to
+//! orient permutative rules such as commutativity, so that they terminate.
+
+use std::cmp::Ordering;
+use num::ToPrimitive;
+use crate::{AtomId, Expr, ExprNode, Span, TermId, TermKind};
+use super::{ElabError, Elaborator};
+use super::lisp::{LispKind, LispVal, Uncons, eval::SResult};
+use super::proof::Subst;
+
+/// A rewrite rule for [`Elaborator::conv_rewrite`].
+#[derive(Debug)]
+pub(crate) enum RwRule {
+  /// Unfold the definition `t`.
+  Unfold(TermId),
+  /// Use the conversion `c`, whose left side is `lhs`.
+  Conv(LispVal, LispVal),
+}
+
+/// A rewrite performed by [`Elaborator::conv_rewrite`], which is reported if
+/// `set-rw-trace` is on.
+struct RwStep {
+  /// The position of the rewritten subterm, relative to the subterm being rewritten.
+  path: Vec<usize>,
+  /// The conversion from the old subterm to the new one.
+  conv: LispVal,
+}
+
+/// A conversion, classified by its outermost step. Conversions whose two sides are equal
+/// are always [`Refl`](Shape::Refl), and symmetry is pushed inward until it reaches an unfolding.
+enum Shape {
+  /// `e: e = e`
+  Refl(LispVal),
+  /// `(t c1 ... cn)`
+  Cong(AtomId, Vec<LispVal>),
+  /// `(:unfold t es c)`
+  Unfold(AtomId, Vec<LispVal>, LispVal),
+  /// `(:sym (:unfold t es c))`
+  SymUnfold(AtomId, Vec<LispVal>, LispVal),
+}
+
+/// Parse the arguments of `(:unfold t es c)` or `(:unfold t es sub_lhs c)`.
+fn parse_unfold(mut u: Uncons) -> Option<(LispVal, LispVal, LispVal)> {
+  match (u.next(), u.next(), u.next(), u.next()) {
+    (Some(t), Some(es), Some(c), None) if u.exactly(0) => Some((t, es, c)),
+    (Some(t), Some(es), Some(_), Some(c)) if u.exactly(0) => Some((t, es, c)),
+    _ => None
+  }
+}
+
+/// Returns true if the definition value `val` contains dummy variables.
+fn has_dummies(val: &Expr) -> bool {
+  val.heap.iter().chain(Some(&val.head)).any(|e| matches!(e, ExprNode::Dummy(..)))
+}
+
+/// Parse a path for [`Elaborator::conv_at_path`], which is a list of numbers.
+pub(crate) fn parse_path(e: &LispVal) -> SResult<Vec<usize>> {
+  Uncons::from(e.clone()).map(|e| e.as_int(|n| n.to_usize()).flatten())
+    .collect::<Option<Vec<_>>>().ok_or_else(|| "expected a list of numbers".into())
+}
+
+/// Parse the optional `:at path` arguments of `rw` and `rw-in`.
+pub(crate) fn parse_rw_path(args: &[LispVal]) -> SResult<Option<Vec<usize>>> {
+  match args {
+    [] => Ok(None),
+    [k, p] if k.as_atom() == Some(AtomId::AT) => parse_path(p).map(Some),
+    _ => Err("expected ':at path'".into()),
+  }
+}
+
+/// The number of variables, metavariables and term constructors in `e`.
+fn expr_size(e: &LispVal) -> usize {
+  if e.is_atom() || e.is_mvar() { return 1 }
+  Uncons::from(e.clone()).skip(1).map(|e| expr_size(&e)).sum::<usize>() + 1
+}
+
+fn cong(t: AtomId, cs: impl IntoIterator<Item=LispVal>) -> LispVal {
+  LispVal::list(Some(LispVal::atom(t)).into_iter().chain(cs).collect::<Vec<_>>())
+}
+
+impl Elaborator {
+  /// Get the term constructor for `a`, checking that it is applied to `n` arguments.
+  fn conv_term(&self, a: AtomId, n: usize) -> SResult<TermId> {
+    let t = self.term(a).ok_or_else(|| format!("unknown term constructor '{}'", self.print(&a)))?;
+    let nargs = self.terms[t].args.len();
+    if n != nargs {
+      return Err(format!("term '{}' expects {} arguments, got {}", self.print(&a), nargs, n))
+    }
+    Ok(t)
+  }
+
+  /// Substitute `args` into the value of definition `t`. The arguments may themselves be
+  /// conversions, in which case the result is the congruence proof for the whole body.
+  /// Definitions with dummy variables are not supported, because the body would need
+  /// fresh variables each time it is unfolded.
+  fn conv_body(&self, t: TermId, args: Vec<LispVal>) -> SResult<LispVal> {
+    let tdata = &self.terms[t];
+    match &tdata.kind {
+      TermKind::Def(Some(val)) => {
+        if has_dummies(val) {
+          return Err(format!("definition '{}' has dummy variables", self.print(&tdata.atom)))
+        }
+        Ok(Subst::new(&self.env, &val.heap, args).subst(&val.head))
+      }
+      _ => Err(format!("not a definition: {}", self.print(&tdata.atom)))
+    }
+  }
+
+  /// Check that `c` is a well formed conversion, and return its left and right sides.
+  pub(crate) fn conv_sides(&self, c: &LispVal) -> SResult<(LispVal, LispVal)> {
+    if c.is_mvar() || c.as_atom().is_some() { return Ok((c.clone(), c.clone())) }
+    let mut u = Uncons::from(c.clone());
+    let head = u.next().and_then(|a| a.as_atom())
+      .ok_or_else(|| format!("bad conversion: {}", self.print(c)))?;
+    match head {
+      AtomId::SYM => match u.next() {
+        Some(c1) if u.exactly(0) => { let (l, r) = self.conv_sides(&c1)?; Ok((r, l)) }
+        _ => Err(format!("incorrect :sym format {}", self.print(c)))
+      },
+      AtomId::UNFOLD => {
+        let (t, es, c1) = parse_unfold(u)
+          .ok_or_else(|| format!("incorrect :unfold format {}", self.print(c)))?;
+        let a = t.as_atom().ok_or_else(|| format!("expected a term, got {}", self.print(&t)))?;
+        let args = Uncons::from(es).collect::<Vec<_>>();
+        let t = self.conv_term(a, args.len())?;
+        let (l1, r) = self.conv_sides(&c1)?;
+        let tdata = &self.terms[t];
+        match &tdata.kind {
+          // The dummy variables can be renamed, so this is checked later by the kernel
+          TermKind::Def(Some(val)) if has_dummies(val) => {}
+          _ => {
+            let body = self.conv_body(t, args.clone())?;
+            if body != l1 {
+              let fe = self.format_env();
+              return Err(format!("unfolding '{}' gives\n  {}\nbut the conversion is for\n  {}",
+                self.print(&a), fe.pp(&body, fe.env.pp.width), fe.pp(&l1, fe.env.pp.width)))
+            }
+          }
+        }
+        Ok((cong(a, args), r))
+      }
+      a => {
+        self.conv_term(a, u.len())?;
+        let (mut ls, mut rs) = (vec![], vec![]);
+        for c in u {
+          let (l, r) = self.conv_sides(&c)?;
+          ls.push(l);
+          rs.push(r);
+        }
+        Ok((cong(a, ls), cong(a, rs)))
+      }
+    }
+  }
+
+  /// Classify a conversion which is known to be well formed.
+  fn conv_shape(&self, c: &LispVal) -> SResult<Shape> {
+    let (l, r) = self.conv_sides(c)?;
+    if l == r { return Ok(Shape::Refl(l)) }
+    let mut u = Uncons::from(c.clone());
+    Ok(match u.next().and_then(|a| a.as_atom()) {
+      Some(AtomId::SYM) => match self.conv_shape(&u.next().expect("checked"))? {
+        Shape::Refl(e) => Shape::Refl(e),
+        Shape::Cong(t, cs) =>
+          Shape::Cong(t, cs.iter().map(|c| self.conv_sym(c)).collect::<SResult<_>>()?),
+        Shape::Unfold(t, es, c) => Shape::SymUnfold(t, es, c),
+        Shape::SymUnfold(t, es, c) => Shape::Unfold(t, es, c),
+      },
+      Some(AtomId::UNFOLD) => {
+        let (t, es, c) = parse_unfold(u).expect("checked");
+        Shape::Unfold(t.as_atom().expect("checked"), Uncons::from(es).collect(), c)
+      }
+      Some(t) => Shape::Cong(t, u.collect()),
+      None => unreachable!("atoms are reflexive"),
+    })
+  }
+
+  /// Given `c: e1 = e2`, construct a proof of `e2 = e1`.
+  pub(crate) fn conv_sym(&self, c: &LispVal) -> SResult<LispVal> {
+    Ok(match self.conv_shape(c)? {
+      Shape::Refl(e) => e,
+      Shape::Cong(t, cs) => cong(t, cs.iter().map(|c| self.conv_sym(c)).collect::<SResult<Vec<_>>>()?),
+      Shape::Unfold(t, es, c) => LispVal::sym(LispVal::unfold(t, es, c)),
+      Shape::SymUnfold(t, es, c) => LispVal::unfold(t, es, c),
+    })
+  }
+
+  /// The main part of [`conv_trans`](Self::conv_trans), for conversions that are known
+  /// to compose.
+  fn conv_trans_core(&self, c1: &LispVal, c2: &LispVal) -> SResult<LispVal> {
+    Ok(match (self.conv_shape(c1)?, self.conv_shape(c2)?) {
+      (Shape::Refl(_), _) => c2.clone(),
+      (_, Shape::Refl(_)) => c1.clone(),
+      (Shape::Unfold(t, es, c), _) => LispVal::unfold(t, es, self.conv_trans_core(&c, c2)?),
+      (_, Shape::SymUnfold(t, es, c)) => {
+        let c = self.conv_trans_core(&c, &self.conv_sym(c1)?)?;
+        LispVal::sym(LispVal::unfold(t, es, c))
+      }
+      (Shape::Cong(t, cs), Shape::Cong(_, ds)) => cong(t, cs.iter().zip(&ds)
+        .map(|(c, d)| self.conv_trans_core(c, d)).collect::<SResult<Vec<_>>>()?),
+      (Shape::Cong(t, cs), Shape::Unfold(_, _, c)) => {
+        let (l1, _) = self.conv_sides(c1)?;
+        let mut u = Uncons::from(l1);
+        u.next();
+        let body = self.conv_body(self.conv_term(t, cs.len())?, cs)?;
+        LispVal::unfold(t, u.collect(), self.conv_trans_core(&body, &c)?)
+      }
+      (Shape::SymUnfold(t, _, c), Shape::Cong(_, ds)) => {
+        let (_, r2) = self.conv_sides(c2)?;
+        let mut u = Uncons::from(r2);
+        u.next();
+        let body = self.conv_body(self.conv_term(t, ds.len())?, ds)?;
+        let c = self.conv_trans_core(&self.conv_sym(&body)?, &c)?;
+        LispVal::sym(LispVal::unfold(t, u.collect(), c))
+      }
+      (Shape::SymUnfold(_, _, c), Shape::Unfold(_, _, d)) =>
+        self.conv_trans_core(&self.conv_sym(&c)?, &d)?,
+    })
+  }
+
+  /// Given `c1: e1 = e2` and `c2: e2 = e3`, construct a proof of `e1 = e3`. There is no
+  /// transitivity step in the proof format, so this works by pushing the two conversions
+  /// into each other, which can fail for definitions with dummy variables.
+  pub(crate) fn conv_trans(&self, c1: &LispVal, c2: &LispVal) -> SResult<LispVal> {
+    let (l1, r1) = self.conv_sides(c1)?;
+    let (l2, r2) = self.conv_sides(c2)?;
+    if r1 != l2 {
+      let fe = self.format_env();
+      return Err(format!("conversions do not compose:\n  {}\n!=\n  {}", fe.pp(&r1, fe.env.pp.width), fe.pp(&l2, fe.env.pp.width)))
+    }
+    let c = self.conv_trans_core(c1, c2)?;
+    match self.conv_sides(&c) {
+      Ok((l, r)) if l == l1 && r == r2 => Ok(c),
+      _ => Err("unable to compose conversions through a definition with dummy variables".into())
+    }
+  }
+
+  /// Given a term constructor `t` and `ci: ai = bi`, construct a proof of
+  /// `(t a1 ... an) = (t b1 ... bn)`.
+  pub(crate) fn conv_cong(&self, t: AtomId, cs: Vec<LispVal>) -> SResult<LispVal> {
+    self.conv_term(t, cs.len())?;
+    for c in &cs { self.conv_sides(c)?; }
+    Ok(cong(t, cs))
+  }
+
+  /// Given `e = (t a1 ... an)` where `t` is a definition, construct a proof of `e = body`,
+  /// where `body` is the value of `t` with the `ai` substituted. Dummy variables in the
+  /// definition are replaced by new metavariables.
+  pub(crate) fn conv_unfold(&mut self, e: &LispVal) -> SResult<LispVal> {
+    let mut u = Uncons::from(e.clone());
+    let a = u.next().and_then(|a| a.as_atom())
+      .ok_or_else(|| format!("expected a term, got {}", self.print(e)))?;
+    let args = u.collect::<Vec<_>>();
+    let t = self.conv_term(a, args.len())?;
+    for e in &args { self.conv_sides(e)?; }
+    if let TermKind::Def(Some(val)) = &self.env.terms[t].kind {
+      let body = Subst::new(&self.env, &val.heap, args.clone()).subst_mut(&mut self.lc, &val.head);
+      Ok(LispVal::unfold(a, args, body))
+    } else {
+      Err(format!("not a definition: {}", self.print(&a)))
+    }
+  }
+
+  /// Given `c: a = b` and a subterm `a` of `e` at `path`, construct a proof of `e = e'`
+  /// where `e'` is `e` with that occurrence of `a` replaced by `b`. The path is the list of
+  /// argument positions (starting at 1) to follow from the root of `e`.
+  pub(crate) fn conv_at_path(&self, e: &LispVal, path: &[usize], c: &LispVal) -> SResult<LispVal> {
+    let (&i, path) = match path.split_first() {
+      None => {
+        let (l, _) = self.conv_sides(c)?;
+        if l != *e {
+          let fe = self.format_env();
+          return Err(format!("conversion is for\n  {}\nbut the subterm is\n  {}",
+            fe.pp(&l, fe.env.pp.width), fe.pp(e, fe.env.pp.width)))
+        }
+        return Ok(c.clone())
+      }
+      Some(p) => p
+    };
+    let mut u = Uncons::from(e.clone());
+    let a = u.next().and_then(|a| a.as_atom())
+      .ok_or_else(|| format!("path too long: {} has no subterms", self.print(e)))?;
+    let mut args = u.collect::<Vec<_>>();
+    let arg = i.checked_sub(1).and_then(|i| args.get_mut(i))
+      .ok_or_else(|| format!("invalid path index {} in {}", i, self.print(e)))?;
+    *arg = self.conv_at_path(arg, path, c)?;
+    self.conv_cong(a, args)
+  }
+
+  /// Interpret `r` as a rewrite rule, which is either the name of a definition to unfold
+  /// or a conversion to rewrite with.
+  pub(crate) fn rw_rule(&self, r: &LispVal) -> SResult<RwRule> {
+    if let Some(a) = r.as_atom() {
+      return match self.term(a) {
+        Some(t) if matches!(self.terms[t].kind, TermKind::Def(Some(_))) => Ok(RwRule::Unfold(t)),
+        _ => Err(format!("expected a definition or a conversion, got {}", self.print(&a)))
+      }
+    }
+    let (lhs, _) = self.conv_sides(r)?;
+    Ok(RwRule::Conv(r.clone(), lhs))
+  }
+
+  /// Apply `rule` to the outermost matching subterms of `e`, returning the conversion
+  /// from `e` to the result, or `None` if there is no match. `path` is the position of `e`,
+  /// and each rewrite is recorded in `steps`.
+  fn rewrite_all(&mut self, e: &LispVal, rule: &RwRule,
+    path: &mut Vec<usize>, steps: &mut Vec<RwStep>
+  ) -> SResult<Option<LispVal>> {
+    let mut step = |c: LispVal| -> SResult<Option<LispVal>> {
+      steps.push(RwStep {path: path.clone(), conv: c.clone()});
+      Ok(Some(c))
+    };
+    if let RwRule::Conv(c, lhs) = rule {
+      if lhs == e { return step(c.clone()) }
+    }
+    let mut u = Uncons::from(e.clone());
+    let a = if let Some(a) = u.next().and_then(|a| a.as_atom()) {a} else {return Ok(None)};
+    if let RwRule::Unfold(t) = *rule {
+      if self.term(a) == Some(t) { return step(self.conv_unfold(e)?) }
+    }
+    let mut changed = false;
+    let mut args = vec![];
+    for (i, e) in u.enumerate() {
+      path.push(i + 1);
+      let res = self.rewrite_all(&e, rule, path, steps);
+      path.pop();
+      match res? {
+        Some(c) => { changed = true; args.push(c) }
+        None => args.push(e)
+      }
+    }
+    Ok(if changed { Some(cong(a, args)) } else { None })
+  }
+
+  /// Report the rewrites `steps` at `sp`, if `set-rw-trace` is on. `prefix` is the path of
+  /// the subterm that was rewritten.
+  fn trace_rewrites(&mut self, sp: Span, rule: &RwRule, prefix: &[usize], steps: Vec<RwStep>) {
+    if !self.rw_trace { return }
+    let n = steps.len();
+    for (i, RwStep {path, conv}) in steps.into_iter().enumerate() {
+      let (before, after) = match self.conv_sides(&conv) {Ok(s) => s, Err(_) => continue};
+      let fe = self.format_env();
+      let name = match rule {
+        RwRule::Unfold(t) => format!("unfold {}", fe.to(&self.terms[*t].atom)),
+        RwRule::Conv(..) => "conversion".into(),
+      };
+      let pos = prefix.iter().chain(&path).map(ToString::to_string).collect::<Vec<_>>();
+      let msg = format!("rewrite {}/{}: {} at ({})\n  {}\n~>\n  {}",
+        i + 1, n, name, pos.join(" "), fe.pp(&before, fe.env.pp.width), fe.pp(&after, fe.env.pp.width));
+      self.report(ElabError::info(sp, msg));
+    }
+  }
+
+  /// Get the subterm of `e` at `path` (see [`conv_at_path`](Self::conv_at_path)).
+  fn subterm(&self, e: &LispVal, path: &[usize]) -> SResult<LispVal> {
+    let mut e = e.clone();
+    for &i in path {
+      e = Uncons::from(e.clone()).nth(i).filter(|_| i != 0)
+        .ok_or_else(|| format!("invalid path index {} in {}", i, self.print(&e)))?;
+    }
+    Ok(e)
+  }
+
+  /// Construct a conversion from `e` to the result of rewriting `e` with `rule`. If `path`
+  /// is given, only the subterm at `path` is rewritten; otherwise every outermost match
+  /// is rewritten. It is an error if there is nothing to rewrite. If `set-rw-trace` is on,
+  /// each rewrite is reported at `sp`.
+  pub(crate) fn conv_rewrite(&mut self, sp: Span,
+    e: &LispVal, rule: &RwRule, path: Option<&[usize]>
+  ) -> SResult<LispVal> {
+    let path = path.unwrap_or(&[]);
+    let sub = self.subterm(e, path)?;
+    let mut steps = vec![];
+    let c = self.rewrite_all(&sub, rule, &mut vec![], &mut steps)?.ok_or_else(|| {
+      let fe = self.format_env();
+      match rule {
+        RwRule::Unfold(t) => format!("rewrite failed: '{}' does not appear in
+  {}",
+          fe.to(&self.terms[*t].atom), fe.pp(&sub, fe.env.pp.width)),
+        RwRule::Conv(_, lhs) => format!("rewrite failed: no instance of
+  {}
+in
+  {}",
+          fe.pp(lhs, fe.env.pp.width), fe.pp(&sub, fe.env.pp.width)),
+      }
+    })?;
+    self.trace_rewrites(sp, rule, path, steps);
+    self.conv_at_path(e, path, &c)
+  }
+
+  /// Rewrite the hypothesis `h: e` with `rule` (see [`conv_rewrite`](Self::conv_rewrite)),
+  /// returning a proof of the rewritten statement `e'`. This is the conversion proof
+  /// `(:conv e' c h)` where `c: e' = e` is the reverse of the rewrite.
+  pub(crate) fn rewrite_hyp(&mut self, sp: Span,
+    h: AtomId, rule: &RwRule, path: Option<&[usize]>
+  ) -> SResult<LispVal> {
+    let e = self.lc.get_proof(h).map(|(_, e, _)| e.clone())
+      .ok_or_else(|| format!("unknown hypothesis '{}'", self.print(&h)))?;
+    let c = self.conv_rewrite(sp, &e, rule, path)?;
+    let c = self.conv_sym(&c)?;
+    let (e2, _) = self.conv_sides(&c)?;
+    Ok(LispVal::conv(e2, c, LispVal::atom(h)))
+  }
+
+  /// Compare the expressions `e1` and `e2` in a total term ordering: smaller expressions
+  /// come first, and expressions of the same size are ordered with variables (by name)
+  /// before metavariables (by number) before applications, which are ordered by their term
+  /// constructor (in declaration order) and then lexicographically by their arguments.
+  ///
+  /// A rewriter can apply a permutative rule like `a + b = b + a`, which would loop if used
+  /// unconditionally, only when the result is smaller than the original expression. Since
+  /// the ordering is total on ground expressions and compatible with the term structure,
+  /// this terminates, and it rewrites AC expressions to a normal form.
+  pub(crate) fn expr_cmp(&self, e1: &LispVal, e2: &LispVal) -> Ordering {
+    fn rank(e: &LispVal) -> u8 {
+      if e.is_atom() {0} else if e.is_mvar() {1} else {2}
+    }
+    expr_size(e1).cmp(&expr_size(e2))
+      .then_with(|| rank(e1).cmp(&rank(e2)))
+      .then_with(|| match (e1.as_atom(), e2.as_atom()) {
+        (Some(a1), Some(a2)) => self.data[a1].name.cmp(&self.data[a2].name),
+        _ if e1.is_mvar() => {
+          let n = |e: &LispKind| if let LispKind::MVar(n, _) = *e {n} else {0};
+          e1.unwrapped(n).cmp(&e2.unwrapped(n))
+        }
+        _ => {
+          let (mut u1, mut u2) = (Uncons::from(e1.clone()), Uncons::from(e2.clone()));
+          let head = |u: &mut Uncons| u.next().and_then(|a| a.as_atom()).and_then(|a| self.term(a));
+          head(&mut u1).cmp(&head(&mut u2)).then_with(|| {
+            u1.zip(u2).map(|(a1, a2)| self.expr_cmp(&a1, &a2))
+              .find(|o| o.is_ne()).unwrap_or(Ordering::Equal)
+          })
+        }
+      })
+  }
+}
@@ -0,0 +1,168 @@
+//! A discrimination tree index over theorem conclusions.
+//!
+//! Each conclusion is flattened into the preorder sequence of its term constructors, where
+//! every variable becomes a wildcard [`Key::Var`], and the sequences are stored in a trie.
+//! To find the theorems that may apply to a goal, the goal is flattened in the same way and
+//! matched against the trie, where a wildcard on either side matches a whole subterm. This
+//! yields a superset of the theorems whose conclusion unifies with the goal, which is
+//! usually very small compared to the whole environment, so that the expensive unification
+//! only needs to be attempted on a few candidates.
+//!
+//! The index also records which theorems mention each term constructor anywhere in their
+//! statement, for [`Environment::theorems_about`].
+//!
+//! The index is maintained in [`Environment::thm_index`], and is updated whenever a
+//! theorem is added to the environment, including by import.
+use std::collections::{HashMap, HashSet};
+use crate::{Environment, ExprNode, TermId, Thm, ThmId};
+use super::lisp::{LispVal, Uncons};
+
+/// A key in the flattened representation of an expression.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+  /// A variable or metavariable, which matches any subterm.
+  Var,
+  /// An application of a term constructor to the given number of arguments,
+  /// which are the following subterms.
+  App(TermId, usize),
+}
+crate::deep_size_0!(Key);
+
+impl Key {
+  /// The number of subterms following this key.
+  fn arity(self) -> usize { match self { Key::Var => 0, Key::App(_, n) => n } }
+}
+
+/// Flatten an expression in a theorem statement, where `heap` is the theorem heap.
+fn flatten_expr(heap: &[ExprNode], nargs: usize, e: &ExprNode, out: &mut Vec<Key>) {
+  match *e {
+    ExprNode::Ref(i) if i >= nargs => flatten_expr(heap, nargs, &heap[i], out),
+    ExprNode::Ref(_) | ExprNode::Dummy(..) => out.push(Key::Var),
+    ExprNode::App(t, ref es) => {
+      out.push(Key::App(t, es.len()));
+      for e in &**es { flatten_expr(heap, nargs, e, out) }
+    }
+  }
+}
+
+/// Flatten the conclusion of a theorem.
+#[must_use] pub fn flatten_thm(td: &Thm) -> Vec<Key> {
+  let mut out = vec![];
+  flatten_expr(&td.heap, td.args.len(), &td.ret, &mut out);
+  out
+}
+
+/// The term constructors that appear in the hypotheses or conclusion of a theorem.
+fn statement_terms(td: &Thm) -> HashSet<TermId> {
+  fn go(e: &ExprNode, out: &mut HashSet<TermId>) {
+    if let ExprNode::App(t, es) = e {
+      out.insert(*t);
+      for e in &**es { go(e, out) }
+    }
+  }
+  let mut out = HashSet::new();
+  for e in td.heap[td.args.len()..].iter().chain(td.hyps.iter().map(|(_, e)| e)) { go(e, &mut out) }
+  go(&td.ret, &mut out);
+  out
+}
+
+/// Flatten an elaborated term s-expression, such as a goal. Variables, metavariables,
+/// and anything else that is not an application of a term constructor become wildcards.
+pub fn flatten_lisp(env: &Environment, e: &LispVal, out: &mut Vec<Key>) {
+  let mut u = Uncons::from(e.clone());
+  match u.next().and_then(|head| head.as_atom()).and_then(|a| env.term(a)) {
+    Some(t) => {
+      let args = u.collect::<Vec<_>>();
+      out.push(Key::App(t, args.len()));
+      for e in &args { flatten_lisp(env, e, out) }
+    }
+    None => out.push(Key::Var),
+  }
+}
+
+/// Skip the first subterm of a flattened expression.
+fn skip(keys: &[Key]) -> &[Key] {
+  let (mut i, mut n) = (0, 1);
+  while n != 0 {
+    n = n - 1 + keys[i].arity();
+    i += 1;
+  }
+  &keys[i..]
+}
+
+#[derive(Default, Debug, DeepSizeOf)]
+struct Node {
+  /// The children of this node, indexed by the next key.
+  children: HashMap<Key, usize>,
+  /// The theorems whose flattened conclusion ends at this node.
+  values: Vec<ThmId>,
+}
+
+/// A discrimination tree, mapping theorem conclusions to theorems.
+#[derive(Default, Debug, DeepSizeOf)]
+pub struct DiscTree {
+  /// The nodes of the trie. If nonempty, the root is node 0.
+  nodes: Vec<Node>,
+  /// The theorems whose statement mentions each term constructor, in declaration order.
+  mentions: HashMap<TermId, Vec<ThmId>>,
+}
+
+impl DiscTree {
+  /// Add theorem `v` with the flattened conclusion `keys` to the index.
+  pub fn insert(&mut self, keys: &[Key], v: ThmId) {
+    if self.nodes.is_empty() { self.nodes.push(Node::default()) }
+    let mut n = 0;
+    for &k in keys {
+      n = match self.nodes[n].children.get(&k) {
+        Some(&c) => c,
+        None => {
+          let c = self.nodes.len();
+          self.nodes.push(Node::default());
+          self.nodes[n].children.insert(k, c);
+          c
+        }
+      }
+    }
+    self.nodes[n].values.push(v)
+  }
+
+  /// Add theorem `v` with the statement `td` to the index.
+  pub fn insert_thm(&mut self, td: &Thm, v: ThmId) {
+    self.insert(&flatten_thm(td), v);
+    for t in statement_terms(td) { self.mentions.entry(t).or_default().push(v) }
+  }
+
+  /// Get all theorems whose statement (hypotheses or conclusion) mentions the term `t`,
+  /// in declaration order.
+  #[must_use] pub fn mentioning(&self, t: TermId) -> &[ThmId] {
+    self.mentions.get(&t).map_or(&[], |v| v)
+  }
+
+  /// Call `f` on every node reachable from node `n` by skipping `k` subterms.
+  fn skip_tree(&self, n: usize, k: usize, f: &mut impl FnMut(usize)) {
+    if k == 0 { return f(n) }
+    for (key, &c) in &self.nodes[n].children { self.skip_tree(c, k - 1 + key.arity(), f) }
+  }
+
+  fn find(&self, n: usize, query: &[Key], out: &mut Vec<ThmId>) {
+    match query.split_first() {
+      None => out.extend_from_slice(&self.nodes[n].values),
+      Some((Key::Var, rest)) => self.skip_tree(n, 1, &mut |c| self.find(c, rest, out)),
+      Some((k, rest)) => {
+        let children = &self.nodes[n].children;
+        if let Some(&c) = children.get(k) { self.find(c, rest, out) }
+        if let Some(&c) = children.get(&Key::Var) { self.find(c, skip(query), out) }
+      }
+    }
+  }
+
+  /// Get all theorems whose conclusion may unify with the flattened expression `query`,
+  /// in declaration order.
+  #[must_use] pub fn unifiable(&self, query: &[Key]) -> Vec<ThmId> {
+    let mut out = vec![];
+    if !self.nodes.is_empty() { self.find(0, query, &mut out) }
+    out.sort_unstable();
+    out.dedup();
+    out
+  }
+}
@@ -0,0 +1,281 @@
+//! Reusing the `do` blocks of a file from its previous elaboration.
+//!
+//! Most `do` blocks only define tactics: they evaluate `def`s of lambdas and constants,
+//! whose values do not depend on the theorems of the file. When the language server
+//! elaborates a file again after an edit, such a block does not need to be evaluated again
+//! unless something it depends on changed. Instead, the definitions it made in the previous
+//! elaboration are copied over (much like an import), with their spans moved to the new
+//! position of the block.
+//!
+//! For this purpose the elaboration of a file is split into two parts:
+//!
+//! * The *declarations*, which are summarized by a running fingerprint: a hash of the
+//!   text of every statement other than `do` blocks and theorems, of the sources of the
+//!   imported files, and of the text of any statement that calls a builtin that changes
+//!   the environment (see [`BuiltinProc::mutates_env`]).
+//! * The *metaprograms*: a `do` block is recorded as reusable if, while evaluating it, it
+//!   called no builtins, reported no messages, and only read globals that are builtins,
+//!   imported, or were defined by earlier reusable blocks. Its key is the hash of its text
+//!   and the fingerprint of the declarations before it, and it records which block defined
+//!   each global it read. A block stops being reusable if a global it defined is defined
+//!   again later in the file, because the values are taken from the final environment.
+//!
+//! A recorded block is reused if its key matches and each global it read was defined
+//! by the same (reused) block in the new elaboration. Since the imports are part of the
+//! fingerprint, the imported globals are the same if the key matches. Editing a proof
+//! changes neither, so it does not cause any tactic definitions to be evaluated again.
+//! Blocks that do anything else, like `(display)` or adding theorems, are always evaluated.
+
+use std::collections::{hash_map::DefaultHasher, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use mm1_parser::ast::{DeclKind, SExpr, Stmt, StmtKind};
+use crate::{Alignment, AtomId, FrozenEnv, Remap, Remapper, Span, StmtTrace};
+use super::{lisp::BuiltinProc, Elaborator, ObjectKind, Result};
+
+fn mix(h: u64, x: impl Hash) -> u64 {
+  let mut s = DefaultHasher::new();
+  h.hash(&mut s);
+  x.hash(&mut s);
+  s.finish()
+}
+
+/// A `do` block whose definitions can be reused. See the [module docs](self).
+#[derive(Debug, DeepSizeOf)]
+pub struct CachedBlock {
+  /// The hash of the text of the block and the fingerprint of the declarations before it.
+  key: u64,
+  /// The span of the block.
+  span: Span,
+  /// The globals that were read while evaluating the block, with the index of the block
+  /// that defined each one, or `None` for a builtin.
+  reads: Box<[(AtomId, Option<usize>)]>,
+  /// The globals defined by the block. Their values are the final values of the globals
+  /// in the environment, because the block is marked stale if any of them is redefined.
+  defs: Box<[AtomId]>,
+  /// Set if a global defined by the block was defined again later, so that the block
+  /// cannot be reused.
+  stale: bool,
+}
+
+/// The reusable `do` blocks of a file, stored in its [`Environment`](super::Environment).
+#[derive(Debug, Default, DeepSizeOf)]
+pub struct DoCache {
+  /// A hash of the source of the file and of the files it imports.
+  pub source_hash: u64,
+  /// The reusable blocks, in order.
+  blocks: Vec<CachedBlock>,
+  /// The number of blocks that were reused from the previous elaboration instead of
+  /// being evaluated.
+  pub reused: usize,
+}
+
+impl DoCache {
+  /// The cache of a file with no `do` blocks or imports, such as an `.mmb` file, whose
+  /// text is `source`.
+  #[must_use] pub fn from_source(source: &[u8]) -> Self {
+    Self {source_hash: mix(0, source), blocks: vec![], reused: 0}
+  }
+
+  /// The blocks that can be reused, with their index.
+  fn valid_blocks(&self) -> impl Iterator<Item=(usize, &CachedBlock)> {
+    self.blocks.iter().enumerate().filter(|(_, b)| !b.stale)
+  }
+}
+
+/// The effects of the `do` block that is currently being evaluated.
+#[derive(Debug, Default)]
+struct Tracking {
+  reads: Vec<(AtomId, Option<usize>)>,
+  defs: Vec<AtomId>,
+  /// Set if the block did something that makes it not reusable.
+  dirty: bool,
+}
+
+/// The state of the [`Elaborator`] for recording and reusing `do` blocks.
+#[derive(Debug, Default)]
+pub(crate) struct DoCacheState {
+  /// The previous elaboration of the file.
+  old: Option<FrozenEnv>,
+  /// The blocks of `old` that have not been reused yet, by key.
+  index: HashMap<u64, VecDeque<usize>>,
+  /// The blocks of `old` that have been reused, with their index in the new cache.
+  reused: HashMap<usize, usize>,
+  /// The remapper from `old` to the current environment, created on first use.
+  remap: Option<Remapper>,
+  /// The fingerprint of the declarations so far.
+  fingerprint: u64,
+  /// The running value of [`DoCache::source_hash`].
+  source: u64,
+  /// The globals defined by `do` blocks so far, with the index of the block in the new
+  /// cache, or `None` if the block (or whatever else defined it) is not reusable.
+  definer: HashMap<AtomId, Option<usize>>,
+  /// The blocks in the new cache that defined a global that was defined again later.
+  stale: Vec<usize>,
+  /// The number of calls to builtins that change the environment.
+  pub(crate) mutations: usize,
+  tracking: Option<Tracking>,
+}
+
+impl DoCacheState {
+  /// Create the state for a file with text `source`, given its previous elaboration `old`.
+  pub(crate) fn new(old: Option<FrozenEnv>, source: &[u8]) -> Self {
+    let mut index = HashMap::<_, VecDeque<_>>::new();
+    if let Some(old) = &old {
+      for (i, b) in old.do_cache().valid_blocks() {
+        index.entry(b.key).or_default().push_back(i)
+      }
+    }
+    Self {old, index, source: mix(0, source), ..Self::default()}
+  }
+
+  /// Record a call to builtin `f`.
+  pub(crate) fn builtin(&mut self, f: BuiltinProc) {
+    if f.mutates_env() { self.mutations += 1 }
+    if let Some(tr) = &mut self.tracking { tr.dirty = true }
+  }
+
+  /// Record a definition of global `a`. `simple` is false if the definition used a
+  /// merge strategy or removed the global.
+  pub(crate) fn define(&mut self, a: AtomId, simple: bool) {
+    if let Some(&Some(i)) = self.definer.get(&a) { self.stale.push(i) }
+    match &mut self.tracking {
+      Some(tr) => { tr.defs.push(a); tr.dirty |= !simple }
+      None => { self.definer.insert(a, None); }
+    }
+  }
+
+  /// Record an import of (or the prelude) `env` into the file.
+  pub(crate) fn import(&mut self, env: &FrozenEnv) {
+    let h = env.do_cache().source_hash;
+    self.fingerprint = mix(self.fingerprint, h);
+    self.source = mix(self.source, h);
+  }
+
+  /// Finish the new cache `cache` at the end of the file.
+  pub(crate) fn finish(&self, cache: &mut DoCache) {
+    cache.source_hash = self.source;
+    for &i in &self.stale { cache.blocks[i].stale = true }
+  }
+}
+
+/// True if `stmt` is a theorem or `do` block, which are not part of the fingerprint
+/// unless they change the environment.
+fn is_theorem_or_do(stmt: &Stmt) -> bool {
+  match &stmt.k {
+    StmtKind::Decl(d) => matches!(d.k, DeclKind::Thm),
+    StmtKind::Do(_) => true,
+    StmtKind::Annot(_, s) | StmtKind::DocComment(_, s) => is_theorem_or_do(s),
+    _ => false,
+  }
+}
+
+impl Elaborator {
+  /// Record a read of global `a`. A global that was not defined in this file is a builtin
+  /// or comes from an import.
+  pub(crate) fn read_global(&mut self, a: AtomId) {
+    let st = &mut self.do_cache;
+    if let Some(tr) = &mut st.tracking {
+      match st.definer.get(&a) {
+        Some(&Some(i)) => tr.reads.push((a, Some(i))),
+        Some(None) => tr.dirty = true,
+        None => tr.reads.push((a, None)),
+      }
+    }
+  }
+
+  /// Update the fingerprint of the declarations after elaborating `stmt`, given the
+  /// number of mutations before it.
+  pub(crate) fn do_cache_stmt(&mut self, stmt: &Stmt, mutations: usize) {
+    let st = &mut self.do_cache;
+    if st.mutations != mutations || !is_theorem_or_do(stmt) {
+      st.fingerprint = mix(st.fingerprint, self.ast.span(stmt.span))
+    }
+  }
+
+  /// Elaborate a `do` block, or reuse it from the previous elaboration if possible.
+  pub(crate) fn elab_do(&mut self, span: Span, es: &[SExpr], mut doc: String) -> Result<()> {
+    let key = mix(self.do_cache.fingerprint, self.ast.span(span));
+    if self.reuse_do(key, span) { return Ok(()) }
+    let errors = self.errors.len();
+    self.do_cache.tracking = Some(Tracking::default());
+    let res = (|| -> Result<()> {
+      for e in es { self.parse_and_print(e, mem::take(&mut doc))? }
+      Ok(())
+    })();
+    let mut tr = self.do_cache.tracking.take().expect("tracking");
+    tr.defs.sort_unstable();
+    tr.defs.dedup();
+    let reusable = res.is_ok() && !tr.dirty && self.errors.len() == errors &&
+      self.spans.iter().all(|(_, k)| matches!(k,
+        ObjectKind::Global(_) | ObjectKind::LispLocal(..) | ObjectKind::Syntax(_) |
+        ObjectKind::Term(..))) &&
+      tr.defs.iter().all(|&a| self.env.data[a].lisp.is_some());
+    let i = self.env.do_cache.blocks.len();
+    for &a in &tr.defs { self.do_cache.definer.insert(a, if reusable {Some(i)} else {None}); }
+    if reusable {
+      self.env.do_cache.blocks.push(CachedBlock {
+        key, span, reads: tr.reads.into(), defs: tr.defs.into(), stale: false
+      });
+    }
+    res
+  }
+
+  /// Reuse the block with key `key` from the previous elaboration, if there is one whose
+  /// reads are satisfied. Returns true if the block was reused.
+  fn reuse_do(&mut self, key: u64, span: Span) -> bool {
+    let st = &mut self.do_cache;
+    let (old, cands) = match (&st.old, st.index.get_mut(&key)) {
+      (Some(old), Some(cands)) => (old.clone(), cands),
+      _ => return false,
+    };
+    let blocks = &old.do_cache().blocks;
+    let (env, definer, reused) = (&self.env, &st.definer, &st.reused);
+    let pos = cands.iter().position(|&j| blocks[j].reads.iter().all(|&(a_old, d)| {
+      let a = env.atoms.get(old.data()[a_old].name()).copied();
+      match d {
+        None => a.map_or(true, |a| !definer.contains_key(&a)),
+        Some(j) => match (a, reused.get(&j)) {
+          (Some(a), Some(&k)) => definer.get(&a) == Some(&Some(k)),
+          _ => false,
+        }
+      }
+    }));
+    let j = match pos.and_then(|p| cands.remove(p)) { Some(j) => j, None => return false };
+    let blk = &blocks[j];
+    let env = &mut self.env;
+    let r = st.remap.get_or_insert_with(|| Remapper::new(env, &old, &Alignment::default()));
+    r.moved.push((blk.span, span.start));
+    let i = env.do_cache.blocks.len();
+    let mut defs = Vec::with_capacity(blk.defs.len());
+    for &a_old in &*blk.defs {
+      let a = r.atom[a_old];
+      let ld = old.data()[a_old].lisp().as_ref().expect("reusable block").remap(r);
+      if mem::replace(&mut env.data[a].lisp, Some(ld)).is_none() {
+        env.stmts.push(StmtTrace::Global(a))
+      }
+      if let Some(&Some(k)) = st.definer.get(&a) { st.stale.push(k) }
+      st.definer.insert(a, Some(i));
+      defs.push(a);
+    }
+    let reads = blk.reads.iter().map(|&(a, d)| (r.atom[a], d.map(|j| st.reused[&j]))).collect();
+    env.do_cache.blocks.push(CachedBlock {key, span, reads, defs: defs.into(), stale: false});
+    env.do_cache.reused += 1;
+    st.reused.insert(j, i);
+    if let Some(spans) = old.find(blk.span.start).filter(|s| s.stmt() == blk.span) {
+      for (sp, k) in spans.iter() {
+        let k = match *k {
+          ObjectKind::Global(a) => ObjectKind::Global(r.atom[a]),
+          ObjectKind::LispLocal(a, sp) => ObjectKind::LispLocal(r.atom[a], r.span(sp)),
+          ObjectKind::Syntax(s) => ObjectKind::Syntax(s),
+          ObjectKind::Term(t, sp) => ObjectKind::Term(t, r.span(sp)),
+          _ => continue,
+        };
+        self.spans.insert(r.span(*sp), k);
+      }
+      self.spans.lisp_scopes.extend(spans.lisp_scopes.iter()
+        .map(|&(sc, a, b)| (r.span(sc), r.atom[a], r.span(b))));
+    }
+    true
+  }
+}
@@ -4,15 +4,19 @@ use std::ops::Deref;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::fmt::Write;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use num::{BigInt, BigUint, ToPrimitive, Zero};
 use super::{ElabError, BoxError, spans::Spans, FrozenEnv, FrozenLispVal};
-use crate::{ArcString, AtomId, AtomVec, DocComment, FileRef, FileSpan, HashMapExt, Modifiers,
-  Prec, SortId, SortVec, Span, TermId, TermVec, ThmId, ThmVec,
-  lisp::{LispVal, RefineSyntax, Syntax}};
+use crate::{ArcString, AtomId, AtomVec, DocComment, FileRef, FileSpan, HashMapExt, LinedString,
+  Modifiers, Prec, SortId, SortVec, Span, TermId, TermVec, ThmId, ThmVec,
+  lisp::{LispKind, LispVal, RefineSyntax, Syntax, Uncons}};
 use super::frozen::{FrozenLispKind, FrozenLispRef};
+use crate::explain::ErrorCode;
+use super::disc_tree::DiscTree;
+use super::compare::{diff_lines, Comparer, Item};
 
 /// The information associated to a defined [`Sort`].
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub struct Sort {
   /// The sort's name, as an atom.
   pub atom: AtomId,
@@ -36,7 +40,7 @@ pub struct Sort {
 /// The type of a variable in the binder list of an `axiom`/`term`/`def`/`theorem`.
 /// The variables themselves are not named because their names are derived from their
 /// positions in the binder list (i.e. `{v0 : s} (v1 : t v0) (v2 : t)`)
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, EnvDebug)]
 #[allow(variant_size_differences)]
 pub enum Type {
   /// A bound variable `{x : s}`, where `s` is the provided [`SortId`].
@@ -66,7 +70,7 @@ impl Type {
 
 /// An [`ExprNode`] is interpreted inside a context containing the `Vec<`[`Type`]`>`
 /// args and the `Vec<ExprNode>` heap.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum ExprNode {
   /// `Ref(n)` is a reference to heap element `n` (the first `args.len()` of them are the variables)
   Ref(usize),
@@ -78,7 +82,7 @@ pub enum ExprNode {
 
 /// The `Expr` type stores expression dags using a local context of expression nodes
 /// and a final expression. See [`ExprNode`] for explanation of the variants.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub struct Expr {
   /// The heap, which is used for subexpressions that appear multiple times.
   /// The first `args.len()` elements of the heap are fixed to the variables.
@@ -88,7 +92,7 @@ pub struct Expr {
 }
 
 /// The value of a term or def.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum TermKind {
   /// This is a `term`, which has no definition
   Term,
@@ -99,7 +103,7 @@ pub enum TermKind {
 }
 
 /// The data associated to a `term` or `def` declaration.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub struct Term {
   /// The name of the term, as an atom.
   pub atom: AtomId,
@@ -129,7 +133,7 @@ pub struct Term {
 /// more constructors, so a [`ProofNode`] can represent an expr, a proof, or a conversion,
 /// and the typing determines which. A [`ProofNode`] is interpreted in a context of
 /// variables `Vec<Type>`, and a heap `Vec<ProofNode>`.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum ProofNode {
   /// `Ref(n)` is a reference to heap element `n` (the first `args.len()` of them are the variables).
   /// This could be an expr, proof, or conv depending on what is referenced.
@@ -190,6 +194,33 @@ impl ProofNode {
       }
     }
   }
+
+  /// Call `f` on each immediate subterm of this node, in order.
+  /// Heap references are not followed.
+  pub fn for_each_child<'a>(&'a self, mut f: impl FnMut(&'a ProofNode)) {
+    match self {
+      ProofNode::Ref(_) | ProofNode::Dummy(..) => {}
+      ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} => args.iter().for_each(f),
+      ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => f(&**e),
+      ProofNode::Thm {args, res, ..} => { args.iter().for_each(&mut f); f(&**res) }
+      ProofNode::Conv(p) => { f(&p.0); f(&p.1); f(&p.2) }
+      ProofNode::Unfold {args, res, ..} => { args.iter().for_each(&mut f); f(&res.0); f(&res.1) }
+    }
+  }
+
+  /// Push the theorems applied in this proof onto `out`, skipping those already present.
+  /// Heap references are not followed.
+  pub fn thms_used(&self, out: &mut Vec<ThmId>) {
+    match self {
+      &ProofNode::Thm {thm, ref args, ..} => {
+        if !out.contains(&thm) { out.push(thm) }
+        for p in &**args { p.thms_used(out) }
+      }
+      ProofNode::Conv(p) => p.2.thms_used(out),
+      ProofNode::Hyp(..) | ProofNode::Ref(_) | ProofNode::Dummy(..) | ProofNode::Term {..} |
+      ProofNode::Refl(_) | ProofNode::Sym(_) | ProofNode::Cong {..} | ProofNode::Unfold {..} => {}
+    }
+  }
 }
 
 impl From<&ExprNode> for ProofNode {
@@ -206,7 +237,7 @@ impl From<&ExprNode> for ProofNode {
 
 /// The [`Proof`] type stores proof term dags using a local context of proof nodes
 /// and a final proof. See [`ProofNode`] for explanation of the variants.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub struct Proof {
   /// The heap, which is used for subexpressions that appear multiple times.
   /// The first `args.len()` elements of the heap are fixed to the variables.
@@ -221,10 +252,35 @@ pub struct Proof {
   pub hyps: Box<[ProofNode]>,
   /// The target proof term.
   pub head: ProofNode,
+  /// The source map of the proof, sorted by step. Each entry pairs a step (an index into
+  /// [`steps`](Self::steps)) with the span of the tactic or lisp expression that produced it.
+  /// Steps with no known origin, such as those from subterms of the statement, are omitted.
+  pub spans: Box<[(u32, FileSpan)]>,
+}
+
+impl Proof {
+  /// The steps of the proof, in preorder: the heap elements, then the hypotheses,
+  /// then the head, with heap references counted as steps but not followed.
+  /// This is the numbering used by the source map [`spans`](Self::spans).
+  #[must_use] pub fn steps(&self) -> Vec<&ProofNode> {
+    fn rec<'a>(p: &'a ProofNode, out: &mut Vec<&'a ProofNode>) {
+      out.push(p);
+      p.for_each_child(|p| rec(p, out))
+    }
+    let mut out = vec![];
+    for p in self.heap.iter().chain(&*self.hyps).chain(Some(&self.head)) { rec(p, &mut out) }
+    out
+  }
+
+  /// Get the span of the expression that produced step `n` of the proof, if known.
+  #[must_use] pub fn step_span(&self, n: u32) -> Option<&FileSpan> {
+    let i = self.spans.binary_search_by_key(&n, |p| p.0).ok()?;
+    Some(&self.spans[i].1)
+  }
 }
 
 /// The proof of the axiom or theorem.
-#[derive(Clone, Debug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum ThmKind {
   /// This is an `axiom`, which has no proof.
   Axiom,
@@ -269,6 +325,20 @@ pub struct Thm {
   pub kind: ThmKind,
 }
 
+impl Thm {
+  /// The head term constructor of the conclusion, or `None` if the conclusion is a variable.
+  #[must_use] pub fn head(&self) -> Option<TermId> {
+    let mut e = &self.ret;
+    loop {
+      match *e {
+        ExprNode::Ref(i) if i >= self.args.len() => e = &self.heap[i],
+        ExprNode::App(t, _) => return Some(t),
+        _ => return None,
+      }
+    }
+  }
+}
+
 /// An `output string` directive, which is anonymous and hence stored directly
 /// in the [`StmtTrace`] list.
 #[derive(Clone, Debug, DeepSizeOf)]
@@ -348,6 +418,32 @@ pub enum Coe {
   Trans(Arc<Coe>, SortId, Arc<Coe>),
 }
 
+/// A numeral system for a sort, registered using `set-numeral`, which allows decimal
+/// numbers like `123` to be used in math formulas of that sort.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct Numeral {
+  /// The nullary term constructor for `0`.
+  pub zero: TermId,
+  /// The digit constructors, which are unary. If there is only one, it is a successor
+  /// function, with `d n = n + 1`; otherwise `digits[i] n = b * n + i` where `b` is the
+  /// number of digits.
+  pub digits: Box<[TermId]>,
+}
+
+/// A string literal system for a sort, registered using `set-string`, which allows
+/// string literals like `"foo"` to be used in math formulas of that sort. The characters
+/// are elaborated using the numeral system of the character sort.
+#[derive(Copy, Clone, Debug, DeepSizeOf)]
+pub struct StringLit {
+  /// The nullary term constructor for the empty string.
+  pub nil: TermId,
+  /// The term constructor `cons: char > s > s`, which adds a character to the front of a string.
+  pub cons: TermId,
+}
+
+/// The largest number that will be elaborated into a unary numeral (a successor chain).
+pub const MAX_UNARY_NUMERAL: usize = 1 << 12;
+
 impl Coe {
   fn write_arrows_r(&self, sorts: &SortVec<Sort>, s: &mut String, related: &mut Vec<(FileSpan, BoxError)>,
       sl: SortId, sr: SortId) -> Result<(), std::fmt::Error> {
@@ -398,6 +494,14 @@ pub struct ParserEnv {
   /// and there is one `(c, infx)` for each constant `c` that maps to `t`, where `infx` is true
   /// if `c` is infix and false if `c` is prefix.
   pub decl_nota: HashMap<TermId, (bool, Vec<(ArcString, bool)>)>,
+  /// A map of sorts to the numeral system used to elaborate number literals in that sort.
+  pub numerals: HashMap<SortId, Numeral>,
+  /// A map of sorts to the string system used to elaborate string literals in that sort.
+  pub strings: HashMap<SortId, StringLit>,
+  /// A map of term constructors to the mask of their implicit arguments, set using
+  /// `set-implicit`. An application that omits exactly the implicit arguments has them
+  /// filled in with `_`.
+  pub implicits: HashMap<TermId, Box<[bool]>>,
 }
 
 /// The merge strategy for a lisp definition, which allows a global to be multiply-declared,
@@ -486,6 +590,12 @@ pub enum ObjectKind {
   /// This is a global lisp definition; hovering yields the lisp definition line and go-to-definition works.
   /// Either `lisp` or `graveyard` for the atom must be non-`None` if this is constructed
   Global(AtomId),
+  /// This is a local lisp variable, with the span of its binder; hovering shows the binder
+  /// and go-to-definition goes to it.
+  LispLocal(AtomId, Span),
+  /// This is a variable in an MMC program, with its type at this point and the span of its
+  /// binder; hovering shows the type and go-to-definition goes to the binder.
+  MmcVar(Box<str>, Span),
   /// This is an expression; hovering shows the type and go-to-definition goes to the head term definition
   Expr(FrozenLispVal),
   /// This is a proof; hovering shows the intermediate statement
@@ -516,6 +626,19 @@ impl ObjectKind {
   }
 }
 
+/// The correspondences between sorts, terms and theorems registered by `add-transfer`.
+/// The `transfer` tactic translates a theorem by replacing each sort, term and theorem
+/// in its statement and proof by the corresponding one (see [`Environment::transfer_thm`]).
+#[derive(Clone, Default, Debug, DeepSizeOf)]
+pub struct TransferMap {
+  /// The sort correspondences
+  pub sorts: HashMap<SortId, SortId>,
+  /// The term correspondences
+  pub terms: HashMap<TermId, TermId>,
+  /// The theorem correspondences, including the theorems produced by `transfer`
+  pub thms: HashMap<ThmId, ThmId>,
+}
+
 /// The main environment struct, containing all permanent data to be exported from an MM1 file.
 #[derive(Debug, DeepSizeOf)]
 pub struct Environment {
@@ -527,6 +650,24 @@ pub struct Environment {
   pub terms: TermVec<Term>,
   /// The theorem/axiom map, which is a vector because theorem names are allocated in order.
   pub thms: ThmVec<Thm>,
+  /// An index of the theorems by their conclusions.
+  pub thm_index: DiscTree,
+  /// The hint database used by the `auto` tactic, in the order the hints were added.
+  pub hints: Vec<ThmId>,
+  /// The set of theorems in [`hints`](Self::hints), for fast membership tests.
+  pub hint_set: HashSet<ThmId>,
+  /// The theorems whose proofs were skipped because proof checking was disabled
+  /// (by `--no-proofs` or `(check-proofs #f)`). They have no proof, like theorems with a
+  /// missing proof, but they are not counted as incomplete.
+  pub unchecked: HashSet<ThmId>,
+  /// The correspondences registered by `add-transfer`, which are used by `transfer`.
+  pub transfer: TransferMap,
+  /// The handlers registered by `set-extra-args-handler`, which are called by the default
+  /// `refine-extra-args` when a theorem is applied to more arguments than it takes.
+  pub extra_args: HashMap<ThmId, LispVal>,
+  /// The attributes of sorts, terms and theorems, that is, the source text of the
+  /// annotations on their declarations (other than `@(allow)` and `@(expect)`), in order.
+  pub attrs: HashMap<AtomId, Vec<ArcString>>,
   /// The map from strings to allocated atoms. This is used to ensure atom injectivity
   pub atoms: HashMap<ArcString, AtomId>,
   /// The atom map, which is a vector because atoms are allocated in order.
@@ -535,6 +676,10 @@ pub struct Environment {
   pub stmts: Vec<StmtTrace>,
   /// The list of spans that have been collected in the current statement.
   pub spans: Vec<Spans<ObjectKind>>,
+  /// The `do` blocks that can be reused by the next elaboration of the file.
+  pub do_cache: super::do_cache::DoCache,
+  /// The settings of the pretty printer, set by `set-pp-options`. These are not imported.
+  pub pp: super::lisp::pretty::PpOptions,
 }
 
 impl Environment {
@@ -555,10 +700,39 @@ impl Environment {
       pe: Default::default(),
       terms: Default::default(),
       thms: Default::default(),
+      thm_index: Default::default(),
+      hints: Default::default(),
+      hint_set: Default::default(),
+      unchecked: Default::default(),
+      transfer: Default::default(),
+      extra_args: Default::default(),
+      attrs: Default::default(),
       stmts: Default::default(),
       spans: Default::default(),
+      do_cache: Default::default(),
+      pp: Default::default(),
+    }
+  }
+}
+
+/// Returns true if the theorems `td1` and `td2` have the same binders, hypotheses and
+/// conclusion, up to the names of the variables and the sharing of subterms.
+fn same_statement(td1: &Thm, td2: &Thm) -> bool {
+  fn eq(td1: &Thm, e1: &ExprNode, td2: &Thm, e2: &ExprNode) -> bool {
+    match (e1, e2) {
+      (&ExprNode::Ref(i), _) if i >= td1.args.len() => eq(td1, &td1.heap[i], td2, e2),
+      (_, &ExprNode::Ref(j)) if j >= td2.args.len() => eq(td1, e1, td2, &td2.heap[j]),
+      (&ExprNode::Ref(i), &ExprNode::Ref(j)) => i == j,
+      (ExprNode::App(t1, es1), ExprNode::App(t2, es2)) => t1 == t2 && es1.len() == es2.len() &&
+        es1.iter().zip(&**es2).all(|(e1, e2)| eq(td1, e1, td2, e2)),
+      _ => false,
     }
   }
+  td1.args.len() == td2.args.len() &&
+  td1.args.iter().zip(&*td2.args).all(|((_, ty1), (_, ty2))| ty1 == ty2) &&
+  td1.hyps.len() == td2.hyps.len() &&
+  td1.hyps.iter().zip(&*td2.hyps).all(|((_, e1), (_, e2))| eq(td1, e1, td2, e2)) &&
+  eq(td1, &td1.ret, td2, &td2.ret)
 }
 
 /// An implementation of a map `u8 -> bool` using a 32 byte array as a bitset.
@@ -578,6 +752,50 @@ impl Delims {
   }
 }
 
+/// A name alignment map, which declares that the declaration `foo` in one development
+/// corresponds to `bar` in another. When importing a file, the foreign name `foo` is
+/// renamed to `bar` (so if `bar` is already declared with the same statement, the two
+/// are identified). A separate map is used for exporting (see
+/// [`set_export_alignment`](crate::set_export_alignment)), where the local name `foo` is
+/// written out as `bar`.
+#[derive(Clone, Default, Debug)]
+pub struct Alignment(HashMap<ArcString, ArcString>);
+
+impl Alignment {
+  /// Returns true if there are no renamings in the map.
+  #[must_use] pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+  /// Declare that the name `from` should be renamed to `to`.
+  pub fn insert(&mut self, from: ArcString, to: ArcString) { self.0.insert(from, to); }
+
+  /// Get the aligned name of `name`, which is `name` itself if it is not renamed.
+  #[must_use] pub fn get<'a>(&'a self, name: &'a ArcString) -> &'a ArcString {
+    self.0.get(name).unwrap_or(name)
+  }
+
+  /// Get the new name of `name`, if it is renamed.
+  #[must_use] pub fn rename(&self, name: &[u8]) -> Option<&ArcString> { self.0.get(name) }
+
+  /// The renamings in the map, as `(from, to)` pairs in no particular order.
+  pub fn iter(&self) -> impl Iterator<Item=(&ArcString, &ArcString)> { self.0.iter() }
+
+  /// Parse an alignment file. Each line has the form `foo = bar`, and `--` starts
+  /// a line comment.
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let mut align = Self::default();
+    for (n, line) in text.lines().enumerate() {
+      let line = line.split("--").next().unwrap_or_default().trim();
+      if line.is_empty() { continue }
+      match line.split_once('=') {
+        Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() =>
+          align.insert(from.trim().as_bytes().into(), to.trim().as_bytes().into()),
+        _ => return Err(format!("line {}: expected 'foo = bar'", n + 1))
+      }
+    }
+    Ok(align)
+  }
+}
+
 /// An auxiliary structure for performing [`Environment`] deep copies. This is needed
 /// because [`AtomId`]s from other, previously elaborated files may not be consistent with
 /// the current file, so we have to remap them to the current file's namespace
@@ -606,6 +824,73 @@ pub struct Remapper {
   /// That way, a mutable cell is remapped to another mutable cell, but we can
   /// detect cycles and correctly remap them into cycles.
   pub(crate) refs: HashMap<*const FrozenLispRef, LispVal>,
+  /// The spans in lisp code that have moved, as pairs `(old, start)` of the old span of a
+  /// region and its new start position. This is used when lisp values are reused from a
+  /// previous elaboration of the same file (see [`do_cache`](super::do_cache)), and is empty
+  /// for imports.
+  pub(crate) moved: Vec<(Span, usize)>,
+}
+
+impl Remapper {
+  /// Create a remapper from the environment `other` into `env`, renaming atoms
+  /// according to `align`.
+  pub fn new(env: &mut Environment, other: &FrozenEnv, align: &Alignment) -> Self {
+    Remapper {
+      atom: other.data().iter().map(|d| env.get_atom_arc(align.get(d.name()).clone())).collect(),
+      ..Default::default()
+    }
+  }
+
+  /// Create a remapper from the environment `other` into `env`, for translating values
+  /// between two environments that both have the declarations of `other`, for example
+  /// because `env` imports the same files. Unlike [`new`](Self::new), which is used while
+  /// the declarations of `other` are merged into `env`, the sorts, terms and theorems are
+  /// matched up by name (after renaming according to `align`) right away.
+  /// Returns the name of a sort or declaration of `other` that is missing from `env`.
+  pub fn by_name(env: &mut Environment, other: &FrozenEnv, align: &Alignment
+  ) -> Result<Self, ArcString> {
+    let mut r = Self::new(env, other, align);
+    let missing = |a: AtomId| env.data[a].name.clone();
+    for (_, sd) in other.sorts().enum_iter() {
+      let a = r.atom[sd.atom];
+      r.sort.push(env.data[a].sort.ok_or_else(|| missing(a))?);
+    }
+    for (_, td) in other.terms().enum_iter() {
+      let a = r.atom[td.atom];
+      match env.data[a].decl {
+        Some(DeclKey::Term(t)) => { r.term.push(t); }
+        _ => return Err(missing(a)),
+      }
+    }
+    for (_, td) in other.thms().enum_iter() {
+      let a = r.atom[td.atom];
+      match env.data[a].decl {
+        Some(DeclKey::Thm(t)) => { r.thm.push(t); }
+        _ => return Err(missing(a)),
+      }
+    }
+    Ok(r)
+  }
+
+  /// Create a remapper from `env` into itself, which replaces sorts, terms and theorems
+  /// according to `map` and leaves everything else unchanged.
+  #[must_use] pub fn transfer(env: &Environment, map: &TransferMap) -> Self {
+    Remapper {
+      sort: env.sorts.enum_iter().map(|(s, _)| map.sorts.get(&s).copied().unwrap_or(s)).collect(),
+      term: env.terms.enum_iter().map(|(t, _)| map.terms.get(&t).copied().unwrap_or(t)).collect(),
+      thm: env.thms.enum_iter().map(|(t, _)| map.thms.get(&t).copied().unwrap_or(t)).collect(),
+      atom: env.data.enum_iter().map(|(a, _)| a).collect(),
+      ..Default::default()
+    }
+  }
+
+  /// The new position of span `sp` in the current file, see [`moved`](Self::moved).
+  #[must_use] pub fn span(&self, sp: Span) -> Span {
+    match self.moved.iter().find(|(old, _)| old.start <= sp.start && sp.end <= old.end) {
+      Some(&(old, start)) => (sp.start - old.start + start .. sp.end - old.start + start).into(),
+      None => sp,
+    }
+  }
 }
 
 /// A trait for types that can be remapped.
@@ -632,6 +917,14 @@ impl Remap for AtomId {
   type Target = Self;
   fn remap(&self, r: &mut Remapper) -> Self { r.atom[*self] }
 }
+impl Remap for Span {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self { r.span(*self) }
+}
+impl Remap for FileSpan {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self { FileSpan {file: self.file.clone(), span: r.span(self.span)} }
+}
 impl Remap for bool {
   type Target = Self;
   fn remap(&self, _: &mut Remapper) -> Self { *self }
@@ -767,6 +1060,7 @@ impl Remap for Proof {
       heap: self.heap.remap(r),
       hyps: self.hyps.remap(r),
       head: self.head.remap(r),
+      spans: self.spans.iter().map(|(i, fsp)| (*i, fsp.remap(r))).collect(),
     }
   }
 }
@@ -808,6 +1102,18 @@ impl Remap for NotaInfo {
     }
   }
 }
+impl Remap for Numeral {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    Numeral { zero: self.zero.remap(r), digits: self.digits.remap(r) }
+  }
+}
+impl Remap for StringLit {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    StringLit { nil: self.nil.remap(r), cons: self.cons.remap(r) }
+  }
+}
 impl Remap for Coe {
   type Target = Self;
   fn remap(&self, r: &mut Remapper) -> Self {
@@ -987,6 +1293,15 @@ impl ParserEnv {
         }
       }
     }
+    for (s, num) in &other.numerals {
+      self.numerals.insert(s.remap(r), num.remap(r));
+    }
+    for (s, sl) in &other.strings {
+      self.strings.insert(s.remap(r), sl.remap(r));
+    }
+    for (t, imp) in &other.implicits {
+      self.implicits.insert(t.remap(r), imp.clone());
+    }
     self.update_provs(sp, sorts).unwrap_or_else(|r| errors.push(r))
   }
 }
@@ -1018,6 +1333,316 @@ impl Environment {
   #[must_use] pub fn thm(&self, a: AtomId) -> Option<ThmId> {
     if let Some(DeclKey::Thm(i)) = self.data[a].decl { Some(i) } else { None }
   }
+
+  /// Register the correspondence `x ~> y` for `transfer`, where `x` and `y` are both sorts,
+  /// both terms or both theorems. Corresponding sorts must have the same modifiers,
+  /// corresponding terms must have corresponding signatures, and corresponding theorems
+  /// must have corresponding statements, using the correspondences registered so far.
+  pub fn add_transfer(&mut self, x: AtomId, y: AtomId) -> Result<(), String> {
+    let (dx, dy) = (&self.data[x], &self.data[y]);
+    let (sorts, decls) = ((dx.sort, dy.sort), (dx.decl, dy.decl));
+    let err = |env: &Self, what: &str| Err(format!("the {} of '{}' and '{}' do not correspond",
+      what, env.data[x].name, env.data[y].name));
+    let mut found = false;
+    if let (Some(s1), Some(s2)) = sorts {
+      if self.sorts[s1].mods != self.sorts[s2].mods { return err(self, "modifiers") }
+      self.transfer.sorts.insert(s1, s2);
+      found = true;
+    }
+    match decls {
+      (Some(DeclKey::Term(t1)), Some(DeclKey::Term(t2))) => {
+        let mut r = Remapper::transfer(self, &self.transfer);
+        let (td1, td2) = (&self.terms[t1], &self.terms[t2]);
+        if td1.args.len() != td2.args.len() ||
+          td1.args.iter().zip(&*td2.args).any(|((_, ty1), (_, ty2))| ty1.remap(&mut r) != *ty2) ||
+          (td1.ret.0.remap(&mut r), td1.ret.1) != td2.ret {
+          return err(self, "signatures")
+        }
+        self.transfer.terms.insert(t1, t2);
+      }
+      (Some(DeclKey::Thm(t1)), Some(DeclKey::Thm(t2))) => {
+        let td1 = self.thms[t1].remap(&mut Remapper::transfer(self, &self.transfer));
+        if !same_statement(&td1, &self.thms[t2]) { return err(self, "statements") }
+        self.transfer.thms.insert(t1, t2);
+      }
+      _ if found => {}
+      _ => return Err(format!("'{}' and '{}' are not both sorts, terms or theorems",
+        self.data[x].name, self.data[y].name))
+    }
+    Ok(())
+  }
+
+  /// Translate the theorem `t` along the correspondences registered by `add-transfer`,
+  /// producing a theorem named `x` declared at `span` (with the whole declaration at `full`).
+  /// The proof is translated along with the statement and then checked, because the sorts,
+  /// terms and theorems without a correspondence are left unchanged, and the translated
+  /// proof is only valid if every theorem it uses has a translation.
+  pub fn transfer_thm(&self, t: ThmId, x: AtomId, span: FileSpan, full: Span) -> Result<Thm, String> {
+    let td = &self.thms[t];
+    match td.kind {
+      ThmKind::Axiom => return Err(format!("cannot transfer axiom '{}'", self.data[td.atom].name)),
+      ThmKind::Thm(None) => return Err(format!("'{}' has no proof", self.data[td.atom].name)),
+      ThmKind::Thm(Some(_)) => {}
+    }
+    let mut thm = td.remap(&mut Remapper::transfer(self, &self.transfer));
+    thm.atom = x;
+    thm.span = span;
+    thm.full = full;
+    thm.doc = None;
+    if let ThmKind::Thm(Some(pf)) = &mut thm.kind { pf.spans = Box::new([]) }
+    super::verify::verify(self, &thm).map_err(|e|
+      format!("the translated proof of '{}' does not check: {}", self.data[td.atom].name, e))?;
+    Ok(thm)
+  }
+
+  /// Compute the set of incomplete theorems. A theorem is incomplete if it has no proof
+  /// (because it used `sorry`, or the proof failed), or if its proof refers to an incomplete
+  /// theorem. Axioms and theorems whose proofs were [not checked](Self::unchecked) are
+  /// never incomplete.
+  #[must_use] pub fn incomplete_thms(&self) -> ThmVec<bool> {
+    fn uses(inc: &ThmVec<bool>, p: &ProofNode) -> bool {
+      match p {
+        ProofNode::Ref(_) | ProofNode::Dummy(..) => false,
+        ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} =>
+          args.iter().any(|p| uses(inc, p)),
+        ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => uses(inc, p),
+        ProofNode::Thm {thm, args, res} =>
+          inc[*thm] || args.iter().any(|p| uses(inc, p)) || uses(inc, res),
+        ProofNode::Conv(p) => uses(inc, &p.0) || uses(inc, &p.1) || uses(inc, &p.2),
+        ProofNode::Unfold {args, res, ..} =>
+          args.iter().any(|p| uses(inc, p)) || uses(inc, &res.0) || uses(inc, &res.1),
+      }
+    }
+    let mut inc = ThmVec::default();
+    for (t, td) in self.thms.enum_iter() {
+      let b = match &td.kind {
+        ThmKind::Axiom => false,
+        ThmKind::Thm(None) => !self.unchecked.contains(&t),
+        ThmKind::Thm(Some(pr)) => pr.heap.iter().chain(Some(&pr.head)).any(|p| uses(&inc, p)),
+      };
+      inc.push(b);
+    }
+    inc
+  }
+
+  /// Add a theorem to the hint database used by the `auto` tactic, if it is not already there.
+  pub fn add_hint(&mut self, t: ThmId) {
+    if self.hint_set.insert(t) { self.hints.push(t) }
+  }
+
+  /// Find all theorems whose statement (hypotheses or conclusion) mentions the term `t`,
+  /// grouped by the head term constructor of the conclusion (`None` if the conclusion is
+  /// a variable). Groups are ordered by their first theorem, and theorems are in
+  /// declaration order.
+  #[must_use] pub fn theorems_about(&self, t: TermId) -> Vec<(Option<TermId>, Vec<ThmId>)> {
+    let mut groups: Vec<(Option<TermId>, Vec<ThmId>)> = vec![];
+    for &i in self.thm_index.mentioning(t) {
+      let head = self.thms[i].head();
+      match groups.iter_mut().find(|g| g.0 == head) {
+        Some(g) => g.1.push(i),
+        None => groups.push((head, vec![i])),
+      }
+    }
+    groups
+  }
+
+  fn binder_name(&self, a: Option<AtomId>) -> &str { a.map_or("_", |a| self.data[a].name.as_str()) }
+
+  fn audit_binders(&self, args: &[(Option<AtomId>, Type)], out: &mut Vec<String>) {
+    for &(a, ty) in args {
+      if let Type::Bound(s) = ty {
+        if self.sorts[s].mods.contains(Modifiers::STRICT) {
+          out.push(format!("strict sort '{}' does not admit bound variable {{{}: {}}}",
+            self.sorts[s].name, self.binder_name(a), self.sorts[s].name))
+        }
+      }
+    }
+  }
+
+  fn audit_dummy(&self, a: AtomId, s: SortId, out: &mut Vec<String>) {
+    let mods = self.sorts[s].mods;
+    let kind = if mods.contains(Modifiers::STRICT) {"strict"}
+      else if mods.contains(Modifiers::FREE) {"free"} else {return};
+    let msg = format!("{} sort '{}' does not admit dummy variable {{{}: {}}}",
+      kind, self.sorts[s].name, self.data[a].name, self.sorts[s].name);
+    if !out.contains(&msg) { out.push(msg) }
+  }
+
+  fn audit_expr(&self, e: &ExprNode, out: &mut Vec<String>) {
+    match *e {
+      ExprNode::Ref(_) => {}
+      ExprNode::Dummy(a, s) => self.audit_dummy(a, s, out),
+      ExprNode::App(_, ref es) => for e in &**es { self.audit_expr(e, out) }
+    }
+  }
+
+  /// The bound variables (as a bitset of the bound binders in `args`) that are free in
+  /// the value `val` of a definition, following the rules in `mm0.md`. Dummy variables are
+  /// not tracked, since they are not allowed to have `free` sort anyway.
+  fn free_vars(&self, args: &[(Option<AtomId>, Type)], val: &Expr) -> u64 {
+    fn go(env: &Environment, heap: &[u64], e: &ExprNode) -> u64 {
+      match *e {
+        ExprNode::Ref(i) => heap[i],
+        ExprNode::Dummy(..) => 0,
+        ExprNode::App(t, ref es) => {
+          let td = &env.terms[t];
+          let bvs = td.args.iter().zip(&**es).filter(|((_, ty), _)| ty.bound())
+            .map(|(_, e)| go(env, heap, e)).collect::<Vec<_>>();
+          let mut out = 0;
+          for (i, &bv) in bvs.iter().enumerate() {
+            if td.ret.1 & (1 << i) != 0 { out |= bv }
+          }
+          for ((_, ty), e) in td.args.iter().zip(&**es) {
+            if let Type::Reg(_, deps) = *ty {
+              let mut fv = go(env, heap, e);
+              for (i, &bv) in bvs.iter().enumerate() {
+                if deps & (1 << i) != 0 { fv &= !bv }
+              }
+              out |= fv
+            }
+          }
+          out
+        }
+      }
+    }
+    let mut heap = Vec::with_capacity(val.heap.len());
+    let mut bv = 0;
+    for &(_, ty) in args {
+      heap.push(match ty {
+        Type::Bound(_) => { bv += 1; 1 << (bv - 1) }
+        Type::Reg(_, deps) => deps,
+      })
+    }
+    for e in &val.heap[args.len()..] { let fv = go(self, &heap, e); heap.push(fv) }
+    go(self, &heap, &val.head)
+  }
+
+  fn audit_proof(&self, p: &ProofNode, out: &mut Vec<String>) {
+    match p {
+      ProofNode::Ref(_) => {}
+      &ProofNode::Dummy(a, s) => self.audit_dummy(a, s, out),
+      ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} =>
+        for p in &**args { self.audit_proof(p, out) },
+      ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => self.audit_proof(p, out),
+      ProofNode::Thm {args, res, ..} => {
+        for p in &**args { self.audit_proof(p, out) }
+        self.audit_proof(res, out)
+      }
+      ProofNode::Conv(p) => {
+        self.audit_proof(&p.0, out);
+        self.audit_proof(&p.1, out);
+        self.audit_proof(&p.2, out)
+      }
+      ProofNode::Unfold {args, res, ..} => {
+        for p in &**args { self.audit_proof(p, out) }
+        self.audit_proof(&res.0, out);
+        self.audit_proof(&res.1, out)
+      }
+    }
+  }
+
+  /// Check that a term or definition respects the modifiers of the sorts it mentions:
+  /// it does not construct a `pure` sort, has no bound variables of `strict` sort,
+  /// has no dummy variables of `strict` or `free` sort, and every bound variable of
+  /// `free` sort that is free in the definiens is a dependency of the return type.
+  /// Returns a list of violations, each naming the offending binder.
+  #[must_use] pub fn audit_term(&self, td: &Term) -> Vec<String> {
+    let mut out = vec![];
+    self.audit_binders(&td.args, &mut out);
+    let s = td.ret.0;
+    if self.sorts[s].mods.contains(Modifiers::PURE) {
+      out.push(format!("pure sort '{}' cannot have term constructors", self.sorts[s].name))
+    }
+    if let TermKind::Def(Some(val)) = &td.kind {
+      for e in val.heap.iter().chain(Some(&val.head)) { self.audit_expr(e, &mut out) }
+      let fv = self.free_vars(&td.args, val) & !td.ret.1;
+      for (i, &(a, ty)) in td.args.iter().filter(|(_, ty)| ty.bound()).enumerate() {
+        if fv & (1 << i) != 0 && self.sorts[ty.sort()].mods.contains(Modifiers::FREE) {
+          out.push(format!("free sort '{}' requires the return type to depend on {{{}: {}}}, \
+            which is free in the definition", self.sorts[ty.sort()].name, self.binder_name(a),
+            self.sorts[ty.sort()].name))
+        }
+      }
+    }
+    out
+  }
+
+  /// Check that an axiom or theorem respects the modifiers of the sorts it mentions:
+  /// its hypotheses and conclusion have `provable` sort, it has no bound variables of
+  /// `strict` sort, and its proof has no dummy variables of `strict` or `free` sort.
+  /// Returns a list of violations, each naming the offending binder or hypothesis.
+  #[must_use] pub fn audit_thm(&self, td: &Thm) -> Vec<String> {
+    fn sort_of(env: &Environment, td: &Thm, e: &ExprNode) -> SortId {
+      match *e {
+        ExprNode::Ref(i) if i < td.args.len() => td.args[i].1.sort(),
+        ExprNode::Ref(i) => sort_of(env, td, &td.heap[i]),
+        ExprNode::Dummy(_, s) => s,
+        ExprNode::App(t, _) => env.terms[t].ret.0,
+      }
+    }
+    let mut out = vec![];
+    self.audit_binders(&td.args, &mut out);
+    for (i, (h, e)) in td.hyps.iter().enumerate() {
+      let s = sort_of(self, td, e);
+      if !self.sorts[s].mods.contains(Modifiers::PROVABLE) {
+        let h = h.map_or_else(|| format!("#{}", i + 1), |h| format!("'{}'", self.data[h].name));
+        out.push(format!("hypothesis {} has non-provable sort '{}'", h, self.sorts[s].name))
+      }
+    }
+    let s = sort_of(self, td, &td.ret);
+    if !self.sorts[s].mods.contains(Modifiers::PROVABLE) {
+      out.push(format!("conclusion has non-provable sort '{}'", self.sorts[s].name))
+    }
+    if let ThmKind::Thm(Some(pf)) = &td.kind {
+      for p in pf.heap.iter().chain(Some(&pf.head)) { self.audit_proof(p, &mut out) }
+    }
+    out
+  }
+}
+
+/// The maximum number of names suggested by [`did_you_mean`].
+const MAX_SUGGESTIONS: usize = 3;
+
+/// The Levenshtein distance between two byte strings, or `None` if it exceeds `max`.
+fn edit_distance(a: &[u8], b: &[u8], max: usize) -> Option<usize> {
+  if a.len().max(b.len()) - a.len().min(b.len()) > max { return None }
+  let mut row = (0..=b.len()).collect::<Vec<_>>();
+  for (i, &ca) in a.iter().enumerate() {
+    let mut diag = row[0];
+    row[0] = i + 1;
+    for (j, &cb) in b.iter().enumerate() {
+      let sub = diag + usize::from(ca != cb);
+      diag = row[j + 1];
+      row[j + 1] = sub.min(diag + 1).min(row[j] + 1);
+    }
+  }
+  Some(row[b.len()]).filter(|&d| d <= max)
+}
+
+/// Find up to three names among `cands` that are close to `name` (up to typos), and
+/// format them as a suffix for an error message about the unknown identifier `name`,
+/// like `"; did you mean 'foo' or 'bar'?"`. Returns the empty string if there are none.
+pub fn did_you_mean<'a>(name: &[u8], cands: impl IntoIterator<Item = &'a [u8]>) -> String {
+  use std::fmt::Write;
+  let max = (name.len() / 3).max(1);
+  let mut found = cands.into_iter()
+    .filter(|&c| c != name)
+    .filter_map(|c| Some((edit_distance(name, c, max)?, c)))
+    .collect::<Vec<_>>();
+  found.sort_unstable();
+  found.dedup();
+  found.truncate(MAX_SUGGESTIONS);
+  let mut out = String::new();
+  for (i, (_, c)) in found.iter().enumerate() {
+    out.push_str(match i {
+      0 => "; did you mean ",
+      _ if i + 1 == found.len() => " or ",
+      _ => ", ",
+    });
+    write!(out, "'{}'", String::from_utf8_lossy(c)).expect("writing to a string");
+  }
+  if !out.is_empty() { out.push('?') }
+  out
 }
 
 /// Adding an item (sort, term, theorem, atom) can result in a redeclaration error,
@@ -1040,7 +1665,8 @@ impl<A> AddItemError<A> {
   pub fn into_elab_error(self, sp: Span) -> ElabError {
     match self {
       AddItemError::Redeclaration(_, r) =>
-        ElabError::with_info(sp, r.msg.into(), vec![(r.other, r.othermsg.into())]),
+        ElabError::with_info(sp, r.msg.into(), vec![(r.other, r.othermsg.into())])
+          .with_code(ErrorCode::Redeclaration),
       AddItemError::Overflow =>
         ElabError::new_e(sp, "too many sorts"),
     }
@@ -1126,7 +1752,9 @@ impl Environment {
       }))
     } else {
       data.decl = Some(DeclKey::Thm(new_id));
-      self.thms.push(t());
+      let t = t();
+      self.thm_index.insert_thm(&t, new_id);
+      self.thms.push(t);
       self.stmts.push(StmtTrace::Decl(a));
       Ok(new_id)
     }
@@ -1183,11 +1811,8 @@ impl Environment {
           sort.span.clone(), sort.full, sort.mods, sort.doc.clone())
         {
           Ok(id) => id,
-          Err(AddItemError::Redeclaration(id, r)) => {
-            errors.push(ElabError::with_info(sp, r.msg.into(), vec![
-              (sort.span.clone(), r.othermsg.clone().into()),
-              (r.other, r.othermsg.into())
-            ]));
+          Err(AddItemError::Redeclaration(id, _)) => {
+            if let Err(e) = self.check_duplicate(other, Item::Sort(a), sp) { errors.push(e) }
             id
           }
           Err(AddItemError::Overflow) => return Err(ElabError::new_e(sp, "too many sorts"))
@@ -1200,13 +1825,12 @@ impl Environment {
           let otd: &Term = other.term(tid);
           let id = match self.try_add_term(a.remap(remap), &otd.span, || otd.remap(remap)) {
             Ok(id) => id,
-            Err(AddItemError::Redeclaration(id, r)) => {
-              let e = ElabError::with_info(sp, r.msg.into(), vec![
-                (otd.span.clone(), r.othermsg.clone().into()),
-                (r.other, r.othermsg.into())
-              ]);
-              match id { None => return Err(e), Some(id) => {errors.push(e); id} }
-            }
+            Err(AddItemError::Redeclaration(id, _)) =>
+              match (id, self.check_duplicate(other, Item::Decl(a), sp)) {
+                (Some(id), Ok(())) => id,
+                (Some(id), Err(e)) => {errors.push(e); id}
+                (None, res) => return Err(res.expect_err("a term and a theorem can't be the same")),
+              }
             Err(AddItemError::Overflow) => return Err(ElabError::new_e(sp, "too many terms"))
           };
           assert_eq!(remap.term.len(), tid.0 as usize);
@@ -1216,25 +1840,79 @@ impl Environment {
           let otd: &Thm = other.thm(tid);
           let id = match self.try_add_thm(a.remap(remap), &otd.span, || otd.remap(remap)) {
             Ok(id) => id,
-            Err(AddItemError::Redeclaration(id, r)) => {
-              let e = ElabError::with_info(sp, r.msg.into(), vec![
-                (otd.span.clone(), r.othermsg.clone().into()),
-                (r.other, r.othermsg.into())
-              ]);
-              match id { None => return Err(e), Some(id) => {errors.push(e); id} }
-            }
+            Err(AddItemError::Redeclaration(id, _)) =>
+              match (id, self.check_duplicate(other, Item::Decl(a), sp)) {
+                (Some(id), Ok(())) => id,
+                (Some(id), Err(e)) => {errors.push(e); id}
+                (None, res) => return Err(res.expect_err("a term and a theorem can't be the same")),
+              }
             Err(AddItemError::Overflow) => return Err(ElabError::new_e(sp, "too many theorems"))
           };
           assert_eq!(remap.thm.len(), tid.0 as usize);
           remap.thm.push(id);
+          if other.is_hint(tid) { self.add_hint(id) }
+          if other.is_unchecked(tid) { self.unchecked.insert(id); }
+          if let Some(f) = other.extra_args_handler(tid) { self.extra_args.insert(id, f.remap(remap)); }
         }
       },
       StmtTrace::Global(_) => {}
       StmtTrace::OutputString(ref e) => self.stmts.push(StmtTrace::OutputString(e.remap(remap))),
     }
+    if let StmtTrace::Sort(a) | StmtTrace::Decl(a) = *s {
+      self.merge_transfer(remap, other.transfer());
+      let attrs = other.attrs(a);
+      if !attrs.is_empty() { self.attrs.insert(a.remap(remap), attrs.to_vec()); }
+    }
     Ok(())
   }
 
+  /// Check that item `it` of `other`, which is being merged into this environment, has the
+  /// same statement as the item of the same name that was already declared here in a
+  /// different place. This happens when the same declaration is imported along two paths
+  /// from different copies of a file, and the two declarations are then identified.
+  /// Otherwise the error, at the import `sp`, shows both statements.
+  fn check_duplicate(&self, other: &FrozenEnv, it: Item, sp: Span) -> Result<(), ElabError> {
+    let other = unsafe { other.thaw() };
+    let old = it.rename_env(other, self).expect("redeclared item");
+    // The definitions of defs are only compared if the first one has one, so we compare
+    // in both directions.
+    let reason = match Comparer::new_env(self, other).item(old).unwrap_or(Ok(()))
+      .and_then(|()| Comparer::new_env(other, self).item(it).unwrap_or(Ok(()))) {
+      Ok(()) => return Ok(()),
+      Err(reason) => reason,
+    };
+    let src = LinedString::default();
+    let mut info = vec![];
+    if let Some(fsp) = old.span_env(self) { info.push((fsp.clone(), "first declared here".into())) }
+    if let Some(fsp) = it.span_env(other) { info.push((fsp.clone(), "and declared here".into())) }
+    Err(ElabError::with_info(sp, format!(
+      "'{}' is imported twice with different statements: {}\n{}",
+      self.data[old.atom()].name, reason,
+      diff_lines(&old.print_env(self, &src), &it.print_env(other, &src))).into(), info)
+      .with_code(ErrorCode::Redeclaration))
+  }
+
+  /// Import the correspondences of `other` whose sorts, terms or theorems have both been
+  /// merged so far. This is called after each sort and declaration, so each correspondence
+  /// is imported as soon as its second side is.
+  fn merge_transfer(&mut self, remap: &Remapper, other: &TransferMap) {
+    for (&s1, &s2) in &other.sorts {
+      if let (Some(&s1), Some(&s2)) = (remap.sort.get(s1), remap.sort.get(s2)) {
+        self.transfer.sorts.insert(s1, s2);
+      }
+    }
+    for (&t1, &t2) in &other.terms {
+      if let (Some(&t1), Some(&t2)) = (remap.term.get(t1), remap.term.get(t2)) {
+        self.transfer.terms.insert(t1, t2);
+      }
+    }
+    for (&t1, &t2) in &other.thms {
+      if let (Some(&t1), Some(&t2)) = (remap.thm.get(t1), remap.thm.get(t2)) {
+        self.transfer.thms.insert(t1, t2);
+      }
+    }
+  }
+
   /// Return an error if the term has the wrong number of arguments, based on its declaration.
   pub(crate) fn check_term_nargs(&self, sp: Span, term: TermId, nargs: usize) -> Result<(), ElabError> {
     let td = &self.terms[term];
@@ -1242,6 +1920,122 @@ impl Environment {
     Err(ElabError::with_info(sp, "incorrect number of arguments".into(),
       vec![(td.span.clone(), "declared here".into())]))
   }
+
+  /// If an application of the term `t` to `n` arguments omits exactly the implicit
+  /// arguments of `t` (see `set-implicit`), returns the implicit argument mask.
+  #[must_use] pub fn omits_implicits(&self, t: TermId, n: usize) -> Option<&[bool]> {
+    let imp = self.pe.implicits.get(&t)?;
+    if n != imp.iter().filter(|&&b| !b).count() || n == imp.len() { return None }
+    Some(imp)
+  }
+
+  /// If `args` omits exactly the implicit arguments of the term `t` (see `set-implicit`),
+  /// returns the full argument list with `_` in the implicit positions. These must then be
+  /// determined by unification.
+  #[must_use] pub fn insert_implicits(&self, t: TermId, args: &[LispVal]) -> Option<Vec<LispVal>> {
+    let imp = self.omits_implicits(t, args.len())?;
+    let mut it = args.iter();
+    Some(imp.iter().map(|&b| if b { LispVal::atom(AtomId::UNDER) } else {
+      it.next().expect("counted above").clone()
+    }).collect())
+  }
+
+  /// Elaborate a number or string literal `e` with target sort `s`, using the systems
+  /// registered by `set-numeral` and `set-string`. If `s` is unknown or has no such system,
+  /// this uses the only one, if there is exactly one. Returns `None` if `e` is not a literal
+  /// or there is no applicable system, and otherwise the sort and the elaborated term.
+  #[must_use] pub fn mk_literal(&self, e: &LispVal, s: Option<SortId>) -> Option<Result<(SortId, LispVal), String>> {
+    fn system<T>(map: &HashMap<SortId, T>, s: Option<SortId>) -> Option<(SortId, &T)> {
+      match s.and_then(|s| map.get_key_value(&s)) {
+        Some((&s, v)) => Some((s, v)),
+        None if map.len() == 1 => map.iter().next().map(|(&s, v)| (s, v)),
+        None => None,
+      }
+    }
+    if let Some(n) = e.as_int(BigInt::to_biguint).flatten() {
+      let (s, num) = system(&self.pe.numerals, s)?;
+      Some(self.mk_numeral(num, &n).map(|e| (s, e)))
+    } else if let Some(val) = e.unwrapped(|e| if let LispKind::String(s) = e {Some(s.clone())} else {None}) {
+      let (s, sl) = system(&self.pe.strings, s)?;
+      Some(self.mk_string(sl, &val).map(|e| (s, e)))
+    } else { None }
+  }
+
+  /// Build the term for the number `n` in the numeral system `num`.
+  pub fn mk_numeral(&self, num: &Numeral, n: &BigUint) -> Result<LispVal, String> {
+    let b = num.digits.len();
+    let mut ds = vec![];
+    if b == 1 {
+      let k = n.to_usize().filter(|&k| k <= MAX_UNARY_NUMERAL)
+        .ok_or_else(|| format!("numeral {} is too large for a unary representation", n))?;
+      ds.resize(k, 0)
+    } else {
+      let (mut n, base) = (n.clone(), BigUint::from(b));
+      while !n.is_zero() {
+        ds.push((&n % &base).to_usize().expect("digit < base"));
+        n /= &base;
+      }
+    }
+    let mut e = LispVal::list(vec![LispVal::atom(self.terms[num.zero].atom)]);
+    for &i in ds.iter().rev() {
+      e = LispVal::list(vec![LispVal::atom(self.terms[num.digits[i]].atom), e])
+    }
+    Ok(e)
+  }
+
+  /// Build the term for the string `val` in the string system `sl`.
+  pub fn mk_string(&self, sl: &StringLit, val: &[u8]) -> Result<LispVal, String> {
+    let cs = self.terms[sl.cons].args[0].1.sort();
+    let num = self.pe.numerals.get(&cs).ok_or_else(||
+      format!("no numeral system for sort '{}', needed for string literals", self.sorts[cs].name))?;
+    let mut e = LispVal::list(vec![LispVal::atom(self.terms[sl.nil].atom)]);
+    for &c in val.iter().rev() {
+      let c = self.mk_numeral(num, &c.into())?;
+      e = LispVal::list(vec![LispVal::atom(self.terms[sl.cons].atom), c, e])
+    }
+    Ok(e)
+  }
+
+  /// If `e` is a string in the string system of its sort, return its value.
+  #[must_use] pub fn string_value(&self, e: &LispVal) -> Option<Vec<u8>> {
+    let mut u = Uncons::from(e.clone());
+    let mut t = self.term(u.next()?.as_atom()?)?;
+    let sl = self.pe.strings.get(&self.terms[t].ret.0)?;
+    let mut out = vec![];
+    while t == sl.cons {
+      let c = self.numeral_value(&u.next()?)?.to_u8()?;
+      let e = u.next()?;
+      if !u.exactly(0) { return None }
+      out.push(c);
+      u = Uncons::from(e);
+      t = self.term(u.next()?.as_atom()?)?;
+    }
+    if t != sl.nil || !u.exactly(0) { return None }
+    Some(out)
+  }
+
+  /// If `e` is a numeral in the numeral system of its sort, return its value. Only the
+  /// canonical form produced by [`mk_numeral`](Self::mk_numeral) is accepted, so (unless
+  /// the system is unary) the digit applied to `zero` may not be the zero digit.
+  #[must_use] pub fn numeral_value(&self, e: &LispVal) -> Option<BigUint> {
+    let mut u = Uncons::from(e.clone());
+    let mut t = self.term(u.next()?.as_atom()?)?;
+    let num = self.pe.numerals.get(&self.terms[t].ret.0)?;
+    let mut ds = vec![];
+    while t != num.zero {
+      ds.push(num.digits.iter().position(|&d| d == t)?);
+      let e = u.next()?;
+      if !u.exactly(0) { return None }
+      u = Uncons::from(e);
+      t = self.term(u.next()?.as_atom()?)?;
+    }
+    if !u.exactly(0) { return None }
+    let b = num.digits.len();
+    if b != 1 && ds.last() == Some(&0) { return None }
+    Some(if b == 1 { ds.len().into() } else {
+      ds.iter().rev().fold(BigUint::zero(), |n, &i| n * b + i)
+    })
+  }
 }
 
 /// An iterator-like interface to environment merging. This is required because
@@ -1278,13 +2072,10 @@ pub struct AwaitingMerge<'a> {
 }
 
 impl<'a> EnvMergeIter<'a> {
-  /// Starts an environment merge operation.
-  pub fn new(env: &mut Environment, other: &'a FrozenEnv, sp: Span) -> Self {
-    let remap = Remapper {
-      atom: other.data().iter().map(|d| env.get_atom_arc(d.name().clone())).collect(),
-      ..Default::default()
-    };
-    Self {remap, other, sp, it: other.stmts().iter()}
+  /// Starts an environment merge operation. Names in `other` are renamed according to
+  /// `align` as they are imported.
+  pub fn new(env: &mut Environment, other: &'a FrozenEnv, align: &Alignment, sp: Span) -> Self {
+    Self {remap: Remapper::new(env, other, align), other, sp, it: other.stmts().iter()}
   }
 
   /// Poll the environment merge iterator for a result.
@@ -12,7 +12,9 @@
 //! [`Rc`], it is not safe to clone a [`LispVal`] during this period, and instead the
 //! [`FrozenLispVal`] type is used as a read only wrapper around [`LispVal`].
 //! At this point the data becomes safe to read from other threads, such as other files
-//! that `import` this one and were waiting on elaboration to complete.
+//! that `import` this one and were waiting on elaboration to complete, or the exporters
+//! and documentation generator, which `mm0-rs build` runs concurrently with the
+//! elaboration of later targets.
 //!
 //! There are two ways in which this strict time separation is violated. One is that
 //! an elaborator may yield when it hits an `import` statement, while waiting for that
@@ -39,7 +41,7 @@ use std::collections::{HashMap, hash_map::Entry};
 use num::BigInt;
 use crate::{mk_lisp_kind, ArcString, AtomData, AtomId, AtomVec, DeclKey, DocComment, Environment,
   FileSpan, LinedString, LispData, LispKind, LispVal, MergeStrategy, MergeStrategyInner, ParserEnv, Sort,
-  SortId, SortVec, Span, StmtTrace, Term, TermId, TermVec, Thm, ThmId, ThmVec,
+  SortId, SortVec, Span, StmtTrace, Term, TermId, TermVec, Thm, ThmId, ThmVec, TransferMap,
   lisp::{print::FormatEnv, Annot, InferTarget, LispRef, LispWeak, Proc, Syntax}};
 use super::{ObjectKind, Remap, Remapper, Spans};
 
@@ -95,6 +97,34 @@ impl FrozenEnv {
   #[must_use] pub fn thms(&self) -> &ThmVec<Thm> { &unsafe { self.thaw() }.thms }
   /// Accessor for [`Environment::thms`]
   #[must_use] pub fn thm(&self, t: ThmId) -> &Thm { &self.thms()[t] }
+  /// Accessor for [`Environment::incomplete_thms`]
+  #[must_use] pub fn incomplete_thms(&self) -> ThmVec<bool> { unsafe { self.thaw() }.incomplete_thms() }
+  /// Accessor for [`Environment::audit_term`]
+  #[must_use] pub fn audit_term(&self, t: TermId) -> Vec<String> { unsafe { self.thaw() }.audit_term(self.term(t)) }
+  /// Accessor for [`Environment::audit_thm`]
+  #[must_use] pub fn audit_thm(&self, t: ThmId) -> Vec<String> { unsafe { self.thaw() }.audit_thm(self.thm(t)) }
+  /// Accessor for [`Environment::theorems_about`]
+  #[must_use] pub fn theorems_about(&self, t: TermId) -> Vec<(Option<TermId>, Vec<ThmId>)> {
+    unsafe { self.thaw() }.theorems_about(t)
+  }
+  /// Accessor for [`Environment::hints`]
+  #[must_use] pub fn hints(&self) -> &[ThmId] { &unsafe { self.thaw() }.hints }
+  /// Returns true if `t` is in the hint database.
+  #[must_use] pub fn is_hint(&self, t: ThmId) -> bool { unsafe { self.thaw() }.hint_set.contains(&t) }
+  /// Returns true if the proof of `t` was [not checked](Environment::unchecked).
+  #[must_use] pub fn is_unchecked(&self, t: ThmId) -> bool { unsafe { self.thaw() }.unchecked.contains(&t) }
+  /// Accessor for [`Environment::do_cache`]
+  #[must_use] pub fn do_cache(&self) -> &super::do_cache::DoCache { &unsafe { self.thaw() }.do_cache }
+  /// Accessor for [`Environment::transfer`]
+  #[must_use] pub fn transfer(&self) -> &TransferMap { &unsafe { self.thaw() }.transfer }
+  /// Accessor for [`Environment::extra_args`]
+  #[must_use] pub fn extra_args_handler(&self, t: ThmId) -> Option<&FrozenLispVal> {
+    unsafe { self.thaw().extra_args.get(&t).map(|f| f.freeze()) }
+  }
+  /// Accessor for [`Environment::attrs`]
+  #[must_use] pub fn attrs(&self, a: AtomId) -> &[ArcString] {
+    unsafe { self.thaw() }.attrs.get(&a).map_or(&[][..], Vec::as_slice)
+  }
   /// Accessor for [`Environment::stmts`]
   #[must_use] pub fn stmts(&self) -> &[StmtTrace] { &unsafe { self.thaw() }.stmts }
   /// Parse a string into an atom.
@@ -341,7 +371,7 @@ impl Remap for FrozenLispData {
   type Target = LispData;
   fn remap(&self, r: &mut Remapper) -> LispData {
     LispData {
-      src: self.src().clone(),
+      src: self.src().as_ref().map(|(fsp, sp)| (fsp.remap(r), sp.remap(r))),
       doc: self.doc().clone(),
       val: (**self).remap(r),
       merge: self.merge().remap(r),
@@ -363,7 +393,7 @@ impl Remap for FrozenLispKind {
       FrozenLispKind::Atom(a) => LispVal::atom(a.remap(r)),
       FrozenLispKind::List(v) => LispVal::list(v.remap(r)),
       FrozenLispKind::DottedList(v, l) => LispVal::dotted_list(v.remap(r), l.remap(r)),
-      FrozenLispKind::Annot(sp, m) => LispVal::new(LispKind::Annot(sp.clone(), m.remap(r))),
+      FrozenLispKind::Annot(sp, m) => LispVal::new(LispKind::Annot(sp.remap(r), m.remap(r))),
       FrozenLispKind::Proc(f) => LispVal::proc(f.remap(r)),
       FrozenLispKind::AtomMap(m) => LispVal::new(LispKind::AtomMap(m.remap(r))),
       FrozenLispKind::Ref(m) => match r.refs.entry(m as *const _) {
@@ -384,6 +414,7 @@ impl Remap for FrozenLispKind {
       &FrozenLispKind::Bool(b) => LispVal::bool(b),
       &FrozenLispKind::Syntax(s) => LispVal::syntax(s),
       FrozenLispKind::Undef => LispVal::undef(),
+      FrozenLispKind::Proof(td) => LispVal::new(LispKind::Proof(td.remap(r))),
     };
     r.lisp.entry(ptr).or_insert(v).clone()
   }
@@ -327,7 +327,8 @@ impl Elaborator {
       let (s, map) = self.env.new_string_handler().map_err(|e| ElabError::new_e(sp, e))?;
       self.inout.string = Some((s, map));
     }
-    let_unchecked!(Some((s, map)) = &mut self.inout.string, Ok((*s, map)))
+    let (s, map) = self.inout.string.as_mut().expect("initialized above");
+    Ok((*s, map))
   }
 
   fn elab_output_string(&mut self, sp: Span, hs: &[SExpr]) -> EResult<()> {
@@ -388,6 +389,31 @@ impl Elaborator {
     Ok(w.w)
   }
 
+  /// Build a term of sort `string` denoting `bytes`, using the terms of the string preamble
+  /// (see [`eval_string`](Self::eval_string)). The term is a balanced tree of `sadd` with
+  /// leaves `(s1 (ch xh xl))`, so that its depth is logarithmic in the length.
+  pub fn bytes_to_term(&mut self, sp: Span, bytes: &[u8]) -> EResult<LispVal> {
+    fn app(env: &Environment, f: &str, args: impl IntoIterator<Item=LispVal>) -> LispVal {
+      let mut v = vec![LispVal::atom(env.atoms[f.as_bytes()])];
+      v.extend(args);
+      LispVal::list(v)
+    }
+    fn go(env: &Environment, hex: &[LispVal; 16], bytes: &[u8]) -> LispVal {
+      match *bytes {
+        [] => app(env, "s0", []),
+        [b] => app(env, "s1", [app(env, "ch",
+          [hex[usize::from(b >> 4)].clone(), hex[usize::from(b & 15)].clone()])]),
+        _ => {
+          let (l, r) = bytes.split_at(bytes.len() / 2);
+          app(env, "sadd", [go(env, hex, l), go(env, hex, r)])
+        }
+      }
+    }
+    self.get_string_handler(sp)?;
+    let hex = std::array::from_fn(|i| app(&self.env, &format!("x{:x}", i), []));
+    Ok(go(&self.env, &hex, bytes))
+  }
+
   /// Elaborate an `output` command. Note that in server mode, this does not actually run
   /// the operation of printing a string to standard out, as this would be disruptive.
   /// It is triggered only in "compile" mode, and by manual selection in server mode.
@@ -407,6 +433,21 @@ impl Elaborator {
 }
 
 impl FrozenEnv {
+  /// Evaluate the definition `name`, which should be a `def` of sort `string` with no
+  /// arguments, to the bytes that it denotes.
+  pub fn eval_string_def(&self, name: &str) -> Result<Vec<u8>, String> {
+    let env = unsafe {self.thaw()};
+    let (s, terms) = env.new_string_handler()?;
+    let t = env.check_term(name, &[], s.str, true)?;
+    let mut w = StringWriter::default();
+    env.write_node(&terms, &[], &ExprNode::App(t, Box::new([])), &mut w).map_err(|e| match e {
+      OutputError::IoError(e) => e.to_string(),
+      OutputError::String(e) => e,
+    })?;
+    if w.hex.is_some() { return Err(format!("'{}' has an odd number of hex digits", name)) }
+    Ok(w.w)
+  }
+
   /// Run all the `output` directives in the environment,
   /// writing output to the provided writer.
   pub fn run_output(&self, w: impl io::Write) -> Result<(), (FileSpan, OutputError)> {
@@ -417,11 +458,8 @@ impl FrozenEnv {
       if let StmtTrace::OutputString(os) = s {
         let OutputString {span, heap, exprs} = &**os;
         (|| -> Result<(), OutputError> {
-          let terms = {
-            handler = Some(unsafe {self.thaw()}.new_string_handler()
-              .map_err(OutputError::String)?);
-            let_unchecked!(Some((_, t)) = &handler, t)
-          };
+          let terms = &handler.insert(unsafe {self.thaw()}.new_string_handler()
+            .map_err(OutputError::String)?).1;
         env.write_output_string(terms, &mut w, heap, exprs)
         })().map_err(|e| (span.clone(), e))?;
       }
@@ -19,8 +19,9 @@ use std::collections::HashMap;
 use num::BigInt;
 use owning_ref::{OwningRef, StableAddress, CloneStableAddress};
 use crate::{ast::Atom, ArcString, AtomId, FileSpan, MergeStrategy, MergeStrategyInner, Modifiers,
-  MutexExt, Remap, Remapper, SliceExt, Span, StackList};
+  MutexExt, Remap, Remapper, SliceExt, Span, StackList, Thm};
 use parser::Ir;
+use super::Capability;
 pub use super::math_parser::{QExpr, QExprKind};
 
 macro_rules! str_enum {
@@ -286,6 +287,10 @@ macro_rules! mk_lisp_kind {
       /// A proof metavariable, also known as a goal. The argument is the expected
       /// theorem statement.
       Goal($val),
+      /// A proof object, which is a theorem statement together with a proof of it.
+      /// It is created by `get-proof`, and can only be inspected using the `proof-*`
+      /// accessor functions.
+      Proof(Rc<Thm>),
     }
   }
 }
@@ -369,6 +374,17 @@ impl LispVal {
 
   /// Returns true if this is a clone of `e`.
   #[must_use] pub fn ptr_eq(&self, e: &Self) -> bool { Rc::ptr_eq(&self.0, &e.0) }
+  /// Returns true if this is a weak reference (possibly under annotations)
+  /// whose target has been dropped.
+  #[must_use] pub fn is_dangling(&self) -> bool {
+    match &**self {
+      LispKind::Ref(m) => m.is_dangling(),
+      LispKind::Annot(_, e) => e.is_dangling(),
+      _ => false
+    }
+  }
+  /// Returns true if this value is not shared, i.e. there are no other clones of it.
+  #[must_use] pub fn is_unique(&self) -> bool { Rc::strong_count(&self.0) == 1 }
   /// Try to get at the inner data, if this value is not shared,
   /// otherwise return self.
   pub fn try_unwrap(self) -> Result<LispKind, LispVal> { Rc::try_unwrap(self.0).map_err(LispVal) }
@@ -499,7 +515,10 @@ impl LispWeak {
           Some(e) => LispVal(e)
         };
         *self = LispWeak::Strong(e);
-        let_unchecked!(LispWeak::Strong(e) = self, f(e))
+        match self {
+          LispWeak::Strong(e) => f(e),
+          LispWeak::Weak(_) => unreachable!(),
+        }
       }
     }
   }
@@ -521,7 +540,7 @@ impl LispWeak {
   }
 }
 /// A mutable reference to a [`LispVal`], the inner type used by `ref!` and related functions.
-#[derive(Debug, EnvDebug, DeepSizeOf)]
+#[derive(Debug, DeepSizeOf)]
 pub struct LispRef(RefCell<LispWeak>);
 
 impl LispRef {
@@ -546,6 +565,10 @@ impl LispRef {
   }
   /// Get a mutable reference to the stored value.
   pub fn get_mut_weak(&self) -> impl DerefMut<Target=LispWeak> + '_ { self.0.borrow_mut() }
+  /// Returns true if this is a weak reference whose target has been dropped.
+  #[must_use] pub fn is_dangling(&self) -> bool {
+    matches!(&*self.0.borrow(), LispWeak::Weak(e) if e.strong_count() == 0)
+  }
   /// Set this reference to a weak reference to `e`.
   pub fn set_weak(&self, e: &LispVal) {
     *self.0.borrow_mut() = LispWeak::Weak(Rc::downgrade(&e.0))
@@ -727,6 +750,10 @@ impl LispKind {
   pub fn is_goal(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::Goal(_)))
   }
+  /// Get the theorem stored in a proof object, if applicable.
+  pub fn as_proof(&self) -> Option<Rc<Thm>> {
+    self.unwrapped(|e| if let LispKind::Proof(td) = e {Some(td.clone())} else {None})
+  }
   /// Get the goal's target statement, if applicable.
   pub fn goal_type(&self) -> Option<LispVal> {
     self.unwrapped(|e| if let LispKind::Goal(e) = e {Some(e.clone())} else {None})
@@ -951,6 +978,17 @@ impl ProcSpec {
   }
 }
 
+impl std::fmt::Display for ProcSpec {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match *self {
+      ProcSpec::Exact(1) => write!(f, "exactly 1 argument"),
+      ProcSpec::Exact(n) => write!(f, "exactly {} arguments", n),
+      ProcSpec::AtLeast(1) => write!(f, "at least 1 argument"),
+      ProcSpec::AtLeast(n) => write!(f, "at least {} arguments", n),
+    }
+  }
+}
+
 impl Proc {
   /// Returns the specification (number of expected arguments) for a procedure.
   #[allow(clippy::match_same_arms)]
@@ -970,7 +1008,8 @@ impl Proc {
 
 str_enum! {
   /// The set of built in procedures. These each have names that can be shadowed
-  /// but not overridden by global names in the environment.
+  /// but not overridden by global names in the environment; the original procedure
+  /// is still accessible using `(builtin 'name)`.
   enum BuiltinProc {
     /// `display` takes a string and prints it. In the interactive editor mode,
     /// this appears as an info diagnostic over the word "`display`".
@@ -981,12 +1020,50 @@ str_enum! {
     Display: "display",
     /// `error` takes a string and throws an error with the given string as the message.
     Error: "error",
+    /// `(try f h)` calls `(f)` and returns the result. If an error is raised during the call,
+    /// the goals are restored to their state at the start of the `try`, and the result is
+    /// `(h err)`, where `err` is an atom map describing the error, with the fields:
+    /// * `kind`: the name of the error code, like `'not-a-list`, `'empty-list`,
+    ///   `'wrong-arity` or `'length-mismatch` (see `mm0-rs explain`), or `'error` for errors
+    ///   without a code, such as those raised by `error`
+    /// * `msg`: the error message, as a string with the location of the error, which can be
+    ///   transferred to another value using `copy-span`
+    /// * `value`: the value that caused the error, if known; for example the argument of `hd`
+    ///   that is not a list, or the procedure that was called with the wrong number of
+    ///   arguments
+    ///
+    /// Timeouts and stack overflows are not caught.
+    ///
+    /// ```metamath-zero
+    /// (try (fn () (hd 1)) (fn (e) (lookup e 'kind)))   -- not-a-list
+    /// ```
+    Try: "try",
     /// `print` takes an arbitrary expression and pretty-prints it.
     Print: "print",
+    /// `(help x)` prints the documentation of `x`, which can be the name of a builtin,
+    /// a syntax form or a global definition (as an atom or a string), or a procedure.
+    /// For builtins, this includes a usage example and the number of arguments.
+    /// ```metamath-zero
+    /// (help 'map)     -- `(map f '(a1 a2) '(b1 b2))` (builtin, takes at least 1 argument) ...
+    /// (help map)      -- same as above
+    /// ```
+    Help: "help",
+    /// `(builtin x)` returns the builtin procedure named `x`, which can be an atom or
+    /// a string. This is useful to call the original builtin from a global definition
+    /// which shadows it.
+    /// ```metamath-zero
+    /// (def (map f xs) (display "mapping") ((builtin 'map) f xs))
+    /// (map + '(1 2))          -- mapping, (1 2)
+    /// (builtin 'foo)          -- error, 'foo' is not a builtin
+    /// ```
+    Builtin: "builtin",
     /// `(report-at sp type msg)` will report the message `msg` at a position
     /// derived from the value `sp` (one can use `copy-span` to pass a value with the
     /// right span here), with error type `type`, which can be `'error`, `'info` or
     /// `'warn`. If `sp` is `#t`, then it will also display a stack trace.
+    /// `type` can also be a warning category, `'unused`, `'shadowing`, `'deprecated` or
+    /// `'slow-decl`, and then the message is reported with the severity set for the
+    /// category (see `set-option`).
     ReportAt: "report-at",
     /// `begin` returns its last argument, or `#undef` if it is given no arguments.
     /// In Scheme this is a syntax form, but in MM1 all functions have the same
@@ -1004,7 +1081,8 @@ str_enum! {
     /// `(* a b c)` computes the product of the (integer) arguments. `(*)` is one and `(* a)` is `a`.
     Mul: "*",
     /// `{a ^ b}` computes `a` to the power of `b`. It gives an error if `b` is negative.
-    /// Additional arguments are right associative.
+    /// Additional arguments are right associative. It is an error if the result is too large
+    /// (more than `2 ^ 26` bits).
     Pow: "^",
     /// `(max a b c)` computes the maximum of the (integer) arguments. `(max)` is an error.
     Max: "max",
@@ -1013,9 +1091,12 @@ str_enum! {
     /// `(- a b)` computes the subtraction `a - b`. `(- a b c)` is `a - b - c`,
     /// `(- a)` is `-a`, and `(-)` is an error.
     Sub: "-",
-    /// {a // b}` computes the integer (flooring) division. More arguments associate to the left.
+    /// `{a // b}` computes the integer (flooring) division. More arguments associate to the left.
+    /// `{a // 0}` is `0`, matching the `div0` theorem of `peano.mm1`, or an error if
+    /// `(set-option 'div-by-zero 'error)` is used.
     Div: "//",
     /// `{a % b}` computes the integer modulus. More arguments associate to the left.
+    /// `{a % 0}` is `a`, or an error with `(set-option 'div-by-zero 'error)`, like `//`.
     Mod: "%",
     /// `{a < b}` is true if `a` is less than `b`. `(< a b c)` means `a < b` and `b < c`.
     Lt: "<",
@@ -1029,7 +1110,7 @@ str_enum! {
     Eq: "=",
     /// `{a shl b}` performs a left shift `a << b`, equivalent to `a * 2 ^ b`.
     /// Negative `b` causes a right shift. Additional arguments are left associative;
-    /// `3 << -1 << 1 = 2`.
+    /// `3 << -1 << 1 = 2`. Like `^`, it is an error if the result is too large.
     Shl: "shl",
     /// `{a shr b}` performs a right shift `a >> b`, equivalent to `a // 2 ^ b`.
     /// Negative `b` causes a left shift. Additional arguments are left associative;
@@ -1057,6 +1138,22 @@ str_enum! {
     /// * Like the numeric equality operator `=`, `==` can be used on more than two arguments,
     ///   in which case it will compare all elements to the first.
     Equal: "==",
+    /// `(hash e)` returns a structural hash of `e` as a nonnegative integer, which is
+    /// consistent with `==`: values that are equal according to `==` have the same hash.
+    /// This can be used to bucket values in user-level caches without converting them to
+    /// strings first.
+    ///
+    /// * Atoms are hashed by name, so the hash of a value built from atoms, strings, numbers,
+    ///   booleans and lists is stable across files and elaboration sessions (for a given
+    ///   version of `mm0-rs`).
+    /// * Values that have only pointer equality (procedures, `atom-map`s, `goal`s and `mvar`s)
+    ///   are hashed by address, so their hashes are not stable.
+    /// * Like `==`, `hash` performs no cycle detection, and can loop on cyclic data.
+    /// ```metamath-zero
+    /// (= (hash '(a "b" 1)) (hash '(a "b" 1)))   -- #t
+    /// (= (hash '(a . (b))) (hash '(a b)))       -- #t
+    /// ```
+    Hash: "hash",
     /// `(->string e)` converts an expression to a string. Numbers are converted in the usual
     /// way, strings, atoms and formulas (which are all containers for strings) get the underlying
     /// string, and other expressions are pretty printed using the same method as `print`.
@@ -1118,12 +1215,15 @@ str_enum! {
     /// It is not short-circuiting.
     Or: "or",
     /// `(list e1 e2 e3)` returns the list `(e1 e2 e3)`. It differs from `quote`
-    /// in that it evaluates its arguments.
+    /// in that it evaluates its arguments. Like a quoted list, the result has the
+    /// position information of the `(list)` call.
     List: "list",
     /// `(cons e1 e2)` returns `(e1 . e2)`. With more or less arguments:
     /// * `(cons)` returns the empty list.
     /// * `(cons e1)` returns `e1`.
     /// * `(cons e1 e2 e3)` returns `(e1 e2 . e3)`.
+    ///
+    /// The result has the position information of the `(cons)` call, like `(list)`.
     Cons: "cons",
     /// `(hd e)` returns the head of the list, or left element of the cons expression.
     /// It is known as `car` in most lisps.
@@ -1166,6 +1266,9 @@ str_enum! {
     SetRef: "set!",
     /// `(set-weak! r v)` sets the value of the ref-cell `r` to a weak reference to `v`.
     SetWeak: "set-weak!",
+    /// `(weak-ref! e)` constructs a new ref-cell containing a weak reference to `e`.
+    /// It is equivalent to `(def r (ref!)) (set-weak! r e) r`.
+    WeakRef: "weak-ref!",
     /// `(copy-span from to)` makes a copy of `to` with its position information copied from `from`.
     /// (This can be used for improved error reporting, but
     /// otherwise has no effect on program semantics.)
@@ -1175,6 +1278,13 @@ str_enum! {
     /// which can then be copied to a term using `(copy-span)`.
     /// (Useful for targeted error reporting in scripts.)
     StackSpan: "stack-span",
+    /// `(get-span e)` returns the position information of `e` as a list `(start end)` of
+    /// byte offsets in the current file, or `#undef` if `e` has no position in this file.
+    GetSpan: "get-span",
+    /// `(with-span sp e)` makes a copy of `e` with the position `sp`, which is either a list
+    /// `(start end)` of byte offsets in the current file, as returned by `(get-span)`,
+    /// or another value whose position is used, as in `(copy-span sp e)`.
+    WithSpan: "with-span",
     /// `(async f args)` evaluates `(f args)` on another thread, and returns a
     /// procedure that will join on the thread to wait for the result.
     Async: "async",
@@ -1185,12 +1295,20 @@ str_enum! {
     /// * `(lookup m k)` gets the value stored in the atom map `m` at `k`, or `#undef` if not present.
     /// * `(lookup m k v)` will return `v` instead if the key is not present,
     ///   unless `v` is a procedure, in which case it will be called with no arguments on lookup failure.
+    /// * A key whose value is a weak reference to a value that has been dropped
+    ///   (see `insert-weak!`) is treated as not present.
     Lookup: "lookup",
     /// * `(insert! m k v)` inserts the value `v` at key `k` in the mutable map `m`,
     ///   and returns `#undef`.
     /// * `(insert! m k)` "undefines" the value at key `k` in `m`, that is,
     ///   it erases whatever is there.
     Insert: "insert!",
+    /// `(insert-weak! m k v)` inserts a weak reference to `v` at key `k` in the mutable map `m`,
+    /// and returns `#undef`. The map does not keep `v` alive: once `v` is no longer referenced
+    /// elsewhere, the entry is treated as not present by `lookup`, and it is eventually removed
+    /// from the map by later calls to `insert-weak!`. This is useful for large caches, which
+    /// should not keep every value ever computed alive.
+    InsertWeak: "insert-weak!",
     /// * `(insert m k v)` returns an immutable map based on the immutable map `m`,
     ///   with the value `v` inserted at key `k`.
     /// * `(insert m k)` returns `k` erased from `m`.
@@ -1257,6 +1375,43 @@ str_enum! {
     /// `(local-ctx)` returns the list of hypothesis names (`(infer-type)`
     /// can be used to get the type of the hypotheses).
     LocalCtx: "local-ctx",
+    /// `(goal-target g)` returns the statement that needs to be proven to solve goal `g`
+    /// (wrapped by any number of refs). This is the same as `goal-type`.
+    GoalTarget: "goal-target",
+    /// `(add-hint x1 x2 ...)` adds the theorems `x1`, `x2`, ... to the hint database used
+    /// by `auto`. This is usually used from an `annotate` function, so that theorems can be
+    /// tagged as hints with an annotation like `@auto theorem foo ...`.
+    AddHint: "add-hint",
+    /// `(auto)` searches for a proof of the first goal by backward chaining, using the
+    /// hypotheses in the local context and the theorems added by `add-hint`, and applies it
+    /// with `refine`. It reports the proof that was found as a `refine` script, which the
+    /// language server offers to write into the source in place of the `auto` call, to
+    /// avoid repeating the search.
+    ///
+    /// * `(auto n)` limits the depth of the proof to `n` theorem applications (default 5).
+    ///   Shallower proofs are tried first.
+    /// * `(auto n ms)` also limits the search to `ms` milliseconds (default 1000).
+    ///
+    /// If the function `rank-premises` is defined, it is called as `(rank-premises g xs)`
+    /// for each goal `g` of the search, where `xs` is the list of hints that may apply to
+    /// it, and should return the list of hints to try, best first.
+    Auto: "auto",
+    /// `(assumption)` solves the first goal using a hypothesis from the local context
+    /// whose statement unifies with it, so that trivial goals can be closed without naming
+    /// the hypothesis. `(assumption 'foo 'bar)` also allows the definitions `foo` and `bar`
+    /// to be unfolded when searching for a matching hypothesis. It is an error if no
+    /// hypothesis matches.
+    Assumption: "assumption",
+    /// `(goal-hyps g)` returns the list of hypotheses that can be used to solve goal `g`,
+    /// in the order they were introduced, omitting shadowed hypotheses. Each hypothesis
+    /// should be inspected using `hyp-name` and `hyp-type`, because the representation
+    /// may change in future versions.
+    GoalHyps: "goal-hyps",
+    /// `(hyp-name h)` returns the name of the hypothesis `h`, as returned by `goal-hyps`.
+    HypName: "hyp-name",
+    /// `(hyp-type h)` returns the statement of the hypothesis `h`, where `h` is either a
+    /// hypothesis returned by `goal-hyps` or the name of a hypothesis in the local context.
+    HypType: "hyp-type",
     ///`(to-expr e)` elaborates a term pre-expression into an expression,
     /// producing metavariables for `_` placeholders in the expression.
     ToExpr: "to-expr",
@@ -1270,9 +1425,35 @@ str_enum! {
     ///   after which `h` may be referred to like any other theorem hypothesis.
     /// * `(have h e p)` is the same except that `p` is elaborated with `e` as the expected type.
     Have: "have",
+    /// `(suffices h e)` elaborates the expression `e` and adds it to the list of proven
+    /// subproofs under the name `h`, like `(have h e _)`, except that the new goal `e` is
+    /// placed after the existing goals instead of before them. That is, the current goals
+    /// are proven first, using `h`, and the proof of `e` itself is deferred until the end.
+    Suffices: "suffices",
+    /// `(admit-step h e)` elaborates the expression `e` and adds it to the list of proven
+    /// subproofs under the name `h` without proving it, like `(have h e :sorry)`. As with
+    /// `sorry`, the theorem being proved is then added without a proof and reported as
+    /// incomplete.
+    AdmitStep: "admit-step",
     /// `(stat)` prints the current proof state, which consists of a list of
     /// subproofs, a list of goals, and a list of metavariables accompanied by their sorts.
+    /// Each subproof (including the hypotheses) is shown on its own line with its name,
+    /// and each goal is shown after a turnstile `|-`, using the declared notations.
     Stat: "stat",
+    /// `(debug-dump 'context)` returns a string showing the internal representation of the
+    /// local context, including the goals and metavariables, for debugging tactics.
+    /// `(debug-dump 'globals "prefix")` shows the values of the lisp globals whose names
+    /// start with `prefix`, and `(debug-dump 'decl 'foo)` shows the sort, term or theorem
+    /// `foo`. Cyclic values are cut off with `_cycle_`.
+    /// ```metamath-zero
+    /// (display (debug-dump 'context))
+    /// ```
+    DebugDump: "debug-dump",
+    /// `(sorry)` closes all current goals without proving them. The theorem being proved
+    /// is added without a proof, and it and all theorems that depend on it are
+    /// reported as incomplete. (Inside `refine`, the atom `:sorry` can be used to
+    /// close a single goal in the same way.)
+    Sorry: "sorry",
     /// `(get-decl x)` returns the declaration information associated to declaration `x`.
     /// The result has one of the following forms:
     ///
@@ -1300,6 +1481,82 @@ str_enum! {
     ///    or atom map of dummy variables, and `proof` is the proof s-expression. `vtask`
     ///    can also have the form `(ds proof)` itself.
     GetDecl: "get-decl",
+    /// `(conv-refl e)` checks that `e` is a well formed expression and returns it,
+    /// as the reflexivity conversion `e = e`.
+    ConvRefl: "conv-refl",
+    /// `(conv-sym c)`, where `c: a = b` is a conversion, returns a conversion `b = a`.
+    ConvSym: "conv-sym",
+    /// `(conv-trans c1 ... cn)`, where `c1: a = b`, `c2: b = c` and so on, returns a
+    /// conversion from `a` to the right side of `cn`. It is an error if the sides do not match.
+    ConvTrans: "conv-trans",
+    /// `(conv-cong t c1 ... cn)`, where `t` is a term constructor and `ci: ai = bi`, returns
+    /// a conversion `(t a1 ... an) = (t b1 ... bn)`.
+    ConvCong: "conv-cong",
+    /// `(conv-unfold e)`, where `e` is `(t a1 ... an)` and `t` is a definition, returns
+    /// a conversion from `e` to the body of `t` with the arguments substituted. Dummy
+    /// variables in the definition are replaced by new metavariables.
+    ConvUnfold: "conv-unfold",
+    /// `(conv-at-path e path c)`, where `c: a = b` and `path` is a list of argument positions
+    /// (starting at 1) locating an occurrence of `a` in `e`, returns a conversion from `e` to
+    /// `e` with that occurrence replaced by `b`.
+    ConvAtPath: "conv-at-path",
+    /// `(conv-sides c)` checks that `c` is a well formed conversion `a = b` and returns `(a b)`.
+    ConvSides: "conv-sides",
+    /// `(expr< e1 e2)` returns true if `e1` comes before `e2` in the term ordering used for
+    /// ordered rewriting. Smaller expressions come first, and expressions of the same size
+    /// are ordered with variables (by name) before metavariables before applications, which
+    /// are ordered by the declaration order of their term constructors and then by their
+    /// arguments. A rewriter should only apply a permutative rule such as commutativity
+    /// `a + b = b + a` when the result is `expr<` the original expression; this ensures that
+    /// rewriting with commutativity and associativity terminates, and that sums and
+    /// products are sorted into a normal form.
+    ExprLt: "expr<",
+    /// * `(rw r)` rewrites the first goal using the rule `r`, replacing it with the rewritten
+    ///   goal. The rule is either the name of a definition, which is unfolded, or a conversion
+    ///   `a = b` (see `conv-sides`), which replaces `a` with `b`. Every outermost occurrence
+    ///   is rewritten.
+    /// * `(rw r :at path)` only rewrites inside the subterm of the goal at `path`, which is a
+    ///   list of argument positions (starting at 1) as in `conv-at-path`.
+    /// * `(rw thm)` and `(rw thm :at path)`, where `thm` is the name of a theorem, rewrite
+    ///   using an equality proved by the theorem instead of a conversion. Since this depends
+    ///   on the logic, it calls the user-defined function `(rw-thm thm path)`, where `path`
+    ///   is `#undef` if it was not given, and it is an error if `rw-thm` is not defined.
+    Rw: "rw",
+    /// `(rw-in h r)` and `(rw-in h r :at path)` rewrite the statement of hypothesis `h`
+    /// using the rule `r`, in the same way as `rw`. The result is a proof of the rewritten
+    /// statement, which can be used in a refine script, for example `(have 'h2 (rw-in 'h r))`.
+    RwIn: "rw-in",
+    /// `(get-proof x)` returns a proof object for the theorem `x`, which must have
+    /// a complete proof. Proof objects are opaque values which can be stored in
+    /// variables and maps, and inspected using the following functions.
+    GetProof: "get-proof",
+    /// `(proof? e)` returns `#t` if `e` is a proof object.
+    IsProof: "proof?",
+    /// `(proof-statement p)` returns the statement proven by the proof object `p`, as a
+    /// list `(bis hyps ret)`, where `bis`, `hyps` and `ret` have the same format as in the
+    /// result of `get-decl`.
+    ProofStatement: "proof-statement",
+    /// `(proof-term p)` returns the proof in the proof object `p` as a list `(ds proof)`,
+    /// where `ds` is the list of dummy variables and `proof` is the proof s-expression,
+    /// in the same format as the proof thunk returned by `get-decl`.
+    ProofTerm: "proof-term",
+    /// `(proof-size p)` returns the number of theorem applications in the proof object `p`,
+    /// counting shared subproofs once.
+    ProofSize: "proof-size",
+    /// `(proof-thms-used p)` returns the list of theorems referenced by the proof object
+    /// `p`, in order of first use.
+    ProofThmsUsed: "proof-thms-used",
+    /// `(theorems-about x)` returns all theorems and axioms whose statement mentions the
+    /// term constructor `x`, grouped by the head term constructor of the conclusion.
+    /// The result is a list of groups `(head thm1 thm2 ...)`, where `head` is `_` for
+    /// theorems whose conclusion is a variable.
+    TheoremsAbout: "theorems-about",
+    /// `(search e)` returns the list of theorems and axioms whose conclusion may unify with
+    /// the term s-expression `e`, such as `$ _ + 0 = _ $`, in declaration order. Atoms
+    /// that are not term constructors (like `_`) and metavariables match any subterm.
+    /// This uses an index of theorem conclusions, so it is fast even in large environments,
+    /// but it does not check dependencies or sorts, so some results may not actually unify.
+    Search: "search",
     /// `(add-decl! decl-data ...)` adds a new declaration, as if a new `def` or `theorem`
     /// declaration was created. This does not do any elaboration - all information is
     /// expected to be fully elaborated. The input format is the same as the output format
@@ -1313,6 +1570,23 @@ str_enum! {
     /// * `(add-thm! x bis hyps ret vis vtask)` is the same as
     ///   `(add-decl! 'theorem x bis hyps ret vis vtask)`.
     AddThm: "add-thm!",
+    /// `(add-transfer x y)` registers the correspondence `x ~> y` used by `transfer`, where
+    /// `x` and `y` are both sorts, both terms or both theorems. Corresponding sorts must have
+    /// the same modifiers, and corresponding terms must have the same arguments and result,
+    /// up to the sort correspondences registered before. Corresponding theorems must have
+    /// the same statement up to the registered correspondences; they are the base lemmas
+    /// from which the translated proofs are built.
+    AddTransfer: "add-transfer",
+    /// `(transfer x y)` adds a new theorem `y` whose statement and proof are those of the
+    /// theorem `x` with every sort, term and theorem replaced by the one registered with
+    /// `add-transfer`, and then registers `x ~> y`, so that theorems proved using `x` can be
+    /// transferred in turn. For example, after `(add-transfer 'nat 'int)`,
+    /// `(add-transfer 'add 'iadd)` and `(add-transfer 'addcom 'iaddcom)`, a theorem `foo`
+    /// about `add` whose proof uses `addcom` can be transferred with `(transfer 'foo 'ifoo)`.
+    /// Sorts, terms and theorems without a correspondence are left unchanged, and it is an
+    /// error if the translated proof does not check, for example because it uses a theorem
+    /// that has not been transferred.
+    Transfer: "transfer",
     /// * `(dummy! x s)` produces a new dummy variable called `x` with sort `s`, and returns `x`;
     /// * `(dummy! s)` automatically gives the variable a name like `_123` that is guaranteed to be unused.
     NewDummy: "dummy!",
@@ -1328,15 +1602,136 @@ str_enum! {
     /// * `(set-backtrace type b)` does the same but for specific error type `type`,
     ///   which can be `'error`, `'info` or `'warn`.
     SetBacktrace: "set-backtrace",
-    /// `refine-extra-args` can be called directly, but it simply returns an error. It is called
-    /// by `refine` when elaborating a term with too many arguments, and is expected to be
-    /// overridden by user code to provide a more useful behavior.
+    /// `(set-rw-trace b)` turns on (`b = #t`) or off (`b = #f`) tracing of `rw` and `rw-in`.
+    /// When it is on, each rewrite is reported as an info message at the call, with the rule,
+    /// the position of the subterm (a path as in `conv-at-path`), and the subterm before and
+    /// after the rewrite.
+    SetRwTrace: "set-rw-trace",
+    /// `(set-dep-policy p)` sets the policy for dependencies that are inferred rather than
+    /// written explicitly, where `p` is one of:
+    /// * `'infer`: infer dependencies silently (the default)
+    /// * `'warn`: report a warning for each inferred dependency, explaining where it came from
+    /// * `'strict`: report an error for each inferred dependency
+    ///
+    /// This affects the return type of a `def` with no written type, and variables with an
+    /// inferred type, which are not allowed to depend on any bound variable.
+    SetDepPolicy: "set-dep-policy",
+    /// `(set-option 'key v)` sets an elaborator option for the rest of the file. The options
+    /// are `'timeout`, `'stack-limit`, `'check-proofs` and `'dep-policy`, which are the same
+    /// as the corresponding commands, `'report-error`, `'report-warn` and `'report-info`,
+    /// which are like `set-reporting`, `'div-by-zero`, which is `'zero` (the default) or
+    /// `'error` to select the behavior of `//` and `%` with a zero divisor, `'suggest-proofs`,
+    /// which if `#t` makes each theorem proved by a tactic script suggest its proof term as a
+    /// replacement for the script, and `'pp-width`, `'pp-coercions`, `'pp-implicits` and
+    /// `'pp-radix`, which are the settings of `set-pp-options`.
+    ///
+    /// The options `'warn-unused`, `'warn-shadowing`, `'warn-deprecated` and `'warn-slow-decl`
+    /// set the severity of a category of warnings to `'off`, `'info`, `'warn` or `'error`.
+    /// `'slow-decl` warnings are off by default, and are reported for declarations that take
+    /// longer than `'slow-decl-ms` milliseconds (default 1000) to elaborate. The initial
+    /// severities can be set with the `--warn` command line flag.
+    ///
+    /// An annotation `@(options key1 v1 key2 v2 ...) stmt` sets the options only for `stmt`,
+    /// and restores the previous values afterward.
+    SetOption: "set-option",
+    /// `(set-pp-options 'key1 v1 'key2 v2 ...)` changes the settings of the pretty printer,
+    /// which is used by `pp`, in goal displays and in error messages. The settings are
+    /// `'width`, the line width (default 80), `'coercions`, which makes coercions visible
+    /// as applications if `#t` (default `#f`), `'implicits`, which hides the implicit
+    /// arguments set by `set-implicit` if `#f` (default `#t`), and `'radix`, which is `10`
+    /// (the default) or `16` to print numerals in hexadecimal, like `0xff`.
+    /// These are the same as the `set-option` options `'pp-width`, `'pp-coercions`,
+    /// `'pp-implicits` and `'pp-radix`.
+    /// ```metamath-zero
+    /// (set-pp-options 'width 120 'radix 16)
+    /// (pp $ 255 + x $)         -- "0xff + x"
+    /// ```
+    SetPpOptions: "set-pp-options",
+    /// `(refine-extra-args refine tgt e a1 a2 ...)` is called by `refine` when elaborating
+    /// a term with too many arguments. Here `refine` is a procedure that elaborates a refine
+    /// script against a target (`(refine tgt p)`, or `(refine p)` to infer the type),
+    /// `tgt` is the expected type of the whole application, `e` is the elaborated proof of
+    /// the application without the extra arguments, and `a1 a2 ...` are the unelaborated
+    /// extra arguments. The result is a proof, which is coerced to `tgt`.
+    ///
+    /// The default implementation looks up the theorem at the head of `e`, and if a handler
+    /// was registered for it using `set-extra-args-handler`, it calls the handler with the
+    /// same arguments. Otherwise it reports an error. This function can also be overridden
+    /// by user code to change the behavior for all theorems.
     RefineExtraArgs: "refine-extra-args",
+    /// `(set-extra-args-handler x f)` registers `f` as the handler for applications of the
+    /// theorem `x` to extra arguments, which will be called by the default
+    /// `refine-extra-args` as `(f refine tgt e a1 a2 ...)`. This can be used to
+    /// resolve arguments that do not correspond to hypotheses of `x`, for example by
+    /// applying another theorem to `e`. `(set-extra-args-handler x #undef)` removes the
+    /// handler.
+    SetExtraArgsHandler: "set-extra-args-handler",
     /// `(eval-string e1 e2 ...)` takes as input zero or more expressions which are elaborated
     /// as type `string`, and then evaluates them to an actual lisp string. This has the same
     /// effect as the top level command `output string: e1 e2 ...;` but this command is only
     /// triggered on a compile, while `eval-string` works also in server mode.
     EvalString: "eval-string",
+    /// `(bytes->term s)` returns a term of type `string` denoting the bytes of the string `s`,
+    /// built from the terms of the string preamble (`s0`, `s1`, `sadd`, `ch` and `x0` to
+    /// `xf`) as a balanced tree of `sadd`. This is the inverse of `eval-string`.
+    /// ```metamath-zero
+    /// (bytes->term "AB") -- (sadd (s1 (ch (x4) (x1))) (s1 (ch (x4) (x2))))
+    /// ```
+    BytesToTerm: "bytes->term",
+    /// `(hex->bytes s)` parses the string `s` of hex digits as a byte string, two digits per
+    /// byte. Whitespace and `_` between the digits are ignored.
+    /// ```metamath-zero
+    /// (hex->bytes "7f45 4c46") -- "\x7fELF"
+    /// ```
+    HexToBytes: "hex->bytes",
+    /// `(hexdump s)` formats the bytes of the string `s` as a hex dump, with 16 bytes per
+    /// line, each line starting with the offset and ending with the printable characters.
+    /// ```metamath-zero
+    /// (display (hexdump (eval-string foo)))
+    /// ```
+    HexDump: "hexdump",
+    /// `(align-name 'foo 'bar)` declares that the declaration `foo` in subsequently
+    /// imported files corresponds to `bar` in this file. Imported references to `foo`
+    /// will refer to `bar` instead, and if `bar` is already declared with the same
+    /// statement then the two declarations are identified.
+    AlignName: "align-name",
+    /// `(add-virtual-file name text)` registers a virtual file with contents `text` (a string)
+    /// at path `name`, relative to the current file. It can then be imported as if it was a
+    /// file on disk, but only by files that import the current file, and it may not have the
+    /// same path as a file on disk. The virtual files registered by a file are removed when
+    /// it is elaborated again. Imports are resolved when a file starts elaborating, so a
+    /// virtual file can only be imported by files which are elaborated after it is registered
+    /// (in particular, not by the file that registers it).
+    AddVirtualFile: "add-virtual-file",
+    /// `(set-numeral s zero (d0 d1 ... dn))` sets the numeral system for sort `s`, so that
+    /// number literals like `$ 123 $` in math formulas of sort `s` are elaborated into terms
+    /// built from the constant `zero` and the unary digit constructors `di`.
+    /// * If there is only one digit constructor `d`, it is interpreted as a successor
+    ///   function, and `$ 3 $` elaborates to `$ d (d (d zero)) $`.
+    /// * Otherwise, the numeral is written in base `b = n + 1`, where `di x` means
+    ///   `b * x + i`. For example `(set-numeral 'nat 'z '(b0 b1))` makes `$ 6 $` elaborate
+    ///   to `$ b0 (b1 (b1 z)) $`.
+    ///
+    /// Terms of this form are displayed in decimal, and a number in a math pattern matches
+    /// the corresponding term. Number literals are only recognized if they are not
+    /// already declared as notations.
+    SetNumeral: "set-numeral",
+    /// `(set-string s nil cons)` sets the string system for sort `s`, so that string literals
+    /// like `$ "foo" $` in math formulas of sort `s` are elaborated into terms built from the
+    /// empty string `nil` and `cons: char > s > s`, which adds a character to the front.
+    /// Each byte of the string is elaborated as a number in the numeral system for the sort
+    /// `char`, which must be set using `set-numeral`. Terms of this form are displayed as
+    /// string literals, and a string literal in a math pattern matches the corresponding term.
+    SetString: "set-string",
+    /// `(set-implicit t xs)` marks the arguments `xs` of the term constructor `t` as implicit,
+    /// where each argument is given by name or by index. An application of `t` that omits
+    /// exactly the implicit arguments, like `$ t a b $`, is elaborated as if `_` was written
+    /// for each of them in `refine`, so they must be determined by unification, and it is an
+    /// error if they are not. In a statement there is nothing to determine them, so they
+    /// cannot be omitted. For the same reason the pretty printer displays implicit arguments,
+    /// unless they are hidden with `(set-pp-options 'implicits #f)`, which is useful for
+    /// goal displays. `(set-implicit t ())` makes all arguments explicit again.
+    SetImplicit: "set-implicit",
     /// `(mmc-init)` returns a new compiler object, which is itself a procedure that can
     /// be called to compile MMC functions. See [`Compiler::call`].
     ///
@@ -1352,6 +1747,84 @@ impl std::fmt::Display for BuiltinProc {
   }
 }
 
+impl BuiltinProc {
+  /// The capability that lisp code needs to call this builtin (see [`Capability`]).
+  /// Builtins that only print messages, like `display`, are [`Pure`](Capability::Pure).
+  /// There is no default, so that each new builtin has to be given a capability.
+  #[must_use] pub fn capability(self) -> Capability {
+    match self {
+      BuiltinProc::AddVirtualFile => Capability::Io,
+      #[cfg(feature = "mmc")]
+      BuiltinProc::MmcInit => Capability::EnvWrite,
+      BuiltinProc::AddDecl | BuiltinProc::AddTerm | BuiltinProc::AddThm | BuiltinProc::AddHint |
+      BuiltinProc::SetExtraArgsHandler | BuiltinProc::AlignName | BuiltinProc::SetNumeral |
+      BuiltinProc::SetString | BuiltinProc::SetImplicit | BuiltinProc::CheckProofs |
+      BuiltinProc::SetReporting | BuiltinProc::SetBacktrace | BuiltinProc::SetRwTrace |
+      BuiltinProc::SetDepPolicy | BuiltinProc::SetOption | BuiltinProc::SetPpOptions |
+      BuiltinProc::SetTimeout | BuiltinProc::SetStackLimit | BuiltinProc::AddTransfer |
+      BuiltinProc::Transfer =>
+        Capability::EnvWrite,
+      BuiltinProc::Help | BuiltinProc::PrettyPrint | BuiltinProc::NewMVar |
+      BuiltinProc::NewGoal | BuiltinProc::GoalType | BuiltinProc::InferType |
+      BuiltinProc::InferSort | BuiltinProc::GetMVars | BuiltinProc::GetGoals |
+      BuiltinProc::SetGoals | BuiltinProc::SetCloseFn | BuiltinProc::LocalCtx |
+      BuiltinProc::GoalTarget | BuiltinProc::Auto | BuiltinProc::Assumption |
+      BuiltinProc::GoalHyps | BuiltinProc::HypName | BuiltinProc::HypType |
+      BuiltinProc::ToExpr | BuiltinProc::Refine | BuiltinProc::Have | BuiltinProc::Suffices |
+      BuiltinProc::AdmitStep | BuiltinProc::Stat | BuiltinProc::DebugDump |
+      BuiltinProc::Sorry | BuiltinProc::GetDecl | BuiltinProc::ConvRefl | BuiltinProc::ConvSym |
+      BuiltinProc::ConvTrans | BuiltinProc::ConvCong | BuiltinProc::ConvUnfold |
+      BuiltinProc::ConvAtPath | BuiltinProc::ConvSides | BuiltinProc::ExprLt | BuiltinProc::Rw |
+      BuiltinProc::RwIn |
+      BuiltinProc::GetProof | BuiltinProc::IsProof | BuiltinProc::ProofStatement |
+      BuiltinProc::ProofTerm | BuiltinProc::ProofSize | BuiltinProc::ProofThmsUsed |
+      BuiltinProc::TheoremsAbout | BuiltinProc::Search | BuiltinProc::NewDummy |
+      BuiltinProc::RefineExtraArgs | BuiltinProc::EvalString | BuiltinProc::BytesToTerm =>
+        Capability::EnvRead,
+      BuiltinProc::Display | BuiltinProc::Error | BuiltinProc::Try | BuiltinProc::Print |
+      BuiltinProc::Builtin | BuiltinProc::ReportAt | BuiltinProc::Begin | BuiltinProc::Apply |
+      BuiltinProc::Add | BuiltinProc::Mul | BuiltinProc::Pow | BuiltinProc::Max |
+      BuiltinProc::Min | BuiltinProc::Sub | BuiltinProc::Div | BuiltinProc::Mod |
+      BuiltinProc::Lt | BuiltinProc::Le | BuiltinProc::Gt | BuiltinProc::Ge | BuiltinProc::Eq |
+      BuiltinProc::Shl | BuiltinProc::Shr | BuiltinProc::BAnd | BuiltinProc::BOr |
+      BuiltinProc::BXor | BuiltinProc::BNot | BuiltinProc::Equal | BuiltinProc::Hash |
+      BuiltinProc::ToString | BuiltinProc::StringToAtom | BuiltinProc::StringAppend |
+      BuiltinProc::StringLen | BuiltinProc::StringNth | BuiltinProc::Substr |
+      BuiltinProc::StringToList | BuiltinProc::ListToString | BuiltinProc::Not |
+      BuiltinProc::And | BuiltinProc::Or | BuiltinProc::List | BuiltinProc::Cons |
+      BuiltinProc::Head | BuiltinProc::Tail | BuiltinProc::Nth | BuiltinProc::Map |
+      BuiltinProc::IsBool | BuiltinProc::IsAtom | BuiltinProc::IsPair | BuiltinProc::IsNull |
+      BuiltinProc::IsNumber | BuiltinProc::IsString | BuiltinProc::IsProc | BuiltinProc::IsDef |
+      BuiltinProc::IsRef | BuiltinProc::NewRef | BuiltinProc::GetRef | BuiltinProc::SetRef |
+      BuiltinProc::SetWeak | BuiltinProc::WeakRef | BuiltinProc::CopySpan |
+      BuiltinProc::StackSpan | BuiltinProc::GetSpan | BuiltinProc::WithSpan | BuiltinProc::Async |
+      BuiltinProc::IsAtomMap | BuiltinProc::NewAtomMap | BuiltinProc::Lookup |
+      BuiltinProc::Insert | BuiltinProc::InsertWeak | BuiltinProc::InsertNew |
+      BuiltinProc::MergeMap | BuiltinProc::IsMVar | BuiltinProc::IsGoal |
+      BuiltinProc::HexToBytes | BuiltinProc::HexDump =>
+        Capability::Pure,
+    }
+  }
+
+  /// Returns true if this builtin adds declarations, changes how the rest of the file is
+  /// elaborated, or has effects outside the current elaboration. These are the builtins
+  /// that need at least [`EnvWrite`](Capability::EnvWrite).
+  #[must_use] pub fn mutates_env(self) -> bool { self.capability() >= Capability::EnvWrite }
+
+  /// Returns true if the elaborator calls this builtin through the usual name resolution
+  /// (see `Evaluator::call_overridable`), so that redefining it is the intended way to
+  /// customize its behavior rather than an accidental shadowing.
+  #[must_use] pub fn is_overridable(self) -> bool {
+    matches!(self, BuiltinProc::RefineExtraArgs)
+  }
+
+  /// The help text for the builtin, consisting of the usage, the number of arguments,
+  /// and the documentation. This is shown by `(help)` and in hovers.
+  #[must_use] pub fn help(self) -> String {
+    format!("`{}` (builtin, takes {})\n\n{}", self.usage(), self.spec(), self.doc())
+  }
+}
+
 /// An iterator over lisp values, for dealing with lists. Semantically this is
 /// the same as a [`LispVal`], but in order to decrease allocations this allows
 /// holding on to incomplete subparts of the arrays used in [`LispKind::List`]
@@ -1558,12 +2031,20 @@ impl Remap for InferTarget {
     }
   }
 }
+impl Remap for Annot {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match self {
+      Annot::Span(fsp) => Annot::Span(fsp.remap(r)),
+    }
+  }
+}
 impl Remap for ProcPos {
   type Target = Self;
   fn remap(&self, r: &mut Remapper) -> Self {
     match self {
-      ProcPos::Named(fsp, sp, a) => ProcPos::Named(fsp.clone(), *sp, a.remap(r)),
-      ProcPos::Unnamed(fsp) => ProcPos::Unnamed(fsp.clone()),
+      ProcPos::Named(fsp, sp, a) => ProcPos::Named(fsp.remap(r), sp.remap(r), a.remap(r)),
+      ProcPos::Unnamed(fsp) => ProcPos::Unnamed(fsp.remap(r)),
     }
   }
 }
@@ -210,6 +210,33 @@ impl<A: EnvDebug + ?Sized> EnvDebug for std::rc::Weak<A> {
   }
 }
 
+thread_local! {
+  /// The references that are currently being printed, used to detect cycles.
+  static PRINTING_REFS: std::cell::RefCell<std::collections::HashSet<*const super::LispRef>> =
+    Default::default();
+}
+
+// Refs are the only way to make a cyclic lisp value, so we print `_cycle_` instead of
+// a ref that is already being printed.
+impl EnvDebug for super::LispRef {
+  fn env_dbg<'a>(&self, fe: FormatEnv<'a>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let p: *const super::LispRef = self;
+    if !PRINTING_REFS.with(|refs| refs.borrow_mut().insert(p)) {
+      return write!(f, "_cycle_")
+    }
+    let res = f.debug_tuple("LispRef").field(&fe.to(&self.0)).finish();
+    PRINTING_REFS.with(|refs| refs.borrow_mut().remove(&p));
+    res
+  }
+}
+
+// Theorems appear in proof objects, where we only show the name.
+impl EnvDebug for crate::Thm {
+  fn env_dbg<'a>(&self, fe: FormatEnv<'a>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_tuple("Thm").field(&fe.to(&self.atom)).finish()
+  }
+}
+
 // Needs a separate implementation since it doesn't have
 // an `atom` field, and the others don't have `name` field.
 impl EnvDebug for AtomId {
@@ -245,6 +272,7 @@ env_debug! {
   std::path::PathBuf,
   std::sync::atomic::AtomicBool,
   num::BigInt,
+  num::BigUint,
   crate::ArcString,
   crate::lisp::Syntax,
   crate::lisp::BuiltinProc,
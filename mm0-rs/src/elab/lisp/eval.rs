@@ -4,7 +4,8 @@
 //! stack traces, as well as having a uniform location to be able to check for interrupts
 //! and timeout.
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::{DefaultHasher, Entry}, HashMap};
+use std::hash::{Hash, Hasher};
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::Ordering;
@@ -13,15 +14,18 @@ use num::{BigInt, Signed, ToPrimitive, Zero};
 use crate::{ast::SExpr, ArcString, AtomData, AtomId, BoxError, DeclKey, DocComment, ElabError,
   Elaborator, Environment, ErrorLevel, FileRef, FileSpan, LispData,
   MergeStrategy, MergeStrategyInner, ObjectKind, SliceExt, Span, StmtTrace,
-  ExprNode, ProofNode, TermKind, ThmKind, ThmId};
+  ExprNode, Numeral, ProofNode, StringLit, TermId, TermKind, Thm, ThmKind, Type, did_you_mean};
 use crate::elab::local_context::{try_get_span, try_get_span_from, AwaitingProof, InferSort};
+use crate::elab::disc_tree::flatten_lisp;
+use crate::elab::conv::{parse_path, parse_rw_path};
+use crate::explain::ErrorCode;
 use crate::elab::{
   refine::{RStack, RState, RefineResult},
-  ElabErrorKind, ReportMode, Result};
+  Capability, ElabErrorKind, ReportMode, Result, WarningCategory};
 use super::parser::{Branch, DefTarget, Ir, MVarPattern, Pattern};
 use super::print::{EnvDisplay, FormatEnv};
 use super::{Arc, BuiltinProc, Cell, InferTarget, LispKind, LispRef, LispVal, Modifiers, Proc,
-  ProcPos, ProcSpec, QExpr, Rc, RefCell, Uncons};
+  ProcPos, ProcSpec, QExpr, Rc, RefCell, Syntax, Uncons};
 
 #[derive(Debug)]
 enum Stack<'a> {
@@ -40,7 +44,7 @@ enum Stack<'a> {
   TestPattern(Span, LispVal, std::slice::Iter<'a, Branch>,
     &'a Branch, Vec<PatternStack<'a>>, Box<[LispVal]>),
   Drop(usize),
-  Ret(FileSpan, ProcPos, Vec<LispVal>, Arc<Ir>),
+  Ret(FileSpan, ProcPos, Vec<LispVal>),
   MatchCont(Span, LispVal, std::slice::Iter<'a, Branch>, Rc<Cell<bool>>),
   SetMergeStrategy(Span, AtomId),
   MapProc(Span, Span, LispVal, Box<[Uncons]>, Vec<LispVal>),
@@ -50,6 +54,8 @@ enum Stack<'a> {
   Refine {sp: Span, stack: Vec<RStack>},
   Focus(Span, bool, Vec<LispVal>),
   Have(Span, LispVal, AtomId),
+  Suffices(Span, usize),
+  Catch(Span, LispVal, Vec<LispVal>),
 }
 
 impl<'a> EnvDisplay for Stack<'a> {
@@ -75,7 +81,7 @@ impl<'a> EnvDisplay for Stack<'a> {
         "(match {}\n  {}\n  {})\n  ->(? _)",
         fe.to(e), fe.to(br), fe.to(bs.as_slice())),
       &Stack::Drop(n) => write!(f, "drop {}", n),
-      Stack::Ret(_, pos, _, _) => match pos {
+      Stack::Ret(_, pos, _) => match pos {
         &ProcPos::Named(_, _, a) => write!(f, "ret {}", fe.to(&a)),
         ProcPos::Unnamed(_) => write!(f, "ret"),
       },
@@ -90,6 +96,8 @@ impl<'a> EnvDisplay for Stack<'a> {
       Stack::Refine {..} => write!(f, "(refine _)"),
       &Stack::Focus(_, cl, ref es) => write!(f, "(focus {} _)\n  ->{}", cl, fe.to(es)),
       Stack::Have(_, _, a) => write!(f, "(have {} _)", fe.to(a)),
+      Stack::Suffices(..) => write!(f, "(suffices _)"),
+      Stack::Catch(_, h, _) => write!(f, "(try _ {})", fe.to(h)),
     }
   }
 }
@@ -221,7 +229,7 @@ struct TestPending<'a>(Span, LispVal, &'a Ir);
 /// work without an elaboration context.
 pub type SResult<T> = std::result::Result<T, String>;
 
-fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
+fn pattern_match<'b>(env: &Environment, stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
     mut active: PatternState<'b>) -> std::result::Result<bool, TestPending<'b>> {
   loop {
     // println!("{}\n", self.print(&active));
@@ -267,6 +275,15 @@ fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
             es[0].unwrapped(|e| if let LispKind::Atom(a2) = *e {a == a2} else {false}),
           _ => false
         })),
+        Pattern::Numeral(n) => PatternState::Ret(match e.as_int(|i| i.to_biguint()) {
+          Some(i) => i.as_ref() == Some(n),
+          None => env.numeral_value(&e).as_ref() == Some(n),
+        }),
+        Pattern::StringLit(s) => PatternState::Ret(
+          match e.unwrapped(|e| if let LispKind::String(s2) = e {Some(s == s2)} else {None}) {
+            Some(b) => b,
+            None => env.string_value(&e).map_or(false, |s2| **s == *s2),
+          }),
         Pattern::DottedList(ps, r) => PatternState::List(Uncons::from(e), ps.iter(), Dot::DottedList(r)),
         &Pattern::List(ref ps, n) => PatternState::List(Uncons::from(e), ps.iter(), Dot::List(n)),
         Pattern::And(ps) => PatternState::Binary(false, false, e, ps.iter()),
@@ -320,6 +337,80 @@ impl Elaborator {
     self.report(ElabError::info(sp, format!("{}", self.print(e))))
   }
 
+  /// Set the elaborator option `key` to `v`, for `set-option` and the `@(options)`
+  /// annotation. The options are:
+  ///
+  /// * `timeout`: the timeout in milliseconds, or `0` for no timeout (see `set-timeout`)
+  /// * `stack-limit`: the maximum number of stack frames (see `set-stack-limit`)
+  /// * `check-proofs`: whether to check proofs (see `check-proofs`)
+  /// * `report-error`, `report-warn`, `report-info`: whether to report diagnostics of
+  ///   each level (see `set-reporting`)
+  /// * `dep-policy`: the policy for inferred dependencies (see `set-dep-policy`)
+  /// * `div-by-zero`: `'zero` if `{a // 0}` is `0` and `{a % 0}` is `a` (the default),
+  ///   or `'error` if `//` and `%` report an error for a zero divisor
+  /// * `suggest-proofs`: whether a theorem proved by a tactic script suggests its proof term
+  ///   as a replacement for the script
+  /// * `pp-width`: the line width for pretty printing expressions in messages
+  /// * `pp-coercions`: whether the pretty printer shows coercions
+  /// * `pp-implicits`: whether the pretty printer shows implicit arguments
+  /// * `pp-radix`: the radix of numerals in the pretty printer, 10 or 16
+  /// * `warn-unused`, `warn-shadowing`, `warn-deprecated`, `warn-slow-decl`: the severity
+  ///   of each [`WarningCategory`], one of `'off`, `'info`, `'warn` or `'error`
+  /// * `slow-decl-ms`: the time in milliseconds above which a declaration is slow
+  pub(crate) fn set_option(&mut self, key: &[u8], v: &LispVal) -> SResult<()> {
+    let as_bool = || v.as_bool().ok_or("expected a boolean");
+    match key {
+      b"timeout" => match v.as_int(BigInt::to_u64).ok_or("expected a number")? {
+        None | Some(0) => {self.timeout = None; self.cur_timeout = None},
+        Some(n) => {
+          let d = Duration::from_millis(n);
+          self.timeout = Some(d);
+          self.cur_timeout = Instant::now().checked_add(d)
+        }
+      },
+      b"stack-limit" => self.stack_limit =
+        v.as_int(BigInt::to_usize).ok_or("expected a number")?.unwrap_or(usize::MAX),
+      b"check-proofs" => self.check_proofs = as_bool()?,
+      b"report-error" => self.reporting.error = as_bool()?,
+      b"report-warn" => self.reporting.warn = as_bool()?,
+      b"report-info" => self.reporting.info = as_bool()?,
+      b"dep-policy" => {
+        let p = v.as_atom().ok_or("expected an atom")?;
+        self.dep_policy = self.data[p].name.as_str().parse()?
+      }
+      b"div-by-zero" => self.div_zero_error = match v.as_atom() {
+        Some(AtomId::ERROR) => true,
+        a => match a.map(|a| &*self.data[a].name) {
+          Some(b"zero") => false,
+          _ => return Err("expected 'error or 'zero".into())
+        }
+      },
+      b"suggest-proofs" => self.suggest_proofs = as_bool()?,
+      b"pp-width" => self.env.pp.width =
+        v.as_int(BigInt::to_usize).flatten().filter(|&n| n != 0).ok_or("expected a positive number")?,
+      b"pp-coercions" => self.env.pp.coercions = as_bool()?,
+      b"pp-implicits" => self.env.pp.implicits = as_bool()?,
+      b"pp-radix" => self.env.pp.radix =
+        v.as_int(BigInt::to_u32).flatten().filter(|&n| n == 10 || n == 16).ok_or("expected 10 or 16")?,
+      b"slow-decl-ms" => self.slow_decl = Duration::from_millis(
+        v.as_int(BigInt::to_u64).flatten().ok_or("expected a number")?),
+      _ => if let Some(cat) = key.strip_prefix(b"warn-").and_then(WarningCategory::from_bytes) {
+        let level = v.as_atom().and_then(|a| crate::elab::parse_severity(&self.data[a].name))
+          .ok_or("expected 'off, 'info, 'warn or 'error")?;
+        self.warnings.set(cat, level)
+      } else {
+        const OPTIONS: [&[u8]; 18] = [b"timeout", b"stack-limit", b"check-proofs",
+          b"report-error", b"report-warn", b"report-info", b"dep-policy", b"div-by-zero",
+          b"suggest-proofs",
+          b"pp-width", b"pp-coercions", b"pp-implicits", b"pp-radix", b"warn-unused", b"warn-shadowing", b"warn-deprecated", b"warn-slow-decl",
+          b"slow-decl-ms"];
+        return Err(format!("unknown option '{}'{}", String::from_utf8_lossy(key),
+          crate::did_you_mean(key, OPTIONS)))
+      }
+    }
+    Ok(())
+  }
+
   /// Parse and evaluate a lisp expression. This is the main entry point.
   pub fn eval_lisp(&mut self, e: &SExpr) -> Result<LispVal> {
     self.eval_lisp_doc(e, String::new())
@@ -345,22 +436,32 @@ impl Elaborator {
   pub fn elab_lisp(&mut self, e: &SExpr) -> Result<LispVal> {
     let sp = e.span;
     let ir = self.parse_lisp(e)?;
-    Evaluator::new(self, sp).run(State::Refines(sp, [ir].iter()))
+    Evaluator::new(self, &CodeArena::default(), sp).run(State::Refines(sp, [ir].iter()))
   }
 
   /// Evaluate a compiled lisp expression.
   pub fn evaluate<'b>(&'b mut self, sp: Span, ir: &'b Ir) -> Result<LispVal> {
-    Evaluator::new(self, sp).run(State::Eval(ir))
+    Evaluator::new(self, &CodeArena::default(), sp).run(State::Eval(ir))
   }
 
   /// Shorthand to call a lisp function from the top level.
   pub fn call_func(&mut self, sp: Span, f: LispVal, es: Vec<LispVal>) -> Result<LispVal> {
-    Evaluator::new(self, sp).run(State::App(sp, sp, f, es, [].iter()))
+    Evaluator::new(self, &CodeArena::default(), sp).run(State::App(sp, sp, f, es, [].iter()))
+  }
+
+  /// Call the hook function stored in the global `a` (like `before-decl`) with arguments
+  /// `es`, if the user has defined it. The result of the hook is ignored.
+  pub fn call_hook(&mut self, sp: Span, a: AtomId, es: Vec<LispVal>) -> Result<()> {
+    if let Some(f) = self.data[a].lisp.as_ref().map(|d| d.val.clone()) {
+      self.call_func(sp, f, es)?;
+    }
+    Ok(())
   }
 
   /// Call an overridable lisp function. This uses the name of a builtin procedure `foo`
   /// and calls `(foo)` using the usual name resolution, meaning that if the user redefines
   /// `foo` then that function will be called instead of the builtin.
+  /// Builtins used this way should be listed in [`BuiltinProc::is_overridable`].
   pub fn call_overridable(&mut self, sp: Span, p: BuiltinProc, es: Vec<LispVal>) -> Result<LispVal> {
     let a = self.get_atom(p.to_byte_str());
     let val = match &self.data[a].lisp {
@@ -373,7 +474,8 @@ impl Elaborator {
   /// Run a merge operation from the top level. This is used during `import` in order to handle
   /// merge operations that occur due to diamond dependencies.
   pub fn apply_merge(&mut self, sp: Span, strat: Option<&MergeStrategyInner>, old: LispVal, new: LispVal) -> Result<LispVal> {
-    let mut eval = Evaluator::new(self, sp);
+    let code = CodeArena::default();
+    let mut eval = Evaluator::new(self, &code, sp);
     let st = eval.apply_merge(sp, strat, old, new)?;
     eval.run(st)
   }
@@ -384,6 +486,41 @@ impl Elaborator {
     })
   }
 
+  /// Compute a structural hash of `e`, consistent with `==`: equal values have equal hashes.
+  /// Atoms are hashed by name, so the hash of data built from atoms, strings, numbers and
+  /// lists does not depend on the elaboration session. Values that only have reference
+  /// equality (procedures, goals, metavariables, atom maps) are hashed by address.
+  fn hash_value(&self, e: &LispKind) -> u64 {
+    fn spine(elab: &Elaborator, e: &LispKind, h: &mut DefaultHasher) {
+      e.unwrapped(|e| match e {
+        LispKind::List(es) => {
+          for e in &**es { h.write_u8(1); value(elab, e, h) }
+          h.write_u8(0)
+        }
+        LispKind::DottedList(es, r) => {
+          for e in &**es { h.write_u8(1); value(elab, e, h) }
+          spine(elab, r, h)
+        }
+        _ => { h.write_u8(2); value(elab, e, h) }
+      })
+    }
+    fn value(elab: &Elaborator, e: &LispKind, h: &mut DefaultHasher) {
+      e.unwrapped(|e| match e {
+        LispKind::List(_) | LispKind::DottedList(..) => { h.write_u8(b'l'); spine(elab, e, h) }
+        &LispKind::Atom(a) => { h.write_u8(b'a'); (*elab.data[a].name).hash(h) }
+        LispKind::Number(n) => { h.write_u8(b'n'); n.hash(h) }
+        LispKind::String(s) => { h.write_u8(b's'); (**s).hash(h) }
+        LispKind::Bool(b) => { h.write_u8(b'b'); b.hash(h) }
+        LispKind::Syntax(s) => { h.write_u8(b'x'); s.to_str().hash(h) }
+        LispKind::Undef => h.write_u8(b'u'),
+        _ => { h.write_u8(b'p'); (e as *const LispKind).hash(h) }
+      })
+    }
+    let mut h = DefaultHasher::new();
+    value(self, e, &mut h);
+    h.finish()
+  }
+
   fn as_string_atom(&mut self, e: &LispVal) -> Option<AtomId> {
     e.unwrapped(|e| match e {
       LispKind::String(s) => Some(self.get_atom(s)),
@@ -392,6 +529,36 @@ impl Elaborator {
     })
   }
 
+  /// Get the documentation of `e`, which is a name or a procedure, for `(help e)`.
+  fn help(&mut self, e: &LispVal) -> SResult<String> {
+    let a = match e.unwrapped(|e| match *e {
+      LispKind::Proc(Proc::Builtin(p)) => Ok(p.help()),
+      LispKind::Syntax(stx) => Ok(stx.doc().into()),
+      LispKind::Proc(Proc::Lambda {pos: ProcPos::Named(_, _, a), ..}) => Err(Some(a)),
+      _ => Err(None),
+    }) {
+      Ok(doc) => return Ok(doc),
+      Err(Some(a)) => a,
+      Err(None) => self.as_string_atom(e).ok_or("expected a name or a procedure")?,
+    };
+    let ad = &self.data[a];
+    if let Some(ld) = &ad.lisp {
+      if let Some(doc) = &ld.doc { return Ok(doc.to_string()) }
+      if let Some(p) = ld.unwrapped(|e| if let LispKind::Proc(Proc::Builtin(p)) = *e {Some(p)} else {None}) {
+        return Ok(p.help())
+      }
+    } else if let Some(p) = BuiltinProc::from_bytes(&ad.name) {
+      return Ok(p.help())
+    }
+    if let Some(stx) = Syntax::from_bytes(&ad.name) { return Ok(stx.doc().into()) }
+    let doc = match ad.decl {
+      Some(DeclKey::Term(t)) => self.terms[t].doc.clone(),
+      Some(DeclKey::Thm(t)) => self.thms[t].doc.clone(),
+      None => None,
+    };
+    doc.map(|doc| doc.to_string()).ok_or_else(|| format!("no documentation for '{}'", self.print(&a)))
+  }
+
   fn with_int<T>(&self, e: &LispVal, f: impl FnOnce(&BigInt) -> SResult<T>) -> SResult<T> {
     e.unwrapped(|e| if let LispKind::Number(n) = e {f(n)} else {
       Err(format!("expected a integer, got {}", self.print(e)))
@@ -439,56 +606,82 @@ impl Elaborator {
     Ok(true)
   }
 
-  /// Returns a string representation of the current proof context.
+  /// Write `prefix` followed by the pretty printed expression `e` to `s`, indenting the
+  /// continuation lines so that they line up with the start of the expression.
+  fn write_pp(&self, s: &mut String, prefix: &str, e: &LispVal) {
+    let width = self.pp.width.saturating_sub(prefix.len()).max(40);
+    let body = format!("{}", self.format_env().pp(e, width));
+    s.push_str(prefix);
+    for (i, line) in body.lines().enumerate() {
+      if i != 0 { s.push('\n'); s.extend(std::iter::repeat(' ').take(prefix.len())) }
+      s.push_str(line)
+    }
+    s.push('\n')
+  }
+
+  /// Returns a string representation of the current proof context. This lists the
+  /// hypotheses and subproofs one per line with their names (hypotheses that are shadowed
+  /// by a later one with the same name are marked with `✝`), followed by the goals, each
+  /// after a turnstile `|-`, and the unassigned metavariables with their targets.
   pub fn stat(&self) -> String {
     use std::fmt::Write;
     let mut s = String::new();
-    for (a, e, _) in &self.lc.proof_order {
-      writeln!(s, "{}: {}", self.print(a), self.format_env().pp(e, 80)).unwrap()
+    for (i, (a, e, _)) in self.lc.proof_order.iter().enumerate() {
+      let shadowed = self.lc.proofs.get(a).map_or(false, |&j| j != i);
+      let prefix = format!("{}{}: ", self.print(a), if shadowed {"✝"} else {""});
+      self.write_pp(&mut s, &prefix, e)
     }
-    for e in &self.lc.goals {
-      e.unwrapped(|r| if let LispKind::Goal(e) = r {
-        writeln!(s, "|- {}", self.format_env().pp(e, 80)).unwrap()
+    let goals = self.lc.goals.iter().filter_map(|g| g.goal_type()).collect::<Vec<_>>();
+    if goals.len() > 1 { writeln!(s, "{} goals", goals.len()).unwrap() }
+    for e in &goals { self.write_pp(&mut s, "|- ", e) }
+    let mut first = true;
+    for e in &self.lc.mvars {
+      e.unwrapped(|r| if let LispKind::MVar(_, tgt) = r {
+        if mem::take(&mut first) { s.push_str("\nmetavariables:\n") }
+        writeln!(s, "  {}: {}", self.print(e), self.print(tgt)).unwrap()
       })
     }
+    if self.lc.sorries != 0 {
+      writeln!(s, "({} sorry)", self.lc.sorries).unwrap()
+    }
     s
   }
 
-  fn head_err(&self, e: &LispKind) -> SResult<LispVal> {
+  fn head_err(&self, e: &LispKind) -> Result<LispVal, (ErrorCode, String)> {
     e.unwrapped(|e| match e {
-      LispKind::List(es) if es.is_empty() => Err("evaluating 'hd ()'".into()),
+      LispKind::List(es) if es.is_empty() => Err((ErrorCode::EmptyList, "evaluating 'hd ()'".into())),
       LispKind::List(es) => Ok(es[0].clone()),
       LispKind::DottedList(es, r) if es.is_empty() => self.head_err(r),
       LispKind::DottedList(es, _) => Ok(es[0].clone()),
-      _ => Err(format!("expected a list, got {}", self.print(e)))
+      _ => Err((ErrorCode::NotAList, format!("expected a list, got {}", self.print(e))))
     })
   }
 
-  fn tail(&self, e: &LispKind) -> SResult<LispVal> {
+  fn tail(&self, e: &LispKind) -> Result<LispVal, (ErrorCode, String)> {
     fn exponential_backoff(es: &[LispVal], i: usize, r: impl FnOnce(Vec<LispVal>) -> LispVal) -> LispVal {
       let j = 2 * i;
       if j >= es.len() { r(es[i..].into()) }
       else { LispVal::dotted_list(es[i..j].cloned_box(), exponential_backoff(es, j, r)) }
     }
     e.unwrapped(|e| match e {
-      LispKind::List(es) if es.is_empty() => Err("evaluating 'tl ()'".into()),
+      LispKind::List(es) if es.is_empty() => Err((ErrorCode::EmptyList, "evaluating 'tl ()'".into())),
       LispKind::List(es) =>
         Ok(exponential_backoff(es, 1, LispVal::list)),
       LispKind::DottedList(es, r) if es.is_empty() => self.tail(r),
       LispKind::DottedList(es, r) =>
         Ok(exponential_backoff(es, 1, |v| LispVal::dotted_list(v, r.clone()))),
-      _ => Err(format!("expected a list, got {}", self.print(e)))
+      _ => Err((ErrorCode::NotAList, format!("expected a list, got {}", self.print(e))))
     })
   }
 
-  fn nth(&self, e: &LispKind, i: usize) -> SResult<LispVal> {
+  fn nth(&self, e: &LispKind, i: usize) -> Result<LispVal, (ErrorCode, String)> {
     e.unwrapped(|e| match e {
       LispKind::List(es) => Ok(es.get(i).cloned().unwrap_or_else(LispVal::undef)),
       LispKind::DottedList(es, r) => match es.get(i) {
         Some(e) => Ok(e.clone()),
         None => self.nth(r, i - es.len()),
       },
-      _ => Err(format!("expected a list, got {}", self.print(e)))
+      _ => Err((ErrorCode::NotAList, format!("expected a list, got {}", self.print(e))))
     })
   }
 
@@ -532,8 +725,7 @@ impl Elaborator {
     }
   }
 
-  fn get_proof(&self, t: ThmId, mut heap: Vec<LispVal>) -> LispVal {
-    let tdata = &self.thms[t];
+  fn get_proof(&self, tdata: &Thm, mut heap: Vec<LispVal>) -> LispVal {
     match &tdata.kind {
       ThmKind::Thm(Some(pr)) => {
         let mut ds = Vec::new();
@@ -596,26 +788,13 @@ impl Elaborator {
           self.spans.insert_if(fsp.span, || ObjectKind::Thm(t));
         }
         let tdata = &self.thms[t];
-        let mut bvs = Vec::new();
-        let mut heap = Vec::new();
+        let (bis, hyps, ret, mut heap) = self.thm_statement(tdata);
         let mut args = vec![
           LispVal::atom(match tdata.kind {
             ThmKind::Axiom => AtomId::AXIOM,
             ThmKind::Thm(_) => AtomId::THM
           }),
-          LispVal::atom(x),
-          self.binders(&tdata.args, &mut heap, &mut bvs),
-          {
-            for e in &tdata.heap[heap.len()..] {
-              let e = self.expr_node(&heap, &mut None, e);
-              heap.push(e)
-            }
-            LispVal::list(tdata.hyps.iter().map(|(a, e)| LispVal::list(vec![
-              LispVal::atom(a.unwrap_or(AtomId::UNDER)),
-              self.expr_node(&heap, &mut None, e)
-            ])).collect::<Vec<_>>())
-          },
-          self.expr_node(&heap, &mut None, &tdata.ret)
+          LispVal::atom(x), bis, hyps, ret
         ];
         if let ThmKind::Thm(_) = tdata.kind {
           args.push(vis(tdata.vis));
@@ -647,6 +826,21 @@ fn set_report_mode(fe: FormatEnv<'_>, mode: &mut ReportMode, args: &[LispVal]) -
   } else {Err("invalid arguments".into())}
 }
 
+/// The largest number of bits in the result of `^` and `shl`. Larger numbers are an error
+/// rather than an allocation that can exhaust the memory of the process.
+const MAX_INT_BITS: u64 = 1 << 26;
+
+/// Shift `n` left (if `left` is true) or right by the absolute value of `e`.
+fn shift(n: &mut BigInt, left: bool, e: &BigInt) -> SResult<()> {
+  let i: u64 = e.magnitude().try_into().map_err(|_| "shift out of range")?;
+  if !left { *n >>= i }
+  else if !n.is_zero() {
+    if n.bits().saturating_add(i) > MAX_INT_BITS { return Err("number too large".into()) }
+    *n <<= i
+  }
+  Ok(())
+}
+
 /// The lisp evaluation context, representing a lisp evaluation in progress.
 /// This is an explicitly unfolled state machine (rather than using recursive functions)
 /// so that we can explicitly manipulate the program stack for error reporting purposes.
@@ -666,6 +860,25 @@ pub struct Evaluator<'a> {
   /// The evaluation stack. This is a structured object containing a stack of continuations
   /// each of which represent a context which awaiting a value from a sub-computation.
   stack: Vec<Stack<'a>>,
+  /// The code of the lambdas that have been called in this evaluation. The IR of a lambda is
+  /// owned by an `Arc` in the closure, which may be dropped while the code is still running
+  /// (for example if the function redefines itself), so we keep it alive here instead, for
+  /// the whole evaluation, which allows the stack to borrow it.
+  code: &'a CodeArena,
+  /// The entries of `code`, indexed by address, so that each function is stored only once.
+  code_map: HashMap<*const Ir, &'a Ir>,
+  /// The value that caused the error being raised, if any, which is passed to a `try`
+  /// handler as the `value` field of the error object.
+  err_value: Option<LispVal>,
+}
+
+/// An arena holding the code of the lambdas called during an evaluation.
+/// See [`Evaluator::code`].
+#[derive(Default)]
+pub(crate) struct CodeArena(typed_arena::Arena<Arc<Ir>>);
+
+impl std::fmt::Debug for CodeArena {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "CodeArena") }
 }
 impl<'a> Deref for Evaluator<'a> {
   type Target = Elaborator;
@@ -676,14 +889,26 @@ impl<'a> DerefMut for Evaluator<'a> {
 }
 
 impl<'a> Evaluator<'a> {
-  fn new(elab: &'a mut Elaborator, orig_span: Span) -> Evaluator<'a> {
+  fn new(elab: &'a mut Elaborator, code: &'a CodeArena, orig_span: Span) -> Evaluator<'a> {
     let file = elab.path.clone();
-    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![]}
+    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![], code, code_map: HashMap::new(),
+      err_value: None}
+  }
+
+  /// The capability of the code being evaluated, which depends on the file it comes from.
+  fn capability(&self) -> Capability {
+    self.caps.get(&self.file).min(self.elab.capability)
+  }
+
+  /// Borrow the code of a lambda for the rest of the evaluation.
+  fn borrow_code(&mut self, code: &Arc<Ir>) -> &'a Ir {
+    let arena = self.code;
+    *self.code_map.entry(Arc::as_ptr(code)).or_insert_with(|| &**arena.0.alloc(code.clone()))
   }
 
   fn fspan_base(&mut self, sp: Span) -> FileSpan {
     for s in &self.stack {
-      if let Stack::Ret(fsp, _, _, _) = s {return fsp.clone()}
+      if let Stack::Ret(fsp, _, _) = s {return fsp.clone()}
     }
     self.fspan(sp)
   }
@@ -693,7 +918,7 @@ impl<'a> Evaluator<'a> {
     let mut old = sp.map(|(sp, good)| (self.fspan(sp), good, base));
     let mut info = vec![];
     for s in self.stack.iter().rev() {
-      if let Stack::Ret(fsp, pos, _, _) = s {
+      if let Stack::Ret(fsp, pos, _) = s {
         let x = match pos {
           ProcPos::Named(_, _, a) => format!("({})", self.data[*a].name).into(),
           ProcPos::Unnamed(_) => "[fn]".into(),
@@ -711,13 +936,14 @@ impl<'a> Evaluator<'a> {
       pos: old.map_or(self.orig_span, |(sp, _, _)| sp.span),
       level,
       kind: ElabErrorKind::Boxed(err.into(),
-        if self.backtrace.active(level) {Some(info)} else {None})
+        if self.backtrace.active(level) {Some(info)} else {None}),
+      code: None,
     }
   }
 
   fn stack_span(&self, mut n: usize) -> Option<FileSpan> {
     for s in self.stack.iter().rev() {
-      if let Stack::Ret(fsp, _, _, _) = s {
+      if let Stack::Ret(fsp, _, _) = s {
         match n.checked_sub(1) {
           None => return Some(fsp.clone()),
           Some(i) => n = i
@@ -792,7 +1018,7 @@ impl<'a> Evaluator<'a> {
 
 macro_rules! make_builtins {
   ($self:ident, $sp1:ident, $sp2:ident, $args:ident,
-      $($(#[$attr:meta])* $e:ident: $ty:ident($n:expr) => $res:expr,)*) => {
+      $($(#[$attr:meta])* $e:ident: $ty:ident($n:expr) $usage:literal => $res:expr,)*) => {
     impl BuiltinProc {
       /// Get the argument specification for a builtin.
       #[must_use] pub fn spec(self) -> ProcSpec {
@@ -800,6 +1026,13 @@ macro_rules! make_builtins {
           $($(#[$attr])* BuiltinProc::$e => ProcSpec::$ty($n)),*
         }
       }
+
+      /// A usage example for the builtin, like `(nth n e)`, naming its arguments.
+      #[must_use] pub fn usage(self) -> &'static str {
+        match self {
+          $($(#[$attr])* BuiltinProc::$e => $usage),*
+        }
+      }
     }
 
     impl<'a> Evaluator<'a> {
@@ -815,7 +1048,23 @@ macro_rules! make_builtins {
               Some(($sp1, false)), ErrorLevel::Error, format!("({})", f).into(), s))
           }
         }}}
+        macro_rules! try_code {($v:expr, $x:expr) => {{
+          match $x {
+            Ok(e) => e,
+            Err((code, s)) => {
+              $self.err_value = Some($v.clone());
+              return Err($self.make_stack_err(
+                Some(($sp1, false)), ErrorLevel::Error, format!("({})", f).into(), s).with_code(code))
+            }
+          }
+        }}}
 
+        $self.do_cache.builtin(f);
+        let cap = $self.capability();
+        if f.capability() > cap {
+          try1!(Err(format!("this requires the {} capability, but the code only has {}",
+            f.capability(), cap)))
+        }
         Ok(State::Ret(match f { $($(#[$attr])* BuiltinProc::$e => $res),* }))
       }
     }
@@ -823,38 +1072,60 @@ macro_rules! make_builtins {
 }
 
 make_builtins! { self, sp1, sp2, args,
-  Display: Exact(1) => {
+  Display: Exact(1) "(display s)" => {
     let s = try1!(self.as_string(&args[0]));
     print!(sp1, String::from_utf8_lossy(&s));
     LispVal::undef()
   },
-  Error: Exact(1) => {
+  Try: Exact(2) "(try f h)" => {
+    let handler = args.pop().unwrap();
+    let proc = args.pop().unwrap();
+    let goals = self.lc.goals.clone();
+    self.stack.push(Stack::Catch(sp1, handler, goals));
+    return Ok(State::App(sp1, sp1, proc, vec![], [].iter()))
+  },
+  Error: Exact(1) "(error s)" => {
     let s = try1!(self.as_string(&args[0]));
     try1!(Err(String::from_utf8_lossy(&s)))
   },
-  Print: Exact(1) => {print!(sp1, format!("{}", self.print(&args[0]))); LispVal::undef()},
-  ReportAt: Exact(3) => {
-    let level = match args[0].as_atom() {
-      Some(AtomId::ERROR) => ErrorLevel::Error,
-      Some(AtomId::WARN) => ErrorLevel::Warning,
-      Some(AtomId::INFO) =>  ErrorLevel::Info,
-      _ => try1!(Err("expected 'error, 'warn, or 'info"))
+  Print: Exact(1) "(print e)" => {print!(sp1, format!("{}", self.print(&args[0]))); LispVal::undef()},
+  Help: Exact(1) "(help x)" => {
+    let doc = try1!(self.help(&args[0]));
+    print!(sp1, doc);
+    LispVal::undef()
+  },
+  Builtin: Exact(1) "(builtin x)" => {
+    let a = try1!(self.as_string_atom(&args[0]).ok_or("expected an atom or string"));
+    match BuiltinProc::from_bytes(&self.data[a].name) {
+      Some(p) => LispVal::proc(Proc::Builtin(p)),
+      None => try1!(Err(format!("'{}' is not a builtin", self.data[a].name))),
+    }
+  },
+  ReportAt: Exact(3) "(report-at sp type msg)" => {
+    let (level, code) = match args[0].as_atom() {
+      Some(AtomId::ERROR) => (ErrorLevel::Error, None),
+      Some(AtomId::WARN) => (ErrorLevel::Warning, None),
+      Some(AtomId::INFO) =>  (ErrorLevel::Info, None),
+      a => match a.and_then(|a| WarningCategory::from_bytes(&self.data[a].name)) {
+        Some(cat) => (ErrorLevel::Warning, Some(cat.code())),
+        None => try1!(Err("expected 'error, 'warn, 'info, or a warning category"))
+      }
     };
     let FileSpan {file, span} = try1!(args[1].fspan().ok_or("expected a span"));
     if file == self.file {
       let s = try1!(self.as_string(&args[2]));
       let s = String::from_utf8_lossy(&s).into();
       let msg = if args[1].as_bool() == Some(true) {
-        self.make_stack_err(Some((span, true)), level, "(report-at)".into(), s)
+        ElabError { code, ..self.make_stack_err(Some((span, true)), level, "(report-at)".into(), s) }
       } else {
-        ElabError { pos: span, level, kind: ElabErrorKind::Boxed(s, None) }
+        ElabError { pos: span, level, kind: ElabErrorKind::Boxed(s, None), code }
       };
       self.report(msg);
     }
     LispVal::undef()
   },
-  Begin: AtLeast(0) => args.last().cloned().unwrap_or_else(LispVal::undef),
-  Apply: AtLeast(2) => {
+  Begin: AtLeast(0) "(begin e1 e2 ...)" => args.last().cloned().unwrap_or_else(LispVal::undef),
+  Apply: AtLeast(2) "(apply f a b '(c d))" => {
     fn gather(args: &mut Vec<LispVal>, e: &LispKind) -> bool {
       e.unwrapped(|e| match e {
         LispKind::List(es) => {args.extend_from_slice(es); true}
@@ -870,17 +1141,17 @@ make_builtins! { self, sp1, sp2, args,
     }
     return Ok(State::App(sp1, sp, proc, args, [].iter()))
   },
-  Add: AtLeast(0) => {
+  Add: AtLeast(0) "(+ a b c)" => {
     let mut n: BigInt = 0.into();
     for e in args { n += try1!(self.as_int(&e)) }
     LispVal::number(n)
   },
-  Mul: AtLeast(0) => {
+  Mul: AtLeast(0) "(* a b c)" => {
     let mut n: BigInt = 1.into();
     for e in args { n *= try1!(self.as_int(&e)) }
     LispVal::number(n)
   },
-  Pow: AtLeast(0) => {
+  Pow: AtLeast(0) "{a ^ b}" => {
     let mut it = args.into_iter().rev();
     match it.next() {
       None => LispVal::number(1.into()),
@@ -889,25 +1160,28 @@ make_builtins! { self, sp1, sp2, args,
         for e in it {
           let exp: u32 = try1!(n.try_into().map_err(|_| "exponent out of range"));
           let base = try1!(self.as_int(&e));
+          if base.bits() > 1 && (base.bits() - 1).saturating_mul(exp.into()) > MAX_INT_BITS {
+            try1!(Err("number too large"))
+          }
           n = if base == 2.into() { BigInt::from(1) << exp } else { BigInt::pow(&base, exp) };
         }
         LispVal::number(n)
       }
     }
   },
-  Max: AtLeast(1) => {
+  Max: AtLeast(1) "(max a b c)" => {
     let mut it = args.into_iter();
     let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
     for e in it { n = n.max(try1!(self.as_int(&e)).clone()) }
     LispVal::number(n)
   },
-  Min: AtLeast(1) => {
+  Min: AtLeast(1) "(min a b c)" => {
     let mut it = args.into_iter();
     let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
     for e in it { n = n.min(try1!(self.as_int(&e)).clone()) }
     LispVal::number(n)
   },
-  Sub: AtLeast(1) => if args.len() == 1 {
+  Sub: AtLeast(1) "(- a b)" => if args.len() == 1 {
     LispVal::number(-try1!(self.as_int(&args[0])))
   } else {
     let mut it = args.into_iter();
@@ -915,79 +1189,60 @@ make_builtins! { self, sp1, sp2, args,
     for e in it { n -= try1!(self.as_int(&e)) }
     LispVal::number(n)
   },
-  Div: AtLeast(1) => {
+  Div: AtLeast(1) "{a // b}" => {
     let mut it = args.into_iter();
     let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
     for e in it {
       let a = try1!(self.as_int(&e));
-      if a.is_zero() {n.set_zero()} else {n /= a}
+      if !a.is_zero() {n /= a}
+      else if self.div_zero_error {try1!(Err("division by zero"))}
+      else {n.set_zero()}
     }
     LispVal::number(n)
   },
-  Mod: AtLeast(1) => {
+  Mod: AtLeast(1) "{a % b}" => {
     let mut it = args.into_iter();
     let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
     for e in it {
       let a = try1!(self.as_int(&e));
       if !a.is_zero() {n %= a}
+      else if self.div_zero_error {try1!(Err("division by zero"))}
     }
     LispVal::number(n)
   },
-  Lt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a < b, &args))),
-  Le: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a <= b, &args))),
-  Gt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a > b, &args))),
-  Ge: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a >= b, &args))),
-  Eq: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a == b, &args))),
-  Shl: AtLeast(1) => {
+  Lt: AtLeast(1) "{a < b}" => LispVal::bool(try1!(self.int_bool_binop(|a, b| a < b, &args))),
+  Le: AtLeast(1) "{a <= b}" => LispVal::bool(try1!(self.int_bool_binop(|a, b| a <= b, &args))),
+  Gt: AtLeast(1) "{a > b}" => LispVal::bool(try1!(self.int_bool_binop(|a, b| a > b, &args))),
+  Ge: AtLeast(1) "{a >= b}" => LispVal::bool(try1!(self.int_bool_binop(|a, b| a >= b, &args))),
+  Eq: AtLeast(1) "{a = b}" => LispVal::bool(try1!(self.int_bool_binop(|a, b| a == b, &args))),
+  Shl: AtLeast(1) "{a shl b}" => {
     let mut it = args.into_iter();
     let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it {
-      try1!(self.with_int(&e, |e| {
-        if e.is_negative() {
-          let i: u64 = e.magnitude().try_into().map_err(|_| "shift out of range")?;
-          n >>= &i
-        } else {
-          let i: u64 = e.try_into().map_err(|_| "shift out of range")?;
-          n <<= &i
-        }
-        Ok(())
-      }))
-    }
+    for e in it { try1!(self.with_int(&e, |e| shift(&mut n, !e.is_negative(), e))) }
     LispVal::number(n)
   },
-  Shr: AtLeast(1) => {
+  Shr: AtLeast(1) "{a shr b}" => {
     let mut it = args.into_iter();
     let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it {
-      try1!(self.with_int(&e, |e| {
-        if e.is_negative() {
-          let i: u64 = e.magnitude().try_into().map_err(|_| "shift out of range")?;
-          n <<= &i
-        } else {
-          let i: u64 = e.try_into().map_err(|_| "shift out of range")?;
-          n >>= &i
-        }
-        Ok(())
-      }))
-    }
+    for e in it { try1!(self.with_int(&e, |e| shift(&mut n, e.is_negative(), e))) }
     LispVal::number(n)
   },
-  BAnd: AtLeast(0) => {
+  BAnd: AtLeast(0) "{a band b band ...}" => {
     let mut n: BigInt = (-1).into();
     for e in args { n &= try1!(self.as_int(&e)) }
     LispVal::number(n)
   },
-  BOr: AtLeast(0) => {
+  BOr: AtLeast(0) "{a bor b bor ...}" => {
     let mut n: BigInt = 0.into();
     for e in args { n |= try1!(self.as_int(&e)) }
     LispVal::number(n)
   },
-  BXor: AtLeast(0) => {
+  BXor: AtLeast(0) "{a bxor b bxor ...}" => {
     let mut n: BigInt = 0.into();
     for e in args { n ^= try1!(self.as_int(&e)) }
     LispVal::number(n)
   },
-  BNot: AtLeast(0) => {
+  BNot: AtLeast(0) "(bnot a)" => {
     let n = if let [e] = &*args {
       try1!(self.as_int(e))
     } else {
@@ -997,22 +1252,23 @@ make_builtins! { self, sp1, sp2, args,
     };
     LispVal::number(!n)
   },
-  Equal: AtLeast(1) => {
+  Equal: AtLeast(1) "(== e1 e2 ...)" => {
     let (e1, args) = args.split_first().unwrap();
     LispVal::bool(args.iter().all(|e2| e1 == e2))
   },
-  ToString: Exact(1) => LispVal::string(self.to_string(&args[0])),
-  StringToAtom: Exact(1) => {
+  Hash: Exact(1) "(hash e)" => LispVal::number(self.hash_value(&args[0]).into()),
+  ToString: Exact(1) "(->string e)" => LispVal::string(self.to_string(&args[0])),
+  StringToAtom: Exact(1) "(string->atom s)" => {
     let s = try1!(self.as_string(&args[0]));
     LispVal::atom(self.get_atom(&s))
   },
-  StringAppend: AtLeast(0) => {
+  StringAppend: AtLeast(0) "(string-append s1 s2 s3)" => {
     let mut out = Vec::new();
     for e in args { out.extend_from_slice(&self.to_string(&e)) }
     LispVal::string(out.into())
   },
-  StringLen: Exact(1) => LispVal::number(try1!(self.as_string(&args[0])).len().into()),
-  StringNth: Exact(2) => {
+  StringLen: Exact(1) "(string-len s)" => LispVal::number(try1!(self.as_string(&args[0])).len().into()),
+  StringNth: Exact(2) "(string-nth n s)" => {
     let i: usize = try1!(self.with_int(&args[0],
       |n| n.try_into().map_err(|_| format!("index out of range: {}", n))));
     let s = try1!(self.as_string(&args[1]));
@@ -1020,7 +1276,7 @@ make_builtins! { self, sp1, sp2, args,
       format!("index out of range: index {}, length {}", i, s.len())));
     LispVal::number(c.into())
   },
-  Substr: Exact(3) => {
+  Substr: Exact(3) "(substr start end s)" => {
     let start: usize = try1!(self.with_int(&args[0],
       |n| n.try_into().map_err(|_| format!("index out of range: start {}", n))));
     let end: usize = try1!(self.with_int(&args[1],
@@ -1030,13 +1286,13 @@ make_builtins! { self, sp1, sp2, args,
     if end > s.len() { try1!(Err(format!("index out of range: end {}, length {}", end, s.len()))) }
     LispVal::string(ArcString::new(s[start..end].into()))
   },
-  StringToList: Exact(1) => {
+  StringToList: Exact(1) "(string->list s)" => {
     let s = try1!(self.as_string(&args[0]));
     LispVal::list(s.iter()
       .map(|&c| LispVal::number(c.into()))
       .collect::<Vec<_>>())
   },
-  ListToString: Exact(1) => {
+  ListToString: Exact(1) "(list->string s)" => {
     let mut u = Uncons::New(args[0].clone());
     let mut out: Vec<u8> = Vec::with_capacity(u.len());
     for e in &mut u {
@@ -1048,24 +1304,24 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::string(out.into())
   },
-  Not: AtLeast(0) => LispVal::bool(!args.iter().any(|e| e.truthy())),
-  And: AtLeast(0) => LispVal::bool(args.iter().all(|e| e.truthy())),
-  Or: AtLeast(0) => LispVal::bool(args.iter().any(|e| e.truthy())),
-  List: AtLeast(0) => LispVal::list(args),
-  Cons: AtLeast(0) => match args.len() {
+  Not: AtLeast(0) "(not e1 e2 e3)" => LispVal::bool(!args.iter().any(|e| e.truthy())),
+  And: AtLeast(0) "(and e1 e2 e3)" => LispVal::bool(args.iter().all(|e| e.truthy())),
+  Or: AtLeast(0) "(or e1 e2 e3)" => LispVal::bool(args.iter().any(|e| e.truthy())),
+  List: AtLeast(0) "(list e1 e2 e3)" => LispVal::list(args).span(self.fspan(sp1)),
+  Cons: AtLeast(0) "(cons e1 e2)" => match args.len() {
     0 => LispVal::nil(),
     1 => args[0].clone(),
     _ => {
       let r = args.pop().unwrap();
       if r.exactly(0) {LispVal::list(args)}
-      else {LispVal::dotted_list(args, r)}
+      else {LispVal::dotted_list(args, r)}.span(self.fspan(sp1))
     }
   },
-  Head: Exact(1) => try1!(self.head_err(&args[0])),
-  Tail: Exact(1) => try1!(self.tail(&args[0])),
-  Nth: Exact(2) => try1!(self.nth(&args[1],
+  Head: Exact(1) "(hd e)" => try_code!(args[0], self.head_err(&args[0])),
+  Tail: Exact(1) "(tl e)" => try_code!(args[0], self.tail(&args[0])),
+  Nth: Exact(2) "(nth n e)" => try_code!(args[1], self.nth(&args[1],
     try1!(args[0].as_int(|n| n.to_usize().unwrap_or(usize::MAX)).ok_or("expected a number")))),
-  Map: AtLeast(1) => {
+  Map: AtLeast(1) "(map f '(a1 a2) '(b1 b2))" => {
     let mut it = args.into_iter();
     let proc = it.next().unwrap();
     let sp = proc.fspan().map_or(sp2, |fsp| fsp.span);
@@ -1075,47 +1331,68 @@ make_builtins! { self, sp1, sp2, args,
     return Ok(State::MapProc(sp1, sp, proc,
       it.map(Uncons::from).collect(), vec![]))
   },
-  IsBool: Exact(1) => LispVal::bool(args[0].is_bool()),
-  IsAtom: Exact(1) => LispVal::bool(args[0].is_atom()),
-  IsPair: Exact(1) => LispVal::bool(args[0].at_least(1)),
-  IsNull: Exact(1) => LispVal::bool(args[0].exactly(0)),
-  IsNumber: Exact(1) => LispVal::bool(args[0].is_int()),
-  IsString: Exact(1) => LispVal::bool(args[0].is_string()),
-  IsProc: Exact(1) => LispVal::bool(args[0].is_proc()),
-  IsDef: Exact(1) => LispVal::bool(args[0].is_def()),
-  IsRef: Exact(1) => LispVal::bool(args[0].is_ref()),
-  NewRef: AtLeast(0) => LispVal::new_ref(args.get(0).cloned().unwrap_or_else(LispVal::undef)),
-  GetRef: Exact(1) => try1!(self.as_ref(&args[0], |e| Ok(e.clone()))),
-  SetRef: Exact(2) => {
+  IsBool: Exact(1) "(bool? e)" => LispVal::bool(args[0].is_bool()),
+  IsAtom: Exact(1) "(atom? e)" => LispVal::bool(args[0].is_atom()),
+  IsPair: Exact(1) "(pair? e)" => LispVal::bool(args[0].at_least(1)),
+  IsNull: Exact(1) "(null? e)" => LispVal::bool(args[0].exactly(0)),
+  IsNumber: Exact(1) "(number? e)" => LispVal::bool(args[0].is_int()),
+  IsString: Exact(1) "(string? e)" => LispVal::bool(args[0].is_string()),
+  IsProc: Exact(1) "(fn? e)" => LispVal::bool(args[0].is_proc()),
+  IsDef: Exact(1) "(def? e)" => LispVal::bool(args[0].is_def()),
+  IsRef: Exact(1) "(ref? e)" => LispVal::bool(args[0].is_ref()),
+  NewRef: AtLeast(0) "(ref! e)" => LispVal::new_ref(args.get(0).cloned().unwrap_or_else(LispVal::undef)),
+  GetRef: Exact(1) "(get! r)" => try1!(self.as_ref(&args[0], |e| Ok(e.clone()))),
+  SetRef: Exact(2) "(set! r v)" => {
     try1!(self.as_ref(&args[0], |e| {*e = args[1].clone(); Ok(())}));
     LispVal::undef()
   },
-  SetWeak: Exact(2) => {
+  SetWeak: Exact(2) "(set-weak! r v)" => {
     try1!(self.as_lref(&args[0], |e| {e.set_weak(&args[1]); Ok(())}));
     LispVal::undef()
   },
-  CopySpan: Exact(2) => {
+  WeakRef: Exact(1) "(weak-ref! e)" => LispVal::weak_ref(&args[0]),
+  CopySpan: Exact(2) "(copy-span from to)" => {
     let mut it = args.drain(..);
     match (it.next().unwrap().fspan(), it.next().unwrap()) {
       (Some(sp), e) => e.replace_span(sp),
       (None, e) => e
     }
   },
-  StackSpan: Exact(1) => {
+  GetSpan: Exact(1) "(get-span e)" => match args[0].fspan() {
+    Some(fsp) if fsp.file.ptr_eq(&self.path) => LispVal::list(vec![
+      LispVal::number(fsp.span.start.into()), LispVal::number(fsp.span.end.into())]),
+    _ => LispVal::undef()
+  },
+  WithSpan: Exact(2) "(with-span sp e)" => {
+    let mut u = Uncons::from(args[0].clone());
+    let pos = |e: Option<LispVal>| e.and_then(|e| e.as_int(|n| n.to_usize()).flatten());
+    let fsp = match (pos(u.next()), pos(u.next()), u.exactly(0)) {
+      (Some(start), Some(end), true) => {
+        if start > end || end > self.ast.source.len() { try1!(Err("span out of range")) }
+        Some(FileSpan {file: self.path.clone(), span: (start..end).into()})
+      }
+      _ => args[0].fspan(),
+    };
+    match fsp {
+      Some(fsp) => args[1].replace_span(fsp),
+      None => args.swap_remove(1),
+    }
+  },
+  StackSpan: Exact(1) "(stack-span n)" => {
     let n = try1!(args[0].as_int(|n| n.to_usize().unwrap_or(usize::MAX)).ok_or("expected a number"));
     match self.stack_span(n) {
       Some(sp) => LispVal::undef().span(sp),
       None => LispVal::undef()
     }
   },
-  Async: AtLeast(1) => {
+  Async: AtLeast(1) "(async f args)" => {
     let proc = args.remove(0);
     let sp = proc.fspan().map_or(sp2, |fsp| fsp.span);
     // TODO: actually async this
     return Ok(State::App(sp1, sp, proc, args, [].iter()))
   },
-  IsAtomMap: Exact(1) => LispVal::bool(args[0].is_map()),
-  NewAtomMap: AtLeast(0) => {
+  IsAtomMap: Exact(1) "(atom-map? m)" => LispVal::bool(args[0].is_map()),
+  NewAtomMap: AtLeast(0) "(atom-map! [k1 v1] [k2 v2] ...)" => {
     let mut m = HashMap::new();
     for e in args {
       let mut u = Uncons::from(e);
@@ -1128,11 +1405,11 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::new_ref(LispVal::new(LispKind::AtomMap(m)))
   },
-  Lookup: AtLeast(2) => {
+  Lookup: AtLeast(2) "(lookup m k)" => {
     match self.as_string_atom(&args[1]) {
       None => LispVal::undef(),
       Some(k) => {
-        let e = try1!(self.as_map(&args[0], |m| Ok(m.get(&k).cloned())));
+        let e = try1!(self.as_map(&args[0], |m| Ok(m.get(&k).filter(|e| !e.is_dangling()).cloned())));
         if let Some(e) = e {e} else {
           let v = args.get(2).cloned().unwrap_or_else(LispVal::undef);
           if v.is_proc() {
@@ -1144,7 +1421,7 @@ make_builtins! { self, sp1, sp2, args,
       }
     }
   },
-  Insert: AtLeast(2) => {
+  Insert: AtLeast(2) "(insert! m k v)" => {
     try1!(try1!(args[0].as_ref_mut(|r| {
       r.as_map_mut(|m| -> SResult<_> {
         let k = self.as_string_atom(&args[1])
@@ -1158,7 +1435,21 @@ make_builtins! { self, sp1, sp2, args,
     }).unwrap_or(None).ok_or("expected a mutable map")));
     LispVal::undef()
   },
-  InsertNew: AtLeast(2) => {
+  InsertWeak: Exact(3) "(insert-weak! m k v)" => {
+    try1!(try1!(args[0].as_ref_mut(|r| {
+      r.as_map_mut(|m| -> SResult<_> {
+        let k = self.as_string_atom(&args[1])
+          .ok_or_else(|| format!("expected an atom, got {}", self.print(&args[1])))?;
+        // Remove dropped entries whenever the map doubles in size,
+        // so that the cost of pruning is amortized over the insertions.
+        if m.len() >= 16 && m.len().is_power_of_two() { m.retain(|_, e| !e.is_dangling()) }
+        m.insert(k, LispVal::weak_ref(&args[2]));
+        Ok(())
+      })
+    }).unwrap_or(None).ok_or("expected a mutable map")));
+    LispVal::undef()
+  },
+  InsertNew: AtLeast(2) "(insert m k v)" => {
     let mut it = args.into_iter();
     let mut m = it.next().unwrap();
     let k = it.next().unwrap();
@@ -1173,7 +1464,7 @@ make_builtins! { self, sp1, sp2, args,
     }).ok_or("expected a map")));
     LispVal::undef()
   },
-  MergeMap: AtLeast(0) => {
+  MergeMap: AtLeast(0) "(merge-map old new)" => {
     let mut it = args.drain(..);
     if let Some(arg1) = it.next() {
       if let Some(arg2) = it.next() {
@@ -1185,7 +1476,7 @@ make_builtins! { self, sp1, sp2, args,
       } else {LispVal::proc(Proc::MergeMap(arg1.into_merge_strategy()))}
     } else {LispVal::proc(Proc::MergeMap(None))}
   },
-  SetTimeout: Exact(1) => {
+  SetTimeout: Exact(1) "(set-timeout n)" => {
     match try1!(args[0].as_int(BigInt::to_u64).ok_or("expected a number")) {
       None | Some(0) => {self.timeout = None; self.cur_timeout = None},
       Some(n) => {
@@ -1196,15 +1487,15 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::undef()
   },
-  SetStackLimit: Exact(1) => {
+  SetStackLimit: Exact(1) "(set-stack-limit n)" => {
     self.stack_limit =
       try1!(args[0].as_int(BigInt::to_usize).ok_or("expected a number"))
         .unwrap_or(usize::MAX);
     LispVal::undef()
   },
-  IsMVar: Exact(1) => LispVal::bool(args[0].is_mvar()),
-  IsGoal: Exact(1) => LispVal::bool(args[0].is_goal()),
-  NewMVar: AtLeast(0) => {
+  IsMVar: Exact(1) "(mvar? e)" => LispVal::bool(args[0].is_mvar()),
+  IsGoal: Exact(1) "(goal? e)" => LispVal::bool(args[0].is_goal()),
+  NewMVar: AtLeast(0) "(mvar! s bd)" => {
     let fsp = self.fspan(sp1);
     self.lc.new_mvar(
       if args.is_empty() { InferTarget::Unknown }
@@ -1218,38 +1509,115 @@ make_builtins! { self, sp1, sp2, args,
       } else {try1!(Err("invalid arguments"))},
       Some(fsp))
   },
-  PrettyPrint: Exact(1) =>
-    LispVal::string(format!("{}", self.format_env().pp(&args[0], 80)).into()),
-  NewGoal: Exact(1) => LispVal::goal(self.fspan(sp1), args.pop().unwrap()),
-  GoalType: Exact(1) => try1!(args[0].goal_type().ok_or("expected a goal")),
-  InferType: Exact(1) => try1!(self.infer_type(sp1, &args[0]).map_err(|e| e.kind.msg())),
-  InferSort: Exact(1) => match try1!(self.infer_target(sp1, &args[0]).map_err(|e| e.kind.msg())) {
+  PrettyPrint: Exact(1) "(pp e)" =>
+    LispVal::string(format!("{}", self.format_env().pp(&args[0], self.pp.width)).into()),
+  SetPpOptions: AtLeast(0) "(set-pp-options 'key1 v1 'key2 v2 ...)" => {
+    if args.len() % 2 != 0 { try1!(Err("expected an even number of arguments")) }
+    for kv in args.chunks_exact(2) {
+      let k = try1!(kv[0].as_atom().ok_or("expected an atom"));
+      let key = [b"pp-", &*self.data[k].name].concat();
+      try1!(self.set_option(&key, &kv[1]))
+    }
+    LispVal::undef()
+  },
+  NewGoal: Exact(1) "(goal e)" => LispVal::goal(self.fspan(sp1), args.pop().unwrap()),
+  GoalType: Exact(1) "(goal-type g)" => try1!(args[0].goal_type().ok_or("expected a goal")),
+  InferType: Exact(1) "(infer-type p)" => try1!(self.infer_type(sp1, &args[0]).map_err(|e| e.kind.msg())),
+  InferSort: Exact(1) "(infer-sort e)" => match try1!(self.infer_target(sp1, &args[0]).map_err(|e| e.kind.msg())) {
     InferTarget::Bound(s) | InferTarget::Reg(s) => LispVal::atom(s),
     InferTarget::Unknown | InferTarget::Provable => LispVal::undef(),
   },
-  GetMVars: AtLeast(0) => LispVal::list(self.lc.mvars.clone()),
-  GetGoals: AtLeast(0) => LispVal::list(self.lc.goals.clone()),
-  SetGoals: AtLeast(0) => {self.lc.set_goals(args); LispVal::undef()},
-  SetCloseFn: AtLeast(0) => {
+  GetMVars: AtLeast(0) "(get-mvars)" => LispVal::list(self.lc.mvars.clone()),
+  GetGoals: AtLeast(0) "(get-goals)" => LispVal::list(self.lc.goals.clone()),
+  SetGoals: AtLeast(0) "(set-goals g1 g2 g3)" => {self.lc.set_goals(args); LispVal::undef()},
+  SetCloseFn: AtLeast(0) "(set-close-fn f)" => {
     let e = args.drain(..).next().unwrap_or_default();
     if e.is_def() && !e.is_proc() {try1!(Err("expected a procedure"))}
     self.lc.closer = e;
     LispVal::undef()
   },
-  LocalCtx: Exact(0) =>
+  LocalCtx: Exact(0) "(local-ctx)" =>
     LispVal::list(self.lc.proof_order.iter().map(|a| LispVal::atom(a.0)).collect::<Vec<_>>()),
-  ToExpr: Exact(1) => return Ok(State::Refine {
+  Assumption: AtLeast(0) "(assumption)" => {
+    let mut unfold = vec![];
+    for e in &args {
+      let a = try1!(e.as_atom().ok_or("expected an atom"));
+      unfold.push(try1!(self.term(a).ok_or_else(|| format!("unknown definition '{}'", self.print(&a)))));
+    }
+    let tgt = try1!(self.lc.goals.iter().find_map(|g| g.goal_type()).ok_or("no goals"));
+    let h = try1!(self.find_assumption(&unfold, &tgt).ok_or_else(|| format!(
+      "no hypothesis matches the goal {}", self.format_env().pp(&tgt, self.pp.width))));
+    return Ok(State::Refine {
+      sp: sp1, stack: vec![],
+      state: RState::Goals {
+        gs: mem::take(&mut self.lc.goals).into_iter(),
+        es: vec![LispVal::atom(h)].into_iter()
+      }
+    })
+  },
+  AddHint: AtLeast(0) "(add-hint x1 x2 ...)" => {
+    for e in &args {
+      let a = try1!(e.as_atom().ok_or("expected an atom"));
+      let t = try1!(self.thm(a).ok_or_else(|| format!("unknown theorem '{}'", self.print(&a))));
+      self.add_hint(t)
+    }
+    LispVal::undef()
+  },
+  Auto: AtLeast(0) "(auto)" => {
+    if args.len() > 2 {try1!(Err("expected at most 2 arguments"))}
+    let depth = match args.get(0) {
+      None => 5,
+      Some(e) => try1!(e.as_int(|n| n.to_usize()).flatten().ok_or("expected a number")),
+    };
+    let ms = match args.get(1) {
+      None => 1000,
+      Some(e) => try1!(e.as_int(|n| n.to_u64()).flatten().ok_or("expected a number")),
+    };
+    let tgt = try1!(self.lc.goals.iter().find_map(|g| g.goal_type()).ok_or("no goals"));
+    let script = try1!(self.auto(sp1, &tgt, depth, Instant::now() + Duration::from_millis(ms))
+      .ok_or_else(|| format!("auto failed to prove {}", self.format_env().pp(&tgt, self.pp.width))));
+    let text = format!("(refine '{})", self.print(&script));
+    self.suggest(sp1, &text);
+    return Ok(State::Refine {
+      sp: sp1, stack: vec![],
+      state: RState::Goals {
+        gs: mem::take(&mut self.lc.goals).into_iter(),
+        es: vec![script].into_iter()
+      }
+    })
+  },
+  GoalTarget: Exact(1) "(goal-target g)" => try1!(args[0].goal_type().ok_or("expected a goal")),
+  GoalHyps: Exact(1) "(goal-hyps g)" => {
+    try1!(args[0].goal_type().ok_or("expected a goal"));
+    LispVal::list(self.lc.proof_order.iter().enumerate()
+      .filter(|&(i, (a, _, _))| self.lc.proofs.get(a) == Some(&i))
+      .map(|(_, (a, e, _))| LispVal::list(vec![LispVal::atom(*a), e.clone()]))
+      .collect::<Vec<_>>())
+  },
+  HypName: Exact(1) "(hyp-name h)" => {
+    let mut u = Uncons::from(args[0].clone());
+    try1!(u.next().filter(|a| a.is_atom() && u.exactly(1)).ok_or("expected a hypothesis"))
+  },
+  HypType: Exact(1) "(hyp-type h)" => match args[0].as_atom() {
+    Some(a) => try1!(self.lc.get_proof(a).map(|p| p.1.clone())
+      .ok_or_else(|| format!("unknown hypothesis '{}'", self.print(&a)))),
+    None => {
+      let mut u = Uncons::from(args[0].clone());
+      try1!(u.nth(1).filter(|_| u.exactly(0)).ok_or("expected a hypothesis"))
+    }
+  },
+  ToExpr: Exact(1) "(to-expr e)" => return Ok(State::Refine {
     sp: sp1, stack: vec![RStack::DeferGoals(mem::take(&mut self.lc.goals))],
     state: RState::RefineExpr {tgt: InferTarget::Unknown, e: args.swap_remove(0)}
   }),
-  Refine: AtLeast(0) => return Ok(State::Refine {
+  Refine: AtLeast(0) "(refine p)" => return Ok(State::Refine {
     sp: sp1, stack: vec![],
     state: RState::Goals {
       gs: mem::take(&mut self.lc.goals).into_iter(),
       es: args.into_iter()
     }
   }),
-  Have: AtLeast(2) => {
+  Have: AtLeast(2) "(have h p)" => {
     if args.len() > 3 {try1!(Err("invalid arguments"))}
     let mut args = args.drain(..);
     let xarg = args.next().unwrap();
@@ -1269,12 +1637,162 @@ make_builtins! { self, sp1, sp2, args,
     };
     return Ok(State::Refine {sp: sp1, stack, state})
   },
-  Stat: Exact(0) => {print!(sp1, self.stat()); LispVal::undef()},
-  GetDecl: Exact(1) => {
+  Suffices: Exact(2) "(suffices h e)" => {
+    let a = try1!(args[0].as_atom().ok_or("expected an atom"));
+    self.stack.push(Stack::Suffices(sp1, self.lc.goals.len()));
+    self.stack.push(Stack::Have(sp1, args[0].clone(), a));
+    let p = LispVal::atom(AtomId::UNDER).span(self.fspan(sp1));
+    return Ok(State::Refine {
+      sp: sp1,
+      stack: vec![RStack::DeferGoals(mem::take(&mut self.lc.goals)), RStack::Typed(p)],
+      state: RState::RefineExpr {tgt: InferTarget::Unknown, e: args.swap_remove(1)}
+    })
+  },
+  AdmitStep: Exact(2) "(admit-step h e)" => {
+    let a = try1!(args[0].as_atom().ok_or("expected an atom"));
+    self.stack.push(Stack::Have(sp1, args[0].clone(), a));
+    let p = LispVal::atom(AtomId::SORRY).span(self.fspan(sp1));
+    return Ok(State::Refine {
+      sp: sp1,
+      stack: vec![RStack::DeferGoals(mem::take(&mut self.lc.goals)), RStack::Typed(p)],
+      state: RState::RefineExpr {tgt: InferTarget::Unknown, e: args.swap_remove(1)}
+    })
+  },
+  Stat: Exact(0) "(stat)" => {print!(sp1, self.stat()); LispVal::undef()},
+  DebugDump: AtLeast(1) "(debug-dump 'context)" => {
+    let kind = args[0].as_atom().map(|a| self.data[a].name.clone());
+    let s = match (kind.as_deref(), args.get(1)) {
+      (Some(b"context"), None) => crate::debug_dump::context(self.format_env(), &self.lc),
+      (Some(b"globals"), Some(arg)) => {
+        let s = try1!(self.as_string(arg));
+        crate::debug_dump::globals(self.format_env(), &String::from_utf8_lossy(&s))
+      }
+      (Some(b"decl"), Some(arg)) => {
+        let x = try1!(self.as_string_atom(arg).ok_or("expected an atom"));
+        let name = self.data[x].name.clone();
+        try1!(crate::debug_dump::decl(self.format_env(), name.as_str())
+          .ok_or_else(|| format!("unknown declaration '{}'", name)))
+      }
+      _ => try1!(Err("expected 'context, 'globals \"prefix\", or 'decl 'name"))
+    };
+    LispVal::string(s.into())
+  },
+  Sorry: Exact(0) "(sorry)" => {
+    let p = LispVal::atom(AtomId::SORRY).span(self.fspan(sp1));
+    for g in mem::take(&mut self.lc.goals) {
+      g.as_ref_mut(|e| *e = p.clone());
+    }
+    self.lc.sorries += 1;
+    LispVal::undef()
+  },
+  GetDecl: Exact(1) "(get-decl x)" => {
     let x = try1!(args[0].as_atom().ok_or("expected an atom"));
     self.get_decl(args[0].fspan(), x)
   },
-  AddDecl: AtLeast(4) => {
+  TheoremsAbout: Exact(1) "(theorems-about x)" => {
+    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let t = try1!(self.term(x).ok_or_else(|| format!("unknown term '{}'", self.print(&x))));
+    LispVal::list(self.theorems_about(t).into_iter().map(|(head, thms)| {
+      let head = head.map_or(AtomId::UNDER, |h| self.terms[h].atom);
+      LispVal::list(Some(LispVal::atom(head)).into_iter()
+        .chain(thms.into_iter().map(|t| LispVal::atom(self.thms[t].atom)))
+        .collect::<Vec<_>>())
+    }).collect::<Vec<_>>())
+  },
+  Search: Exact(1) "(search e)" => {
+    let mut keys = vec![];
+    flatten_lisp(&self.env, &args[0], &mut keys);
+    LispVal::list(self.thm_index.unifiable(&keys).into_iter()
+      .map(|t| LispVal::atom(self.thms[t].atom)).collect::<Vec<_>>())
+  },
+  ConvRefl: Exact(1) "(conv-refl e)" => {
+    let (l, r) = try1!(self.conv_sides(&args[0]));
+    if l != args[0] || r != args[0] { try1!(Err("expected an expression")) }
+    l
+  },
+  ConvSym: Exact(1) "(conv-sym c)" => try1!(self.conv_sym(&args[0])),
+  ConvTrans: AtLeast(1) "(conv-trans c1 ... cn)" => {
+    let mut it = args.iter();
+    let mut c = it.next().expect("nonempty").clone();
+    try1!(self.conv_sides(&c));
+    for c2 in it { c = try1!(self.conv_trans(&c, c2)) }
+    c
+  },
+  ConvCong: AtLeast(1) "(conv-cong t c1 ... cn)" => {
+    let t = try1!(args[0].as_atom().ok_or("expected an atom"));
+    try1!(self.conv_cong(t, args[1..].to_vec()))
+  },
+  ConvUnfold: Exact(1) "(conv-unfold e)" => try1!(self.conv_unfold(&args[0])),
+  ConvAtPath: Exact(3) "(conv-at-path e path c)" => {
+    let path = try1!(parse_path(&args[1]));
+    try1!(self.conv_at_path(&args[0], &path, &args[2]))
+  },
+  ConvSides: Exact(1) "(conv-sides c)" => {
+    let (l, r) = try1!(self.conv_sides(&args[0]));
+    LispVal::list(vec![l, r])
+  },
+  ExprLt: Exact(2) "(expr< e1 e2)" => LispVal::bool(self.expr_cmp(&args[0], &args[1]).is_lt()),
+  Rw: AtLeast(1) "(rw r)" => {
+    let path = try1!(parse_rw_path(&args[1..]));
+    if args[0].as_atom().map_or(false, |a| self.thm(a).is_some()) {
+      let f = try1!(self.data[AtomId::RW_THM].lisp.as_ref().map(|ld| ld.val.clone())
+        .ok_or("rw: rewriting with a theorem requires a definition of 'rw-thm'"));
+      let path = path.map_or_else(LispVal::undef, |p|
+        LispVal::list(p.into_iter().map(|i| LispVal::number(i.into())).collect::<Vec<_>>()));
+      return Ok(State::App(sp1, sp2, f, vec![args[0].clone(), path], [].iter()))
+    }
+    let rule = try1!(self.rw_rule(&args[0]));
+    let i = try1!(self.lc.goals.iter().position(|g| g.goal_type().is_some()).ok_or("no goals"));
+    let tgt = self.lc.goals[i].goal_type().expect("is a goal");
+    let c = try1!(self.conv_rewrite(sp1, &tgt, &rule, path.as_deref()));
+    let (_, tgt2) = try1!(self.conv_sides(&c));
+    let g = LispVal::new_ref(LispVal::goal(self.fspan(sp1), tgt2));
+    let old = mem::replace(&mut self.lc.goals[i], g.clone());
+    old.as_ref_mut(|e| *e = LispVal::conv(tgt, c, g.clone()));
+    self.goal_created(sp1, &g);
+    LispVal::undef()
+  },
+  RwIn: AtLeast(2) "(rw-in h r)" => {
+    let h = try1!(args[0].as_atom().ok_or("expected a hypothesis name"));
+    let path = try1!(parse_rw_path(&args[2..]));
+    let rule = try1!(self.rw_rule(&args[1]));
+    let p = try1!(self.rewrite_hyp(sp1, h, &rule, path.as_deref()));
+    LispVal::list(vec![LispVal::atom(AtomId::VERB), p])
+  },
+  GetProof: Exact(1) "(get-proof x)" => {
+    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let t = try1!(self.thm(x).ok_or_else(|| format!("unknown theorem '{}'", self.print(&x))));
+    let td = &self.thms[t];
+    if !matches!(td.kind, ThmKind::Thm(Some(_))) {
+      try1!(Err(format!("theorem '{}' has no proof", self.print(&x))))
+    }
+    LispVal::new(LispKind::Proof(Rc::new(td.clone())))
+  },
+  IsProof: Exact(1) "(proof? e)" => LispVal::bool(args[0].unwrapped(|e| matches!(e, LispKind::Proof(_)))),
+  ProofStatement: Exact(1) "(proof-statement p)" => {
+    let td = try1!(args[0].as_proof().ok_or("expected a proof object"));
+    let (bis, hyps, ret, _) = self.thm_statement(&td);
+    LispVal::list(vec![bis, hyps, ret])
+  },
+  ProofTerm: Exact(1) "(proof-term p)" => {
+    let td = try1!(args[0].as_proof().ok_or("expected a proof object"));
+    let (_, _, _, mut heap) = self.thm_statement(&td);
+    heap.truncate(td.args.len());
+    self.get_proof(&td, heap)
+  },
+  ProofSize: Exact(1) "(proof-size p)" => {
+    let td = try1!(args[0].as_proof().ok_or("expected a proof object"));
+    let pf = if let ThmKind::Thm(Some(pf)) = &td.kind { pf } else { try1!(Err("theorem has no proof")) };
+    LispVal::number(crate::elab::minimize::proof_size(pf).into())
+  },
+  ProofThmsUsed: Exact(1) "(proof-thms-used p)" => {
+    let td = try1!(args[0].as_proof().ok_or("expected a proof object"));
+    let pf = if let ThmKind::Thm(Some(pf)) = &td.kind { pf } else { try1!(Err("theorem has no proof")) };
+    let mut thms = vec![];
+    for p in pf.heap.iter().chain(Some(&pf.head)) { p.thms_used(&mut thms) }
+    LispVal::list(thms.into_iter().map(|t| LispVal::atom(self.thms[t].atom)).collect::<Vec<_>>())
+  },
+  AddDecl: AtLeast(4) "(add-decl! decl-data ...)" => {
     let fsp = self.fspan_base(sp1);
     match try1!(args[0].as_atom().ok_or("expected an atom")) {
       AtomId::TERM | AtomId::DEF => self.add_term(&fsp, &args[1..])?,
@@ -1283,16 +1801,36 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::undef()
   },
-  AddTerm: AtLeast(3) => {
+  AddTerm: AtLeast(3) "(add-term! x bis ret)" => {
     let fsp = self.fspan_base(sp1);
     self.add_term(&fsp, &args)?;
     LispVal::undef()
   },
-  AddThm: AtLeast(4) => {
+  AddThm: AtLeast(4) "(add-thm! x bis hyps ret)" => {
     let fsp = self.fspan_base(sp1);
     return self.add_thm(fsp, &args)
   },
-  NewDummy: AtLeast(1) => {
+  AddTransfer: Exact(2) "(add-transfer x y)" => {
+    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let y = try1!(args[1].as_atom().ok_or("expected an atom"));
+    try1!(self.add_transfer(x, y));
+    LispVal::undef()
+  },
+  Transfer: Exact(2) "(transfer x y)" => {
+    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let y = try1!(args[1].as_atom().ok_or("expected an atom"));
+    let t = try1!(self.thm(x).ok_or_else(|| format!("unknown theorem '{}'", self.print(&x))));
+    if self.data[y].decl.is_some() {
+      try1!(Err(format!("duplicate axiom/theorem declaration '{}'", self.print(&y))))
+    }
+    let fsp = self.fspan_base(sp1);
+    let span = args[1].fspan().unwrap_or_else(|| fsp.clone());
+    let thm = try1!(self.transfer_thm(t, y, span, fsp.span));
+    let id = self.elab.env.add_thm(thm).map_err(|e| e.into_elab_error(sp1))?;
+    self.elab.env.transfer.thms.insert(t, id);
+    LispVal::undef()
+  },
+  NewDummy: AtLeast(1) "(dummy! x s)" => {
     if args.len() > 2 {try1!(Err("expected 1 or 2 armuments"))}
     let (x, s) = match args.get(1) {
       None => {
@@ -1310,33 +1848,169 @@ make_builtins! { self, sp1, sp2, args,
     self.lc.vars.insert(x, (true, InferSort::Bound(sort)));
     LispVal::atom(x)
   },
-  SetReporting: AtLeast(1) => {
+  SetReporting: AtLeast(1) "(set-reporting type b)" => {
     let fe = FormatEnv {source: &self.elab.ast.source, env: &self.elab.env};
     try1!(set_report_mode(fe, &mut self.elab.reporting, &args));
     LispVal::undef()
   },
-  SetBacktrace: AtLeast(1) => {
+  SetBacktrace: AtLeast(1) "(set-backtrace b)" => {
     let fe = FormatEnv {source: &self.elab.ast.source, env: &self.elab.env};
     try1!(set_report_mode(fe, &mut self.elab.backtrace, &args));
     LispVal::undef()
   },
-  CheckProofs: Exact(1) => {
+  SetRwTrace: Exact(1) "(set-rw-trace b)" => {
+    self.rw_trace = try1!(args[0].as_bool().ok_or("expected a boolean"));
+    LispVal::undef()
+  },
+  SetOption: Exact(2) "(set-option 'key v)" => {
+    let k = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let k = self.data[k].name.clone();
+    try1!(self.elab.set_option(&k, &args[1]));
+    LispVal::undef()
+  },
+  SetDepPolicy: Exact(1) "(set-dep-policy p)" => {
+    let p = try1!(args[0].as_atom().ok_or("expected an atom"));
+    self.dep_policy = try1!(self.data[p].name.as_str().parse());
+    LispVal::undef()
+  },
+  CheckProofs: Exact(1) "(check-proofs b)" => {
     if let Some(b) = args[0].as_bool() {
       self.check_proofs = b;
     } else {try1!(Err("invalid arguments"))}
     LispVal::undef()
   },
-  RefineExtraArgs: AtLeast(2) => {
-    if args.len() > 2 {try1!(Err("too many arguments"))}
-    args.into_iter().nth(1).unwrap()
+  RefineExtraArgs: AtLeast(2) "(refine-extra-args refine tgt e a1 a2 ...)" => {
+    if args.len() == 2 { args.into_iter().nth(1).unwrap() } else {
+      let t = args[2].head().unwrap_or_else(|| args[2].clone()).as_atom().and_then(|a| self.thm(a));
+      match t.and_then(|t| self.extra_args.get(&t)) {
+        Some(f) => {
+          let f = f.clone();
+          return Ok(State::App(sp1, sp2, f, args, [].iter()))
+        }
+        None => try1!(Err(match t {
+          Some(t) => format!("too many arguments to '{}'", self.print(&self.thms[t].atom)),
+          None => "too many arguments".into()
+        }))
+      }
+    }
+  },
+  SetExtraArgsHandler: Exact(2) "(set-extra-args-handler x f)" => {
+    let a = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let t = try1!(self.thm(a).ok_or_else(|| format!("unknown theorem '{}'", self.print(&a))));
+    if !args[1].is_def() {
+      self.extra_args.remove(&t);
+    } else if args[1].is_proc() {
+      self.extra_args.insert(t, args[1].clone());
+    } else {try1!(Err("expected a procedure"))}
+    LispVal::undef()
   },
-  EvalString: AtLeast(0) => {
+  EvalString: AtLeast(0) "(eval-string e1 e2 ...)" => {
     let fsp = self.fspan(sp1);
     let bytes = self.eval_string(&fsp, &args)?;
     LispVal::string(bytes.into())
   },
+  BytesToTerm: Exact(1) "(bytes->term s)" => {
+    let s = try1!(self.as_string(&args[0]));
+    self.bytes_to_term(sp1, &s)?
+  },
+  HexToBytes: Exact(1) "(hex->bytes s)" => {
+    let s = try1!(self.as_string(&args[0]));
+    let digits = try1!(s.iter().filter(|c| !c.is_ascii_whitespace() && **c != b'_')
+      .map(|&c| char::from(c).to_digit(16).ok_or_else(|| format!("invalid hex digit '{}'", char::from(c))))
+      .collect::<Result<Vec<_>, _>>());
+    if digits.len() % 2 != 0 { try1!(Err("odd number of hex digits")) }
+    #[allow(clippy::cast_possible_truncation)]
+    let bytes = digits.chunks(2).map(|p| (p[0] << 4 | p[1]) as u8).collect::<Vec<_>>();
+    LispVal::string(bytes.into())
+  },
+  HexDump: Exact(1) "(hexdump s)" => {
+    use std::fmt::Write;
+    let s = try1!(self.as_string(&args[0]));
+    let mut out = String::new();
+    for (i, line) in s.chunks(16).enumerate() {
+      write!(out, "{:08x}:", i * 16).expect("writing to a string");
+      for b in line { write!(out, " {:02x}", b).expect("writing to a string") }
+      let pad = 3 * (16 - line.len());
+      let text = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' {char::from(b)} else {'.'});
+      writeln!(out, "{:pad$}  |{}|", "", text.collect::<String>(), pad = pad).expect("writing to a string");
+    }
+    LispVal::string(out.into_bytes().into())
+  },
+  AlignName: Exact(2) "(align-name 'foo 'bar)" => {
+    let from = try1!(self.as_string_atom(&args[0]).ok_or("expected an atom"));
+    let to = try1!(self.as_string_atom(&args[1]).ok_or("expected an atom"));
+    let (from, to) = (self.data[from].name.clone(), self.data[to].name.clone());
+    Arc::make_mut(&mut self.align).insert(from, to);
+    LispVal::undef()
+  },
+  AddVirtualFile: Exact(2) "(add-virtual-file name text)" => {
+    let name = try1!(self.as_string(&args[0]));
+    let text = try1!(self.as_string(&args[1]));
+    let name = try1!(std::str::from_utf8(&name).map_err(|e| e.to_string()));
+    let text = try1!(std::str::from_utf8(&text).map_err(|e| e.to_string()));
+    try1!(crate::add_scoped_virtual_file(self.path.clone(),
+      crate::imports::resolve_path(&self.path, name), text));
+    LispVal::undef()
+  },
+  SetNumeral: Exact(3) "(set-numeral s zero (d0 d1 ... dn))" => {
+    let num = try1!((|| -> SResult<_> {
+      let s = args[0].as_atom().and_then(|a| self.data[a].sort).ok_or("expected a sort")?;
+      let term = |e: &LispVal, nargs: usize| -> SResult<TermId> {
+        let t = e.as_atom().and_then(|a| self.term(a))
+          .ok_or_else(|| format!("expected a term constructor, got {}", self.print(e)))?;
+        let td = &self.terms[t];
+        if td.ret.0 == s && td.args.len() == nargs &&
+          td.args.iter().all(|&(_, ty)| ty == Type::Reg(s, 0)) { return Ok(t) }
+        let s = &self.sorts[s].name;
+        Err(format!("'{}' should be a term of type {}{}", self.data[td.atom].name,
+          if nargs == 0 { String::new() } else { format!("{} > ", s) }, s))
+      };
+      let zero = term(&args[1], 0)?;
+      let digits = Uncons::from(args[2].clone()).map(|e| term(&e, 1)).collect::<SResult<Box<[_]>>>()?;
+      if digits.is_empty() { return Err("expected at least one digit".into()) }
+      Ok((s, Numeral {zero, digits}))
+    })());
+    self.pe.numerals.insert(num.0, num.1);
+    LispVal::undef()
+  },
+  SetString: Exact(3) "(set-string s nil cons)" => {
+    let res = try1!((|| -> SResult<_> {
+      let s = args[0].as_atom().and_then(|a| self.data[a].sort).ok_or("expected a sort")?;
+      let term = |e: &LispVal| e.as_atom().and_then(|a| self.term(a))
+        .ok_or_else(|| format!("expected a term constructor, got {}", self.print(e)));
+      let (nil, cons) = (term(&args[1])?, term(&args[2])?);
+      let (nd, cd) = (&self.terms[nil], &self.terms[cons]);
+      if nd.ret.0 != s || !nd.args.is_empty() {
+        return Err(format!("'{}' should be a term of type {}",
+          self.data[nd.atom].name, self.sorts[s].name))
+      }
+      match *cd.args {
+        [(_, Type::Reg(_, 0)), (_, ty)] if cd.ret.0 == s && ty == Type::Reg(s, 0) => {}
+        _ => return Err(format!("'{}' should be a term of type char > {1} > {1}",
+          self.data[cd.atom].name, self.sorts[s].name))
+      }
+      Ok((s, StringLit {nil, cons}))
+    })());
+    self.pe.strings.insert(res.0, res.1);
+    LispVal::undef()
+  },
+  SetImplicit: Exact(2) "(set-implicit t xs)" => {
+    let t = try1!(args[0].as_atom().and_then(|a| self.term(a)).ok_or("expected a term"));
+    let mut imp = vec![false; self.terms[t].args.len()].into_boxed_slice();
+    for e in Uncons::from(args[1].clone()) {
+      let i = try1!(match e.as_atom() {
+        Some(a) => self.terms[t].args.iter().position(|&(x, _)| x == Some(a))
+          .ok_or_else(|| format!("'{}' is not an argument of the term", self.print(&a))),
+        None => e.as_int(|n| n.to_usize()).flatten().filter(|&i| i < imp.len())
+          .ok_or_else(|| "expected an argument name or index".into()),
+      });
+      imp[i] = true
+    }
+    if imp.contains(&true) { self.pe.implicits.insert(t, imp); } else { self.pe.implicits.remove(&t); }
+    LispVal::undef()
+  },
   #[cfg(feature = "mmc")]
-  MmcInit: Exact(0) => LispVal::proc(Proc::MmcCompiler(
+  MmcInit: Exact(0) "(mmc-init)" => LispVal::proc(Proc::MmcCompiler(
     RefCell::new(Box::new(crate::mmc::Compiler::new(self)))
   )),
 }
@@ -1351,9 +2025,17 @@ impl<'a> Evaluator<'a> {
     try_get_span_from(&orig, fsp)
   }
 
-  #[allow(unused)]
   fn respan(&self, sp: Span) -> Span { self.try_get_span(Some(&self.fspan(sp))) }
 
+  /// Returns true if the match continuation with validity flag `valid` may still be called
+  /// after a tail call, because the continuation has been copied out of the context
+  /// (for example, passed as an argument or captured in a closure).
+  fn cont_escapes(&self, valid: &Rc<Cell<bool>>) -> bool {
+    self.ctx.iter().rev().find(|e| matches!(***e,
+      LispKind::Proc(Proc::MatchCont(ref v)) if Rc::ptr_eq(v, valid)
+    )).map_or(true, |e| !e.is_unique())
+  }
+
   fn proc_pos(&self, sp: Span) -> ProcPos {
     if let Some(Stack::Def(Some(&Some((sp1, sp2, _, x))))) = self.stack.last() {
       ProcPos::Named(self.fspan(sp2), sp1, x)
@@ -1362,8 +2044,52 @@ impl<'a> Evaluator<'a> {
     }
   }
 
-  #[allow(clippy::never_loop)]
   fn run(&mut self, mut active: State<'a>) -> Result<LispVal> {
+    loop {
+      match self.run_uncaught(active) {
+        Ok(ret) => return Ok(ret),
+        Err(e) => active = self.catch(e)?,
+      }
+    }
+  }
+
+  /// Unwind the stack to the innermost `try`, restore its goals, and call its handler with
+  /// the error object for `e`. Timeouts, stack overflows and cancellation are not caught,
+  /// and `e` is returned if there is no enclosing `try`.
+  fn catch(&mut self, e: ElabError) -> Result<State<'a>> {
+    let value = self.err_value.take();
+    if matches!(e.code, Some(ErrorCode::Timeout | ErrorCode::StackOverflow)) ||
+      self.cancel.load(Ordering::Relaxed) ||
+      !self.stack.iter().any(|s| matches!(s, Stack::Catch(..))) {
+      return Err(e)
+    }
+    loop {
+      match self.stack.pop() {
+        Some(Stack::Catch(sp, handler, goals)) => {
+          self.lc.goals = goals;
+          let kind = match e.code {
+            Some(code) => self.get_atom(code.name().as_bytes()),
+            None => AtomId::ERROR,
+          };
+          let mut map = HashMap::new();
+          map.insert(self.get_atom(b"kind"), LispVal::atom(kind));
+          map.insert(self.get_atom(b"msg"),
+            LispVal::string(e.kind.msg().into()).span(self.elab.fspan(e.pos)));
+          if let Some(v) = value { map.insert(self.get_atom(b"value"), v); }
+          let err = LispVal::new(LispKind::AtomMap(map));
+          return Ok(State::App(sp, sp, handler, vec![err], [].iter()))
+        }
+        Some(Stack::Drop(n)) => self.ctx.truncate(n),
+        Some(Stack::Ret(fsp, _, old)) => {self.file = fsp.file; self.ctx = old}
+        Some(Stack::MatchCont(_, _, _, valid)) => valid.set(false),
+        Some(_) => {}
+        None => unreachable!("checked above"),
+      }
+    }
+  }
+
+  #[allow(clippy::never_loop)]
+  fn run_uncaught(&mut self, mut active: State<'a>) -> Result<LispVal> {
     macro_rules! throw {($sp:expr, $e:expr) => {{
       let err = $e;
       return Err(self.err(Some(($sp, false)), err))
@@ -1379,14 +2105,14 @@ impl<'a> Evaluator<'a> {
       iters = iters.wrapping_add(1);
       if iters == 0 {
         if self.cur_timeout.map_or(false, |t| t < Instant::now()) {
-          return Err(self.err(None, "timeout"))
+          return Err(self.err(None, "timeout").with_code(ErrorCode::Timeout))
         }
         if self.cancel.load(Ordering::Relaxed) {
           return Err(self.err(None, "cancelled"))
         }
       }
       if self.stack.len() >= self.stack_limit {
-        return Err(self.err(None, "stack overflow"))
+        return Err(self.err(None, "stack overflow").with_code(ErrorCode::StackOverflow))
       }
       // if self.check_proofs {
       //   if self.stack.len() < stacklen {
@@ -1406,19 +2132,28 @@ impl<'a> Evaluator<'a> {
       active = match active {
         State::Eval(ir) => match ir {
           &Ir::Local(i) => State::Ret(self.ctx[i].clone()),
-          &Ir::Global(sp, a) => State::Ret(match &self.data[a] {
-            AtomData {name, lisp: None, ..} => match BuiltinProc::from_bytes(name) {
-              None => throw!(sp, format!("Reference to unbound variable '{}'", name)),
-              Some(p) => {
-                let s = name.clone();
-                let a = self.get_atom(&s);
-                let ret = LispVal::proc(Proc::Builtin(p));
-                self.data[a].lisp = Some(LispData {src: None, doc: None, val: ret.clone(), merge: None});
-                ret
-              }
-            },
-            AtomData {lisp: Some(x), ..} => x.val.clone(),
-          }),
+          &Ir::Global(sp, a) => {
+            self.read_global(a);
+            State::Ret(match &self.data[a] {
+              AtomData {name, lisp: None, ..} => match BuiltinProc::from_bytes(name) {
+                None => {
+                  let mut builtins = vec![];
+                  BuiltinProc::for_each(|_, s| builtins.push(s.as_bytes()));
+                  let globals = self.data.0.iter().filter(|ad| ad.lisp.is_some()).map(|ad| &*ad.name);
+                  throw!(sp, format!("Reference to unbound variable '{}'{}", name,
+                    did_you_mean(name, globals.chain(builtins))))
+                }
+                Some(p) => {
+                  let s = name.clone();
+                  let a = self.get_atom(&s);
+                  let ret = LispVal::proc(Proc::Builtin(p));
+                  self.data[a].lisp = Some(LispData {src: None, doc: None, val: ret.clone(), merge: None});
+                  ret
+                }
+              },
+              AtomData {lisp: Some(x), ..} => x.val.clone(),
+            })
+          }
           Ir::Const(val) => State::Ret(val.clone()),
           Ir::List(sp, ls) => State::List(*sp, vec![], ls.iter()),
           Ir::DottedList(ls, e) => State::DottedList(vec![], ls.iter(), e),
@@ -1497,6 +2232,9 @@ impl<'a> Evaluator<'a> {
             }
           } else if let Some(&Some((sp1, sp2, ref doc, a))) = x {
             let loc = (self.fspan(sp2), sp1);
+            let simple = ret.is_def_strict() &&
+              self.data[a].lisp.as_ref().map_or(true, |ld| ld.merge.is_none());
+            self.do_cache.define(a, simple);
             let lisp = &mut self.data[a].lisp;
             if let Some(LispData {merge: strat @ Some(_), val, ..}) = lisp {
               let (strat, old) = (strat.clone(), val.clone());
@@ -1506,6 +2244,13 @@ impl<'a> Evaluator<'a> {
               if ret.is_def_strict() {
                 let e = mem::replace(&mut self.data[a].lisp,
                   Some(LispData {src: Some(loc), doc: doc.clone(), val: ret, merge: None}));
+                if e.as_ref().map_or(true, |e| e.src.is_none()) &&
+                  BuiltinProc::from_bytes(&self.data[a].name).map_or(false, |p| !p.is_overridable()) {
+                  let msg = format!("'{}' shadows a builtin; use (builtin '{0}) to access the original",
+                    self.data[a].name);
+                  let sp = self.respan(sp2);
+                  self.report(ElabError::warn(sp, msg).with_code(ErrorCode::ShadowedBuiltin))
+                }
                 if e.is_none() {
                   self.stmts.push(StmtTrace::Global(a))
                 }
@@ -1531,12 +2276,13 @@ impl<'a> Evaluator<'a> {
           Some(Stack::TestPattern(sp, e, it, br, pstack, vars)) =>
             State::Pattern(sp, e, it, br, pstack, vars, PatternState::Ret(ret.truthy())),
           Some(Stack::Drop(n)) => {self.ctx.truncate(n); State::Ret(ret)}
-          Some(Stack::Ret(fsp, _, old, _)) => {self.file = fsp.file; self.ctx = old; State::Ret(ret)}
+          Some(Stack::Ret(fsp, _, old)) => {self.file = fsp.file; self.ctx = old; State::Ret(ret)}
           Some(Stack::MatchCont(_, _, _, valid)) => {
             if let Err(valid) = Rc::try_unwrap(valid) {valid.set(false)}
             State::Ret(ret)
           }
           Some(Stack::SetMergeStrategy(sp1, a)) => {
+            self.do_cache.define(a, false);
             if let Some(ref mut data) = self.data[a].lisp {
               data.merge = ret.into_merge_strategy()
             } else {
@@ -1573,7 +2319,7 @@ impl<'a> Evaluator<'a> {
                 let span = self.fspan(sp);
                 for g in mem::take(&mut self.lc.goals) {
                   let err = ElabError::new_e(try_get_span(&span, &g),
-                    format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), 80)));
+                    format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), self.pp.width)));
                   self.report(err)
                 }
                 throw!(sp, format!("focused goal has not been solved\n\n{}", stat))
@@ -1593,6 +2339,15 @@ impl<'a> Evaluator<'a> {
             }
             State::Ret(LispVal::undef())
           },
+          Some(Stack::Suffices(sp, n)) => {
+            let len = self.lc.goals.len();
+            if len < n {
+              throw!(sp, format!("suffices: expected at least {} goals after the proof, found {}", n, len))
+            }
+            self.lc.goals.rotate_left(len - n);
+            State::Ret(ret)
+          },
+          Some(Stack::Catch(..)) => State::Ret(ret),
         },
         State::Evals(e, mut it) => match it.next() {
           None => State::Eval(e),
@@ -1608,37 +2363,49 @@ impl<'a> Evaluator<'a> {
         },
         State::App(sp1, sp2, func, mut args, mut it) => match it.next() {
           Some(e) => push!(App2(sp1, sp2, func, args, it); Eval(e)),
-          None => func.unwrapped(|func| {
-            let func = if let LispKind::Proc(f) = func { f }
+          None => func.unwrapped(|fk| {
+            let proc = &func;
+            let func = if let LispKind::Proc(f) = fk { f }
             else { throw!(sp1, "not a function, cannot apply") };
             let spec = func.spec();
             if !spec.valid(args.len()) {
-              match spec {
-                ProcSpec::Exact(n) => throw!(sp1, format!("expected {} argument(s)", n)),
-                ProcSpec::AtLeast(n) => throw!(sp1, format!("expected at least {} argument(s)", n)),
-              }
+              let msg = match spec {
+                ProcSpec::Exact(n) => format!("expected {} argument(s)", n),
+                ProcSpec::AtLeast(n) => format!("expected at least {} argument(s)", n),
+              };
+              self.err_value = Some(proc.clone());
+              return Err(self.err(Some((sp1, false)), msg).with_code(ErrorCode::WrongArity))
             }
             Ok(match func {
               &Proc::Builtin(func) => self.evaluate_builtin(sp1, sp2, func, args)?,
               Proc::Lambda {pos, env, code, ..} => {
+                // A call is in tail position if the only frames above the nearest return
+                // are context drops and match continuations that can no longer be called.
                 let tail_call = (|| {
                   for (i, s) in self.stack.iter().enumerate().rev() {
                     match s {
                       Stack::Ret(..) => return Some(i),
                       Stack::Drop(_) => {}
+                      Stack::MatchCont(_, _, _, valid) if !self.cont_escapes(valid) => {}
                       _ => break
                     }
                   }
                   None
                 })();
                 if let Some(i) = tail_call { // tail call
-                  let s = self.stack.drain(i..).next();
-                  let_unchecked!((fsp, old) as Some(Stack::Ret(fsp, _, old, _)) = s);
+                  let mut frames = self.stack.drain(i..);
+                  let (fsp, old) = match frames.next() {
+                    Some(Stack::Ret(fsp, _, old)) => (fsp, old),
+                    _ => unreachable!("tail_call points at a return frame"),
+                  };
+                  for s in frames {
+                    if let Stack::MatchCont(_, _, _, valid) = s { valid.set(false) }
+                  }
                   self.ctx = (**env).into();
-                  self.stack.push(Stack::Ret(fsp, pos.clone(), old, code.clone()));
+                  self.stack.push(Stack::Ret(fsp, pos.clone(), old));
                 } else {
                   self.stack.push(Stack::Ret(self.fspan(sp1), pos.clone(),
-                    mem::replace(&mut self.ctx, (**env).into()), code.clone()));
+                    mem::replace(&mut self.ctx, (**env).into())));
                 }
                 self.file = pos.fspan().file.clone();
                 self.stack.push(Stack::Drop(self.ctx.len()));
@@ -1649,16 +2416,7 @@ impl<'a> Evaluator<'a> {
                     self.ctx.push(LispVal::list(args));
                   }
                 }
-                // Unfortunately we're fighting the borrow checker here. The problem is that
-                // ir is borrowed in the Stack type, with most IR being owned outside the
-                // function, but when you apply a lambda, the Proc::LambdaExact constructor
-                // stores an Arc to the code to execute, hence it comes under our control,
-                // which means that when the temporaries in this block go away, so does
-                // ir (which is borrowed from f). We solve the problem by storing an Arc of
-                // the IR inside the Ret instruction above, so that it won't get deallocated
-                // while in use. Rust doesn't reason about other owners of an Arc though, so...
-                let code: *const Ir = &**code;
-                State::Eval(unsafe { &*code })
+                State::Eval(self.borrow_code(code))
               },
               Proc::MatchCont(valid) => {
                 if !valid.get() {throw!(sp2, "continuation has expired")}
@@ -1671,7 +2429,7 @@ impl<'a> Evaluator<'a> {
                       }
                     }
                     Some(Stack::Drop(n)) => {self.ctx.truncate(n);}
-                    Some(Stack::Ret(fsp, _, old, _)) => {self.file = fsp.file; self.ctx = old},
+                    Some(Stack::Ret(fsp, _, old)) => {self.file = fsp.file; self.ctx = old},
                     Some(_) => {}
                     None => throw!(sp2, "continuation has expired")
                   }
@@ -1704,11 +2462,14 @@ impl<'a> Evaluator<'a> {
                 match &*g {
                   Ok(e) => State::Ret(e.clone()),
                   Err(_) => if let Some(DeclKey::Thm(t)) = self.data[x].decl {
-                    let_unchecked!(heap as Err(heap) = mem::replace(&mut *g, Ok(LispVal::undef())));
-                    let e = self.get_proof(t, heap.into());
+                    let heap = match mem::replace(&mut *g, Ok(LispVal::undef())) {
+                      Err(heap) => heap,
+                      Ok(_) => unreachable!("checked above"),
+                    };
+                    let e = self.get_proof(&self.thms[t], heap.into());
                     *g = Ok(e.clone());
                     State::Ret(e)
-                  } else {unreachable!()}
+                  } else {throw!(sp1, format!("theorem '{}' not found", self.print(&x)))}
                 }
               }
               #[cfg(feature = "mmc")]
@@ -1726,7 +2487,7 @@ impl<'a> Evaluator<'a> {
               PatternState::Eval(&br.pat, e))
         },
         State::Pattern(sp, e, it, br, mut pstack, mut vars, st) => {
-          match pattern_match(&mut pstack, &mut vars, st) {
+          match pattern_match(&self.env, &mut pstack, &mut vars, st) {
             Err(TestPending(sp2, e2, ir)) => push!(
               TestPattern(sp, e, it, br, pstack, vars),
               AppHead(sp2, sp2, e2),
@@ -1752,7 +2513,8 @@ impl<'a> Evaluator<'a> {
           match u0.next() {
             None => {
               if !(u0.exactly(0) && it.all(|u| u.exactly(0))) {
-                throw!(sp1, "mismatched input length")
+                return Err(self.err(Some((sp1, false)), "mismatched input length")
+                  .with_code(ErrorCode::LengthMismatch))
               }
               State::Ret(LispVal::list(vec))
             }
@@ -1760,7 +2522,10 @@ impl<'a> Evaluator<'a> {
               let mut args = vec![e0];
               for u in it {
                 if let Some(e) = u.next() {args.push(e)}
-                else {throw!(sp1, "mismatched input length")}
+                else {
+                  return Err(self.err(Some((sp1, false)), "mismatched input length")
+                    .with_code(ErrorCode::LengthMismatch))
+                }
               }
               push!(MapProc(sp1, sp2, f.clone(), us, vec); App(sp1, sp2, f, args, [].iter()))
             }
@@ -5,10 +5,11 @@
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 use std::collections::HashMap;
-use num::{BigInt, ToPrimitive};
+use num::{BigInt, BigUint, ToPrimitive};
 use itertools::Itertools;
 use crate::ast::{SExpr, SExprKind, Atom};
 use crate::ArcString;
+use crate::explain::ErrorCode;
 use super::super::{AtomId, Span, DocComment, Elaborator, ElabError, ObjectKind};
 use super::{BuiltinProc, FileSpan, LispKind, LispVal, Proc, ProcSpec,
   Remap, Remapper, Syntax};
@@ -230,6 +231,12 @@ pub enum Pattern {
   Test(Span, Box<Ir>, Box<[Pattern]>),
   /// The `$foo$` pattern. This is equivalent to `(or 'foo ('foo))`.
   QExprAtom(AtomId),
+  /// The `$123$` pattern. Matches the number `123`, or a term that denotes `123`
+  /// in the numeral system of its sort (see `set-numeral`). Binds nothing.
+  Numeral(BigUint),
+  /// The `$"foo"$` pattern. Matches the string `"foo"`, or a term that denotes `"foo"`
+  /// in the string system of its sort (see `set-string`). Binds nothing.
+  StringLit(ArcString),
 }
 
 /// The `(mvar)` patterns, which match a metavariable of different kinds.
@@ -272,6 +279,8 @@ impl<'a> EnvDisplay for Pattern {
       Pattern::Not(es) => write!(f, "(not {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
       Pattern::Test(_, ir, p) => write!(f, "(? {} {})", fe.to(&**ir), fe.to(&**p)),
       Pattern::QExprAtom(a) => write!(f, "${}$", fe.to(a)),
+      Pattern::Numeral(n) => write!(f, "${}$", n),
+      Pattern::StringLit(s) => write!(f, "${:?}$", s),
     }
   }
 }
@@ -281,21 +290,21 @@ impl Remap for Ir {
   fn remap(&self, r: &mut Remapper) -> Self {
     match self {
       &Ir::Local(i) => Ir::Local(i),
-      &Ir::Global(sp, a) => Ir::Global(sp, a.remap(r)),
+      &Ir::Global(sp, a) => Ir::Global(sp.remap(r), a.remap(r)),
       Ir::Const(v) => Ir::Const(unsafe { v.freeze() }.remap(r)),
-      Ir::List(sp, v) => Ir::List(*sp, v.remap(r)),
+      Ir::List(sp, v) => Ir::List(sp.remap(r), v.remap(r)),
       Ir::DottedList(v, e) => Ir::DottedList(v.remap(r), e.remap(r)),
-      &Ir::App(s, t, ref e, ref es) => Ir::App(s, t, e.remap(r), es.remap(r)),
+      &Ir::App(s, t, ref e, ref es) => Ir::App(s.remap(r), t.remap(r), e.remap(r), es.remap(r)),
       Ir::If(e) => Ir::If(e.remap(r)),
       Ir::NoTailRec => Ir::NoTailRec,
-      Ir::Focus(sp, e) => Ir::Focus(*sp, e.remap(r)),
-      &Ir::SetMergeStrategy(sp, a, ref e) => Ir::SetMergeStrategy(sp, a.remap(r), e.remap(r)),
+      Ir::Focus(sp, e) => Ir::Focus(sp.remap(r), e.remap(r)),
+      &Ir::SetMergeStrategy(sp, a, ref e) => Ir::SetMergeStrategy(sp.remap(r), a.remap(r), e.remap(r)),
       &Ir::Def(n, ref a, ref e) => Ir::Def(n,
-        a.as_ref().map(|&(sp1, sp2, ref doc, a)| (sp1, sp2, doc.clone(), a.remap(r))),
+        a.as_ref().map(|&(sp1, sp2, ref doc, a)| (sp1.remap(r), sp2.remap(r), doc.clone(), a.remap(r))),
         e.remap(r)),
       &Ir::Eval(b, ref e) => Ir::Eval(b, e.remap(r)),
-      &Ir::Lambda(sp, n, spec, ref e) => Ir::Lambda(sp, n, spec, e.remap(r)),
-      &Ir::Match(sp, ref e, ref br) => Ir::Match(sp, e.remap(r), br.remap(r)),
+      &Ir::Lambda(sp, n, spec, ref e) => Ir::Lambda(sp.remap(r), n, spec, e.remap(r)),
+      &Ir::Match(sp, ref e, ref br) => Ir::Match(sp.remap(r), e.remap(r), br.remap(r)),
     }
   }
 }
@@ -330,8 +339,10 @@ impl Remap for Pattern {
       Pattern::And(es) => Pattern::And(es.remap(r)),
       Pattern::Or(es) => Pattern::Or(es.remap(r)),
       Pattern::Not(es) => Pattern::Not(es.remap(r)),
-      &Pattern::Test(sp, ref ir, ref es) => Pattern::Test(sp, ir.remap(r), es.remap(r)),
+      &Pattern::Test(sp, ref ir, ref es) => Pattern::Test(sp.remap(r), ir.remap(r), es.remap(r)),
       Pattern::QExprAtom(a) => Pattern::QExprAtom(a.remap(r)),
+      Pattern::Numeral(n) => Pattern::Numeral(n.clone()),
+      Pattern::StringLit(s) => Pattern::StringLit(s.clone()),
     }
   }
 }
@@ -350,7 +361,7 @@ impl Remap for MVarPattern {
 impl Ir {
   fn unconst(cs: Vec<Ir>) -> Result<Vec<LispVal>, Vec<Ir>> {
     if cs.iter().all(|c| matches!(c, Ir::Const(_))) {
-      Ok(cs.into_iter().map(|c| let_unchecked!(Ir::Const(v) = c, v)).collect())
+      Ok(cs.into_iter().map(|c| if let Ir::Const(v) = c {v} else {unreachable!()}).collect())
     } else {Err(cs)}
   }
   fn list(fsp: FileSpan, cs: Vec<Ir>) -> Ir {
@@ -391,33 +402,42 @@ impl Ir {
 
 struct LocalCtx {
   names: HashMap<AtomId, Vec<usize>>,
-  ctx: Vec<AtomId>,
+  /// The variables in the context, with the spans of their binders.
+  ctx: Vec<(Span, AtomId)>,
+  /// The end of the last expression that was parsed.
+  end: usize,
+  /// The variables that have gone out of scope, as `(scope, x, binder)`
+  /// (see [`Spans::lisp_scopes`](crate::elab::spans::Spans::lisp_scopes)).
+  scopes: Vec<(Span, AtomId, Span)>,
 }
 
 impl LocalCtx {
-  fn new() -> Self { Self {names: HashMap::new(), ctx: vec![]} }
+  fn new() -> Self { Self {names: HashMap::new(), ctx: vec![], end: 0, scopes: vec![]} }
   fn len(&self) -> usize { self.ctx.len() }
   fn get(&self, x: AtomId) -> Option<usize> {
     Some(*self.names.get(&x)?.last()?)
   }
-  fn push(&mut self, x: AtomId) -> usize {
+  fn push(&mut self, sp: Span, x: AtomId) -> usize {
     let old = self.ctx.len();
     if x != AtomId::UNDER { self.names.entry(x).or_insert_with(Vec::new).push(old) }
-    self.ctx.push(x);
+    self.ctx.push((sp, x));
     old
   }
-  fn push_list(&mut self, xs: &[AtomId]) -> usize {
+  fn push_list(&mut self, xs: &[(Span, AtomId)]) -> usize {
     let old = self.ctx.len();
-    for &x in xs { self.push(x); }
+    for &(sp, x) in xs { self.push(sp, x); }
     old
   }
-  fn get_or_push(&mut self, x: AtomId) -> usize {
-    self.get(x).unwrap_or_else(|| self.push(x))
+  fn get_or_push(&mut self, sp: Span, x: AtomId) -> usize {
+    self.get(x).unwrap_or_else(|| self.push(sp, x))
   }
 
   fn pop(&mut self) {
-    let x = self.ctx.pop().expect("context underflow");
-    if x != AtomId::UNDER {self.names.get_mut(&x).expect("missing name").pop();}
+    let (sp, x) = self.ctx.pop().expect("context underflow");
+    if x != AtomId::UNDER {
+      self.names.get_mut(&x).expect("missing name").pop();
+      self.scopes.push(((sp.start..self.end.max(sp.end)).into(), x, sp))
+    }
   }
   fn restore(&mut self, n: usize) {
     while self.ctx.len() > n { self.pop() }
@@ -469,13 +489,13 @@ impl<'a> LispParser<'a> {
         Item::DottedList(xs, y) => {
           let xs = self.parse_idents(xs)?;
           self.ctx.push_list(&xs);
-          let y = self.parse_ident(y)?;
-          self.ctx.push(y);
+          let x = self.parse_ident(y)?;
+          self.ctx.push(y.span, x);
         }
       }
     }
     let mut len = self.ctx.len();
-    let mut ir = self.exprs(false, es)?;
+    let mut ir = self.body(es)?;
     for e in stack {
       ir = match e {
         Item::List(xs) => {
@@ -523,9 +543,9 @@ impl<'a> LispParser<'a> {
     }
   }
 
-  fn parse_idents(&mut self, es: &[SExpr]) -> Result<Vec<AtomId>, ElabError> {
+  fn parse_idents(&mut self, es: &[SExpr]) -> Result<Vec<(Span, AtomId)>, ElabError> {
     let mut xs = vec![];
-    for e in es {xs.push(self.parse_ident(e)?)}
+    for e in es {xs.push((e.span, self.parse_ident(e)?))}
     Ok(xs)
   }
 
@@ -546,9 +566,11 @@ impl<'a> LispParser<'a> {
         for e in es.into_vec() { cs.push(self.qexpr(e)?) }
         Ok(Ir::list(self.fspan(e.span), cs))
       }
+      QExprKind::Number(n) => Ok(Ir::Const(LispVal::number(n.into()).span(self.fspan(e.span)))),
+      QExprKind::String(s) => Ok(Ir::Const(LispVal::string(s).span(self.fspan(e.span)))),
       QExprKind::Unquote(e) => {
         if self.mm0_mode {
-          self.report(ElabError::warn(e.span, "(MM0 mode) unquotation not allowed"))
+          self.report(ElabError::warn(e.span, "(MM0 mode) unquotation not allowed").with_code(ErrorCode::Mm0Mode))
         }
         self.expr(false, &e)
       }
@@ -575,25 +597,12 @@ impl<'a> LispParser<'a> {
     };
     let mut cs = vec![];
     if rec {
-      let mut ds = Vec::with_capacity(ls.len());
+      let mut defs = Vec::with_capacity(ls.len());
       for l in ls {
-        let ((sp, x, stk), e2) = self.let_var(l)?;
-        let n = self.ctx.push(x);
-        let sps = if x == AtomId::UNDER {None} else {Some((l.span, sp, None, x))};
-        cs.push(Ir::Def(n, sps.clone(),
-          Box::new(Ir::new_ref(sp, sp, Ir::Const(LispVal::undef())))));
-        ds.push((sp, x, stk, e2, n, sps));
-      }
-      for (sp, x, stk, e2, n, sps) in ds {
-        let mut v = self.def_ir(sp, e2, stk)?;
-        if let Some(r) = v.pop() {
-          cs.extend(v);
-          let m = self.ctx.push(x);
-          cs.push(Ir::Def(m, sps, r.into()));
-          cs.push(Ir::set_weak(sp, sp, Ir::Local(n), Ir::Local(m)));
-        }
+        let (v, e2) = self.let_var(l)?;
+        defs.push((l.span, v, e2));
       }
-      cs.push(Ir::NoTailRec);
+      self.rec_defs(&mut cs, defs)?;
     } else {
       for l in ls {
         let ((sp, x, stk), e2) = self.let_var(l)?;
@@ -601,14 +610,77 @@ impl<'a> LispParser<'a> {
         if x == AtomId::UNDER {
           cs.push(Ir::Eval(false, v.into()))
         } else {
-          cs.push(Ir::Def(self.ctx.push(x), Some((l.span, sp, None, x)), Ir::eval(v).into()))
+          cs.push(Ir::Def(self.ctx.push(sp, x), Some((l.span, sp, None, x)), Ir::eval(v).into()))
         }
       }
     }
-    for e in &es[1..] { cs.push(self.expr(false, e)?) }
+    cs.extend(self.body(&es[1..])?);
     Ok(Ir::Eval(true, cs.into()))
   }
 
+  /// Compile a group of mutually recursive definitions, as in `letrec`. Each variable is
+  /// first bound to an empty ref-cell, and then the ref-cells are weakly set to the values,
+  /// so that each definition can refer to all the others.
+  fn rec_defs(&mut self, cs: &mut Vec<Ir>,
+      defs: Vec<(Span, Var<'_>, &[SExpr])>) -> Result<(), ElabError> {
+    let mut ds = Vec::with_capacity(defs.len());
+    for (span, (sp, x, stk), e2) in defs {
+      let n = self.ctx.push(sp, x);
+      let sps = if x == AtomId::UNDER {None} else {Some((span, sp, None, x))};
+      cs.push(Ir::Def(n, sps.clone(),
+        Box::new(Ir::new_ref(sp, sp, Ir::Const(LispVal::undef())))));
+      ds.push((sp, x, stk, e2, n, sps));
+    }
+    for (sp, x, stk, e2, n, sps) in ds {
+      let mut v = self.def_ir(sp, e2, stk)?;
+      if let Some(r) = v.pop() {
+        cs.extend(v);
+        let m = self.ctx.push(sp, x);
+        cs.push(Ir::Def(m, sps, r.into()));
+        cs.push(Ir::set_weak(sp, sp, Ir::Local(n), Ir::Local(m)));
+      }
+    }
+    cs.push(Ir::NoTailRec);
+    Ok(())
+  }
+
+  /// If `e` is a `letrec` with no body, `(letrec ([x e] ...))`, returns the list of
+  /// variable declarations.
+  fn letrec_group<'c>(&mut self, e: &'c SExpr) -> Option<&'c [SExpr]> {
+    if let SExprKind::List(es) = &e.k {
+      if let [head @ SExpr {k: SExprKind::Atom(a), ..}, SExpr {k: SExprKind::List(ls), ..}] = &**es {
+        if let Ok(Syntax::Letrec) = Syntax::parse(self.ast.span(head.span), *a) {
+          self.spans.insert_if(head.span, || ObjectKind::Syntax(Syntax::Letrec));
+          return Some(ls)
+        }
+      }
+    }
+    None
+  }
+
+  /// Compile the expressions in a body, like [`exprs`](Self::exprs). In addition, a
+  /// `letrec` with no body that is followed by more expressions binds its variables in
+  /// the rest of the body, like `def`, so that local helper functions can be
+  /// (mutually) recursive.
+  fn body(&mut self, es: &[SExpr]) -> Result<Vec<Ir>, ElabError> {
+    let mut cs = vec![];
+    for (i, e) in es.iter().enumerate() {
+      match self.letrec_group(e) {
+        Some(ls) if i + 1 < es.len() => {
+          let mut defs = Vec::with_capacity(ls.len());
+          for l in ls {
+            let (v, e2) = self.let_var(l)?;
+            defs.push((l.span, v, e2));
+          }
+          self.rec_defs(&mut cs, defs)?;
+          self.ctx.end = self.ctx.end.max(e.span.end);
+        }
+        _ => cs.push(self.expr(false, e)?),
+      }
+    }
+    Ok(cs)
+  }
+
   fn list_pattern(&mut self, ctx: &mut LocalCtx, code: &mut Vec<Ir>,
       quote: bool, mut es: &[SExpr]) -> Result<Pattern, ElabError> {
     let mut pfx = vec![];
@@ -705,6 +777,8 @@ impl<'a> LispParser<'a> {
           Ok(Pattern::List(cs.into(), None))
         }
       }
+      QExprKind::Number(n) => Ok(Pattern::Numeral(n)),
+      QExprKind::String(s) => Ok(Pattern::StringLit(s)),
       QExprKind::Unquote(e) => self.pattern(ctx, code, false, &e)
     }
   }
@@ -726,7 +800,7 @@ impl<'a> LispParser<'a> {
         } else {
           let x = self.parse_atom(e.span, a)?;
           if x == AtomId::UNDER {Pattern::Skip}
-          else {Pattern::Atom(ctx.get_or_push(x))}
+          else {Pattern::Atom(ctx.get_or_push(e.span, x))}
         }
       ),
       SExprKind::DottedList(es, e) => Ok(Pattern::DottedList(
@@ -750,12 +824,13 @@ impl<'a> LispParser<'a> {
       SExprKind::List(es) if !es.is_empty() => (&es[0], &es[1..]),
       _ => return Err(ElabError::new_e(e.span, "match: improper syntax"))
     };
-    let mut cont = AtomId::UNDER;
+    let (mut cont, mut cont_sp) = (AtomId::UNDER, e.span);
     if let Some(e2) = es.get(0) {
       if let SExprKind::List(v) = &e2.k {
         if let [SExpr {span, k: SExprKind::Atom(a)}, ref x] = **v {
           if self.ast.span_atom(span, a) == b"=>" {
             cont = self.parse_ident(x)?;
+            cont_sp = x.span;
             es = &es[1..];
           }
         }
@@ -765,8 +840,8 @@ impl<'a> LispParser<'a> {
     let pat = self.pattern(&mut ctx, code, false, e)?;
     let vars = ctx.ctx.len();
     let start = self.ctx.push_list(&ctx.ctx);
-    if cont != AtomId::UNDER {self.ctx.push(cont);}
-    let eval = Box::new(Ir::eval(self.exprs(false, es)?));
+    if cont != AtomId::UNDER {self.ctx.push(cont_sp, cont);}
+    let eval = Box::new(Ir::eval(self.body(es)?));
     self.ctx.restore(start);
     Ok(Branch {pat, vars, cont: cont != AtomId::UNDER, eval})
   }
@@ -792,7 +867,11 @@ impl<'a> LispParser<'a> {
         self.spans.insert(sp, ObjectKind::Global(x));
         Ir::Global(sp, x)
       },
-      Some(i) => Ir::Local(i)
+      Some(i) => {
+        let def = self.ctx.ctx[i].0;
+        self.spans.insert(sp, ObjectKind::LispLocal(x, def));
+        Ir::Local(i)
+      }
     }
   }
 
@@ -867,7 +946,7 @@ impl<'a> LispParser<'a> {
           Err(stx) => {
             self.spans.insert_if(es[0].span, || ObjectKind::Syntax(stx));
             match stx {
-              Syntax::Begin => Ok(Ir::Eval(true, self.exprs(false, &es[1..])?.into())),
+              Syntax::Begin => Ok(Ir::Eval(true, self.body(&es[1..])?.into())),
               Syntax::Define if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Define =>
@@ -876,7 +955,7 @@ impl<'a> LispParser<'a> {
                   (sp, x, cs) => {
                     restore = None;
                     let doc = if doc.is_empty() {None} else {Some(doc.into())};
-                    Ir::Def(self.ctx.push(x), Some((e.span, sp, doc, x)), Ir::eval(cs).into())
+                    Ir::Def(self.ctx.push(sp, x), Some((e.span, sp, doc, x)), Ir::eval(cs).into())
                   }
                 }),
               Syntax::Lambda if es.len() < 2 => return Err(
@@ -885,20 +964,20 @@ impl<'a> LispParser<'a> {
                 SExprKind::List(xs) => {
                   let xs = self.parse_idents(xs)?;
                   Ok(Ir::Lambda(es[0].span, self.ctx.push_list(&xs), ProcSpec::Exact(xs.len()),
-                    Ir::eval(self.exprs(false, &es[2..])?).into()))
+                    Ir::eval(self.body(&es[2..])?).into()))
                 }
                 SExprKind::DottedList(xs, y) => {
                   let xs = self.parse_idents(xs)?;
-                  let y = self.parse_ident(y)?;
+                  let x = self.parse_ident(y)?;
                   let n = self.ctx.push_list(&xs);
-                  self.ctx.push(y);
+                  self.ctx.push(y.span, x);
                   Ok(Ir::Lambda(es[0].span, n, ProcSpec::AtLeast(xs.len()),
-                    Ir::eval(self.exprs(false, &es[2..])?).into()))
+                    Ir::eval(self.body(&es[2..])?).into()))
                 }
                 _ => {
                   let x = self.parse_ident(&es[1])?;
-                  Ok(Ir::Lambda(es[0].span, self.ctx.push(x), ProcSpec::AtLeast(0),
-                    Ir::eval(self.exprs(false, &es[2..])?).into()))
+                  Ok(Ir::Lambda(es[0].span, self.ctx.push(es[1].span, x), ProcSpec::AtLeast(0),
+                    Ir::eval(self.body(&es[2..])?).into()))
                 }
               },
               Syntax::Quote if es.len() < 2 => return Err(
@@ -911,7 +990,7 @@ impl<'a> LispParser<'a> {
                 self.expr(false, &es[1])?,
                 self.expr(false, &es[2])?,
                 if let Some(e) = es.get(3) {
-                  self.ctx.restore(unwrap_unchecked!(restore));
+                  self.ctx.restore(restore.expect("not a definition"));
                   self.expr(false, e)?
                 } else { Ir::Const(LispVal::undef()) }
               )))),
@@ -933,16 +1012,16 @@ impl<'a> LispParser<'a> {
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Match => {
                 let e = self.expr(false, &es[1])?;
-                self.ctx.restore(unwrap_unchecked!(restore));
+                self.ctx.restore(restore.expect("not a definition"));
                 self.match_(&es[2..], |m| Ir::Match(es[0].span, Box::new(e), m))
               },
               Syntax::MatchFn => {
-                let i = self.ctx.push(AtomId::UNDER);
+                let i = self.ctx.push(es[0].span, AtomId::UNDER);
                 Ok(Ir::Lambda(es[0].span, i, ProcSpec::Exact(1),
                   Arc::new(self.match_(&es[1..], |m| Ir::match_fn_body(es[0].span, i, m))?)))
               }
               Syntax::MatchFns => {
-                let i = self.ctx.push(AtomId::UNDER);
+                let i = self.ctx.push(es[0].span, AtomId::UNDER);
                 Ok(Ir::Lambda(es[0].span, i, ProcSpec::AtLeast(0),
                   Arc::new(self.match_(&es[1..], |m| Ir::match_fn_body(es[0].span, i, m))?)))
               }
@@ -956,8 +1035,19 @@ impl<'a> LispParser<'a> {
       &SExprKind::Formula(f) => {let q = self.parse_formula(f)?; self.qexpr(q)}
     };
     if let Some(old) = restore { self.ctx.restore(old) }
+    self.ctx.end = self.ctx.end.max(e.span.end);
     res
   }
+
+  /// Record the binders and scopes of the local variables in the
+  /// [`Spans`](crate::elab::spans::Spans).
+  fn finish(mut self) {
+    self.ctx.restore(0);
+    for &(_, x, sp) in &self.ctx.scopes {
+      self.elab.spans.insert(sp, ObjectKind::LispLocal(x, sp));
+    }
+    self.elab.spans.lisp_scopes.append(&mut self.ctx.scopes)
+  }
 }
 
 impl Elaborator {
@@ -969,13 +1059,19 @@ impl Elaborator {
   /// Parse a lisp `SExpr` from the surface syntax into an `IR` object suitable for evaluation.
   /// The `doc` argument is an additional doc string, if applicable.
   pub fn parse_lisp_doc(&mut self, e: &SExpr, doc: String) -> Result<Ir, ElabError> {
-    LispParser {elab: &mut *self, ctx: LocalCtx::new()}.expr_doc(doc, false, e)
+    let mut p = LispParser {elab: &mut *self, ctx: LocalCtx::new()};
+    let res = p.expr_doc(doc, false, e);
+    p.finish();
+    res
   }
 
   /// Parse a `QExpr`, the result of parsing a math formula,
   /// into an `IR` object suitable for evaluation. (Usually this will be a `IR::Const`,
   /// but `QExpr`'s can contain antiquotations which require evaluation.)
   pub fn parse_qexpr(&mut self, e: QExpr) -> Result<Ir, ElabError> {
-    LispParser {elab: &mut *self, ctx: LocalCtx::new()}.qexpr(e)
+    let mut p = LispParser {elab: &mut *self, ctx: LocalCtx::new()};
+    let res = p.qexpr(e);
+    p.finish();
+    res
   }
 }
\ No newline at end of file
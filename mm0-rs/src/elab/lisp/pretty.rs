@@ -3,9 +3,10 @@
 //! The main interface is the [`FormatEnv::pretty`] function, which provides a
 //! [`Pretty`] arena on which various methods exist to print different kinds of object.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cell::RefCell;
 use std::{mem, fmt};
+use std::fmt::Write;
 use std::borrow::Cow;
 use pretty::DocAllocator;
 use itertools::Itertools;
@@ -78,6 +79,7 @@ impl LispKind {
       LispKind::DottedList(..) |
       LispKind::AtomMap(..) |
       LispKind::Goal(..) => false,
+      LispKind::Proof(..) |
       LispKind::Atom(..) |
       LispKind::MVar(..) |
       LispKind::Proc(..) |
@@ -92,14 +94,56 @@ impl LispKind {
   }
 }
 
+/// Render a string literal for use in a math formula. This escapes `$`, since
+/// it would otherwise end the formula.
+fn quote_math_string(s: &[u8]) -> String {
+  let mut out = String::from("\"");
+  for &c in s {
+    match c {
+      b'\\' => out.push_str("\\\\"),
+      b'"' => out.push_str("\\\""),
+      b'\n' => out.push_str("\\n"),
+      b'\r' => out.push_str("\\r"),
+      b' '..=b'~' if c != b'$' => out.push(c.into()),
+      _ => write!(out, "\\x{:02x}", c).expect("writing to a string"),
+    }
+  }
+  out.push('"');
+  out
+}
+
 type PrettyCache<'a> = (LispVal, (Prec, Pp<'a>));
 
+/// The settings of the pretty printer, which are stored in the [`Environment`] and can be
+/// changed from lisp using `set-pp-options`.
+#[derive(Copy, Clone, Debug)]
+pub struct PpOptions {
+  /// The line width used for expressions in messages and by `pp` (default 80).
+  pub width: usize,
+  /// Print coercions as applications, instead of hiding them (default false).
+  pub coercions: bool,
+  /// Print the implicit arguments of terms marked by `set-implicit` (default true). These
+  /// can only be omitted in `refine`, so hiding them makes statements unparseable.
+  pub implicits: bool,
+  /// The radix for numerals, 10 or 16 (default 10).
+  pub radix: u32,
+}
+crate::deep_size_0!(PpOptions);
+
+impl Default for PpOptions {
+  fn default() -> Self { Self {width: 80, coercions: false, implicits: true, radix: 10} }
+}
+
 /// A state object for constructing pretty printing nodes `PP<'a>`.
 /// All pretty printing nodes will be tied to the lifetime of the struct.
 pub struct Pretty<'a> {
   fe: FormatEnv<'a>,
   pub(crate) alloc: &'a Arena<'a>,
   hash: RefCell<HashMap<*const LispKind, PrettyCache<'a>>>,
+  /// The arguments of digit applications that are known not to be numerals, because the
+  /// digit application is not. This avoids recomputing the value of every suffix of a long
+  /// chain of digits that does not end in zero.
+  not_numeral: RefCell<HashSet<*const LispKind>>,
   lparen: Pp<'a>,
   rparen: Pp<'a>,
 }
@@ -138,7 +182,7 @@ impl<'a> Pretty<'a> {
     Pretty {
       lparen: Pp::token(alloc, fe.env, "("),
       rparen: Pp::token(alloc, fe.env, ")"),
-      fe, alloc, hash: RefCell::new(HashMap::new())
+      fe, alloc, hash: RefCell::new(HashMap::new()), not_numeral: RefCell::new(HashSet::new())
     }
   }
 
@@ -262,8 +306,33 @@ impl<'a> Pretty<'a> {
     let v = (|| Some({
       let env = self.fe.env;
       let (ad, t, args) = self.get_term_args(e)?;
+      if let Some(num) = env.pe.numerals.get(&env.terms[t].ret.0) {
+        let known = self.not_numeral.borrow_mut().remove(&p);
+        match if known {None} else {env.numeral_value(e)} {
+          Some(n) => {
+            let n = if env.pp.radix == 16 {format!("{:#x}", n)} else {n.to_string()};
+            if !env.pe.consts.contains_key(n.as_bytes()) {
+              return Some((Prec::Max, Pp::word(self.alloc, n)))
+            }
+          }
+          // If a digit applied to another digit application `e2` is not a numeral,
+          // then neither is `e2`
+          None => if num.digits.contains(&t) {
+            let digit = |e2: &&LispVal| e2.head().and_then(|a| env.term(a.as_atom()?))
+              .map_or(false, |t2| num.digits.contains(&t2));
+            self.not_numeral.borrow_mut().extend(args.iter().filter(digit).map(|e2| -> *const LispKind {&**e2}))
+          }
+        }
+      }
+      if env.pe.strings.contains_key(&env.terms[t].ret.0) {
+        if let Some(s) = env.string_value(e) {
+          if !env.pe.consts.keys().any(|c| c.starts_with(b"\"")) {
+            return Some((Prec::Max, Pp::word(self.alloc, quote_math_string(&s))))
+          }
+        }
+      }
       if let Some(&(coe, ref fix)) = env.pe.decl_nota.get(&t) {
-        if coe {return Some(self.pp_expr(&args[0]))}
+        if coe && !env.pp.coercions {return Some(self.pp_expr(&args[0]))}
         if let Some(&(ref tk, infix)) = fix.first() {
           let doc = if infix {
             let info = &env.pe.infixes[tk];
@@ -276,14 +345,28 @@ impl<'a> Pretty<'a> {
           } else {
             let info = &env.pe.prefixes[tk];
             let mut doc = self.token(tk);
-            for lit in &info.lits {
-              doc = self.append(doc, self.lit(lit, &args))
+            let mut mixfix = false;
+            for (i, lit) in info.lits.iter().enumerate() {
+              let d = self.lit(lit, &args);
+              // In a mixfix notation like `if p then a else b`, the keywords
+              // following an argument start a new line when the whole does not fit.
+              doc = match (lit, i.checked_sub(1).map(|j| &info.lits[j])) {
+                (Literal::Const(_), Some(Literal::Var(..))) if !doc.right && !d.left => {
+                  mixfix = true;
+                  self.append_with(doc, Self::line(), d)
+                }
+                _ => self.append(doc, d)
+              }
             }
-            doc
+            if mixfix { self.group(self.nest(2, doc)) } else { doc }
           };
           return Some((env.pe.consts[tk].1, doc))
         }
       }
+      let args = match env.pe.implicits.get(&t).filter(|_| !env.pp.implicits) {
+        Some(imp) => args.into_iter().zip(imp.iter()).filter(|p| !*p.1).map(|p| p.0).collect(),
+        None => args,
+      };
       if args.is_empty() {
         (Prec::Max, self.word(&ad.name))
       } else {
@@ -160,15 +160,15 @@ impl EnvDisplay for LispKind {
       LispKind::Undef => write!(f, "#undef"),
       LispKind::Proc(Proc::Builtin(p)) => p.fmt(f),
       LispKind::Proc(Proc::Lambda {pos: ProcPos::Unnamed(pos), ..}) => {
-        let r = fe.source.to_pos(pos.span.start);
+        let (line, col) = fe.source.to_line_col(pos.span.start);
         let fname = pos.file.path().file_name().and_then(std::ffi::OsStr::to_str).unwrap_or("?");
-        write!(f, "#[fn at {} {}:{}]", fname, r.line + 1, r.character + 1)
+        write!(f, "#[fn at {} {}:{}]", fname, line, col)
       }
       &LispKind::Proc(Proc::Lambda {pos: ProcPos::Named(ref pos, _, a), ..}) => {
-        let r = fe.source.to_pos(pos.span.start);
+        let (line, col) = fe.source.to_line_col(pos.span.start);
         let fname = pos.file.path().file_name().and_then(std::ffi::OsStr::to_str).unwrap_or("?");
         let x = &fe.data[a].name;
-        write!(f, "#[fn {} at {} {}:{}]", x, fname, r.line + 1, r.character + 1)
+        write!(f, "#[fn {} at {} {}:{}]", x, fname, line, col)
       }
       LispKind::Proc(Proc::MatchCont(_)) => write!(f, "#[match cont]"),
       LispKind::Proc(Proc::RefineCallback) => write!(f, "#[refine]"),
@@ -185,6 +185,7 @@ impl EnvDisplay for LispKind {
       LispKind::Ref(m) => m.get(|e| e.fmt(fe, f)),
       &LispKind::MVar(n, _) => write!(f, "?{}", alphanumber(n)),
       LispKind::Goal(e) => write!(f, "(goal {})", fe.to(e)),
+      LispKind::Proof(td) => write!(f, "#[proof of {}]", fe.data[td.atom].name),
     }
   }
 }
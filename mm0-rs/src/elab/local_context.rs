@@ -5,9 +5,10 @@ use std::ops::Deref;
 use std::mem;
 use std::collections::{HashMap, hash_map::Entry};
 use itertools::Itertools;
-use crate::{AtomId, TermKind, ThmKind, Type as EType, Span, FileSpan, BoxError, MAX_BOUND_VARS};
+use crate::{AtomId, DeclKey, TermKind, ThmKind, Type as EType, Span, FileSpan, BoxError, MAX_BOUND_VARS};
+use crate::explain::ErrorCode;
 use crate::ast::{Decl, Type, DepType, LocalKind};
-use super::{Coe, DeclKind, DerefMut, DocComment, ElabError, Elaborator, Environment,
+use super::{Coe, DeclKind, DepPolicy, DerefMut, DocComment, ElabError, Elaborator, Environment,
   Expr, Modifiers, ObjectKind, Proof, Result, SExprKind, SortId, Term, TermId, Thm};
 use super::lisp::{LispVal, LispKind, Uncons, InferTarget, print::FormatEnv};
 use super::proof::{NodeHasher, ProofKind, ProofHash, build, Dedup};
@@ -100,6 +101,10 @@ pub struct LocalContext {
   ///
   /// [`set-close-fn`]: super::lisp::BuiltinProc::SetCloseFn
   pub closer: LispVal,
+  /// The number of times `sorry` has been used in the current proof. If this is nonzero
+  /// at the end of the proof, the theorem is added without a proof, and it (and anything
+  /// that depends on it) is considered incomplete.
+  pub sorries: usize,
 }
 
 fn new_mvar(mvars: &mut Vec<LispVal>, tgt: InferTarget, sp: Option<FileSpan>) -> LispVal {
@@ -124,6 +129,7 @@ impl LocalContext {
     self.proofs.clear();
     self.proof_order.clear();
     self.closer = LispVal::undef();
+    self.sorries = 0;
   }
 
   /// Set the list of goals to `gs`, after filtering the elements that are not
@@ -227,6 +233,20 @@ pub fn try_get_span(fsp: &FileSpan, e: &LispKind) -> Span {
   try_get_span_from(fsp, e.fspan().as_ref())
 }
 
+/// Find an innermost subterm of the expression `e` that has the variable `a` as a direct
+/// argument, which explains why `e` depends on `a`. Subterms inside earlier arguments
+/// are preferred, and `e` itself is only returned if no argument contains a witness.
+fn dep_witness(e: &LispVal, a: AtomId) -> Option<LispVal> {
+  let mut u = Uncons::from(e.clone());
+  u.next()?;
+  let mut direct = false;
+  for arg in u {
+    if arg.as_atom() == Some(a) { direct = true }
+    else if let Some(w) = dep_witness(&arg, a) { return Some(w) }
+  }
+  if direct { Some(e.clone()) } else { None }
+}
+
 /// Get the span from `fsp2`, but only if it lies inside the
 /// span `fsp`, otherwise return `fsp`. (This prevents errors in
 /// one statement from causing error reports further up the file or
@@ -276,6 +296,17 @@ impl<'a> ElabTerm<'a> {
     ElabError::new_e(self.try_get_span(e), msg)
   }
 
+  /// Suggest variables in the local context with names close to `a`, for error messages.
+  fn similar_vars(&self, a: AtomId) -> String {
+    crate::did_you_mean(&self.fe.data[a].name, self.lc.vars.keys().map(|&v| &*self.fe.data[v].name))
+  }
+
+  /// Suggest terms with names close to `a`, for error messages.
+  fn similar_terms(&self, a: AtomId) -> String {
+    crate::did_you_mean(&self.fe.data[a].name, self.fe.data.0.iter()
+      .filter(|ad| matches!(ad.decl, Some(DeclKey::Term(_)))).map(|ad| &*ad.name))
+  }
+
   fn coerce(&self, src: &LispVal, from: SortId, res: LispVal, tgt: InferTarget) -> Result<LispVal> {
     let fsp = src.fspan();
     let res = match &fsp { None => res, Some(fsp) => res.replace_span(fsp.clone()) };
@@ -300,14 +331,16 @@ impl<'a> ElabTerm<'a> {
   fn infer_sort(&self, e: &LispKind) -> Result<SortId> {
     e.unwrapped(|r| match r {
       &LispKind::Atom(a) => match self.lc.vars.get(&a) {
-        None => Err(self.err(e, "variable not found")),
+        None => Err(self.err(e, format!("variable '{}' not found{}", self.fe.data[a].name,
+          self.similar_vars(a))).with_code(ErrorCode::UnknownVariable)),
         Some(&(_, InferSort::Bound(sort) | InferSort::Reg(sort, _))) => Ok(sort),
         Some((_, InferSort::Unknown {..})) => panic!("finalized vars already"),
       },
       LispKind::List(es) if !es.is_empty() => {
         let a = es[0].as_atom().ok_or_else(|| self.err(&es[0], "expected an atom"))?;
         let tid = self.fe.term(a).ok_or_else(||
-          self.err(&es[0], format!("term '{}' not declared", self.fe.data[a].name)))?;
+          self.err(&es[0], format!("term '{}' not declared{}", self.fe.data[a].name,
+            self.similar_terms(a))).with_code(ErrorCode::UnknownTerm))?;
         Ok(self.fe.terms[tid].ret.0)
       }
       _ => Err(self.err(e, "invalid expression"))
@@ -381,9 +414,20 @@ impl<'a> ElabTermMut<'a> {
         format!("term '{}' is shadowed by a local variable", self.env.data[a].name)))
     }
     let tid = self.env.term(a).ok_or_else(||
-      self.as_ref().err(&t, format!("term '{}' not declared", self.env.data[a].name)))?;
+      self.as_ref().err(&t, format!("term '{}' not declared{}", self.env.data[a].name,
+        self.as_ref().similar_terms(a))).with_code(ErrorCode::UnknownTerm))?;
     let sp1 = self.as_ref().try_get_span(e);
     self.spans_insert(&t, || ObjectKind::Term(tid, sp1));
+    let args = it.collect::<Vec<_>>();
+    if let Some(imp) = self.env.omits_implicits(tid, args.len()) {
+      let names = self.env.terms[tid].args.iter().zip(imp).filter(|p| *p.1)
+        .map(|((x, _), _)| x.map_or_else(|| "_".into(), |x| format!("'{}'", self.env.data[x].name)))
+        .collect::<Vec<_>>().join(", ");
+      return Err(self.as_ref().err(e, format!("cannot infer the implicit arguments {} of '{}', \
+        because nothing determines them by unification here; write them explicitly",
+        names, self.env.data[a].name)))
+    }
+    let mut it = args.into_iter();
     let tdata = &self.env.terms[tid];
     let nargs = tdata.args.len();
     let ret = tdata.ret.0;
@@ -407,6 +451,10 @@ impl<'a> ElabTermMut<'a> {
   }
 
   fn other(&mut self, e: &LispVal, tgt: InferTarget) -> Result<LispVal> {
+    if let Some(res) = self.env.mk_literal(e, tgt.sort().and_then(|s| self.data[s].sort)) {
+      let (s, res) = res.map_err(|msg| self.as_ref().err(e, msg))?;
+      return self.as_ref().coerce(e, s, res, tgt)
+    }
     let proc = match &self.data[AtomId::TO_EXPR_FALLBACK].lisp {
       Some(e) => e.val.clone(),
       None => return Err(self.as_ref().err(e, format!("Not a valid expression: {}", self.print(e))))
@@ -418,6 +466,29 @@ impl<'a> ElabTermMut<'a> {
     et.coerce(e, et.infer_sort(&res)?, res, tgt)
   }
 
+  /// Elaborate a sort ascription `e = (: e1 s)`, which elaborates `e1` at sort `s` and then
+  /// coerces the result to `tgt`. The ascription itself does not appear in the result.
+  fn ascription(&mut self, e: &LispVal, tgt: InferTarget) -> Result<LispVal> {
+    let mut u = Uncons::from(e.clone());
+    u.next();
+    let (e1, s) = match (u.next(), u.next()) {
+      (Some(e1), Some(s)) if u.exactly(0) => (e1, s),
+      _ => return Err(self.as_ref().err(e, "':' expected two arguments"))
+    };
+    let a = s.as_atom().filter(|&a| self.data[a].sort.is_some())
+      .ok_or_else(|| self.as_ref().err(&s, "expected a sort"))?;
+    let sort = self.data[a].sort.expect("expected a sort");
+    match tgt {
+      InferTarget::Bound(b) if b != a => Err(self.as_ref().err(e, format!(
+        "type error: expected {}, got {}", self.data[b].name, self.data[a].name))),
+      InferTarget::Bound(_) => self.expr(&e1, tgt),
+      _ => {
+        let res = self.expr(&e1, InferTarget::Reg(a))?;
+        self.as_ref().coerce(&e1, sort, res, tgt)
+      }
+    }
+  }
+
   // TODO: Unify this with RState::RefineExpr
   fn expr(&mut self, e: &LispVal, tgt: InferTarget) -> Result<LispVal> {
     e.unwrapped(|r| match r {
@@ -428,6 +499,7 @@ impl<'a> ElabTermMut<'a> {
       LispKind::List(_) | LispKind::DottedList(_, _) if e.is_list() => match e.len() {
         0 => self.other(e, tgt),
         1 => self.expr(&e.head().expect("nonempty"), tgt),
+        _ if e.head().and_then(|h| h.as_atom()) == Some(AtomId::COLON) => self.ascription(e, tgt),
         _ => self.list(e, Uncons::from(e.clone()), tgt),
       },
       _ => self.other(e, tgt),
@@ -527,7 +599,8 @@ impl Elaborator {
   /// on dummy variables. The bool in the result's pair indicates whether the variable is a dummy variable.
   fn elab_dep_type(&mut self, error: &mut bool, lk: LocalKind, d: &DepType) -> Result<(bool, InferSort)> {
     let a = self.env.get_atom(self.ast.span(d.sort));
-    let sort = self.data[a].sort.ok_or_else(|| ElabError::new_e(d.sort, "sort not found"))?;
+    let sort = self.data[a].sort.ok_or_else(||
+      ElabError::new_e(d.sort, "sort not found").with_code(ErrorCode::UnknownSort))?;
     self.spans.insert(d.sort, ObjectKind::Sort(sort));
     Ok(if lk.is_bound() {
       if let Some(&Span {end, ..}) = d.deps.last() {
@@ -569,7 +642,7 @@ impl Elaborator {
         let src = sp.expect("omitted type must come from a span");
         let fsp = self.fspan(src);
         if self.mm0_mode {
-          self.report(ElabError::warn(src, "(MM0 mode) variable missing sort"))
+          self.report(ElabError::warn(src, "(MM0 mode) variable missing sort").with_code(ErrorCode::Mm0Mode))
         }
         let mv = self.lc.new_mvar(InferTarget::Unknown, Some(fsp));
         let dummy = lk == LocalKind::Dummy;
@@ -635,15 +708,18 @@ impl Elaborator {
               if self.mm0_mode {
                 errs.push(ElabError::warn(src,
                   format!("(MM0 mode) inferred {{{}: {}}}, type inference is not allowed in MM0 files",
-                    self.env.data[a].name, self.env.sorts[sort].name)))
+                    self.env.data[a].name, self.env.sorts[sort].name)).with_code(ErrorCode::Mm0Mode))
               }
               dummy && d2
             } else {
               *is = InferSort::Reg(sort, Box::new([]));
+              errs.extend(self.dep_policy.report(src, format!(
+                "inferred ({}: {}) with no dependencies, so it must be disjoint from all bound variables",
+                self.env.data[a].name, self.env.sorts[sort].name)));
               if self.mm0_mode {
                 errs.push(ElabError::warn(src,
                   format!("(MM0 mode) inferred ({}: {}), type inference is not allowed in MM0 files",
-                    self.env.data[a].name, self.env.sorts[sort].name)))
+                    self.env.data[a].name, self.env.sorts[sort].name)).with_code(ErrorCode::Mm0Mode))
               }
               false
             };
@@ -669,7 +745,7 @@ impl Elaborator {
       ($sp:expr, $e:expr) => {report!(ElabError::new_e($sp, $e))};
     }
     if self.mm0_mode && !d.mods.is_empty() {
-      self.report(ElabError::warn(d.id, "(MM0 mode) decl modifiers not allowed"))
+      self.report(ElabError::warn(d.id, "(MM0 mode) decl modifiers not allowed").with_code(ErrorCode::Mm0Mode))
     }
 
     // log!("elab {}", self.ast.span(d.id));
@@ -699,7 +775,7 @@ impl Elaborator {
     let atom = self.env.get_atom(self.ast.span(d.id));
     self.spans.set_decl(atom);
     if self.mm0_mode && atom == AtomId::UNDER {
-      self.report(ElabError::warn(d.id, "(MM0 mode) declaration name required"))
+      self.report(ElabError::warn(d.id, "(MM0 mode) declaration name required").with_code(ErrorCode::Mm0Mode))
     }
     match d.k {
       DeclKind::Term | DeclKind::Def => {
@@ -707,7 +783,7 @@ impl Elaborator {
         let ret = match &d.ty {
           None => {
             if self.mm0_mode {
-              self.report(ElabError::warn(d.id, "(MM0 mode) return type required"))
+              self.report(ElabError::warn(d.id, "(MM0 mode) return type required").with_code(ErrorCode::Mm0Mode))
             }
             None
           }
@@ -726,14 +802,14 @@ impl Elaborator {
         if d.k == DeclKind::Term {
           if let Some(v) = &d.val {report!(v.span, "term declarations have no definition")}
         } else if d.val.is_none() && !self.mm0_mode {
-          self.report(ElabError::warn(d.id, "def declaration missing value"));
+          self.report(ElabError::warn(d.id, "def declaration missing value").with_code(ErrorCode::MissingValue));
         } else {}
         let val = match &d.val {
           None => None,
           Some(f) => (|| -> Result<Option<(Span, LispVal)>> {
             if self.mm0_mode {
               if let SExprKind::Formula(_) = f.k {} else {
-                self.report(ElabError::warn(f.span, "(MM0 mode) expected formula"))
+                self.report(ElabError::warn(f.span, "(MM0 mode) expected formula").with_code(ErrorCode::Mm0Mode))
               }
             }
             let e = self.eval_lisp(f)?;
@@ -770,6 +846,7 @@ impl Elaborator {
               return Err(ElabError::new_e(sp, format!("too many bound variables (max {})", MAX_BOUND_VARS)))
             }
             let deps = ba.expr_deps(&self.env, &val);
+            let val_e = val.clone();
             let val = {
               let mut de = Dedup::new(&args);
               let nh = NodeHasher::new(&self.lc, self.format_env(), self.fspan(sp));
@@ -789,6 +866,22 @@ impl Elaborator {
                   return Err(ElabError::new_e(sp, format!("dummy variables {{{}}} are unbound",
                     dummy_deps.iter().sorted().format(", "))))
                 }
+                if self.dep_policy != DepPolicy::Infer {
+                  let fsp = self.fspan(sp);
+                  for &(a, _) in &args {
+                    let a = if let Some(a) = a {a} else {continue};
+                    if ba.map.get(&a).map_or(true, |&i| deps & i == 0) { continue }
+                    let (sp1, msg) = match dep_witness(&val_e, a) {
+                      Some(w) => (try_get_span(&fsp, &w), format!(
+                        "inferred dependency of the return type on {{{}}}, because of subterm {}",
+                        self.data[a].name, self.format_env().pp(&w, self.pp.width))),
+                      None => (sp, format!("inferred dependency of the return type on {{{}}}",
+                        self.data[a].name)),
+                    };
+                    if let Some(e) = self.dep_policy.report(sp1, msg) { report!(e) }
+                  }
+                  if error { return Ok(()) }
+                }
                 ((s, deps), TermKind::Def(Some(val)))
               }
               Some((sp, s2, ref deps2)) => {
@@ -811,13 +904,15 @@ impl Elaborator {
           }
         };
         if atom != AtomId::UNDER {
-          let tid = self.env.add_term(Term {
+          let t = Term {
             atom, args: args.into(), ret, kind,
             span: self.fspan(d.id),
             doc,
             vis: d.mods,
             full,
-          }).map_err(|e| e.into_elab_error(d.id))?;
+          };
+          self.report_sort_mods(d.id, self.env.audit_term(&t));
+          let tid = self.env.add_term(t).map_err(|e| e.into_elab_error(d.id))?;
           self.spans.insert(d.id, ObjectKind::Term(tid, d.id));
         }
       }
@@ -825,7 +920,7 @@ impl Elaborator {
         if d.val.is_none() {
           for bi in &d.bis {
             if bi.kind == LocalKind::Dummy {
-              self.report(ElabError::warn(bi.local.unwrap_or(bi.span), "useless dummy variable"))
+              self.report(ElabError::warn(bi.local.unwrap_or(bi.span), "useless dummy variable").with_code(ErrorCode::UselessDummy))
             }
           }
         }
@@ -842,11 +937,11 @@ impl Elaborator {
           if let Some(v) = &d.val {report!(v.span, "axiom declarations have no definition")}
         } else if let Some(v) = &d.val {
           if self.mm0_mode {
-            self.report(ElabError::warn(v.span, "(MM0 mode) theorems should not have proofs"))
+            self.report(ElabError::warn(v.span, "(MM0 mode) theorems should not have proofs").with_code(ErrorCode::Mm0Mode))
           }
         } else if self.mm0_mode {
         } else {
-          self.report(ElabError::warn(d.id, "theorem declaration missing value"))
+          self.report(ElabError::warn(d.id, "theorem declaration missing value").with_code(ErrorCode::MissingValue))
         }
         for e in self.finalize_vars(false) {report!(e)}
         if error {return Ok(())}
@@ -895,30 +990,45 @@ impl Elaborator {
                 }
                 let g = LispVal::new_ref(LispVal::goal(self.fspan(e.span), e_ret));
                 self.lc.goals = vec![g.clone()];
+                self.goal_created(e.span, &g);
                 self.elab_lisp(e)?;
                 if !self.lc.goals.is_empty() {
                   let stat = self.stat();
                   self.call_goal_listener(&stat);
                 }
                 for g in mem::take(&mut self.lc.goals) {
-                  report!(try_get_span(&span, &g),
-                    format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), 80)))
+                  report!(ElabError::new_e(try_get_span(&span, &g),
+                    format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), self.pp.width)))
+                    .with_code(ErrorCode::UnsolvedGoal))
                 }
                 if error {return Ok(None)}
+                if self.lc.sorries != 0 {
+                  self.report(ElabError::warn(d.id, "declaration uses sorry").with_code(ErrorCode::Sorry));
+                  return Ok(None)
+                }
+                if self.suggest_proofs && self.span(e.span).first() != Some(&b'\'') {
+                  let text = format!("'(:verb {})", self.print(&g));
+                  self.suggest(e.span, &text);
+                }
                 let nh = NodeHasher {var_map, fsp, fe: self.format_env(), lc: &self.lc};
                 let ip = de.dedup(&nh, ProofKind::Proof, &g)?;
+                let spans = de.source_map(&is2, ip);
                 let (mut ids, heap) = build(&de);
                 let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-                Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
+                Ok(Some(Proof {heap, hyps, head: ids[ip].take(), spans}))
               })().unwrap_or_else(|e| {self.report(e); None})
             } else {None}
           })
         };
         if atom != AtomId::UNDER {
-          let tid = self.env.add_thm(Thm {
+          let mut t = Thm {
             atom, span, vis: d.mods, full, doc,
             args: args.into(), heap, hyps, ret, kind
-          }).map_err(|e| e.into_elab_error(d.id))?;
+          };
+          self.minimize_thm(d.id, &mut t);
+          self.report_sort_mods(d.id, self.env.audit_thm(&t));
+          let tid = self.env.add_thm(t).map_err(|e| e.into_elab_error(d.id))?;
+          if d.val.is_some() && !self.check_proofs { self.env.unchecked.insert(tid); }
           self.spans.insert(d.id, ObjectKind::Thm(tid));
         }
       }
@@ -969,7 +1079,7 @@ fn dummies(fe: FormatEnv<'_>, fsp: &FileSpan, lc: &mut LocalContext, e: &LispVal
   let mut dummy = |x: AtomId, es: &LispKind| -> Result<()> {
     let s = es.as_atom().ok_or_else(|| ElabError::new_e(sp!(es), "expected an atom"))?;
     let sort = fe.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(es),
-      format!("unknown sort '{}'", fe.to(&s))))?;
+      format!("unknown sort '{}'", fe.to(&s))).with_code(ErrorCode::UnknownSort))?;
     if x != AtomId::UNDER {lc.vars.insert(x, (true, InferSort::Bound(sort)));}
     Ok(())
   };
@@ -1019,7 +1129,7 @@ impl Elaborator {
         let a = if a == AtomId::UNDER {None} else {Some(a)};
         let s = es.as_atom().ok_or_else(|| ElabError::new_e(sp!(es), "expected an atom"))?;
         let sort = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(es),
-          format!("unknown sort '{}'", self.print(&s))))?;
+          format!("unknown sort '{}'", self.print(&s))).with_code(ErrorCode::UnknownSort))?;
         let (is, ty) = match u.next() {
           None => {
             if let Some(a) = a {
@@ -1076,14 +1186,14 @@ impl Elaborator {
     let (mut lc, args) = self.binders(fsp, Uncons::from(args.clone()), &mut vars)?;
     let ret = if let Some(s) = ret.as_atom() {
       let s = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(ret),
-        format!("unknown sort '{}'", self.print(&s))))?;
+        format!("unknown sort '{}'", self.print(&s))).with_code(ErrorCode::UnknownSort))?;
       (s, 0)
     } else {
       let mut u = Uncons::from(ret.clone());
       if let (Some(e), Some(vs)) = (u.next(), u.next()) {
         let s = e.as_atom().ok_or_else(|| ElabError::new_e(sp!(e), "expected an atom"))?;
         let s = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp!(e),
-          format!("unknown sort '{}'", self.print(&s))))?;
+          format!("unknown sort '{}'", self.print(&s))).with_code(ErrorCode::UnknownSort))?;
         (s, self.deps(fsp, &vars.0, vs)?.1)
       } else {
         return Err(ElabError::new_e(sp!(ret), format!("syntax error: {}", self.print(ret))))
@@ -1108,11 +1218,19 @@ impl Elaborator {
       })))
     } else {(Modifiers::NONE, TermKind::Term)};
     let full = fsp.span;
-    self.env.add_term(Term {atom: x, span, full, vis, doc: None, args, ret, kind})
-      .map_err(|e| e.into_elab_error(full))?;
+    let t = Term {atom: x, span, full, vis, doc: None, args, ret, kind};
+    self.report_sort_mods(full, self.env.audit_term(&t));
+    self.env.add_term(t).map_err(|e| e.into_elab_error(full))?;
     Ok(())
   }
 
+  /// Report the sort modifier violations found by [`Environment::audit_term`] or
+  /// [`Environment::audit_thm`] for a declaration about to be added. These should already
+  /// have been caught during elaboration, so this is a safety net.
+  fn report_sort_mods(&mut self, sp: Span, errs: Vec<String>) {
+    for msg in errs { self.report(ElabError::new_e(sp, msg)) }
+  }
+
   /// Parse and add a term/def declaration (this is called by the `(add-thm!)` lisp function).
   ///
   /// This function may either complete successfully, in which case it returns `Ok(Ok(()))`,
@@ -1186,8 +1304,10 @@ impl Elaborator {
           Some(de.add(ProofKind::Proof, p, ProofHash::Hyp(i, j)))
         }).collect();
         if proof.is_proc() {
-          lc.set_goals(Some(LispVal::goal(fsp, e_ret)).into_iter());
+          lc.set_goals(Some(LispVal::goal(fsp.clone(), e_ret)).into_iter());
           let lc = Box::new(mem::replace(&mut self.lc, lc));
+          let g = self.lc.goals[0].clone();
+          self.goal_created(fsp.span, &g);
           return Ok(Err((AwaitingProof {thm, de, var_map, lc, is}, proof)))
         }
         Some(ThmVal {de, var_map, lc: Some(Box::new(lc)), is, proof})
@@ -1200,6 +1320,7 @@ impl Elaborator {
   #[allow(clippy::option_option)]
   fn finish_add_thm(&mut self, fsp: &FileSpan, mut t: Thm, res: Option<Option<ThmVal>>) -> Result<()> {
     macro_rules! sp {($e:expr) => {$e.fspan().unwrap_or(fsp.clone()).span}}
+    let unchecked = matches!(res, Some(None));
     t.kind = match res {
       None => ThmKind::Axiom,
       Some(res) => ThmKind::Thm(res.and_then(|ThmVal {mut de, var_map, mut lc, is: is2, proof: e}| {
@@ -1210,13 +1331,15 @@ impl Elaborator {
             _ => return Err(ElabError::new_e(sp!(e), "bad proof format, expected (ds proof)"))
           };
           let lc = lc.as_deref_mut().unwrap_or(&mut self.lc);
+          if lc.sorries != 0 { return Ok(None) }
           let fe = FormatEnv {source: &self.ast.source, env: &self.env};
           dummies(fe, fsp, lc, &ds)?;
           let nh = NodeHasher {var_map, lc, fe, fsp: fsp.clone()};
           let ip = de.dedup(&nh, ProofKind::Proof, &pf)?;
+          let spans = de.source_map(&is2, ip);
           let (mut ids, heap) = build(&de);
           let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-          Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
+          Ok(Some(Proof {heap, hyps, head: ids[ip].take(), spans}))
         })().unwrap_or_else(|e| {
           self.report(ElabError::new_e(e.pos,
             format!("while adding {}: {}", self.print(&t.atom), e.kind.msg())));
@@ -1225,7 +1348,24 @@ impl Elaborator {
       }))
     };
     let sp = fsp.span;
-    self.env.add_thm(t).map_err(|e| e.into_elab_error(sp))?;
+    self.minimize_thm(sp, &mut t);
+    self.report_sort_mods(sp, self.env.audit_thm(&t));
+    let tid = self.env.add_thm(t).map_err(|e| e.into_elab_error(sp))?;
+    if unchecked { self.env.unchecked.insert(tid); }
     Ok(())
   }
+
+  /// Run the proof minimizer on a theorem before it is added, if `--minimize` is enabled.
+  fn minimize_thm(&mut self, sp: Span, t: &mut Thm) {
+    if let Some(budget) = crate::get_minimize() {
+      let deadline = std::time::Instant::now() + budget;
+      match super::minimize::minimize(&self.env, t, deadline) {
+        Ok(None) => {}
+        Ok(Some((old, new))) => self.report(ElabError::info(sp,
+          format!("proof shortened from {} to {} steps", old, new))),
+        Err(e) => self.report(ElabError::warn(sp,
+          format!("shortened proof failed to check, keeping the original: {}", e))),
+      }
+    }
+  }
 }
\ No newline at end of file
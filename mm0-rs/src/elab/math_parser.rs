@@ -10,12 +10,13 @@
 use std::ops::{Deref, DerefMut};
 use std::mem;
 use std::fmt::{self, Display};
+use num::BigUint;
 use mm1_parser::{Parser, ParseError, ident_start, ident_rest, whitespace};
 use crate::elab::{Elaborator, ElabError, ObjectKind};
 use crate::elab::ast::{Formula, SExpr};
 use crate::elab::lisp::print::{EnvDisplay, FormatEnv};
 use crate::elab::spans::Spans;
-use crate::{SliceUninit, Span, Literal, ParserEnv, Prec, TermId, APP_PREC};
+use crate::{ArcString, SliceUninit, Span, Literal, ParserEnv, Prec, TermId, APP_PREC};
 
 /// A parsed math expression (quoted expression). This is like [`SExpr`] but it
 /// has a much simpler grammar.
@@ -45,6 +46,14 @@ pub enum QExprKind {
   /// the `add` term constructor, we know that it is a term constructor and
   /// we have ensured it has the right number of arguments.
   App(Span, TermId, Box<[QExpr]>),
+  /// A number literal `123`, which is elaborated using the numeral system for the
+  /// target sort (see `set-numeral`). This is only used for tokens that are not
+  /// declared as constants.
+  Number(BigUint),
+  /// A string literal `"foo"`, which is elaborated using the string system for the
+  /// target sort (see `set-string`). This is only used if the token starting with `"`
+  /// is not declared as a constant.
+  String(ArcString),
   /// An unquotation `,e`. Here `e` can be any lisp expression, and its
   /// interpretation depends on whether the formula is being evaluated or
   /// is being used as a pattern.
@@ -66,6 +75,8 @@ impl EnvDisplay for QExpr {
         for e in &**es {write!(f, " {}", fe.to(e))?}
         write!(f, ")")
       }
+      QExprKind::Number(n) => n.fmt(f),
+      QExprKind::String(s) => write!(f, "{:?}", s),
       QExprKind::Unquote(e) => write!(f, ",{}", fe.to(e))
     }
   }
@@ -177,6 +188,12 @@ impl<'a> MathParser<'a> {
         let e = self.sexpr()?;
         return Ok(QExpr {span: (start..e.span.end).into(), k: QExprKind::Unquote(e) })
       }
+      b'"' if !self.peek_token().0.map_or(false, |tk| self.pe.consts.contains_key(self.span(tk))) => {
+        let (span, s) = self.string()?;
+        self.idx = span.end;
+        self.ws();
+        return Ok(QExpr {span, k: QExprKind::String(s.into())})
+      }
       b'(' => {
         self.idx += 1;
         self.ws();
@@ -215,6 +232,13 @@ impl<'a> MathParser<'a> {
       }
       self.idx = start;
       return Ok(QExpr {span, k: QExprKind::IdentApp(sp, args.into_boxed_slice())})
+    } else if v.iter().all(u8::is_ascii_digit) {
+      let n = BigUint::parse_bytes(v, 10).expect("valid number");
+      return Ok(QExpr {span: sp, k: QExprKind::Number(n)})
+    } else if let Some(n) = v.strip_prefix(b"0x").filter(|h| !h.is_empty() && h.iter().all(u8::is_ascii_hexdigit)) {
+      // hex numerals, as printed with `(set-pp-options 'radix 16)`
+      let n = BigUint::parse_bytes(n, 16).expect("valid number");
+      return Ok(QExpr {span: sp, k: QExprKind::Number(n)})
     } else {}
     Err(ParseError::new(sp, format!("expecting prefix expression >= {}", p).into()))
   }
@@ -0,0 +1,336 @@
+//! A proof shortening pass, in the style of the metamath `MINIMIZE_WITH` command.
+//!
+//! After a theorem's proof has been elaborated, we look for subproofs that consist of
+//! more than one step, and try to replace them by a single application of an existing
+//! theorem whose conclusion matches the statement of the subproof, and whose hypotheses
+//! are discharged by hypotheses of the theorem or by subproofs that already exist
+//! in the proof. The candidate theorems are looked up in [`Environment::thm_index`].
+//! The search is bounded by a deadline, after which the proof is left as it is
+//! (including any replacements made so far). The shortened proof is checked with
+//! [`verify`](super::verify::verify) before it replaces the original.
+//!
+//! This is enabled by the `--minimize` option of `mm0-rs compile`.
+
+use std::time::Instant;
+use crate::{AtomId, Environment, ExprNode, Proof, ProofNode, SortId, Thm, ThmKind, Type};
+use super::disc_tree::Key;
+
+struct Minimizer<'a> {
+  env: &'a Environment,
+  /// The binders of the theorem being minimized.
+  args: &'a [(Option<AtomId>, Type)],
+  /// `bound[i]` is the index of variable `i` among the bound variables, if it is bound.
+  bound: Vec<Option<u32>>,
+  /// The original heap. Replacements preserve the statement proven by each heap element,
+  /// so this can be used to look up expressions throughout the process.
+  heap: &'a [ProofNode],
+  /// The proof facts available, as pairs `(stmt, pf)` where `pf` proves `|- stmt`,
+  /// and the maximum heap index that `pf` refers to.
+  facts: Vec<(&'a ProofNode, ProofNode, Option<usize>)>,
+  deadline: Instant,
+}
+
+/// The largest heap reference that appears (syntactically) in the node.
+fn max_ref(p: &ProofNode) -> Option<usize> {
+  match p {
+    &ProofNode::Ref(i) => Some(i),
+    ProofNode::Dummy(..) => None,
+    ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} => args.iter().filter_map(max_ref).max(),
+    ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => max_ref(p),
+    ProofNode::Thm {args, res, ..} => args.iter().chain(Some(&**res)).filter_map(max_ref).max(),
+    ProofNode::Conv(p) => [&p.0, &p.1, &p.2].iter().copied().filter_map(max_ref).max(),
+    ProofNode::Unfold {args, res, ..} =>
+      args.iter().chain([&res.0, &res.1].iter().copied()).filter_map(max_ref).max(),
+  }
+}
+
+/// Count the number of theorem applications in a node, not following heap references.
+fn count_steps(p: &ProofNode) -> usize {
+  match p {
+    ProofNode::Thm {args, ..} => 1 + args.iter().map(count_steps).sum::<usize>(),
+    ProofNode::Conv(p) => count_steps(&p.2),
+    ProofNode::Hyp(..) | ProofNode::Ref(_) | ProofNode::Dummy(..) | ProofNode::Term {..} |
+    ProofNode::Refl(_) | ProofNode::Sym(_) | ProofNode::Cong {..} | ProofNode::Unfold {..} => 0,
+  }
+}
+
+/// The number of theorem applications in a proof.
+#[must_use] pub fn proof_size(pf: &Proof) -> usize {
+  pf.heap.iter().chain(Some(&pf.head)).map(count_steps).sum()
+}
+
+impl<'a> Minimizer<'a> {
+  /// Follow heap references (other than variables).
+  fn deref(&self, mut p: &'a ProofNode) -> &'a ProofNode {
+    while let ProofNode::Ref(i) = *p {
+      if i < self.args.len() { break }
+      p = &self.heap[i]
+    }
+    p
+  }
+
+  /// The statement proven by a node, if it is a proof.
+  fn stmt(&self, p: &'a ProofNode) -> Option<&'a ProofNode> {
+    match self.deref(p) {
+      ProofNode::Thm {res, ..} => Some(&**res),
+      ProofNode::Hyp(_, e) => Some(&**e),
+      ProofNode::Conv(p) => Some(&p.0),
+      _ => None,
+    }
+  }
+
+  /// Count the number of theorem applications in a node, following heap references.
+  fn deep_steps(&self, p: &ProofNode) -> usize {
+    match p {
+      &ProofNode::Ref(i) if i >= self.args.len() => self.deep_steps(&self.heap[i]),
+      ProofNode::Thm {args, ..} => 1 + args.iter().map(|p| self.deep_steps(p)).sum::<usize>(),
+      ProofNode::Conv(p) => self.deep_steps(&p.2),
+      _ => 0,
+    }
+  }
+
+  /// Flatten an expression for lookup in the theorem index.
+  fn flatten(&self, e: &ProofNode, out: &mut Vec<Key>) {
+    match self.deref(e) {
+      ProofNode::Term {term, args} => {
+        out.push(Key::App(*term, args.len()));
+        for e in &**args { self.flatten(e, out) }
+      }
+      _ => out.push(Key::Var),
+    }
+  }
+
+  fn sort(&self, e: &ProofNode) -> Option<SortId> {
+    match *self.deref(e) {
+      ProofNode::Ref(i) => Some(self.args[i].1.sort()),
+      ProofNode::Dummy(_, s) => Some(s),
+      ProofNode::Term {term, ..} => Some(self.env.terms[term].ret.0),
+      _ => None,
+    }
+  }
+
+  fn is_bound(&self, e: &ProofNode) -> bool {
+    match *self.deref(e) {
+      ProofNode::Ref(i) => self.bound[i].is_some(),
+      ProofNode::Dummy(..) => true,
+      _ => false,
+    }
+  }
+
+  fn eq(&self, e1: &ProofNode, e2: &ProofNode) -> bool {
+    match (self.deref(e1), self.deref(e2)) {
+      (ProofNode::Ref(i), ProofNode::Ref(j)) => i == j,
+      (ProofNode::Dummy(a, _), ProofNode::Dummy(b, _)) => a == b,
+      (ProofNode::Term {term: t1, args: a1}, ProofNode::Term {term: t2, args: a2}) =>
+        t1 == t2 && a1.iter().zip(&**a2).all(|(e1, e2)| self.eq(e1, e2)),
+      _ => false,
+    }
+  }
+
+  /// Returns true if the expression `e` may depend on the bound variable `v`.
+  fn depends(&self, e: &ProofNode, v: &ProofNode) -> bool {
+    if self.eq(e, v) { return true }
+    match (self.deref(e), self.deref(v)) {
+      (&ProofNode::Ref(i), &ProofNode::Ref(j)) => match (self.args[i].1, self.bound[j]) {
+        (Type::Reg(_, deps), Some(n)) => deps & (1 << n) != 0,
+        _ => false
+      },
+      (ProofNode::Term {args, ..}, _) => args.iter().any(|e| self.depends(e, v)),
+      _ => false
+    }
+  }
+
+  /// Match the pattern `pat` from the statement of a theorem (with heap `uheap`
+  /// and binders `bis`) against the expression `e`, extending the substitution.
+  fn matches(&self, bis: &[(Option<AtomId>, Type)], uheap: &[ExprNode], pat: &ExprNode,
+    e: &'a ProofNode, subst: &mut [Option<&'a ProofNode>]
+  ) -> bool {
+    match *pat {
+      ExprNode::Ref(i) if i < bis.len() => match subst[i] {
+        Some(e2) => self.eq(e, e2),
+        None => {
+          let ty = bis[i].1;
+          if self.sort(e) != Some(ty.sort()) || ty.bound() && !self.is_bound(e) { return false }
+          subst[i] = Some(e);
+          true
+        }
+      },
+      ExprNode::Ref(i) => self.matches(bis, uheap, &uheap[i], e, subst),
+      ExprNode::Dummy(..) => false,
+      ExprNode::App(t, ref es) => match self.deref(e) {
+        ProofNode::Term {term, args} if *term == t && args.len() == es.len() =>
+          es.iter().zip(&**args).all(|(pat, e)| self.matches(bis, uheap, pat, e, subst)),
+        _ => false
+      }
+    }
+  }
+
+  /// Check the disjoint variable conditions of the binders `bis` under substitution `es`.
+  fn check_dv(&self, bis: &[(Option<AtomId>, Type)], es: &[&ProofNode]) -> bool {
+    let mut bvs: Vec<&ProofNode> = vec![];
+    for (&(_, ty), &e) in bis.iter().zip(es) {
+      match ty {
+        Type::Bound(_) => {
+          if bvs.iter().any(|v| self.eq(v, e)) { return false }
+          bvs.push(e)
+        }
+        Type::Reg(_, deps) => for (n, v) in bvs.iter().enumerate() {
+          if deps & (1 << n) == 0 && self.depends(e, v) { return false }
+        }
+      }
+    }
+    true
+  }
+
+  /// Try to prove `|- res` using a single application of some existing theorem,
+  /// using only heap references less than `limit` (if given).
+  fn find(&self, res: &'a ProofNode, limit: Option<usize>) -> Option<ProofNode> {
+    if !matches!(self.deref(res), ProofNode::Term {..}) { return None }
+    let mut keys = vec![];
+    self.flatten(res, &mut keys);
+    for u in self.env.thm_index.unifiable(&keys) {
+      if Instant::now() > self.deadline { return None }
+      let td = &self.env.thms[u];
+      let mut subst = vec![None; td.args.len()];
+      if !self.matches(&td.args, &td.heap, &td.ret, res, &mut subst) { continue }
+      let subst = if let Some(s) = subst.into_iter().collect::<Option<Vec<_>>>() {s} else {continue};
+      if !self.check_dv(&td.args, &subst) { continue }
+      let mut args = subst.iter().map(|&e| e.clone()).collect::<Vec<_>>();
+      let ok = td.hyps.iter().all(|(_, h)| {
+        self.facts.iter().find(|&&(stmt, _, max)|
+          limit.map_or(true, |l| max.map_or(true, |m| m < l)) &&
+          self.matches(&td.args, &td.heap, h, stmt, &mut subst.iter().map(|&e| Some(e)).collect::<Vec<_>>())
+        ).map(|(_, pf, _)| args.push(pf.clone())).is_some()
+      });
+      if ok {
+        return Some(ProofNode::Thm {thm: u, args: args.into(), res: Box::new(res.clone())})
+      }
+    }
+    None
+  }
+
+  /// Traverse the node `p`, which is the original node at the same position, replacing
+  /// subproofs where possible. Returns true if anything was changed.
+  fn visit(&self, p: &mut ProofNode, orig: &'a ProofNode, limit: Option<usize>) -> bool {
+    match orig {
+      ProofNode::Thm {thm, args: oargs, res} => {
+        if self.deep_steps(orig) > 1 {
+          if let Some(q) = self.find(res, limit) {
+            *p = q;
+            return true
+          }
+        }
+        let nargs = self.env.thms[*thm].args.len();
+        let mut changed = false;
+        if let ProofNode::Thm {args, ..} = p {
+          for (p, orig) in args.iter_mut().zip(&**oargs).skip(nargs) {
+            changed |= self.visit(p, orig, limit)
+          }
+        }
+        changed
+      }
+      ProofNode::Conv(orig) => match p {
+        ProofNode::Conv(p) => self.visit(&mut p.2, &orig.2, limit),
+        _ => false,
+      },
+      _ => false,
+    }
+  }
+}
+
+/// Drop the heap elements which are not reachable from the head or hypotheses
+/// of the proof, renumbering the remaining references.
+fn compact(pf: &mut Proof, nargs: usize) {
+  fn mark(heap: &[ProofNode], live: &mut [bool], p: &ProofNode) {
+    match p {
+      &ProofNode::Ref(i) => if !live[i] {
+        live[i] = true;
+        mark(heap, live, &heap[i])
+      },
+      ProofNode::Dummy(..) => {}
+      ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} =>
+        for p in &**args { mark(heap, live, p) },
+      ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => mark(heap, live, p),
+      ProofNode::Thm {args, res, ..} => {
+        for p in &**args { mark(heap, live, p) }
+        mark(heap, live, res)
+      }
+      ProofNode::Conv(p) => { mark(heap, live, &p.0); mark(heap, live, &p.1); mark(heap, live, &p.2) }
+      ProofNode::Unfold {args, res, ..} => {
+        for p in &**args { mark(heap, live, p) }
+        mark(heap, live, &res.0);
+        mark(heap, live, &res.1)
+      }
+    }
+  }
+  fn remap(map: &[usize], p: &mut ProofNode) {
+    match p {
+      ProofNode::Ref(i) => *i = map[*i],
+      ProofNode::Dummy(..) => {}
+      ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} =>
+        for p in &mut **args { remap(map, p) },
+      ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => remap(map, p),
+      ProofNode::Thm {args, res, ..} => {
+        for p in &mut **args { remap(map, p) }
+        remap(map, res)
+      }
+      ProofNode::Conv(p) => { remap(map, &mut p.0); remap(map, &mut p.1); remap(map, &mut p.2) }
+      ProofNode::Unfold {args, res, ..} => {
+        for p in &mut **args { remap(map, p) }
+        remap(map, &mut res.0);
+        remap(map, &mut res.1)
+      }
+    }
+  }
+  let mut live = vec![false; pf.heap.len()];
+  for b in &mut live[..nargs] { *b = true }
+  for p in pf.hyps.iter().chain(Some(&pf.head)) { mark(&pf.heap, &mut live, p) }
+  if live.iter().all(|&b| b) { return }
+  let mut map = vec![usize::MAX; live.len()];
+  let mut heap = Vec::with_capacity(live.len());
+  for (i, p) in std::mem::take(&mut pf.heap).into_vec().into_iter().enumerate() {
+    if live[i] {
+      map[i] = heap.len();
+      heap.push(p)
+    }
+  }
+  for p in heap.iter_mut().chain(&mut *pf.hyps).chain(Some(&mut pf.head)) { remap(&map, p) }
+  pf.heap = heap.into();
+}
+
+/// Attempt to shorten the proof of the theorem `td`, using the theorems in `env`,
+/// until `deadline`. If a shorter proof is found and it checks, the proof of `td` is
+/// updated and the old and new proof sizes (in theorem applications) are returned.
+/// If the shorter proof does not check, `td` is left unchanged and the error is returned.
+pub fn minimize(env: &Environment, td: &mut Thm, deadline: Instant
+) -> Result<Option<(usize, usize)>, String> {
+  let pf = if let ThmKind::Thm(Some(pf)) = &mut td.kind {pf} else {return Ok(None)};
+  let args = &td.args;
+  let nargs = args.len();
+  let mut n = 0;
+  let bound = args.iter().map(|(_, ty)| if ty.bound() { n += 1; Some(n - 1) } else { None }).collect();
+  let orig = pf.clone();
+  let mut mz = Minimizer {env, args, bound, heap: &orig.heap, facts: vec![], deadline};
+  for h in &*orig.hyps {
+    if let Some(stmt) = mz.stmt(h) { mz.facts.push((stmt, h.clone(), max_ref(h))) }
+  }
+  for (i, p) in orig.heap.iter().enumerate().skip(nargs) {
+    if let Some(stmt) = mz.stmt(p) { mz.facts.push((stmt, ProofNode::Ref(i), Some(i))) }
+  }
+  let mut new = orig.clone();
+  let mut changed = false;
+  for (i, (p, o)) in new.heap.iter_mut().zip(&*orig.heap).enumerate().skip(nargs) {
+    changed |= mz.visit(p, o, Some(i))
+  }
+  changed |= mz.visit(&mut new.head, &orig.head, None);
+  if !changed { return Ok(None) }
+  compact(&mut new, nargs);
+  let (old_size, new_size) = (proof_size(&orig), proof_size(&new));
+  if new_size >= old_size { return Ok(None) }
+  *pf = new;
+  if let Err(e) = super::verify::verify(env, td) {
+    td.kind = ThmKind::Thm(Some(orig));
+    return Err(e)
+  }
+  Ok(Some((old_size, new_size)))
+}
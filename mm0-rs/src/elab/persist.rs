@@ -0,0 +1,1029 @@
+//! Saving an elaborated [`Environment`] to a file and loading it back.
+//!
+//! This is used to keep elaboration results across runs: the language server saves the
+//! environments of the files it has elaborated when it shuts down, and loads them on the
+//! next start instead of elaborating the files again, as long as the files and their
+//! imports have not changed, and `mm0-rs compile` keeps the environment of the prelude
+//! next to it (see [`set_prelude_snapshot`](crate::set_prelude_snapshot)). Everything in the environment is saved, including the lisp
+//! globals and the code of the lisp procedures, except for the spans used for hovers,
+//! which are only needed for a file that is open in the editor (and such a file is
+//! always elaborated again).
+//!
+//! A saved environment starts with a [`Header`] listing the files that it was elaborated
+//! from together with the hashes of their contents, and the settings that affect
+//! elaboration. The environment is only valid if these are still the same, which is
+//! checked by [`Header::is_current`].
+//!
+//! The lisp values are a graph, which can share subterms and can have cycles through
+//! references. Each value is written once and later occurrences refer back to it by
+//! index, and the contents of references are written after the value that contains them,
+//! so that a reference can point (weakly) to a closure that captures it, as in a `letrec`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use num::{BigInt, BigUint};
+use serde::{Deserialize, Serialize};
+use crate::{ArcString, AtomData, AtomId, AtomVec, Coe, DeclKey, DocComment, Environment,
+  Expr, ExprNode, FileRef, FileSpan, FrozenEnv, LispData, LispKind, LispVal, Literal,
+  MergeStrategyInner, Modifiers, NotaInfo, Numeral, OutputString, ParserEnv, Prec, Proof,
+  ProofNode, Sort, SortId, SortVec, Span, StmtTrace, StringLit, Term, TermId, TermKind,
+  TermVec, Thm, ThmId, ThmKind, ThmVec, TransferMap, Type};
+use super::environment::Delims;
+use super::lisp::{Annot, BuiltinProc, InferTarget, Proc, ProcPos, ProcSpec, Syntax,
+  parser::{Branch, Ir, MVarPattern, Pattern}};
+
+/// The first bytes of a saved environment. The last byte is the version of the format.
+const MAGIC: &[u8; 8] = b"MM0-ENV\x02";
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// The description of what a saved environment was elaborated from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+  /// The version of `mm0-rs` that saved the environment.
+  pub version: String,
+  /// The settings that affect elaboration (see [`settings_key`](crate::settings_key)).
+  pub settings: String,
+  /// The direct imports of the file, including the prelude.
+  pub deps: Vec<PathBuf>,
+  /// The file itself and all the files it imports, directly or indirectly, with the
+  /// hashes of their contents.
+  pub files: Vec<(PathBuf, String)>,
+}
+
+impl Header {
+  /// Create a header for an environment elaborated with the current settings.
+  #[must_use] pub fn new(deps: Vec<PathBuf>, files: Vec<(PathBuf, String)>) -> Self {
+    Self {
+      version: env!("CARGO_PKG_VERSION").into(),
+      settings: crate::settings_key(),
+      deps, files,
+    }
+  }
+
+  /// Returns true if the environment can be used in place of elaborating the files again,
+  /// that is, it was saved by this version with the current settings, and `hash` returns
+  /// the recorded hash for each file.
+  pub fn is_current(&self, mut hash: impl FnMut(&PathBuf) -> Option<String>) -> bool {
+    self.version == env!("CARGO_PKG_VERSION") && self.settings == crate::settings_key() &&
+    self.files.iter().all(|(path, h)| hash(path).map_or(false, |h2| *h == h2))
+  }
+}
+
+/// Write the environment `env` with header `header` to `w`. This fails if the lisp
+/// globals contain values that can't be saved, like the MMC compiler state.
+pub fn save(mut w: impl Write, header: &Header, env: &FrozenEnv) -> io::Result<()> {
+  // Safety: the environment is only read
+  let env = unsafe { env.thaw() };
+  let header = serde_json::to_vec(header)?;
+  let mut s = Saver::default();
+  s.usize(header.len());
+  s.buf.extend_from_slice(&header);
+  env.save(&mut s)?;
+  w.write_all(MAGIC)?;
+  w.write_all(&s.buf)?;
+  w.flush()
+}
+
+/// Read a saved environment from `r`, returning only the header. This is cheap, and can
+/// be used to decide whether the environment should be loaded using [`load`].
+pub fn load_header(mut r: impl Read) -> io::Result<Header> {
+  let mut data = vec![0; MAGIC.len()];
+  r.read_exact(&mut data)?;
+  if *data != *MAGIC { return Err(invalid("not a saved environment")) }
+  let mut len = 0;
+  for i in 0.. {
+    let mut b = [0];
+    r.read_exact(&mut b)?;
+    len |= usize::from(b[0] & 0x7f).checked_shl(7 * i).ok_or_else(|| invalid("bad length"))?;
+    if b[0] & 0x80 == 0 { break }
+  }
+  data.resize(len, 0);
+  r.read_exact(&mut data)?;
+  Ok(serde_json::from_slice(&data)?)
+}
+
+/// Load an environment saved by [`save`] from `data`, together with its header.
+pub fn load(data: &[u8]) -> io::Result<(Header, Environment)> {
+  let data = data.strip_prefix(MAGIC).ok_or_else(|| invalid("not a saved environment"))?;
+  let mut l = Loader {data, ..Loader::default()};
+  let len = l.usize()?;
+  let header = serde_json::from_slice(l.bytes(len)?)?;
+  let env = Environment::load(&mut l)?;
+  if !l.data.is_empty() { return Err(invalid("trailing data")) }
+  Ok((header, env))
+}
+
+/// The state of the writer of a saved environment.
+#[derive(Default, Debug)]
+pub struct Saver {
+  /// The output.
+  buf: Vec<u8>,
+  /// The files that have been written, by index.
+  files: HashMap<FileRef, usize>,
+  /// The lisp values that have been written, by index. The values are kept alive so
+  /// that their addresses are not reused.
+  vals: HashMap<*const LispKind, usize>,
+  /// The lisp values in `vals`.
+  keep: Vec<LispVal>,
+  /// The lisp procedure bodies that have been written, by index.
+  irs: HashMap<*const Ir, usize>,
+  /// The lisp procedure bodies in `irs`.
+  keep_irs: Vec<Arc<Ir>>,
+  /// The references whose contents have not been written yet.
+  refs: VecDeque<LispVal>,
+  /// The nesting depth of lisp values that are being written.
+  depth: usize,
+}
+
+/// The state of the reader of a saved environment.
+#[derive(Default, Debug)]
+pub struct Loader<'a> {
+  /// The remaining input.
+  data: &'a [u8],
+  /// The files that have been read.
+  files: Vec<FileRef>,
+  /// The lisp values that have been read.
+  vals: Vec<LispVal>,
+  /// The lisp procedure bodies that have been read.
+  irs: Vec<Arc<Ir>>,
+  /// The references whose contents have not been read yet.
+  refs: VecDeque<LispVal>,
+  /// The nesting depth of lisp values that are being read.
+  depth: usize,
+}
+
+impl Saver {
+  fn u8(&mut self, n: u8) { self.buf.push(n) }
+  fn u64(&mut self, mut n: u64) {
+    while n >= 0x80 {
+      self.buf.push((n & 0x7f) as u8 | 0x80);
+      n >>= 7;
+    }
+    self.buf.push(n as u8)
+  }
+  fn usize(&mut self, n: usize) { self.u64(n as u64) }
+  fn bytes(&mut self, s: &[u8]) {
+    self.usize(s.len());
+    self.buf.extend_from_slice(s)
+  }
+
+  /// Write the contents of the references encountered so far. This is done when the
+  /// outermost value has been written, so that the value that a reference points to has
+  /// been registered even if it contains the reference.
+  fn flush_refs(&mut self) -> io::Result<()> {
+    self.depth += 1;
+    while let Some(r) = self.refs.pop_front() {
+      let (weak, v) = r.as_lref(|r| (matches!(*r.get_weak(), super::lisp::LispWeak::Weak(_)), r.unref()))
+        .expect("not a ref");
+      weak.save(self)?;
+      v.save(self)?
+    }
+    self.depth -= 1;
+    Ok(())
+  }
+}
+
+impl<'a> Loader<'a> {
+  fn u8(&mut self) -> io::Result<u8> {
+    let (&n, rest) = self.data.split_first().ok_or_else(|| invalid("unexpected end of data"))?;
+    self.data = rest;
+    Ok(n)
+  }
+  fn u64(&mut self) -> io::Result<u64> {
+    let mut n = 0;
+    for i in 0.. {
+      let b = self.u8()?;
+      n |= u64::from(b & 0x7f).checked_shl(7 * i).ok_or_else(|| invalid("bad number"))?;
+      if b & 0x80 == 0 { break }
+    }
+    Ok(n)
+  }
+  fn usize(&mut self) -> io::Result<usize> {
+    self.u64()?.try_into().map_err(|_| invalid("bad number"))
+  }
+  fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+    if self.data.len() < len { return Err(invalid("unexpected end of data")) }
+    let (s, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(s)
+  }
+  fn str(&mut self) -> io::Result<&'a str> {
+    let len = self.usize()?;
+    std::str::from_utf8(self.bytes(len)?).map_err(invalid)
+  }
+
+  /// Read the contents of the references encountered so far (see [`Saver::flush_refs`]).
+  fn flush_refs(&mut self) -> io::Result<()> {
+    self.depth += 1;
+    while let Some(r) = self.refs.pop_front() {
+      let weak = bool::load(self)?;
+      let v = LispVal::load(self)?;
+      r.as_lref(|r| if weak { r.set_weak(&v) } else { r.get_mut(|e| *e = v) });
+    }
+    self.depth -= 1;
+    Ok(())
+  }
+}
+
+/// A type that can be written to a saved environment.
+pub trait Persist: Sized {
+  /// Write `self` to `s`.
+  fn save(&self, s: &mut Saver) -> io::Result<()>;
+  /// Read a value written by [`save`](Self::save).
+  fn load(l: &mut Loader<'_>) -> io::Result<Self>;
+}
+
+impl Persist for bool {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.u8((*self).into()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? { 0 => Ok(false), 1 => Ok(true), _ => Err(invalid("bad bool")) }
+  }
+}
+impl Persist for u8 {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.u8(*self); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { l.u8() }
+}
+impl Persist for u32 {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.u64((*self).into()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    l.u64()?.try_into().map_err(|_| invalid("bad number"))
+  }
+}
+impl Persist for u64 {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.u64(*self); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { l.u64() }
+}
+impl Persist for usize {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.usize(*self); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { l.usize() }
+}
+impl Persist for BigInt {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.bytes(&self.to_signed_bytes_le()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let len = l.usize()?;
+    Ok(BigInt::from_signed_bytes_le(l.bytes(len)?))
+  }
+}
+impl Persist for BigUint {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.bytes(&self.to_bytes_le()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let len = l.usize()?;
+    Ok(BigUint::from_bytes_le(l.bytes(len)?))
+  }
+}
+impl Persist for ArcString {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.bytes(self); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let len = l.usize()?;
+    Ok(l.bytes(len)?.into())
+  }
+}
+impl Persist for DocComment {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.bytes(self.as_bytes()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok(l.str()?.into()) }
+}
+impl Persist for Span {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.usize(self.start); s.usize(self.end); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok((l.usize()?..l.usize()?).into()) }
+}
+impl Persist for FileRef {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    if let Some(&i) = s.files.get(self) {
+      s.usize(i + 1)
+    } else {
+      s.files.insert(self.clone(), s.files.len());
+      s.usize(0);
+      let path = self.path().to_str().ok_or_else(|| invalid("bad unicode in path"))?;
+      s.bytes(path.as_bytes())
+    }
+    Ok(())
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.usize()? {
+      0 => {
+        let file = FileRef::from(PathBuf::from(l.str()?));
+        l.files.push(file.clone());
+        Ok(file)
+      }
+      i => l.files.get(i - 1).cloned().ok_or_else(|| invalid("bad file"))
+    }
+  }
+}
+impl Persist for FileSpan {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { self.file.save(s)?; self.span.save(s) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(FileSpan {file: FileRef::load(l)?, span: Span::load(l)?})
+  }
+}
+impl Persist for Modifiers {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.u8(self.bits()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Modifiers::from_bits(l.u8()?).ok_or_else(|| invalid("bad modifiers"))
+  }
+}
+impl Persist for Prec {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match *self {
+      Prec::Prec(n) => { s.u8(0); n.save(s) }
+      Prec::Max => { s.u8(1); Ok(()) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(Prec::Prec(u32::load(l)?)),
+      1 => Ok(Prec::Max),
+      _ => Err(invalid("bad precedence"))
+    }
+  }
+}
+impl Persist for Delims {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    for c in 0..=u8::MAX { self.get(c).save(s)? }
+    Ok(())
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let mut d = Delims::default();
+    for c in 0..=u8::MAX { if bool::load(l)? { d.set(c) } }
+    Ok(d)
+  }
+}
+
+macro_rules! persist_id {
+  ($($id:ident: $ty:ty, $vec:ident;)*) => {$(
+    impl Persist for $id {
+      fn save(&self, s: &mut Saver) -> io::Result<()> { self.0.save(s) }
+      fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok($id(<$ty>::load(l)?)) }
+    }
+    impl<A: Persist> Persist for $vec<A> {
+      fn save(&self, s: &mut Saver) -> io::Result<()> { self.0.save(s) }
+      fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok($vec(Vec::load(l)?)) }
+    }
+  )*}
+}
+persist_id! {
+  SortId: u8, SortVec;
+  TermId: u32, TermVec;
+  ThmId: u32, ThmVec;
+  AtomId: u32, AtomVec;
+}
+
+impl<A: Persist> Persist for Option<A> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      None => { s.u8(0); Ok(()) }
+      Some(a) => { s.u8(1); a.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(None),
+      1 => Ok(Some(A::load(l)?)),
+      _ => Err(invalid("bad option"))
+    }
+  }
+}
+impl<A: Persist> Persist for Box<A> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { (**self).save(s) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok(Box::new(A::load(l)?)) }
+}
+impl<A: Persist> Persist for Rc<A> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { (**self).save(s) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok(Rc::new(A::load(l)?)) }
+}
+impl<A: Persist> Persist for Vec<A> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    s.usize(self.len());
+    self.iter().try_for_each(|a| a.save(s))
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let len = l.usize()?;
+    // The length is not trusted for the allocation, since the data may be corrupt.
+    let mut vec = Vec::with_capacity(len.min(l.data.len()));
+    for _ in 0..len { vec.push(A::load(l)?) }
+    Ok(vec)
+  }
+}
+impl<A: Persist> Persist for Box<[A]> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    s.usize(self.len());
+    self.iter().try_for_each(|a| a.save(s))
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok(Vec::load(l)?.into()) }
+}
+impl<A: Persist, B: Persist> Persist for (A, B) {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { self.0.save(s)?; self.1.save(s) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> { Ok((A::load(l)?, B::load(l)?)) }
+}
+impl<A: Persist, B: Persist, C: Persist, D: Persist> Persist for (A, B, C, D) {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    self.0.save(s)?; self.1.save(s)?; self.2.save(s)?; self.3.save(s)
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok((A::load(l)?, B::load(l)?, C::load(l)?, D::load(l)?))
+  }
+}
+impl<K: Persist + Eq + std::hash::Hash, V: Persist> Persist for HashMap<K, V> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    s.usize(self.len());
+    self.iter().try_for_each(|(k, v)| { k.save(s)?; v.save(s) })
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let len = l.usize()?;
+    let mut map = HashMap::with_capacity(len.min(l.data.len()));
+    for _ in 0..len { map.insert(K::load(l)?, V::load(l)?); }
+    Ok(map)
+  }
+}
+
+/// Implement [`Persist`] for a struct by writing its fields in order.
+macro_rules! persist_struct {
+  ($($ty:ident {$($field:ident),*};)*) => {$(
+    impl Persist for $ty {
+      fn save(&self, s: &mut Saver) -> io::Result<()> {
+        $(self.$field.save(s)?;)*
+        Ok(())
+      }
+      fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+        // Struct fields are evaluated in the order they are written
+        Ok($ty {$($field: Persist::load(l)?),*})
+      }
+    }
+  )*}
+}
+persist_struct! {
+  Sort {atom, name, span, full, doc, mods};
+  Expr {heap, head};
+  Term {atom, span, vis, full, doc, args, ret, kind};
+  Proof {heap, hyps, head, spans};
+  Thm {atom, span, vis, full, doc, args, heap, hyps, ret, kind};
+  OutputString {span, heap, exprs};
+  NotaInfo {span, term, nargs, rassoc, lits};
+  Numeral {zero, digits};
+  StringLit {nil, cons};
+  ParserEnv {delims_l, delims_r, consts, prec_assoc, prefixes, infixes, coes, coe_prov,
+    decl_nota, numerals, strings, implicits};
+  LispData {src, doc, val, merge};
+  TransferMap {sorts, terms, thms};
+  Branch {vars, cont, pat, eval};
+}
+
+impl Persist for Type {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match *self {
+      Type::Bound(sort) => { s.u8(0); sort.save(s) }
+      Type::Reg(sort, deps) => { s.u8(1); sort.save(s)?; deps.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(Type::Bound(SortId::load(l)?)),
+      1 => Ok(Type::Reg(SortId::load(l)?, u64::load(l)?)),
+      _ => Err(invalid("bad type"))
+    }
+  }
+}
+
+impl Persist for ExprNode {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      &ExprNode::Ref(i) => { s.u8(0); i.save(s) }
+      &ExprNode::Dummy(a, sort) => { s.u8(1); a.save(s)?; sort.save(s) }
+      ExprNode::App(t, es) => { s.u8(2); t.save(s)?; es.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(ExprNode::Ref(usize::load(l)?)),
+      1 => Ok(ExprNode::Dummy(AtomId::load(l)?, SortId::load(l)?)),
+      2 => Ok(ExprNode::App(TermId::load(l)?, Persist::load(l)?)),
+      _ => Err(invalid("bad expression"))
+    }
+  }
+}
+
+impl Persist for TermKind {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      TermKind::Term => { s.u8(0); Ok(()) }
+      TermKind::Def(e) => { s.u8(1); e.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(TermKind::Term),
+      1 => Ok(TermKind::Def(Persist::load(l)?)),
+      _ => Err(invalid("bad term"))
+    }
+  }
+}
+
+impl Persist for ProofNode {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      &ProofNode::Ref(i) => { s.u8(0); i.save(s) }
+      &ProofNode::Dummy(a, sort) => { s.u8(1); a.save(s)?; sort.save(s) }
+      ProofNode::Term {term, args} => { s.u8(2); term.save(s)?; args.save(s) }
+      ProofNode::Hyp(i, e) => { s.u8(3); i.save(s)?; e.save(s) }
+      ProofNode::Thm {thm, args, res} => { s.u8(4); thm.save(s)?; args.save(s)?; res.save(s) }
+      ProofNode::Conv(p) => { s.u8(5); p.0.save(s)?; p.1.save(s)?; p.2.save(s) }
+      ProofNode::Refl(p) => { s.u8(6); p.save(s) }
+      ProofNode::Sym(p) => { s.u8(7); p.save(s) }
+      ProofNode::Cong {term, args} => { s.u8(8); term.save(s)?; args.save(s) }
+      ProofNode::Unfold {term, args, res} => { s.u8(9); term.save(s)?; args.save(s)?; res.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(match l.u8()? {
+      0 => ProofNode::Ref(usize::load(l)?),
+      1 => ProofNode::Dummy(AtomId::load(l)?, SortId::load(l)?),
+      2 => ProofNode::Term {term: TermId::load(l)?, args: Persist::load(l)?},
+      3 => ProofNode::Hyp(usize::load(l)?, Persist::load(l)?),
+      4 => ProofNode::Thm {thm: ThmId::load(l)?, args: Persist::load(l)?, res: Persist::load(l)?},
+      5 => ProofNode::Conv(Box::new((Persist::load(l)?, Persist::load(l)?, Persist::load(l)?))),
+      6 => ProofNode::Refl(Persist::load(l)?),
+      7 => ProofNode::Sym(Persist::load(l)?),
+      8 => ProofNode::Cong {term: TermId::load(l)?, args: Persist::load(l)?},
+      9 => ProofNode::Unfold {term: TermId::load(l)?, args: Persist::load(l)?, res: Persist::load(l)?},
+      _ => return Err(invalid("bad proof"))
+    })
+  }
+}
+
+impl Persist for ThmKind {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      ThmKind::Axiom => { s.u8(0); Ok(()) }
+      ThmKind::Thm(p) => { s.u8(1); p.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(ThmKind::Axiom),
+      1 => Ok(ThmKind::Thm(Persist::load(l)?)),
+      _ => Err(invalid("bad theorem"))
+    }
+  }
+}
+
+impl Persist for StmtTrace {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      StmtTrace::Sort(a) => { s.u8(0); a.save(s) }
+      StmtTrace::Decl(a) => { s.u8(1); a.save(s) }
+      StmtTrace::Global(a) => { s.u8(2); a.save(s) }
+      StmtTrace::OutputString(out) => { s.u8(3); out.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(StmtTrace::Sort(AtomId::load(l)?)),
+      1 => Ok(StmtTrace::Decl(AtomId::load(l)?)),
+      2 => Ok(StmtTrace::Global(AtomId::load(l)?)),
+      3 => Ok(StmtTrace::OutputString(Persist::load(l)?)),
+      _ => Err(invalid("bad statement"))
+    }
+  }
+}
+
+impl Persist for DeclKey {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match *self {
+      DeclKey::Term(t) => { s.u8(0); t.save(s) }
+      DeclKey::Thm(t) => { s.u8(1); t.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(DeclKey::Term(TermId::load(l)?)),
+      1 => Ok(DeclKey::Thm(ThmId::load(l)?)),
+      _ => Err(invalid("bad declaration"))
+    }
+  }
+}
+
+impl Persist for Literal {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      &Literal::Var(i, p) => { s.u8(0); i.save(s)?; p.save(s) }
+      Literal::Const(c) => { s.u8(1); c.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(Literal::Var(usize::load(l)?, Prec::load(l)?)),
+      1 => Ok(Literal::Const(ArcString::load(l)?)),
+      _ => Err(invalid("bad notation"))
+    }
+  }
+}
+
+impl Persist for Arc<Coe> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match &**self {
+      Coe::One(fsp, t) => { s.u8(0); fsp.save(s)?; t.save(s) }
+      Coe::Trans(c1, m, c2) => { s.u8(1); c1.save(s)?; m.save(s)?; c2.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(Arc::new(match l.u8()? {
+      0 => Coe::One(FileSpan::load(l)?, TermId::load(l)?),
+      1 => Coe::Trans(Persist::load(l)?, SortId::load(l)?, Persist::load(l)?),
+      _ => return Err(invalid("bad coercion"))
+    }))
+  }
+}
+
+impl Persist for MergeStrategyInner {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      MergeStrategyInner::AtomMap(m) => { s.u8(0); m.save(s) }
+      MergeStrategyInner::Custom(f) => { s.u8(1); f.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(MergeStrategyInner::AtomMap(Persist::load(l)?)),
+      1 => Ok(MergeStrategyInner::Custom(LispVal::load(l)?)),
+      _ => Err(invalid("bad merge strategy"))
+    }
+  }
+}
+
+impl Persist for AtomData {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    self.name.save(s)?;
+    self.lisp.save(s)?;
+    self.graveyard.save(s)?;
+    self.sort.save(s)?;
+    self.decl.save(s)
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(AtomData {
+      name: Persist::load(l)?,
+      lisp: Persist::load(l)?,
+      graveyard: Persist::load(l)?,
+      sort: Persist::load(l)?,
+      decl: Persist::load(l)?,
+    })
+  }
+}
+
+impl Persist for Environment {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    self.sorts.save(s)?;
+    self.pe.save(s)?;
+    self.terms.save(s)?;
+    self.thms.save(s)?;
+    self.hints.save(s)?;
+    let mut unchecked = self.unchecked.iter().copied().collect::<Vec<_>>();
+    unchecked.sort_unstable();
+    unchecked.save(s)?;
+    self.transfer.save(s)?;
+    self.extra_args.save(s)?;
+    self.attrs.save(s)?;
+    self.data.save(s)?;
+    self.stmts.save(s)?;
+    self.do_cache.source_hash.save(s)
+  }
+
+  /// Load an environment. The theorem index, the hint set and the atom map are rebuilt
+  /// from the other data.
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let mut env = Environment {
+      sorts: Persist::load(l)?,
+      pe: Persist::load(l)?,
+      terms: Persist::load(l)?,
+      thms: Persist::load(l)?,
+      hints: Persist::load(l)?,
+      unchecked: Vec::<ThmId>::load(l)?.into_iter().collect(),
+      transfer: Persist::load(l)?,
+      extra_args: Persist::load(l)?,
+      attrs: Persist::load(l)?,
+      data: Persist::load(l)?,
+      stmts: Persist::load(l)?,
+      ..Environment::new()
+    };
+    env.do_cache.source_hash = u64::load(l)?;
+    env.hint_set = env.hints.iter().copied().collect::<HashSet<_>>();
+    env.atoms = env.data.enum_iter().map(|(a, d)| (d.name.clone(), a)).collect();
+    for (tid, td) in env.thms.enum_iter() { env.thm_index.insert_thm(td, tid) }
+    Ok(env)
+  }
+}
+
+impl Persist for LispVal {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    let key: *const LispKind = &**self;
+    if let Some(&i) = s.vals.get(&key) {
+      s.u8(0);
+      return i.save(s)
+    }
+    s.depth += 1;
+    match &**self {
+      &LispKind::Atom(a) => { s.u8(1); a.save(s)? }
+      LispKind::List(es) => { s.u8(2); es.save(s)? }
+      LispKind::DottedList(es, r) => { s.u8(3); es.save(s)?; r.save(s)? }
+      LispKind::Annot(Annot::Span(fsp), e) => { s.u8(4); fsp.save(s)?; e.save(s)? }
+      LispKind::Number(n) => { s.u8(5); n.save(s)? }
+      LispKind::String(str) => { s.u8(6); str.save(s)? }
+      &LispKind::Bool(b) => { s.u8(7); b.save(s)? }
+      &LispKind::Syntax(stx) => { s.u8(8); stx.save(s)? }
+      LispKind::Undef => s.u8(9),
+      LispKind::Proc(p) => { s.u8(10); p.save(s)? }
+      LispKind::AtomMap(m) => { s.u8(11); m.save(s)? }
+      LispKind::Ref(_) => {
+        // The reference is registered before its contents are written, see `flush_refs`
+        s.u8(12);
+        s.refs.push_back(self.clone());
+      }
+      &LispKind::MVar(n, tgt) => { s.u8(13); n.save(s)?; tgt.save(s)? }
+      LispKind::Goal(e) => { s.u8(14); e.save(s)? }
+      LispKind::Proof(thm) => { s.u8(15); thm.save(s)? }
+    }
+    s.vals.insert(key, s.keep.len());
+    s.keep.push(self.clone());
+    s.depth -= 1;
+    if s.depth == 0 { s.flush_refs()? }
+    Ok(())
+  }
+
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    let tag = l.u8()?;
+    if tag == 0 {
+      let i = usize::load(l)?;
+      return l.vals.get(i).cloned().ok_or_else(|| invalid("bad value"))
+    }
+    l.depth += 1;
+    let e = match tag {
+      1 => LispKind::Atom(AtomId::load(l)?),
+      2 => LispKind::List(Persist::load(l)?),
+      3 => LispKind::DottedList(Persist::load(l)?, LispVal::load(l)?),
+      4 => LispKind::Annot(Annot::Span(FileSpan::load(l)?), LispVal::load(l)?),
+      5 => LispKind::Number(BigInt::load(l)?),
+      6 => LispKind::String(ArcString::load(l)?),
+      7 => LispKind::Bool(bool::load(l)?),
+      8 => LispKind::Syntax(Syntax::load(l)?),
+      9 => LispKind::Undef,
+      10 => LispKind::Proc(Proc::load(l)?),
+      11 => LispKind::AtomMap(Persist::load(l)?),
+      12 => {
+        let r = LispVal::new_ref(LispVal::undef());
+        l.refs.push_back(r.clone());
+        l.vals.push(r.clone());
+        l.depth -= 1;
+        if l.depth == 0 { l.flush_refs()? }
+        return Ok(r)
+      }
+      13 => LispKind::MVar(usize::load(l)?, InferTarget::load(l)?),
+      14 => LispKind::Goal(LispVal::load(l)?),
+      15 => LispKind::Proof(Persist::load(l)?),
+      _ => return Err(invalid("bad value"))
+    };
+    let e = LispVal::new(e);
+    l.vals.push(e.clone());
+    l.depth -= 1;
+    if l.depth == 0 { l.flush_refs()? }
+    Ok(e)
+  }
+}
+
+impl Persist for Syntax {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.bytes(self.to_byte_str()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Syntax::from_str(l.str()?).ok_or_else(|| invalid("bad syntax"))
+  }
+}
+
+impl Persist for BuiltinProc {
+  fn save(&self, s: &mut Saver) -> io::Result<()> { s.bytes(self.to_byte_str()); Ok(()) }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    BuiltinProc::from_str(l.str()?).ok_or_else(|| invalid("bad builtin"))
+  }
+}
+
+impl Persist for InferTarget {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match *self {
+      InferTarget::Unknown => { s.u8(0); Ok(()) }
+      InferTarget::Provable => { s.u8(1); Ok(()) }
+      InferTarget::Bound(a) => { s.u8(2); a.save(s) }
+      InferTarget::Reg(a) => { s.u8(3); a.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(InferTarget::Unknown),
+      1 => Ok(InferTarget::Provable),
+      2 => Ok(InferTarget::Bound(AtomId::load(l)?)),
+      3 => Ok(InferTarget::Reg(AtomId::load(l)?)),
+      _ => Err(invalid("bad metavariable"))
+    }
+  }
+}
+
+impl Persist for ProcSpec {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match *self {
+      ProcSpec::Exact(n) => { s.u8(0); n.save(s) }
+      ProcSpec::AtLeast(n) => { s.u8(1); n.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(ProcSpec::Exact(usize::load(l)?)),
+      1 => Ok(ProcSpec::AtLeast(usize::load(l)?)),
+      _ => Err(invalid("bad procedure"))
+    }
+  }
+}
+
+impl Persist for ProcPos {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      ProcPos::Named(fsp, sp, a) => { s.u8(0); fsp.save(s)?; sp.save(s)?; a.save(s) }
+      ProcPos::Unnamed(fsp) => { s.u8(1); fsp.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => Ok(ProcPos::Named(FileSpan::load(l)?, Span::load(l)?, AtomId::load(l)?)),
+      1 => Ok(ProcPos::Unnamed(FileSpan::load(l)?)),
+      _ => Err(invalid("bad procedure"))
+    }
+  }
+}
+
+impl Persist for Proc {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      Proc::Builtin(p) => { s.u8(0); p.save(s) }
+      Proc::Lambda {pos, env, spec, code} => {
+        s.u8(1); pos.save(s)?; env.save(s)?; spec.save(s)?; code.save(s)
+      }
+      Proc::MergeMap(m) => { s.u8(2); m.save(s) }
+      Proc::ProofThunk(a, res) => {
+        s.u8(3); a.save(s)?;
+        match &*res.borrow() {
+          Ok(e) => { s.u8(0); e.save(s) }
+          Err(args) => { s.u8(1); args.save(s) }
+        }
+      }
+      Proc::MatchCont(_) => Err(invalid("cannot save a match continuation")),
+      Proc::RefineCallback => Err(invalid("cannot save a refine callback")),
+      #[cfg(feature = "mmc")]
+      Proc::MmcCompiler(_) => Err(invalid("cannot save the MMC compiler")),
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(match l.u8()? {
+      0 => Proc::Builtin(BuiltinProc::load(l)?),
+      1 => Proc::Lambda {
+        pos: ProcPos::load(l)?,
+        env: Persist::load(l)?,
+        spec: ProcSpec::load(l)?,
+        code: Persist::load(l)?,
+      },
+      2 => Proc::MergeMap(Persist::load(l)?),
+      3 => {
+        let a = AtomId::load(l)?;
+        let res = match l.u8()? {
+          0 => Ok(LispVal::load(l)?),
+          1 => Err(Persist::load(l)?),
+          _ => return Err(invalid("bad procedure"))
+        };
+        Proc::ProofThunk(a, RefCell::new(res))
+      }
+      _ => return Err(invalid("bad procedure"))
+    })
+  }
+}
+
+impl Persist for Arc<Ir> {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    let key: *const Ir = &**self;
+    if let Some(&i) = s.irs.get(&key) {
+      s.u8(0);
+      return i.save(s)
+    }
+    s.u8(1);
+    (**self).save(s)?;
+    s.irs.insert(key, s.keep_irs.len());
+    s.keep_irs.push(self.clone());
+    Ok(())
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    match l.u8()? {
+      0 => {
+        let i = usize::load(l)?;
+        l.irs.get(i).cloned().ok_or_else(|| invalid("bad code"))
+      }
+      1 => {
+        let ir = Arc::new(Ir::load(l)?);
+        l.irs.push(ir.clone());
+        Ok(ir)
+      }
+      _ => Err(invalid("bad code"))
+    }
+  }
+}
+
+impl Persist for Ir {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      &Ir::Local(i) => { s.u8(0); i.save(s) }
+      &Ir::Global(sp, a) => { s.u8(1); sp.save(s)?; a.save(s) }
+      Ir::Const(e) => { s.u8(2); e.save(s) }
+      Ir::List(sp, es) => { s.u8(3); sp.save(s)?; es.save(s) }
+      Ir::DottedList(es, r) => { s.u8(4); es.save(s)?; r.save(s) }
+      Ir::App(sp1, sp2, f, es) => { s.u8(5); sp1.save(s)?; sp2.save(s)?; f.save(s)?; es.save(s) }
+      Ir::If(es) => { s.u8(6); es.0.save(s)?; es.1.save(s)?; es.2.save(s) }
+      Ir::Focus(sp, es) => { s.u8(7); sp.save(s)?; es.save(s) }
+      Ir::SetMergeStrategy(sp, a, e) => { s.u8(8); sp.save(s)?; a.save(s)?; e.save(s) }
+      Ir::Def(n, x, e) => { s.u8(9); n.save(s)?; x.save(s)?; e.save(s) }
+      Ir::Eval(keep, es) => { s.u8(10); keep.save(s)?; es.save(s) }
+      Ir::NoTailRec => { s.u8(11); Ok(()) }
+      Ir::Lambda(sp, n, spec, code) => {
+        s.u8(12); sp.save(s)?; n.save(s)?; spec.save(s)?; code.save(s)
+      }
+      Ir::Match(sp, e, brs) => { s.u8(13); sp.save(s)?; e.save(s)?; brs.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(match l.u8()? {
+      0 => Ir::Local(usize::load(l)?),
+      1 => Ir::Global(Span::load(l)?, AtomId::load(l)?),
+      2 => Ir::Const(LispVal::load(l)?),
+      3 => Ir::List(Span::load(l)?, Persist::load(l)?),
+      4 => Ir::DottedList(Persist::load(l)?, Persist::load(l)?),
+      5 => Ir::App(Span::load(l)?, Span::load(l)?, Persist::load(l)?, Persist::load(l)?),
+      6 => Ir::If(Box::new((Ir::load(l)?, Ir::load(l)?, Ir::load(l)?))),
+      7 => Ir::Focus(Span::load(l)?, Persist::load(l)?),
+      8 => Ir::SetMergeStrategy(Span::load(l)?, AtomId::load(l)?, Persist::load(l)?),
+      9 => Ir::Def(usize::load(l)?, Persist::load(l)?, Persist::load(l)?),
+      10 => Ir::Eval(bool::load(l)?, Persist::load(l)?),
+      11 => Ir::NoTailRec,
+      12 => Ir::Lambda(Span::load(l)?, usize::load(l)?, ProcSpec::load(l)?, Persist::load(l)?),
+      13 => Ir::Match(Span::load(l)?, Persist::load(l)?, Persist::load(l)?),
+      _ => return Err(invalid("bad code"))
+    })
+  }
+}
+
+impl Persist for Pattern {
+  fn save(&self, s: &mut Saver) -> io::Result<()> {
+    match self {
+      Pattern::Skip => { s.u8(0); Ok(()) }
+      &Pattern::Atom(i) => { s.u8(1); i.save(s) }
+      &Pattern::QuoteAtom(a) => { s.u8(2); a.save(s) }
+      Pattern::String(str) => { s.u8(3); str.save(s) }
+      &Pattern::Bool(b) => { s.u8(4); b.save(s) }
+      Pattern::Undef => { s.u8(5); Ok(()) }
+      Pattern::Number(n) => { s.u8(6); n.save(s) }
+      Pattern::MVar(MVarPattern::Unknown) => { s.u8(7); Ok(()) }
+      Pattern::MVar(MVarPattern::Any) => { s.u8(8); Ok(()) }
+      Pattern::MVar(MVarPattern::Simple(p)) => { s.u8(9); p.0.save(s)?; p.1.save(s) }
+      Pattern::Goal(p) => { s.u8(10); p.save(s) }
+      Pattern::DottedList(ps, p) => { s.u8(11); ps.save(s)?; p.save(s) }
+      Pattern::List(ps, dot) => { s.u8(12); ps.save(s)?; dot.save(s) }
+      Pattern::And(ps) => { s.u8(13); ps.save(s) }
+      Pattern::Or(ps) => { s.u8(14); ps.save(s) }
+      Pattern::Not(ps) => { s.u8(15); ps.save(s) }
+      Pattern::Test(sp, f, ps) => { s.u8(16); sp.save(s)?; f.save(s)?; ps.save(s) }
+      &Pattern::QExprAtom(a) => { s.u8(17); a.save(s) }
+      Pattern::Numeral(n) => { s.u8(18); n.save(s) }
+      Pattern::StringLit(str) => { s.u8(19); str.save(s) }
+    }
+  }
+  fn load(l: &mut Loader<'_>) -> io::Result<Self> {
+    Ok(match l.u8()? {
+      0 => Pattern::Skip,
+      1 => Pattern::Atom(usize::load(l)?),
+      2 => Pattern::QuoteAtom(AtomId::load(l)?),
+      3 => Pattern::String(ArcString::load(l)?),
+      4 => Pattern::Bool(bool::load(l)?),
+      5 => Pattern::Undef,
+      6 => Pattern::Number(BigInt::load(l)?),
+      7 => Pattern::MVar(MVarPattern::Unknown),
+      8 => Pattern::MVar(MVarPattern::Any),
+      9 => Pattern::MVar(MVarPattern::Simple(Box::new((Pattern::load(l)?, Pattern::load(l)?)))),
+      10 => Pattern::Goal(Persist::load(l)?),
+      11 => Pattern::DottedList(Persist::load(l)?, Persist::load(l)?),
+      12 => Pattern::List(Persist::load(l)?, Persist::load(l)?),
+      13 => Pattern::And(Persist::load(l)?),
+      14 => Pattern::Or(Persist::load(l)?),
+      15 => Pattern::Not(Persist::load(l)?),
+      16 => Pattern::Test(Span::load(l)?, Persist::load(l)?, Persist::load(l)?),
+      17 => Pattern::QExprAtom(AtomId::load(l)?),
+      18 => Pattern::Numeral(BigUint::load(l)?),
+      19 => Pattern::StringLit(ArcString::load(l)?),
+      _ => return Err(invalid("bad pattern"))
+    })
+  }
+}
@@ -6,12 +6,13 @@ use std::hash::Hash;
 use std::ops::Index;
 use std::mem;
 use std::collections::{HashMap, hash_map::Entry};
-use crate::{AtomId, Type};
+use crate::{AtomId, TermKind, Type};
 use super::{LocalContext, ElabError, Result, Environment,
-  SortId, TermId, ThmId, ExprNode, ProofNode, DeclKey, Modifiers};
+  SortId, TermId, ThmId, Thm, ExprNode, ProofNode, DeclKey, Modifiers};
 use super::lisp::{LispVal, LispKind, Uncons, InferTarget, print::FormatEnv};
 use super::local_context::{InferSort, try_get_span_from};
-use crate::{BoxError, FileSpan};
+use crate::{BoxError, FileSpan, did_you_mean};
+use crate::explain::ErrorCode;
 
 /// This struct represents the context for the hash-consing step of proof compaction
 #[derive(Debug)]
@@ -46,6 +47,63 @@ impl<'a> NodeHasher<'a> {
   fn err_sp(&self, fsp: Option<&FileSpan>, msg: impl Into<BoxError>) -> ElabError {
     ElabError::new_e(try_get_span_from(&self.fsp, fsp), msg)
   }
+
+  /// Suggest variables in the local context with names close to `a`, for error messages.
+  fn similar_vars(&self, a: AtomId) -> String {
+    did_you_mean(&self.fe.data[a].name, self.lc.vars.keys().map(|&v| &*self.fe.data[v].name))
+  }
+
+  /// Suggest hypotheses in the local context with names close to `a`, for error messages.
+  fn similar_hyps(&self, a: AtomId) -> String {
+    did_you_mean(&self.fe.data[a].name, self.lc.proofs.keys().map(|&h| &*self.fe.data[h].name))
+  }
+
+  /// Suggest terms (and theorems, if `thms` is true) with names close to `a`,
+  /// for error messages.
+  fn similar_decls(&self, a: AtomId, thms: bool) -> String {
+    did_you_mean(&self.fe.data[a].name, self.fe.data.0.iter()
+      .filter(|ad| match ad.decl {
+        Some(DeclKey::Term(_)) => true,
+        Some(DeclKey::Thm(_)) => thms,
+        None => false,
+      })
+      .map(|ad| &*ad.name))
+  }
+
+  /// The sort of variable `i` in the variable map, if it is known.
+  fn var_sort(&self, i: usize) -> Option<SortId> {
+    self.var_infer(i)?.sort()
+  }
+
+  /// The inferred sort of variable `i` in the variable map. Named variables store it in
+  /// the variable context rather than in the variable order.
+  fn var_infer(&self, i: usize) -> Option<&InferSort> {
+    match self.lc.var_order.get(i)? {
+      (_, _, Some(is)) => Some(is),
+      (_, a, None) => Some(&self.lc.vars.get(a.as_ref()?)?.1),
+    }
+  }
+
+  /// Parse the arguments `u` of a sort ascription `r = (: e s)`, returning `e` and `s`.
+  fn ascription(&self, fsp: Option<&FileSpan>, r: &LispVal, mut u: Uncons) -> Result<(LispVal, SortId)> {
+    match (u.next(), u.next()) {
+      (Some(e), Some(s)) if u.exactly(0) => {
+        let sort = s.as_atom().and_then(|a| self.fe.data[a].sort)
+          .ok_or_else(|| self.err(&s, "expected a sort"))?;
+        Ok((e, sort))
+      }
+      _ => Err(self.err_sp(fsp, format!("incorrect ':' format {}", self.fe.to(r))))
+    }
+  }
+
+  /// Check the sort `found` of the expression `e` against the ascribed sort `s`.
+  fn check_sort(&self, e: &LispVal, s: SortId, found: Option<SortId>) -> Result<()> {
+    match found {
+      Some(found) if found != s => Err(self.err(e, format!("type error: expected {}, got {}",
+        self.fe.sorts[s].name, self.fe.sorts[found].name))),
+      _ => Ok(())
+    }
+  }
 }
 
 /// Because the s-expr representation of proof terms is ambiguous between terms,
@@ -125,6 +183,10 @@ pub struct Dedup<H: NodeHash> {
   /// reference, and `deps` is the dependencies of this expression
   /// (calculated as a useful side effect of deduplication).
   pub vec: Vec<(Rc<H>, bool, u64)>,
+  /// The spans of the lisp expressions that produced the proof and conversion nodes,
+  /// used to build the source map of a proof
+  /// (see [`Proof::spans`](super::environment::Proof::spans)).
+  pub spans: HashMap<usize, FileSpan>,
   /// `2 ^ n` where `n` is the number of bound variables currently allocated.
   /// (Yes, this puts a limit of 64 simultaneous bound variables. In fact the limit is
   /// lower than that, [55](mm0b_parser::MAX_BOUND_VARS),
@@ -146,6 +208,7 @@ impl<H: NodeHash> Dedup<H> {
       map: vec.iter().enumerate().map(|(i, r)| (r.0.clone(), i)).collect(),
       prev: Default::default(),
       vec,
+      spans: HashMap::new(),
       bv,
     }
   }
@@ -170,8 +233,15 @@ impl<H: NodeHash> Dedup<H> {
       },
       Entry::Vacant(v) => {
         v.insert(None);
-        let n = match H::from(nh, e.fspan().as_ref(), kind, &arc, self)? {
-          Ok(v) => self.add_direct(v),
+        let fsp = e.fspan();
+        let n = match H::from(nh, fsp.as_ref(), kind, &arc, self)? {
+          Ok(v) => {
+            let n = self.add_direct(v);
+            if let (ProofKind::Proof | ProofKind::Conv, Some(fsp)) = (kind, fsp) {
+              self.spans.entry(n).or_insert(fsp);
+            }
+            n
+          }
           Err(n) => n,
         };
         self.prev.get_mut(kind).insert(ptr, Some((arc, n))); n
@@ -189,7 +259,7 @@ impl<H: NodeHash> Dedup<H> {
       map.insert(t.clone(), i);
       (t, b, v)
     }).collect();
-    Dedup { map, prev: self.prev.clone(), vec, bv: self.bv }
+    Dedup { map, prev: self.prev.clone(), vec, spans: self.spans.clone(), bv: self.bv }
   }
 }
 
@@ -369,18 +439,26 @@ impl NodeHash for ExprHash {
             }
             ExprHash::Dummy(a, sort)
           }
-          _ => return Err(nh.err_sp(fsp, format!("variable '{}' not found", nh.fe.data[a].name))),
+          _ => return Err(nh.err_sp(fsp, format!("variable '{}' not found{}", nh.fe.data[a].name, nh.similar_vars(a)))
+            .with_code(ErrorCode::UnknownVariable)),
         }
       },
       LispKind::MVar(_, tgt) => return Err(nh.err_sp(fsp,
-        format!("{}: {}", nh.fe.to(r), nh.fe.to(tgt)))),
+        format!("{}: {}", nh.fe.to(r), nh.fe.to(tgt))).with_code(ErrorCode::UnsolvedGoal)),
       _ => {
         let mut u = Uncons::from(r.clone());
         let head = u.next().ok_or_else(||
           nh.err_sp(fsp, format!("bad expression {}", nh.fe.to(r))))?;
         let a = head.as_atom().ok_or_else(|| nh.err(&head, "expected an atom"))?;
+        if a == AtomId::COLON {
+          let (e, s) = nh.ascription(fsp, r, u)?;
+          let n = de.dedup(nh, ProofKind::Expr, &e)?;
+          nh.check_sort(&e, s, Self::sort(nh, de, n))?;
+          return Ok(Err(n))
+        }
         let tid = nh.fe.term(a).ok_or_else(||
-          nh.err(&head, format!("term '{}' not declared", nh.fe.data[a].name)))?;
+          nh.err(&head, format!("term '{}' not declared{}",
+            nh.fe.data[a].name, nh.similar_decls(a, false))).with_code(ErrorCode::UnknownTerm))?;
         let mut ns = Vec::new();
         for e in &mut u { ns.push(de.dedup(nh, ProofKind::Expr, &e)?) }
         if !u.exactly(0) {
@@ -463,6 +541,24 @@ impl Environment {
       }
     }
   }
+
+  /// Get the statement of a theorem in the format used by `get-decl`, that is, the binders,
+  /// hypotheses and conclusion, as well as the heap of the statement.
+  #[must_use] pub fn thm_statement(&self, tdata: &Thm) -> (LispVal, LispVal, LispVal, Vec<LispVal>) {
+    let mut bvs = Vec::new();
+    let mut heap = Vec::new();
+    let bis = self.binders(&tdata.args, &mut heap, &mut bvs);
+    for e in &tdata.heap[heap.len()..] {
+      let e = self.expr_node(&heap, &mut None, e);
+      heap.push(e)
+    }
+    let hyps = LispVal::list(tdata.hyps.iter().map(|(a, e)| LispVal::list(vec![
+      LispVal::atom(a.unwrap_or(AtomId::UNDER)),
+      self.expr_node(&heap, &mut None, e)
+    ])).collect::<Vec<_>>());
+    let ret = self.expr_node(&heap, &mut None, &tdata.ret);
+    (bis, hyps, ret, heap)
+  }
 }
 
 /// The [`NodeHash`] version of [`ProofNode`]. It has the same structure except that
@@ -496,6 +592,20 @@ pub enum ProofHash {
 }
 
 impl ProofHash {
+  /// Call `f` on the indexes of the subterms of this node, in the same order as
+  /// [`ProofNode::for_each_child`] visits the corresponding children of the built node.
+  fn for_each_child(&self, mut f: impl FnMut(usize)) {
+    match *self {
+      ProofHash::Ref(..) | ProofHash::Dummy(..) => {}
+      ProofHash::Term(_, ref ns) => ns.iter().for_each(|&i| f(i)),
+      ProofHash::Cong(_, ref ns) => ns.iter().for_each(|&i| f(i)),
+      ProofHash::Hyp(_, e) | ProofHash::Refl(e) | ProofHash::Sym(e) => f(e),
+      ProofHash::Thm(_, ref ns, r) => { ns.iter().for_each(|&i| f(i)); f(r) }
+      ProofHash::Conv(i, j, k) => { f(i); f(j); f(k) }
+      ProofHash::Unfold(_, ref ns, _, m, c) => { ns.iter().for_each(|&i| f(i)); f(m); f(c) }
+    }
+  }
+
   /// Apply a substitution, while preserving sharing. The `n_heap` array contains
   /// indexes for substituted subterms, in case we see the same subterm multiple times.
   pub fn subst(de: &mut impl IDedup<Self>,
@@ -517,6 +627,81 @@ impl ProofHash {
     }
   }
 
+  /// The sort of the expression at index `i`, or of the sides of the conversion at `i`.
+  fn sort(nh: &NodeHasher<'_>, de: &impl IDedup<Self>, i: usize) -> Option<SortId> {
+    match de[i] {
+      ProofHash::Ref(_, j) => nh.var_sort(j),
+      ProofHash::Dummy(_, s) => Some(s),
+      ProofHash::Term(t, _) | ProofHash::Cong(t, _) | ProofHash::Unfold(t, ..) =>
+        Some(nh.fe.terms[t].ret.0),
+      ProofHash::Refl(e) | ProofHash::Sym(e) => Self::sort(nh, de, e),
+      ProofHash::Hyp(..) | ProofHash::Thm(..) | ProofHash::Conv(..) => None,
+    }
+  }
+
+  /// Returns true if the expression at index `i` is a bound variable, or the reflexivity
+  /// conversion on one.
+  fn is_bound(nh: &NodeHasher<'_>, de: &impl IDedup<Self>, i: usize) -> bool {
+    match de[i] {
+      ProofHash::Dummy(..) => true,
+      ProofHash::Ref(_, j) => matches!(nh.var_infer(j), Some(InferSort::Bound(_))),
+      ProofHash::Refl(e) => Self::is_bound(nh, de, e),
+      _ => false,
+    }
+  }
+
+  /// The statement proved by the proof at index `i`, if it is easily available.
+  fn proof_type(de: &impl IDedup<Self>, i: usize) -> Option<usize> {
+    match de[i] {
+      ProofHash::Hyp(_, e) | ProofHash::Thm(_, _, e) | ProofHash::Conv(e, _, _) => Some(e),
+      _ => None,
+    }
+  }
+
+  /// Check the arguments `args` of an application of term `t` (or a congruence on `t`),
+  /// which have been deduplicated to `ns`: the number of arguments, their sorts, and that
+  /// the bound variable arguments are bound variables.
+  fn check_args(nh: &NodeHasher<'_>, fsp: Option<&FileSpan>, t: TermId,
+    args: &[LispVal], ns: &[usize], de: &impl IDedup<Self>
+  ) -> Result<()> {
+    let td = &nh.fe.terms[t];
+    if ns.len() != td.args.len() {
+      return Err(nh.err_sp(fsp, format!("incorrect number of arguments to '{}': expected {}, got {}",
+        nh.fe.data[td.atom].name, td.args.len(), ns.len())))
+    }
+    for ((&(_, ty), e), &n) in td.args.iter().zip(args).zip(ns) {
+      nh.check_sort(e, ty.sort(), Self::sort(nh, de, n))?;
+      if ty.bound() && !Self::is_bound(nh, de, n) {
+        return Err(nh.err(e, format!("expected a bound variable, got {}", nh.fe.pp(e, nh.fe.env.pp.width))))
+      }
+    }
+    Ok(())
+  }
+
+  /// Match the value `e` of a definition (with heap `heap` and arguments `args`) against the
+  /// expression at index `tgt`, assigning the dummy variables of the definition as we go.
+  /// A dummy can be assigned to any bound variable of the right sort, not just a dummy.
+  fn unfold_match(nh: &NodeHasher<'_>, de: &impl IDedup<Self>, heap: &[ExprNode], args: &[usize],
+    dummies: &mut HashMap<AtomId, usize>, e: &ExprNode, tgt: usize
+  ) -> bool {
+    match *e {
+      ExprNode::Ref(i) if i < args.len() => args[i] == tgt,
+      ExprNode::Ref(i) => Self::unfold_match(nh, de, heap, args, dummies, &heap[i], tgt),
+      ExprNode::Dummy(a, s) => {
+        if let Some(&v) = dummies.get(&a) { return v == tgt }
+        if !Self::is_bound(nh, de, tgt) || Self::sort(nh, de, tgt) != Some(s) ||
+          dummies.values().any(|&v| v == tgt) { return false }
+        dummies.insert(a, tgt);
+        true
+      }
+      ExprNode::App(t, ref es) => match de[tgt] {
+        ProofHash::Term(t2, ref ns) if t == t2 && es.len() == ns.len() =>
+          es.iter().zip(&**ns).all(|(e, &n)| Self::unfold_match(nh, de, heap, args, dummies, e, n)),
+        _ => false
+      }
+    }
+  }
+
   /// Returns true if this proof term represents a conversion.
   pub fn is_conv(de: &impl IDedup<Self>, i: usize) -> bool {
     match de[i] {
@@ -537,6 +722,8 @@ impl ProofHash {
   /// represented by proof term index `i`.
   pub fn conv_side(de: &mut impl IDedup<Self>, i: usize, right: bool) -> usize {
     match de[i] {
+      // a variable is its own reflexivity proof
+      ProofHash::Ref(ProofKind::Expr, j) => de.reuse(j),
       ProofHash::Ref(_, j) => Self::conv_side(de, j, right),
       ProofHash::Dummy(..) |
       ProofHash::Term(..) |
@@ -586,27 +773,38 @@ impl NodeHash for ProofHash {
               let e = ProofHash::Dummy(a, sort);
               if kind == ProofKind::Conv { ProofHash::Refl(de.add_direct(e)) } else {e}
             }
-            _ => return Err(nh.err_sp(fsp, format!("variable '{}' not found", nh.fe.data[a].name))),
+            _ => return Err(nh.err_sp(fsp, format!("variable '{}' not found{}", nh.fe.data[a].name, nh.similar_vars(a)))
+              .with_code(ErrorCode::UnknownVariable)),
           }
         },
         ProofKind::Proof => match nh.lc.get_proof(a) {
           Some((_, _, p)) => return Ok(Err(de.dedup(nh, ProofKind::Proof, p)?)),
-          None => return Err(nh.err_sp(fsp, format!("hypothesis '{}' not found", nh.fe.data[a].name))),
+          None => return Err(nh.err_sp(fsp, format!("hypothesis '{}' not found{}", nh.fe.data[a].name, nh.similar_hyps(a)))
+            .with_code(ErrorCode::UnknownTheorem)),
         }
       },
       LispKind::MVar(_, tgt) => return Err(nh.err_sp(fsp,
-        format!("{}: {}", nh.fe.to(r), nh.fe.to(tgt)))),
-      LispKind::Goal(tgt) => return Err(nh.err_sp(fsp, format!("|- {}", nh.fe.to(tgt)))),
+        format!("{}: {}", nh.fe.to(r), nh.fe.to(tgt))).with_code(ErrorCode::UnsolvedGoal)),
+      LispKind::Goal(tgt) => return Err(nh.err_sp(fsp, format!("|- {}", nh.fe.to(tgt)))
+        .with_code(ErrorCode::UnsolvedGoal)),
       _ => {
         let mut u = Uncons::from(r.clone());
         let th_head = u.next().ok_or_else(||
           nh.err_sp(fsp, format!("bad expression {}", nh.fe.to(r))))?;
         let a = th_head.as_atom().ok_or_else(|| nh.err(&th_head, "expected an atom"))?;
+        if a == AtomId::COLON && kind != ProofKind::Proof {
+          let (e, s) = nh.ascription(fsp, r, u)?;
+          let n = de.dedup(nh, kind, &e)?;
+          nh.check_sort(&e, s, Self::sort(nh, de, n))?;
+          return Ok(Err(n))
+        }
         let adata = &nh.fe.data[a];
         match adata.decl {
           Some(DeclKey::Term(tid)) => {
+            let args = u.collect::<Vec<_>>();
             let mut ns = Vec::new();
-            for e in u { ns.push(de.dedup(nh, ProofKind::Expr, &e)?) }
+            for e in &args { ns.push(de.dedup(nh, ProofKind::Expr, e)?) }
+            Self::check_args(nh, fsp, tid, &args, &ns, de)?;
             if ns.iter().any(|&i| Self::is_conv(de, i)) {
               for i in &mut ns {*i = Self::as_conv(de, *i)}
               ProofHash::Cong(tid, ns.into())
@@ -664,10 +862,10 @@ impl NodeHash for ProofHash {
                     write!(err, "\n  ({}, {}) -> ({}, {})",
                       nh.fe.to(&td.args[i].0.unwrap_or(AtomId::UNDER)),
                       nh.fe.to(&td.args[j].0.unwrap_or(AtomId::UNDER)),
-                      nh.fe.pp(&args[i], 80), nh.fe.pp(&args[j], 80)).unwrap();
+                      nh.fe.pp(&args[i], nh.fe.env.pp.width), nh.fe.pp(&args[j], nh.fe.env.pp.width)).unwrap();
                   }
                 }
-                return Err(nh.err(&th_head, err))
+                return Err(nh.err(&th_head, err).with_code(ErrorCode::DisjointVars))
               }
             }
             let rhs = Self::subst(de, &td.heap, &mut heap, &td.ret);
@@ -675,12 +873,20 @@ impl NodeHash for ProofHash {
           },
           None => match a {
             AtomId::CONV => match (u.next(), u.next(), u.next()) {
-              (Some(tgt), Some(conv), Some(prf)) if u.exactly(0) => {
-                let tgt = de.dedup(nh, ProofKind::Expr, &tgt)?;
-                let conv = de.dedup(nh, ProofKind::Conv, &conv)?;
-                let conv = Self::as_conv(de, conv);
-                let prf = de.dedup(nh, ProofKind::Proof, &prf)?;
-                ProofHash::Conv(tgt, Self::as_conv(de, conv), prf)
+              (Some(e), Some(conv), Some(prf)) if u.exactly(0) => {
+                let tgt = de.dedup(nh, ProofKind::Expr, &e)?;
+                let c = de.dedup(nh, ProofKind::Conv, &conv)?;
+                let c = Self::as_conv(de, c);
+                let p = de.dedup(nh, ProofKind::Proof, &prf)?;
+                if Self::conv_side(de, c, false) != tgt {
+                  return Err(nh.err(&conv, format!(
+                    "conversion mismatch: the left side of the conversion is not {}", nh.fe.pp(&e, nh.fe.env.pp.width))))
+                }
+                if Self::proof_type(de, p).map_or(false, |e| e != Self::conv_side(de, c, true)) {
+                  return Err(nh.err(&prf,
+                    "conversion mismatch: the right side of the conversion is not the type of the proof"))
+                }
+                ProofHash::Conv(tgt, c, p)
               }
               _ => return Err(nh.err_sp(fsp, format!("incorrect :conv format {}", nh.fe.to(r))))
             },
@@ -699,15 +905,31 @@ impl NodeHash for ProofHash {
               };
               let tid = ty.as_atom().and_then(|a| nh.fe.term(a))
                 .ok_or_else(|| nh.err(&ty, "expected a term"))?;
+              let td = &nh.fe.terms[tid];
+              let val = match &td.kind {
+                TermKind::Def(Some(val)) => val,
+                TermKind::Def(None) => return Err(nh.err(&ty,
+                  format!("definition '{}' has no value", nh.fe.data[td.atom].name))),
+                TermKind::Term => return Err(nh.err(&ty,
+                  format!("'{}' is not a definition", nh.fe.data[td.atom].name))),
+              };
+              let args = Uncons::from(es.clone()).collect::<Vec<_>>();
               let mut ns = Vec::new();
-              for e in Uncons::from(es) { ns.push(de.dedup(nh, ProofKind::Expr, &e)?) }
+              for e in &args { ns.push(de.dedup(nh, ProofKind::Expr, e)?) }
+              Self::check_args(nh, es.fspan().as_ref().or(fsp), tid, &args, &ns, de)?;
               let lhs = de.add_direct(ProofHash::Term(tid, ns.clone().into()));
               let c = de.dedup(nh, ProofKind::Conv, &prf)?;
               let c = Self::as_conv(de, c);
               let l2 = Self::conv_side(de, c, false);
+              if !Self::unfold_match(nh, de, &val.heap, &ns, &mut HashMap::new(), &val.head, l2) {
+                return Err(nh.err(&prf, format!(
+                  "unfolding mismatch: the left side of the conversion is not an unfolding of '{}'",
+                  nh.fe.data[td.atom].name)))
+              }
               ProofHash::Unfold(tid, ns.into(), lhs, l2, c)
             },
-            _ => return Err(nh.err(&th_head, format!("term/theorem '{}' not declared", adata.name)))
+            _ => return Err(nh.err(&th_head, format!("term/theorem '{}' not declared{}",
+              adata.name, nh.similar_decls(a, true))).with_code(ErrorCode::UnknownTheorem))
           }
         }
       }
@@ -734,6 +956,15 @@ impl ExprHash {
       ExprHash::App(t, ref ns) => ProofHash::Term(t, ns.clone()),
     }
   }
+
+  /// The sort of the expression at index `i`.
+  fn sort(nh: &NodeHasher<'_>, de: &Dedup<Self>, i: usize) -> Option<SortId> {
+    match de[i] {
+      ExprHash::Ref(_, j) => nh.var_sort(j),
+      ExprHash::Dummy(_, s) => Some(s),
+      ExprHash::App(t, _) => Some(nh.fe.terms[t].ret.0),
+    }
+  }
 }
 
 impl Dedup<ExprHash> {
@@ -745,6 +976,36 @@ impl Dedup<ExprHash> {
   }
 }
 
+impl Dedup<ProofHash> {
+  /// Build the source map for the proof that [`build`] constructs from this [`Dedup`],
+  /// given the indexes of the hypotheses and the head of the proof. The result pairs each
+  /// step (in the numbering of [`Proof::steps`]) that was produced by a lisp expression
+  /// with a known span with that span. See [`Proof::spans`].
+  ///
+  /// [`Proof::steps`]: super::environment::Proof::steps
+  /// [`Proof::spans`]: super::environment::Proof::spans
+  #[must_use] pub fn source_map(&self, hyps: &[usize], head: usize) -> Box<[(u32, FileSpan)]> {
+    fn visit(de: &Dedup<ProofHash>, i: usize, root: bool,
+      step: &mut u32, out: &mut Vec<(u32, FileSpan)>
+    ) {
+      let (ref h, shared, _) = de.vec[i];
+      // Shared nodes are built on the heap, and everywhere else they are a `Ref` step
+      let inline = root || !shared;
+      if inline {
+        if let Some(fsp) = de.spans.get(&i) { out.push((*step, fsp.clone())) }
+      }
+      *step += 1;
+      if inline { h.for_each_child(|j| visit(de, j, false, step, out)) }
+    }
+    let (mut step, mut out) = (0, vec![]);
+    for (i, _) in self.vec.iter().enumerate().filter(|(_, e)| e.1) {
+      visit(self, i, true, &mut step, &mut out)
+    }
+    for &i in hyps.iter().chain(Some(&head)) { visit(self, i, false, &mut step, &mut out) }
+    out.into()
+  }
+}
+
 impl Node for ProofNode {
   type Hash = ProofHash;
   const REF: fn(usize) -> Self = ProofNode::Ref;
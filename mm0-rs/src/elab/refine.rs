@@ -6,11 +6,12 @@
 //! [`mm1.md`]: https://github.com/digama0/mm0/blob/master/mm0-hs/mm1.md#pre-expressions
 
 use crate::{FileSpan, Span};
-use super::{Elaborator, ElabError, Result};
-use crate::{AtomId, TermKind, DeclKey, Modifiers,
+use crate::explain::ErrorCode;
+use super::{Elaborator, ElabError, ElabErrorKind, Result};
+use crate::{AtomId, TermKind, DeclKey, ExprNode, Modifiers,
   ObjectKind, SortId, TermId, ThmId, Type};
 use super::lisp::{InferTarget, LispKind, LispRef, LispVal, Uncons, RefineSyntax,
-  print::{FormatEnv, EnvDisplay}, eval::SResult};
+  print::{FormatEnv, EnvDisplay}};
 use super::local_context::{InferSort, try_get_span, try_get_span_opt};
 use super::proof::Subst;
 
@@ -345,13 +346,13 @@ pub enum RefineResult {
 }
 
 impl LispVal {
-  fn conv(tgt: Self, u: Self, p: Self) -> Self {
+  pub(crate) fn conv(tgt: Self, u: Self, p: Self) -> Self {
     Self::list(vec![Self::atom(AtomId::CONV), tgt, u, p])
   }
-  fn unfold(t: AtomId, es: Vec<Self>, p: Self) -> Self {
+  pub(crate) fn unfold(t: AtomId, es: Vec<Self>, p: Self) -> Self {
     Self::list(vec![Self::atom(AtomId::UNFOLD), Self::atom(t), Self::list(es), p])
   }
-  fn sym(p: Self) -> Self {
+  pub(crate) fn sym(p: Self) -> Self {
     Self::list(vec![Self::atom(AtomId::SYM), p])
   }
   fn apply_conv(c: Self, tgt: Self, p: Self) -> Self {
@@ -381,6 +382,34 @@ impl LispVal {
 #[derive(Debug)]
 enum AssignError { Cyclic, BoundVar }
 
+/// A unification failure, with the innermost pair of subterms that failed to unify
+/// (expected, found), if the failure was caused by a mismatch.
+#[derive(Debug)]
+struct UnifyError {
+  msg: String,
+  at: Option<(LispVal, LispVal)>,
+}
+
+impl From<String> for UnifyError {
+  fn from(msg: String) -> Self { UnifyError {msg, at: None} }
+}
+
+impl From<&str> for UnifyError {
+  fn from(msg: &str) -> Self { msg.to_owned().into() }
+}
+
+impl UnifyError {
+  /// Build an error for a mismatch between `e1` and `e2`.
+  fn mismatch(msg: String, e1: &LispVal, e2: &LispVal) -> Self {
+    UnifyError {msg, at: Some((e1.clone(), e2.clone()))}
+  }
+
+  /// Prepend `msg` to the error message.
+  fn context(self, msg: impl std::fmt::Display) -> Self {
+    UnifyError {msg: format!("{}\n{}", msg, self.msg), ..self}
+  }
+}
+
 impl Elaborator {
 
   fn parse_refine(&mut self, fsp: &FileSpan, e: &LispVal) -> Result<RefineExpr> {
@@ -456,9 +485,16 @@ impl Elaborator {
   fn new_goal(&mut self, sp: Span, ty: LispVal) -> LispVal {
     let r = LispVal::new_ref(LispVal::goal(self.fspan(sp), ty));
     self.lc.goals.push(r.clone());
+    self.goal_created(sp, &r);
     r
   }
 
+  /// Call the `on-new-goal` hook on a goal `g` that was just created. Errors in the hook
+  /// are reported, but do not stop the elaboration of the proof.
+  pub(crate) fn goal_created(&mut self, sp: Span, g: &LispVal) {
+    if let Err(e) = self.call_hook(sp, AtomId::ON_NEW_GOAL, vec![g.clone()]) { self.report(e) }
+  }
+
   /// Get the sort of the term `e` (with only minimal type-checking).
   pub fn infer_target(&self, sp: Span, e: &LispVal) -> Result<InferTarget> {
     macro_rules! err {
@@ -476,7 +512,7 @@ impl Elaborator {
         let head = u.next().ok_or_else(|| err!(e, "not a term"))?;
         let a = head.as_atom().ok_or_else(|| err!(head, "expected an atom"))?;
         let tid = self.term(a).ok_or_else(||
-          err!(head, format!("unknown term '{}'", self.data[a].name)))?;
+          err!(head, format!("unknown term '{}'", self.data[a].name)).with_code(ErrorCode::UnknownTerm))?;
         let sort = self.env.terms[tid].ret.0;
         InferTarget::Reg(self.sorts[sort].atom)
       }
@@ -502,7 +538,8 @@ impl Elaborator {
           AtomId::CONV => u.next().ok_or_else(|| err!(e, "bad :conv"))?,
           a => {
             let tid = self.thm(a).ok_or_else(||
-              err!(head, format!("unknown theorem '{}'", self.data[a].name)))?;
+              err!(head, format!("unknown theorem '{}'", self.data[a].name))
+                .with_code(ErrorCode::UnknownTheorem))?;
             let tdata = &self.env.thms[tid];
             let num_args = tdata.args.len();
             let mut args = Vec::with_capacity(num_args);
@@ -571,6 +608,13 @@ impl Elaborator {
       if e.fspan().is_none() {
         if let Some(sp) = m.get(|e2| e2.fspan()) {e = e.span(sp)}
       }
+      // The hook is called before the assignment, so that it can still inspect `m`
+      if self.data[AtomId::ON_ASSIGN_MVAR].lisp.is_some() {
+        let sp = try_get_span(&self.fspan(Span::default()), mv);
+        if let Err(err) = self.call_hook(sp, AtomId::ON_ASSIGN_MVAR, vec![mv.clone(), e.clone()]) {
+          self.report(err)
+        }
+      }
       m.get_mut(|e2| *e2 = e);
       Ok(())
     }
@@ -579,19 +623,30 @@ impl Elaborator {
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
   fn unify(&mut self, sp: Span, e1: &LispVal, e2: &LispVal) -> Result<LispVal> {
-    self.unify1(e1, e2).map_err(|e| ElabError::new_e(sp, e))
+    self.unify1(e1, e2).map_err(|e| {
+      let mut info = vec![];
+      if let Some((e1, e2)) = e.at {
+        let fe = self.format_env();
+        if let Some(fsp) = e1.fspan() { info.push((fsp, format!("expected {}", fe.pp(&e1, fe.env.pp.width)).into())) }
+        if let Some(fsp) = e2.fspan() { info.push((fsp, format!("found {}", fe.pp(&e2, fe.env.pp.width)).into())) }
+      }
+      let info = if info.is_empty() {None} else {Some(info)};
+      ElabError::new(sp, ElabErrorKind::Boxed(e.msg.into(), info)).with_code(ErrorCode::UnifyFailure)
+    })
   }
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
-  fn unify1(&mut self, e1: &LispVal, e2: &LispVal) -> SResult<LispVal> {
-    self.unify_core(e1, e2).map_err(|e| self.format_env().pretty(|p|
-      format!("{}\n{}", p.unify_err(e1, e2).pretty(80), e)))
+  fn unify1(&mut self, e1: &LispVal, e2: &LispVal) -> Result<LispVal, UnifyError> {
+    self.unify_core(e1, e2).map_err(|e| {
+      let msg = self.format_env().pretty(|p| p.unify_err(e1, e2).pretty(self.pp.width).to_string());
+      e.context(msg)
+    })
   }
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
-  fn unify_core(&mut self, e1: &LispVal, e2: &LispVal) -> SResult<LispVal> {
+  fn unify_core(&mut self, e1: &LispVal, e2: &LispVal) -> Result<LispVal, UnifyError> {
     // println!("{} =?= {}", self.format_env().pp(e1, 80), self.format_env().pp(e2, 80));
     // (|| {
     if e1.ptr_eq(e2) {return Ok(LispVal::undef())}
@@ -604,17 +659,17 @@ impl Elaborator {
         (_, Some(Err(AssignError::Cyclic))) =>
           return Err("occurs-check failed, can't build infinite assignment".into()),
         (Some(Err(AssignError::BoundVar)), None) =>
-          return Err(format!("type error: expected bound var, got {}", self.print(e2))),
+          return Err(format!("type error: expected bound var, got {}", self.print(e2)).into()),
         (None, Some(Err(AssignError::BoundVar))) =>
-          return Err(format!("type error: expected bound var, got {}", self.print(e1))),
+          return Err(format!("type error: expected bound var, got {}", self.print(e1)).into()),
         (None, None) => {},
         _ => unreachable!()
       }
     }
     match (e1.as_atom(), e2.as_atom()) {
       (Some(a1), Some(a2)) if a1 == a2 => Ok(LispVal::undef()),
-      (Some(a1), Some(a2)) => Err(format!(
-        "variables do not match: {} != {}", self.data[a1].name, self.data[a2].name)),
+      (Some(a1), Some(a2)) => Err(UnifyError::mismatch(format!(
+        "variables do not match: {} != {}", self.data[a1].name, self.data[a2].name), e1, e2)),
       (None, None) => {
         let mut u1 = Uncons::from(e1.clone());
         let mut u2 = Uncons::from(e2.clone());
@@ -640,7 +695,7 @@ impl Elaborator {
               Ok(LispVal::undef())
             }
           } else {
-            Err(format!("bad terms: {}, {}", self.print(e1), self.print(e2)))
+            Err(format!("bad terms: {}, {}", self.print(e1), self.print(e2)).into())
           }
         } else {
           let t1 = self.term(a_t1).ok_or_else(||
@@ -654,15 +709,18 @@ impl Elaborator {
           }}
 
           match (&tdata1.kind, &tdata2.kind) {
-            (_, TermKind::Def(_)) if t1 < t2 => self.unfold(true, t2, &u2, e1).map_err(|e| format!("{}\n{}", s!(), e)),
-            (TermKind::Def(_), _) => self.unfold(false, t1, &u1, e2).map_err(|e| format!("{}\n{}", s!(), e)),
-            (_, TermKind::Def(_)) => self.unfold(true, t2, &u2, e1).map_err(|e| format!("{}\n{}", s!(), e)),
-            _ => Err(s!())
+            (_, TermKind::Def(_)) if t1 < t2 => self.unfold(true, t2, &u2, e1).map_err(|e| e.context(s!())),
+            (TermKind::Def(_), _) => self.unfold(false, t1, &u1, e2).map_err(|e| e.context(s!())),
+            (_, TermKind::Def(_)) => self.unfold(true, t2, &u2, e1).map_err(|e| e.context(s!())),
+            _ => Err(UnifyError::mismatch(s!(), e1, e2))
           }
         }
       }
-      _ => Err(format!(
-        "variable vs term: {} != {}", self.print(e1), self.print(e2))),
+      _ => {
+        let fe = self.format_env();
+        Err(UnifyError::mismatch(format!("variable vs term: {} != {}",
+          fe.pp(e1, fe.env.pp.width), fe.pp(e2, fe.env.pp.width)), e1, e2))
+      }
     }
     // })().map(|r| {
     //   let fe = self.format_env();
@@ -672,25 +730,71 @@ impl Elaborator {
   }
 
   /// Produce a proof that `(tid u1) = e2` if `sym` is false, or `e2 = (tid u1)` if `sym` is true.
-  fn unfold(&mut self, sym: bool, tid: TermId, u1: &Uncons, e2: &LispVal) -> SResult<LispVal> {
+  fn unfold(&mut self, sym: bool, tid: TermId, u1: &Uncons, e2: &LispVal) -> Result<LispVal, UnifyError> {
     let tdata = &self.env.terms[tid];
     let a = tdata.atom;
     let nargs = tdata.args.len();
     if let TermKind::Def(Some(val)) = &tdata.kind {
       let mut args = Vec::with_capacity(nargs);
       if !u1.extend_into(nargs, &mut args) {
-        return Err(format!("bad term: {}", self.print(u1)))
+        return Err(format!("bad term: {}", self.print(u1)).into())
       }
       let e1_unfolded = Subst::new(&self.env, &val.heap, args.clone())
         .subst_mut(&mut self.lc, &val.head);
-      let conv = self.unify1(&e1_unfolded, e2)?;
+      let conv = self.unify1(&e1_unfolded, e2).map_err(|mut e| {
+        if sym { e.at = e.at.map(|(a, b)| (b, a)) }
+        e
+      })?;
       let conv = LispVal::unfold(a, args, if conv.is_def() {conv} else {e1_unfolded});
       Ok(if sym {LispVal::sym(conv)} else {conv})
     } else {
-      Err(format!("not a definition: {}", self.print(&a)))
+      Err(format!("not a definition: {}", self.print(&a)).into())
+    }
+  }
+
+  /// Returns true if `e1` and `e2` may unify, where metavariables match anything, and
+  /// definitions in `unfold` are unfolded as needed. Unlike [`unify`](Self::unify), this
+  /// does not assign any metavariables. Definitions with dummy variables are not unfolded.
+  fn may_unify(&self, unfold: &[TermId], e1: &LispVal, e2: &LispVal) -> bool {
+    if e1.ptr_eq(e2) || e1.is_mvar() || e2.is_mvar() { return true }
+    match (e1.as_atom(), e2.as_atom()) {
+      (Some(a1), Some(a2)) => a1 == a2,
+      (None, None) => {
+        let (mut u1, mut u2) = (Uncons::from(e1.clone()), Uncons::from(e2.clone()));
+        let (a1, a2) = match (u1.next().and_then(|e| e.as_atom()), u2.next().and_then(|e| e.as_atom())) {
+          (Some(a1), Some(a2)) => (a1, a2),
+          _ => return false,
+        };
+        if a1 == a2 {
+          return u1.len() == u2.len() && u1.zip(u2).all(|(x1, x2)| self.may_unify(unfold, &x1, &x2))
+        }
+        let unfold1 = |a, u: Uncons| -> Option<LispVal> {
+          let t = self.term(a).filter(|t| unfold.contains(t))?;
+          let val = if let TermKind::Def(Some(val)) = &self.terms[t].kind {val} else {return None};
+          if val.heap.iter().chain(Some(&val.head)).any(|e| matches!(e, ExprNode::Dummy(..))) {
+            return None
+          }
+          let args = u.collect::<Vec<_>>();
+          if args.len() != self.terms[t].args.len() { return None }
+          Some(Subst::new(&self.env, &val.heap, args).subst(&val.head))
+        };
+        if let Some(e1) = unfold1(a1, u1) { return self.may_unify(unfold, &e1, e2) }
+        if let Some(e2) = unfold1(a2, u2) { return self.may_unify(unfold, e1, &e2) }
+        false
+      }
+      _ => false,
     }
   }
 
+  /// Find a hypothesis in the local context whose statement may unify with `tgt`,
+  /// unfolding definitions in `unfold` as needed. Later hypotheses are preferred.
+  pub(crate) fn find_assumption(&self, unfold: &[TermId], tgt: &LispVal) -> Option<AtomId> {
+    self.lc.proof_order.iter().enumerate().rev()
+      .filter(|&(i, (a, _, _))| self.lc.proofs.get(a) == Some(&i))
+      .find(|(_, (_, e, _))| self.may_unify(unfold, e, tgt))
+      .map(|(_, &(a, _, _))| a)
+  }
+
   fn type_target(&self, ty: &Type) -> InferTarget {
     match *ty {
       Type::Bound(s) => InferTarget::Bound(self.sorts[s].atom),
@@ -728,6 +832,13 @@ impl Elaborator {
         RState::RefineProof {tgt, p} => match self.parse_refine(&fsp, &p)? {
           RefineExpr::App {sp, sp2, head: AtomId::QMARK, ..} => {
             let head = LispVal::new_ref(LispVal::goal(self.fspan(sp), tgt));
+            self.goal_created(sp, &head);
+            self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
+            RState::Ret(head)
+          }
+          RefineExpr::App {sp2, head: AtomId::SORRY, ..} => {
+            self.lc.sorries += 1;
+            let head = LispVal::atom(AtomId::SORRY).span(self.fspan(sp2));
             self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
             RState::Ret(head)
           }
@@ -752,7 +863,7 @@ impl Elaborator {
               RState::RefineBis {sp, sp2, tgt, im, t, args: vec![head], u}
             } else {
               return Err(ElabError::new_e(sp2, format!(
-                "unknown theorem/hypothesis '{}'", self.data[a].name)))
+                "unknown theorem/hypothesis '{}'", self.data[a].name)).with_code(ErrorCode::UnknownTheorem))
             }
           }
           RefineExpr::Typed {ty, e: q} => {
@@ -783,13 +894,18 @@ impl Elaborator {
               };
               RState::Ret(self.coerce_term(sp, tgt, sort, bd, head)?)
             } else if let Some(t) = if tgt.bound() {None} else {self.term(a)} {
+              let u = match self.env.insert_implicits(t, &u.clone().collect::<Vec<_>>()) {
+                Some(args) => Uncons::from(LispVal::list(args)),
+                None => u,
+              };
               RState::RefineApp {sp2, tgt, t, u, args: vec![head]}
             } else if let Some(s) = tgt.sort().filter(|_| empty) {
               let sort = self.data[s].sort.ok_or_else(|| ElabError::new_e(sp, "bad sort"))?;
               self.lc.vars.insert(a, (true, InferSort::Bound(sort)));
               RState::Ret(head)
             } else {
-              return Err(ElabError::new_e(sp, format!("unknown term '{}'", self.data[a].name)))
+              return Err(ElabError::new_e(sp, format!("unknown term '{}'", self.data[a].name))
+                .with_code(ErrorCode::UnknownTerm))
             }
           }
           Ok(RefineExpr::Typed {ty: s, e}) => {
@@ -803,6 +919,10 @@ impl Elaborator {
           Ok(RefineExpr::Exact(e)) => RState::Ret(e),
           Ok(RefineExpr::Proc) => RState::Ret(e),
           Err(err) => (|| -> Result<_> {
+            if let Some(res) = self.env.mk_literal(&e, tgt.sort().and_then(|s| self.data[s].sort)) {
+              let (s, res) = res.map_err(|msg| ElabError::new_e(sp, msg))?;
+              return Ok(RState::Ret(self.coerce_term(sp, tgt, s, false, res)?))
+            }
             if let Some(proc) = &self.data[AtomId::TO_EXPR_FALLBACK].lisp {
               let proc = proc.val.clone();
               let args = vec![tgt.sort().map_or_else(LispVal::undef, LispVal::atom), e.clone()];
@@ -872,7 +992,7 @@ impl Elaborator {
               }
               args.push(self.new_goal(sp, h))
             }
-            let head = LispVal::list(args);
+            let head = LispVal::list(args).span(self.fspan(sp));
             self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
             break match res {
               RefineHypsResult::Ok(c) => RState::Ret(LispVal::apply_conv(c, tgt, head)),
@@ -0,0 +1,64 @@
+//! Evaluating lisp code against a read-only snapshot of an environment.
+//!
+//! This is the entry point for tools that embed the elaborator and want to query an
+//! elaborated file, and it is used by the `$/mm0/eval` request of the language server.
+//! The code is run by a fresh [`Elaborator`] whose environment is a copy of a
+//! [`FrozenEnv`], so it can see everything declared in the file and its imports, but
+//! nothing it does is visible outside of the evaluation. In addition, the code runs with
+//! at most the [`EnvRead`](super::Capability::EnvRead) capability, so the builtins that
+//! add declarations or change how the rest of a file is elaborated report an error, and
+//! a query can't be mistaken for a change to the file.
+
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+use mm1_parser::Ast;
+use crate::{Alignment, ElabError, EnvMergeIter, FileRef, FrozenEnv, LinedString};
+use super::{lisp::LispArena, Elaborator};
+
+/// The result of [`eval`].
+#[derive(Debug)]
+pub struct EvalOutput {
+  /// The code that was evaluated. The positions of the errors are relative to this.
+  pub source: Arc<LinedString>,
+  /// The printed values of the expressions, except for those that evaluate to `#undef`.
+  pub values: Vec<String>,
+  /// The errors, warnings and messages (such as the output of `display`) from the evaluation.
+  pub errors: Vec<ElabError>,
+}
+
+/// Evaluate `code`, a sequence of lisp expressions, against the environment `env` of the
+/// file `path`. The environment is not modified. The evaluation stops at the first
+/// expression that fails, or when `cancel` is set.
+#[must_use] pub fn eval(env: &FrozenEnv, path: FileRef, code: String, cancel: Arc<AtomicBool>) -> EvalOutput {
+  let source = Arc::new(LinedString::from(code));
+  let (es, parse_errors) = mm1_parser::parse_sexprs(source.as_bytes());
+  let ast = Arc::new(Ast {source: source.clone(), ..Ast::default()});
+  let mut elab = Elaborator::new(ast, path, false, crate::get_check_proofs(), cancel, None);
+  elab.capability = elab.capability.min(super::Capability::EnvRead);
+  elab.errors.extend(parse_errors.into_iter().map(ElabError::from));
+  elab.arena.install_thread_local();
+  let mut it = EnvMergeIter::new(&mut elab.env, env, &Alignment::default(), (0..0).into());
+  loop {
+    match it.next(&mut elab.env, &mut elab.errors) {
+      Err(e) => {elab.report(e); break}
+      Ok(None) => break,
+      Ok(Some(mut merge)) => {
+        merge.val = elab.apply_merge((0..0).into(),
+            merge.strat.as_deref(), merge.val.clone(), merge.new.val.clone())
+          .unwrap_or_else(|e| {elab.report(e); merge.new.val.clone()});
+        merge.apply(&mut elab.env);
+      }
+    }
+  }
+  let mut values = vec![];
+  for e in &es {
+    if elab.cancel.load(Ordering::Relaxed) { break }
+    match elab.eval_lisp(e) {
+      Ok(v) => if v.is_def() { values.push(format!("{}", elab.print(&v))) },
+      Err(e) => { elab.report(e); break }
+    }
+  }
+  LispArena::uninstall_thread_local();
+  let Elaborator {errors, arena, ..} = elab;
+  arena.clear();
+  EvalOutput {source, values, errors}
+}
@@ -28,6 +28,10 @@ pub struct Spans<T> {
   /// The local context as of the end of the proof. This is used to resolve variables
   /// and subproof names.
   pub lc: Option<LocalContext>,
+  /// The local variables of the lisp code in the statement, as `(scope, x, binder)`, where
+  /// `binder` is the span of the binder of `x` and `scope` is the part of the statement
+  /// in which `x` can be used. This is used to offer the variables in completions.
+  pub lisp_scopes: Vec<(Span, AtomId, Span)>,
   /// The actual data associated to spans. They are indexed by span start, and one
   /// start point can contain many spans, even multiple data elements at the same span.
   data: BTreeMap<usize, Vec<(Span, T)>>,
@@ -59,6 +63,7 @@ impl<T> Spans<T> {
       stmt: MaybeUninit::uninit(),
       decl: MaybeUninit::uninit(),
       lc: None,
+      lisp_scopes: vec![],
       data: BTreeMap::new()
     }
   }
@@ -114,7 +119,7 @@ impl<T> Spans<T> {
     }
     let v = & /* 'c */ mut *v;
     v.push((sp, val));
-    &mut unwrap_unchecked!(v.last_mut()).1
+    &mut v.last_mut().expect("just pushed").1
   }
 
   /// Insert a data element at a given span, if it lies within the current statement's extent.
@@ -142,6 +147,9 @@ impl<T> Spans<T> {
       v.iter_mut().find(|x| x.0 == sp).map(|x| &mut x.1))
   }
 
+  /// Returns an iterator over all data elements, in order of their start position.
+  pub fn iter(&self) -> impl Iterator<Item=&(Span, T)> { self.data.values().flatten() }
+
   /// Returns an iterator over all data elements in spans that overlap the target
   /// position. ([`Span`]s are considered as closed,
   /// i.e. `start <= pos <= end`, for this purpose.)
@@ -0,0 +1,290 @@
+//! A standalone proof checker, which replays a stored [`Proof`] against the statements
+//! of the theorems and definitions it refers to.
+//!
+//! Proofs produced by the elaborator are correct by construction (modulo bugs), but proofs
+//! that come from other sources, such as imported `.mmb` files, are otherwise trusted
+//! without checking. This module is used to audit such proofs on request.
+
+use std::rc::Rc;
+use std::collections::HashMap;
+use crate::{AtomId, Environment, ExprNode, Proof, ProofNode, SortId, TermId, TermKind,
+  Thm, ThmId, ThmKind, Type};
+
+/// A fully expanded expression. Sharing is preserved through the [`Rc`]s, so that
+/// comparing expressions which came from the same heap element is cheap.
+#[derive(Debug, PartialEq, Eq)]
+enum Tm {
+  /// A variable of the theorem being checked.
+  Var(usize),
+  /// A dummy variable introduced in the proof.
+  Dummy(AtomId, SortId),
+  /// A term constructor applied to arguments.
+  App(TermId, Box<[Rc<Tm>]>),
+}
+
+/// The result of checking a proof node.
+#[derive(Clone, Debug)]
+enum Val {
+  /// An expression
+  Expr(Rc<Tm>),
+  /// A proof of `|- e`
+  Proof(Rc<Tm>),
+  /// A conversion proof `e1 = e2`
+  Conv(Rc<Tm>, Rc<Tm>),
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+struct Checker<'a> {
+  env: &'a Environment,
+  args: &'a [(Option<AtomId>, Type)],
+  /// `bound[i]` is the index of variable `i` among the bound variables, if it is bound.
+  bound: Vec<Option<u32>>,
+  hyps: Vec<Rc<Tm>>,
+  heap: Vec<Val>,
+}
+
+/// Instantiate an expression from a statement (a theorem hypothesis or conclusion),
+/// given the values of the heap so far. The first elements of `heap` are the
+/// substitution for the variables.
+fn inst(heap: &[Rc<Tm>], e: &ExprNode) -> Rc<Tm> {
+  match *e {
+    ExprNode::Ref(i) => heap[i].clone(),
+    ExprNode::Dummy(a, s) => Rc::new(Tm::Dummy(a, s)),
+    ExprNode::App(t, ref es) => Rc::new(Tm::App(t, es.iter().map(|e| inst(heap, e)).collect())),
+  }
+}
+
+/// Build the heap of a statement given the substitution for the variables.
+fn inst_heap(heap: &[ExprNode], subst: Vec<Rc<Tm>>) -> Vec<Rc<Tm>> {
+  let mut out = subst;
+  for e in &heap[out.len()..] {
+    let v = inst(&out, e);
+    out.push(v)
+  }
+  out
+}
+
+impl Val {
+  fn as_expr(self) -> Result<Rc<Tm>> {
+    if let Val::Expr(e) = self { Ok(e) } else { Err("expected an expression".into()) }
+  }
+
+  fn as_proof(self) -> Result<Rc<Tm>> {
+    if let Val::Proof(e) = self { Ok(e) } else { Err("expected a proof".into()) }
+  }
+
+  fn as_conv(self) -> Result<(Rc<Tm>, Rc<Tm>)> {
+    if let Val::Conv(e1, e2) = self { Ok((e1, e2)) } else { Err("expected a conversion".into()) }
+  }
+}
+
+impl Checker<'_> {
+  fn name_thm(&self, t: ThmId) -> &str { self.env.data[self.env.thms[t].atom].name.as_str() }
+  fn name_term(&self, t: TermId) -> &str { self.env.data[self.env.terms[t].atom].name.as_str() }
+
+  fn sort(&self, e: &Tm) -> SortId {
+    match *e {
+      Tm::Var(i) => self.args[i].1.sort(),
+      Tm::Dummy(_, s) => s,
+      Tm::App(t, _) => self.env.terms[t].ret.0,
+    }
+  }
+
+  fn is_bound(&self, e: &Tm) -> bool {
+    match *e {
+      Tm::Var(i) => self.bound[i].is_some(),
+      Tm::Dummy(..) => true,
+      Tm::App(..) => false,
+    }
+  }
+
+  /// Returns true if the expression `e` may depend on the bound variable `v`.
+  fn depends(&self, e: &Tm, v: &Tm) -> bool {
+    if e == v { return true }
+    match (e, v) {
+      (&Tm::Var(i), &Tm::Var(j)) => match (self.args[i].1, self.bound[j]) {
+        (Type::Reg(_, deps), Some(n)) => deps & (1 << n) != 0,
+        _ => false
+      },
+      (Tm::App(_, es), _) => es.iter().any(|e| self.depends(e, v)),
+      _ => false
+    }
+  }
+
+  /// Check that `es` is a valid substitution for the binders `bis`, including
+  /// the sort, boundedness and disjoint variable conditions.
+  fn check_subst(&self, bis: &[(Option<AtomId>, Type)], es: &[Rc<Tm>]) -> Result<()> {
+    let mut bvs: Vec<&Rc<Tm>> = vec![];
+    for (&(_, ty), e) in bis.iter().zip(es) {
+      if self.sort(e) != ty.sort() { return Err("sort mismatch".into()) }
+      match ty {
+        Type::Bound(_) => {
+          if !self.is_bound(e) { return Err("expected a bound variable".into()) }
+          if bvs.iter().any(|&v| v == e) { return Err("disjoint variable violation".into()) }
+          bvs.push(e)
+        }
+        Type::Reg(_, deps) => for (n, &v) in bvs.iter().enumerate() {
+          if deps & (1 << n) == 0 && self.depends(e, v) {
+            return Err("disjoint variable violation".into())
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Match the body of a definition (with the given argument substitution) against `tgt`,
+  /// assigning the dummy variables of the definition as we go.
+  fn unfold_match(&self, heap: &[ExprNode], args: &[Rc<Tm>],
+    dummies: &mut HashMap<AtomId, Rc<Tm>>, e: &ExprNode, tgt: &Rc<Tm>
+  ) -> bool {
+    match *e {
+      ExprNode::Ref(i) if i < args.len() => args[i] == *tgt,
+      ExprNode::Ref(i) => self.unfold_match(heap, args, dummies, &heap[i], tgt),
+      ExprNode::Dummy(a, s) => {
+        if let Some(v) = dummies.get(&a) { return v == tgt }
+        // the dummy can be instantiated by a dummy of the proof or a bound variable
+        // of the theorem, as long as it is fresh
+        if !self.is_bound(tgt) || self.sort(tgt) != s ||
+          dummies.values().any(|v| v == tgt) ||
+          args.iter().any(|e| self.depends(e, tgt)) { return false }
+        dummies.insert(a, tgt.clone());
+        true
+      }
+      ExprNode::App(t, ref es) => match &**tgt {
+        Tm::App(t2, es2) if t == *t2 && es.len() == es2.len() =>
+          es.iter().zip(&**es2).all(|(e, tgt)| self.unfold_match(heap, args, dummies, e, tgt)),
+        _ => false
+      }
+    }
+  }
+
+  fn node(&self, p: &ProofNode) -> Result<Val> {
+    Ok(match *p {
+      ProofNode::Ref(i) => self.heap.get(i).ok_or("reference out of range")?.clone(),
+      ProofNode::Dummy(a, s) => Val::Expr(Rc::new(Tm::Dummy(a, s))),
+      ProofNode::Term {term, ref args} => {
+        let td = self.env.terms.get(term).ok_or("unknown term")?;
+        if td.args.len() != args.len() {
+          return Err(format!("{}: incorrect number of arguments", self.name_term(term)))
+        }
+        let args = args.iter().map(|e| self.node(e)?.as_expr()).collect::<Result<Box<[_]>>>()?;
+        for (&(_, ty), e) in td.args.iter().zip(&*args) {
+          if self.sort(e) != ty.sort() || (ty.bound() && !self.is_bound(e)) {
+            return Err(format!("{}: argument type mismatch", self.name_term(term)))
+          }
+        }
+        Val::Expr(Rc::new(Tm::App(term, args)))
+      }
+      ProofNode::Hyp(i, ref e) => {
+        let e = self.node(e)?.as_expr()?;
+        if self.hyps.get(i) != Some(&e) { return Err(format!("hypothesis {} mismatch", i)) }
+        Val::Proof(e)
+      }
+      ProofNode::Thm {thm, ref args, ref res} => {
+        let td = self.env.thms.get(thm).ok_or("unknown theorem")?;
+        let nargs = td.args.len();
+        if args.len() != nargs + td.hyps.len() {
+          return Err(format!("{}: incorrect number of arguments", self.name_thm(thm)))
+        }
+        let subst = args[..nargs].iter()
+          .map(|e| self.node(e)?.as_expr()).collect::<Result<Vec<_>>>()?;
+        self.check_subst(&td.args, &subst).map_err(|e| format!("{}: {}", self.name_thm(thm), e))?;
+        let heap = inst_heap(&td.heap, subst);
+        for (i, ((_, h), p)) in td.hyps.iter().zip(&args[nargs..]).enumerate() {
+          if inst(&heap, h) != self.node(p)?.as_proof()? {
+            return Err(format!("{}: hypothesis {} mismatch", self.name_thm(thm), i))
+          }
+        }
+        let res = self.node(res)?.as_expr()?;
+        if inst(&heap, &td.ret) != res {
+          return Err(format!("{}: conclusion mismatch", self.name_thm(thm)))
+        }
+        Val::Proof(res)
+      }
+      ProofNode::Conv(ref p) => {
+        let tgt = self.node(&p.0)?.as_expr()?;
+        let (e1, e2) = self.node(&p.1)?.as_conv()?;
+        let src = self.node(&p.2)?.as_proof()?;
+        if tgt != e1 || src != e2 { return Err("conversion mismatch".into()) }
+        Val::Proof(tgt)
+      }
+      ProofNode::Refl(ref e) => {
+        let e = self.node(e)?.as_expr()?;
+        Val::Conv(e.clone(), e)
+      }
+      ProofNode::Sym(ref c) => {
+        let (e1, e2) = self.node(c)?.as_conv()?;
+        Val::Conv(e2, e1)
+      }
+      ProofNode::Cong {term, ref args} => {
+        let td = self.env.terms.get(term).ok_or("unknown term")?;
+        if td.args.len() != args.len() {
+          return Err(format!("{}: incorrect number of arguments", self.name_term(term)))
+        }
+        let (mut lhs, mut rhs) = (Vec::with_capacity(args.len()), Vec::with_capacity(args.len()));
+        for (&(_, ty), c) in td.args.iter().zip(&**args) {
+          let (e1, e2) = self.node(c)?.as_conv()?;
+          if self.sort(&e1) != ty.sort() || (ty.bound() && !self.is_bound(&e1)) {
+            return Err(format!("{}: argument type mismatch", self.name_term(term)))
+          }
+          // bound variables can't be rewritten, so this must be a reflexivity proof
+          if ty.bound() && e1 != e2 {
+            return Err(format!("{}: bound argument is not reflexive", self.name_term(term)))
+          }
+          lhs.push(e1);
+          rhs.push(e2);
+        }
+        Val::Conv(Rc::new(Tm::App(term, lhs.into())), Rc::new(Tm::App(term, rhs.into())))
+      }
+      ProofNode::Unfold {term, ref args, ref res} => {
+        let td = self.env.terms.get(term).ok_or("unknown term")?;
+        let val = match &td.kind {
+          TermKind::Def(Some(val)) => val,
+          _ => return Err(format!("{}: not a definition", self.name_term(term)))
+        };
+        let args = args.iter().map(|e| self.node(e)?.as_expr()).collect::<Result<Box<[_]>>>()?;
+        let sub_lhs = self.node(&res.0)?.as_expr()?;
+        let (e1, rhs) = self.node(&res.1)?.as_conv()?;
+        if e1 != sub_lhs || args.len() != td.args.len() ||
+          !self.unfold_match(&val.heap, &args, &mut HashMap::new(), &val.head, &sub_lhs) {
+          return Err(format!("{}: unfolding mismatch", self.name_term(term)))
+        }
+        Val::Conv(Rc::new(Tm::App(term, args)), rhs)
+      }
+    })
+  }
+}
+
+/// Replay the proof of theorem `t` against the current environment. Axioms and
+/// theorems without a proof trivially succeed. On failure, returns a description of the
+/// first step that failed to check.
+pub fn verify_thm(env: &Environment, t: ThmId) -> Result<()> { verify(env, &env.thms[t]) }
+
+/// Replay the proof of the theorem `td`, which need not be in the environment yet.
+/// See [`verify_thm`].
+pub fn verify(env: &Environment, td: &Thm) -> Result<()> {
+  let Proof {heap, head, ..} = match &td.kind {
+    ThmKind::Thm(Some(pf)) => pf,
+    ThmKind::Axiom | ThmKind::Thm(None) => return Ok(())
+  };
+  let nargs = td.args.len();
+  let mut n = 0;
+  let bound = td.args.iter().map(|(_, ty)| if ty.bound() { n += 1; Some(n - 1) } else { None }).collect();
+  let vars = (0..nargs).map(|i| Rc::new(Tm::Var(i))).collect::<Vec<_>>();
+  let stmt = inst_heap(&td.heap, vars.clone());
+  let mut ck = Checker {
+    env, args: &td.args, bound,
+    hyps: td.hyps.iter().map(|(_, h)| inst(&stmt, h)).collect(),
+    heap: vars.into_iter().map(Val::Expr).collect(),
+  };
+  for p in heap.get(nargs..).unwrap_or_default() {
+    let v = ck.node(p)?;
+    ck.heap.push(v)
+  }
+  if ck.node(head)?.as_proof()? != inst(&stmt, &td.ret) {
+    return Err("proof does not match the theorem statement".into())
+  }
+  Ok(())
+}
@@ -0,0 +1,418 @@
+//! Stable error codes for elaboration errors, and the `explain` subcommand which prints
+//! an extended description of an error code.
+//!
+//! Error codes are attached to an [`ElabError`] using [`ElabError::with_code`], and are
+//! shown in the command line output as `error[E0004]: ...` and in the language server as
+//! the diagnostic code. Codes are never reused or renumbered, so that they can be
+//! searched for; an error that no longer exists keeps its code reserved. Each code also has
+//! a name like `unknown-sort`, which is used to refer to it in `@(allow)` and `@(expect)`
+//! annotations.
+//!
+//! [`ElabError`]: crate::ElabError
+//! [`ElabError::with_code`]: crate::ElabError::with_code
+
+use std::fmt;
+use clap::ArgMatches;
+
+macro_rules! error_codes {
+  ($($(#[doc=$doc:expr])* $e:ident: $s:expr, $name:expr, $expl:expr;)*) => {
+    /// An error code, which identifies a class of errors reported by the elaborator.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum ErrorCode { $($(#[doc=$doc])* $e),* }
+    crate::deep_size_0!(ErrorCode);
+
+    impl ErrorCode {
+      /// Convert an `ErrorCode` to a string, like `E0001`.
+      #[must_use] pub fn to_str(self) -> &'static str {
+        match self { $(Self::$e => $s),* }
+      }
+
+      /// Convert a string like `E0001` into an `ErrorCode`.
+      #[allow(clippy::should_implement_trait)]
+      #[must_use] pub fn from_str(s: &str) -> Option<Self> {
+        match s { $($s => Some(Self::$e),)* _ => None }
+      }
+
+      /// The name of the error code, like `unknown-sort`, which can be used in place of the
+      /// code in `@(allow)` and `@(expect)` annotations.
+      #[must_use] pub fn name(self) -> &'static str {
+        match self { $(Self::$e => $name),* }
+      }
+
+      /// Convert an error code name like `unknown-sort` into an `ErrorCode`.
+      #[must_use] pub fn from_name(s: &str) -> Option<Self> {
+        match s { $($name => Some(Self::$e),)* _ => None }
+      }
+
+      /// A one line description of the error.
+      #[must_use] pub fn description(self) -> &'static str {
+        match self { $(Self::$e => concat!($($doc),*).trim()),* }
+      }
+
+      /// The extended description of the error, with examples and common fixes.
+      #[must_use] pub fn explanation(self) -> &'static str {
+        match self { $(Self::$e => $expl),* }
+      }
+
+      /// Iterate over all the error codes.
+      pub fn for_each(mut f: impl FnMut(Self)) { $(f(Self::$e);)* }
+    }
+  }
+}
+
+error_codes! {
+  /// Syntax error
+  Syntax: "E0001", "syntax", r#"
+The file could not be parsed. The parser recovers from most errors by skipping to the
+next `;`, so an error can cause the following statement to be ignored as well.
+
+    term foo: wff > wff    -- missing ';'
+    term bar: wff;
+
+Common fixes:
+* Check that every statement ends with `;`.
+* Math strings are delimited by `$`, and cannot contain `$` themselves.
+* Lisp expressions in `do` blocks must have balanced parentheses.
+"#;
+
+  /// Redeclaration
+  Redeclaration: "E0002", "redeclaration", r#"
+A sort, term, theorem or notation was declared with a name that is already in use. The
+error points at the second declaration, and the related information points at the first.
+
+    term foo: wff;
+    term foo: wff > wff;   -- error: term 'foo' already declared
+
+Terms and theorems share a namespace, so a theorem cannot have the same name as a term.
+A notation constant can only be declared once, unless it is declared again with the same
+precedence. Names declared in imported files are also in scope. If two imports declare
+the same name with the same statement (for example two copies of a common dependency),
+the declarations are identified; otherwise the error shows both statements.
+
+Common fixes:
+* Rename one of the declarations.
+* If the name comes from an import, use `(align-name)` to identify the two declarations.
+"#;
+
+  /// Unknown sort
+  UnknownSort: "E0003", "unknown-sort", r#"
+A sort name was used that has not been declared (yet). Declarations are processed in
+order, so a sort must be declared before it is used.
+
+    term foo: nat > wff;   -- error: unknown sort 'nat'
+    sort nat;
+
+Common fixes:
+* Check the spelling of the sort name.
+* Move the `sort` declaration before its first use, or add the missing `import`.
+"#;
+
+  /// Unknown term
+  UnknownTerm: "E0004", "unknown-term", r#"
+An expression used a term constructor that has not been declared (yet).
+
+    theorem foo: $ bar x $;   -- error: term 'bar' not declared
+
+This error also occurs when a notation is used before it is declared, or when a lisp
+value that is not a valid expression is used in a math expression.
+
+Common fixes:
+* Check the spelling of the term name.
+* Move the `term` or `def` before its first use, or add the missing `import`.
+"#;
+
+  /// Unknown theorem
+  UnknownTheorem: "E0005", "unknown-theorem", r#"
+A proof referred to a theorem or hypothesis that does not exist (yet).
+
+    theorem foo (h: $ a $): $ a $ = 'hh;   -- error: unknown theorem/hypothesis 'hh'
+
+Theorems can only be used after they are declared, and hypotheses are only in scope in
+the proof of the theorem that declares them.
+
+Common fixes:
+* Check the spelling of the theorem or hypothesis name.
+* Move the theorem before its first use, or add the missing `import`.
+"#;
+
+  /// Unknown variable
+  UnknownVariable: "E0006", "unknown-variable", r#"
+An expression used a variable that is not bound in the current declaration.
+
+    theorem foo (a: wff): $ a -> b $;   -- error: variable 'b' not found
+
+If the variable is declared in a `variable` statement or the file uses `(set-dep-policy)`
+this can be inferred, but only in theorem statements, not in `def` bodies.
+
+Common fixes:
+* Add the variable to the binders of the declaration.
+* Check the spelling of the variable name, which may also be a term that was not declared.
+"#;
+
+  /// Unification failure
+  UnifyFailure: "E0007", "unify-failure", r#"
+A proof step does not prove the statement that is required at that point. The error shows
+both sides as `failed to unify: e1 =?= e2`, where `e1` is the expected statement and `e2`
+is the statement of the proof.
+
+    axiom ax_mp (h1: $ a -> b $) (h2: $ a $): $ b $;
+    theorem foo (h: $ a $): $ b $ = '(ax_mp h h);   -- error: failed to unify
+
+Common fixes:
+* Check the order of the hypotheses of the theorem being applied.
+* Use `_` to leave a subproof as a goal, and look at the goal with hover.
+* If the statements are equal up to definitions, use `(:conv)` or `unfold`.
+"#;
+
+  /// Disjoint variable violation
+  DisjointVars: "E0008", "disjoint-vars", r#"
+A theorem was applied with a substitution that does not respect its disjoint variable
+conditions: a bound variable is substituted with an expression that contains a variable
+that the theorem requires to be disjoint from it.
+
+    axiom ax_17 {x: nat} (a: wff): $ a -> A. x a $;
+    theorem foo {x: nat} (a: wff x): $ a -> A. x a $ = 'ax_17;   -- error
+
+Here `a` may depend on `x` in `foo`, but `ax_17` requires that it does not.
+
+Common fixes:
+* Remove the dependency from the binders of the theorem being proved, like `(a: wff)`,
+  if it is not needed.
+* Rename bound variables apart, using `(! foo y)` to choose a different bound variable.
+"#;
+
+  /// Timeout
+  Timeout: "E0009", "timeout", r#"
+Elaborating a declaration or `do` block took longer than the timeout, which is 5 seconds
+by default. The error points at the lisp expression that was running when the time ran
+out.
+
+Common fixes:
+* Check for infinite loops in tactics.
+* Use `(set-timeout n)` to raise the timeout to `n` milliseconds, or `(set-timeout 0)`
+  to remove it.
+"#;
+
+  /// Stack overflow
+  StackOverflow: "E0010", "stack-overflow", r#"
+A lisp evaluation used more than the maximum number of stack frames, which is 1024 by
+default. This is usually caused by unbounded recursion.
+
+    do { (def (f x) (+ (f x) 1)) (f 0) };   -- error: stack overflow
+
+Common fixes:
+* Check that recursive functions have a base case that is reached.
+* Use `(set-stack-limit n)` to allow deeper recursion.
+"#;
+
+  /// Import cycle
+  ImportCycle: "E0011", "import-cycle", r#"
+A file imports itself, directly or through other files. The error lists the chain of
+imports that leads back to the file, and points at each `import` statement in the cycle.
+Files that import a file in the cycle, without being part of it, get the same error on
+that import.
+
+Common fixes:
+* Move the declarations needed by both files into a third file, imported by both.
+"#;
+
+  /// Useless dummy variable
+  UselessDummy: "E0012", "useless-dummy", r#"
+A dummy variable was declared in the binders of a definition or theorem but is not used
+in its value or proof. This is a warning.
+
+    def foo {.x: nat}: wff = $ T. $;   -- warning: useless dummy variable
+
+Common fixes:
+* Remove the dummy variable from the binders.
+* Use `@(allow useless-dummy)` on the declaration to silence the warning.
+"#;
+
+  /// Declaration uses sorry
+  Sorry: "E0013", "sorry", r#"
+A theorem or definition was completed using `sorry`, or a proof left goals that were closed
+by `sorry`. The declaration is accepted but is not actually proved, and it cannot be
+exported. This is a warning.
+
+    theorem foo: $ a $ = 'sorry;   -- warning: declaration uses sorry
+
+Common fixes:
+* Finish the proof.
+* Use `@(allow sorry)` on the declaration to silence the warning while it is in progress.
+"#;
+
+  /// Declaration missing value
+  MissingValue: "E0014", "missing-value", r#"
+A `theorem` or `def` in an MM1 file was declared without a proof or value. It is treated
+as an axiom, or an abstract definition, respectively. This is a warning.
+
+    theorem foo: $ a $;   -- warning: theorem declaration missing value
+
+Common fixes:
+* Add the proof or value, like `theorem foo: $ a $ = '(...);`.
+* Use `axiom` instead of `theorem` if the statement is intended to be an axiom.
+"#;
+
+  /// Inferred dependency
+  InferredDep: "E0015", "inferred-dep", r#"
+A variable was not declared in the binders, and its dependencies were inferred. This is
+only reported if the dependency policy is set to `warn` or `strict` using
+`(set-dep-policy)`, in which case it is a warning or an error respectively.
+
+    do { (set-dep-policy 'warn) };
+    theorem foo {x: nat}: $ A. x a $;   -- warning: inferred (a: wff) with no dependencies
+
+Common fixes:
+* Declare the variable in the binders, with the intended dependencies, like `(a: wff x)`.
+"#;
+
+  /// Not allowed in MM0 mode
+  Mm0Mode: "E0016", "mm0-mode", r#"
+An MM0 file (with the `.mm0` extension) used a feature that is only available in MM1 files,
+such as `do` blocks, proofs, or type inference. This is a warning, because the file is
+still elaborated, but it will not be accepted by other MM0 verifiers.
+
+Common fixes:
+* Remove the MM1 feature, for example by declaring all variables in the binders.
+* Rename the file to `.mm1` if it is not intended to be an MM0 specification.
+"#;
+
+  /// Unmet expectation
+  UnmetExpectation: "E0017", "unmet-expectation", r#"
+A declaration with an `@(expect)` annotation did not produce the expected error. The
+annotation lists what is expected: `error` or `warning` for any diagnostic of that level,
+or an error code or its name for diagnostics with that code. If no argument is given,
+any error is expected.
+
+    @(expect unknown-sort) term foo: nat > wff;   -- ok, if 'nat' is not declared
+    @(expect error) theorem bar: $ a $ = 'ax_1;  -- error, if the proof is correct
+
+Diagnostics matching the expectation are not reported, so this can be used to write
+negative tests, which check that an invalid declaration is rejected.
+
+Common fixes:
+* Check that the declaration actually fails, and with the expected error code.
+"#;
+
+  /// Builtin redefined
+  ShadowedBuiltin: "E0018", "shadowed-builtin", r#"
+A global lisp definition uses the name of a builtin procedure. This is allowed, and later
+references to the name will use the new definition, but it is usually a mistake, because
+the builtin can no longer be called by name. The original builtin is still available using
+`(builtin 'name)`.
+
+    do { (def (map f xs) (f xs)) };   -- warning: 'map' shadows a builtin
+    do { (def (map f xs) ((builtin 'map) f xs)) };   -- same warning, but calls the builtin
+
+Common fixes:
+* Rename the definition, if the builtin was not intended to be replaced.
+* Use `@(allow shadowed-builtin)` on the `do` block if the redefinition is intentional.
+"#;
+
+  /// Unsolved goal
+  UnsolvedGoal: "E0019", "unsolved-goal", r#"
+A proof ended with goals that were not solved, or the proof term contains a metavariable
+(a hole like `_` that unification did not fill in). The error message shows the statement
+of the goal, or the type of the metavariable.
+
+    theorem foo (h: $ a $): $ a /\ a $ = '(andI h _);   -- error: |- a
+
+Common fixes:
+* Finish the proof, for example by replacing the hole with a proof of the goal.
+* Use `sorry` to close the goal for now, which turns the error into a warning.
+* `mm0-rs holes` lists all unsolved goals and uses of `sorry` in a file and its imports.
+"#;
+
+  /// Not a list
+  NotAList: "E0020", "not-a-list", r#"
+A lisp builtin that takes a list, such as `hd`, `tl` or `nth`, was applied to something
+else. The error object passed to a `try` handler has the offending value as its `value`.
+
+    do { (hd 1) };   -- error: expected a list, got 1
+
+Common fixes:
+* Check the value with `pair?` or `null?` before taking it apart, or use `match`.
+"#;
+
+  /// Empty list
+  EmptyList: "E0021", "empty-list", r#"
+`hd` or `tl` was applied to the empty list `()`.
+
+    do { (hd ()) };   -- error: evaluating 'hd ()'
+
+Common fixes:
+* Check for the empty list with `null?` first, or use `match` with a case for `()`.
+"#;
+
+  /// Wrong number of arguments
+  WrongArity: "E0022", "wrong-arity", r#"
+A lisp procedure was called with the wrong number of arguments. The error object passed
+to a `try` handler has the procedure as its `value`.
+
+    do { (def (f x) x) (f 1 2) };   -- error: expected 1 argument(s)
+
+Common fixes:
+* Check the definition of the function, or use `(help f)` to see the usage of a builtin.
+"#;
+
+  /// Mismatched list lengths
+  LengthMismatch: "E0023", "length-mismatch", r#"
+`map` was given several lists of different lengths, or an argument that is not a list.
+
+    do { (map + '(1 2) '(3)) };   -- error: mismatched input length
+
+Common fixes:
+* Check that the lists have the same length before mapping over them together.
+"#;
+
+  /// Use of a deprecated declaration
+  Deprecated: "E0024", "deprecated", r#"
+A declaration that has been marked as deprecated was used. mm0-rs does not mark
+declarations itself; this warning is reported by lisp code in the library, using
+`(report-at sp 'deprecated msg)`, usually from an `after-decl` hook or a tactic.
+It belongs to the `deprecated` warning category.
+
+    do { (report-at 'foo 'deprecated "foo is deprecated, use bar") };
+
+Common fixes:
+* Use the replacement suggested in the message.
+* Use `(set-option 'warn-deprecated 'off)` or `--warn deprecated=off` to hide these warnings.
+"#;
+
+  /// Slow declaration
+  SlowDecl: "E0025", "slow-decl", r#"
+A declaration took longer to elaborate than the `slow-decl-ms` option (1000 by default).
+This warning belongs to the `slow-decl` warning category, which is off unless enabled
+with `(set-option 'warn-slow-decl 'warn)` or `--warn slow-decl=warn`.
+
+    do { (set-option 'warn-slow-decl 'warn) (set-option 'slow-decl-ms 100) };
+    theorem foo: $ a $ = (slow-tactic);   -- warning: declaration took 250ms to elaborate
+
+Common fixes:
+* Replace expensive tactics like `auto` by the proof they find (see `mm0-rs compile --minimize`).
+* Use `mm0-rs profile` to find the slowest declarations of a file.
+"#;
+}
+
+impl fmt::Display for ErrorCode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.to_str()) }
+}
+
+/// Main entry point for `mm0-rs explain` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs explain [CODE]`, where:
+///
+/// - `CODE` is an error code like `E0001`, or its name like `syntax`. If omitted, all error codes are listed.
+pub fn main(args: &ArgMatches<'_>) -> std::io::Result<()> {
+  match args.value_of("CODE") {
+    None => ErrorCode::for_each(|c| println!("{} {}: {}", c, c.name(), c.description())),
+    Some(s) => match ErrorCode::from_str(&s.to_ascii_uppercase()).or_else(|| ErrorCode::from_name(s)) {
+      Some(c) => println!("{}: {}\n{}", c, c.description(), c.explanation()),
+      None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+        format!("unknown error code '{}'", s))),
+    }
+  }
+  Ok(())
+}
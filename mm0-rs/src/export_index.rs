@@ -0,0 +1,235 @@
+//! Declaration index export.
+//!
+//! This module implements the `mm0-rs export-index` subcommand, which elaborates a file and
+//! writes a JSON description of every sort and declaration in it and its imports: the name
+//! and kind, the location, the pretty printed statement (as shown on hover), the statement as
+//! an s-expression (in the format of `get-decl`), the doc comment, and the sorts, terms and
+//! theorems that the declaration uses. It is meant as input for external search tools, which
+//! can index a large library without having to parse or elaborate MM1 themselves.
+use std::collections::BTreeSet;
+use std::{fs, io};
+use std::io::Write;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use crate::compiler::{elab_for_result, mk_get_text};
+use crate::{AtomId, DeclKey, Environment, ExprNode, FileRef, FileSpan, FormatEnv, LinedString,
+  LispVal, Modifiers, ProofNode, SortId, StmtTrace, TermId, TermKind, ThmId, ThmKind};
+
+/// The declarations used by a declaration.
+#[derive(Default)]
+struct Deps {
+  sorts: BTreeSet<SortId>,
+  terms: BTreeSet<TermId>,
+  thms: BTreeSet<ThmId>,
+}
+
+impl Deps {
+  fn expr(&mut self, e: &ExprNode) {
+    match *e {
+      ExprNode::Ref(_) => {}
+      ExprNode::Dummy(_, s) => { self.sorts.insert(s); }
+      ExprNode::App(t, ref es) => { self.terms.insert(t); for e in &**es { self.expr(e) } }
+    }
+  }
+
+  fn proof(&mut self, p: &ProofNode) {
+    match p {
+      ProofNode::Ref(_) => {}
+      &ProofNode::Dummy(_, s) => { self.sorts.insert(s); }
+      ProofNode::Term {term, args} | ProofNode::Cong {term, args} => {
+        self.terms.insert(*term);
+        for p in &**args { self.proof(p) }
+      }
+      ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => self.proof(p),
+      ProofNode::Thm {thm, args, res} => {
+        self.thms.insert(*thm);
+        for p in &**args { self.proof(p) }
+        self.proof(res)
+      }
+      ProofNode::Conv(p) => { self.proof(&p.0); self.proof(&p.1); self.proof(&p.2) }
+      ProofNode::Unfold {term, args, res} => {
+        self.terms.insert(*term);
+        for p in &**args { self.proof(p) }
+        self.proof(&res.0);
+        self.proof(&res.1)
+      }
+    }
+  }
+
+  fn to_json(&self, env: &Environment) -> Value {
+    let name = |a: AtomId| env.data[a].name.to_string();
+    json!({
+      "sorts": self.sorts.iter().map(|&s| env.sorts[s].name.to_string()).collect::<Vec<_>>(),
+      "terms": self.terms.iter().map(|&t| name(env.terms[t].atom)).collect::<Vec<_>>(),
+      "theorems": self.thms.iter().map(|&t| name(env.thms[t].atom)).collect::<Vec<_>>(),
+    })
+  }
+}
+
+/// The visibility modifier of a declaration, as used by `get-decl`.
+fn vis(mods: Modifiers) -> LispVal {
+  match mods {
+    Modifiers::PUB => LispVal::atom(AtomId::PUB),
+    Modifiers::ABSTRACT => LispVal::atom(AtomId::ABSTRACT),
+    Modifiers::LOCAL => LispVal::atom(AtomId::LOCAL),
+    _ => LispVal::nil(),
+  }
+}
+
+/// The JSON description of the sort or declaration `a`, or `None` if there is no such
+/// declaration. `get_text` gets the source text of a file, for computing line numbers.
+fn entry(fe: FormatEnv<'_>, a: AtomId, sort: bool,
+  get_text: &mut impl FnMut(&FileSpan) -> Option<std::sync::Arc<LinedString>>
+) -> Option<Value> {
+  let env = fe.env;
+  let ad = &env.data[a];
+  let mut deps = Deps::default();
+  let (kind, fsp, doc, statement, sexpr) = if sort {
+    let sd = &env.sorts[ad.sort?];
+    ("sort", &sd.span, &sd.doc, format!("{}sort {};", sd.mods, sd.name), Value::Null)
+  } else {
+    match ad.decl? {
+      DeclKey::Term(t) => {
+        let td = &env.terms[t];
+        let mut bvs = vec![];
+        let mut heap = vec![];
+        let mut sexpr = vec![
+          LispVal::atom(if let TermKind::Term = td.kind {AtomId::TERM} else {AtomId::DEF}),
+          LispVal::atom(a),
+          env.binders(&td.args, &mut heap, &mut bvs),
+          LispVal::list(vec![LispVal::atom(env.sorts[td.ret.0].atom), Environment::deps(&bvs, td.ret.1)]),
+        ];
+        for (_, ty) in &*td.args { deps.sorts.insert(ty.sort()); }
+        deps.sorts.insert(td.ret.0);
+        if let TermKind::Def(Some(v)) = &td.kind {
+          sexpr.push(vis(td.vis));
+          let mut ds = vec![];
+          for e in &v.heap[heap.len()..] {
+            let e = env.expr_node(&heap, &mut Some(&mut ds), e);
+            heap.push(e)
+          }
+          let ret = env.expr_node(&heap, &mut Some(&mut ds), &v.head);
+          sexpr.push(LispVal::list(ds));
+          sexpr.push(ret);
+          for e in &*v.heap { deps.expr(e) }
+          deps.expr(&v.head);
+        }
+        let kind = if let TermKind::Term = td.kind {"term"} else {"def"};
+        let sexpr = LispVal::list(sexpr);
+        (kind, &td.span, &td.doc, format!("{}", fe.to(td)), json!(format!("{}", fe.to(&sexpr))))
+      }
+      DeclKey::Thm(t) => {
+        let td = &env.thms[t];
+        let (bis, hyps, ret, _) = env.thm_statement(td);
+        let (head, kind) = if let ThmKind::Axiom = td.kind {
+          (AtomId::AXIOM, "axiom")
+        } else {
+          (AtomId::THM, "theorem")
+        };
+        let sexpr = LispVal::list(vec![LispVal::atom(head), LispVal::atom(a), bis, hyps, ret]);
+        for (_, ty) in &*td.args { deps.sorts.insert(ty.sort()); }
+        for e in &*td.heap { deps.expr(e) }
+        for (_, e) in &*td.hyps { deps.expr(e) }
+        deps.expr(&td.ret);
+        if let ThmKind::Thm(Some(pf)) = &td.kind {
+          for p in pf.heap.iter().chain(&*pf.hyps).chain(Some(&pf.head)) { deps.proof(p) }
+        }
+        deps.thms.remove(&t);
+        (kind, &td.span, &td.doc, format!("{}", fe.to(td)), json!(format!("{}", fe.to(&sexpr))))
+      }
+    }
+  };
+  if let Some(DeclKey::Term(t)) = ad.decl { deps.terms.remove(&t); }
+  let line = get_text(fsp).map(|text| text.to_pos(fsp.span.start).line + 1);
+  Some(json!({
+    "name": ad.name.to_string(),
+    "kind": kind,
+    "file": fsp.file.rel(),
+    "line": line,
+    "statement": statement,
+    "sexpr": sexpr,
+    "doc": doc.as_deref(),
+    "deps": deps.to_json(env),
+  }))
+}
+
+/// The premise selection record of theorem `t`, or `None` if it has no proof. It contains
+/// some simple features of the statement, namely the head term of the conclusion and the
+/// terms that appear in the conclusion and the hypotheses, and the theorems used in the proof.
+fn premises(env: &Environment, t: ThmId) -> Option<Value> {
+  let td = &env.thms[t];
+  let pf = if let ThmKind::Thm(Some(pf)) = &td.kind {pf} else {return None};
+  let mut head = &td.ret;
+  while let ExprNode::Ref(i) = *head {
+    if i < td.args.len() { break }
+    head = &td.heap[i]
+  }
+  let head = if let ExprNode::App(t, _) = *head {
+    Some(env.data[env.terms[t].atom].name.to_string())
+  } else {None};
+  let mut concl = Deps::default();
+  concl.expr(&td.ret);
+  let mut hyps = Deps::default();
+  for (_, e) in &*td.hyps { hyps.expr(e) }
+  let mut lemmas = Deps::default();
+  for p in pf.heap.iter().chain(&*pf.hyps).chain(Some(&pf.head)) { lemmas.proof(p) }
+  let terms = |deps: &Deps| deps.terms.iter()
+    .map(|&t| env.data[env.terms[t].atom].name.to_string()).collect::<Vec<_>>();
+  Some(json!({
+    "name": env.data[td.atom].name.to_string(),
+    "head": head,
+    "conclusion_terms": terms(&concl),
+    "hypothesis_terms": terms(&hyps),
+    "lemmas": lemmas.thms.iter().map(|&t| env.data[env.thms[t].atom].name.to_string()).collect::<Vec<_>>(),
+  }))
+}
+
+/// Main entry point for `mm0-rs export-index` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs export-index <in.mm1> [-o index.json] [--premises premises.jsonl]`, where:
+///
+/// - `in.mm1` is the file to elaborate. All the declarations in it and in the files it
+///   imports are exported, in declaration order.
+/// - `index.json` is the file to write the index to, or stdout if omitted. It contains a
+///   JSON array with an object for each declaration, with the fields `name`, `kind` (one
+///   of `sort`, `term`, `def`, `axiom` or `theorem`), `file`, `line`, `statement`, `sexpr`,
+///   `doc` and `deps` (an object with the names of the `sorts`, `terms` and `theorems`
+///   used in the statement, definition or proof).
+/// - `premises.jsonl`, if given, is a file to write data for premise selection experiments
+///   to. It has a line with a JSON object for each theorem with a proof, containing the
+///   head term of the conclusion, the terms in the conclusion and in the hypotheses, and the
+///   theorems used in the proof. This can be used to train a model that ranks the hints of
+///   `auto`, using the `rank-premises` hook.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  let (file, env) = elab_for_result(path.clone())?;
+  let env = env.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+    format!("{}: elaboration failed", path)))?;
+  let source = file.try_ascii().map_or_else(LinedString::default, |s| (**s).clone());
+  let fe = FormatEnv { source: &source, env: unsafe { env.thaw() } };
+  let mut get_text = mk_get_text();
+  let index = env.stmts().iter().filter_map(|s| match *s {
+    StmtTrace::Sort(a) => entry(fe, a, true, &mut get_text),
+    StmtTrace::Decl(a) => entry(fe, a, false, &mut get_text),
+    StmtTrace::Global(_) | StmtTrace::OutputString(_) => None,
+  }).collect::<Vec<_>>();
+  match args.value_of("output") {
+    Some(out) => serde_json::to_writer_pretty(io::BufWriter::new(fs::File::create(out)?), &index)?,
+    None => serde_json::to_writer_pretty(io::stdout().lock(), &index)?,
+  }
+  if let Some(out) = args.value_of("premises") {
+    let mut w = io::BufWriter::new(fs::File::create(out)?);
+    for s in env.stmts() {
+      let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+      let t = if let Some(DeclKey::Thm(t)) = env.data()[a].decl() {t} else {continue};
+      if let Some(v) = premises(fe.env, t) {
+        serde_json::to_writer(&mut w, &v)?;
+        writeln!(w)?
+      }
+    }
+  }
+  Ok(())
+}
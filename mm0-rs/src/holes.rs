@@ -0,0 +1,111 @@
+//! Report of unfinished proofs.
+//!
+//! This module collects the proof obligations that are left in a file and its imports: goals
+//! that were not solved at the end of a proof, metavariables in proof terms (both reported as
+//! [`UnsolvedGoal`](ErrorCode::UnsolvedGoal) errors), and declarations that use `sorry`
+//! (reported as [`Sorry`](ErrorCode::Sorry) warnings). It implements the `mm0-rs holes`
+//! subcommand and the `mm0.holes` command of the language server, which are useful for
+//! tracking the remaining work in a large development.
+use std::collections::HashSet;
+use std::{fs, io};
+use clap::ArgMatches;
+use serde_json::json;
+use crate::compiler::{elab_for_diagnostics, import_diagnostics};
+use crate::explain::ErrorCode;
+use crate::{ElabError, FileRef, LinedString, Span};
+
+/// The kind of an unfinished proof obligation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HoleKind {
+  /// A goal that was not solved, or a metavariable that was not assigned
+  Goal,
+  /// A declaration that uses `sorry`
+  Sorry,
+}
+
+impl HoleKind {
+  /// The name of the kind, as used in the JSON output.
+  #[must_use] pub fn name(self) -> &'static str {
+    match self {
+      HoleKind::Goal => "goal",
+      HoleKind::Sorry => "sorry",
+    }
+  }
+}
+
+/// An unfinished proof obligation.
+#[derive(Clone, Debug)]
+pub struct Hole {
+  /// The kind of obligation.
+  pub kind: HoleKind,
+  /// The location of the goal or metavariable, or the name of the declaration using `sorry`.
+  pub pos: Span,
+  /// The pretty printed goal, or the diagnostic message for `sorry`.
+  pub msg: String,
+}
+
+/// Find the unfinished proof obligations among the diagnostics `errors` of a file.
+#[must_use] pub fn holes(errors: &[ElabError]) -> Vec<Hole> {
+  errors.iter().filter_map(|e| {
+    let kind = match e.code? {
+      ErrorCode::UnsolvedGoal => HoleKind::Goal,
+      ErrorCode::Sorry => HoleKind::Sorry,
+      _ => return None,
+    };
+    Some(Hole {kind, pos: e.pos, msg: e.kind.msg()})
+  }).collect()
+}
+
+/// Main entry point for `mm0-rs holes` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs holes <in.mm1>... [--json]`, where:
+///
+/// - `in.mm1` are the files to elaborate. The obligations in these files and all the
+///   files they import are listed, grouped by file. Other diagnostics are not printed.
+/// - `--json` prints the list as a JSON array of objects with the fields `file`, `line`,
+///   `col` (both 1-based), `kind` (`goal` or `sorry`) and `message`.
+///
+/// The command fails if there are any obligations, so it can be used in CI to check that
+/// a development is complete.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let mut files = vec![];
+  let mut done = HashSet::new();
+  for path in args.values_of("INPUT").expect("required arg") {
+    let path: FileRef = fs::canonicalize(path)?.into();
+    if elab_for_diagnostics(path.clone())?.1.is_err() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: elaboration failed", path)))
+    }
+    for (file, text, errs) in import_diagnostics(&path) {
+      if done.insert(file.clone()) { files.push((file, text, holes(&errs))) }
+    }
+  }
+  files.sort_by(|a, b| a.0.rel().cmp(b.0.rel()));
+  let mut out = vec![];
+  let mut n = 0;
+  for (file, text, holes) in &files {
+    if holes.is_empty() { continue }
+    let text = text.try_ascii().map_or_else(LinedString::default, |s| (**s).clone());
+    if !args.is_present("json") { println!("{}:", file.rel()) }
+    for h in holes {
+      let (line, col) = text.to_line_col(h.pos.start);
+      if args.is_present("json") {
+        out.push(json!({
+          "file": file.rel(), "line": line, "col": col, "kind": h.kind.name(), "message": h.msg
+        }))
+      } else {
+        println!("  {}:{}: {}: {}", line, col, h.kind.name(), h.msg.replace('\n', "\n    "))
+      }
+      n += 1;
+    }
+  }
+  if args.is_present("json") {
+    serde_json::to_writer_pretty(io::stdout().lock(), &out)?;
+    println!();
+  } else {
+    println!("{} unfinished proof obligations", n);
+  }
+  if n != 0 { std::process::exit(1) }
+  Ok(())
+}
@@ -0,0 +1,404 @@
+//! Minimal-imports analysis.
+//!
+//! For a given file, this determines which of its `import` statements are actually needed
+//! by the declarations in the file, and which ones can be removed or replaced by a smaller
+//! import. It is used by the `--check-imports` option of `mm0-rs compile`, and by the
+//! language server to provide code actions on import statements.
+//!
+//! A file is considered *used* if one of the declarations in the current file refers to
+//! a sort, term, theorem or lisp global that was declared in it. This is determined from
+//! the statements and proofs of the current file, as well as the span information
+//! collected during elaboration. Notations are attributed to the file containing the
+//! term they are notation for.
+//!
+//! This module also maintains the [`ImportGraph`], which is used to detect import cycles
+//! before the files involved are elaborated, and decides which files use the
+//! [prelude](crate::set_prelude).
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::{MutexExt, BoxError, DeclKey, ExprNode, FileRef, FileSpan, FrozenEnv, ObjectKind, ProofNode,
+  SortId, Span, StmtTrace, TermId, TermKind, ThmId, ThmKind};
+
+/// The result of analyzing a single `import` statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImportStatus {
+  /// The import is needed.
+  Used,
+  /// Nothing from the imported file (or anything it imports) is used.
+  Unused,
+  /// The imported file is already imported (transitively) by this other direct import,
+  /// so the statement has no effect.
+  Redundant(FileRef),
+  /// Everything used from the imported file comes from this file, which it imports,
+  /// so the import can be replaced by a direct import of this file.
+  Narrow(FileRef),
+}
+
+/// Information about an `import` statement in a file.
+#[derive(Clone, Debug)]
+pub struct ImportInfo {
+  /// The span of the whole `import` statement.
+  pub stmt: Span,
+  /// The span of the file name string.
+  pub span: Span,
+  /// The imported file.
+  pub file: FileRef,
+  /// The analysis result.
+  pub status: ImportStatus,
+}
+
+impl ImportInfo {
+  /// A description of the problem with this import, or `None` if the import is used.
+  #[must_use] pub fn msg(&self) -> Option<String> {
+    match &self.status {
+      ImportStatus::Used => None,
+      ImportStatus::Unused => Some(format!("unused import {}", self.file)),
+      ImportStatus::Redundant(by) =>
+        Some(format!("import {} is redundant, it is already imported by {}", self.file, by)),
+      ImportStatus::Narrow(to) =>
+        Some(format!("only {} is used from import {}", to, self.file)),
+    }
+  }
+
+  /// The replacement text for the import statement in file `from`: `None` if the
+  /// import is used, `Some("")` if it should be removed, and otherwise the new statement.
+  #[must_use] pub fn replacement(&self, from: &FileRef) -> Option<String> {
+    match &self.status {
+      ImportStatus::Used => None,
+      ImportStatus::Unused | ImportStatus::Redundant(_) => Some(String::new()),
+      ImportStatus::Narrow(to) => {
+        let dir = from.path().parent().unwrap_or_else(|| Path::new(""));
+        let rel = relative_path(dir, to.path());
+        Some(format!("import {:?};", rel.to_string_lossy().replace('\\', "/")))
+      }
+    }
+  }
+}
+
+/// Get a path to `to` relative to directory `dir`. Both paths should be canonical.
+fn relative_path(dir: &Path, to: &Path) -> PathBuf {
+  let (mut it1, mut it2) = (dir.components().peekable(), to.components().peekable());
+  while let (Some(a), Some(b)) = (it1.peek(), it2.peek()) {
+    if a != b { break }
+    it1.next();
+    it2.next();
+  }
+  it1.map(|_| Component::ParentDir).chain(it2).collect()
+}
+
+/// The direct imports of a file, in order, along with the statement and string spans.
+fn direct_imports(env: &FrozenEnv) -> Vec<(Span, Span, FileRef)> {
+  let mut out = vec![];
+  for spans in env.spans() {
+    for (sp, k) in spans {
+      if let ObjectKind::Import(f) = k { out.push((spans.stmt(), *sp, f.clone())) }
+    }
+  }
+  out
+}
+
+/// The set of files used by the declarations of file `path` in environment `env`.
+fn used_files(path: &FileRef, env: &FrozenEnv) -> HashSet<FileRef> {
+  struct Used<'a> {
+    env: &'a FrozenEnv,
+    files: HashSet<FileRef>,
+  }
+
+  impl Used<'_> {
+    fn file(&mut self, f: &FileRef) {
+      if !self.files.contains(f) { self.files.insert(f.clone()); }
+    }
+    fn sort(&mut self, s: SortId) { self.file(&self.env.sort(s).span.file) }
+    fn term(&mut self, t: TermId) { self.file(&self.env.term(t).span.file) }
+    fn thm(&mut self, t: ThmId) { self.file(&self.env.thm(t).span.file) }
+    fn expr(&mut self, e: &ExprNode) {
+      match e {
+        ExprNode::Ref(_) => {}
+        &ExprNode::Dummy(_, s) => self.sort(s),
+        ExprNode::App(t, es) => { self.term(*t); for e in &**es { self.expr(e) } }
+      }
+    }
+    fn proof(&mut self, p: &ProofNode) {
+      match p {
+        ProofNode::Ref(_) => {}
+        &ProofNode::Dummy(_, s) => self.sort(s),
+        ProofNode::Term {term, args} | ProofNode::Cong {term, args} => {
+          self.term(*term);
+          for p in &**args { self.proof(p) }
+        }
+        ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => self.proof(p),
+        ProofNode::Thm {thm, args, res} => {
+          self.thm(*thm);
+          for p in &**args { self.proof(p) }
+          self.proof(res)
+        }
+        ProofNode::Conv(p) => { self.proof(&p.0); self.proof(&p.1); self.proof(&p.2) }
+        ProofNode::Unfold {term, args, res} => {
+          self.term(*term);
+          for p in &**args { self.proof(p) }
+          self.proof(&res.0);
+          self.proof(&res.1)
+        }
+      }
+    }
+  }
+
+  let mut u = Used {env, files: HashSet::new()};
+  for s in env.stmts() {
+    let a = if let StmtTrace::Decl(a) = *s {a} else {continue};
+    match env.data()[a].decl() {
+      Some(DeclKey::Term(t)) => {
+        let td = env.term(t);
+        if td.span.file != *path { continue }
+        for (_, ty) in &*td.args { u.sort(ty.sort()) }
+        u.sort(td.ret.0);
+        if let TermKind::Def(Some(e)) = &td.kind {
+          for e in &*e.heap { u.expr(e) }
+          u.expr(&e.head)
+        }
+      }
+      Some(DeclKey::Thm(t)) => {
+        let td = env.thm(t);
+        if td.span.file != *path { continue }
+        for (_, ty) in &*td.args { u.sort(ty.sort()) }
+        for e in &*td.heap { u.expr(e) }
+        for (_, e) in &*td.hyps { u.expr(e) }
+        u.expr(&td.ret);
+        if let ThmKind::Thm(Some(pf)) = &td.kind {
+          for p in &*pf.heap { u.proof(p) }
+          u.proof(&pf.head)
+        }
+      }
+      None => {}
+    }
+  }
+  for spans in env.spans() {
+    for (_, k) in spans {
+      match *k {
+        ObjectKind::Sort(s) => u.sort(s),
+        ObjectKind::Term(t, _) => u.term(t),
+        ObjectKind::Thm(t) => u.thm(t),
+        ObjectKind::Global(a) =>
+          if let Some((fsp, _)) = env.data()[a].lisp().as_ref().and_then(|l| l.src().as_ref()) {
+            u.file(&fsp.file)
+          },
+        ObjectKind::Expr(ref e) | ObjectKind::Proof(ref e) => {
+          let a = if let Some(a) = e.uncons().next().unwrap_or(e).as_atom() {a} else {continue};
+          match env.data()[a].decl() {
+            Some(DeclKey::Term(t)) => u.term(t),
+            Some(DeclKey::Thm(t)) => u.thm(t),
+            None => {}
+          }
+        }
+        ObjectKind::Var(_) | ObjectKind::LispLocal(..) | ObjectKind::MmcVar(..) |
+        ObjectKind::Syntax(_) | ObjectKind::RefineSyntax(_) | ObjectKind::Import(_) => {}
+      }
+    }
+  }
+  u.files.remove(path);
+  u.files
+}
+
+/// Analyze the imports of file `path`, with elaborated environment `env`.
+///
+/// `get_env` is used to look up the environments of the other files in the import graph,
+/// in order to determine their imports. If it returns `None` for some file, that file is
+/// treated as having no imports, which can only make the analysis more conservative.
+pub fn analyze(path: &FileRef, env: &FrozenEnv,
+  mut get_env: impl FnMut(&FileRef) -> Option<FrozenEnv>
+) -> Vec<ImportInfo> {
+  /// Compute the set of files transitively imported by `f`, including `f` itself.
+  fn closure(f: &FileRef,
+    get_env: &mut impl FnMut(&FileRef) -> Option<FrozenEnv>,
+    direct: &mut HashMap<FileRef, Vec<FileRef>>,
+    memo: &mut HashMap<FileRef, HashSet<FileRef>>,
+  ) -> HashSet<FileRef> {
+    if let Some(c) = memo.get(f) { return c.clone() }
+    let imps = direct.entry(f.clone()).or_insert_with(||
+      get_env(f).map_or_else(Vec::new, |env|
+        direct_imports(&env).into_iter().map(|(_, _, f)| f).collect())).clone();
+    // Prevent infinite recursion on import cycles, which are reported elsewhere
+    memo.insert(f.clone(), Some(f.clone()).into_iter().collect());
+    let mut out = HashSet::new();
+    out.insert(f.clone());
+    for g in &imps { out.extend(closure(g, get_env, direct, memo)) }
+    memo.insert(f.clone(), out.clone());
+    out
+  }
+
+  let used = used_files(path, env);
+  let imports = direct_imports(env);
+  let (mut direct, mut memo) = Default::default();
+  let closures = imports.iter()
+    .map(|(_, _, f)| closure(f, &mut get_env, &mut direct, &mut memo))
+    .collect::<Vec<_>>();
+  imports.iter().enumerate().map(|(i, (stmt, span, file))| {
+    let c = &closures[i];
+    let status = if c.is_disjoint(&used) {
+      ImportStatus::Unused
+    } else if let Some((_, (_, _, by))) = imports.iter().enumerate().find(|&(j, (_, _, g))|
+      j != i && closures[j].contains(file) && (g != file || j < i)) {
+      ImportStatus::Redundant(by.clone())
+    } else if used.contains(file) {
+      ImportStatus::Used
+    } else {
+      let subs = direct.get(file).map_or(&[][..], |v| &**v);
+      match subs.iter().find(|g| used.iter().all(|u| !c.contains(u) || memo[*g].contains(u))) {
+        Some(g) => ImportStatus::Narrow(g.clone()),
+        None => ImportStatus::Used,
+      }
+    };
+    ImportInfo {stmt: *stmt, span: *span, file: file.clone(), status}
+  }).collect()
+}
+
+/// Resolve the file name `name` in an `import` statement in file `from`. The path is
+/// normalized according to the current [`PathPolicy`](crate::PathPolicy), and it is an error
+/// if the file does not exist, either on disk or as a [virtual file](crate::add_virtual_file).
+/// A virtual file registered by `(add-virtual-file)` is only found if `from` is the file
+/// that registered it or imports it (see [`add_scoped_virtual_file`](crate::add_scoped_virtual_file)).
+///
+/// The name is first looked up relative to the directory of `from`, and then in each
+/// directory of the [import path](crate::set_import_path).
+pub fn resolve(from: &FileRef, name: &[u8]) -> Result<FileRef, BoxError> {
+  resolve_in(from, name, true)
+}
+
+/// Like [`resolve`], but scoped virtual files are only considered if `scoped` is true.
+fn resolve_in(from: &FileRef, name: &[u8], scoped: bool) -> Result<FileRef, BoxError> {
+  let name = std::str::from_utf8(name)?;
+  let exists = |f: &FileRef| match crate::virtual_file_owner(f) {
+    Some(owner) => scoped && (owner == *from || import_closure_in(from, false).contains(&owner)),
+    None => crate::get_virtual_file(f).is_some() || f.path().exists(),
+  };
+  let file = resolve_path(from, name);
+  if exists(&file) { return Ok(file) }
+  for dir in &*crate::get_import_path() {
+    let f = FileRef::from(dir.join(name));
+    if exists(&f) { return Ok(f) }
+  }
+  Err(format!("file '{}' not found", name).into())
+}
+
+/// Get the file with path `name` relative to the directory of file `from`,
+/// whether or not it exists.
+#[must_use] pub fn resolve_path(from: &FileRef, name: &str) -> FileRef {
+  from.path().parent().map_or_else(|| PathBuf::from(name), |p| p.join(name)).into()
+}
+
+/// The files that `path` imports, directly or indirectly, and `path` itself. This only
+/// parses the files, without elaborating them. Files that can't be read are skipped,
+/// because the error will be reported when they are elaborated.
+#[must_use] pub fn import_closure(path: &FileRef) -> HashSet<FileRef> {
+  import_closure_in(path, true)
+}
+
+/// Like [`import_closure`], but scoped virtual files are only considered if `scoped` is true.
+fn import_closure_in(path: &FileRef, scoped: bool) -> HashSet<FileRef> {
+  let mut done = HashSet::new();
+  let mut stack = vec![path.clone()];
+  while let Some(path) = stack.pop() {
+    if !done.insert(path.clone()) || !(path.has_extension("mm1") || path.has_extension("mm0")) {
+      continue
+    }
+    let text = match crate::get_virtual_file(&path) {
+      Some(text) => text.to_string(),
+      None => if let Ok(text) = std::fs::read_to_string(path.path()) {text} else {continue},
+    };
+    let (_, ast) = mm1_parser::parse(Arc::new(text.into()), None);
+    stack.extend(ast.imports.iter().filter_map(|(_, f)| resolve_in(&path, f, scoped).ok()));
+  }
+  done
+}
+
+lazy_static! {
+  /// The current prelude and its [import closure](import_closure), which is computed
+  /// the first time it is needed.
+  static ref PRELUDE_DEPS: Mutex<Option<(FileRef, Arc<HashSet<FileRef>>)>> = Mutex::new(None);
+}
+
+/// The prelude that should be imported at the start of file `path`, if any. This is the
+/// file set by [`set_prelude`](crate::set_prelude), unless `path` is an `.mm0` file or
+/// is the prelude or one of the files it imports, since that would be an import cycle.
+#[must_use] pub fn prelude_for(path: &FileRef) -> Option<FileRef> {
+  if path.has_extension("mm0") { return None }
+  let prelude = crate::get_prelude()?;
+  let deps = {
+    let mut g = PRELUDE_DEPS.ulock();
+    match &*g {
+      Some((p, deps)) if *p == prelude => deps.clone(),
+      _ => {
+        let deps = Arc::new(import_closure(&prelude));
+        *g = Some((prelude.clone(), deps.clone()));
+        deps
+      }
+    }
+  };
+  if deps.contains(path) { None } else { Some(prelude) }
+}
+
+/// An import cycle, as the list of `import` statements that form it. Each entry is the
+/// location of an `import` statement and the file it imports, which is the file containing
+/// the next statement in the list, and the last statement imports the file of the first.
+pub type ImportCycle = std::sync::Arc<[(FileSpan, FileRef)]>;
+
+/// The import graph of all the files that have been parsed so far.
+///
+/// Each file registers its imports with [`set_imports`](Self::set_imports) after it is
+/// parsed, and before any of its imports are elaborated. An import that would close a cycle
+/// is then found by [`find_cycle`](Self::find_cycle) before anything waits on it: for any
+/// cycle, the last file to check its imports sees all the others.
+#[derive(Debug, Default)]
+pub struct ImportGraph(HashMap<FileRef, Box<[(Span, FileRef)]>>);
+
+impl ImportGraph {
+  /// Set the imports of `file`, as a list of spans of `import` statements and the files
+  /// they import. This replaces the previous imports of the file, if any.
+  pub fn set_imports(&mut self, file: FileRef, imports: Box<[(Span, FileRef)]>) {
+    self.0.insert(file, imports);
+  }
+
+  /// If the import of `to` in `from` is part of an import cycle, return the shortest such
+  /// cycle, starting with that import.
+  #[must_use] pub fn find_cycle(&self, from: &FileRef, to: &FileRef) -> Option<ImportCycle> {
+    let sp = self.0.get(from)?.iter().find(|(_, f)| f == to)?.0;
+    let mut prev: HashMap<&FileRef, (&FileRef, Span)> = HashMap::new();
+    let mut queue = VecDeque::from([to]);
+    while let Some(f) = queue.pop_front() {
+      if f == from {
+        let mut cyc = vec![];
+        let mut g = from;
+        while g != to {
+          let (h, sp) = prev[g];
+          cyc.push((FileSpan {file: h.clone(), span: sp}, g.clone()));
+          g = h;
+        }
+        cyc.push((FileSpan {file: from.clone(), span: sp}, to.clone()));
+        cyc.reverse();
+        return Some(cyc.into())
+      }
+      for (sp, g) in self.0.get(f).map_or(&[][..], |v| &**v) {
+        if g != to && !prev.contains_key(g) {
+          prev.insert(g, (f, *sp));
+          queue.push_back(g);
+        }
+      }
+    }
+    None
+  }
+}
+
+/// The error message for an import of `to` in file `from`, which is part of import cycle `cyc`
+/// or depends on a file in the cycle.
+#[must_use] pub fn cycle_msg(from: &FileRef, to: &FileRef, cyc: &[(FileSpan, FileRef)]) -> String {
+  use std::fmt::Write;
+  let i = cyc.iter().position(|(fsp, _)| fsp.file == *from);
+  let mut s = match i {
+    Some(_) => format!("import cycle: {}", from),
+    None => format!("{} is part of an import cycle: {}", to, cyc[0].0.file),
+  };
+  let (left, right) = cyc.split_at(i.unwrap_or(0));
+  for (_, f) in right.iter().chain(left) { write!(s, " -> {}", f).expect("writing to a string") }
+  s
+}
@@ -0,0 +1,211 @@
+//! Inspector for `.mmb` files.
+//!
+//! This module implements the `mm0-rs info` subcommand, which prints a summary of the contents
+//! of an `.mmb` file: the header, the sizes of the tables, the list of declarations with the
+//! size of their proofs, the largest theorems, and the tables in the debugging index. It does
+//! not verify the file, so it can be used to debug files that fail to parse or check.
+use std::fmt::Write as _;
+use std::io;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use mm0b_parser::{cmd, BasicMmbFile, Header, NumdStmtCmd, ParseError, TableEntry};
+use crate::compiler::FileContents;
+use crate::{u32_as_usize, ThmId};
+
+/// A declaration in the proof stream.
+struct Decl {
+  kind: &'static str,
+  name: String,
+  /// The position of the statement in the file
+  pos: usize,
+  /// The size of the statement in the proof stream, in bytes
+  size: usize,
+  /// The number of proof commands in the statement
+  steps: usize,
+}
+
+fn read_decls(file: &BasicMmbFile<'_>) -> Result<(Vec<Decl>, usize), ParseError> {
+  let mut decls = vec![];
+  let mut it = file.proof();
+  let mut pos = it.pos;
+  while let Some(e) = it.next() {
+    let (stmt, pf) = e?;
+    let (kind, name) = match stmt {
+      NumdStmtCmd::Sort {sort_id} => ("sort", file.sort_name(sort_id)),
+      NumdStmtCmd::TermDef {term_id, local} => {
+        let def = file.term(term_id).map_or(false, |td| td.def());
+        (match (def, local) {
+          (false, _) => "term",
+          (true, false) => "def",
+          (true, true) => "local def",
+        }, file.term_name(term_id))
+      }
+      NumdStmtCmd::Axiom {thm_id} => ("axiom", file.thm_name(thm_id)),
+      NumdStmtCmd::Thm {thm_id, local} =>
+        (if local {"local theorem"} else {"theorem"}, file.thm_name(thm_id)),
+    };
+    // A statement without a proof has a null proof stream, which must not be read
+    let steps = if pf.is_null() { 0 } else { pf.collect::<Result<Vec<_>, _>>()?.len() };
+    decls.push(Decl {kind, name: name.into(), pos, size: it.pos - pos, steps});
+    pos = it.pos;
+  }
+  Ok((decls, it.after_end()?))
+}
+
+fn table_name(id: [u8; 4]) -> String {
+  id.iter().map(|&c| if c.is_ascii_graphic() || c == b' ' {c as char} else {'?'}).collect()
+}
+
+/// Describe the contents of a known index table.
+fn describe_table(file: &BasicMmbFile<'_>, e: &TableEntry) -> Option<String> {
+  let num_decls = file.sorts.len() + file.terms.len() + file.thms.len();
+  let stmts = || file.proof().map_while(Result::ok).map(|(stmt, _)| stmt);
+  Some(match e.id {
+    cmd::INDEX_NAME => {
+      let named = stmts().filter(|&s| file.stmt_index(s).and_then(|n| n.value()).is_some());
+      format!("names for {} of {} declarations", named.count(), num_decls)
+    }
+    cmd::INDEX_VAR_NAME => format!("variable names for {} terms and {} theorems",
+      file.terms.len(), file.thms.len()),
+    cmd::INDEX_HYP_NAME => format!("hypothesis names for {} theorems", file.thms.len()),
+    cmd::INDEX_SOURCE_MAP => {
+      let (mut thms, mut steps) = (0, 0);
+      for i in 0..file.header.num_thms.get() {
+        let n = file.thm_source_map(ThmId(i)).count();
+        if n != 0 { thms += 1; steps += n }
+      }
+      format!("{} proof steps in {} theorems", steps, thms)
+    }
+    cmd::INDEX_FILE => {
+      let files = file.source_files();
+      let mut s = format!("{} files", files.len());
+      for i in 0..files.len() { write!(s, "\n    {}", files.get(i)).expect("writing to a string") }
+      s
+    }
+    cmd::INDEX_SPAN =>
+      format!("spans for {} declarations", stmts().filter(|&s| file.stmt_span(s).is_some()).count()),
+    cmd::INDEX_DOC =>
+      format!("{} doc comments", stmts().filter(|&s| file.stmt_doc(s).is_some()).count()),
+    cmd::INDEX_ATTR =>
+      format!("{} attributes", stmts().map(|s| file.stmt_attrs(s).len()).sum::<usize>()),
+    cmd::INDEX_PRODUCER => format!("produced by {}", file.producer()?),
+    _ => return None
+  })
+}
+
+fn print_human(
+  path: &str, file: &BasicMmbFile<'_>,
+  decls: &[Decl], sizes: &[(&str, usize, usize)], largest: &[&Decl],
+) {
+  let Header {version, num_sorts, num_terms, num_thms, ..} = file.header;
+  println!("{}: {} bytes", path, file.buf.len());
+  println!("version {}, {} sorts, {} terms, {} theorems",
+    version, num_sorts, num_terms.get(), num_thms.get());
+  if let Some(producer) = file.producer() { println!("produced by {}", producer) }
+  println!("\ntables:");
+  for &(name, start, size) in sizes {
+    println!("  {:<8} {:#010x}  {:>10} bytes", name, start, size)
+  }
+  println!("\ndeclarations:");
+  for d in decls {
+    println!("  {:#010x}  {:>10} bytes  {:>8} steps  {:<13} {}",
+      d.pos, d.size, d.steps, d.kind, d.name)
+  }
+  if !largest.is_empty() {
+    println!("\nlargest theorems:");
+    for d in largest { println!("  {:>10} bytes  {:>8} steps  {}", d.size, d.steps, d.name) }
+  }
+  println!("\nindex:");
+  match file.index_entries() {
+    None if file.header.p_index.get() == 0 => println!("  (none)"),
+    None => println!("  (malformed)"),
+    Some(entries) => for e in entries {
+      print!("  {:?} {:#010x}", table_name(e.id), e.ptr.get());
+      match describe_table(file, e) {
+        Some(s) => println!(": {}", s),
+        None => println!(" (unknown table)"),
+      }
+    }
+  }
+}
+
+fn to_json(
+  file: &BasicMmbFile<'_>,
+  decls: &[Decl], sizes: &[(&str, usize, usize)], largest: &[&Decl],
+) -> Value {
+  let Header {version, num_sorts, num_terms, num_thms, ..} = file.header;
+  let decl = |d: &Decl| json!({
+    "kind": d.kind, "name": d.name, "pos": d.pos, "size": d.size, "steps": d.steps,
+  });
+  json!({
+    "size": file.buf.len(),
+    "header": {
+      "version": version,
+      "producer": file.producer(),
+      "num_sorts": num_sorts,
+      "num_terms": num_terms.get(),
+      "num_thms": num_thms.get(),
+      "p_terms": file.header.p_terms.get(),
+      "p_thms": file.header.p_thms.get(),
+      "p_proof": file.header.p_proof.get(),
+      "p_index": file.header.p_index.get(),
+    },
+    "tables": sizes.iter().map(|&(name, start, size)|
+      json!({"name": name, "pos": start, "size": size})).collect::<Vec<_>>(),
+    "decls": decls.iter().map(decl).collect::<Vec<_>>(),
+    "largest": largest.iter().map(|d| decl(d)).collect::<Vec<_>>(),
+    "index": file.index_entries().unwrap_or(&[]).iter().map(|e| json!({
+      "id": table_name(e.id),
+      "data": e.data.get(),
+      "ptr": e.ptr.get(),
+      "description": describe_table(file, e),
+    })).collect::<Vec<_>>(),
+  })
+}
+
+/// Main entry point for `mm0-rs info` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs info <in.mmb> [--json] [--top N]`, where:
+///
+/// - `in.mmb` is the file to inspect.
+/// - `--json` prints the summary as a JSON object instead of human readable text.
+/// - `N` (default 10) is the number of largest theorems to list.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let top = args.value_of("top").map_or(Ok(10), str::parse).map_err(|_|
+    io::Error::new(io::ErrorKind::InvalidInput, "--top: expected a number"))?;
+  let buf = FileContents::new_bin_from_file(path.as_ref())?;
+  let err = |e: ParseError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+  let file = BasicMmbFile::parse(&buf).map_err(err)?;
+  let (decls, p_end) = read_decls(&file).map_err(err)?;
+
+  let p_terms = u32_as_usize(file.header.p_terms.get());
+  let p_thms = u32_as_usize(file.header.p_thms.get());
+  let p_proof = u32_as_usize(file.header.p_proof.get());
+  let p_index = usize::try_from(file.header.p_index.get()).unwrap_or(usize::MAX);
+  let p_index = if p_index == 0 {file.buf.len()} else {p_index};
+  let header_size = std::mem::size_of::<Header>();
+  let sizes = [
+    ("header", 0, header_size),
+    ("sorts", header_size, p_terms.saturating_sub(header_size)),
+    ("terms", p_terms, p_thms.saturating_sub(p_terms)),
+    ("theorems", p_thms, p_proof.saturating_sub(p_thms)),
+    ("proofs", p_proof, p_end.saturating_sub(p_proof)),
+    ("index", p_index, file.buf.len().saturating_sub(p_index)),
+  ];
+
+  let mut largest = decls.iter()
+    .filter(|d| matches!(d.kind, "theorem" | "local theorem")).collect::<Vec<_>>();
+  largest.sort_by(|a, b| b.size.cmp(&a.size));
+  largest.truncate(top);
+
+  if args.is_present("json") {
+    serde_json::to_writer_pretty(io::stdout().lock(), &to_json(&file, &decls, &sizes, &largest))?;
+    println!();
+  } else {
+    print_human(path, &file, &decls, &sizes, &largest)
+  }
+  Ok(())
+}
@@ -12,15 +12,23 @@
 //! of the `import "peano.mm0";` statement. The resulting file will be a proper MM0 file and
 //! can be run through the `mm0-c` verifier and other conforming verifiers.
 //!
+//! If a name alignment is given with `--align` (see [`Alignment`]), the references to
+//! declarations in the imported files are renamed according to it, as they would be on
+//! import. These are found using the AST of each statement, and math strings are split into
+//! tokens using the delimiters in effect, so identifiers that merely contain a renamed name,
+//! local variables and notation tokens are left alone.
+//!
 //! [`mm0.mm0`]: https://github.com/digama0/mm0/blob/master/examples/mm0.mm0
 use std::collections::HashSet;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
 use std::sync::Arc;
 use clap::ArgMatches;
 use mm0_util::{FileRef, LinedString};
-use mm1_parser::{parse, ast::StmtKind};
+use mm0_util::{ArcString, Span};
+use crate::Alignment;
+use crate::elab::environment::Delims;
+use mm1_parser::{parse, ast::{Binder, Delimiter, Formula, SExpr, SExprKind, Stmt, StmtKind, Type}};
 
 /// Running data for the file join process.
 struct Joiner<W: Write> {
@@ -37,6 +45,10 @@ struct Joiner<W: Write> {
   working: HashSet<FileRef>,
   /// The list of files that were output, in post-order traversal.
   done: Vec<FileRef>,
+  /// The renamings to apply to the identifiers in imported files.
+  align: Arc<Alignment>,
+  /// The left and right delimiters declared so far, used to tokenize math strings.
+  delims: (Delims, Delims),
   /// The writer to print the output file to
   w: W,
 }
@@ -44,7 +56,35 @@ struct Joiner<W: Write> {
 impl<W: Write> Joiner<W> {
   /// Create a new [`Joiner`] from a writer.
   fn new(comments: bool, w: W) -> Self {
-    Self {comments, stack: vec![], working: HashSet::new(), done: vec![], w}
+    Self {comments, stack: vec![], working: HashSet::new(), done: vec![],
+      align: crate::get_alignment(), delims: Default::default(), w}
+  }
+
+  /// Write `src[start..end]`, applying the edits (which are sorted) in that range.
+  fn write_text(&mut self, src: &[u8], start: usize, end: usize,
+    edits: &[(Span, ArcString)]
+  ) -> io::Result<()> {
+    let mut pos = start;
+    for (sp, to) in edits.iter().filter(|(sp, _)| start <= sp.start && sp.end <= end) {
+      self.w.write_all(&src[pos..sp.start])?;
+      self.w.write_all(to)?;
+      pos = sp.end;
+    }
+    self.w.write_all(&src[pos..end])
+  }
+
+  /// Update the delimiters according to statement `s`, and if the file on top of the stack
+  /// is an imported file, push the edits that rename the declarations referenced in it.
+  fn rename_stmt(&mut self, src: &[u8], s: &Stmt, edits: &mut Vec<(Span, ArcString)>) {
+    if let StmtKind::Delimiter(d) = &s.k {
+      let (l, r) = &mut self.delims;
+      match d {
+        Delimiter::Both(cs) => for &c in &**cs { l.set(c); r.set(c) },
+        Delimiter::LeftRight(ls, rs) => { for &c in &**ls { l.set(c) } for &c in &**rs { r.set(c) } }
+      }
+    }
+    if self.align.is_empty() || self.stack.len() <= 1 { return }
+    Renamer {align: &self.align, src, delims: &self.delims, locals: vec![], edits}.stmt(s)
   }
 
   /// Write the file at `path` to `self.w`, following all imports recursively.
@@ -57,14 +97,12 @@ impl<W: Write> Joiner<W> {
     let src = Arc::<LinedString>::new(fs::read_to_string(path.path())?.into());
     let (_, ast) = parse(src.clone(), None);
     let mut start = 0;
+    let mut edits = vec![];
     for s in &ast.stmts {
       if let StmtKind::Import(_, f) = &s.k {
-        let f = std::str::from_utf8(f).map_err(|_|
-          io::Error::new(io::ErrorKind::InvalidInput, "invalid utf8"))?;
-        let r: FileRef = path.path().parent()
-          .map_or_else(|| PathBuf::from(f), |p| p.join(f))
-          .canonicalize()?.into();
-        self.w.write_all(&src.as_bytes()[start..s.span.start])?;
+        let r = crate::imports::resolve(&path, f).map_err(|e|
+          io::Error::new(io::ErrorKind::NotFound, e))?;
+        self.write_text(src.as_bytes(), start, s.span.start, &edits)?;
         if self.working.insert(r.clone()) {
           self.write(r)?;
           if self.comments {
@@ -72,7 +110,7 @@ impl<W: Write> Joiner<W> {
           }
         }
         start = s.span.end;
-      }
+      } else { self.rename_stmt(src.as_bytes(), s, &mut edits) }
     }
     if self.comments {
       write!(self.w, "{}\n-- {} --\n{0}\n",
@@ -80,14 +118,108 @@ impl<W: Write> Joiner<W> {
         unsafe { String::from_utf8_unchecked(vec![b'-'; path.rel().len() + 6]) },
         path.rel())?;
     }
-    self.w.write_all(&src.as_bytes()[start..])?;
+    self.write_text(src.as_bytes(), start, src.len(), &edits)?;
     self.stack.pop();
     self.done.push(path);
     Ok(())
   }
 }
 
-fn join_with_header(comments: bool, header: bool, mut w: impl Write, file: FileRef) -> io::Result<()> {
+/// Collects the edits that rename references to declarations in a statement.
+struct Renamer<'a> {
+  align: &'a Alignment,
+  src: &'a [u8],
+  delims: &'a (Delims, Delims),
+  /// The names of the local variables of the current declaration, which are not renamed.
+  locals: Vec<&'a [u8]>,
+  edits: &'a mut Vec<(Span, ArcString)>,
+}
+
+impl<'a> Renamer<'a> {
+  /// Rename the identifier at `sp`, if it is renamed and not a local variable.
+  fn ident(&mut self, sp: Span) {
+    let name = &self.src[sp.start..sp.end];
+    if self.locals.contains(&name) { return }
+    if let Some(to) = self.align.rename(name) { self.edits.push((sp, to.clone())) }
+  }
+
+  /// Rename the tokens of a math string that are identifiers, using the same tokenization
+  /// as the math parser.
+  fn formula(&mut self, f: Formula) {
+    let Span {mut start, end} = f.inner();
+    let (left, right) = self.delims;
+    while start < end {
+      let c = self.src[start];
+      if c == b' ' || c == b'\n' { start += 1; continue }
+      let mut i = start;
+      while i < end {
+        let c = self.src[i];
+        if c == b' ' || c == b'\n' || (right.get(c) && i != start) { break }
+        i += 1;
+        if left.get(c) { break }
+      }
+      let tk = &self.src[start..i];
+      if tk.iter().all(|&c| c == b'_' || c.is_ascii_alphanumeric()) && !tk[0].is_ascii_digit() {
+        self.ident((start..i).into())
+      }
+      start = i;
+    }
+  }
+
+  fn sexpr(&mut self, e: &SExpr) {
+    match &e.k {
+      SExprKind::Atom(mm1_parser::ast::Atom::Ident) => self.ident(e.span),
+      SExprKind::List(es) => for e in es { self.sexpr(e) },
+      SExprKind::DottedList(es, e) => { for e in es { self.sexpr(e) } self.sexpr(e) }
+      SExprKind::DocComment(_, e) => self.sexpr(e),
+      &SExprKind::Formula(f) => self.formula(f),
+      SExprKind::Atom(_) | SExprKind::Number(_) | SExprKind::String(_) |
+      SExprKind::Bool(_) | SExprKind::Undef => {}
+    }
+  }
+
+  fn ty(&mut self, ty: &Option<Type>) {
+    match ty {
+      Some(Type::DepType(d)) => self.ident(d.sort),
+      &Some(Type::Formula(f)) => self.formula(f),
+      None => {}
+    }
+  }
+
+  fn binders(&mut self, bis: &[Binder]) {
+    self.locals = bis.iter().filter_map(|bi| bi.local).map(|sp| &self.src[sp.start..sp.end]).collect();
+    for bi in bis { self.ty(&bi.ty) }
+  }
+
+  fn stmt(&mut self, s: &Stmt) {
+    match &s.k {
+      &StmtKind::Sort(id, _) => self.ident(id),
+      StmtKind::Decl(d) => {
+        self.binders(&d.bis);
+        self.ty(&d.ty);
+        if let Some(val) = &d.val { self.sexpr(val) }
+        self.locals.clear();
+        self.ident(d.id);
+      }
+      StmtKind::SimpleNota(n) => self.ident(n.id),
+      &StmtKind::Coercion {id, from, to} => { self.ident(id); self.ident(from); self.ident(to) }
+      StmtKind::Notation(n) => {
+        self.binders(&n.bis);
+        self.ty(&n.ty);
+        self.locals.clear();
+        self.ident(n.id);
+      }
+      StmtKind::Inout {hs, ..} => for e in hs { self.sexpr(e) },
+      StmtKind::Annot(e, s) => { self.sexpr(e); self.stmt(s) }
+      StmtKind::DocComment(_, s) => self.stmt(s),
+      StmtKind::Do(es) => for e in es { self.sexpr(e) },
+      StmtKind::Delimiter(_) | StmtKind::Import(..) => {}
+    }
+    self.edits.sort_by_key(|(sp, _)| sp.start);
+  }
+}
+
+pub(crate) fn join_with_header(comments: bool, header: bool, mut w: impl Write, file: FileRef) -> io::Result<()> {
   let mut buf = vec![];
   if comments && header {
     let mut joiner = Joiner::new(comments, &mut buf);
@@ -113,7 +245,13 @@ fn join_with_header(comments: bool, header: bool, mut w: impl Write, file: FileR
 ///
 /// - `in.mm0` (or `in.mm1`) is the file to join, an MM0 file with `import`s
 /// - `out.mm0` is the output location, or stdin if omitted.
+/// - `--align FILE` renames the identifiers of the imported files according to `FILE`.
 pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  if let Some(file) = args.value_of("align") {
+    let align = Alignment::parse(&fs::read_to_string(file)?).map_err(|e|
+      io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e)))?;
+    crate::set_alignment(align)
+  }
   let path = args.value_of("INPUT").expect("required arg");
   let file = fs::canonicalize(path)?.into();
   let comments = !args.is_present("bare");
@@ -9,9 +9,14 @@
 //!     -V, --version    Prints version information
 //!
 //! SUBCOMMANDS:
+//!     build      Build the targets of a project (mm0.toml)
 //!     compile    Compile MM1 files into MMB
+//!     diff-src   Compare the declarations of two versions of an MM1/MM0 file
+//!     fetch      Download the packaged dependencies of a project (mm0.toml)
 //!     help       Prints this message or the help of the given subcommand(s)
+//!     info       Print a summary of the contents of an MMB file
 //!     join       Join MM1/MM0 files with imports by concatenation
+//!     package    Build a project (mm0.toml) and package it as a library
 //!     server     MM1 LSP server
 //! ```
 //!
@@ -60,6 +65,22 @@ pub use mm0_deepsize::deep_size_0;
 #[macro_use] pub mod server;
 pub mod compiler;
 pub mod joiner;
+pub mod refactor;
+pub mod imports;
+pub mod binders;
+pub mod profile;
+pub mod explain;
+pub mod info;
+pub mod diff;
+pub mod proof_tree;
+pub mod export_index;
+pub mod holes;
+pub mod debug_dump;
+#[cfg(feature = "package")]
+pub mod project;
+#[cfg(feature = "package")]
+pub mod package;
+pub mod test_runner;
 pub mod elab;
 #[cfg(feature = "doc")]
 pub mod doc;
@@ -78,7 +99,8 @@ pub mod mmu { pub mod import; pub mod export; }
 #[cfg(feature = "mmc")]
 pub mod mmc;
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::time::Duration;
 
 pub use elab::{environment::*,
   frozen::{FrozenAtomData, FrozenEnv, FrozenLispKind, FrozenLispVal},
@@ -95,3 +117,207 @@ pub(crate) fn get_check_proofs() -> bool { CHECK_PROOFS.load(Ordering::Relaxed)
 /// Set the initial proof checking behavior at the start of an MM1 file
 /// before a `(check-proofs)` command is found.
 pub fn set_check_proofs(b: bool) { CHECK_PROOFS.store(b, Ordering::Relaxed) }
+
+static MINIMIZE: AtomicU64 = AtomicU64::new(0);
+pub(crate) fn get_minimize() -> Option<Duration> {
+  match MINIMIZE.load(Ordering::Relaxed) { 0 => None, n => Some(Duration::from_millis(n)) }
+}
+
+/// Enable the proof minimizer (see [`elab::minimize`]), with the given time budget per theorem.
+pub fn set_minimize(budget: Option<Duration>) {
+  MINIMIZE.store(budget.map_or(0, |d| d.as_millis().try_into().unwrap_or(u64::MAX).max(1)),
+    Ordering::Relaxed)
+}
+
+#[cfg(feature = "mmc")]
+static MMC_DUMP: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+#[cfg(feature = "mmc")]
+pub(crate) fn get_mmc_dump() -> mmcc::DumpFlags {
+  mmcc::DumpFlags::from_bits_truncate(MMC_DUMP.load(Ordering::Relaxed))
+}
+
+/// Set the intermediate representations of MMC programs to print, and whether to check them
+/// for consistency between passes. The output is reported as info diagnostics at the
+/// `mmc-add` and `mmc-finish` calls.
+#[cfg(feature = "mmc")]
+pub fn set_mmc_dump(flags: mmcc::DumpFlags) { MMC_DUMP.store(flags.bits(), Ordering::Relaxed) }
+
+lazy_static! {
+  static ref DEP_POLICY: Mutex<elab::DepPolicy> = Mutex::new(elab::DepPolicy::Infer);
+}
+pub(crate) fn get_dep_policy() -> elab::DepPolicy { *DEP_POLICY.ulock() }
+
+/// Set the initial dependency inference policy at the start of an MM1 file
+/// before a `(set-dep-policy)` command is found.
+pub fn set_dep_policy(p: elab::DepPolicy) { *DEP_POLICY.ulock() = p }
+
+lazy_static! {
+  static ref WARNING_LEVELS: Mutex<elab::WarningLevels> = Default::default();
+}
+pub(crate) fn get_warning_levels() -> elab::WarningLevels { *WARNING_LEVELS.ulock() }
+
+/// Set the initial severity of the warnings in category `cat` at the start of an MM1 file,
+/// before a `(set-option)` command is found. `None` means the warnings are not reported.
+pub fn set_warning_level(cat: elab::WarningCategory, level: Option<ErrorLevel>) {
+  WARNING_LEVELS.ulock().set(cat, level)
+}
+
+lazy_static! {
+  static ref ALIGNMENT: Mutex<Arc<Alignment>> = Default::default();
+}
+pub(crate) fn get_alignment() -> Arc<Alignment> { ALIGNMENT.ulock().clone() }
+
+/// Set the initial name alignment at the start of each MM1 file, which is applied to
+/// imported declarations (see [`Alignment`]). It can be extended using `(align-name)`.
+pub fn set_alignment(align: Alignment) { *ALIGNMENT.ulock() = Arc::new(align) }
+
+lazy_static! {
+  static ref EXPORT_ALIGNMENT: Mutex<Arc<Alignment>> = Default::default();
+}
+pub(crate) fn get_export_alignment() -> Arc<Alignment> { EXPORT_ALIGNMENT.ulock().clone() }
+
+/// Set the name alignment that is applied to declaration names when exporting to MMB.
+/// This is separate from [`set_alignment`], because the names of the exported environment
+/// are already the local names after the import alignment has been applied.
+pub fn set_export_alignment(align: Alignment) { *EXPORT_ALIGNMENT.ulock() = Arc::new(align) }
+
+lazy_static! {
+  static ref IMPORT_PATH: Mutex<Arc<[std::path::PathBuf]>> = Mutex::new(Arc::new([]));
+}
+pub(crate) fn get_import_path() -> Arc<[std::path::PathBuf]> { IMPORT_PATH.ulock().clone() }
+
+/// Set the directories that are searched for an imported file, in order, if it is not
+/// found relative to the importing file. This is set from the source roots, import paths
+/// and dependencies of a [project](project::Project).
+pub fn set_import_path(path: Vec<std::path::PathBuf>) { *IMPORT_PATH.ulock() = path.into() }
+
+lazy_static! {
+  static ref PRELUDE: Mutex<Option<FileRef>> = Mutex::new(None);
+}
+pub(crate) fn get_prelude() -> Option<FileRef> { PRELUDE.ulock().clone() }
+
+/// Set the prelude, an MM1 file that is implicitly imported at the start of every MM1 file
+/// (except for the files that the prelude itself imports). This is used for a library of
+/// tactics that every file needs: the prelude is elaborated once, and its environment is
+/// then shared by all the files, instead of each file re-elaborating it.
+pub fn set_prelude(path: Option<FileRef>) { *PRELUDE.ulock() = path }
+
+static PRELUDE_SNAPSHOT: AtomicBool = AtomicBool::new(true);
+pub(crate) fn get_prelude_snapshot() -> bool { PRELUDE_SNAPSHOT.load(Ordering::Relaxed) }
+
+/// Set whether the compiled environment of the prelude is kept in a file next to it,
+/// like `prelude.mm1.env` for `prelude.mm1`, and loaded from there instead of elaborating
+/// the prelude again when it and its imports have not changed (default true). This is
+/// turned off when working on the prelude itself.
+pub fn set_prelude_snapshot(b: bool) { PRELUDE_SNAPSHOT.store(b, Ordering::Relaxed) }
+
+lazy_static! {
+  static ref CAPABILITIES: Mutex<Arc<elab::Capabilities>> = Default::default();
+}
+pub(crate) fn get_capabilities() -> Arc<elab::Capabilities> { CAPABILITIES.ulock().clone() }
+
+/// Set the maximum capability of lisp code (default [`Io`](elab::Capability::Io)).
+/// Builtins that need more than this report an error when called.
+pub fn set_capability(cap: elab::Capability) {
+  Arc::make_mut(&mut *CAPABILITIES.ulock()).max = cap
+}
+
+/// Set the capability of the lisp code in the files in directory `dir`, for example a
+/// third-party tactic library. It can only lower the capability set by [`set_capability`].
+pub fn set_path_capability(dir: std::path::PathBuf, cap: elab::Capability) {
+  let mut caps = CAPABILITIES.ulock();
+  let paths = &mut Arc::make_mut(&mut *caps).paths;
+  paths.retain(|(d, _)| *d != dir);
+  paths.push((dir, cap))
+}
+
+static TIMEOUT: AtomicU64 = AtomicU64::new(5000);
+pub(crate) fn get_timeout() -> Option<Duration> {
+  match TIMEOUT.load(Ordering::Relaxed) { 0 => None, n => Some(Duration::from_millis(n)) }
+}
+
+/// Set the initial lisp evaluation timeout at the start of each MM1 file (default 5 seconds),
+/// before a `(set-timeout)` command is found. `None` disables the timeout.
+pub fn set_timeout(t: Option<Duration>) {
+  TIMEOUT.store(t.map_or(0, |d| d.as_millis().try_into().unwrap_or(u64::MAX).max(1)),
+    Ordering::Relaxed)
+}
+
+lazy_static! {
+  /// The registered virtual files, with the file that registered each one
+  /// (or `None` if it was registered by [`add_virtual_file`]).
+  static ref VIRTUAL_FILES: Mutex<std::collections::HashMap<FileRef, (Option<FileRef>, Arc<str>)>> =
+    Default::default();
+}
+pub(crate) fn get_virtual_file(path: &FileRef) -> Option<Arc<str>> {
+  VIRTUAL_FILES.ulock().get(path).map(|(_, text)| text.clone())
+}
+
+/// The file that registered the virtual file at `path` using `(add-virtual-file)`, if any.
+pub(crate) fn virtual_file_owner(path: &FileRef) -> Option<FileRef> {
+  VIRTUAL_FILES.ulock().get(path).and_then(|(owner, _)| owner.clone())
+}
+
+/// Register a virtual file at `path` with contents `text`. It can be imported and elaborated
+/// like a file on disk, and it takes precedence over any file on disk with the same path.
+/// Like a file on disk, the contents are read when the file is first loaded, so changing
+/// them afterwards does not affect elaborations that have already loaded it.
+///
+/// Files registered this way are visible to all elaborations in the process. See
+/// [`add_scoped_virtual_file`] for the version used by `(add-virtual-file)` in lisp.
+pub fn add_virtual_file(path: FileRef, text: impl Into<Arc<str>>) {
+  VIRTUAL_FILES.ulock().insert(path, (None, text.into()));
+}
+
+/// Register a virtual file at `path` with contents `text` on behalf of the elaboration of
+/// `owner`, as `(add-virtual-file)` does. Unlike [`add_virtual_file`], the file is only
+/// visible to imports from `owner` and from files that import `owner`, it may not shadow
+/// a file on disk, and it is removed when `owner` is elaborated again.
+pub fn add_scoped_virtual_file(owner: FileRef, path: FileRef, text: impl Into<Arc<str>>
+) -> Result<(), String> {
+  use std::collections::hash_map::Entry;
+  if path.path().exists() {
+    return Err(format!("file '{}' already exists on disk", path))
+  }
+  match VIRTUAL_FILES.ulock().entry(path) {
+    Entry::Occupied(e) if e.get().0.as_ref() != Some(&owner) =>
+      Err(format!("virtual file '{}' is already registered by another file", e.key())),
+    Entry::Occupied(mut e) => { e.get_mut().1 = text.into(); Ok(()) }
+    Entry::Vacant(e) => { e.insert((Some(owner), text.into())); Ok(()) }
+  }
+}
+
+/// Remove the virtual files registered by `owner`, when it is about to be elaborated again.
+pub(crate) fn clear_virtual_files(owner: &FileRef) {
+  VIRTUAL_FILES.ulock().retain(|_, (o, _)| o.as_ref() != Some(owner))
+}
+
+/// A description of the global settings that affect the result of elaborating a file,
+/// such as whether proofs are checked and the import path. An elaboration result that was
+/// saved with a different description (see [`elab::persist`]) can't be reused.
+#[must_use] pub fn settings_key() -> String {
+  let mut align = get_alignment().iter().map(|(a, b)| format!("{}={}", a, b)).collect::<Vec<_>>();
+  align.sort();
+  format!("check_proofs={} minimize={:?} dep_policy={:?} warnings={:?} timeout={:?} \
+      import_path={:?} prelude={:?} capabilities={:?} align={:?}",
+    get_check_proofs(), get_minimize(), get_dep_policy(), get_warning_levels(), get_timeout(),
+    get_import_path(), get_prelude().map(|p| p.path().clone()), get_capabilities(), align)
+}
+
+/// The SHA-256 hash of `data` in hex. This is used to check the files of packages, and to
+/// recognize saved elaboration results whose sources have not changed.
+pub(crate) fn content_hash(data: &[u8]) -> String {
+  use sha2::Digest;
+  format!("{:x}", sha2::Sha256::digest(data))
+}
+
+/// The cache directory of `mm0-rs`, which holds the fetched packages and the saved
+/// environments of the language server. This is `$MM0_CACHE` if it is set, and otherwise
+/// `$XDG_CACHE_HOME/mm0` or `~/.cache/mm0`.
+pub fn cache_root() -> std::io::Result<std::path::PathBuf> {
+  use std::path::Path;
+  if let Some(dir) = std::env::var_os("MM0_CACHE") { return Ok(dir.into()) }
+  if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") { return Ok(Path::new(&dir).join("mm0")) }
+  if let Some(dir) = std::env::var_os("HOME") { return Ok(Path::new(&dir).join(".cache/mm0")) }
+  Err(std::io::Error::new(std::io::ErrorKind::NotFound, "could not find the cache directory; set MM0_CACHE"))
+}
@@ -1,6 +1,61 @@
 
 use clap::clap_app;
 
+fn set_dep_policy(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  if let Some(p) = m.value_of("deps") {
+    mm0_rs::set_dep_policy(p.parse().map_err(|e: String|
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?)
+  }
+  Ok(())
+}
+
+fn set_warnings(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  for w in m.values_of("warn").into_iter().flatten() {
+    let (cat, level) = mm0_rs::elab::WarningCategory::parse_setting(w).map_err(|e|
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    mm0_rs::set_warning_level(cat, level)
+  }
+  Ok(())
+}
+
+fn set_path_policy(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  if let Some(p) = m.value_of("paths") {
+    mm0_rs::set_path_policy(p.parse().map_err(|e: String|
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?)
+  }
+  Ok(())
+}
+
+fn set_capability(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  if let Some(c) = m.value_of("capability") {
+    mm0_rs::set_capability(c.parse().map_err(|e: String|
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?)
+  }
+  Ok(())
+}
+
+fn set_prelude(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  if m.is_present("no_prelude") {
+    mm0_rs::set_prelude(None)
+  } else if let Some(p) = m.value_of("prelude") {
+    mm0_rs::set_prelude(Some(std::fs::canonicalize(p)?.into()))
+  }
+  if m.is_present("no_prelude_snapshot") { mm0_rs::set_prelude_snapshot(false) }
+  Ok(())
+}
+
+fn set_mmc_dump(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  if let Some(s) = m.value_of("dump_mmc") {
+    #[cfg(feature = "mmc")]
+    mm0_rs::set_mmc_dump(mmcc::DumpFlags::parse(s).map_err(|e|
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?);
+    #[cfg(not(feature = "mmc"))]
+    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+      format!("--dump-mmc {}: mm0-rs was built without the mmc feature", s)))
+  }
+  Ok(())
+}
+
 fn main() -> std::io::Result<()> {
   let app = clap_app!(mm0_rs =>
     (name: "mm0-rs")
@@ -13,16 +68,63 @@ fn main() -> std::io::Result<()> {
     (@subcommand compile =>
       (about: "Compile MM1 files into MMB")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg no_sorry: --("no-sorry") "Fail if any theorem is incomplete (uses sorry)")
+      (@arg deps: --deps [POLICY] "Set the initial policy for inferred dependencies (infer, warn or strict)")
+      (@arg warn: -W --warn [SETTING] ... number_of_values(1)
+        "Set the severity of a warning category, as CATEGORY=LEVEL (like unused=error or slow-decl=warn)")
+      (@arg capability: --capability [LEVEL] possible_values(&["pure", "env-read", "env-write", "io"])
+        "Limit what lisp code can do (default io: everything)")
+      (@arg paths: --paths [POLICY] possible_values(&["lexical", "canonical", "ignore-case"])
+        "How to decide whether two paths are the same file (default canonical: resolve symlinks)")
+      (@arg prelude: --prelude [FILE] conflicts_with("no_prelude") "Import FILE implicitly at the start of every MM1 file")
+      (@arg no_prelude: --("no-prelude") "Don't import the prelude, for working on the prelude itself")
+      (@arg no_prelude_snapshot: --("no-prelude-snapshot")
+        "Always elaborate the prelude, instead of loading its compiled environment from FILE.env")
       (@arg quiet: -q --quiet "Hide diagnostic messages")
+      (@arg progress: --progress "Show the number of statements done and the current one on stderr")
+      (@arg color: --color [WHEN] possible_values(&["auto", "always", "never"])
+        "Use colors in diagnostic messages (default auto: only if the output is a terminal)")
+      (@arg minimize: --minimize [MS]
+        "Try to shorten proofs using existing theorems, spending up to MS milliseconds per theorem")
+      (@arg show_binders: --("show-binders") "Show the elaborated binders of declarations with inferred binders")
+      (@arg check_sorts: --("check-sorts") "Check that all declarations respect the sort modifiers")
+      (@arg spec: --spec [FILE] "Check the statements against the .mm0 file FILE that specifies them")
+      (@arg check_imports: --("check-imports") "Report unused and unnecessarily broad imports")
+      (@arg align: --align [FILE] "Rename the declarations of imported files according to FILE")
+      (@arg export_align: --("export-align") [FILE] "Rename declarations in the output according to FILE")
+      (@arg replay: --replay [THMS]
+        "Re-verify the proofs of THMS (a comma separated list, or 'all') in imported .mmb files")
       (@arg output: -o --output [FILE] "Print 'output' commands to a file (use '-' to print to stdout)")
+      (@arg emit_def: --("emit-def") [DEF_FILE] ... number_of_values(1)
+        "Write the bytes denoted by the string definition DEF to FILE, given as DEF=FILE")
+      (@arg emit_elf: --("emit-elf") [NAME_FILE] ... number_of_values(1)
+        "Write the executable built by the MMC program NAME to FILE, given as NAME=FILE")
+      (@arg dump_mmc: --("dump-mmc") [PASSES]
+        "Print the MMC intermediate representations (a comma separated list of mir, mir-opt, vcode, check or all)")
+      (@arg expect_output: --("expect-output") [FILE]
+        "Check that the 'output' commands produce exactly the contents of FILE")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
       (@arg OUTPUT: "Sets the output file (.mmb or .mmu)"))
     (@subcommand join =>
       (about: "Join MM1/MM0 files with imports by concatenation")
       (@arg no_header: -h --("no-header") "Skip top header")
       (@arg bare: -b --("bare") "Don't add any comments")
+      (@arg align: --align [FILE] "Rename the declarations of imported files according to FILE")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
       (@arg OUTPUT: "Sets the output file (.mm1 or .mm0), or stdin if omitted"))
+    (@subcommand refactor =>
+      (about: "Rename or move declarations in a whole import graph")
+      (@arg rename: --rename [RENAME] "Rename a declaration and all its uses (OLD=NEW)")
+      (@arg move_decl: --("move") [MOVE] "Move a declaration to the end of another file (NAME=FILE)")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
+    (@subcommand profile =>
+      (about: "Time the elaboration of each declaration, and compare against a baseline")
+      (@arg output: -o --output [FILE] "Write the timings to a JSON file")
+      (@arg baseline: --baseline [FILE] "Compare against timings from a previous run")
+      (@arg fail_on_regression: --("fail-on-regression") [PERCENT]
+        "Fail if any declaration is slower than the baseline by more than PERCENT (default 20%)")
+      (@arg min_time: --("min-time") [MS] "Ignore declarations faster than MS milliseconds in the baseline")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
     (@subcommand doc =>
       (about: "Build documentation pages")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
@@ -31,13 +133,94 @@ fn main() -> std::io::Result<()> {
       (@arg order: --("order") <ORDER>
          possible_values(&["pre", "post"]) default_value("post")
          "Proof tree traversal order")
-      (@arg src: --src [URL] "Use URL as the base for source doc links (use - to disable)")));
+      (@arg src: --src [URL] "Use URL as the base for source doc links (use - to disable)"))
+    (@subcommand test =>
+      (about: "Run a directory of MM1 test files")
+      (@arg filter: --filter [PAT] "Only run the tests whose path contains PAT")
+      (@arg jobs: -j --jobs [N] "Run N tests in parallel (default: the number of CPUs)")
+      (@arg format: --format [FMT] possible_values(&["human", "tap", "junit"])
+        "Report format (default human)")
+      (@arg bless: --bless "Write the output of each test to its .expected file instead of checking it")
+      (@arg deps: --deps [POLICY] "Set the initial policy for inferred dependencies (infer, warn or strict)")
+      (@arg warn: -W --warn [SETTING] ... number_of_values(1)
+        "Set the severity of a warning category, as CATEGORY=LEVEL (like unused=error or slow-decl=warn)")
+      (@arg capability: --capability [LEVEL] possible_values(&["pure", "env-read", "env-write", "io"])
+        "Limit what lisp code can do (default io: everything)")
+      (@arg paths: --paths [POLICY] possible_values(&["lexical", "canonical", "ignore-case"])
+        "How to decide whether two paths are the same file (default canonical: resolve symlinks)")
+      (@arg prelude: --prelude [FILE] conflicts_with("no_prelude") "Import FILE implicitly at the start of every MM1 file")
+      (@arg no_prelude: --("no-prelude") "Don't import the prelude, for working on the prelude itself")
+      (@arg no_prelude_snapshot: --("no-prelude-snapshot")
+        "Always elaborate the prelude, instead of loading its compiled environment from FILE.env")
+      (@arg INPUT: +required ... "Directories (searched recursively for .mm1 files) or test files"))
+    (@subcommand info =>
+      (about: "Print a summary of the contents of an MMB file")
+      (@arg json: --json "Print the summary as JSON")
+      (@arg top: --top [N] "List the N largest theorems (default 10)")
+      (@arg INPUT: +required "Sets the input file (.mmb)"))
+    (@subcommand diff_src =>
+      (name: "diff-src")
+      (about: "Compare the declarations of two versions of an MM1/MM0 file")
+      (@arg OLD: +required "The old version of the file")
+      (@arg NEW: +required "The new version of the file"))
+    (@subcommand export_index =>
+      (name: "export-index")
+      (about: "Write the statements of all declarations as JSON, for search tools")
+      (@arg output: -o --output [FILE] "Write the index to FILE instead of stdout")
+      (@arg premises: --premises [FILE]
+        "Also write the features and the lemmas used of each proof to FILE, as JSON lines")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
+    (@subcommand proof_tree =>
+      (name: "proof-tree")
+      (about: "Show the elaborated proof of a theorem as an outline or a graph")
+      (@arg format: --format [FMT] possible_values(&["outline", "dot"])
+        "Output format (default outline; dot is a Graphviz graph)")
+      (@arg output: -o --output [FILE] "Write the result to FILE instead of stdout")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg THM: +required "The theorem whose proof is shown"))
+    (@subcommand holes =>
+      (about: "List the unsolved goals and uses of sorry in files and their imports")
+      (@arg json: --json "Print the list as JSON")
+      (@arg INPUT: +required +multiple "Sets the input files (.mm1 or .mm0)"))
+    (@subcommand debug_dump =>
+      (name: "debug-dump")
+      (about: "Dump the internal representation of lisp globals or a declaration, for debugging")
+      (@arg globals: --globals [PREFIX] "Dump the lisp globals whose names start with PREFIX")
+      (@arg decl: --decl [NAME] "Dump the sort, term or theorem NAME")
+      (@arg output: -o --output [FILE] "Write the dump to FILE instead of stdout")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
+    (@subcommand explain =>
+      (about: "Print an extended description of an error code")
+      (@arg CODE: "The error code, like E0001 or syntax, or all codes if omitted")));
+
+  #[cfg(feature = "package")]
+  let app = clap_app!(@app (app)
+    (@subcommand build =>
+      (about: "Build the targets of a project (mm0.toml)")
+      (@arg manifest: --manifest [FILE] "Use this project manifest instead of searching for mm0.toml")
+      (@arg TARGET: ... "The targets to build, or all targets if omitted"))
+    (@subcommand package =>
+      (about: "Build a project (mm0.toml) and package it as a library")
+      (@arg manifest: --manifest [FILE] "Use this project manifest instead of searching for mm0.toml")
+      (@arg output: -o --output [FILE] "Write the package to this file (default NAME-VERSION.tar)"))
+    (@subcommand fetch =>
+      (about: "Download the packaged dependencies of a project (mm0.toml)")
+      (@arg manifest: --manifest [FILE] "Use this project manifest instead of searching for mm0.toml")
+      (@arg force: -f --force "Download the packages even if they are already in the cache")));
 
   #[cfg(feature = "server")]
   let app = clap_app!(@app (app)
     (@subcommand server =>
       (about: "MM1 LSP server")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg deps: --deps [POLICY] "Set the initial policy for inferred dependencies (infer, warn or strict)")
+      (@arg warn: -W --warn [SETTING] ... number_of_values(1)
+        "Set the severity of a warning category, as CATEGORY=LEVEL (like unused=error or slow-decl=warn)")
+      (@arg capability: --capability [LEVEL] possible_values(&["pure", "env-read", "env-write", "io"])
+        "Limit what lisp code can do (default io: everything)")
+      (@arg paths: --paths [POLICY] possible_values(&["lexical", "canonical", "ignore-case"])
+        "How to decide whether two paths are the same file (default canonical: resolve symlinks)")
+      (@arg no_prelude: --("no-prelude") "Don't import the prelude of the project, for working on the prelude itself")
       (@arg debug: -d --debug "Enable debug logging")
       (@arg no_log_errors: -q --quiet "Don't print errors in server output log")));
 
@@ -46,14 +229,47 @@ fn main() -> std::io::Result<()> {
   match m.subcommand() {
     ("compile", Some(m)) => {
       if m.is_present("no_proofs") { mm0_rs::set_check_proofs(false) }
+      set_dep_policy(m)?;
+      set_warnings(m)?;
+      set_path_policy(m)?;
+      set_capability(m)?;
+      set_prelude(m)?;
+      set_mmc_dump(m)?;
       mm0_rs::compiler::main(m)?
     }
     ("join", Some(m)) => mm0_rs::joiner::main(m)?,
+    ("refactor", Some(m)) => mm0_rs::refactor::main(m)?,
+    ("profile", Some(m)) => mm0_rs::profile::main(m)?,
+    ("test", Some(m)) => {
+      set_dep_policy(m)?;
+      set_warnings(m)?;
+      set_path_policy(m)?;
+      set_capability(m)?;
+      set_prelude(m)?;
+      mm0_rs::test_runner::main(m)?
+    }
+    ("explain", Some(m)) => mm0_rs::explain::main(m)?,
+    ("info", Some(m)) => mm0_rs::info::main(m)?,
+    ("diff-src", Some(m)) => mm0_rs::diff::main(m)?,
+    ("export-index", Some(m)) => mm0_rs::export_index::main(m)?,
+    ("proof-tree", Some(m)) => mm0_rs::proof_tree::main(m)?,
+    ("holes", Some(m)) => mm0_rs::holes::main(m)?,
+    ("debug-dump", Some(m)) => mm0_rs::debug_dump::main(m)?,
+    #[cfg(feature = "package")]
+    ("build", Some(m)) => mm0_rs::project::main(m)?,
+    #[cfg(feature = "package")]
+    ("package", Some(m)) => mm0_rs::package::main(m)?,
+    #[cfg(feature = "package")]
+    ("fetch", Some(m)) => mm0_rs::package::fetch_main(m)?,
     #[cfg(feature = "doc")]
     ("doc", Some(m)) => mm0_rs::doc::main(m)?,
     #[cfg(feature = "server")]
     ("server", Some(m)) => {
       if m.is_present("no_proofs") { mm0_rs::set_check_proofs(false) }
+      set_dep_policy(m)?;
+      set_warnings(m)?;
+      set_path_policy(m)?;
+      set_capability(m)?;
       mm0_rs::server::main(m)
     }
     _ => unreachable!()
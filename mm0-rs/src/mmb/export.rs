@@ -7,11 +7,16 @@ use zerocopy::{AsBytes, U32, U64};
 use crate::{
   Type, Expr, Proof, SortId, AtomId, AtomVec, TermKind, ThmKind,
   TermVec, ExprNode, ProofNode, StmtTrace, DeclKey, Modifiers,
-  FrozenEnv, FileRef, LinedString, ErrorLevel};
+  FrozenEnv, FileRef, FileSpan, Span, LinedString, ErrorLevel, Alignment};
+use std::sync::Arc;
+use std::collections::{HashMap, hash_map::Entry};
 
 #[allow(clippy::wildcard_imports)]
 use mm0b_parser::{ProofCmd, UnifyCmd, cmd::*, write_cmd_bytes};
 
+/// The producer string written to the `"Prod"` table of the index.
+pub const PRODUCER: &str = concat!("mm0-rs ", env!("CARGO_PKG_VERSION"));
+
 #[derive(Debug)]
 struct Reorder<T=u32> {
   map: Box<[Option<T>]>,
@@ -53,6 +58,8 @@ pub struct Exporter<'a, W> {
   /// than the current writer location. We buffer these to avoid too many seeks
   /// of the underlying writer.
   fixups: Vec<(u64, Value)>,
+  /// The renamings to apply to declaration names in the debugging index.
+  align: Arc<Alignment>,
 }
 
 impl<'a, W: std::fmt::Debug> std::fmt::Debug for Exporter<'a, W> {
@@ -216,6 +223,36 @@ impl<W: Write> Drop for BigBuffer<W> {
   }
 }
 
+/// The buffer for the proof stream of a statement. This also tracks the positions of the
+/// steps of the proof which have a known source, for the source map in the debugging index.
+#[derive(Default)]
+struct ProofStream<'a> {
+  /// The statement data written so far.
+  buf: Vec<u8>,
+  /// The spans of the nodes of the current proof (see [`Proof::spans`]), by address.
+  spans: HashMap<*const ProofNode, &'a FileSpan>,
+  /// The positions in `buf` where the nodes in `spans` have been written.
+  out: Vec<(usize, &'a FileSpan)>,
+}
+
+impl<'a> ProofStream<'a> {
+  /// Record that `node` is about to be written, if it has a known source.
+  fn mark(&mut self, node: &ProofNode) {
+    if let Some(&fsp) = self.spans.get(&(node as *const _)) { self.out.push((self.buf.len(), fsp)) }
+  }
+
+  fn clear(&mut self) {
+    self.buf.clear();
+    self.spans.clear();
+    self.out.clear();
+  }
+}
+
+impl Write for ProofStream<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.buf.write(buf) }
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
 struct NameData {
   name: AtomId,
   p_proof: u64,
@@ -227,11 +264,13 @@ struct VarData {
   vars: Vec<AtomId>,
 }
 
-struct IndexTemp {
+struct IndexTemp<'a> {
   sort_names: Vec<NameData>,
   term_names: Vec<(NameData, VarData)>,
   /// The second `VarData` is the list of hypotheses
   thm_names: Vec<((NameData, VarData), VarData)>,
+  /// The source map of each theorem, as pairs of a position in the file and a span
+  src_maps: Vec<Vec<(u64, &'a FileSpan)>>,
 }
 
 impl<'a, W: Write + Seek> Exporter<'a, W> {
@@ -246,7 +285,8 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
   ) -> Self {
     Self {
       term_reord: TermVec(Vec::with_capacity(env.terms().len())),
-      file, source, env, report, w, pos: 0, fixups: vec![]
+      file, source, env, report, w, pos: 0, fixups: vec![],
+      align: crate::get_export_alignment(),
     }
   }
 
@@ -258,7 +298,12 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     WriteBytesExt::write_u64::<LE>(self, n)
   }
 
-  fn write_str(&mut self, s: &'a [u8]) -> io::Result<()> {
+  fn write_span(&mut self, sp: Span) -> io::Result<()> {
+    self.write_u32(sp.start.try_into().expect("span out of range"))?;
+    self.write_u32(sp.end.try_into().expect("span out of range"))
+  }
+
+  fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
     for &c in s {assert!(c != 0)}
     self.write_all(s)?;
     self.write_u8(0)
@@ -356,13 +401,14 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     Ok(())
   }
 
-  fn write_proof(&self, w: &mut impl Write,
+  fn write_proof(&self, w: &mut ProofStream<'_>,
     heap: &[ProofNode],
     reorder: &mut Reorder,
     hyps: &[u32],
     node: &ProofNode,
     save: bool
   ) -> io::Result<u32> {
+    w.mark(node);
     Ok(match node {
       &ProofNode::Ref(i) => match reorder.map[i] {
         None => {
@@ -411,12 +457,13 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     })
   }
 
-  fn write_conv(&self, w: &mut impl Write,
+  fn write_conv(&self, w: &mut ProofStream<'_>,
     heap: &[ProofNode],
     reorder: &mut Reorder,
     hyps: &[u32],
     node: &ProofNode,
   ) -> io::Result<()> {
+    w.mark(node);
     match node {
       &ProofNode::Ref(i) => match reorder.map[i] {
         None => {
@@ -534,12 +581,13 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
 
     // main body (proofs of theorems)
     p_proof.commit(self);
-    let vec = &mut vec![];
+    let vec = &mut ProofStream::default();
     let mut index_temp = if index {
       Some(IndexTemp {
         sort_names: Vec::with_capacity(num_sorts),
         term_names: Vec::with_capacity(num_terms),
         thm_names: Vec::with_capacity(num_thms),
+        src_maps: Vec::with_capacity(num_thms),
       })
     } else { None };
     for s in self.env.stmts() {
@@ -560,7 +608,7 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                   NameData {name: a, p_proof: self.pos},
                   VarData {p_vars: 0, vars}
                 ));
-                &mut unwrap_unchecked!(temp.term_names.last_mut()).1.vars
+                &mut temp.term_names.last_mut().expect("just pushed").1.vars
               });
               match &td.kind {
                 TermKind::Term => write_cmd_bytes(self, STMT_TERM, &[])?,
@@ -572,7 +620,7 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                   write_expr_proof(vec, heap, &mut reorder, vars, head, false)?;
                   vec.write_u8(0)?;
                   let cmd = STMT_DEF | if td.vis == Modifiers::LOCAL {STMT_LOCAL} else {0};
-                  write_cmd_bytes(self, cmd, vec)?;
+                  write_cmd_bytes(self, cmd, &vec.buf)?;
                   vec.clear();
                 }
               }
@@ -587,7 +635,7 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                   VarData {p_vars: 0, vars: td.hyps.iter()
                     .map(|p| p.0.unwrap_or(AtomId::UNDER)).collect()},
                 ));
-                &mut unwrap_unchecked!(temp.thm_names.last_mut()).1.vars
+                &mut temp.thm_names.last_mut().expect("just pushed").1.vars
               });
               #[allow(clippy::cast_possible_truncation)] // no truncation
               let nargs = td.args.len() as u32;
@@ -603,12 +651,21 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                     STMT_AXIOM
                   } else {
                     ProofCmd::Sorry.write_to(vec)?;
-                    (self.report)(ErrorLevel::Warning, &format!(
-                      "theorem {} contains sorry", self.env.data()[td.atom].name()));
+                    (self.report)(ErrorLevel::Warning, &if self.env.is_unchecked(t) {
+                      format!("theorem {} was not checked", self.env.data()[td.atom].name())
+                    } else {
+                      format!("theorem {} contains sorry", self.env.data()[td.atom].name())
+                    });
                     STMT_THM | if td.vis == Modifiers::PUB {0} else {STMT_LOCAL}
                   }
                 }
-                ThmKind::Thm(Some(Proof {heap, hyps, head})) => {
+                ThmKind::Thm(Some(proof)) => {
+                  let Proof {heap, hyps, head, ..} = proof;
+                  if index_temp.is_some() && !proof.spans.is_empty() {
+                    let steps = proof.steps();
+                    vec.spans = proof.spans.iter()
+                      .map(|(n, fsp)| (steps[*n as usize] as *const _, fsp)).collect();
+                  }
                   let mut reorder = Reorder::new(nargs, heap.len(), |i| i);
                   let mut ehyps = Vec::with_capacity(hyps.len());
                   for h in &**hyps {
@@ -626,7 +683,11 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                 }
               };
               vec.write_u8(0)?;
-              write_cmd_bytes(self, cmd, vec)?;
+              write_cmd_bytes(self, cmd, &vec.buf)?;
+              if let Some(temp) = &mut index_temp {
+                let start = self.pos - vec.buf.len() as u64;
+                temp.src_maps.push(vec.out.drain(..).map(|(i, fsp)| (start + i as u64, fsp)).collect());
+              }
               vec.clear();
             }
           }
@@ -638,15 +699,17 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     self.write_u8(0)?;
 
     // debugging index
-    if let Some(IndexTemp { mut sort_names, mut term_names, mut thm_names }) = index_temp {
+    if let Some(IndexTemp { mut sort_names, mut term_names, mut thm_names, src_maps }) = index_temp {
       assert_eq!(sort_names.len(), num_sorts);
       assert_eq!(term_names.len(), num_terms);
       assert_eq!(thm_names.len(), num_thms);
+      assert_eq!(src_maps.len(), num_thms);
 
+      let align = self.align.clone();
       let mut atom_pos = AtomVec(self.env.data().enum_iter().map(|(_, ad)| -> io::Result<_> {
         if ad.sort().is_some() || ad.decl().is_some() {
           let pos = self.pos;
-          self.write_str(ad.name())?;
+          self.write_str(align.get(ad.name()))?;
           Ok(pos)
         } else { Ok(0) }
       }).collect::<io::Result<Vec<u64>>>()?);
@@ -661,6 +724,50 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
       for &a in decls!().flat_map(|n| &n.1.vars) { add_atom(a)? }
       for &a in thm_names.iter().flat_map(|n| &n.1.vars) { add_atom(a)? }
 
+      let env = self.env;
+      let decl_srcs = sort_names.iter().map(|n| n.name)
+        .chain(term_names.iter().map(|n| n.0.name))
+        .chain(thm_names.iter().map(|n| (n.0).0.name))
+        .map(|a| match env.data()[a].decl() {
+          None => {
+            let sd = env.sort(env.data()[a].sort().expect("expected a sort"));
+            (a, &sd.span, sd.full, &sd.doc)
+          }
+          Some(DeclKey::Term(t)) => { let td = env.term(t); (a, &td.span, td.full, &td.doc) }
+          Some(DeclKey::Thm(t)) => { let td = env.thm(t); (a, &td.span, td.full, &td.doc) }
+        })
+        .collect::<Vec<_>>();
+
+      let (mut files, mut file_pos) = (vec![], HashMap::new());
+      let fsps = src_maps.iter().flatten().map(|p| p.1).chain(decl_srcs.iter().map(|p| p.1));
+      for fsp in fsps {
+        if let Entry::Vacant(e) = file_pos.entry(fsp.file.rel()) {
+          e.insert(self.pos);
+          files.push(self.pos);
+          self.write_str(fsp.file.rel().as_bytes())?
+        }
+      }
+
+      let doc_pos = decl_srcs.iter().map(|&(_, _, _, doc)| match doc {
+        Some(doc) if !doc.contains('\0') => {
+          let pos = self.pos;
+          self.write_str(doc.as_bytes())?;
+          Ok(pos)
+        }
+        _ => Ok(0)
+      }).collect::<io::Result<Vec<u64>>>()?;
+
+      let attr_pos = decl_srcs.iter().map(|&(a, ..)| {
+        env.attrs(a).iter().filter(|s| !s.contains(&0)).map(|s| {
+          let pos = self.pos;
+          self.write_str(s)?;
+          Ok(pos)
+        }).collect::<io::Result<Vec<u64>>>()
+      }).collect::<io::Result<Vec<_>>>()?;
+
+      let p_producer = self.pos;
+      self.write_str(PRODUCER.as_bytes())?;
+
       self.align_to(8)?;
       let mut write_vd = |vd: &mut VarData| -> io::Result<()> {
         vd.p_vars = self.pos;
@@ -671,6 +778,30 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
       for (_, vd) in &mut term_names { write_vd(vd)? }
       for ((_, vd), hs) in &mut thm_names { write_vd(vd)?; write_vd(hs)? }
 
+      let mut p_src_maps = Vec::with_capacity(num_thms);
+      for srcs in &src_maps {
+        if srcs.is_empty() { p_src_maps.push(0); continue }
+        p_src_maps.push(self.pos);
+        self.write_u64(srcs.len() as u64)?;
+        for &(pos, fsp) in srcs {
+          self.write_u64(pos)?;
+          self.write_u64(file_pos[fsp.file.rel()])?;
+          self.write_span(fsp.span)?;
+        }
+      }
+
+      let p_files = self.pos;
+      self.write_u64(files.len() as u64)?;
+      for &p in &files { self.write_u64(p)? }
+
+      let mut p_attr_lists = Vec::with_capacity(attr_pos.len());
+      for attrs in &attr_pos {
+        if attrs.is_empty() { p_attr_lists.push(0); continue }
+        p_attr_lists.push(self.pos);
+        self.write_u64(attrs.len() as u64)?;
+        for &p in attrs { self.write_u64(p)? }
+      }
+
       let p_names = self.pos;
       for n in sort_names.iter_mut().chain(decls!().map(|(n, _)| n)) {
         self.write_u64(n.p_proof)?;
@@ -683,8 +814,26 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
       let p_hyps = self.pos;
       for (_, hs) in &thm_names { self.write_u64(hs.p_vars)? }
 
+      let p_srcs = self.pos;
+      for &p in &p_src_maps { self.write_u64(p)? }
+
+      let p_spans = self.pos;
+      for &(_, fsp, full, _) in &decl_srcs {
+        self.write_u64(file_pos[fsp.file.rel()])?;
+        self.write_span(full)?;
+        self.write_span(fsp.span)?;
+      }
+
+      let p_docs = self.pos;
+      for &p in &doc_pos { self.write_u64(p)? }
+
+      let p_attrs = self.pos;
+      for &p in &p_attr_lists { self.write_u64(p)? }
+
       p_index.commit(self);
-      let index = [(INDEX_NAME, p_names), (INDEX_VAR_NAME, p_vars), (INDEX_HYP_NAME, p_hyps)];
+      let index = [(INDEX_NAME, p_names), (INDEX_VAR_NAME, p_vars), (INDEX_HYP_NAME, p_hyps),
+        (INDEX_SOURCE_MAP, p_srcs), (INDEX_FILE, p_files), (INDEX_SPAN, p_spans),
+        (INDEX_DOC, p_docs), (INDEX_ATTR, p_attrs), (INDEX_PRODUCER, p_producer)];
       self.write_u64(index.len() as u64)?;
       for (name, ptr) in &index {
         self.write_all(name)?;
@@ -1,11 +1,12 @@
 //! Importer for MMB files into the [`Environment`].
 
 use std::rc::Rc;
+use std::collections::HashMap;
 use crate::{Environment, Modifiers, AtomId, TermId,
     Type, Term, Thm, TermKind, ThmKind, ExprNode, Expr, Proof};
 use crate::elab::proof::{IDedup, ProofKind, ProofHash, build};
-use crate::{FileRef, FileSpan, SliceExt};
-use mm0b_parser::{NumdStmtCmd, UnifyCmd, ProofCmd, BasicMmbFile,
+use crate::{FileRef, FileSpan, Span, SliceExt};
+use mm0b_parser::{NumdStmtCmd, UnifyCmd, ProofCmd, BasicMmbFile, DeclSpanRef,
   ParseError, UnifyIter, ProofIter, exhausted};
 
 
@@ -362,12 +363,29 @@ fn parse_proof(
   }.as_proof(pos)?.0;
   let (mut ids, heap) = build(&st.de);
   let hyps = st.hyps.into_iter().map(|i| ids[i].take()).collect();
-  Ok(Proof {heap, hyps, head: ids[ret].take()})
+  Ok(Proof {heap, hyps, head: ids[ret].take(), spans: Box::new([])})
+}
+
+/// Replace the location of a declaration in the `mmb` file with its location in the original
+/// source file, if the index records one and the source file can still be found.
+fn source_span(
+  srcs: &mut HashMap<String, Option<FileRef>>, sp: Option<DeclSpanRef<'_>>,
+  fsp: FileSpan, full: Span,
+) -> (FileSpan, Span) {
+  if let Some(DeclSpanRef {file: Some(path), full, name}) = sp {
+    let file = srcs.entry(path.into())
+      .or_insert_with(|| std::fs::canonicalize(path).ok().map(FileRef::from));
+    if let Some(file) = file {
+      return (FileSpan {file: file.clone(), span: name.into()}, full.into())
+    }
+  }
+  (fsp, full)
 }
 
 fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
   use ParseError::StrError;
   let file = BasicMmbFile::parse(buf)?;
+  let mut srcs = HashMap::new();
   let mut it = file.proof();
   let mut start = it.pos;
   macro_rules! get_get_var {($list:expr) => {{
@@ -385,16 +403,18 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
   }}
   while let Some(e) = it.next() {
     let (stmt, mut pf) = e?;
-    match stmt {
+    let atom = match stmt {
       NumdStmtCmd::Sort {sort_id} => {
         if !pf.is_null() { return Err(StrError("Next statement incorrect", pf.pos)) }
         let atom = env.get_atom(file.sort_name(sort_id).as_bytes());
         let span = (start..pf.pos).into();
-        let fsp = FileSpan {file: fref.clone(), span};
+        let (fsp, span) = source_span(&mut srcs, file.stmt_span(stmt),
+          FileSpan {file: fref.clone(), span}, span);
         let sd = file.sort(sort_id).and_then(|sd| sd.try_into().ok())
           .ok_or(StrError("Step sort overflow", start))?;
-        env.add_sort(atom, fsp, span, sd, None)
+        env.add_sort(atom, fsp, span, sd, file.stmt_doc(stmt).map(Into::into))
           .map_err(|_| StrError("double add sort", start))?;
+        atom
       }
       NumdStmtCmd::TermDef {term_id, local} => {
         let atom = env.get_atom(file.term_name(term_id).as_bytes());
@@ -415,12 +435,14 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
           if !pf.is_null() { return Err(StrError("Next statement incorrect", pf.pos)) }
           TermKind::Term
         };
-        let full = (start..pf.pos).into();
+        let (fsp, full) =
+          source_span(&mut srcs, file.stmt_span(stmt), fsp, (start..pf.pos).into());
         env.add_term(Term {
-          atom, span: fsp, full, doc: None, args, kind,
+          atom, span: fsp, full, doc: file.stmt_doc(stmt).map(Into::into), args, kind,
           vis: if local {Modifiers::LOCAL} else {Modifiers::empty()},
           ret: (ret.sort(), ret.deps_unchecked()),
         }).map_err(|_| StrError("double add term", start))?;
+        atom
       }
       NumdStmtCmd::Axiom {thm_id} | NumdStmtCmd::Thm {thm_id, ..} => {
         let atom = env.get_atom(file.thm_name(thm_id).as_bytes());
@@ -445,15 +467,22 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
             Err(e) => return Err(e)
           }
         };
-        let full = (start..pf.pos).into();
+        let (fsp, full) =
+          source_span(&mut srcs, file.stmt_span(stmt), fsp, (start..pf.pos).into());
         let vis =
           if matches!(stmt, NumdStmtCmd::Thm {local: false, ..}) {Modifiers::PUB}
           else {Modifiers::empty()};
         env.add_thm(Thm {
-          atom, span: fsp, full, doc: None, args, kind,
+          atom, span: fsp, full, doc: file.stmt_doc(stmt).map(Into::into), args, kind,
           vis, heap, hyps: hyps.into_boxed_slice(), ret,
         }).map_err(|_| StrError("double add term", start))?;
+        atom
       }
+    };
+    let attrs = file.stmt_attrs(stmt);
+    if !attrs.is_empty() {
+      let attrs = (0..attrs.len()).map(|i| attrs.get(i).as_bytes().into()).collect();
+      env.attrs.insert(atom, attrs);
     }
     start = it.pos;
   }
@@ -463,5 +492,6 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
 /// Construct an [`Environment`] from an `mmb` file.
 pub fn elab(file: &FileRef, source: &[u8]) -> (crate::elab::Result<()>, Environment) {
   let mut env = Environment::new();
+  env.do_cache = crate::elab::do_cache::DoCache::from_source(source);
   (parse(file, source, &mut env).map_err(From::from), env)
 }
\ No newline at end of file
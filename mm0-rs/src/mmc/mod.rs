@@ -12,10 +12,10 @@ mod proof;
 
 use std::{collections::HashMap, rc::Rc};
 // use bumpalo::Bump;
-use mmcc::{infer::TypeError, types::{IdxVec, LambdaId, hir, ty::CtxPrint}};
+use mmcc::{infer::{TypeError, VarOccurrence}, types::{IdxVec, LambdaId, hir, ty::CtxPrint}};
 use parser::{ItemIter, Parser, Keyword};
-use crate::{FileSpan, Span, AtomId, Remap, Remapper, Elaborator, ElabError,
-  elab::Result, LispVal, EnvDebug, FormatEnv};
+use crate::{FileSpan, Span, AtomId, Remap, Remapper, Elaborator, ElabError, ObjectKind,
+  elab::Result, LispKind, LispVal, EnvDebug, FormatEnv, lisp::Proc};
 
 use self::parser::Mm0ExprNode;
 
@@ -59,6 +59,7 @@ struct ItemContext<'a> {
   elab: &'a Elaborator,
   lambdas: &'a IdxVec<LambdaId, Mm0ExprNode>,
   errors: &'a mut Vec<ElabError>,
+  spans: &'a mut Vec<(Span, ObjectKind)>,
 }
 
 impl Clone for Config {
@@ -89,6 +90,32 @@ impl<'a> mmcc::ItemContext<Config> for ItemContext<'a> {
     }));
     Ok(false)
   }
+
+  fn emit_var_occurrences<'b>(&mut self, _: &mut Config,
+    vars: Vec<VarOccurrence<'b>>,
+    pr: &impl mmcc::DisplayCtx<'b>,
+  ) {
+    let path = &self.elab.path;
+    for v in vars {
+      if !v.span.file.ptr_eq(path) { continue }
+      let binder = match v.binder {
+        Some(fsp) if fsp.file.ptr_eq(path) => fsp.span,
+        _ => v.span.span,
+      };
+      let ty = format!("{}: {}", CtxPrint(pr, &v.var), CtxPrint(pr, v.ty));
+      self.spans.push((v.span.span, ObjectKind::MmcVar(ty.into(), binder)))
+    }
+  }
+}
+
+/// Report the intermediate representations printed by the compiler, and the failed
+/// consistency checks, at `sp` (see [`set_mmc_dump`](crate::set_mmc_dump)).
+fn report_dump(compiler: &mut mmcc::Compiler<Config>, elab: &mut Elaborator, sp: Span) {
+  let (out, errors) = compiler.dump.take();
+  if !out.is_empty() { elab.report(ElabError::info(sp, out)) }
+  for e in errors {
+    elab.report(ElabError::new_e(sp, format!("MMC consistency check failed: {}", e)))
+  }
 }
 
 /// The MMC compiler, which contains local state for the functions that have been
@@ -139,6 +166,7 @@ impl Compiler {
     elab: &mut Elaborator, sp: Span, it: impl Iterator<Item=LispVal>
   ) -> Result<()> {
     let compiler = Rc::make_mut(&mut self.inner);
+    compiler.dump.flags = crate::get_mmc_dump();
     let fsp = FileSpan {file: elab.path.clone(), span: sp};
     let mut cache = HashMap::default();
     for e in it {
@@ -151,13 +179,16 @@ impl Compiler {
           Ok(None) => break,
         };
         let (var_names, lambdas) = p.finish();
-        let mut errors = vec![];
+        let (mut errors, mut spans) = (vec![], vec![]);
         compiler.add(&item, var_names, ItemContext {
           elab,
           lambdas: &lambdas,
-          errors: &mut errors
+          errors: &mut errors,
+          spans: &mut spans,
         })?;
         for e in errors { elab.report(e) }
+        for (sp, k) in spans { elab.spans.insert_if(sp, || k) }
+        report_dump(compiler, elab, sp);
       }
     }
     Ok(())
@@ -169,11 +200,31 @@ impl Compiler {
     if compiler.has_type_errors() {
       return Err(ElabError::new_e(sp, "Compilation failed due to previous errors"))
     }
-    proof::render_proof(&self.predef, elab, sp, name, &compiler.finish().proof())
+    compiler.dump.flags = crate::get_mmc_dump();
+    let code = compiler.finish();
+    report_dump(compiler, elab, sp);
+    proof::render_proof(&self.predef, elab, sp, name, &code.proof())
+  }
+
+  /// Add the items of the compilers `others` (typically from other files) to this one, so
+  /// that they are linked into the program built by [`finish`](Self::finish).
+  /// See [`mmcc::Compiler::link`].
+  pub fn link(&mut self, elab: &Elaborator, sp: Span, others: impl Iterator<Item=LispVal>) -> Result<()> {
+    for e in others {
+      let other = e.unwrapped(|e| match e {
+        LispKind::Proc(Proc::MmcCompiler(c)) => Some(c.try_borrow().map(|c| c.inner.clone())),
+        _ => None,
+      }).ok_or_else(|| ElabError::new_e(sp,
+        format!("mmc-link: expected a compiler, got {}", elab.print(&e))))?
+        .map_err(|_| ElabError::new_e(sp, "mmc-link: cannot link a compiler with itself"))?;
+      Rc::make_mut(&mut self.inner).link(&other).map_err(|errs|
+        ElabError::new_e(sp, format!("mmc-link failed:\n{}", errs.join("\n"))))?
+    }
+    Ok(())
   }
 
   /// Main entry point to the compiler. Does basic parsing and forwards to
-  /// [`add`](Self::add) and [`finish`](Self::finish).
+  /// [`add`](Self::add), [`link`](Self::link) and [`finish`](Self::finish).
   pub fn call(&mut self, elab: &mut Elaborator, sp: Span, args: Vec<LispVal>) -> Result<LispVal> {
     let mut it = args.into_iter();
     let e = it.next().expect("expected 1 argument");
@@ -189,6 +240,10 @@ impl Compiler {
         self.finish(elab, sp, name)?;
         Ok(LispVal::undef())
       }
+      None if e.as_atom().map_or(false, |a| elab.data[a].name.as_str() == "link") => {
+        self.link(elab, sp, it)?;
+        Ok(LispVal::undef())
+      }
       _ => Err(ElabError::new_e(sp,
         format!("mmc-compiler: unknown subcommand '{}'", elab.print(&e))))
     }
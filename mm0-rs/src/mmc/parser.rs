@@ -861,8 +861,10 @@ impl<'a, C> Parser<'a, C> {
         return Err(ElabError::new_e(span, "a labeled block is a statement, not an expression"))
       }
       let expr = if let Some(Spanned {k: ast::StmtKind::Expr(_), ..}) = stmts.last() {
-        let_unchecked!(Some(Spanned {span, k: ast::StmtKind::Expr(expr)}) = stmts.pop(),
-          Some(Box::new(Spanned {span, k: expr})))
+        match stmts.pop() {
+          Some(Spanned {span, k: ast::StmtKind::Expr(expr)}) => Some(Box::new(Spanned {span, k: expr})),
+          _ => unreachable!("checked above"),
+        }
       } else {
         None
       };
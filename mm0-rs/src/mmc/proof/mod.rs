@@ -223,7 +223,7 @@ impl ProofDedup<'_> {
     Thm {
       atom, span, full, doc: None, vis,
       args: Box::new([]), hyps: Box::new([]), heap: eheap, ret,
-      kind: ThmKind::Thm(Some(Proof { heap, hyps: Box::new([]), head })),
+      kind: ThmKind::Thm(Some(Proof { heap, hyps: Box::new([]), head, spans: Box::new([]) })),
     }
   }
 }
@@ -290,6 +290,20 @@ impl Mangler {
   }
 }
 
+/// Build a `string` expression denoting `bytes`, as a balanced tree of `sadd` with
+/// leaves `s1 (ch _ _)`.
+fn string_expr<'a, D: Dedup<'a>>(de: &mut D, hex: &HexCache<D::Id>, bytes: &[u8]) -> D::Id {
+  match *bytes {
+    [] => app!(de, s0[]),
+    [b] => app!(de, s1[hex.ch(de, b)]),
+    _ => {
+      let (l, r) = bytes.split_at(bytes.len() / 2);
+      let (l, r) = (string_expr(de, hex, l), string_expr(de, hex, r));
+      app!(de, sadd[l, r])
+    }
+  }
+}
+
 pub(crate) fn render_proof(
   pd: &Predefs, elab: &mut Elaborator, sp: Span,
   name: AtomId, proof: &ElfProof<'_>
@@ -300,6 +314,13 @@ pub(crate) fn render_proof(
   let fsp = elab.fspan(sp);
   let mut proc_asm = HashMap::new();
   assembler::assemble_proof(&mut elab.env, pd, &mut proc_asm, &mangler, proof, &fsp, sp)?;
+  // `def name: string`: the complete ELF file, including the headers around `content`
+  elab.env.add_term({
+    let mut de = ExprDedup::new(pd, &[]);
+    let hex = HexCache::new(&mut de);
+    let e = string_expr(&mut de, &hex, proof);
+    de.build_def0(name, Modifiers::empty(), fsp.clone(), sp, e, pd.string)
+  }).map_err(|e| e.into_elab_error(sp))?;
   elab.report(ElabError::info(sp, format!("{:#?}", proof)));
   Ok(())
 }
@@ -353,7 +353,7 @@ impl<'a> Importer<'a> {
           let ip = self.proof(&mut de, &vars, &mut proofs, ProofKind::Proof)?;
           let (mut ids, heap) = build(&de);
           let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-          ThmKind::Thm(Some(Proof {heap, hyps, head: ids[ip].take()}))
+          ThmKind::Thm(Some(Proof {heap, hyps, head: ids[ip].take(), spans: Box::new([])}))
         };
         let end = self.close_err()?;
         self.env.add_thm(Thm {
@@ -490,6 +490,8 @@ impl<'a> Importer<'a> {
 
 /// Construct an [`Environment`] from an `mmu` file.
 pub fn elab(file: &FileRef, source: &[u8]) -> (Result<()>, Environment) {
-  let mut p = Importer { file, source, idx: 0, env: Environment::new() };
+  let mut env = Environment::new();
+  env.do_cache = crate::elab::do_cache::DoCache::from_source(source);
+  let mut p = Importer { file, source, idx: 0, env };
   (p.run(), p.env)
 }
\ No newline at end of file
@@ -0,0 +1,270 @@
+//! Library packages, made by `mm0-rs package` and downloaded by `mm0-rs fetch`.
+//!
+//! A package is a `.tar` archive of a project (see [`crate::project`]). It contains
+//! the manifest, the `.mm0` and `.mm1` files in the source roots, the outputs of the
+//! targets (so that users of the library do not need to rebuild it), and a file
+//! [`CONTENTS`] listing the SHA-256 hash of every other file in the archive.
+//!
+//! A project uses a package by listing it in its dependencies with a `url`:
+//!
+//! ```toml
+//! [dependencies]
+//! peano = { url = "https://example.org/peano-0.1.0.tar", hash = "3b2c..." }
+//! ```
+//!
+//! The `hash` is the SHA-256 hash of the archive, which is printed by `mm0-rs package`,
+//! and it is required. `mm0-rs fetch` downloads the archives of all such dependencies
+//! (and their own dependencies), checks them against the given `hash` and the
+//! [`CONTENTS`] file, which must list every file in the archive, and unpacks them into
+//! the package cache, where they are found by `mm0-rs build` and the
+//! language server. The cache is the directory `$MM0_CACHE` if it is set, and otherwise
+//! `$XDG_CACHE_HOME/mm0` or `~/.cache/mm0`.
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::fs;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use crate::{cache_root, content_hash as hash};
+use crate::project::{Project, MANIFEST};
+
+/// The name of the file in a package that lists the hashes of the other files.
+pub const CONTENTS: &str = "mm0-package.toml";
+
+/// The contents of the [`CONTENTS`] file of a package.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Contents {
+  /// The name of the package.
+  pub name: String,
+  /// The version of the package.
+  pub version: String,
+  /// The files in the package, relative to the root of the archive, with their
+  /// SHA-256 hashes in hex.
+  pub files: BTreeMap<String, String>,
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// The directory in the package cache for dependency `name`, whose archive has hash `hash`.
+/// It is keyed by the hash, so that changing it causes the package to be fetched again.
+/// Both come from the manifest, so a name that is not a single path component or a hash
+/// that is not in hex is an error, instead of a path outside of the cache.
+pub fn cache_dir(name: &str, hash: &str) -> io::Result<PathBuf> {
+  let err = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+  if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+    return Err(err(format!("invalid dependency name '{}'", name)))
+  }
+  if hash.is_empty() || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+    return Err(err(format!("dependency {}: 'hash' must be a SHA-256 hash in hex", name)))
+  }
+  let key = hash.to_ascii_lowercase();
+  Ok(cache_root()?.join(format!("{}-{}", name, key.chars().take(16).collect::<String>())))
+}
+
+/// Add the files with extension `.mm0` or `.mm1` in `dir` and its subdirectories to `out`.
+pub(crate) fn sources(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.is_dir() {
+      sources(&path, out)?
+    } else if path.extension().map_or(false, |e| e == "mm0" || e == "mm1") {
+      out.push(path)
+    }
+  }
+  Ok(())
+}
+
+fn no_package() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, "the manifest needs a [package] section to make a package")
+}
+
+/// Build all targets of `project` and write a package archive to `out`.
+/// Returns the hash of the archive, or `None` if a target failed to build.
+pub fn package(project: &Project, out: &Path) -> io::Result<Option<String>> {
+  let pkg = project.manifest.package.as_ref().ok_or_else(no_package)?;
+  if !project.build_all(&project.manifest.targets)?.is_empty() { return Ok(None) }
+  let mut files = vec![project.path(MANIFEST.as_ref())];
+  for root in project.roots() { sources(&root, &mut files)? }
+  for t in &project.manifest.targets {
+    files.extend([&t.mmb, &t.mmu, &t.mm0].into_iter().flatten().map(|p| project.path(p)))
+  }
+  files.sort();
+  files.dedup();
+  let mut contents = Contents {
+    name: pkg.name.clone(),
+    version: pkg.version.clone(),
+    files: BTreeMap::new(),
+  };
+  let mut tar = tar::Builder::new(vec![]);
+  for file in files {
+    let name = file.strip_prefix(&project.dir).map_err(|_| io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("{} is outside of the project directory", file.display())))?;
+    let name = name.to_str().ok_or_else(|| invalid("file name is not valid UTF-8"))?
+      .replace('\\', "/");
+    contents.files.insert(name.clone(), hash(&fs::read(&file)?));
+    tar.append_path_with_name(&file, name)?;
+  }
+  let contents = toml::to_string(&contents).map_err(invalid)?;
+  let mut header = tar::Header::new_gnu();
+  header.set_size(contents.len() as u64);
+  header.set_mode(0o644);
+  header.set_cksum();
+  tar.append_data(&mut header, CONTENTS, contents.as_bytes())?;
+  let data = tar.into_inner()?;
+  fs::write(out, &data)?;
+  Ok(Some(hash(&data)))
+}
+
+/// Read the archive at `url`, which is either an `http(s)` URL, a `file` URL, or a path
+/// relative to `dir`.
+fn download(dir: &Path, url: &str) -> io::Result<Vec<u8>> {
+  if url.starts_with("http://") || url.starts_with("https://") {
+    let resp = ureq::get(url).call().map_err(|e|
+      io::Error::new(io::ErrorKind::Other, format!("could not download {}: {}", url, e)))?;
+    let mut data = vec![];
+    resp.into_reader().read_to_end(&mut data)?;
+    Ok(data)
+  } else {
+    fs::read(dir.join(url.strip_prefix("file://").unwrap_or(url)))
+  }
+}
+
+/// The path of an entry of a package archive, as it is listed in [`CONTENTS`].
+fn entry_name(entry: &tar::Entry<'_, &[u8]>) -> io::Result<String> {
+  Ok(entry.path()?.to_str().ok_or_else(|| invalid("file name is not valid UTF-8"))?
+    .replace('\\', "/"))
+}
+
+/// Unpack the package `data` into `dest`. Every file in the archive other than
+/// [`CONTENTS`] must be a regular file listed in [`CONTENTS`] with the right hash,
+/// and every listed file must be present.
+fn unpack(data: &[u8], dest: &Path) -> io::Result<()> {
+  let mut contents = None;
+  for entry in tar::Archive::new(data).entries()? {
+    let mut entry = entry?;
+    if entry_name(&entry)? == CONTENTS {
+      let mut s = String::new();
+      entry.read_to_string(&mut s)?;
+      contents = Some(toml::from_str::<Contents>(&s).map_err(invalid)?);
+    }
+  }
+  let contents = contents.ok_or_else(|| invalid(format!("{} is missing", CONTENTS)))?;
+  let mut tmp = dest.as_os_str().to_owned();
+  tmp.push(".tmp");
+  let tmp = PathBuf::from(tmp);
+  if tmp.exists() { fs::remove_dir_all(&tmp)? }
+  fs::create_dir_all(&tmp)?;
+  let mut seen = HashSet::new();
+  for entry in tar::Archive::new(data).entries()? {
+    let mut entry = entry?;
+    let name = entry_name(&entry)?;
+    if name == CONTENTS { continue }
+    let expected = contents.files.get(&name).ok_or_else(|| invalid(format!(
+      "{} in package {} is not listed in {}", name, contents.name, CONTENTS)))?;
+    if !entry.header().entry_type().is_file() {
+      return Err(invalid(format!("{} in package {} is not a regular file", name, contents.name)))
+    }
+    let mut buf = vec![];
+    entry.read_to_end(&mut buf)?;
+    if hash(&buf) != *expected {
+      return Err(invalid(format!("hash mismatch for {} in package {}", name, contents.name)))
+    }
+    let path = tmp.join(&name);
+    if !path.starts_with(&tmp) || name.split('/').any(|c| c == "..") {
+      return Err(invalid(format!("{} in package {} is outside of the package", name, contents.name)))
+    }
+    if let Some(dir) = path.parent() { fs::create_dir_all(dir)? }
+    fs::write(path, buf)?;
+    seen.insert(name);
+  }
+  if let Some(name) = contents.files.keys().find(|name| !seen.contains(*name)) {
+    return Err(invalid(format!("{} in package {} is missing", name, contents.name)))
+  }
+  fs::write(tmp.join(CONTENTS), toml::to_string(&contents).map_err(invalid)?)?;
+  fs::rename(&tmp, dest)
+}
+
+/// Fetch the packaged dependencies of `project`, and their dependencies, into the cache.
+/// Packages that are already in the cache are not downloaded again, unless `force` is set.
+pub fn fetch(project: &Project, force: bool) -> io::Result<()> {
+  fetch_in(project, force, &mut HashSet::new())
+}
+
+fn fetch_in(project: &Project, force: bool, done: &mut HashSet<PathBuf>) -> io::Result<()> {
+  if !done.insert(project.dir.clone()) { return Ok(()) }
+  for (name, dep) in &project.manifest.dependencies {
+    let dir = match &dep.url {
+      None => match &dep.path {
+        Some(path) if project.path(path).join(MANIFEST).is_file() => project.path(path),
+        _ => continue,
+      },
+      Some(url) => {
+        let expected = dep.hash.as_deref().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+          format!("dependency {}: 'hash' is required with 'url'", name)))?;
+        let dest = cache_dir(name, expected)?;
+        if force && dest.exists() { fs::remove_dir_all(&dest)? }
+        if !dest.exists() {
+          println!("fetching {} from {}", name, url);
+          let data = download(&project.dir, url)?;
+          let actual = hash(&data);
+          if !actual.eq_ignore_ascii_case(expected) {
+            return Err(invalid(format!(
+              "dependency {}: hash mismatch (expected {}, got {})", name, expected, actual)))
+          }
+          unpack(&data, &dest)
+            .map_err(|e| io::Error::new(e.kind(), format!("dependency {}: {}", name, e)))?;
+        }
+        dest
+      }
+    };
+    if dir.join(MANIFEST).is_file() {
+      fetch_in(&Project::load(&dir.join(MANIFEST))?, force, done)?
+    }
+  }
+  Ok(())
+}
+
+/// Main entry point for `mm0-rs package` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs package [--manifest mm0.toml] [-o out.tar]`, where:
+///
+/// - `mm0.toml` is the project manifest. If omitted, it is searched for in the current
+///   directory and its ancestors.
+/// - `out.tar` is the archive to write, by default `NAME-VERSION.tar` in the project
+///   directory.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let project = Project::from_args(args)?;
+  project.configure()?;
+  let pkg = project.manifest.package.as_ref().ok_or_else(no_package)?;
+  let out = match args.value_of("output") {
+    Some(out) => PathBuf::from(out),
+    None => project.path(format!("{}-{}.tar", pkg.name, pkg.version).as_ref()),
+  };
+  match package(&project, &out)? {
+    Some(hash) => println!("wrote {}\nsha256: {}", out.display(), hash),
+    None => {
+      println!("failed to build the package");
+      std::process::exit(1)
+    }
+  }
+  Ok(())
+}
+
+/// Main entry point for `mm0-rs fetch` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs fetch [--manifest mm0.toml] [--force]`, where:
+///
+/// - `mm0.toml` is the project manifest. If omitted, it is searched for in the current
+///   directory and its ancestors.
+/// - `--force` downloads the packages again even if they are already in the cache.
+pub fn fetch_main(args: &ArgMatches<'_>) -> io::Result<()> {
+  fetch(&Project::from_args(args)?, args.is_present("force"))
+}
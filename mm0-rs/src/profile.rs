@@ -0,0 +1,91 @@
+//! Per-declaration timing, and regression tracking against a stored baseline.
+//!
+//! This module implements the `mm0-rs profile` subcommand. It elaborates a file (and its
+//! imports) while recording the time spent on each declaration, and writes the results as a
+//! JSON object mapping declaration names to times in milliseconds. Given a previous run as
+//! a baseline, it reports declarations that have become slower, which is useful for catching
+//! accidental tactic blowups in CI.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{fs, io};
+use clap::ArgMatches;
+use crate::compiler::elab_for_result;
+use crate::{FileRef, MutexExt};
+
+lazy_static! {
+  /// The collected timings, or `None` if profiling is disabled.
+  static ref TIMINGS: Mutex<Option<Vec<(String, Duration)>>> = Mutex::new(None);
+}
+
+/// Returns true if declaration timings should be recorded.
+pub(crate) fn enabled() -> bool { TIMINGS.ulock().is_some() }
+
+/// Record the time taken to elaborate a declaration.
+pub(crate) fn record(name: &[u8], time: Duration) {
+  if let Some(v) = &mut *TIMINGS.ulock() {
+    v.push((String::from_utf8_lossy(name).into(), time))
+  }
+}
+
+fn parse_percent(s: &str) -> io::Result<f64> {
+  s.trim_end_matches('%').parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+    format!("--fail-on-regression: expected a percentage, got '{}'", s)))
+}
+
+/// Main entry point for `mm0-rs profile` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs profile <in.mm1> [-o out.json] [--baseline old.json] [--fail-on-regression N%]
+/// [--min-time MS]`, where:
+///
+/// - `in.mm1` is the file to elaborate. All declarations in its import graph are timed.
+/// - `out.json` is the file to write the timings to, which can be used as a baseline
+///   for later runs. If omitted, the slowest declarations are printed instead.
+/// - `old.json` is a previous output of `mm0-rs profile`. Every declaration that became
+///   slower by more than `N` percent (default 20%) is reported, and if
+///   `--fail-on-regression` is given, the command fails.
+/// - `MS` (default 10) is the baseline time below which declarations are too fast to
+///   be compared reliably, and are skipped.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  *TIMINGS.ulock() = Some(vec![]);
+  if elab_for_result(path)?.1.is_none() { std::process::exit(1) }
+  let timings = TIMINGS.ulock().take().expect("profiling enabled");
+  let timings = timings.into_iter()
+    .map(|(name, d)| (name, d.as_secs_f64() * 1000.))
+    .collect::<BTreeMap<_, _>>();
+
+  if let Some(out) = args.value_of("output") {
+    serde_json::to_writer_pretty(io::BufWriter::new(fs::File::create(out)?), &timings)?;
+  } else if !args.is_present("baseline") {
+    let mut slowest = timings.iter().collect::<Vec<_>>();
+    slowest.sort_by(|a, b| b.1.total_cmp(a.1));
+    for (name, t) in slowest.into_iter().take(20) { println!("{:10.2}ms  {}", t, name) }
+  }
+
+  if let Some(baseline) = args.value_of("baseline") {
+    let old: BTreeMap<String, f64> =
+      serde_json::from_reader(io::BufReader::new(fs::File::open(baseline)?))?;
+    let threshold = args.value_of("fail_on_regression").map_or(Ok(20.), parse_percent)?;
+    let min_time = args.value_of("min_time").map_or(Ok(10.), str::parse).map_err(|_|
+      io::Error::new(io::ErrorKind::InvalidInput, "--min-time: expected a number of milliseconds"))?;
+    let mut regressions = 0;
+    for (name, &t) in &timings {
+      let t0 = if let Some(&t0) = old.get(name) {t0} else {continue};
+      if t0 < min_time { continue }
+      let pct = (t - t0) / t0 * 100.;
+      if pct > threshold {
+        println!("{}: {:.2}ms -> {:.2}ms (+{:.0}%)", name, t0, t, pct);
+        regressions += 1;
+      }
+    }
+    if regressions != 0 {
+      println!("{} declarations regressed by more than {}%", regressions, threshold);
+      if args.is_present("fail_on_regression") { std::process::exit(1) }
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,410 @@
+//! Project manifests (`mm0.toml`).
+//!
+//! A project manifest collects the settings of a multi-file development, so that they do not
+//! have to be passed on the command line every time. It is read by the `mm0-rs build`
+//! subcommand, which builds the targets listed in the manifest, and by the language server,
+//! which uses the import path and flags of the project containing the workspace. The
+//! manifest is found by searching the current directory and its ancestors. For example:
+//!
+//! ```toml
+//! # Directories containing the sources of the project. Files in these directories can be
+//! # imported by name from any file. The default is the directory of the manifest.
+//! roots = ["src"]
+//! # More directories to search for imported files.
+//! import-path = ["vendor"]
+//! # A file that is imported implicitly at the start of every .mm1 file (see `set_prelude`).
+//! prelude = "src/prelude.mm1"
+//!
+//! # The default flags for all targets.
+//! [flags]
+//! timeout = 10000
+//!
+//! # The name and version of the project, used by `mm0-rs package`.
+//! [package]
+//! name = "set"
+//! version = "0.1.0"
+//!
+//! [dependencies]
+//! # Another project, whose import path is added to the import path of this one.
+//! peano = { path = "../peano" }
+//! # A packaged library, which is downloaded by `mm0-rs fetch` (see [`crate::package`]).
+//! logic = { url = "https://example.org/logic-0.1.0.tar", hash = "3b2c..." }
+//! # A third-party tactic library, whose lisp code can only read the environment.
+//! tactics = { path = "../tactics", capability = "env-read" }
+//!
+//! [[target]]
+//! name = "main"
+//! input = "src/main.mm1"
+//! mmb = "out/main.mmb"
+//! doc = "out/doc"
+//! flags = { warnings-as-errors = true }
+//! ```
+//!
+//! All paths are relative to the directory containing the manifest.
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{fs, io};
+use clap::ArgMatches;
+use serde::Deserialize;
+use crate::{FileRef, FrozenEnv, MutexExt};
+use crate::compiler::{ElabCache, FileContents};
+
+/// The file name of a project manifest.
+pub const MANIFEST: &str = "mm0.toml";
+
+/// The contents of a project manifest. See the [module documentation](self) for the format.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Manifest {
+  /// The name and version of the project, if it is a library.
+  pub package: Option<Package>,
+  /// The directories containing the sources of the project.
+  pub roots: Vec<PathBuf>,
+  /// Additional directories to search for imported files.
+  pub import_path: Vec<PathBuf>,
+  /// The prelude of the project, see [`set_prelude`](crate::set_prelude).
+  pub prelude: Option<PathBuf>,
+  /// The default flags for all targets.
+  pub flags: Flags,
+  /// The libraries this project depends on, by name.
+  pub dependencies: BTreeMap<String, Dependency>,
+  /// The build targets.
+  #[serde(rename = "target")]
+  pub targets: Vec<Target>,
+}
+
+/// The `[package]` section of a manifest.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+  /// The name of the library.
+  pub name: String,
+  /// The version of the library.
+  pub version: String,
+}
+
+/// A library that a project depends on. Exactly one of `path` and `url` should be given.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dependency {
+  /// The directory of the library. If it contains a manifest, the import path of that
+  /// project is used, otherwise the directory itself is added to the import path.
+  pub path: Option<PathBuf>,
+  /// The location of a package archive made by `mm0-rs package`. This can be a URL or
+  /// a path relative to the manifest.
+  pub url: Option<String>,
+  /// The SHA-256 hash of the archive, in hex, which is required with `url`. The download
+  /// is checked against it.
+  pub hash: Option<String>,
+  /// The capability of the lisp code in the library (see [`crate::set_path_capability`]).
+  pub capability: Option<String>,
+}
+
+impl Dependency {
+  /// The directory containing dependency `name` of the project in directory `dir`.
+  /// For a packaged dependency, this is its directory in the package cache, which is
+  /// an error if it has not been fetched yet.
+  pub fn dir(&self, dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let err = |e: String| io::Error::new(io::ErrorKind::InvalidInput, format!("dependency {}: {}", name, e));
+    match (&self.path, &self.url) {
+      (Some(path), None) =>
+        fs::canonicalize(dir.join(path)).map_err(|e| io::Error::new(e.kind(), format!("dependency {}: {}", name, e))),
+      (None, Some(_)) => {
+        let hash = self.hash.as_deref().ok_or_else(|| err("'hash' is required with 'url'".into()))?;
+        let cache = crate::package::cache_dir(name, hash)?;
+        if cache.is_dir() { return Ok(cache) }
+        Err(io::Error::new(io::ErrorKind::NotFound,
+          format!("dependency {} has not been fetched; run `mm0-rs fetch`", name)))
+      }
+      (None, None) => Err(err("one of 'path' or 'url' is required".into())),
+      (Some(_), Some(_)) => Err(err("'path' and 'url' cannot both be given".into())),
+    }
+  }
+}
+
+/// Elaboration flags, which can be set for the whole project or for each target.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Flags {
+  /// The initial lisp evaluation timeout in milliseconds, or 0 for no timeout (default 5000).
+  pub timeout: Option<u64>,
+  /// Fail the target if there are any warnings.
+  pub warnings_as_errors: Option<bool>,
+  /// Check proofs (default true). This is the opposite of the `--no-proofs` option.
+  pub check_proofs: Option<bool>,
+  /// The initial policy for inferred dependencies: `infer`, `warn` or `strict`.
+  pub deps: Option<String>,
+}
+
+impl Flags {
+  /// The default value of all flags.
+  #[must_use] pub fn defaults() -> Flags {
+    Flags {
+      timeout: Some(5000),
+      warnings_as_errors: Some(false),
+      check_proofs: Some(true),
+      deps: Some("infer".into()),
+    }
+  }
+
+  /// The flags in `self`, with the unset ones taken from `base`.
+  #[must_use] pub fn or(&self, base: &Flags) -> Flags {
+    Flags {
+      timeout: self.timeout.or(base.timeout),
+      warnings_as_errors: self.warnings_as_errors.or(base.warnings_as_errors),
+      check_proofs: self.check_proofs.or(base.check_proofs),
+      deps: self.deps.clone().or_else(|| base.deps.clone()),
+    }
+  }
+
+  /// Set the global elaboration options according to these flags. Unset flags are left alone.
+  pub fn apply(&self) -> io::Result<()> {
+    if let Some(t) = self.timeout {
+      crate::set_timeout(if t == 0 {None} else {Some(Duration::from_millis(t))})
+    }
+    if let Some(b) = self.check_proofs { crate::set_check_proofs(b) }
+    if let Some(p) = &self.deps {
+      crate::set_dep_policy(p.parse().map_err(|e: String|
+        io::Error::new(io::ErrorKind::InvalidInput, e))?)
+    }
+    Ok(())
+  }
+}
+
+/// A build target.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Target {
+  /// The name of the target, used to select it on the command line.
+  pub name: String,
+  /// The file to elaborate.
+  pub input: PathBuf,
+  /// Write the result as an MMB file.
+  pub mmb: Option<PathBuf>,
+  /// Write the result as an MMU file.
+  pub mmu: Option<PathBuf>,
+  /// Write the input, joined with its imports, to a single file (see `mm0-rs join`).
+  /// This is normally used with an `.mm0` input.
+  pub mm0: Option<PathBuf>,
+  /// Write the documentation pages to this folder (see `mm0-rs doc`).
+  pub doc: Option<PathBuf>,
+  /// The flags for this target, which override the project flags.
+  #[serde(default)]
+  pub flags: Flags,
+}
+
+/// A project, that is, a manifest and the directory that contains it.
+#[derive(Debug)]
+pub struct Project {
+  /// The (canonical) directory containing the manifest.
+  pub dir: PathBuf,
+  /// The manifest.
+  pub manifest: Manifest,
+  /// The flags that the currently elaborated files were elaborated with, and the
+  /// elaboration results for the other flags that have been used.
+  caches: Mutex<(Option<Flags>, Vec<(Flags, ElabCache)>)>,
+}
+
+impl Project {
+  /// Load the manifest at `path`.
+  pub fn load(path: &Path) -> io::Result<Project> {
+    let manifest = toml::from_str(&fs::read_to_string(path)?).map_err(|e|
+      io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    let dir = fs::canonicalize(path)?.parent().map_or_else(PathBuf::new, Path::to_owned);
+    Ok(Project {dir, manifest, caches: Default::default()})
+  }
+
+  /// Load the manifest given by the `--manifest` argument, or else the one found from the
+  /// current directory.
+  pub(crate) fn from_args(args: &ArgMatches<'_>) -> io::Result<Project> {
+    match args.value_of("manifest") {
+      Some(path) => Project::load(path.as_ref()),
+      None => Project::find(".".as_ref())?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+        format!("could not find {} in this directory or any parent", MANIFEST))),
+    }
+  }
+
+  /// Find the manifest in directory `start` or the closest of its ancestors, and load it.
+  /// Returns `None` if there is no manifest.
+  pub fn find(start: &Path) -> io::Result<Option<Project>> {
+    for dir in fs::canonicalize(start)?.ancestors() {
+      let path = dir.join(MANIFEST);
+      if path.is_file() { return Self::load(&path).map(Some) }
+    }
+    Ok(None)
+  }
+
+  pub(crate) fn path(&self, p: &Path) -> PathBuf { self.dir.join(p) }
+
+  /// The source roots of the project.
+  #[must_use] pub fn roots(&self) -> Vec<PathBuf> {
+    if self.manifest.roots.is_empty() { return vec![self.dir.clone()] }
+    self.manifest.roots.iter().map(|p| self.path(p)).collect()
+  }
+
+  /// The directories to search for imported files: the source roots, the import path, and
+  /// the import paths of the dependencies.
+  pub fn import_path(&self) -> io::Result<Vec<PathBuf>> {
+    fn go(p: &Project, done: &mut HashSet<PathBuf>, out: &mut Vec<PathBuf>) -> io::Result<()> {
+      if !done.insert(p.dir.clone()) { return Ok(()) }
+      out.extend(p.roots());
+      out.extend(p.manifest.import_path.iter().map(|d| p.path(d)));
+      for (name, dep) in &p.manifest.dependencies {
+        let dir = dep.dir(&p.dir, name)?;
+        if dir.join(MANIFEST).is_file() {
+          go(&Project::load(&dir.join(MANIFEST))?, done, out)?
+        } else {
+          out.push(dir)
+        }
+      }
+      Ok(())
+    }
+    let mut out = vec![];
+    go(self, &mut HashSet::new(), &mut out)?;
+    Ok(out)
+  }
+
+  /// Set the global import path and elaboration flags for this project.
+  pub fn configure(&self) -> io::Result<()> {
+    crate::set_import_path(self.import_path()?);
+    if let Some(p) = &self.manifest.prelude {
+      crate::set_prelude(Some(fs::canonicalize(self.path(p))?.into()))
+    }
+    for (name, dep) in &self.manifest.dependencies {
+      if let Some(cap) = &dep.capability {
+        crate::set_path_capability(dep.dir(&self.dir, name)?, cap.parse().map_err(|e: String|
+          io::Error::new(io::ErrorKind::InvalidInput, format!("dependency {}: {}", name, e)))?)
+      }
+    }
+    self.manifest.flags.apply()
+  }
+
+  /// Get the path to output file `p`, creating its directory if needed.
+  pub(crate) fn output(&self, p: &Path) -> io::Result<PathBuf> {
+    let p = self.path(p);
+    if let Some(dir) = p.parent() { fs::create_dir_all(dir)? }
+    Ok(p)
+  }
+
+  /// Make the elaboration results for `flags` current, saving the current ones.
+  fn use_flags(&self, flags: &Flags) {
+    let mut g = self.caches.ulock();
+    let (current, saved) = &mut *g;
+    if current.as_ref() == Some(flags) { return }
+    let cache = match saved.iter().position(|(f, _)| f == flags) {
+      Some(i) => saved.swap_remove(i).1,
+      None => ElabCache::default(),
+    };
+    let old = crate::compiler::swap_elaborated(cache);
+    if let Some(f) = current.replace(flags.clone()) { saved.push((f, old)) }
+  }
+
+  /// Elaborate target `t`. Returns `None` if the target failed because of errors in the input.
+  ///
+  /// Files that are shared between targets with the same flags are elaborated only once.
+  pub(crate) fn elab(&self, t: &Target) -> io::Result<Option<Elaborated>> {
+    let flags = t.flags.or(&self.manifest.flags).or(&Flags::defaults());
+    self.use_flags(&flags);
+    flags.apply()?;
+    println!("building {}", t.name);
+    let path: FileRef = fs::canonicalize(self.path(&t.input))?.into();
+    let (file, env) = crate::compiler::elab_for_result(path.clone())?;
+    let (errors, warnings) = crate::compiler::diagnostic_counts(&path);
+    match env {
+      Some(env) if errors == 0 && (warnings == 0 || flags.warnings_as_errors != Some(true)) =>
+        Ok(Some(Elaborated {path, file, env})),
+      _ => {
+        println!("{}: {} errors, {} warnings", t.name, errors, warnings);
+        Ok(None)
+      }
+    }
+  }
+
+  /// Write the outputs of target `t`, which has been elaborated to `el`.
+  ///
+  /// This only reads the frozen environment, so it can run on another thread
+  /// while later targets are elaborated.
+  pub(crate) fn write(&self, t: &Target, el: &Elaborated) -> io::Result<()> {
+    let Elaborated {path, file, env} = el;
+    if let Some(out) = &t.mmb { crate::compiler::export(path.clone(), file, env, &self.output(out)?, false)? }
+    if let Some(out) = &t.mmu { crate::compiler::export(path.clone(), file, env, &self.output(out)?, true)? }
+    if let Some(out) = &t.mm0 {
+      crate::joiner::join_with_header(true, true, fs::File::create(self.output(out)?)?, path.clone())?
+    }
+    if let Some(out) = &t.doc {
+      #[cfg(feature = "doc")]
+      crate::doc::build(path, file, env, self.path(out), crate::doc::ProofOrder::Post, None, None)?;
+      #[cfg(not(feature = "doc"))]
+      println!("{}: skipping {}, mm0-rs was built without the doc feature", t.name, out.display());
+    }
+    Ok(())
+  }
+
+  /// Build target `t`. Returns false if the target failed because of errors in the input.
+  pub fn build(&self, t: &Target) -> io::Result<bool> {
+    match self.elab(t)? {
+      Some(el) => { self.write(t, &el)?; Ok(true) }
+      None => Ok(false)
+    }
+  }
+
+  /// Build the targets `ts`, and return the names of the ones that failed because of
+  /// errors in the input.
+  ///
+  /// Elaboration is sequential, because the flags of a target are global settings, but
+  /// the outputs of each target are written on a separate thread while the following
+  /// targets are elaborated.
+  pub fn build_all<'a>(&self, ts: impl IntoIterator<Item=&'a Target>) -> io::Result<Vec<&'a str>> {
+    std::thread::scope(|scope| {
+      let mut failed = vec![];
+      let mut writers = vec![];
+      for t in ts {
+        match self.elab(t)? {
+          Some(el) => writers.push(scope.spawn(move || self.write(t, &el))),
+          None => failed.push(&*t.name),
+        }
+      }
+      for w in writers {
+        w.join().unwrap_or_else(|e| std::panic::resume_unwind(e))?
+      }
+      Ok(failed)
+    })
+  }
+}
+
+/// The result of elaborating a target, which is passed from [`Project::elab`] to
+/// [`Project::write`].
+pub(crate) struct Elaborated {
+  path: FileRef,
+  file: FileContents,
+  env: FrozenEnv,
+}
+
+/// Main entry point for `mm0-rs build` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs build [--manifest mm0.toml] [TARGET...]`, where:
+///
+/// - `mm0.toml` is the project manifest. If omitted, it is searched for in the current
+///   directory and its ancestors.
+/// - `TARGET` are the names of the targets to build, or all targets if omitted.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let project = Project::from_args(args)?;
+  project.configure()?;
+  let names = args.values_of("TARGET").map(Iterator::collect::<Vec<_>>);
+  for &name in names.iter().flatten() {
+    if !project.manifest.targets.iter().any(|t| t.name == name) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown target '{}'", name)))
+    }
+  }
+  let failed = project.build_all(project.manifest.targets.iter()
+    .filter(|t| names.as_ref().map_or(true, |names| names.contains(&&*t.name))))?;
+  if !failed.is_empty() {
+    println!("failed to build {}", failed.join(", "));
+    std::process::exit(1)
+  }
+  Ok(())
+}
@@ -0,0 +1,263 @@
+//! Proof tree visualization.
+//!
+//! This module renders the elaborated proof of a theorem (see [`ProofNode`]) as an indented
+//! outline or as a graph in the DOT language of Graphviz. Only the proof and conversion steps
+//! are shown as nodes, each labeled with the theorem or conversion rule that it applies and the
+//! statement that it proves; expressions only appear inside these statements. A step that is
+//! used more than once is stored on the proof heap, and it is shown only once, under its heap
+//! index `#n`, and referred to by this index everywhere else. This module implements the
+//! `mm0-rs proof-tree` subcommand and the `mm0.proofTree` command of the language server.
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::{fs, io};
+use clap::ArgMatches;
+use crate::compiler::elab_for_result;
+use crate::{AtomId, DeclKey, FileRef, FormatEnv, LinedString, LispVal, ProofNode, Thm, ThmId, ThmKind};
+
+/// The width used for pretty printing statements. It is large so that every statement
+/// fits on one line.
+const WIDTH: usize = 1000;
+
+/// The output format of [`render`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+  /// An indented outline, with one line per step.
+  Outline,
+  /// A directed graph in the DOT language, with an edge from each step to its subproofs.
+  Dot,
+}
+
+impl std::str::FromStr for Format {
+  type Err = String;
+  /// Parse a format name, one of `outline` or `dot`.
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s {
+      "outline" => Ok(Self::Outline),
+      "dot" => Ok(Self::Dot),
+      _ => Err(format!("unknown proof tree format '{}', expected outline or dot", s)),
+    }
+  }
+}
+
+/// A short description of a proof step, usually the name of the theorem being applied.
+/// `td` is the theorem whose proof contains the step.
+#[must_use] pub fn label(fe: FormatEnv<'_>, td: &Thm, p: &ProofNode) -> String {
+  let name = |a: AtomId| fe.data[a].name.to_string();
+  match *p {
+    ProofNode::Ref(_) => "ref".into(),
+    ProofNode::Dummy(a, _) => name(a),
+    ProofNode::Term {term, ..} => name(fe.terms[term].atom),
+    ProofNode::Hyp(i, _) => td.hyps[i].0.map_or_else(|| format!("hyp {}", i), name),
+    ProofNode::Thm {thm, ..} => name(fe.thms[thm].atom),
+    ProofNode::Conv(_) => "conv".into(),
+    ProofNode::Refl(_) => "refl".into(),
+    ProofNode::Sym(_) => "sym".into(),
+    ProofNode::Cong {term, ..} => format!("cong {}", name(fe.terms[term].atom)),
+    ProofNode::Unfold {term, ..} => format!("unfold {}", name(fe.terms[term].atom)),
+  }
+}
+
+/// The type of a proof node.
+#[derive(Clone)]
+enum Stmt {
+  /// An expression, which is its own type
+  Expr(LispVal),
+  /// A proof of `|- e`
+  Proof(LispVal),
+  /// A proof of `e1 = e2`
+  Conv(LispVal, LispVal),
+}
+
+impl Stmt {
+  fn into_expr(self) -> LispVal {
+    if let Stmt::Expr(e) = self {e} else {panic!("bad proof")}
+  }
+  fn into_conv(self) -> (LispVal, LispVal) {
+    if let Stmt::Conv(e1, e2) = self {(e1, e2)} else {panic!("bad proof")}
+  }
+}
+
+/// The state of the rendering of a proof.
+struct ProofTree<'a> {
+  fe: FormatEnv<'a>,
+  td: &'a Thm,
+  heap: &'a [ProofNode],
+  /// The types of the heap elements, computed on demand.
+  stmts: Box<[Option<Stmt>]>,
+  /// The heap elements that have already been printed, with their DOT node numbers.
+  shown: HashMap<usize, usize>,
+  /// The number of DOT nodes so far.
+  nodes: usize,
+  out: String,
+}
+
+impl<'a> ProofTree<'a> {
+  fn stmt(&mut self, p: &ProofNode) -> Stmt {
+    match *p {
+      ProofNode::Ref(i) => {
+        if let Some(s) = &self.stmts[i] { return s.clone() }
+        let heap = self.heap;
+        let s = self.stmt(&heap[i]);
+        self.stmts[i].get_or_insert(s).clone()
+      }
+      ProofNode::Dummy(a, _) => Stmt::Expr(LispVal::atom(a)),
+      ProofNode::Term {term, ref args} => {
+        let mut out = vec![LispVal::atom(self.fe.terms[term].atom)];
+        out.extend(args.iter().map(|e| self.stmt(e).into_expr()));
+        Stmt::Expr(LispVal::list(out))
+      }
+      ProofNode::Hyp(_, ref e) | ProofNode::Thm {res: ref e, ..} => Stmt::Proof(self.stmt(e).into_expr()),
+      ProofNode::Conv(ref p) => Stmt::Proof(self.stmt(&p.0).into_expr()),
+      ProofNode::Refl(ref e) => { let e = self.stmt(e).into_expr(); Stmt::Conv(e.clone(), e) }
+      ProofNode::Sym(ref c) => { let (e1, e2) = self.stmt(c).into_conv(); Stmt::Conv(e2, e1) }
+      ProofNode::Cong {term, ref args} => {
+        let t = LispVal::atom(self.fe.terms[term].atom);
+        let (mut lhs, mut rhs) = (vec![t.clone()], vec![t]);
+        for c in &**args {
+          let (e1, e2) = self.stmt(c).into_conv();
+          lhs.push(e1);
+          rhs.push(e2);
+        }
+        Stmt::Conv(LispVal::list(lhs), LispVal::list(rhs))
+      }
+      ProofNode::Unfold {term, ref args, ref res} => {
+        let mut lhs = vec![LispVal::atom(self.fe.terms[term].atom)];
+        lhs.extend(args.iter().map(|e| self.stmt(e).into_expr()));
+        Stmt::Conv(LispVal::list(lhs), self.stmt(&res.1).into_conv().1)
+      }
+    }
+  }
+
+  /// The pretty printed type of a proof node.
+  fn statement(&mut self, p: &ProofNode) -> String {
+    match self.stmt(p) {
+      Stmt::Expr(e) => self.fe.pp(&e, WIDTH).to_string(),
+      Stmt::Proof(e) => format!("|- {}", self.fe.pp(&e, WIDTH)),
+      Stmt::Conv(e1, e2) => format!("{} = {}", self.fe.pp(&e1, WIDTH), self.fe.pp(&e2, WIDTH)),
+    }
+  }
+
+  /// The proof and conversion steps that step `p` depends on.
+  fn children(&self, p: &'a ProofNode) -> Vec<&'a ProofNode> {
+    match p {
+      ProofNode::Ref(_) | ProofNode::Dummy(..) | ProofNode::Term {..} |
+      ProofNode::Hyp(..) | ProofNode::Refl(_) => vec![],
+      &ProofNode::Thm {thm, ref args, ..} => args[self.fe.thms[thm].args.len()..].iter().collect(),
+      ProofNode::Conv(p) => vec![&p.1, &p.2],
+      ProofNode::Sym(c) => vec![&**c],
+      ProofNode::Cong {args, ..} => args.iter().collect(),
+      ProofNode::Unfold {res, ..} => vec![&res.1],
+    }
+  }
+
+  fn outline(&mut self, p: &'a ProofNode, depth: usize) {
+    let heap = self.heap;
+    let (p, i) = match *p {
+      ProofNode::Ref(i) => (&heap[i], Some(i)),
+      _ => (p, None),
+    };
+    let stmt = self.statement(p);
+    for _ in 0..depth { self.out.push_str("  ") }
+    if let Some(i) = i {
+      if self.shown.contains_key(&i) {
+        writeln!(self.out, "#{}: {}", i, stmt).expect("writing to a string");
+        return
+      }
+      self.shown.insert(i, 0);
+      write!(self.out, "#{} ", i).expect("writing to a string");
+    }
+    writeln!(self.out, "{}: {}", label(self.fe, self.td, p), stmt).expect("writing to a string");
+    for c in self.children(p) { self.outline(c, depth + 1) }
+  }
+
+  /// Print the DOT node for `p` and everything below it, and return its name.
+  fn dot(&mut self, p: &'a ProofNode) -> usize {
+    let heap = self.heap;
+    let (p, i) = match *p {
+      ProofNode::Ref(i) => (&heap[i], Some(i)),
+      _ => (p, None),
+    };
+    if let Some(&n) = i.and_then(|i| self.shown.get(&i)) { return n }
+    let n = self.nodes;
+    self.nodes += 1;
+    if let Some(i) = i { self.shown.insert(i, n); }
+    let mut text = label(self.fe, self.td, p);
+    if let Some(i) = i { write!(text, " #{}", i).expect("writing to a string") }
+    text.push('\n');
+    text.push_str(&self.statement(p));
+    write!(self.out, "  n{} [label=\"", n).expect("writing to a string");
+    for c in text.chars() {
+      match c {
+        '"' | '\\' => { self.out.push('\\'); self.out.push(c) }
+        '\n' => self.out.push_str("\\n"),
+        _ => self.out.push(c),
+      }
+    }
+    self.out.push_str("\"];\n");
+    for c in self.children(p) {
+      let m = self.dot(c);
+      writeln!(self.out, "  n{} -> n{};", n, m).expect("writing to a string");
+    }
+    n
+  }
+}
+
+/// Render the proof of theorem `tid` in the given format. Returns `None` if the theorem
+/// has no proof, because it is an axiom or its proof failed to elaborate.
+#[must_use] pub fn render(fe: FormatEnv<'_>, tid: ThmId, format: Format) -> Option<String> {
+  let td = &fe.thms[tid];
+  let proof = if let ThmKind::Thm(Some(proof)) = &td.kind {proof} else {return None};
+  let mut stmts = vec![None; proof.heap.len()].into_boxed_slice();
+  for (s, (a, _)) in stmts.iter_mut().zip(&*td.args) {
+    *s = Some(Stmt::Expr(LispVal::atom(a.unwrap_or(AtomId::UNDER))))
+  }
+  let mut tree = ProofTree {
+    fe, td, heap: &proof.heap, stmts, shown: HashMap::new(), nodes: 0, out: String::new()
+  };
+  match format {
+    Format::Outline => tree.outline(&proof.head, 0),
+    Format::Dot => {
+      writeln!(tree.out, "digraph \"{}\" {{", fe.data[td.atom].name).expect("writing to a string");
+      tree.out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+      tree.dot(&proof.head);
+      tree.out.push_str("}\n");
+    }
+  }
+  Some(tree.out)
+}
+
+/// Main entry point for `mm0-rs proof-tree` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs proof-tree <in.mm1> <THM> [--format outline|dot] [-o out]`, where:
+///
+/// - `in.mm1` is the file to elaborate.
+/// - `THM` is the name of the theorem whose proof is shown. It can be declared in `in.mm1`
+///   or in one of its imports.
+/// - `--format` selects an indented outline (the default) or a DOT graph, which can be
+///   rendered with Graphviz, for example using `dot -Tsvg`.
+/// - `out` is the file to write the result to, or stdout if omitted.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let name = args.value_of("THM").expect("required arg");
+  let format = args.value_of("format").map_or(Ok(Format::Outline), str::parse)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  let path: FileRef = fs::canonicalize(path)?.into();
+  let (file, env) = elab_for_result(path.clone())?;
+  let env = env.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+    format!("{}: elaboration failed", path)))?;
+  let tid = match env.get_atom(name.as_bytes()).and_then(|a| env.data()[a].decl()) {
+    Some(DeclKey::Thm(tid)) => tid,
+    _ => return Err(io::Error::new(io::ErrorKind::NotFound,
+      format!("theorem '{}' not found", name))),
+  };
+  let source = file.try_ascii().map_or_else(LinedString::default, |s| (**s).clone());
+  let fe = FormatEnv { source: &source, env: unsafe { env.thaw() } };
+  let out = render(fe, tid, format).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+    format!("'{}' has no proof", name)))?;
+  match args.value_of("output") {
+    Some(file) => fs::write(file, out),
+    None => { print!("{}", out); Ok(()) }
+  }
+}
@@ -0,0 +1,342 @@
+//! Whole-library refactoring operations.
+//!
+//! This module implements the `mm0-rs refactor` subcommand, which edits the source files of an
+//! entire import graph at once. The supported operations are:
+//!
+//! * `--rename old=new`: Rename the sort, term, theorem or lisp global `old` to `new`.
+//!   All references are located using the span information collected during elaboration,
+//!   so this covers the declaration itself, uses in math strings, and uses in lisp code
+//!   (including refine scripts). The companion `.mm0` file next to each `.mm1` file in the
+//!   import graph, if there is one, is renamed as well. It is an error if `old` is not
+//!   defined, or if `new` is already the name of a sort, declaration or lisp global.
+//! * `--move name=file`: Move the declaration of `name` to the end of `file`, which should
+//!   be another file in the import graph. The declarations it depends on that are not
+//!   visible from `file` (because they are declared in a file that `file` does not import)
+//!   are moved along with it, in order. Declarations in `.mm0` files are moved to the end of
+//!   the companion `.mm0` file of `file`. It is an error if a declaration that uses one of
+//!   the moved declarations would no longer see it, because it is declared earlier in `file`
+//!   or in a file that does not import `file`. Notations and coercions are not moved.
+//!
+//! If two edits to the same file overlap, nothing is written and an error is reported.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::{fs, io};
+use clap::ArgMatches;
+use crate::compiler::{elab_for_result, elaborated_files};
+use crate::elab::compare::Item;
+use crate::{AtomId, DeclKey, ExprNode, FileRef, FrozenEnv, LinedString, ObjectKind, ProofNode,
+  Span, TermKind, ThmKind};
+
+/// The pending edits to a single file, which are applied all at once at the end.
+#[derive(Default)]
+struct FileEdits {
+  /// Replacements, ordered by position. These must not overlap.
+  replace: BTreeSet<(usize, usize, Vec<u8>)>,
+  /// Text to append at the end of the file.
+  append: Vec<u8>,
+}
+
+fn is_ident_char(c: u8) -> bool { c.is_ascii_alphanumeric() || c == b'_' }
+
+/// Find the occurrence of the identifier `name` at the start of span `sp` in `text`
+/// (skipping an opening parenthesis, for applications like `(foo x y)`).
+fn find_ident(text: &[u8], sp: Span, name: &[u8]) -> Option<(usize, usize)> {
+  let mut start = sp.start;
+  if text.get(start) == Some(&b'(') { start += 1 }
+  let end = start + name.len();
+  if end > sp.end || text[start..end] != *name { return None }
+  if text.get(end).map_or(false, |&c| is_ident_char(c)) { return None }
+  Some((start, end))
+}
+
+/// Returns true if the span `k` is a reference to the global declaration named by `a`.
+fn refers_to(env: &FrozenEnv, a: AtomId, k: &ObjectKind) -> bool {
+  match *k {
+    ObjectKind::Sort(s) => env.sort(s).atom == a,
+    ObjectKind::Term(t, _) => env.term(t).atom == a,
+    ObjectKind::Thm(t) => env.thm(t).atom == a,
+    ObjectKind::Global(x) => x == a,
+    ObjectKind::Expr(ref e) =>
+      e.uncons().next().unwrap_or(e).as_atom() == Some(a) &&
+      matches!(env.data()[a].decl(), Some(DeclKey::Term(_))),
+    ObjectKind::Proof(ref p) =>
+      p.uncons().next().unwrap_or(p).as_atom() == Some(a) &&
+      matches!(env.data()[a].decl(), Some(DeclKey::Thm(_))),
+    ObjectKind::Var(_) | ObjectKind::LispLocal(..) | ObjectKind::MmcVar(..) |
+    ObjectKind::Syntax(_) | ObjectKind::RefineSyntax(_) | ObjectKind::Import(_) => false,
+  }
+}
+
+/// Returns true if `name` is the name of a sort, declaration or lisp global in `env`.
+fn is_defined(env: &FrozenEnv, name: &str) -> bool {
+  env.get_atom(name.as_bytes()).map_or(false, |a| {
+    let ad = &env.data()[a];
+    ad.sort().is_some() || ad.decl().is_some() || ad.lisp().is_some()
+  })
+}
+
+/// Collect the edits needed to rename `old` to `new` in one file.
+fn rename(env: &FrozenEnv, text: &LinedString, old: &str, new: &str, edits: &mut FileEdits) {
+  let a = if let Some(a) = env.get_atom(old.as_bytes()) {a} else {return};
+  for spans in env.spans() {
+    for (sp, k) in spans {
+      if !refers_to(env, a, k) { continue }
+      if let Some((start, end)) = find_ident(text.as_bytes(), *sp, old.as_bytes()) {
+        edits.replace.insert((start, end, new.as_bytes().into()));
+      }
+    }
+  }
+}
+
+/// The sort or declaration named `name` in `env`, if there is one.
+fn find_decl(env: &FrozenEnv, name: &str) -> Option<Item> {
+  let a = env.get_atom(name.as_bytes())?;
+  let ad = &env.data()[a];
+  if ad.sort().is_some() { return Some(Item::Sort(a)) }
+  if ad.decl().is_some() { return Some(Item::Decl(a)) }
+  None
+}
+
+/// The file in which item `it` is declared, and the full span of the declaration.
+fn decl_span(env: &FrozenEnv, it: Item) -> Option<(FileRef, Span)> {
+  let ad = &env.data()[it.atom()];
+  let (fsp, full) = match (it, ad.sort(), ad.decl()) {
+    (Item::Sort(_), Some(s), _) => { let sd = env.sort(s); (&sd.span, sd.full) }
+    (Item::Decl(_), _, Some(DeclKey::Term(t))) => { let td = env.term(t); (&td.span, td.full) }
+    (Item::Decl(_), _, Some(DeclKey::Thm(t))) => { let td = env.thm(t); (&td.span, td.full) }
+    _ => return None,
+  };
+  Some((fsp.file.clone(), full))
+}
+
+/// The sorts and declarations that item `it` refers to in its statement or proof.
+fn decl_deps(env: &FrozenEnv, it: Item) -> Vec<Item> {
+  fn expr(env: &FrozenEnv, e: &ExprNode, out: &mut Vec<Item>) {
+    match *e {
+      ExprNode::Ref(_) => {}
+      ExprNode::Dummy(_, s) => out.push(Item::Sort(env.sort(s).atom)),
+      ExprNode::App(t, ref es) => {
+        out.push(Item::Decl(env.term(t).atom));
+        for e in &**es { expr(env, e, out) }
+      }
+    }
+  }
+  fn proof(env: &FrozenEnv, p: &ProofNode, out: &mut Vec<Item>) {
+    match *p {
+      ProofNode::Dummy(_, s) => out.push(Item::Sort(env.sort(s).atom)),
+      ProofNode::Term {term, ..} | ProofNode::Cong {term, ..} | ProofNode::Unfold {term, ..} =>
+        out.push(Item::Decl(env.term(term).atom)),
+      ProofNode::Thm {thm, ..} => out.push(Item::Decl(env.thm(thm).atom)),
+      _ => {}
+    }
+    p.for_each_child(|p| proof(env, p, out))
+  }
+  let mut out = vec![];
+  let sorts = |out: &mut Vec<Item>, args: &[(Option<AtomId>, crate::Type)]|
+    out.extend(args.iter().map(|(_, ty)| Item::Sort(env.sort(ty.sort()).atom)));
+  match (it, env.data()[it.atom()].decl()) {
+    (Item::Decl(_), Some(DeclKey::Term(t))) => {
+      let td = env.term(t);
+      sorts(&mut out, &td.args);
+      out.push(Item::Sort(env.sort(td.ret.0).atom));
+      if let TermKind::Def(Some(e)) = &td.kind {
+        for e in e.heap.iter().chain(Some(&e.head)) { expr(env, e, &mut out) }
+      }
+    }
+    (Item::Decl(_), Some(DeclKey::Thm(t))) => {
+      let td = env.thm(t);
+      sorts(&mut out, &td.args);
+      for e in td.heap.iter().chain(td.hyps.iter().map(|(_, h)| h)).chain(Some(&td.ret)) {
+        expr(env, e, &mut out)
+      }
+      if let ThmKind::Thm(Some(pf)) = &td.kind {
+        for p in pf.heap.iter().chain(&*pf.hyps).chain(Some(&pf.head)) { proof(env, p, &mut out) }
+      }
+    }
+    _ => {}
+  }
+  out
+}
+
+/// Remove the declaration at `full` (and the following newline) from a file with source
+/// `src`, returning its text. The pending replacements inside the declaration are applied
+/// to the returned text instead, since they move along with it.
+fn cut(edits: &mut FileEdits, src: &[u8], full: Span) -> Vec<u8> {
+  let inner = edits.replace.iter()
+    .filter(|e| full.start <= e.0 && e.1 <= full.end).cloned().collect::<Vec<_>>();
+  let mut out = vec![];
+  let mut last = full.start;
+  for e in inner {
+    out.extend_from_slice(&src[last..e.0]);
+    out.extend_from_slice(&e.2);
+    last = e.1;
+    edits.replace.remove(&e);
+  }
+  out.extend_from_slice(&src[last..full.end]);
+  let mut end = full.end;
+  if src.get(end) == Some(&b'\n') { end += 1 }
+  edits.replace.insert((full.start, end, vec![]));
+  out
+}
+
+fn split_arg<'a>(arg: &'a str, what: &str) -> io::Result<(&'a str, &'a str)> {
+  arg.split_once('=').map(|(a, b)| (a.trim(), b.trim())).ok_or_else(||
+    io::Error::new(io::ErrorKind::InvalidInput, format!("--{}: expected 'a=b'", what)))
+}
+
+/// Main entry point for `mm0-rs refactor` subcommand.
+///
+/// See the [module documentation](self) for the supported operations.
+///
+/// # Arguments
+///
+/// `mm0-rs refactor [--rename old=new] [--move name=file] <in.mm1>`, where:
+///
+/// - `in.mm1` (or `in.mm0`) is the root of the import graph to refactor. All files
+///   it transitively imports are edited in place.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = fs::canonicalize(path)?.into();
+  let mut roots = vec![path.clone()];
+  if path.has_extension("mm1") {
+    let mm0 = path.path().with_extension("mm0");
+    if mm0.exists() { roots.push(fs::canonicalize(mm0)?.into()) }
+  }
+  // Elaborate the companion `.mm0` files of the imported `.mm1` files as well, so that
+  // they are edited along with them
+  while let Some(p) = roots.pop() {
+    if elab_for_result(p)?.1.is_none() { std::process::exit(1) }
+    if roots.is_empty() {
+      let done = elaborated_files().into_iter().map(|f| f.0).collect::<HashSet<_>>();
+      for p in &done {
+        if !p.has_extension("mm1") { continue }
+        let mm0 = p.path().with_extension("mm0");
+        if !mm0.exists() { continue }
+        let mm0: FileRef = fs::canonicalize(mm0)?.into();
+        if !done.contains(&mm0) && !roots.contains(&mm0) { roots.push(mm0) }
+      }
+    }
+  }
+  let files = elaborated_files().into_iter()
+    .filter_map(|(p, text, env)| Some((p, text.try_ascii()?.clone(), env)))
+    .collect::<Vec<_>>();
+  let mut edits: HashMap<FileRef, FileEdits> = HashMap::new();
+
+  if let Some(arg) = args.value_of("rename") {
+    let (old, new) = split_arg(arg, "rename")?;
+    if !new.bytes().all(is_ident_char) || new.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput,
+        format!("--rename: '{}' is not a valid identifier", new)))
+    }
+    if !files.iter().any(|(_, _, env)| is_defined(env, old)) {
+      return Err(io::Error::new(io::ErrorKind::NotFound,
+        format!("--rename: unknown name '{}'", old)))
+    }
+    if files.iter().any(|(_, _, env)| is_defined(env, new)) {
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+        format!("--rename: '{}' is already defined", new)))
+    }
+    for (p, text, env) in &files {
+      rename(env, text, old, new, edits.entry(p.clone()).or_default())
+    }
+  }
+
+  if let Some(arg) = args.value_of("move_decl") {
+    let (name, target) = split_arg(arg, "move")?;
+    let target: FileRef = fs::canonicalize(target)?.into();
+    if !files.iter().any(|(_, _, env)| find_decl(env, name).is_some()) {
+      return Err(io::Error::new(io::ErrorKind::NotFound,
+        format!("--move: unknown declaration '{}'", name)))
+    }
+    if !files.iter().any(|f| f.0 == target) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput,
+        format!("--move: {} is not in the import graph", target)))
+    }
+    // Declarations in `.mm0` files go to the companion `.mm0` file of the target
+    let target_mm0 = if target.has_extension("mm0") { Some(target.clone()) } else {
+      let mm0 = target.path().with_extension("mm0");
+      files.iter().map(|f| &f.0).find(|p| *p.path() == mm0).cloned()
+    };
+    let dest = |file: &FileRef| if file.has_extension("mm0") {
+      target_mm0.clone().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("--move: '{}' is declared in {}, but {} has no companion .mm0 file", name, file, target)))
+    } else { Ok(target.clone()) };
+    let mut closures = HashMap::new();
+    let mut sees = |from: &FileRef, to: &FileRef| closures.entry(from.clone())
+      .or_insert_with(|| crate::imports::import_closure(from)).contains(to);
+    let mut num_deps = 0;
+    let mut cuts = HashSet::new();
+    for (_, _, env2) in &files {
+      let it = if let Some(it) = find_decl(env2, name) {it} else {continue};
+      // Collect the declaration and the dependencies that are not visible from the target
+      let (mut stack, mut moved) = (vec![it], vec![]);
+      while let Some(it2) = stack.pop() {
+        if moved.contains(&it2) { continue }
+        let (file, _) = if let Some(x) = decl_span(env2, it2) {x} else {continue};
+        if it2 != it && sees(&dest(&file)?, &file) { continue }
+        moved.push(it2);
+        stack.extend(decl_deps(env2, it2));
+      }
+      num_deps = num_deps.max(moved.len() - 1);
+      // Every use of a moved declaration, and every use by one, must still be visible afterwards
+      for user in env2.stmts().iter().filter_map(Item::from_stmt) {
+        let user_moved = moved.contains(&user);
+        let deps = decl_deps(env2, user);
+        if !user_moved && !deps.iter().any(|d| moved.contains(d)) { continue }
+        let (file, _) = if let Some(x) = decl_span(env2, user) {x} else {continue};
+        let user_file = if user_moved { dest(&file)? } else { file };
+        for dep in deps {
+          let dep_moved = moved.contains(&dep);
+          if !user_moved && !dep_moved { continue }
+          let (file, _) = if let Some(x) = decl_span(env2, dep) {x} else {continue};
+          let dep_file = if dep_moved { dest(&file)? } else { file };
+          if !sees(&user_file, &dep_file) || dep_moved && !user_moved && user_file == dep_file {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+              "--move: '{}' in {} uses '{}', which would not be visible from there",
+              env2.data()[user.atom()].name(), user_file, env2.data()[dep.atom()].name())))
+          }
+        }
+      }
+      for it2 in env2.stmts().iter().filter_map(Item::from_stmt).filter(|it2| moved.contains(it2)) {
+        let (file, full) = decl_span(env2, it2).expect("checked above");
+        if !cuts.insert((file.clone(), full)) { continue }
+        let to = dest(&file)?;
+        let text = &files.iter().find(|f| f.0 == file).expect("declaring file was elaborated").1;
+        let decl = cut(edits.entry(file).or_default(), text.as_bytes(), full);
+        let append = &mut edits.entry(to).or_default().append;
+        append.push(b'\n');
+        append.extend_from_slice(&decl);
+        append.push(b'\n');
+      }
+    }
+    if num_deps > 0 {
+      println!("moving {} declarations that '{}' depends on", num_deps, name)
+    }
+  }
+
+  let mut out = vec![];
+  for (p, text, _) in &files {
+    let FileEdits {replace, append} = if let Some(e) = edits.remove(p) {e} else {continue};
+    if replace.is_empty() && append.is_empty() { continue }
+    let src = text.as_bytes();
+    let mut buf = Vec::with_capacity(src.len());
+    let mut last = 0;
+    for (start, end, new) in &replace {
+      if *start < last {
+        let (line, col) = text.to_line_col(*start);
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+          format!("{}:{}:{}: overlapping edits, no files were changed", p, line, col)))
+      }
+      buf.extend_from_slice(&src[last..*start]);
+      buf.extend_from_slice(new);
+      last = *end;
+    }
+    buf.extend_from_slice(&src[last..]);
+    buf.extend_from_slice(&append);
+    out.push((p, buf, replace.len() + usize::from(!append.is_empty())));
+  }
+  for (p, buf, n) in out {
+    fs::write(p.path(), buf)?;
+    println!("{}: {} edits", p, n);
+  }
+  Ok(())
+}
@@ -1,7 +1,8 @@
 //! Implements the bridge between mm0-rs and an editor via an lsp [`Connection`]
 
 use std::{fs, io};
-use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, Condvar};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}, Condvar};
 use std::collections::{VecDeque, HashMap, HashSet, hash_map::{Entry, DefaultHasher}};
 use std::hash::{Hash, Hasher};
 use std::thread::{ThreadId, self};
@@ -12,24 +13,26 @@ use futures::executor::ThreadPool;
 use futures::lock::Mutex as FMutex;
 use lsp_server::{Connection, ErrorCode, Message, Notification, ProtocolError,
   Request, RequestId, Response, ResponseError};
-use serde::ser::Serialize;
+use serde::Serialize;
 use serde_json::{from_value, to_value};
 use serde_repr::{Serialize_repr, Deserialize_repr};
 use serde::Deserialize;
 #[allow(clippy::wildcard_imports)] use lsp_types::*;
 use crossbeam::channel::{SendError, RecvError};
 use clap::ArgMatches;
-use crate::{ArcList, ArcString, BoxError, FileRef, FileSpan, Span,
-  MutexExt, CondvarExt};
-use mm1_parser::{Ast, parse};
+use crate::{ArcList, ArcString, BoxError, FileRef, FileSpan, PositionEncoding, Span,
+  MutexExt, CondvarExt, set_position_encoding};
+use mm1_parser::{Ast, ErrorLevel, parse_no_panic};
 use crate::mmb::import::elab as mmb_elab;
 use crate::mmu::import::elab as mmu_elab;
 use crate::compiler::FileContents;
+use crate::imports::ImportGraph;
 use crate::{ObjectKind, DeclKey, StmtTrace, AtomId, SortId, TermId, ThmId, LinedString, FrozenEnv,
-  FrozenLispKind, FrozenAtomData};
-use crate::elab::{ElabResult, ElaborateBuilder, GoalListener,
+  FrozenLispKind, FrozenAtomData, ThmKind};
+use crate::elab::{ElabResult, ElaborateBuilder, GoalListener, SUGGESTION,
   local_context::InferSort, proof::Subst,
   lisp::{print::FormatEnv, pretty::Pretty, LispKind, Proc, BuiltinProc},
+  disc_tree::flatten_lisp, persist,
   spans::Spans};
 
 // Disabled because vscode doesn't handle them properly
@@ -165,9 +168,10 @@ async fn elaborate(path: FileRef, start: Option<Position>,
           return Ok(recv.await.unwrap_or(ElabResult::Canceled))
         }
         cancel.store(true, Ordering::SeqCst);
-        let_unchecked!(Some(FileCache::InProgress {old, senders, ..}) = g.take(), {
-          (old, (None, None, vec![]), senders)
-        })
+        match g.take() {
+          Some(FileCache::InProgress {old, senders, ..}) => (old, (None, None, vec![]), senders),
+          _ => unreachable!("checked above"),
+        }
       }
       &mut Some(FileCache::Ready {hash, ref deps, ref res, ..}) => {
         let hasher = &mut DefaultHasher::new();
@@ -187,14 +191,13 @@ async fn elaborate(path: FileRef, start: Option<Position>,
         if matches && !matches!(res, ElabResult::Canceled) {
           return Ok(res.clone())
         }
-        let_unchecked!(Some(FileCache::Ready {ast, source, deps, res, ..}) = g.take(), {
-          if let ElabResult::Ok(_, errors, env) = res {
+        match g.take() {
+          Some(FileCache::Ready {ast, source, deps, res: ElabResult::Ok(_, errors, env), ..}) =>
             (Some((source.clone(), env.clone())),
-              (start.map(|s| (s, source, ast)), Some((errors, env)), deps), vec![])
-          } else {
-            (None, (None, None, vec![]), vec![])
-          }
-        })
+              (start.map(|s| (s, source, ast)), Some((errors, env)), deps), vec![]),
+          Some(FileCache::Ready {..}) => (None, (None, None, vec![]), vec![]),
+          _ => unreachable!("checked above"),
+        }
       }
     };
     *g = Some(FileCache::InProgress {old, version: v, cancel: cancel.clone(), senders});
@@ -202,14 +205,34 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     res
   };
   let (version, text) = file.text.ulock().clone();
-  let old_ast = old_ast.and_then(|(s, old_text, ast)|
-    if text.ptr_eq(&old_text) {Some((s, ast?))} else {None});
+  let old_ast = old_ast.and_then(|(s, old_text, ast)| {
+    let ast = ast?;
+    if text.ptr_eq(&old_text) { return Some((s, ast)) }
+    // The text may have changed several times since the last parse, so rather than
+    // trusting the position of the latest change, reuse the statements before the first
+    // byte that differs from the text that `ast` was parsed from.
+    let (old, new) = (old_text.try_ascii()?, text.try_ascii()?);
+    let n = old.as_bytes().iter().zip(new.as_bytes()).take_while(|(a, b)| a == b).count();
+    Some((new.to_pos(n), ast))
+  });
   let mut hasher = DefaultHasher::new();
   version.hash(&mut hasher);
   let source = text.clone();
 
+  let save_envs = SERVER.options.ulock().save_environments.unwrap_or(true);
+  let check_proofs = crate::get_check_proofs();
+  let saved_env = if version.is_none() && save_envs && path.has_extension("mm1") {
+    SERVER.saved_envs.ulock().load(&path)
+  } else { None };
+  let loaded = saved_env.is_some();
   let mut deps = Vec::new();
-  let (ast, (cyc, toks, errors, env)) = if path.has_extension("mmb") {
+  let (ast, (cyc, toks, errors, env)) = if let Some((env, saved_deps)) = saved_env {
+    log!("loaded the environment of {:?} from a previous run", path);
+    SERVER.imports.ulock().set_imports(path.clone(),
+      saved_deps.iter().map(|dep| (Span::default(), dep.clone())).collect());
+    deps = saved_deps;
+    (None, (None, vec![], vec![], env))
+  } else if path.has_extension("mmb") {
     let (error, env) = mmb_elab(&path, &text);
     let errors = if let Err(e) = error {vec![e]} else {vec![]};
     (None, (None, vec![], errors, FrozenEnv::new(env)))
@@ -218,22 +241,25 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     let errors = if let Err(e) = error {vec![e]} else {vec![]};
     (None, (None, vec![], errors, FrozenEnv::new(env)))
   } else {
-    let (idx, ast) = parse(text.ascii().clone(), old_ast);
+    let (idx, ast) = parse_no_panic(text.ascii().clone(), old_ast);
     let ast = Arc::new(ast);
     let rd = rd.push(path.clone());
+    let imports = ast.imports.iter()
+      .filter_map(|(sp, f)| Some((*sp, crate::imports::resolve(&path, f).ok()?))).collect();
+    SERVER.imports.ulock().set_imports(path.clone(), imports);
     let elab = ElaborateBuilder {
       ast: &ast,
       path: path.clone(),
       mm0_mode: path.has_extension("mm0"),
-      check_proofs: crate::get_check_proofs(),
+      check_proofs,
       report_upstream_errors: SERVER.options.ulock().report_upstream_errors.unwrap_or(true),
       cancel: cancel.clone(),
       old: old_env.map(|(errs, e)| (idx, errs, e)),
       recv_dep: |p| {
         let (p, dep) = vfs.get_or_insert(p)?;
         let (send, recv) = channel();
-        if rd.contains(&p) {
-          send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
+        if let Some(cyc) = SERVER.imports.ulock().find_cycle(&path, &p) {
+          send.send(ElabResult::ImportCycle(cyc)).expect("failed to send");
         } else {
           if let Some(Some(FileCache::Ready {res, ..})) =
             dep.parsed.try_lock().as_deref() {
@@ -245,6 +271,8 @@ async fn elaborate(path: FileRef, start: Option<Position>,
         }
         Ok(recv)
       },
+      prelude: crate::imports::prelude_for(&path),
+      on_stmt: None,
       recv_goal: start.filter(|_| SERVER.caps.ulock().goal_view)
         .and_then(|start| ast.source.to_idx(start))
         .filter(|&pos| pos != 0)
@@ -255,7 +283,7 @@ async fn elaborate(path: FileRef, start: Option<Position>,
             }
           })
         }),
-    }.elab();
+    }.elab_no_panic();
     (Some(ast.clone()), elab.await)
   };
   for tok in toks {tok.hash(&mut hasher)}
@@ -283,10 +311,16 @@ async fn elaborate(path: FileRef, start: Option<Position>,
 
     if let Some(ast) = &ast {
       use std::fmt::Write;
+      let max_len = SERVER.options.ulock().max_diagnostic_length
+        .unwrap_or(DEFAULT_MAX_DIAGNOSTIC_LENGTH);
       let (mut n_errs, mut n_warns, mut n_infos, mut n_hints) = (0, 0, 0, 0);
-      let errs: Vec<_> = ast.errors.iter().map(|e| e.to_diag(source.ascii()))
+      let errs: Vec<_> = ast.errors.iter().map(|e| Diagnostic {
+          code: Some(NumberOrString::String(crate::explain::ErrorCode::Syntax.to_str().into())),
+          ..e.to_diag(source.ascii())
+        })
         .chain(errors.iter().map(|e| e.to_diag(source.ascii(), &mut to_loc)))
         .filter(|e| !e.message.is_empty())
+        .map(|mut e| { truncate_message(&mut e.message, max_len); e })
         .inspect(|err| match err.severity {
           None => {}
           Some(DiagnosticSeverity::Error) => n_errs += 1,
@@ -312,6 +346,19 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     }
   }
 
+  if save_envs && !is_canceled && no_change_since_elab {
+    let mut saved = SERVER.saved_envs.ulock();
+    if cyc.is_none() && errors.is_empty() && ast.as_ref().map_or(true, |ast| ast.errors.is_empty()) {
+      // Only environments whose proofs have all been checked are saved, since a file that
+      // imports it would otherwise not be checked either
+      let checked = path.has_extension("mm1") && check_proofs &&
+        env.thms().iter().all(|td| !matches!(td.kind, ThmKind::Thm(None)));
+      saved.insert(&path, &source, &deps, checked.then(|| env.clone()), loaded)
+    } else {
+      saved.files.remove(&path);
+    }
+  }
+
   let res = if is_canceled {
     ElabResult::Canceled
   } else if let Some(cyc) = &cyc {
@@ -380,6 +427,110 @@ enum FileCache {
   }
 }
 
+/// The environments of the files that were elaborated without any diagnostics and with
+/// their proofs checked, in this or a previous run of the server. These are saved in the
+/// package cache when the server shuts down (see [`persist`](crate::elab::persist)), so that
+/// after a restart a file that has not changed, and whose imports have not changed, can be
+/// loaded instead of elaborated. This is only done for files that are not open in the
+/// editor, since the hovers and the goal view need the file to be elaborated.
+#[derive(Default)]
+struct SavedEnvs {
+  files: HashMap<FileRef, SavedEnv>,
+}
+
+/// An entry in [`SavedEnvs`].
+struct SavedEnv {
+  /// The hash of the contents of the file.
+  hash: String,
+  /// The direct imports of the file (including the prelude), with the hashes of the
+  /// contents they had when the file was elaborated, if known.
+  deps: Vec<(FileRef, Option<String>)>,
+  /// The environment, if it should be saved. This is `None` for `.mmb` and `.mmu` files,
+  /// which are fast to load, and for files whose proofs were not all checked.
+  env: Option<FrozenEnv>,
+  /// True if the environment was loaded from the cache, so it does not need to be saved.
+  saved: bool,
+}
+
+/// The hash of the current contents of `path`.
+fn content_hash(path: FileRef) -> Option<String> {
+  let (_, file) = SERVER.vfs.get_or_insert(path).ok()?;
+  let text = file.text.ulock().1.clone();
+  Some(crate::content_hash(&text))
+}
+
+impl SavedEnvs {
+  fn dir() -> io::Result<PathBuf> { Ok(crate::cache_root()?.join("envs")) }
+
+  /// The file in which the environment of `path` is saved.
+  fn file_name(dir: &Path, path: &FileRef) -> PathBuf {
+    dir.join(crate::content_hash(path.path().to_string_lossy().as_bytes())).with_extension("env")
+  }
+
+  /// Load the environment of `path` saved by a previous run, if it is still valid, and
+  /// return it with the direct imports of the file.
+  fn load(&self, path: &FileRef) -> Option<(FrozenEnv, Vec<FileRef>)> {
+    let file = Self::file_name(&Self::dir().ok()?, path);
+    let header = persist::load_header(io::BufReader::new(fs::File::open(&file).ok()?)).ok()?;
+    if header.files.first().map(|f| &f.0) != Some(path.path()) ||
+      !header.is_current(|p| content_hash(p.clone().into())) { return None }
+    match persist::load(&fs::read(&file).ok()?) {
+      Ok((header, env)) => Some((FrozenEnv::new(env),
+        header.deps.into_iter().map(FileRef::from).collect())),
+      Err(e) => { log!("could not load the saved environment of {:?}: {}", path, e); None }
+    }
+  }
+
+  /// Record that `path` with contents `text` was elaborated without diagnostics, with
+  /// imports `deps`. `env` is the environment to save, and `saved` is true if it was
+  /// loaded from the cache.
+  fn insert(&mut self, path: &FileRef, text: &[u8], deps: &[FileRef], env: Option<FrozenEnv>, saved: bool) {
+    let deps = deps.iter().map(|dep| (dep.clone(), self.files.get(dep).map(|f| f.hash.clone()))).collect();
+    self.files.insert(path.clone(), SavedEnv { hash: crate::content_hash(text), deps, env, saved });
+  }
+
+  /// The files that the environment of `path` depends on, that is, `path` and the files it
+  /// imports, directly or indirectly, with their hashes. Returns `None` if one of the imports
+  /// was elaborated again after `path`, or not without diagnostics.
+  fn closure(&self, path: &FileRef) -> Option<Vec<(PathBuf, String)>> {
+    let mut out = vec![];
+    let mut done = HashSet::new();
+    let mut stack = vec![path];
+    while let Some(path) = stack.pop() {
+      if !done.insert(path) { continue }
+      let file = self.files.get(path)?;
+      out.push((path.path().clone(), file.hash.clone()));
+      for (dep, hash) in &file.deps {
+        if Some(&self.files.get(dep)?.hash) != hash.as_ref() { return None }
+        stack.push(dep)
+      }
+    }
+    Some(out)
+  }
+
+  /// Save the environments that are not already in the cache.
+  fn save(&self) -> io::Result<()> {
+    let dir = Self::dir()?;
+    fs::create_dir_all(&dir)?;
+    for (path, file) in &self.files {
+      let env = match &file.env { Some(env) if !file.saved => env, _ => continue };
+      let files = if let Some(files) = self.closure(path) {files} else {continue};
+      let deps = file.deps.iter().map(|(dep, _)| dep.path().clone()).collect();
+      let header = persist::Header::new(deps, files);
+      let dest = Self::file_name(&dir, path);
+      let tmp = dest.with_extension("tmp");
+      let res = fs::File::create(&tmp)
+        .and_then(|f| persist::save(io::BufWriter::new(f), &header, env))
+        .and_then(|()| fs::rename(&tmp, &dest));
+      if let Err(e) = res {
+        drop(fs::remove_file(&tmp));
+        eprintln!("could not save the environment of {}: {}", path.rel(), e)
+      }
+    }
+    Ok(())
+  }
+}
+
 #[derive(DeepSizeOf)]
 struct VirtualFile {
   /// File data, saved (true) or unsaved (false)
@@ -413,7 +564,9 @@ impl Vfs {
       Entry::Occupied(e) => Ok((e.key().clone(), e.get().clone())),
       Entry::Vacant(e) => {
         let path = e.key().clone();
-        let fc = if path.has_extension("mmb") {
+        let fc = if let Some(text) = crate::get_virtual_file(&path) {
+          FileContents::new(text.to_string())
+        } else if path.has_extension("mmb") {
           FileContents::new_bin_from_file(path.path())?
         } else {
           FileContents::new(fs::read_to_string(path.path())?)
@@ -429,16 +582,23 @@ impl Vfs {
   }
 
   fn open_virt(&self, path: FileRef, version: i32, text: String) -> Arc<VirtualFile> {
-    let file = Arc::new(VirtualFile::new(Some(version), FileContents::new(text)));
-    let file = match self.0.ulock().entry(path.clone()) {
+    let mut g = self.0.ulock();
+    let file = match g.entry(path.clone()) {
       Entry::Occupied(entry) => {
-        for dep in entry.get().downstream.ulock().iter() {
+        // The file may already be loaded as an import, under a different spelling of the
+        // path. Re-key it with the client's, so that diagnostics go to the open document.
+        let (_, file) = entry.remove_entry();
+        *file.text.ulock() = (Some(version), FileContents::new(text));
+        for dep in file.downstream.ulock().iter() {
           Job::DepChange(path.clone(), dep.clone(), DepChangeReason::Open).spawn();
         }
+        g.insert(path.clone(), file.clone());
         file
       }
-      Entry::Vacant(entry) => entry.insert(file).clone()
+      Entry::Vacant(entry) =>
+        entry.insert(Arc::new(VirtualFile::new(Some(version), FileContents::new(text)))).clone()
     };
+    drop(g);
     Job::Elaborate(path, ElabReason::Open).spawn();
     file
   }
@@ -485,6 +645,9 @@ enum RequestType {
   DocumentSymbol(DocumentSymbolParams),
   References(ReferenceParams),
   DocumentHighlight(DocumentHighlightParams),
+  CodeAction(CodeActionParams),
+  ExecuteCommand(ExecuteCommandParams),
+  Eval(EvalParams),
 }
 
 fn parse_request(Request {id, method, params}: Request) -> Result<Option<(RequestId, RequestType)>> {
@@ -496,6 +659,9 @@ fn parse_request(Request {id, method, params}: Request) -> Result<Option<(Reques
     "textDocument/documentSymbol"    => Some((id, RequestType::DocumentSymbol(from_value(params)?))),
     "textDocument/references"        => Some((id, RequestType::References(from_value(params)?))),
     "textDocument/documentHighlight" => Some((id, RequestType::DocumentHighlight(from_value(params)?))),
+    "textDocument/codeAction"        => Some((id, RequestType::CodeAction(from_value(params)?))),
+    "workspace/executeCommand"       => Some((id, RequestType::ExecuteCommand(from_value(params)?))),
+    "$/mm0/eval"                     => Some((id, RequestType::Eval(from_value(params)?))),
     _ => None
   })
 }
@@ -540,7 +706,6 @@ type OpenRequests = Mutex<HashMap<RequestId, Arc<AtomicBool>>>;
 
 struct RequestHandler {
   id: RequestId,
-  #[allow(unused)]
   cancel: Arc<AtomicBool>,
 }
 
@@ -583,6 +748,80 @@ impl RequestHandler {
         self.finish(references(file.clone(), doc.position, true,
           |range| DocumentHighlight { range, kind: None }).await)
       }
+      RequestType::CodeAction(CodeActionParams {text_document: doc, range, context, ..}) =>
+        self.finish(code_action(doc.uri.into(), range, context.diagnostics).await),
+      RequestType::ExecuteCommand(ExecuteCommandParams {command, arguments, ..}) =>
+        match &*command {
+          FIND_LEMMAS => match arguments.into_iter().next().map(from_value) {
+            Some(Ok(TextDocumentPositionParams {text_document: doc, position})) =>
+              self.finish(find_lemmas(doc.uri.into(), position).await),
+            _ => self.finish::<()>(Err(response_err(ErrorCode::InvalidParams,
+              "find lemmas: expected a text document position"))),
+          },
+          SHOW_FULL_OUTPUT => match arguments.into_iter().next().map(from_value) {
+            Some(Ok(TextDocumentPositionParams {text_document: doc, position})) =>
+              self.finish(show_full_output(doc.uri.into(), position).await),
+            _ => self.finish::<()>(Err(response_err(ErrorCode::InvalidParams,
+              "show full output: expected a text document position"))),
+          },
+          PROOF_SOURCES => match arguments.into_iter().next().map(from_value) {
+            Some(Ok(TextDocumentPositionParams {text_document: doc, position})) =>
+              self.finish(proof_sources(doc.uri.into(), position).await),
+            _ => self.finish::<()>(Err(response_err(ErrorCode::InvalidParams,
+              "proof sources: expected a text document position"))),
+          },
+          PROOF_TREE => {
+            let mut it = arguments.into_iter();
+            match (it.next().map(from_value), it.next().map(from_value::<String>)) {
+              (Some(Ok(TextDocumentPositionParams {text_document: doc, position})), format) => {
+                let format = match format {
+                  None => Ok(crate::proof_tree::Format::Outline),
+                  Some(Ok(format)) => format.parse().map_err(|e: String|
+                    response_err(ErrorCode::InvalidParams, e)),
+                  Some(Err(e)) => Err(response_err(ErrorCode::InvalidParams, e.to_string())),
+                };
+                match format {
+                  Ok(format) => self.finish(proof_tree(doc.uri.into(), position, format).await),
+                  Err(e) => self.finish::<()>(Err(e)),
+                }
+              }
+              _ => self.finish::<()>(Err(response_err(ErrorCode::InvalidParams,
+                "proof tree: expected a text document position"))),
+            }
+          }
+          DEBUG_DUMP => {
+            let mut it = arguments.into_iter();
+            match (it.next().map(from_value),
+              it.next().map_or_else(|| Ok(DebugDumpParams::default()), from_value)) {
+              (Some(Ok(TextDocumentIdentifier {uri})), Ok(params)) =>
+                self.finish(debug_dump(uri.into(), params).await),
+              _ => self.finish::<()>(Err(response_err(ErrorCode::InvalidParams,
+                "debug dump: expected a text document and an object with 'globals' or 'decl'"))),
+            }
+          }
+          CHECK_PROJECT => {
+            let cancel = self.cancel.clone();
+            self.finish(check_project(cancel).await)
+          }
+          HOLES => match arguments.into_iter().next().map(from_value) {
+            None => {
+              let cancel = self.cancel.clone();
+              self.finish(holes(None, cancel).await)
+            }
+            Some(Ok(TextDocumentIdentifier {uri})) => {
+              let cancel = self.cancel.clone();
+              self.finish(holes(Some(uri.into()), cancel).await)
+            }
+            Some(Err(_)) => self.finish::<()>(Err(response_err(ErrorCode::InvalidParams,
+              "holes: expected a text document or no arguments"))),
+          },
+          _ => self.finish::<()>(Err(response_err(ErrorCode::MethodNotFound,
+            format!("unknown command '{}'", command)))),
+        },
+      RequestType::Eval(EvalParams {text_document: doc, code}) => {
+        let cancel = self.cancel.clone();
+        self.finish(eval(doc.uri.into(), code, cancel).await)
+      }
     }
   }
 
@@ -660,6 +899,34 @@ fn try_old(file: &Arc<VirtualFile>)  -> Option<(FileContents, FrozenEnv)> {
   }))
 }
 
+/// Find a name at `idx` that is quoted, like `'foo` in `(get-decl 'foo)` or a string
+/// `"foo"`, and names a sort or declaration. Returns the span of the name and the atom.
+/// This is a heuristic based on the text, because the name is only data to the lisp
+/// code, and it can be turned off with the `quotedNames` option.
+fn quoted_name(env: &FrozenEnv, text: &LinedString, idx: usize) -> Option<(Span, AtomId)> {
+  if !SERVER.options.ulock().quoted_names.unwrap_or(true) { return None }
+  let s = text.as_bytes();
+  let ident = |c: u8| c.is_ascii_alphanumeric() || c == b'_';
+  let start = s[..idx].iter().rposition(|&c| !ident(c)).map_or(0, |i| i + 1);
+  let end = s[idx..].iter().position(|&c| !ident(c)).map_or(s.len(), |i| idx + i);
+  match (start.checked_sub(1).map(|i| s[i]), s.get(end)) {
+    (Some(b'\''), _) | (Some(b'"'), Some(b'"')) if start < end => {}
+    _ => return None,
+  }
+  let a = env.get_atom(&s[start..end])?;
+  let ad = &env.data()[a];
+  if ad.decl().is_none() && ad.sort().is_none() { return None }
+  Some(((start..end).into(), a))
+}
+
+/// The location of `fsp` for display, as `file:line`.
+fn show_location(fsp: &FileSpan) -> String {
+  match SERVER.vfs.get(&fsp.file).and_then(|f| f.text.ulock().1.try_ascii().cloned()) {
+    Some(text) => format!("{}:{}", fsp.file, text.to_pos(fsp.span.start).line + 1),
+    None => format!("{}", fsp.file),
+  }
+}
+
 async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseError> {
   macro_rules! or {($ret:expr, $e:expr)  => {match $e {
     Some(x) => x,
@@ -677,15 +944,32 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
     response_err(ErrorCode::InvalidRequest, "hover nonexistent file"))?;
   let text = file.text.ulock().1.ascii().clone();
   let idx = or!(Ok(None), text.to_idx(pos));
-  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
     .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
-  let env = or!(Ok(None), env.into_response_error()?).1;
-  let env = unsafe { env.thaw() };
+  let frozen = or!(Ok(None), env.into_response_error()?).1;
+  let quoted = quoted_name(&frozen, &text, idx);
+  let env = unsafe { frozen.thaw() };
   let fe = FormatEnv { source: &text, env };
-  let spans = or!(Ok(None), Spans::find(&env.spans, idx));
 
   let mut out: Vec<(Span, MarkedString)> = vec![];
+  if let Some((sp, a)) = quoted {
+    let (s, doc) = match (env.data[a].decl, env.data[a].sort) {
+      (Some(DeclKey::Term(t)), _) => (format!("{}", fe.to(&env.terms[t])), &env.terms[t].doc),
+      (Some(DeclKey::Thm(t)), _) => (format!("{}", fe.to(&env.thms[t])), &env.thms[t].doc),
+      (None, Some(s)) => (format!("{}", env.sorts[s]), &env.sorts[s].doc),
+      (None, None) => unreachable!(),
+    };
+    out.push((sp, mk_mm0(s)));
+    if let Some(doc) = doc { out.push((sp, mk_doc(doc))) }
+    return Ok(Some(Hover {
+      range: Some(text.to_range(sp)),
+      contents: HoverContents::Array(out.into_iter().map(|s| s.1).collect())
+    }))
+  }
+  let spans = or!(Ok(None), Spans::find(&env.spans, idx));
+  let mut on_decl = false;
   for &(sp, ref k) in spans.find_pos(idx) {
+    on_decl |= matches!(k, ObjectKind::Term(..) | ObjectKind::Thm(_));
     if let Some((r, doc)) = (|| Some(match k {
       &ObjectKind::Sort(s) => {
         let sd = &env.sorts[s];
@@ -768,17 +1052,38 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
         } else { return None }
       }
       &ObjectKind::Global(a) => {
-        let ld = env.data[a].lisp.as_ref()?;
-        if let Some(doc) = &ld.doc {
-          ((sp, mk_doc(doc)), None)
-        } else {
-          let bp = ld.unwrapped(|e| match *e {
+        let ad = &env.data[a];
+        match &ad.lisp {
+          Some(ld) => match ld.unwrapped(|e| match *e {
             LispKind::Proc(Proc::Builtin(p)) => Some(p),
             _ => None
-          })?;
-          ((sp, mk_doc(bp.doc())), None)
+          }) {
+            Some(bp) if ld.doc.is_none() => ((sp, mk_doc(&bp.help())), None),
+            _ => {
+              use std::fmt::Write;
+              let mut s = format!("`{}`", String::from_utf8_lossy(&ad.name));
+              if let Some(spec) = ld.unwrapped(|e| match e {
+                LispKind::Proc(p) => Some(p.spec()),
+                _ => None
+              }) {
+                write!(s, " (takes {})", spec).expect("impossible")
+              }
+              if let Some((fsp, _)) = &ld.src {
+                write!(s, "\n\ndefined at {}", show_location(fsp)).expect("impossible")
+              }
+              ((sp, mk_doc(&s)), ld.doc.clone())
+            }
+          },
+          // An undefined global refers to the builtin of the same name, if there is one
+          None => ((sp, mk_doc(&BuiltinProc::from_bytes(&ad.name)?.help())), None),
         }
       }
+      &ObjectKind::LispLocal(x, binder) =>
+        ((sp, mk_doc(&format!("`{}` (local variable)\n\nbound at line {}",
+          String::from_utf8_lossy(&env.data[x].name), text.to_pos(binder.start).line + 1))), None),
+      &ObjectKind::MmcVar(ref ty, binder) =>
+        ((sp, mk_doc(&format!("`{}` (MMC variable)\n\nbound at line {}",
+          ty, text.to_pos(binder.start).line + 1))), None),
       ObjectKind::Import(_) => return None,
     }))() {
       let sp = r.0;
@@ -788,6 +1093,13 @@ async fn hover(path: FileRef, pos: Position) -> Result<Option<Hover>, ResponseEr
       }
     }
   }
+  if on_decl {
+    // If this is the name of a declaration with inferred binders, show the elaborated ones
+    if let Some(info) = crate::binders::analyze(&path, &text, &frozen).into_iter()
+        .find(|info| info.decl.start <= idx && idx <= info.decl.end) {
+      out.push((info.decl, mk_doc(&format!("inferred binders: `{}`", info.binders))))
+    }
+  }
   if out.is_empty() {return Ok(None)}
   Ok(Some(Hover {
     range: Some(text.to_range(out[0].0)),
@@ -813,6 +1125,17 @@ async fn definition<T>(path: FileRef, pos: Position,
   let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
     .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
   let env = or_none!(env.into_response_error()?).1;
+  if let Some((sp, a)) = quoted_name(&env, &text, idx) {
+    let ad = &env.data()[a];
+    let (fsp, full) = match (ad.decl(), ad.sort()) {
+      (Some(DeclKey::Term(t)), _) => (&env.term(t).span, env.term(t).full),
+      (Some(DeclKey::Thm(t)), _) => (&env.thm(t).span, env.thm(t).full),
+      (None, Some(s)) => (&env.sort(s).span, env.sort(s).full),
+      (None, None) => unreachable!(),
+    };
+    let src = if fsp.file.ptr_eq(&path) { text.clone() } else { vfs.source(&fsp.file) };
+    return Ok(vec![f(&text, &src, sp, fsp, full)])
+  }
   let spans = or_none!(env.find(idx));
   let mut res = vec![];
   for &(sp, ref k) in spans.find_pos(idx) {
@@ -841,6 +1164,8 @@ async fn definition<T>(path: FileRef, pos: Position,
       ObjectKind::Var(_) |
       ObjectKind::Syntax(_) |
       ObjectKind::RefineSyntax(_) => {}
+      &ObjectKind::LispLocal(_, binder) | &ObjectKind::MmcVar(_, binder) =>
+        res.push(g(&FileSpan {file: path.clone(), span: binder}, binder)),
       ObjectKind::Expr(e) => {
         let head = e.uncons().next().unwrap_or(e);
         if let Some(DeclKey::Term(t)) = head.as_atom().and_then(|a| env.data()[a].decl()) {
@@ -946,7 +1271,8 @@ async fn document_symbol(path: FileRef) -> Result<DocumentSymbolResponse, Respon
                 FrozenLispKind::Proc(_) => SymbolKind::Function,
                 FrozenLispKind::AtomMap(_) |
                 FrozenLispKind::Annot(_, _) |
-                FrozenLispKind::Ref(_) => SymbolKind::Object,
+                FrozenLispKind::Ref(_) |
+                FrozenLispKind::Proof(_) => SymbolKind::Object,
               }))() {
                 Some(sk) => sk,
                 None => continue,
@@ -1004,7 +1330,8 @@ fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData,
         FrozenLispKind::Bool(_) |
         FrozenLispKind::AtomMap(_) |
         FrozenLispKind::Annot(_, _) |
-        FrozenLispKind::Ref(_) => CompletionItemKind::Value,
+        FrozenLispKind::Ref(_) |
+        FrozenLispKind::Proof(_) => CompletionItemKind::Value,
         FrozenLispKind::Syntax(_) => CompletionItemKind::Event,
         FrozenLispKind::Proc(ref p) if
           match *unsafe {p.thaw()} {
@@ -1017,7 +1344,7 @@ fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData,
   }
 }
 
-async fn completion(path: FileRef, _pos: Position) -> Result<CompletionResponse, ResponseError> {
+async fn completion(path: FileRef, pos: Position) -> Result<CompletionResponse, ResponseError> {
   let file = SERVER.vfs.get(&path).ok_or_else(||
     response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
   let (text, env) = if let Some(old) = try_old(&file) { old } else {
@@ -1030,6 +1357,15 @@ async fn completion(path: FileRef, _pos: Position) -> Result<CompletionResponse,
   };
   let text = text.ascii().clone();
   let fe = unsafe { env.format_env(&text) };
+  // Theorems that may prove one of the unsolved goals at this position are ranked first
+  let relevant = text.to_idx(pos).and_then(|idx| env.find(idx)?.lc.as_ref()).map_or_else(HashSet::new, |lc| {
+    let env = unsafe { env.thaw() };
+    lc.goals.iter().filter_map(|g| g.goal_type()).flat_map(|ty| {
+      let mut keys = vec![];
+      flatten_lisp(env, &ty, &mut keys);
+      env.thm_index.unifiable(&keys)
+    }).collect()
+  });
   let mut res = vec![];
   BuiltinProc::for_each(|_, s| {
     res.push(CompletionItem {
@@ -1041,21 +1377,43 @@ async fn completion(path: FileRef, _pos: Position) -> Result<CompletionResponse,
   });
   for ad in env.data().iter() {
     if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Sort) {res.push(ci)}
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Decl) {res.push(ci)}
+    if let Some(mut ci) = make_completion_item(&path, fe, ad, false, TraceKind::Decl) {
+      if matches!(ad.decl(), Some(DeclKey::Thm(t)) if relevant.contains(&t)) {
+        ci.sort_text = Some(format!("0{}", ci.label))
+      }
+      res.push(ci)
+    }
     if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Global) {res.push(ci)}
   }
+  // The lisp local variables in scope are ranked first, innermost first
+  if let Some((idx, spans)) = text.to_idx(pos).and_then(|idx| Some((idx, env.find(idx)?))) {
+    let mut seen = HashSet::new();
+    for &(scope, x, _) in spans.lisp_scopes.iter().rev() {
+      if scope.start <= idx && idx <= scope.end && seen.insert(x) {
+        let label: String = String::from_utf8_lossy(env.data()[x].name()).into();
+        res.push(CompletionItem {
+          sort_text: Some(format!("0{}", label)),
+          label,
+          detail: Some("local variable".into()),
+          kind: Some(CompletionItemKind::Variable),
+          ..Default::default()
+        })
+      }
+    }
+  }
   Ok(CompletionResponse::Array(res))
 }
 
 async fn completion_resolve(ci: CompletionItem) -> Result<CompletionItem, ResponseError> {
   let data = if let Some(data) = ci.data {data} else {
+    if ci.kind == Some(CompletionItemKind::Variable) { return Ok(ci) }
     let p = BuiltinProc::from_str(&ci.label)
       .ok_or_else(|| response_err(ErrorCode::InvalidRequest, "missing data"))?;
     return Ok(CompletionItem {
       label: ci.label,
       documentation: Some(Documentation::MarkupContent(MarkupContent {
         kind: MarkupKind::Markdown,
-        value: p.doc().into(),
+        value: p.help(),
       })),
       kind: Some(CompletionItemKind::Keyword),
       ..Default::default()
@@ -1098,6 +1456,10 @@ async fn references<T>(
     Term(TermId),
     Thm(ThmId),
     Global(AtomId),
+    /// A lisp local variable, identified by the span of its binder.
+    LispLocal(Span),
+    /// An MMC variable, identified by the span of its binder.
+    MmcVar(Span),
   }
 
   let file = SERVER.vfs.get(&path).ok_or_else(||
@@ -1134,6 +1496,8 @@ async fn references<T>(
     ObjectKind::Term(a, _) => Some(Key::Term(a)),
     ObjectKind::Thm(a) => Some(Key::Thm(a)),
     ObjectKind::Global(a) => Some(Key::Global(a)),
+    ObjectKind::LispLocal(_, sp) => Some(Key::LispLocal(sp)),
+    ObjectKind::MmcVar(_, sp) => Some(Key::MmcVar(sp)),
   };
 
   let mut res = vec![];
@@ -1154,7 +1518,7 @@ async fn references<T>(
         res.push(f(text.to_range(sp2)))
       }
     };
-    if let Key::Var(_) = key {
+    if let Key::Var(_) | Key::LispLocal(_) = key {
       spans.into_iter().for_each(&mut cont);
     } else {
       for spans2 in env.spans() {
@@ -1165,16 +1529,467 @@ async fn references<T>(
   Ok(res)
 }
 
+/// The inputs of the targets of the project manifest and all the source files in its roots,
+/// sorted and without duplicates. `cmd` is the name of the command, for error messages.
+fn project_files(cmd: &str) -> Result<Vec<FileRef>, ResponseError> {
+  let io_err = |e: io::Error| response_err(ErrorCode::InternalError, e.to_string());
+  let project = SERVER.project.as_ref().ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, format!("{}: there is no project manifest", cmd)))?;
+  let mut files = vec![];
+  for t in &project.manifest.targets { files.push(project.path(&t.input)) }
+  for root in project.roots() { crate::package::sources(&root, &mut files).map_err(io_err)? }
+  let mut paths = files.into_iter().map(|p| Ok(fs::canonicalize(p)?.into()))
+    .collect::<io::Result<Vec<FileRef>>>().map_err(io_err)?;
+  paths.sort_by(|a, b| a.path().cmp(b.path()));
+  paths.dedup();
+  Ok(paths)
+}
+
+/// The command for elaborating all the files of the project, publishing their diagnostics.
+const CHECK_PROJECT: &str = "mm0.checkProject";
+
+/// The response to [`CHECK_PROJECT`].
+#[derive(Default, Serialize)]
+struct ProjectStatus {
+  /// The number of files that were elaborated.
+  files: usize,
+  errors: usize,
+  warnings: usize,
+}
+
+/// Elaborate the inputs of the targets of the project manifest and all the source files
+/// in its roots, and count their errors and warnings. The diagnostics of each file are
+/// published as usual, so this gives feedback about the whole project, not just the open
+/// files. Files that have not changed since they were last elaborated are not elaborated
+/// again. This runs on the [`CHECK_PROJECT`] command, and when a file is saved if the
+/// `checkProjectOnSave` option is set.
+async fn check_project(cancel: Arc<AtomicBool>) -> Result<ProjectStatus, ResponseError> {
+  let mut st = ProjectStatus::default();
+  for path in project_files("check project")? {
+    if cancel.load(Ordering::Relaxed) { break }
+    let res = elaborate(path, Some(Position::default()), cancel.clone(), Default::default()).await
+      .map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+    if let ElabResult::Ok(_, errors, _) = res {
+      st.files += 1;
+      for e in errors.iter().flat_map(|errs| errs.iter()) {
+        match e.level {
+          ErrorLevel::Error => st.errors += 1,
+          ErrorLevel::Warning => st.warnings += 1,
+          ErrorLevel::Info => {}
+        }
+      }
+    }
+  }
+  Ok(st)
+}
+
+/// The command for listing the unfinished proofs in a file, or in the whole project
+/// (see [`crate::holes`]).
+const HOLES: &str = "mm0.holes";
+
+/// An unfinished proof obligation in the response to [`HOLES`].
+#[derive(Serialize)]
+struct Hole {
+  /// Either `goal` (an unsolved goal or unassigned metavariable) or `sorry`.
+  kind: &'static str,
+  message: String,
+  location: Location,
+}
+
+/// List the unsolved goals, unassigned metavariables and uses of `sorry` in file `path`, or
+/// in all the files of the project (see [`project_files`]) if `path` is `None`.
+async fn holes(path: Option<FileRef>, cancel: Arc<AtomicBool>) -> Result<Vec<Hole>, ResponseError> {
+  let paths = match path {
+    Some(path) => vec![path],
+    None => project_files("holes")?,
+  };
+  let mut res = vec![];
+  for path in paths {
+    if cancel.load(Ordering::Relaxed) { break }
+    let elab = elaborate(path.clone(), Some(Position::default()), cancel.clone(), Default::default()).await
+      .map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+    let errors = if let ElabResult::Ok(_, Some(errors), _) = elab {errors} else {continue};
+    let file = if let Some(file) = SERVER.vfs.get(&path) {file} else {continue};
+    let text = file.text.ulock().1.ascii().clone();
+    for h in crate::holes::holes(&errors) {
+      res.push(Hole {
+        kind: h.kind.name(),
+        message: h.msg,
+        location: Location {uri: path.url().clone(), range: text.to_range(h.pos)},
+      })
+    }
+  }
+  Ok(res)
+}
+
+/// The command for finding all lemmas about the term at a position.
+const FIND_LEMMAS: &str = "mm0.findLemmas";
+
+/// A theorem in the response to [`FIND_LEMMAS`].
+#[derive(Serialize)]
+struct Lemma {
+  name: String,
+  statement: String,
+  location: Location,
+}
+
+/// A group of theorems with the same head symbol in the response to [`FIND_LEMMAS`].
+#[derive(Serialize)]
+struct LemmaGroup {
+  /// The head term constructor of the conclusion, or `None` if it is a variable.
+  head: Option<String>,
+  lemmas: Vec<Lemma>,
+}
+
+/// Find all theorems whose statement mentions the term at the given position, grouped by
+/// the head symbol of the conclusion (see [`Environment::theorems_about`]).
+///
+/// [`Environment::theorems_about`]: crate::Environment::theorems_about
+async fn find_lemmas(path: FileRef, pos: Position) -> Result<Vec<LemmaGroup>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "find lemmas: nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let idx = if let Some(idx) = text.to_idx(pos) {idx} else {return Ok(vec![])};
+  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = if let Some((_, env)) = env.into_response_error()? {env} else {return Ok(vec![])};
+  let spans = if let Some(spans) = env.find(idx) {spans} else {return Ok(vec![])};
+  let t = spans.find_pos(idx).find_map(|(_, k)| match *k {
+    ObjectKind::Term(t, _) => Some(t),
+    ObjectKind::Expr(ref e) => match env.data()[e.uncons().next().unwrap_or(e).as_atom()?].decl() {
+      Some(DeclKey::Term(t)) => Some(t),
+      _ => None,
+    },
+    _ => None,
+  });
+  let t = if let Some(t) = t {t} else {return Ok(vec![])};
+  let env = unsafe { env.thaw() };
+  let mut srcs = HashMap::new();
+  Ok(env.theorems_about(t).into_iter().map(|(head, thms)| LemmaGroup {
+    head: head.map(|h| env.data[env.terms[h].atom].name.to_string()),
+    lemmas: thms.into_iter().map(|t| {
+      let td = &env.thms[t];
+      let src = &**srcs.entry(td.span.file.clone())
+        .or_insert_with(|| SERVER.vfs.source(&td.span.file));
+      let fe = FormatEnv { source: src, env };
+      Lemma {
+        name: env.data[td.atom].name.to_string(),
+        statement: format!("{}", fe.to(td)),
+        location: src.to_loc(&td.span),
+      }
+    }).collect(),
+  }).collect())
+}
+
+/// The command for showing the full text of a diagnostic which was truncated because it
+/// is longer than the `maxDiagnosticLength` option.
+const SHOW_FULL_OUTPUT: &str = "mm0.showFullOutput";
+
+/// The default value of the `maxDiagnosticLength` option.
+const DEFAULT_MAX_DIAGNOSTIC_LENGTH: usize = 10_000;
+
+/// The start of the note which is added to truncated diagnostics.
+const TRUNCATED: &str = "[output truncated: ";
+
+/// Truncate `msg` to at most `max` bytes (plus a note about the truncation), preferably at
+/// a line break. A `max` of 0 means no limit. Long messages, usually from printing large
+/// terms, can make the editor unresponsive, so the full text is only provided on request
+/// using [`SHOW_FULL_OUTPUT`].
+fn truncate_message(msg: &mut String, max: usize) {
+  use std::fmt::Write;
+  if max == 0 || msg.len() <= max { return }
+  let mut end = max;
+  while !msg.is_char_boundary(end) { end -= 1 }
+  if let Some(i) = msg[..end].rfind('\n').filter(|&i| i >= end - end / 4) { end = i }
+  let rest = msg.len() - end;
+  msg.truncate(end);
+  write!(msg, "\n\n{}{} more bytes, use the \"Show full output\" code action to see all of it]",
+    TRUNCATED, rest).expect("writing to a string")
+}
+
+/// The full (untruncated) messages of the diagnostics at the given position.
+async fn full_messages(path: FileRef, pos: Position) -> Result<Vec<String>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let idx = if let Some(idx) = text.to_idx(pos) {idx} else {return Ok(vec![])};
+  let res = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let errors = match res {
+    ElabResult::Ok(_, Some(errors), _) => errors,
+    ElabResult::Canceled => return Err(response_err(ErrorCode::RequestCanceled, "")),
+    _ => return Ok(vec![]),
+  };
+  Ok(errors.iter().filter(|e| e.pos.start <= idx && idx <= e.pos.end).map(|e| e.kind.msg()).collect())
+}
+
+/// The number of `window/showDocument` requests sent so far, used to make their ids unique.
+static SHOW_DOCUMENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Write the full text of the longest diagnostic at the given position to a scratch file,
+/// and ask the client to open it. Returns the URI of the file, or `None` if there is no
+/// diagnostic at this position.
+async fn show_full_output(path: FileRef, pos: Position) -> Result<Option<Url>, ResponseError> {
+  let msg = full_messages(path, pos).await?.into_iter().max_by_key(String::len);
+  let msg = if let Some(msg) = msg {msg} else {return Ok(None)};
+  let internal = |e: &dyn std::fmt::Debug| response_err(ErrorCode::InternalError, format!("{:?}", e));
+  let n = SHOW_DOCUMENT_COUNT.fetch_add(1, Ordering::Relaxed);
+  let out = std::env::temp_dir().join(format!("mm0-output-{}-{}.txt", std::process::id(), n));
+  fs::write(&out, msg).map_err(|e| internal(&e))?;
+  let uri = Url::from_file_path(&out).map_err(|e| internal(&e))?;
+  let params = ShowDocumentParams {uri: uri.clone(), external: None, take_focus: Some(true), selection: None};
+  let id = RequestId::from(format!("show_document_{}", n));
+  SERVER.show_document_reqs.ulock().insert(id.clone());
+  send_message(Request {
+    id,
+    method: "window/showDocument".to_owned(),
+    params: to_value(params).map_err(|e| internal(&e))?,
+  }).map_err(|e| internal(&e))?;
+  Ok(Some(uri))
+}
+
+/// The command for getting the source map of a proof, which says which tactic produced
+/// each step of the proof.
+const PROOF_SOURCES: &str = "mm0.proofSources";
+
+/// A step of a proof in the response to [`PROOF_SOURCES`].
+#[derive(Serialize)]
+struct ProofStep {
+  /// The index of the step, in the numbering of [`Proof::steps`](crate::Proof::steps).
+  step: u32,
+  /// A short description of the step, usually the name of the theorem being applied.
+  label: String,
+  /// The location of the expression that produced this step.
+  location: Location,
+}
+
+/// Get the source map of the proof of the theorem at the given position, that is, the
+/// theorem whose name is at the position or whose declaration contains it. This is useful for
+/// finding out which line of tactic code produced a given step of a proof.
+async fn proof_sources(path: FileRef, pos: Position) -> Result<Vec<ProofStep>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "proof sources: nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let idx = if let Some(idx) = text.to_idx(pos) {idx} else {return Ok(vec![])};
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = if let Some((_, env)) = env.into_response_error()? {env} else {return Ok(vec![])};
+  let t = if let Some(t) = thm_at(&env, &path, idx) {t} else {return Ok(vec![])};
+  let env = unsafe { env.thaw() };
+  let td = &env.thms[t];
+  let proof = if let ThmKind::Thm(Some(proof)) = &td.kind {proof} else {return Ok(vec![])};
+  let steps = proof.steps();
+  let mut srcs = HashMap::new();
+  Ok(proof.spans.iter().map(|(n, fsp)| {
+    let src = &**srcs.entry(fsp.file.clone()).or_insert_with(|| SERVER.vfs.source(&fsp.file));
+    let label = crate::proof_tree::label(FormatEnv { source: src, env }, td, steps[*n as usize]);
+    ProofStep { step: *n, label, location: src.to_loc(fsp) }
+  }).collect())
+}
+
+/// The theorem at index `idx` of file `path`, that is, the theorem whose name is at the
+/// position or whose declaration contains it.
+fn thm_at(env: &FrozenEnv, path: &FileRef, idx: usize) -> Option<ThmId> {
+  let t = env.find(idx).and_then(|spans| spans.find_pos(idx).find_map(|(_, k)| match *k {
+    ObjectKind::Thm(t) => Some(t),
+    _ => None,
+  }));
+  let env = unsafe { env.thaw() };
+  t.or_else(|| env.thms.enum_iter().find(|(_, td)|
+    td.span.file == *path && td.full.start <= idx && idx <= td.full.end).map(|(t, _)| t))
+}
+
+/// The command for rendering the proof of a theorem as an outline or a DOT graph
+/// (see [`crate::proof_tree`]).
+const PROOF_TREE: &str = "mm0.proofTree";
+
+/// Render the proof of the theorem at the given position (in the sense of [`thm_at`]) in the
+/// given format. Returns `None` if there is no theorem with a proof at this position.
+async fn proof_tree(path: FileRef, pos: Position, format: crate::proof_tree::Format
+) -> Result<Option<String>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "proof tree: nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let idx = if let Some(idx) = text.to_idx(pos) {idx} else {return Ok(None)};
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = if let Some((_, env)) = env.into_response_error()? {env} else {return Ok(None)};
+  let t = if let Some(t) = thm_at(&env, &path, idx) {t} else {return Ok(None)};
+  Ok(crate::proof_tree::render(FormatEnv { source: &text, env: unsafe { env.thaw() } }, t, format))
+}
+
+/// The command for dumping the internal representation of parts of the environment of a
+/// file to a scratch file (see [`crate::debug_dump`]).
+const DEBUG_DUMP: &str = "mm0.debugDump";
+
+/// The second argument of the [`DEBUG_DUMP`] command, which selects what to dump.
+#[derive(Debug, Default, Deserialize)]
+struct DebugDumpParams {
+  /// Dump the lisp globals whose names start with this prefix.
+  globals: Option<String>,
+  /// Dump the sort, term or theorem with this name.
+  decl: Option<String>,
+}
+
+/// Dump the parts of the environment of file `path` selected by `params` to a file in the
+/// temporary directory, and return the path of the file.
+async fn debug_dump(path: FileRef, params: DebugDumpParams) -> Result<String, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "debug dump: nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = env.into_response_error()?.ok_or_else(||
+    response_err(ErrorCode::InternalError, "debug dump: elaboration failed"))?.1;
+  let out = crate::debug_dump::dump(unsafe { env.format_env(&text) },
+    params.globals.as_deref(), params.decl.as_deref())
+    .map_err(|e| response_err(ErrorCode::InvalidParams, e))?;
+  let name = path.rel().rsplit('/').next().unwrap_or("file").to_owned();
+  let out_path = std::env::temp_dir().join(format!("mm0-debug-{}.txt", name));
+  std::fs::write(&out_path, out).map_err(|e| response_err(ErrorCode::InternalError, e.to_string()))?;
+  Ok(out_path.to_string_lossy().into_owned())
+}
+
+/// The parameters of the `$/mm0/eval` request, which evaluates lisp code against the
+/// environment of a file, without changing it (see [`crate::elab::snapshot`]).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EvalParams {
+  /// The file whose environment the code is evaluated in.
+  text_document: TextDocumentIdentifier,
+  /// The code, a sequence of lisp expressions.
+  code: String,
+}
+
+/// The response to the `$/mm0/eval` request.
+#[derive(Serialize)]
+struct EvalResponse {
+  /// The printed values of the expressions, except for those that evaluate to `#undef`.
+  values: Vec<String>,
+  /// The errors and messages from the evaluation, including the output of `display`.
+  /// The ranges are positions in the evaluated code.
+  diagnostics: Vec<Diagnostic>,
+}
+
+async fn eval(path: FileRef, code: String, cancel: Arc<AtomicBool>) -> Result<EvalResponse, ResponseError> {
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = if let Some((_, env)) = env.into_response_error()? {env} else {
+    return Err(response_err(ErrorCode::InvalidRequest, "eval: the file is part of an import cycle"))
+  };
+  let out = crate::elab::snapshot::eval(&env, path, code, cancel);
+  let to_loc = |fsp: &FileSpan| SERVER.vfs.get(&fsp.file)
+    .and_then(|file| file.text.ulock().1.try_ascii().map(|src| src.to_loc(fsp)))
+    .unwrap_or_else(|| Location {uri: fsp.file.url().clone(), range: Range::default()});
+  Ok(EvalResponse {
+    values: out.values,
+    diagnostics: out.errors.iter().map(|e| e.to_diag(&out.source, to_loc)).collect(),
+  })
+}
+
+async fn code_action(path: FileRef, range: Range, diags: Vec<Diagnostic>
+) -> Result<CodeActionResponse, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "code action: nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = if let Some((_, env)) = env.into_response_error()? {env} else {return Ok(vec![])};
+  let start = text.to_idx(range.start).unwrap_or(0);
+  let end = text.to_idx(range.end).unwrap_or_else(|| text.len());
+  let mut res = vec![];
+  for info in crate::imports::analyze(&path, &env, |f| try_old(&SERVER.vfs.get(f)?).map(|r| r.1)) {
+    if info.stmt.end < start || end < info.stmt.start { continue }
+    let (msg, new_text) = match (info.msg(), info.replacement(&path)) {
+      (Some(msg), Some(new_text)) => (msg, new_text),
+      _ => continue
+    };
+    let mut stmt = info.stmt;
+    if new_text.is_empty() && text.as_bytes().get(stmt.end) == Some(&b'\n') { stmt.end += 1 }
+    let title = if new_text.is_empty() {
+      format!("Remove import of {}", info.file.rel())
+    } else {
+      format!("Replace with {}", new_text)
+    };
+    let edit = TextEdit {range: text.to_range(stmt), new_text};
+    res.push(CodeActionOrCommand::CodeAction(CodeAction {
+      title,
+      kind: Some(CodeActionKind::QUICKFIX),
+      diagnostics: Some(vec![Diagnostic {
+        range: text.to_range(info.span),
+        severity: Some(DiagnosticSeverity::Hint),
+        message: msg,
+        ..Default::default()
+      }]),
+      edit: Some(WorkspaceEdit::new(Some((path.url().clone(), vec![edit])).into_iter().collect())),
+      is_preferred: Some(true),
+      ..Default::default()
+    }))
+  }
+  for info in crate::binders::analyze(&path, &text, &env) {
+    if info.range.end < start || end < info.decl.start { continue }
+    let edit = TextEdit {range: text.to_range(info.range), new_text: info.text};
+    res.push(CodeActionOrCommand::CodeAction(CodeAction {
+      title: "Make binders explicit".into(),
+      kind: Some(CodeActionKind::REFACTOR_REWRITE),
+      edit: Some(WorkspaceEdit::new(Some((path.url().clone(), vec![edit])).into_iter().collect())),
+      ..Default::default()
+    }))
+  }
+  for diag in &diags {
+    if !diag.message.contains(TRUNCATED) { continue }
+    let arg = TextDocumentPositionParams {
+      text_document: TextDocumentIdentifier {uri: path.url().clone()},
+      position: diag.range.start,
+    };
+    res.push(CodeActionOrCommand::Command(Command {
+      title: "Show full output".into(),
+      command: SHOW_FULL_OUTPUT.into(),
+      arguments: Some(vec![to_value(arg)
+        .map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?]),
+    }))
+  }
+  for diag in diags {
+    let new_text = if let Some(s) = diag.message.strip_prefix(SUGGESTION) {s.to_owned()} else {continue};
+    let title = format!("Replace with {}", new_text.lines().next().unwrap_or_default());
+    // The message may have been truncated, so get the full suggestion from the elaborator
+    let new_text = if new_text.contains(TRUNCATED) {
+      let full = full_messages(path.clone(), diag.range.start).await?.into_iter()
+        .find_map(|msg| Some(msg.strip_prefix(SUGGESTION)?.to_owned()));
+      if let Some(full) = full {full} else {continue}
+    } else {new_text};
+    let edit = TextEdit {range: diag.range, new_text};
+    res.push(CodeActionOrCommand::CodeAction(CodeAction {
+      title,
+      kind: Some(CodeActionKind::QUICKFIX),
+      edit: Some(WorkspaceEdit::new(Some((path.url().clone(), vec![edit])).into_iter().collect())),
+      diagnostics: Some(vec![diag]),
+      ..Default::default()
+    }))
+  }
+  Ok(res)
+}
+
 struct Server {
   conn: Connection,
   #[allow(unused)]
   caps: Mutex<ClientCapabilities>,
   reqs: OpenRequests,
   vfs: Vfs,
+  /// The imports of the files that have been parsed, used to detect import cycles.
+  imports: Mutex<ImportGraph>,
   pool: ThreadPool,
   #[allow(clippy::type_complexity)]
   threads: Arc<(Mutex<VecDeque<(Job, Arc<AtomicBool>)>>, Condvar)>,
+  /// Background jobs that are waiting for the elaboration of the open documents to finish.
+  deferred: Mutex<Vec<Job>>,
   options: Mutex<ServerOptions>,
+  /// The project containing the workspace, if it has a manifest.
+  project: Option<crate::project::Project>,
+  /// The environments of the files that elaborated without diagnostics.
+  saved_envs: Mutex<SavedEnvs>,
+  /// The ids of the `window/showDocument` requests that are awaiting a response.
+  show_document_reqs: Mutex<HashSet<RequestId>>,
 }
 
 
@@ -1290,6 +2105,7 @@ enum Job {
   Elaborate(FileRef, ElabReason),
   ElaborateDep(FileRef, FileRef, Option<(FSender<ElabResult<u64>>, ArcList<FileRef>)>),
   DepChange(FileRef, FileRef, DepChangeReason),
+  CheckProject,
 }
 
 impl std::fmt::Display for Job {
@@ -1301,11 +2117,42 @@ impl std::fmt::Display for Job {
       Job::Elaborate(path, ElabReason::Change(_)) => write!(f, "elaborate {} on change", path),
       Job::ElaborateDep(from, to, _) => write!(f, "elaborate {} needed for {}", from, to),
       Job::DepChange(from, to, reason) => write!(f, "elaborate {} for {} {}", to, from, reason),
+      Job::CheckProject => write!(f, "check project"),
     }
   }
 }
 
+/// The scheduling priority of a [`Job`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Priority {
+  /// Re-elaboration of files because an import changed, and project checks. These are
+  /// deferred while a [`Visible`](Priority::Visible) job is running.
+  Background,
+  /// Requests, and elaboration of imports needed by another job.
+  Request,
+  /// Elaboration of an open document after it is opened, edited or saved.
+  Visible,
+}
+
 impl Job {
+  fn priority(&self) -> Priority {
+    match self {
+      Job::DepChange(..) | Job::CheckProject => Priority::Background,
+      Job::RequestHandler(..) | Job::ElaborateDep(..) => Priority::Request,
+      Job::Elaborate(..) => Priority::Visible,
+    }
+  }
+
+  /// Returns true if `self` and `other` are background jobs that do the same work,
+  /// so that only one of them needs to be run.
+  fn same_work(&self, other: &Job) -> bool {
+    match (self, other) {
+      (Job::DepChange(_, to1, _), Job::DepChange(_, to2, _)) => to1 == to2,
+      (Job::CheckProject, Job::CheckProject) => true,
+      _ => false,
+    }
+  }
+
   fn spawn_core<F>(self, cancel: Arc<AtomicBool>, fut: F)
   where F: std::future::Future<Output=()> + Send + 'static {
     SERVER.threads.0.ulock().push_back((self, cancel.clone()));
@@ -1316,12 +2163,50 @@ impl Job {
       let a: *const AtomicBool = &*cancel;
       let i = vec.iter().enumerate().find(|&(_, (_, b))| a == &**b).expect("my job is missing").0;
       vec.swap_remove_front(i);
+      // `spawn` checks for visible jobs and defers under the same lock, so a job
+      // deferred concurrently is either seen here or is not deferred at all
+      let deferred = if vec.iter().any(|(job, _)| job.priority() == Priority::Visible) {
+        vec![]
+      } else {
+        std::mem::take(&mut *SERVER.deferred.ulock())
+      };
       drop(vec);
       cvar.notify_all();
+      for job in deferred { job.spawn() }
     });
   }
 
   fn spawn(mut self) {
+    if let Job::Elaborate(path, ElabReason::Change(_)) = &self {
+      // Downstream files that are being elaborated against the previous contents of this
+      // file will be elaborated again when it is done, so stop them now. This includes
+      // the files downstream of those, which are waiting on a stale elaboration.
+      let threads = SERVER.threads.0.ulock();
+      let mut deferred = SERVER.deferred.ulock();
+      let mut stale = vec![path.clone()];
+      let mut i = 0;
+      while let Some(p) = stale.get(i).cloned() {
+        for job in threads.iter().map(|(job, _)| job).chain(&*deferred) {
+          if let Job::DepChange(from, to, _) = job {
+            if *from == p && !stale.contains(to) { stale.push(to.clone()) }
+          }
+        }
+        i += 1;
+      }
+      let is_stale = |job: &Job| matches!(job, Job::DepChange(from, ..) if stale.contains(from));
+      for (job, cancel) in &*threads {
+        if is_stale(job) { cancel.store(true, Ordering::Relaxed) }
+      }
+      deferred.retain(|job| !is_stale(job));
+    }
+    if self.priority() == Priority::Background {
+      let threads = SERVER.threads.0.ulock();
+      if threads.iter().any(|(job, _)| job.priority() == Priority::Visible) {
+        let mut deferred = SERVER.deferred.ulock();
+        if !deferred.iter().any(|job| job.same_work(&self)) { deferred.push(self) }
+        return
+      }
+    }
     let cancel: Arc<AtomicBool> = Default::default();
     match &mut self {
       Job::RequestHandler(id, req) => {
@@ -1346,6 +2231,14 @@ impl Job {
         let f = dep_change(to.clone(), cancel.clone());
         self.spawn_core(cancel, f)
       }
+      Job::CheckProject => {
+        let f = check_project(cancel.clone()).map(|res| match res {
+          Ok(st) => log(format!("checked project: {} files, {} errors, {} warnings",
+            st.files, st.errors, st.warnings)),
+          Err(e) => log(format!("check project failed: {}", e.message)),
+        });
+        self.spawn_core(cancel, f)
+      }
     }
   }
 }
@@ -1363,8 +2256,13 @@ struct ServerOptions {
   executable_path: Option<std::path::PathBuf>,
   max_number_of_problems: usize,
   syntax_docs: Option<bool>,
+  quoted_names: Option<bool>,
+  check_project_on_save: Option<bool>,
+  save_environments: Option<bool>,
   log_errors: Option<bool>,
   report_upstream_errors: Option<bool>,
+  max_diagnostic_length: Option<usize>,
+  elab_delay: Option<u64>,
 }
 
 impl std::default::Default for ServerOptions {
@@ -1374,12 +2272,21 @@ impl std::default::Default for ServerOptions {
       executable_path: None,
       max_number_of_problems: 100,
       syntax_docs: None,
+      quoted_names: None,
+      check_project_on_save: None,
+      save_environments: None,
       log_errors: None,
       report_upstream_errors: None,
+      max_diagnostic_length: None,
+      elab_delay: None,
     }
   }
 }
 
+/// The default of the `elabDelay` option: the number of milliseconds to wait after a change
+/// before elaborating the file, so that a burst of keystrokes is elaborated only once.
+const DEFAULT_ELAB_DELAY: u64 = 150;
+
 /// Enum for use in [`ServerOptions`] showing when the user wants changes to be applied
 /// and the new file to be elaborated.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
@@ -1414,29 +2321,63 @@ fn send_config_request() -> Result<()> {
 impl Server {
   fn new() -> Result<Server> {
     let (conn, _iot) = Connection::stdio();
-    let params = from_value(conn.initialize(
-      to_value(ServerCapabilities {
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::Incremental)),
-        hover_provider: Some(true.into()),
-        completion_provider: Some(CompletionOptions {
-          resolve_provider: Some(true),
-          ..Default::default()
-        }),
-        definition_provider: Some(OneOf::Left(true)),
-        document_symbol_provider: Some(OneOf::Left(true)),
-        references_provider: Some(OneOf::Left(true)),
-        document_highlight_provider: Some(OneOf::Left(true)),
+    let (id, params) = conn.initialize_start()?;
+    // Prefer byte offsets, which need no conversion, then code points, if the client
+    // supports them; otherwise use UTF-16, the only encoding before LSP 3.17.
+    let encoding = params.pointer("/capabilities/general/positionEncodings")
+      .and_then(|v| v.as_array())
+      .and_then(|encs| [PositionEncoding::Utf8, PositionEncoding::Utf32].into_iter()
+        .find(|e| encs.iter().any(|v| v.as_str() == Some(e.name()))))
+      .unwrap_or(PositionEncoding::Utf16);
+    set_position_encoding(encoding);
+    let mut caps = to_value(ServerCapabilities {
+      text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::Incremental)),
+      hover_provider: Some(true.into()),
+      completion_provider: Some(CompletionOptions {
+        resolve_provider: Some(true),
+        ..Default::default()
+      }),
+      definition_provider: Some(OneOf::Left(true)),
+      document_symbol_provider: Some(OneOf::Left(true)),
+      references_provider: Some(OneOf::Left(true)),
+      document_highlight_provider: Some(OneOf::Left(true)),
+      code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+      execute_command_provider: Some(ExecuteCommandOptions {
+        commands: vec![FIND_LEMMAS.into(), SHOW_FULL_OUTPUT.into(), PROOF_SOURCES.into(),
+          PROOF_TREE.into(), CHECK_PROJECT.into(),
+          HOLES.into(), DEBUG_DUMP.into()],
         ..Default::default()
-      })?
-    )?)?;
+      }),
+      ..Default::default()
+    })?;
+    // lsp-types does not have the `positionEncoding` field yet
+    caps["positionEncoding"] = encoding.name().into();
+    conn.initialize_finish(id, serde_json::json!({"capabilities": caps}))?;
+    let params: InitializeParams = from_value(params)?;
+    let mut project = None;
+    if let Some(root) = params.root_uri.as_ref().and_then(|u| u.to_file_path().ok()) {
+      match crate::project::Project::find(&root) {
+        Ok(None) => {}
+        Ok(Some(p)) => {
+          if let Err(e) = p.configure() { eprintln!("could not configure project: {}", e) }
+          project = Some(p)
+        }
+        Err(e) => eprintln!("could not load project manifest: {}", e),
+      }
+    }
     Ok(Server {
       caps: Mutex::new(ClientCapabilities::new(params)),
       conn,
       reqs: Mutex::new(HashMap::new()),
       vfs: Vfs(Mutex::new(HashMap::new())),
+      imports: Default::default(),
       pool: ThreadPool::new()?,
       threads: Default::default(),
+      deferred: Default::default(),
       options: Mutex::new(ServerOptions::default()),
+      project,
+      saved_envs: Default::default(),
+      show_document_reqs: Default::default(),
     })
   }
 
@@ -1475,6 +2416,8 @@ impl Server {
                 let [config]: [ServerOptions; 1] = from_value(val)?;
                 *self.options.ulock() = config;
               }
+            } else if self.show_document_reqs.ulock().remove(&resp.id) {
+              if let Some(e) = resp.error { log!("show document failed: {}", e.message) }
             } else {
               let mut caps = caps.ulock();
               if caps.reg_id.as_ref().map_or(false, |rid| rid == &resp.id) {
@@ -1513,7 +2456,22 @@ impl Server {
                     start
                   };
                   if options.ulock().elab_on.unwrap_or_default() == ElabOn::Change {
-                    Job::Elaborate(path, ElabReason::Change(start)).spawn();
+                    let delay = options.ulock().elab_delay.unwrap_or(DEFAULT_ELAB_DELAY);
+                    match delay {
+                      0 => Job::Elaborate(path, ElabReason::Change(start)).spawn(),
+                      ms => {
+                        // Wait for the user to stop typing; if there is another change in
+                        // the meantime, it will start the elaboration instead of this one.
+                        let version = doc.version;
+                        thread::spawn(move || {
+                          thread::sleep(std::time::Duration::from_millis(ms));
+                          let current = SERVER.vfs.get(&path).and_then(|file| file.text.ulock().0);
+                          if current == Some(version) {
+                            Job::Elaborate(path, ElabReason::Change(start)).spawn()
+                          }
+                        });
+                      }
+                    }
                   }
                 }
               }
@@ -1530,6 +2488,9 @@ impl Server {
                 if options.ulock().elab_on.unwrap_or_default() == ElabOn::Save {
                   Job::Elaborate(path, ElabReason::Save).spawn();
                 }
+                if options.ulock().check_project_on_save.unwrap_or(false) {
+                  Job::CheckProject.spawn();
+                }
               }
               DidChangeConfiguration::METHOD => send_config_request()?,
               _ => {}
@@ -1544,6 +2505,7 @@ impl Server {
       }
     }
     logger.stop();
+    self.deferred.ulock().clear();
     let (mutex, cvar) = &*self.threads;
     let mut g = mutex.ulock();
     g.iter().for_each(|(_, c)| c.store(true, Ordering::Relaxed));
@@ -1552,6 +2514,12 @@ impl Server {
       // eprintln!("waiting on threads:\n  {}", g.iter().map(|(s, _)| s).format("\n  "));
       g = cvar.uwait(g)
     }
+    drop(g);
+    if self.options.ulock().save_environments.unwrap_or(true) {
+      if let Err(e) = self.saved_envs.ulock().save() {
+        eprintln!("could not save the environments: {}", e)
+      }
+    }
   }
 }
 
@@ -1583,6 +2551,8 @@ pub fn main(args: &ArgMatches<'_>) {
   }
   let server = &*SERVER; // start the server
   drop(log_message("started".into()));
+  // This comes after the server is initialized, because it overrides the project settings
+  if args.is_present("no_prelude") { crate::set_prelude(None) }
   if args.is_present("no_log_errors") {
     server.options.ulock().log_errors = Some(false)
   }
@@ -0,0 +1,248 @@
+//! A test runner for MM1 developments.
+//!
+//! This module implements the `mm0-rs test` subcommand. It finds all `.mm1` files in the
+//! given directories, elaborates each of them, and reports which ones fail. A test file
+//! fails if it has any errors, so negative tests (declarations which should be rejected)
+//! are written using the `@(expect)` annotation, which turns the expected errors into a
+//! success. If there is a file `foo.expected` next to the test `foo.mm1`, the output of
+//! the test (the messages from `(display)` and other info messages, one per line) must also
+//! match the contents of this file. The expected output can be created or updated using
+//! `--bless`.
+//!
+//! The results are printed in a human readable format by default, or as
+//! [TAP](https://testanything.org/) or JUnit XML for consumption by CI systems.
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
+use clap::ArgMatches;
+use crate::compiler::elab_for_diagnostics;
+use crate::{ErrorLevel, FileRef, MutexExt};
+
+/// The result of running a single test file.
+struct TestResult {
+  /// The path to the test, as displayed in the report.
+  name: String,
+  /// The time spent elaborating the test.
+  time: Duration,
+  /// The reasons for the test failure, or empty if the test passed.
+  failures: Vec<String>,
+}
+
+/// Find all `.mm1` files in `path` (recursively, if it is a directory) and add them to `out`.
+fn discover(path: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+  if path.is_dir() {
+    let mut entries = fs::read_dir(path)?.map(|e| Ok(e?.path())).collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for p in entries {
+      if p.is_dir() || p.extension().map_or(false, |ext| ext == "mm1") { discover(&p, out)? }
+    }
+  } else {
+    out.push(path.to_owned())
+  }
+  Ok(())
+}
+
+/// Compare the expected output `expected` with the actual output `actual`, and describe
+/// the first difference.
+fn compare_output(expected: &str, actual: &str) -> Option<String> {
+  let (mut exp, mut act) = (expected.lines(), actual.lines());
+  let mut line = 0;
+  loop {
+    line += 1;
+    match (exp.next(), act.next()) {
+      (None, None) => return None,
+      (e, a) if e == a => {}
+      (e, a) => return Some(format!("line {}: expected {}, found {}", line,
+        e.map_or_else(|| "end of output".into(), |e| format!("'{}'", e)),
+        a.map_or_else(|| "end of output".into(), |a| format!("'{}'", a)))),
+    }
+  }
+}
+
+/// Run the test file `path`, displayed as `name`. If `bless` is true, the expected output
+/// file is overwritten with the actual output instead of being checked. An I/O error or a
+/// panic while running the test fails this test, instead of the whole run.
+fn run_test(path: &Path, name: String, bless: bool) -> TestResult {
+  let start = Instant::now();
+  let res = panic::catch_unwind(AssertUnwindSafe(|| try_run_test(path, name.clone(), bless)));
+  let failure = match res {
+    Ok(Ok(r)) => return r,
+    Ok(Err(e)) => e.to_string(),
+    Err(e) => format!("panicked: {}", e.downcast_ref::<&str>().copied()
+      .or_else(|| e.downcast_ref::<String>().map(|s| &**s)).unwrap_or("unknown error")),
+  };
+  TestResult {name, time: start.elapsed(), failures: vec![failure]}
+}
+
+/// The body of [`run_test`], which fails with an error if a file could not be read or written.
+fn try_run_test(path: &Path, name: String, bless: bool) -> io::Result<TestResult> {
+  let start = Instant::now();
+  let fref = FileRef::from(fs::canonicalize(path)?);
+  let (file, res) = elab_for_diagnostics(fref.clone())?;
+  let time = start.elapsed();
+  let mut failures = vec![];
+  let errors = match res {
+    Ok(errors) => errors,
+    Err(cyc) => {
+      failures.push(match cyc {
+        Some(cyc) => crate::imports::cycle_msg(&fref, &cyc[0].0.file, &cyc),
+        None => "elaboration canceled".into(),
+      });
+      return Ok(TestResult {name, time, failures})
+    }
+  };
+  let mut output = String::new();
+  for e in &*errors {
+    match e.level {
+      ErrorLevel::Info => { output += &e.kind.msg(); output.push('\n') }
+      ErrorLevel::Warning => {}
+      ErrorLevel::Error => {
+        let mut s = match file.try_ascii() {
+          Some(text) => {
+            let (line, col) = text.to_line_col(e.pos.start);
+            format!("{}:{}:{}: ", name, line, col)
+          }
+          None => format!("{}:{:#x}: ", name, e.pos.start),
+        };
+        if let Some(code) = e.code { write!(s, "error[{}]: ", code).expect("writing to a string") }
+        s += &e.kind.msg();
+        failures.push(s)
+      }
+    }
+  }
+  let expected = path.with_extension("expected");
+  if bless {
+    if !output.is_empty() || expected.exists() { fs::write(&expected, &output)? }
+  } else if expected.exists() {
+    if let Some(diff) = compare_output(&fs::read_to_string(&expected)?, &output) {
+      failures.push(format!("output does not match {}: {}", expected.display(), diff))
+    }
+  }
+  Ok(TestResult {name, time, failures})
+}
+
+/// Escape a string for use in an XML attribute or text node.
+fn xml_escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&' => out += "&amp;",
+      '<' => out += "&lt;",
+      '>' => out += "&gt;",
+      '"' => out += "&quot;",
+      '\'' => out += "&apos;",
+      _ => out.push(c),
+    }
+  }
+  out
+}
+
+fn print_human(results: &[TestResult], filtered: usize) {
+  for r in results {
+    println!("test {} ... {}", r.name, if r.failures.is_empty() { "ok" } else { "FAILED" })
+  }
+  let failed = results.iter().filter(|r| !r.failures.is_empty()).collect::<Vec<_>>();
+  if !failed.is_empty() {
+    println!("\nfailures:");
+    for r in &failed {
+      println!("\n---- {} ----", r.name);
+      for f in &r.failures { println!("{}", f) }
+    }
+  }
+  println!("\ntest result: {}. {} passed; {} failed; {} filtered out",
+    if failed.is_empty() { "ok" } else { "FAILED" },
+    results.len() - failed.len(), failed.len(), filtered)
+}
+
+fn print_tap(results: &[TestResult]) {
+  println!("TAP version 13\n1..{}", results.len());
+  for (i, r) in results.iter().enumerate() {
+    if r.failures.is_empty() {
+      println!("ok {} - {}", i + 1, r.name)
+    } else {
+      println!("not ok {} - {}", i + 1, r.name);
+      for f in &r.failures {
+        for line in f.lines() { println!("# {}", line) }
+      }
+    }
+  }
+}
+
+fn print_junit(results: &[TestResult]) {
+  let failed = results.iter().filter(|r| !r.failures.is_empty()).count();
+  let time: Duration = results.iter().map(|r| r.time).sum();
+  println!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+  println!("<testsuites>");
+  println!("  <testsuite name=\"mm0-rs\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+    results.len(), failed, time.as_secs_f64());
+  for r in results {
+    let name = xml_escape(&r.name);
+    let time = r.time.as_secs_f64();
+    match r.failures.first() {
+      None => println!("    <testcase name=\"{}\" time=\"{:.3}\"/>", name, time),
+      Some(first) => {
+        println!("    <testcase name=\"{}\" time=\"{:.3}\">", name, time);
+        println!("      <failure message=\"{}\">{}</failure>",
+          xml_escape(first), xml_escape(&r.failures.join("\n")));
+        println!("    </testcase>");
+      }
+    }
+  }
+  println!("  </testsuite>");
+  println!("</testsuites>");
+}
+
+/// Main entry point for `mm0-rs test` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs test <dir>... [--filter PAT] [-j N] [--format FMT] [--bless]`, where:
+///
+/// - `dir` is a directory containing `.mm1` test files (searched recursively), or a
+///   single test file.
+/// - `PAT` is a string; only the tests whose path contains `PAT` are run.
+/// - `N` is the number of tests to run in parallel (default: the number of CPUs).
+/// - `FMT` is the report format: `human` (the default), `tap`, or `junit`.
+/// - `--bless` writes the output of each test to its `.expected` file, instead of checking it.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let mut paths = vec![];
+  for p in args.values_of("INPUT").expect("required arg") { discover(Path::new(p), &mut paths)? }
+  let total = paths.len();
+  if let Some(pat) = args.value_of("filter") {
+    paths.retain(|p| p.to_string_lossy().contains(pat))
+  }
+  let filtered = total - paths.len();
+  let jobs = match args.value_of("jobs") {
+    Some(n) => n.parse().ok().filter(|&n| n != 0).ok_or_else(||
+      io::Error::new(io::ErrorKind::InvalidInput, "--jobs: expected a positive number"))?,
+    None => thread::available_parallelism().map_or(1, |n| n.get()),
+  };
+  let bless = args.is_present("bless");
+  let queue = Arc::new(Mutex::new(paths.into_iter().enumerate()));
+  let results = Arc::new(Mutex::new(vec![]));
+  let workers = (0..jobs).map(|_| {
+    let (queue, results) = (queue.clone(), results.clone());
+    thread::spawn(move || loop {
+      let next = queue.ulock().next();
+      let (i, path) = if let Some(next) = next { next } else { return };
+      let r = run_test(&path, path.display().to_string(), bless);
+      results.ulock().push((i, r))
+    })
+  }).collect::<Vec<_>>();
+  for w in workers {
+    w.join().map_err(|_| io::Error::new(io::ErrorKind::Other, "test thread panicked"))?
+  }
+  let mut results = std::mem::take(&mut *results.ulock());
+  results.sort_by_key(|&(i, _)| i);
+  let results = results.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+  match args.value_of("format") {
+    Some("tap") => print_tap(&results),
+    Some("junit") => print_junit(&results),
+    _ => print_human(&results, filtered),
+  }
+  if results.iter().any(|r| !r.failures.is_empty()) { std::process::exit(1) }
+  Ok(())
+}
@@ -0,0 +1,373 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::Arc;
+use futures::{channel::oneshot::Receiver, executor::block_on};
+use mm0_rs::{elab::{ElabResult, ElaborateBuilder}, FrozenEnv};
+
+/// Run `mm0-rs` with the given arguments in `dir`, returning the output.
+fn mm0_rs(dir: &Path, args: &[&str]) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_mm0-rs")).current_dir(dir).args(args).output().unwrap()
+}
+
+/// Run `mm0-rs test` on a file in `test_resources`, comparing the `display` output
+/// against the `.expected` file next to it.
+fn run_test(file: &str) {
+  let out = mm0_rs(Path::new("test_resources"), &["test", "--no-prelude", file]);
+  assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+}
+
+#[test]
+fn transfer() { run_test("transfer.mm1") }
+
+#[test]
+fn numeral() { run_test("numeral.mm1") }
+
+#[test]
+fn implicit() { run_test("implicit.mm1") }
+
+#[test]
+fn conv() { run_test("conv.mm1") }
+
+#[test]
+fn rw() { run_test("rw.mm1") }
+
+#[test]
+fn div_zero() { run_test("div_zero.mm1") }
+
+#[test]
+fn hooks() { run_test("hooks.mm1") }
+
+#[test]
+fn packages() { run_test("packages.mm1") }
+
+#[test]
+fn tco() { run_test("tco.mm1") }
+
+#[test]
+fn letrec() { run_test("letrec.mm1") }
+
+/// `add-quotient` rejects a relation whose equivalence proofs do not check.
+#[test]
+fn bad_quotient() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "bad_quotient.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("1 errors, ") && out.contains("(name \"symm\")"), "{out}");
+}
+
+/// An I/O error in one test of `mm0-rs test` fails that test, and the others still run.
+#[test]
+fn test_io_error() {
+  let dir = std::env::temp_dir().join(format!("mm0-rs-test-io-{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(dir.join("b.expected")).unwrap();
+  fs::write(dir.join("a.mm1"), "delimiter $ ( ) $;\n").unwrap();
+  fs::write(dir.join("b.mm1"), "delimiter $ ( ) $;\n").unwrap();
+  let out = mm0_rs(&dir, &["test", "--no-prelude", "."]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("a.mm1 ... ok") && out.contains("b.mm1 ... FAILED"), "{out}");
+  assert!(out.contains("1 passed; 1 failed"), "{out}");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Garbage input is reported as errors by `compile`, instead of crashing it.
+#[test]
+fn garbage_input() {
+  let file = std::env::temp_dir().join(format!("mm0-rs-garbage-{}.mm1", std::process::id()));
+  let garbage = (0..4096u32).map(|i| b' ' + (i.wrapping_mul(2_654_435_761) >> 25) as u8 % 95)
+    .collect::<Vec<_>>();
+  fs::write(&file, [&b"delimiter $ ( ) $; do { (def x '(1 . $ x $)) }; $"[..], &garbage].concat()).unwrap();
+  let out = mm0_rs(Path::new("."), &["compile", file.to_str().unwrap()]);
+  let stdout = String::from_utf8_lossy(&out.stdout);
+  assert!(out.status.success() && stdout.contains(" errors, "), "{stdout}");
+  fs::remove_file(&file).unwrap();
+}
+
+/// `join --align` renames references to the renamed declarations in imported files, but
+/// not local variables, longer identifiers or notation tokens containing the name.
+#[test]
+fn join_align() {
+  let out = mm0_rs(Path::new("test_resources"), &["join", "-b", "--align", "join.align", "join.mm0"]);
+  assert!(out.status.success());
+  let expected = fs::read_to_string("test_resources/join_aligned.mm0").unwrap();
+  assert_eq!(String::from_utf8_lossy(&out.stdout), expected);
+}
+
+/// The import alignment is not applied to the local declarations on export, which use
+/// `--export-align` instead.
+#[test]
+fn export_align() {
+  let contains = |out: &Path, name: &[u8]| {
+    let mmb = fs::read(out).unwrap();
+    let name = [b"\0", name, b"\0"].concat();
+    mmb.windows(name.len()).any(|w| w == name)
+  };
+  let out = std::env::temp_dir().join(format!("mm0-rs-export-align-{}.mmb", std::process::id()));
+  let out_s = out.to_str().unwrap();
+  mm0_rs(Path::new("test_resources"), &["compile", "--align", "join.align", "join.mm0", out_s]);
+  assert!(contains(&out, b"bar") && contains(&out, b"foo"));
+  mm0_rs(Path::new("test_resources"),
+    &["compile", "--align", "join.align", "--export-align", "join_export.align", "join.mm0", out_s]);
+  assert!(contains(&out, b"bar") && contains(&out, b"baz") && !contains(&out, b"foo"));
+  fs::remove_file(&out).unwrap();
+}
+
+/// `info` lists the declarations of an MMB file, including terms, which have no proof.
+#[test]
+fn info() {
+  let out = std::env::temp_dir().join(format!("mm0-rs-info-{}.mmb", std::process::id()));
+  let out_s = out.to_str().unwrap();
+  mm0_rs(Path::new("test_resources"), &["compile", "--align", "join.align", "join.mm0", out_s]);
+  let info = mm0_rs(Path::new("test_resources"), &["info", out_s]);
+  let info = String::from_utf8_lossy(&info.stdout);
+  assert!(info.contains("1 sorts, 5 terms, 3 theorems"), "{info}");
+  assert!(info.lines().any(|l| l.ends_with("0 steps  term          foo_bar")), "{info}");
+  fs::remove_file(&out).unwrap();
+}
+
+/// The warning for an inferred dependency points at the innermost subterm that uses the
+/// variable directly, not the whole definition.
+#[test]
+fn dep_witness() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "dep_witness.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("on {x}, because of subterm eq x x\n"), "{out}");
+}
+
+/// An unknown term in a statement suggests the declared terms with similar names.
+#[test]
+fn did_you_mean() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "did_you_mean.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("term 'impp' not declared; did you mean 'imp'?"), "{out}");
+}
+
+/// Unification errors are printed at the width set by `set-pp-options`.
+#[test]
+fn unify_width() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "unify_width.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("variable vs term: a != a ->\n  a ->\n"), "{out}");
+}
+
+/// With `--capability env-read`, lisp code can still compute and read the environment, but
+/// the builtins that change it, including the options, fail.
+#[test]
+fn capability() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "capability.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(!out.contains(" errors, "), "{out}");
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "--capability", "env-read", "capability.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("info: 3\n") && out.contains("info: im x x\n"), "{out}");
+  assert_eq!(out.matches("requires the env-write capability").count(), 2, "{out}");
+}
+
+/// A bound variable of `free` sort that is free in a definition must be among the
+/// dependencies of its return type, even for definitions added from lisp.
+#[test]
+fn free_sort() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "free_sort.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("1 errors, ") && out.contains("depend on {y: set}"), "{out}");
+}
+
+/// `compile --minimize` shortens the proofs of ordinary `theorem` declarations.
+#[test]
+fn minimize() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "minimize.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("info: 2") && !out.contains("proof shortened"), "{out}");
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "--minimize", "1000", "minimize.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(out.contains("proof shortened from 2 to 1 steps") && out.contains("info: 1"), "{out}");
+  assert!(!out.contains(" errors, "), "{out}");
+}
+
+/// With `suggest-proofs`, a theorem proved by a tactic script suggests its proof term, which
+/// can replace the script.
+#[test]
+fn suggest_proofs() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "suggest.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  let prefix = "info: try this: ";
+  let sugg = out.lines().find_map(|l| l.strip_prefix(prefix)).expect("no suggestion");
+  assert_eq!(out.matches(prefix).count(), 1, "{out}");
+  let src = fs::read_to_string("test_resources/suggest.mm1").unwrap();
+  let src = src.replace("(focus (refine '(mp ax_1 h)))", sugg);
+  let file = std::env::temp_dir().join(format!("mm0-rs-suggest-{}.mm1", std::process::id()));
+  fs::write(&file, src).unwrap();
+  let out = mm0_rs(Path::new("."), &["compile", file.to_str().unwrap()]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(!out.contains(" errors, ") && !out.contains(prefix), "{out}");
+  fs::remove_file(&file).unwrap();
+}
+
+/// `auto` searches the hint database for a proof and suggests the script it found, which
+/// checks on its own; the search fails when the depth bound is too small.
+#[test]
+fn auto() {
+  let out = mm0_rs(Path::new("test_resources"), &["compile", "auto.mm1"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  let prefix = "info: try this: ";
+  let suggs: Vec<_> = out.lines().filter_map(|l| l.strip_prefix(prefix)).collect();
+  assert_eq!(suggs, ["(refine '(a1i (iani ha (iani hb ha))))", "(refine 'ax_1)"], "{out}");
+  assert!(out.contains("1 errors, ") && out.contains("auto failed to prove"), "{out}");
+  let src = fs::read_to_string("test_resources/auto.mm1").unwrap();
+  let src = src.replacen("(focus (auto))", suggs[0], 1).replacen("(focus (auto))", suggs[1], 1);
+  let src = src.replace("(focus (auto 2))", "(focus (auto 3))");
+  let file = std::env::temp_dir().join(format!("mm0-rs-auto-{}.mm1", std::process::id()));
+  fs::write(&file, src).unwrap();
+  let out = mm0_rs(Path::new("."), &["compile", file.to_str().unwrap()]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  assert!(!out.contains(" errors, ") && out.matches(prefix).count() == 1, "{out}");
+  fs::remove_file(&file).unwrap();
+}
+
+/// `refactor` edits the companion `.mm0` files of the whole import graph, and rejects unknown
+/// names and moves that would hide a declaration from one of its users.
+#[test]
+fn refactor() {
+  let dir = std::env::temp_dir().join(format!("mm0-rs-refactor-{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  for f in ["refactor_base.mm1", "refactor_base.mm0", "refactor_main.mm1", "refactor_main.mm0"] {
+    fs::copy(Path::new("test_resources").join(f), dir.join(f)).unwrap();
+  }
+  let read = |f: &str| fs::read_to_string(dir.join(f)).unwrap();
+  let refactor = |op: &str, arg: &str| mm0_rs(&dir, &["refactor", op, arg, "refactor_main.mm1"]);
+
+  assert!(!refactor("--rename", "nope=bar2").status.success());
+  assert!(refactor("--rename", "bar=bar2").status.success());
+  assert!(read("refactor_base.mm1").contains("$ bar2 -> bar2 $"));
+  assert!(read("refactor_base.mm0").contains("$ bar2 -> bar2 $"));
+
+  // `baz` is declared before the end of the target file, so it would not see `bar2`
+  let base = read("refactor_base.mm1");
+  let out = refactor("--move", "bar2=refactor_main.mm1");
+  assert!(!out.status.success() && read("refactor_base.mm1") == base);
+
+  assert!(refactor("--move", "foo=refactor_base.mm1").status.success());
+  for f in ["refactor_base.mm1", "refactor_base.mm0"] {
+    assert!(read(f).ends_with("\nterm foo: wff;\n"), "{}", read(f));
+  }
+  for f in ["refactor_main.mm1", "refactor_main.mm0"] { assert!(!read(f).contains("term foo")) }
+  let out = mm0_rs(&dir, &["compile", "refactor_main.mm1"]);
+  assert!(!String::from_utf8_lossy(&out.stdout).contains(" errors, "));
+
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Compile `cache.mm1` with `cache_prelude.mm1` as the prelude, in a scratch directory so
+/// that the prelude snapshot is not written into the source tree.
+#[test]
+fn prelude_cache() {
+  let dir = std::env::temp_dir().join(format!("mm0-rs-prelude-cache-{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  let res = PathBuf::from("test_resources");
+  fs::copy(res.join("cache_prelude.mm1"), dir.join("prelude.mm1")).unwrap();
+  fs::copy(res.join("cache.mm1"), dir.join("main.mm1")).unwrap();
+  let compile = || {
+    let out = mm0_rs(&dir, &["compile", "--prelude", "prelude.mm1", "main.mm1"]);
+    let out = String::from_utf8_lossy(&out.stdout).into_owned();
+    (!out.contains(" errors, "), out)
+  };
+  let loaded = |out: &str| out.lines().any(|l| l.starts_with("loaded ") && l.ends_with("prelude.mm1.env"));
+
+  // The first run elaborates the prelude and saves the snapshot
+  let (ok, out) = compile();
+  assert!(ok && !loaded(&out) && out.contains("info: 5"), "{out}");
+  assert!(dir.join("prelude.mm1.env").exists());
+
+  // The second run loads it, and the numeral registered in the prelude still works
+  let (ok, out) = compile();
+  assert!(ok && loaded(&out) && out.contains("info: 5"), "{out}");
+
+  // Changing the prelude invalidates the snapshot, and the change is seen
+  let prelude = fs::read_to_string(dir.join("prelude.mm1")).unwrap();
+  let prelude = prelude.replace("do { (set-numeral 'nat 'z '(b0 b1)) };\n", "");
+  fs::write(dir.join("prelude.mm1"), prelude).unwrap();
+  let (ok, out) = compile();
+  assert!(!ok && !loaded(&out) && !out.contains("info: 5"), "{out}");
+
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+/// `fetch` unpacks a package into the cache under its name, which may contain dots, and
+/// rejects names that would put it outside of the cache.
+#[cfg(feature = "package")]
+#[test]
+fn fetch_names() {
+  let dir = std::env::temp_dir().join(format!("mm0-rs-fetch-{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  for d in ["lib", "main", "cache"] { fs::create_dir_all(dir.join(d)).unwrap() }
+  let run = |sub: &str, args: &[&str]| Command::new(env!("CARGO_BIN_EXE_mm0-rs"))
+    .current_dir(dir.join(sub)).env("MM0_CACHE", dir.join("cache")).args(args).output().unwrap();
+  fs::write(dir.join("lib/mm0.toml"), "[package]\nname = \"lib.x\"\nversion = \"0.1.0\"\n").unwrap();
+  fs::write(dir.join("lib/a.mm1"), "delimiter $ ( ) $;\n").unwrap();
+  let out = run("lib", &["package"]);
+  let out = String::from_utf8_lossy(&out.stdout);
+  let hash = out.lines().find_map(|l| l.strip_prefix("sha256: ")).expect("no hash");
+  let manifest = |name: &str| format!(
+    "[dependencies]\n{:?} = {{ url = \"../lib/lib.x-0.1.0.tar\", hash = {:?} }}\n", name, hash);
+
+  fs::write(dir.join("main/mm0.toml"), manifest("lib.x")).unwrap();
+  assert!(run("main", &["fetch"]).status.success());
+  let cached = fs::read_dir(dir.join("cache")).unwrap()
+    .map(|e| e.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+  assert_eq!(cached, [format!("lib.x-{}", &hash[..16])]);
+  assert!(fs::read_dir(dir.join("cache").join(&cached[0])).unwrap().count() > 0);
+
+  fs::write(dir.join("main/mm0.toml"), manifest("../x")).unwrap();
+  let out = run("main", &["fetch"]);
+  assert!(!out.status.success() && String::from_utf8_lossy(&out.stderr).contains("invalid dependency name"));
+  assert!(!dir.join("x-".to_owned() + &hash[..16]).exists());
+
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Elaborate `text` as the file `do_cache.mm1`, given its previous elaboration `old`.
+fn elab_text(text: &str, old: Option<FrozenEnv>) -> FrozenEnv {
+  let ast = Arc::new(mm1_parser::parse(Arc::new(text.to_owned().into()), None).1);
+  let (_, _, errors, env) = block_on(ElaborateBuilder {
+    ast: &ast,
+    path: PathBuf::from("do_cache.mm1").into(),
+    mm0_mode: false,
+    check_proofs: true,
+    report_upstream_errors: false,
+    cancel: Default::default(),
+    old: old.map(|env| (0, None, env)),
+    recv_dep: |_| -> Result<Receiver<ElabResult<()>>, _> { Err("no imports".into()) },
+    recv_goal: None,
+    prelude: None,
+    on_stmt: None,
+  }.elab());
+  assert!(errors.is_empty(), "{:?}", errors.iter().map(|e| e.kind.msg()).collect::<Vec<_>>());
+  env
+}
+
+/// Elaborating a file again reuses the `do` blocks that only define tactics, unless the
+/// block or a declaration before it changed.
+#[test]
+fn do_cache() {
+  let text = |proof: &str, tac: &str, sort: &str| format!("delimiter $ ( ) $;\n\
+    provable sort wff; {sort}\n\
+    term im: wff > wff > wff;\n\
+    axiom id (p: wff): $ im p p $;\n\
+    do {{ (def (tac) {tac}) }};\n\
+    theorem foo (p: wff): $ im p p $ = {proof};\n");
+  let env = elab_text(&text("(tac)", "'id", ""), None);
+  assert_eq!(env.do_cache().reused, 0);
+  // editing a proof does not evaluate the block again
+  let env = elab_text(&text("'(! id p)", "'id", ""), Some(env));
+  assert_eq!(env.do_cache().reused, 1);
+  // editing the block does
+  let env = elab_text(&text("(tac)", "'(id)", ""), Some(env));
+  assert_eq!(env.do_cache().reused, 0);
+  // and so does editing a declaration before it
+  let env = elab_text(&text("(tac)", "'(id)", "sort nat;"), Some(env));
+  assert_eq!(env.do_cache().reused, 0);
+  let env = elab_text(&text("(tac)", "'(id)", "sort nat;"), Some(env));
+  assert_eq!(env.do_cache().reused, 1);
+}